@@ -1,4 +1,4 @@
-use another_ext4::{FileAttr as Ext4FileAttr, FileType as Ext4FileType, INODE_BLOCK_SIZE};
+use another_ext4::{FileAttr as Ext4FileAttr, FileType as Ext4FileType};
 use fuser::{FileAttr, FileType, TimeOrNow};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
@@ -20,17 +20,17 @@ pub fn translate_attr(attr: Ext4FileAttr) -> FileAttr {
         ino: attr.ino as u64,
         size: attr.size,
         blocks: attr.blocks,
-        atime: second2sys_time(attr.atime),
-        mtime: second2sys_time(attr.mtime),
-        ctime: second2sys_time(attr.ctime),
-        crtime: second2sys_time(attr.crtime),
+        atime: second2sys_time(attr.atime, attr.atime_nsec),
+        mtime: second2sys_time(attr.mtime, attr.mtime_nsec),
+        ctime: second2sys_time(attr.ctime, attr.ctime_nsec),
+        crtime: second2sys_time(attr.crtime, attr.crtime_nsec),
         kind: translate_ftype(attr.ftype),
         perm: attr.perm.bits(),
         nlink: attr.links as u32,
         uid: attr.uid,
         gid: attr.gid,
-        rdev: 0,
-        blksize: INODE_BLOCK_SIZE as u32,
+        rdev: makedev(attr.rdev),
+        blksize: attr.blksize,
         flags: 0,
     }
 }
@@ -39,8 +39,14 @@ pub fn sys_time2second(time: SystemTime) -> u32 {
     time.duration_since(UNIX_EPOCH).unwrap().as_secs() as u32
 }
 
-pub fn second2sys_time(time: u32) -> SystemTime {
-    SystemTime::UNIX_EPOCH + Duration::from_secs(time as u64)
+pub fn second2sys_time(time: u32, nsec: u32) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::new(time as u64, nsec)
+}
+
+/// Encode a `(major, minor)` device number as Linux `makedev` does,
+/// truncated to the 32 bits FUSE carries.
+fn makedev((major, minor): (u32, u32)) -> u32 {
+    (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12)
 }
 
 pub fn time_or_now2second(time_or_now: TimeOrNow) -> u32 {
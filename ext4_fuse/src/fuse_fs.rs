@@ -27,7 +27,6 @@ use std::ffi::OsStr;
 use std::sync::Arc;
 use std::time::Duration;
 
-type FId = u64;
 type StateKey = u64;
 
 pub struct StateExt4FuseFs<T> {
@@ -37,10 +36,6 @@ pub struct StateExt4FuseFs<T> {
     fs: Ext4,
     /// Checkpoint states
     states: HashMap<StateKey, T>,
-    /// Next file handler id
-    next_fid: FId,
-    /// Next directory handler id
-    next_did: FId,
 }
 
 impl<T: 'static> StateExt4FuseFs<T> {
@@ -59,8 +54,6 @@ impl<T: 'static> StateExt4FuseFs<T> {
             fs,
             block_dev,
             states: HashMap::new(),
-            next_fid: 0,
-            next_did: 0,
         }
     }
 
@@ -83,6 +76,13 @@ impl<T: 'static> StateExt4FuseFs<T> {
         }
     }
 
+    /// Get the generation number of an inode
+    fn get_generation(&self, inode: u32) -> u64 {
+        self.fs
+            .getattr(inode)
+            .map_or(0, |attr| attr.generation as u64)
+    }
+
     /// Get file attribute and tranlate type
     fn get_attr(&self, inode: u32) -> Result<FileAttr, Ext4Error> {
         match self.fs.getattr(inode) {
@@ -99,15 +99,19 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
 
     fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
         match self.fs.lookup(parent as u32, name.to_str().unwrap()) {
-            Ok(inode_id) => reply.entry(&get_ttl(), &self.get_attr(inode_id).unwrap(), 0),
-            Err(e) => reply.error(e.code() as i32),
+            Ok(inode_id) => reply.entry(
+                &get_ttl(),
+                &self.get_attr(inode_id).unwrap(),
+                self.get_generation(inode_id),
+            ),
+            Err(e) => reply.error(e.into()),
         }
     }
 
     fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
         match self.get_attr(ino as u32) {
             Ok(attr) => reply.attr(&get_ttl(), &attr),
-            Err(e) => reply.error(e.code() as i32),
+            Err(e) => reply.error(e.into()),
         }
     }
 
@@ -131,7 +135,7 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
     ) {
         match self.fs.setattr(
             ino as u32,
-            mode.map(|m| InodeMode::from_bits_truncate(m as u16)),
+            mode.map(InodeMode::from),
             uid,
             gid,
             size,
@@ -141,7 +145,7 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
             crtime.map(|t| sys_time2second(t)),
         ) {
             Ok(_) => reply.attr(&get_ttl(), &self.get_attr(ino as u32).unwrap()),
-            Err(e) => reply.error(e.code() as i32),
+            Err(e) => reply.error(e.into()),
         }
     }
 
@@ -151,34 +155,35 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _umask: u32,
-        _flags: i32,
+        umask: u32,
+        flags: i32,
         reply: ReplyCreate,
     ) {
         // Check if name is already in use
         if let Ok(_) = self.fs.lookup(parent as u32, name.to_str().unwrap()) {
             return reply.error(ErrCode::EEXIST as i32);
         }
-        match self.fs.create(
+        let ino = match self.fs.create(
             parent as u32,
             name.to_str().unwrap(),
-            InodeMode::from_bits_truncate(mode as u16),
+            InodeMode::from(mode & !umask),
         ) {
-            Ok(ino) => {
-                reply.created(
-                    &get_ttl(),
-                    &self.get_attr(ino).unwrap(),
-                    0,
-                    self.next_fid,
-                    0,
-                );
-                self.next_fid += 1;
-            }
-            Err(e) => reply.error(e.code() as i32),
+            Ok(ino) => ino,
+            Err(e) => return reply.error(e.into()),
+        };
+        match self.fs.open(ino, flags as u32) {
+            Ok(fh) => reply.created(
+                &get_ttl(),
+                &self.get_attr(ino).unwrap(),
+                self.get_generation(ino),
+                fh,
+                0,
+            ),
+            Err(e) => reply.error(e.into()),
         }
     }
 
-    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
         let attr = self.get_attr(ino as u32);
         match attr {
             Ok(attr) => {
@@ -186,10 +191,12 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
                     return reply.error(ErrCode::EISDIR as i32);
                 }
             }
-            Err(e) => return reply.error(e.code() as i32),
+            Err(e) => return reply.error(e.into()),
+        }
+        match self.fs.open(ino as u32, flags as u32) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(e.into()),
         }
-        reply.opened(self.next_fid, 0);
-        self.next_fid += 1;
     }
 
     fn read(
@@ -204,9 +211,9 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
         reply: ReplyData,
     ) {
         let mut data = vec![0; size as usize];
-        match self.fs.read(ino as u32, offset as usize, &mut data) {
+        match self.fs.read(ino as u32, offset as u64, &mut data) {
             Ok(sz) => reply.data(&data[..sz]),
-            Err(e) => reply.error(e.code() as i32),
+            Err(e) => reply.error(e.into()),
         }
     }
 
@@ -222,9 +229,9 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
         _lock_owner: Option<u64>,
         reply: ReplyWrite,
     ) {
-        match self.fs.write(ino as u32, offset as usize, data) {
+        match self.fs.write(ino as u32, offset as u64, data) {
             Ok(sz) => reply.written(sz as u32),
-            Err(e) => reply.error(e.code() as i32),
+            Err(e) => reply.error(e.into()),
         }
     }
 
@@ -232,13 +239,16 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
         &mut self,
         _req: &Request<'_>,
         _ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: i32,
         _lock_owner: Option<u64>,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
-        reply.ok();
+        match self.fs.release(fh) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.into()),
+        }
     }
 
     fn link(
@@ -257,15 +267,19 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
             .fs
             .link(ino as u32, newparent as u32, newname.to_str().unwrap())
         {
-            Ok(_) => reply.entry(&get_ttl(), &self.get_attr(ino as u32).unwrap(), 0),
-            Err(e) => reply.error(e.code() as i32),
+            Ok(_) => reply.entry(
+                &get_ttl(),
+                &self.get_attr(ino as u32).unwrap(),
+                self.get_generation(ino as u32),
+            ),
+            Err(e) => reply.error(e.into()),
         }
     }
 
     fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         match self.fs.unlink(parent as u32, name.to_str().unwrap()) {
             Ok(_) => reply.ok(),
-            Err(e) => reply.error(e.code() as i32),
+            Err(e) => reply.error(e.into()),
         }
     }
 
@@ -291,7 +305,7 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
                 {
                     // Overwrite empty directory
                     if let Err(e) = self.fs.rmdir(newparent as u32, newname.to_str().unwrap()) {
-                        return reply.error(e.code() as i32);
+                        return reply.error(e.into());
                     }
                 } else {
                     return reply.error(ErrCode::ENOTEMPTY as i32);
@@ -307,7 +321,7 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
             newname.to_str().unwrap(),
         ) {
             Ok(_) => reply.ok(),
-            Err(e) => reply.error(e.code() as i32),
+            Err(e) => reply.error(e.into()),
         }
     }
 
@@ -317,7 +331,7 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _umask: u32,
+        umask: u32,
         reply: ReplyEntry,
     ) {
         // Check if name is already in use
@@ -327,23 +341,29 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
         match self.fs.mkdir(
             parent as u32,
             name.to_str().unwrap(),
-            InodeMode::from_bits_truncate(mode as u16),
+            InodeMode::from(mode & !umask),
         ) {
-            Ok(ino) => reply.entry(&get_ttl(), &self.get_attr(ino).unwrap(), 0),
-            Err(e) => reply.error(e.code() as i32),
+            Ok(ino) => reply.entry(
+                &get_ttl(),
+                &self.get_attr(ino).unwrap(),
+                self.get_generation(ino),
+            ),
+            Err(e) => reply.error(e.into()),
         }
     }
 
-    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
         match self.get_attr(ino as u32) {
             Ok(attr) => {
                 if attr.kind != FileType::Directory {
                     return reply.error(ErrCode::ENOTDIR as i32);
                 }
-                reply.opened(self.next_did, 0);
-                self.next_did += 1;
             }
-            Err(e) => reply.error(e.code() as i32),
+            Err(e) => return reply.error(e.into()),
+        }
+        match self.fs.open(ino as u32, flags as u32) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(e.into()),
         }
     }
 
@@ -374,7 +394,7 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
                 reply.ok();
             }
             Err(e) => {
-                reply.error(e.code() as i32);
+                reply.error(e.into());
             }
         }
     }
@@ -383,17 +403,20 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
         &mut self,
         _req: &Request<'_>,
         _ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: i32,
         reply: ReplyEmpty,
     ) {
-        reply.ok();
+        match self.fs.release(fh) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.into()),
+        }
     }
 
     fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         match self.fs.rmdir(parent as u32, name.to_str().unwrap()) {
             Ok(()) => reply.ok(),
-            Err(e) => reply.error(e.code() as i32),
+            Err(e) => reply.error(e.into()),
         }
     }
 
@@ -479,7 +502,7 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
                     reply.error(ErrCode::ERANGE as i32);
                 }
             }
-            Err(e) => reply.error(e.code() as i32),
+            Err(e) => reply.error(e.into()),
         }
     }
 
@@ -500,7 +523,7 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
         }
         match self.fs.setxattr(ino as u32, name, value) {
             Ok(_) => reply.ok(),
-            Err(e) => reply.error(e.code() as i32),
+            Err(e) => reply.error(e.into()),
         }
     }
 
@@ -508,7 +531,7 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
         let name = name.to_str().unwrap();
         match self.fs.removexattr(ino as u32, name) {
             Ok(_) => reply.ok(),
-            Err(e) => reply.error(e.code() as i32),
+            Err(e) => reply.error(e.into()),
         }
     }
 
@@ -528,7 +551,7 @@ impl<T: 'static> Filesystem for StateExt4FuseFs<T> {
                     reply.error(ErrCode::ERANGE as i32);
                 }
             }
-            Err(e) => reply.error(e.code() as i32),
+            Err(e) => reply.error(e.into()),
         }
     }
 }
@@ -4,6 +4,8 @@ use crate::prelude::*;
 
 /// The maximum number of blocks in the file system
 pub const MAX_BLOCKS: LBlockId = LBlockId::MAX;
+/// Maximum size of a file in bytes, logical blocks `0..MAX_BLOCKS`
+pub const MAX_FILE_SIZE: u64 = MAX_BLOCKS as u64 * BLOCK_SIZE as u64;
 
 /// Maximum bytes in a path
 pub const PATH_MAX: usize = 4096;
@@ -11,6 +13,23 @@ pub const PATH_MAX: usize = 4096;
 /// Maximum bytes in a file name
 pub const NAME_MAX: usize = 255;
 
+/// Default maximum number of open file handles
+pub const MAX_OPEN_HANDLES: usize = 1024;
+
+/// Open flag for file handles whose I/O bypasses the block cache, same
+/// value as on Linux
+pub const O_DIRECT: u32 = 0o40000;
+
+/// Default maximum number of bytes buffered per inode by a `DelayedWriter`
+pub const DELALLOC_LIMIT: usize = 1024 * 1024;
+
+/// Number of slots a block group is divided in to spread the default
+/// allocation goals of inodes
+pub const BLOCK_GOAL_SLOTS: PBlockId = 16;
+
+/// Symlink targets shorter than this are stored in the inode
+pub const FAST_SYMLINK_MAX: usize = 60;
+
 /// The upper limit for resolving symbolic links
 pub const SYMLINKS_MAX: usize = 40;
 
@@ -36,10 +55,19 @@ pub const CRC32_INIT: u32 = 0xFFFFFFFF;
 /// We implement the larger version of inode size for simplicity.
 pub const SB_GOOD_INODE_SIZE: usize = 256;
 
-/// The value of super block `desc_size` field.
-/// We implement the 64-bit block group descriptor for simplicity.
+/// The smallest super block `inode_size`, used by ext2 images.
+pub const SB_MIN_INODE_SIZE: usize = 128;
+
+/// The size of a block group descriptor with the `64bit` feature, as made
+/// by mkfs. Larger descriptors keep their bytes past it as they are.
 pub const SB_GOOD_DESC_SIZE: usize = 64;
 
+/// The largest block group descriptor with the `64bit` feature.
+pub const SB_MAX_DESC_SIZE: usize = 1024;
+
+/// The size of a block group descriptor without the `64bit` feature.
+pub const SB_MIN_DESC_SIZE: usize = 32;
+
 /// The size of the block cache (cache set number).
 pub const CACHE_SIZE: usize = 4;
 
@@ -17,12 +17,16 @@ pub enum ErrCode {
     ENXIO = 6,
     /// Argument list too long.
     E2BIG = 7,
+    /// Bad file number.
+    EBADF = 9,
     /// Out of memory.
     ENOMEM = 12,
     /// Permission denied.
     EACCES = 13,
     /// Bad address.
     EFAULT = 14,
+    /// Device or resource busy.
+    EBUSY = 16,
     /// File exists.
     EEXIST = 17,
     /// No such device.
@@ -33,6 +37,8 @@ pub enum ErrCode {
     EISDIR = 21,
     /// Invalid argument.
     EINVAL = 22,
+    /// Too many open files.
+    EMFILE = 24,
     /// File too large.
     EFBIG = 27,
     /// No space left on device.
@@ -43,22 +49,103 @@ pub enum ErrCode {
     EMLINK = 31,
     /// Math result not representable.
     ERANGE = 34,
+    /// File name too long.
+    ENAMETOOLONG = 36,
     /// Directory not empty.
     ENOTEMPTY = 39,
     /// No data available.
     ENODATA = 61,
+    /// Bad metadata checksum (EBADMSG).
+    EFSBADCRC = 74,
     /// Not supported.
     ENOTSUP = 95,
     /// Link failed.
     ELINKFAIL = 97,
     /// Inode alloc failed.
     EALLOCFAIL = 98,
+    /// Stale file handle.
+    ESTALE = 116,
+    /// Filesystem is corrupted (EUCLEAN).
+    EFSCORRUPTED = 117,
+    /// Required key not available.
+    ENOKEY = 126,
+}
+
+impl ErrCode {
+    /// Convert an errno value to `ErrCode`. Return `None` if the value
+    /// is not an error code used by this crate.
+    pub const fn from_i32(errno: i32) -> Option<Self> {
+        Some(match errno {
+            1 => ErrCode::EPERM,
+            2 => ErrCode::ENOENT,
+            5 => ErrCode::EIO,
+            6 => ErrCode::ENXIO,
+            7 => ErrCode::E2BIG,
+            9 => ErrCode::EBADF,
+            12 => ErrCode::ENOMEM,
+            13 => ErrCode::EACCES,
+            14 => ErrCode::EFAULT,
+            16 => ErrCode::EBUSY,
+            17 => ErrCode::EEXIST,
+            19 => ErrCode::ENODEV,
+            20 => ErrCode::ENOTDIR,
+            21 => ErrCode::EISDIR,
+            22 => ErrCode::EINVAL,
+            24 => ErrCode::EMFILE,
+            27 => ErrCode::EFBIG,
+            28 => ErrCode::ENOSPC,
+            30 => ErrCode::EROFS,
+            31 => ErrCode::EMLINK,
+            34 => ErrCode::ERANGE,
+            36 => ErrCode::ENAMETOOLONG,
+            39 => ErrCode::ENOTEMPTY,
+            61 => ErrCode::ENODATA,
+            74 => ErrCode::EFSBADCRC,
+            95 => ErrCode::ENOTSUP,
+            97 => ErrCode::ELINKFAIL,
+            98 => ErrCode::EALLOCFAIL,
+            116 => ErrCode::ESTALE,
+            117 => ErrCode::EFSCORRUPTED,
+            126 => ErrCode::ENOKEY,
+            _ => return None,
+        })
+    }
+
+    /// Get the errno value of the error code.
+    pub const fn errno(self) -> i32 {
+        self as i32
+    }
+}
+
+impl core::fmt::Display for ErrCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?} ({})", self, *self as i32)
+    }
+}
+
+/// The message attached to an error.
+#[derive(Clone)]
+enum ErrMessage {
+    /// A message that needs no formatting.
+    Static(&'static str),
+    /// A formatted message.
+    Owned(String),
+}
+
+impl ErrMessage {
+    fn as_str(&self) -> &str {
+        match self {
+            ErrMessage::Static(message) => message,
+            ErrMessage::Owned(message) => message,
+        }
+    }
 }
 
 /// error used in this crate
+#[derive(Clone)]
 pub struct Ext4Error {
     code: ErrCode,
-    message: Option<String>,
+    message: Option<ErrMessage>,
 }
 
 impl Debug for Ext4Error {
@@ -67,7 +154,8 @@ impl Debug for Ext4Error {
             write!(
                 f,
                 "Ext4Error {{ code: {:?}, message: {:?} }}",
-                self.code, message
+                self.code,
+                message.as_str()
             )
         } else {
             write!(f, "Ext4Error {{ code: {:?} }}", self.code)
@@ -75,6 +163,18 @@ impl Debug for Ext4Error {
     }
 }
 
+impl core::fmt::Display for Ext4Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if let Some(message) = &self.message {
+            write!(f, "{}: {}", self.code, message.as_str())
+        } else {
+            write!(f, "{}", self.code)
+        }
+    }
+}
+
+impl core::error::Error for Ext4Error {}
+
 impl Ext4Error {
     pub const fn new(code: ErrCode) -> Self {
         Ext4Error {
@@ -86,17 +186,48 @@ impl Ext4Error {
     pub const fn with_message(code: ErrCode, message: String) -> Self {
         Ext4Error {
             code,
-            message: Some(message),
+            message: Some(ErrMessage::Owned(message)),
+        }
+    }
+
+    /// Create an error with a static message, which requires no allocation.
+    pub const fn with_static_message(code: ErrCode, message: &'static str) -> Self {
+        Ext4Error {
+            code,
+            message: Some(ErrMessage::Static(message)),
         }
     }
 
     pub const fn code(&self) -> ErrCode {
         self.code
     }
+
+    /// Get the message attached to the error, if any.
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_ref().map(|m| m.as_str())
+    }
+}
+
+impl From<ErrCode> for Ext4Error {
+    fn from(code: ErrCode) -> Self {
+        Ext4Error::new(code)
+    }
 }
 
+/// Convert an error to its errno value.
+impl From<Ext4Error> for i32 {
+    fn from(error: Ext4Error) -> Self {
+        error.code as i32
+    }
+}
+
+/// Create an `Ext4Error`. A single message literal without arguments is
+/// stored as a static string, so no allocation is needed.
 #[macro_export]
 macro_rules! format_error {
+    ($code: expr, $message: literal) => {
+        $crate::error::Ext4Error::with_static_message($code, $message)
+    };
     ($code: expr, $message: expr) => {
         $crate::error::Ext4Error::with_message($code, format!($message))
     };
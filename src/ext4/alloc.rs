@@ -4,35 +4,84 @@ use crate::ext4_defs::*;
 use crate::format_error;
 use crate::prelude::*;
 use crate::return_error;
+use core::ops::Range;
+
+/// Allocation shards, see `Ext4::set_alloc_shards`.
+#[derive(Clone, Copy)]
+pub(super) struct AllocShards {
+    /// Number of shards
+    count: u32,
+    /// Get the shard of the caller
+    current: fn() -> u32,
+}
 
 impl Ext4 {
-    /// Create a new inode, returning the inode and its number
-    pub(super) fn create_inode(&self, mode: InodeMode) -> Result<InodeRef> {
+    /// Spread block allocations over `shards` allocation contexts, such as
+    /// CPUs or tasks. `current` returns the shard of the caller, taken
+    /// modulo `shards`.
+    ///
+    /// Each shard gets its own range of block groups for new allocations,
+    /// so that parallel writers neither contend on the same bitmaps nor
+    /// interleave their blocks. Files keep growing where they are, and a
+    /// shard falls back to the other groups when its own are full. At most
+    /// one shard per block group is used. A `shards` of 0 or 1 disables
+    /// sharding, which is the default.
+    pub fn set_alloc_shards(&mut self, shards: u32, current: fn() -> u32) {
+        self.alloc_shards = (shards > 1).then_some(AllocShards {
+            count: shards,
+            current,
+        });
+    }
+
+    /// Create a new inode under `parent`, returning the inode and its number
+    pub(super) fn create_inode(&self, parent: InodeId, mode: InodeMode) -> Result<InodeRef> {
         // Allocate an inode
         let is_dir = mode.file_type() == FileType::Directory;
-        let id = self.alloc_inode(is_dir)?;
+        let id = self.alloc_inode(parent, is_dir)?;
+        // Forget cached mappings of the previous user of the inode number
+        self.es_invalidate(id);
+        // Bump the generation left by the previous user of the inode number
+        let generation = self.read_inode(id).inode.generation().wrapping_add(1);
 
         // Initialize the inode
+        let sb = self.read_super_block();
         let mut inode = Inode::default();
+        inode.set_extra_isize(sb.want_extra_isize());
+        inode.fit_extra_isize(sb.inode_size());
         inode.set_mode(mode);
-        inode.extent_init();
+        inode.set_generation(generation);
+        // Device, FIFO and socket inodes own no blocks. Without the extents
+        // feature, blocks are mapped with the ext2 block map.
+        if sb.has_extents()
+            && matches!(
+                mode.file_type(),
+                FileType::RegularFile | FileType::Directory | FileType::SymLink
+            )
+        {
+            inode.extent_init();
+        }
         let mut inode_ref = InodeRef::new(id, inode);
 
         // Sync the inode to disk
-        self.write_inode_with_csum(&mut inode_ref);
+        self.write_inode(&mut inode_ref);
 
-        trace!("Alloc inode {} ok", inode_ref.id);
+        log_alloc!(trace, "Alloc inode {} ok", inode_ref.id);
         Ok(inode_ref)
     }
 
     /// Create(initialize) the root inode of the file system
     pub(super) fn create_root_inode(&self) -> Result<InodeRef> {
+        let sb = self.read_super_block();
         let mut inode = Inode::default();
+        inode.set_extra_isize(sb.want_extra_isize());
+        inode.fit_extra_isize(sb.inode_size());
         inode.set_mode(InodeMode::from_type_and_perm(
             FileType::Directory,
             InodeMode::from_bits_retain(0o755),
         ));
-        inode.extent_init();
+        if sb.has_extents() {
+            inode.extent_init();
+        }
 
         let mut root = InodeRef::new(EXT4_ROOT_INO, inode);
         let root_self = root.clone();
@@ -42,175 +91,435 @@ impl Ext4 {
         self.dir_add_entry(&mut root, &root_self, "..")?;
         root.inode.set_link_count(2);
 
-        self.write_inode_with_csum(&mut root);
+        self.write_inode(&mut root);
         Ok(root)
     }
 
+    /// Free an inode created by an operation that failed before linking it,
+    /// along with the blocks it got, and return the error of the operation.
+    pub(super) fn free_new_inode(&self, inode: &mut InodeRef, err: Ext4Error) -> Ext4Error {
+        if let Err(free_err) = self.free_inode(inode) {
+            warn!("Failed to free new inode {}: {}", inode.id, free_err);
+        }
+        err
+    }
+
     /// Free an allocated inode and all data blocks allocated for it
     pub(super) fn free_inode(&self, inode: &mut InodeRef) -> Result<()> {
-        // Free the data blocks allocated for the inode
-        let pblocks = self.extent_all_data_blocks(inode);
-        for pblock in pblocks {
-            // Deallocate the block
-            self.dealloc_block(inode, pblock)?;
-            // Clear the block content
-            self.write_block(&Block::new(pblock, [0; BLOCK_SIZE]));
-        }
-        // Free extent tree
-        let pblocks = self.extent_all_tree_blocks(inode);
-        for pblock in pblocks {
-            // Deallocate the block
-            self.dealloc_block(inode, pblock)?;
-            // Clear the block content
-            self.write_block(&Block::new(pblock, [0; BLOCK_SIZE]));
+        self.check_inode_freeable(inode.id)?;
+        self.check_unpinned(inode.id, 0)?;
+        let secure = self.zero_freed(inode);
+        self.es_invalidate(inode.id);
+        // Devices, fifos, sockets and fast symlinks own no blocks but the
+        // xattr block
+        if inode.inode.uses_extents() {
+            let tree = inode.clone();
+            // Free the data blocks allocated for the inode, an extent at a
+            // time, without collecting them
+            self.extent_for_each(&tree, |ex| {
+                self.dealloc_blocks(inode, ex.start_pblock(), ex.block_count() as u64)
+            })?;
+            // Free extent tree
+            self.extent_for_each_tree_block(&tree, |pblock| self.dealloc_blocks(inode, pblock, 1))?;
+        } else if inode.inode.uses_block_map() {
+            self.indirect_free(inode)?;
         }
         // Free xattr block
         let xattr_block = inode.inode.xattr_block();
         if xattr_block != 0 {
-            // Deallocate the block
-            self.dealloc_block(inode, xattr_block)?;
-            // Clear the block content
-            self.write_block(&Block::new(xattr_block, [0; BLOCK_SIZE]));
+            self.dealloc_blocks(inode, xattr_block, 1)?;
         }
         // Deallocate the inode
         self.dealloc_inode(inode)?;
+        self.stats.inode_free();
+        if secure {
+            // Make sure the zeroed content reached stable storage
+            self.flush_device();
+        }
         Ok(())
     }
 
-    /// Append a data block for an inode, return a pair of (logical block id, physical block id)
+    /// Map logical block `iblock` of an inode to its physical block, like
+    /// `ext4_map_blocks` in Linux. A hole is mapped to a new block.
     ///
-    /// Only data blocks allocated by `inode_append_block` will be counted in `inode.block_count`.
-    /// Blocks allocated by calling `alloc_block` directly will not be counted, i.e., blocks
-    /// allocated for the inode's extent tree.
+    /// Return the physical block and whether it was newly mapped. New
+    /// blocks are counted in `inode.block_count` but not zeroed, as are the
+    /// extent tree and indirect blocks allocated to map them.
     ///
-    /// Appending a block does not increase `inode.size`, because `inode.size` records the actual
-    /// size of the data content, not the number of blocks allocated for it.
+    /// Mapping a block does not increase `inode.size`, which records the
+    /// size of the data content. Writers set it when the data is written.
     ///
-    /// If the inode is a file, `inode.size` will be increased when writing to end of the file.
-    /// If the inode is a directory, `inode.size` will be increased when adding a new entry to the
-    /// newly created block.
-    pub(super) fn inode_append_block(&self, inode: &mut InodeRef) -> Result<(LBlockId, PBlockId)> {
-        // The new logical block id
-        let iblock = inode.inode.fs_block_count() as LBlockId;
+    /// # Error
+    ///
+    /// * `EFBIG` - `iblock` is out of the reach of the inode
+    /// * `ENOSPC` - no space left on device
+    pub(super) fn map_block(
+        &self,
+        inode: &mut InodeRef,
+        iblock: LBlockId,
+    ) -> Result<(PBlockId, bool)> {
+        match self.extent_query(inode, iblock) {
+            Ok(pblock) => Ok((pblock, false)),
+            Err(e) if e.code() == ErrCode::ENOENT => {
+                Ok((self.inode_map_block(inode, iblock)?, true))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Map a new data block for an inode at logical block `iblock`, which
+    /// must not be mapped yet and may follow a hole. Return the physical
+    /// block id.
+    pub(super) fn inode_map_block(
+        &self,
+        inode: &mut InodeRef,
+        iblock: LBlockId,
+    ) -> Result<PBlockId> {
+        self.check_block_count(inode, inode.inode.fs_block_count() + 1)?;
         // Check the extent tree to get the physical block id
-        let fblock = self.extent_query_or_create(inode, iblock, 1)?;
-        // Update block count
-        inode.inode.set_fs_block_count(iblock as u64 + 1);
-        self.write_inode_without_csum(inode);
+        let fblock = self.extent_query_or_create(inode, iblock)?;
+        // Update block count, which indirect blocks mapped on the way have
+        // already been added to
+        let block_count = inode.inode.fs_block_count() + 1;
+        inode.inode.set_fs_block_count(block_count);
+        self.write_inode(inode);
+        Ok(fblock)
+    }
 
-        Ok((iblock, fblock))
+    /// Map `count` new data blocks for an inode from logical block `iblock`
+    /// on, which must not be mapped yet.
+    ///
+    /// Unlike calling `inode_map_block` repeatedly, blocks are allocated
+    /// in contiguous runs, each extending the last extent when possible, so
+    /// the file gets as few extents as the free space allows.
+    pub(super) fn inode_map_blocks(
+        &self,
+        inode: &mut InodeRef,
+        iblock: LBlockId,
+        count: u32,
+    ) -> Result<()> {
+        self.check_block_count(inode, inode.inode.fs_block_count() + count as u64)?;
+        let mut iblock = iblock;
+        let mut remaining = count;
+        while remaining > 0 {
+            let (_, len) = self.extent_append(inode, iblock, remaining)?;
+            let block_count = inode.inode.fs_block_count() + len as u64;
+            inode.inode.set_fs_block_count(block_count);
+            self.write_inode(inode);
+            iblock += len;
+            remaining -= len;
+        }
+        Ok(())
+    }
+
+    /// Check that an inode may use `count` filesystem blocks. Without the
+    /// `huge_file` feature, its count of 512-byte blocks must fit in 32 bits.
+    fn check_block_count(&self, inode: &InodeRef, count: u64) -> Result<()> {
+        let sectors = count * (BLOCK_SIZE / INODE_BLOCK_SIZE) as u64;
+        if sectors > u32::MAX as u64
+            && !self
+                .read_super_block()
+                .features_read_only()
+                .contains(FeatureRoCompat::HUGE_FILE)
+        {
+            return_error!(
+                ErrCode::EFBIG,
+                "Inode {} cannot use {} blocks without huge_file",
+                inode.id,
+                count
+            );
+        }
+        Ok(())
+    }
+
+    /// Check that `count` blocks can be allocated, so that an operation
+    /// allocating several blocks fails before changing anything.
+    ///
+    /// # Error
+    ///
+    /// `ENOSPC` - fewer blocks are free, not counting the reserved blocks
+    /// without reserved access
+    pub(super) fn check_free_blocks(&self, count: u64) -> Result<()> {
+        let free = self.usable_blocks(&self.read_super_block());
+        if free < count {
+            return_error!(ErrCode::ENOSPC, "{} blocks needed, {} free", count, free);
+        }
+        Ok(())
     }
 
     /// Allocate a new physical block for an inode, return the physical block number
     pub(super) fn alloc_block(&self, inode: &mut InodeRef) -> Result<PBlockId> {
+        self.alloc_blocks_near(inode, None, 1)
+            .map(|(pblock, _)| pblock)
+    }
+
+    /// Allocate a new physical block for an inode, at `goal` if it is free,
+    /// see `alloc_blocks_near`.
+    pub(super) fn alloc_block_near(
+        &self,
+        inode: &mut InodeRef,
+        goal: Option<PBlockId>,
+    ) -> Result<PBlockId> {
+        self.alloc_blocks_near(inode, goal, 1)
+            .map(|(pblock, _)| pblock)
+    }
+
+    /// Allocate a run of at most `count` contiguous physical blocks for an
+    /// inode, see `alloc_blocks_near`.
+    pub(super) fn alloc_blocks(&self, inode: &mut InodeRef, count: u32) -> Result<(PBlockId, u32)> {
+        self.alloc_blocks_near(inode, None, count)
+    }
+
+    /// Allocate a run of at most `count` contiguous physical blocks for an
+    /// inode. Return the first physical block and the number of blocks
+    /// allocated, which is at least 1.
+    ///
+    /// `goal` is usually the block following the previous allocation of the
+    /// inode. If it is free, the run starts there, so that the extent before
+    /// it can be extended. Otherwise, and without a goal, the search starts
+    /// from the default goal of the inode, see `block_goal`: the first run
+    /// of `count` free blocks after the default goal is taken, or failing
+    /// that, the best fitting run of the group, see `best_fit_run`. Block
+    /// groups are searched starting from the group of the goal.
+    pub(super) fn alloc_blocks_near(
+        &self,
+        inode: &mut InodeRef,
+        goal: Option<PBlockId>,
+        count: u32,
+    ) -> Result<(PBlockId, u32)> {
         let mut sb = self.read_super_block();
 
-        // Calc block group id
-        let inodes_per_group = sb.inodes_per_group();
-        let bgid = ((inode.id - 1) / inodes_per_group) as BlockGroupId;
+        // Only privileged callers may use the reserved blocks
+        let mut count = count.max(1) as u64;
+        if !self.reserved_access {
+            let available = sb
+                .free_blocks_count()
+                .saturating_sub(sb.reserved_blocks_count());
+            if available == 0 {
+                return_error!(
+                    ErrCode::ENOSPC,
+                    "Only {} reserved blocks left",
+                    sb.free_blocks_count()
+                );
+            }
+            count = count.min(available);
+        }
 
-        // Load block group descriptor
-        let mut bg = self.read_block_group(bgid);
+        // Start from the group of the goal
+        let goal = goal
+            .filter(|&goal| goal >= sb.first_data_block() as PBlockId && goal < sb.block_count());
+        let hinted = goal.is_some();
+        let goal = goal.unwrap_or_else(|| self.block_goal(&sb, inode));
+        let bg_count = sb.block_group_count();
+        let start_bgid = ((goal - sb.first_data_block() as PBlockId)
+            / sb.blocks_per_group() as PBlockId) as BlockGroupId;
 
-        // Load block bitmap
-        let bitmap_block_id = bg.desc.block_bitmap_block();
-        let mut bitmap_block = self.read_block(bitmap_block_id);
-        let mut bitmap = Bitmap::new(&mut bitmap_block.data, 8 * BLOCK_SIZE);
+        for i in 0..bg_count {
+            let bgid = (start_bgid + i) % bg_count;
+            // Load block group descriptor
+            let mut bg = self.read_block_group(bgid);
+            // If there are no free blocks in this block group, try the next one
+            if bg.desc.get_free_blocks_count() == 0 {
+                continue;
+            }
 
-        // Find the first free block
-        let fblock = bitmap
-            .find_and_set_first_clear_bit(0, 8 * BLOCK_SIZE)
-            .ok_or(format_error!(
-                ErrCode::ENOSPC,
-                "No free blocks in block group {}",
-                bgid
-            ))? as PBlockId;
-        // Set block group checksum
-        bg.desc.set_block_bitmap_csum(&sb.uuid(), &bitmap);
-        self.write_block(&bitmap_block);
+            // Load block bitmap
+            let mut bitmap_block = self.load_block_bitmap(&sb, &mut bg);
+            let mut bitmap = Bitmap::new(&mut bitmap_block.data, sb.blocks_per_group() as usize);
+
+            // Take the goal block if free, or else a free run of the requested
+            // length after the goal, or else the best fitting free run
+            let end = sb.blocks_in_group(bgid) as usize;
+            let goal_bit = if i == 0 {
+                (goal - sb.group_first_block(bgid)) as usize
+            } else {
+                0
+            };
+            let start = if hinted && i == 0 && bitmap.is_bit_clear(goal_bit) {
+                Some(goal_bit)
+            } else {
+                bitmap
+                    .find_clear_run(goal_bit, end, count as usize)
+                    .or_else(|| best_fit_run(&bitmap, goal_bit, end, count as usize))
+            };
+            let Some(start) = start else {
+                continue;
+            };
+            let len = bitmap.clear_run_len(start, end).min(count as usize);
+            bitmap.set_bits(start, len);
+            // Set block group checksum
+            if sb.has_metadata_csum() {
+                bg.desc.set_block_bitmap_csum(sb.checksum_seed(), &bitmap);
+            }
+            self.write_block(&bitmap_block);
 
-        // Update block group counters
-        bg.desc
-            .set_free_blocks_count(bg.desc.get_free_blocks_count() - 1);
-        self.write_block_group_with_csum(&mut bg);
+            // Update block group counters
+            bg.desc
+                .set_free_blocks_count(bg.desc.get_free_blocks_count() - len as u64);
+            self.write_block_group(&mut bg);
 
-        // Update superblock counters
-        sb.set_free_blocks_count(sb.free_blocks_count() - 1);
-        self.write_super_block(&sb);
+            // Update superblock counters
+            sb.set_free_blocks_count(sb.free_blocks_count() - len as u64);
+            self.write_super_block(&sb);
 
-        trace!("Alloc block {} ok", fblock);
-        Ok(fblock)
+            let fblock = sb.group_first_block(bgid) + start as PBlockId;
+            for _ in 0..len {
+                self.stats.block_alloc();
+            }
+            self.reservation_take(len as u64);
+            log_alloc!(
+                trace,
+                "Alloc blocks {}..{} ok",
+                fblock,
+                fblock + len as PBlockId
+            );
+            return Ok((fblock, len as u32));
+        }
+        return_error!(ErrCode::ENOSPC, "No free blocks");
     }
 
     /// Deallocate a physical block allocated for an inode
     pub(super) fn dealloc_block(&self, inode: &mut InodeRef, pblock: PBlockId) -> Result<()> {
+        self.check_freeable(inode, pblock, 1)?;
+        self.dealloc_block_unchecked(inode, pblock)
+    }
+
+    /// Check that `count` contiguous blocks can be freed for an inode: they
+    /// are in range, and none of them is filesystem metadata.
+    fn check_freeable(&self, inode: &InodeRef, start: PBlockId, count: u64) -> Result<()> {
+        let sb = self.read_super_block();
+        if start < sb.first_data_block() as PBlockId || start + count > sb.block_count() {
+            let err = format_error!(
+                ErrCode::EINVAL,
+                "Blocks {}..{} are out of range",
+                start,
+                start + count
+            );
+            return Err(self.fs_error("dealloc_block", inode.id, start, err));
+        }
+        // Never free filesystem metadata
+        if self.overlaps_metadata(&sb, start..start + count) {
+            let err = format_error!(
+                ErrCode::EFSCORRUPTED,
+                "Blocks {}..{} of inode {} overlap filesystem metadata",
+                start,
+                start + count,
+                inode.id
+            );
+            return Err(self.fs_error("dealloc_block", inode.id, start, err));
+        }
+        Ok(())
+    }
+
+    /// Deallocate a physical block checked by `check_freeable`.
+    fn dealloc_block_unchecked(&self, inode: &mut InodeRef, pblock: PBlockId) -> Result<()> {
         let mut sb = self.read_super_block();
 
-        // Calc block group id
-        let inodes_per_group = sb.inodes_per_group();
-        let bgid = ((inode.id - 1) / inodes_per_group) as BlockGroupId;
+        // Calc block group id and index in block group
+        let blocks_per_group = sb.blocks_per_group() as PBlockId;
+        let bgid =
+            ((pblock - sb.first_data_block() as PBlockId) / blocks_per_group) as BlockGroupId;
+        let idx_in_bg = (pblock - sb.group_first_block(bgid)) as usize;
 
         // Load block group descriptor
         let mut bg = self.read_block_group(bgid);
 
         // Load block bitmap
-        let bitmap_block_id = bg.desc.block_bitmap_block();
-        let mut bitmap_block = self.read_block(bitmap_block_id);
-        let mut bitmap = Bitmap::new(&mut bitmap_block.data, 8 * BLOCK_SIZE);
+        let mut bitmap_block = self.load_block_bitmap(&sb, &mut bg);
+        let mut bitmap = Bitmap::new(&mut bitmap_block.data, blocks_per_group as usize);
 
         // Free the block
-        if bitmap.is_bit_clear(pblock as usize) {
-            return_error!(ErrCode::EINVAL, "Block {} is already free", pblock);
+        if bitmap.is_bit_clear(idx_in_bg) {
+            let err = format_error!(ErrCode::EINVAL, "Block {} is already free", pblock);
+            return Err(self.fs_error("dealloc_block", inode.id, pblock, err));
         }
-        bitmap.clear_bit(pblock as usize);
+        bitmap.clear_bit(idx_in_bg);
         // Set block group checksum
-        bg.desc.set_block_bitmap_csum(&sb.uuid(), &bitmap);
+        if sb.has_metadata_csum() {
+            bg.desc.set_block_bitmap_csum(sb.checksum_seed(), &bitmap);
+        }
         self.write_block(&bitmap_block);
 
         // Update block group counters
         bg.desc
             .set_free_blocks_count(bg.desc.get_free_blocks_count() + 1);
-        self.write_block_group_with_csum(&mut bg);
+        self.write_block_group(&mut bg);
 
         // Update superblock counters
         sb.set_free_blocks_count(sb.free_blocks_count() + 1);
         self.write_super_block(&sb);
 
-        trace!("Free block {} ok", pblock);
+        self.stats.block_free();
+        log_alloc!(trace, "Free block {} ok", pblock);
+        Ok(())
+    }
+
+    /// Deallocate `count` contiguous physical blocks allocated for an inode,
+    /// zero them if `zero_freed` says so, and discard them on the block
+    /// device if enabled
+    pub(super) fn dealloc_blocks(
+        &self,
+        inode: &mut InodeRef,
+        start: PBlockId,
+        count: u64,
+    ) -> Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        // Nothing is cleared before all blocks are known to be the inode's
+        // to free
+        self.check_freeable(inode, start, count)?;
+        for pblock in start..start + count {
+            self.dealloc_block_unchecked(inode, pblock)?;
+        }
+        if self.zero_freed(inode) {
+            for pblock in start..start + count {
+                self.write_block(&Block::new(pblock, [0; BLOCK_SIZE]));
+            }
+        }
+        if self.discard {
+            self.discard_blocks(start, count);
+        }
         Ok(())
     }
 
-    /// Allocate a new inode, returning the inode number.
-    fn alloc_inode(&self, is_dir: bool) -> Result<InodeId> {
+    /// Allocate a new inode under `parent`, returning the inode number.
+    ///
+    /// Block groups are searched starting from the goal group, see
+    /// `inode_goal_group`. Reserved inodes are never allocated.
+    fn alloc_inode(&self, parent: InodeId, is_dir: bool) -> Result<InodeId> {
         let mut sb = self.read_super_block();
         let bg_count = sb.block_group_count();
+        let start_bgid = self.inode_goal_group(&sb, parent, is_dir);
 
-        let mut bgid = 0;
-        while bgid <= bg_count {
+        for i in 0..bg_count {
+            let bgid = (start_bgid + i) % bg_count;
             // Load block group descriptor
             let mut bg = self.read_block_group(bgid);
             // If there are no free inodes in this block group, try the next one
             if bg.desc.free_inodes_count() == 0 {
-                bgid += 1;
                 continue;
             }
             // Load inode bitmap
-            let bitmap_block_id = bg.desc.inode_bitmap_block();
-            let mut bitmap_block = self.read_block(bitmap_block_id);
+            let mut bitmap_block = self.load_inode_bitmap(&sb, &mut bg);
             let inode_count = sb.inode_count_in_group(bgid) as usize;
             let mut bitmap = Bitmap::new(&mut bitmap_block.data, inode_count);
 
-            // Find a free inode
-            let idx_in_bg =
-                bitmap
-                    .find_and_set_first_clear_bit(0, inode_count)
-                    .ok_or(format_error!(
-                        ErrCode::ENOSPC,
-                        "No free inodes in block group {}",
-                        bgid
-                    ))? as u32;
+            // Find a free inode, never one of the reserved inodes below
+            // `s_first_ino`, whose bits may be clear
+            let first = sb
+                .first_inode()
+                .saturating_sub(1)
+                .saturating_sub(bgid * sb.inodes_per_group()) as usize;
+            let first = first.min(inode_count);
+            let Some(idx_in_bg) = bitmap.find_and_set_first_clear_bit(first, inode_count) else {
+                continue;
+            };
+            let idx_in_bg = idx_in_bg as u32;
             // Update bitmap in disk
-            bg.desc.set_inode_bitmap_csum(&sb.uuid(), &bitmap);
+            if sb.has_metadata_csum() {
+                bg.desc.set_inode_bitmap_csum(sb.checksum_seed(), &bitmap);
+            }
             self.write_block(&bitmap_block);
 
             // Modify block group counters
@@ -225,7 +534,7 @@ impl Ext4 {
                 unused = inode_count as u32 - (idx_in_bg + 1);
                 bg.desc.set_itable_unused(unused);
             }
-            self.write_block_group_with_csum(&mut bg);
+            self.write_block_group(&mut bg);
 
             // Update superblock counters
             sb.set_free_inodes_count(sb.free_inodes_count() - 1);
@@ -234,10 +543,66 @@ impl Ext4 {
             // Compute the absolute i-node number
             let inodes_per_group = sb.inodes_per_group();
             let inode_id = bgid * inodes_per_group + (idx_in_bg + 1);
+            self.stats.inode_alloc();
             return Ok(inode_id);
         }
-        trace!("no free inode");
-        return_error!(ErrCode::ENOSPC, "No free inodes in block group {}", bgid);
+        log_alloc!(trace, "no free inode");
+        return_error!(ErrCode::ENOSPC, "No free inodes");
+    }
+
+    /// Whether blocks freed from an inode are zeroed: with zero-on-free
+    /// enabled, see `set_zero_on_free`, or for `SECRM` inodes.
+    pub(super) fn zero_freed(&self, inode: &InodeRef) -> bool {
+        self.zero_on_free || inode.inode.user_flags().contains(InodeFlags::SECRM)
+    }
+
+    /// Check that an inode may be freed: it must be in range and not one
+    /// of the reserved inodes below `s_first_ino`, such as the root.
+    fn check_inode_freeable(&self, ino: InodeId) -> Result<()> {
+        let sb = self.read_super_block();
+        if ino == 0 || ino > sb.inode_count() {
+            let err = format_error!(ErrCode::EINVAL, "Inode {} is out of range", ino);
+            return Err(self.fs_error("free_inode", ino, 0, err));
+        }
+        if ino < sb.first_inode() {
+            let err = format_error!(ErrCode::EFSCORRUPTED, "Inode {} is reserved", ino);
+            return Err(self.fs_error("free_inode", ino, 0, err));
+        }
+        Ok(())
+    }
+
+    /// Whether any block of a non-empty `range` holds filesystem metadata:
+    /// the superblock, group descriptors, bitmaps or inode tables.
+    ///
+    /// With `flex_bg`, the bitmaps and inode tables of a flex group may be
+    /// placed in any of its groups, so all groups of the flex groups that
+    /// `range` spans are checked. Their descriptors are read once, so runs
+    /// of blocks are best checked at once.
+    fn overlaps_metadata(&self, sb: &SuperBlock, range: Range<PBlockId>) -> bool {
+        let overlaps = |meta: Range<PBlockId>| meta.start < range.end && range.start < meta.end;
+        let blocks_per_group = sb.blocks_per_group() as PBlockId;
+        let group_of = |pblock: PBlockId| {
+            ((pblock - sb.first_data_block() as PBlockId) / blocks_per_group) as BlockGroupId
+        };
+        let (first, last) = (group_of(range.start), group_of(range.end - 1));
+        let meta_blocks = (1 + sb.gdt_block_count() + sb.reserved_gdt_blocks()) as PBlockId;
+        let super_overlaps = (first..=last).any(|bgid| {
+            let start = sb.group_first_block(bgid);
+            sb.group_has_super(bgid) && overlaps(start..start + meta_blocks)
+        });
+        if super_overlaps {
+            return true;
+        }
+        let per_flex = sb.groups_per_flex();
+        let flex_first = first / per_flex * per_flex;
+        let flex_end = ((last / per_flex + 1) * per_flex).min(sb.block_group_count());
+        (flex_first..flex_end).any(|id| {
+            let desc = self.read_block_group(id).desc;
+            let itable = desc.inode_table_first_block();
+            overlaps(desc.block_bitmap_block()..desc.block_bitmap_block() + 1)
+                || overlaps(desc.inode_bitmap_block()..desc.inode_bitmap_block() + 1)
+                || overlaps(itable..itable + sb.inode_table_blocks() as PBlockId)
+        })
     }
 
     /// Free an inode
@@ -251,23 +616,25 @@ impl Ext4 {
         // Load block group descriptor
         let mut bg = self.read_block_group(bgid);
         // Load inode bitmap
-        let bitmap_block_id = bg.desc.inode_bitmap_block();
-        let mut bitmap_block = self.read_block(bitmap_block_id);
+        let mut bitmap_block = self.load_inode_bitmap(&sb, &mut bg);
         let inode_count = sb.inode_count_in_group(bgid) as usize;
         let mut bitmap = Bitmap::new(&mut bitmap_block.data, inode_count);
 
         // Free the inode
         if bitmap.is_bit_clear(idx_in_bg as usize) {
-            return_error!(
+            let err = format_error!(
                 ErrCode::EINVAL,
                 "Inode {} is already free in block group {}",
                 inode_ref.id,
                 bgid
             );
+            return Err(self.fs_error("dealloc_inode", inode_ref.id, 0, err));
         }
         bitmap.clear_bit(idx_in_bg as usize);
         // Update bitmap in disk
-        bg.desc.set_inode_bitmap_csum(&sb.uuid(), &bitmap);
+        if sb.has_metadata_csum() {
+            bg.desc.set_inode_bitmap_csum(sb.checksum_seed(), &bitmap);
+        }
         self.write_block(&bitmap_block);
 
         // Update block group counters
@@ -276,17 +643,252 @@ impl Ext4 {
         if inode_ref.inode.is_dir() {
             bg.desc.set_used_dirs_count(bg.desc.used_dirs_count() - 1);
         }
-        bg.desc.set_itable_unused(bg.desc.itable_unused() + 1);
-        self.write_block_group_with_csum(&mut bg);
+        // `itable_unused` is kept, inodes after this one may be in use
+        self.write_block_group(&mut bg);
 
         // Update superblock counters
         sb.set_free_inodes_count(sb.free_inodes_count() + 1);
         self.write_super_block(&sb);
 
-        // Clear inode content
+        // Clear inode content, keep the generation to bump it on reuse
+        let generation = inode_ref.inode.generation();
+        // SAFETY: `Inode` consists of integers only, all-zero is a valid value
         inode_ref.inode = unsafe { mem::zeroed() };
-        self.write_inode_without_csum(inode_ref);
+        inode_ref.inode.set_generation(generation);
+        self.write_inode(inode_ref);
 
         Ok(())
     }
+
+    /// Load the block bitmap of a block group.
+    ///
+    /// If the group is `BLOCK_UNINIT`, the initial bitmap is built in memory
+    /// with the group's metadata blocks marked as used, and the flag is
+    /// cleared in `bg`. Both are persisted when the caller writes them back.
+    pub(super) fn load_block_bitmap(&self, sb: &SuperBlock, bg: &mut BlockGroupRef) -> Block {
+        let bitmap_block_id = bg.desc.block_bitmap_block();
+        if !bg.desc.flags().contains(BlockGroupFlags::BLOCK_UNINIT) {
+            return self.read_block(bitmap_block_id);
+        }
+        let mut bitmap_block = Block::new(bitmap_block_id, [0; BLOCK_SIZE]);
+        let mut bitmap = Bitmap::new(&mut bitmap_block.data, 8 * BLOCK_SIZE);
+        // Bits are clusters with bigalloc
+        let ratio = sb.cluster_ratio() as usize;
+        // Superblock and group descriptor backups
+        if sb.group_has_super(bg.id) {
+            let meta_blocks = 1 + sb.gdt_block_count() + sb.reserved_gdt_blocks();
+            for i in 0..meta_blocks as usize {
+                bitmap.set_bit(i / ratio);
+            }
+        }
+        // Bitmaps and inode table, unless placed in another group (flex_bg)
+        let first = sb.group_first_block(bg.id);
+        let count = sb.blocks_in_group(bg.id) as PBlockId;
+        let itable = bg.desc.inode_table_first_block();
+        let metadata = [bg.desc.block_bitmap_block(), bg.desc.inode_bitmap_block()]
+            .into_iter()
+            .chain(itable..itable + sb.inode_table_blocks() as PBlockId);
+        for pblock in metadata {
+            if (first..first + count).contains(&pblock) {
+                bitmap.set_bit((pblock - first) as usize / ratio);
+            }
+        }
+        // Clusters past the end of the group
+        for i in sb.clusters_in_group(bg.id) as usize..8 * BLOCK_SIZE {
+            bitmap.set_bit(i);
+        }
+        bg.desc
+            .set_flags(bg.desc.flags() - BlockGroupFlags::BLOCK_UNINIT);
+        log_alloc!(debug, "Init block bitmap of block group {}", bg.id);
+        bitmap_block
+    }
+
+    /// Load the inode bitmap of a block group.
+    ///
+    /// If the group is `INODE_UNINIT`, an empty bitmap is built in memory,
+    /// the inode table is zeroed if it is not yet, and the flag is cleared
+    /// in `bg`. The bitmap and `bg` are persisted when the caller writes them
+    /// back.
+    fn load_inode_bitmap(&self, sb: &SuperBlock, bg: &mut BlockGroupRef) -> Block {
+        let bitmap_block_id = bg.desc.inode_bitmap_block();
+        if !bg.desc.flags().contains(BlockGroupFlags::INODE_UNINIT) {
+            return self.read_block(bitmap_block_id);
+        }
+        let mut bitmap_block = Block::new(bitmap_block_id, [0; BLOCK_SIZE]);
+        let mut bitmap = Bitmap::new(&mut bitmap_block.data, 8 * BLOCK_SIZE);
+        // Inodes past the end of the group
+        for i in sb.inodes_per_group() as usize..8 * BLOCK_SIZE {
+            bitmap.set_bit(i);
+        }
+        let mut flags = bg.desc.flags() - BlockGroupFlags::INODE_UNINIT;
+        if !flags.contains(BlockGroupFlags::ITABLE_ZEROED) {
+            let itable = bg.desc.inode_table_first_block();
+            for pblock in itable..itable + sb.inode_table_blocks() as PBlockId {
+                self.write_block(&Block::new(pblock, [0; BLOCK_SIZE]));
+            }
+            flags |= BlockGroupFlags::ITABLE_ZEROED;
+        }
+        bg.desc.set_flags(flags);
+        log_alloc!(debug, "Init inode bitmap of block group {}", bg.id);
+        bitmap_block
+    }
+
+    /// Pick the block group to start searching for a free inode.
+    ///
+    /// This is the Orlov allocator, working on flex groups (block groups if
+    /// `flex_bg` is disabled):
+    ///
+    /// * Files are placed in the group of their parent.
+    /// * Top-level directories, in the root or in a `TOPDIR` directory, are
+    ///   spread: among the flex groups with at least the average number of
+    ///   free inodes and nearly the average number of free blocks, the one
+    ///   with the fewest directories is chosen, the first after the
+    ///   parent's on ties. Superblock backups and the journal take blocks
+    ///   from some groups only, hence the slack on free blocks.
+    /// * Other directories stay in the flex group of their parent, so that
+    ///   a hierarchy is kept together, unless it holds too many directories
+    ///   or too few free inodes or blocks compared to the average. The next
+    ///   flex group that does not is chosen then.
+    fn inode_goal_group(&self, sb: &SuperBlock, parent: InodeId, is_dir: bool) -> BlockGroupId {
+        let bg_count = sb.block_group_count();
+        let parent_bgid = ((parent - 1) / sb.inodes_per_group()) % bg_count;
+        if !is_dir {
+            return parent_bgid;
+        }
+        let per_flex = sb.groups_per_flex();
+        let flex_count = bg_count.div_ceil(per_flex);
+        let parent_flex = parent_bgid / per_flex;
+        // (free inodes, free clusters, directories) of each flex group
+        let stats: Vec<(u32, u64, u32)> = (0..flex_count)
+            .map(|flex| {
+                let first = flex * per_flex;
+                (first..(first + per_flex).min(bg_count)).fold((0, 0, 0), |acc, bgid| {
+                    let bg = self.read_block_group(bgid);
+                    (
+                        acc.0 + bg.desc.free_inodes_count(),
+                        acc.1 + bg.desc.get_free_blocks_count(),
+                        acc.2 + bg.desc.used_dirs_count(),
+                    )
+                })
+            })
+            .collect();
+        let avg_free_inodes = sb.free_inodes_count() / flex_count;
+        let avg_free_blocks = stats.iter().map(|s| s.1).sum::<u64>() / flex_count as u64;
+        let avg_dirs = stats.iter().map(|s| s.2).sum::<u32>() / flex_count;
+        // Flex groups in search order, starting from `start`
+        let flexes = |start: u32| (0..flex_count).map(move |i| (start + i) % flex_count);
+
+        let top_dir = parent == EXT4_ROOT_INO
+            || self
+                .read_inode(parent)
+                .inode
+                .user_flags()
+                .contains(InodeFlags::TOPDIR);
+        let min_blocks =
+            avg_free_blocks.saturating_sub(sb.clusters_per_group() as u64 * per_flex as u64 / 4);
+        let chosen = if top_dir {
+            // Start after the parent, so that ties move away from it
+            flexes(parent_flex + 1)
+                .filter(|&flex| {
+                    let (free_inodes, free_blocks, _) = stats[flex as usize];
+                    free_inodes > 0
+                        && free_inodes >= avg_free_inodes
+                        && free_blocks > 0
+                        && free_blocks >= min_blocks
+                })
+                // The first one on ties
+                .min_by_key(|&flex| stats[flex as usize].2)
+        } else {
+            let max_dirs = avg_dirs + sb.inodes_per_group() * per_flex / 16;
+            let min_inodes = avg_free_inodes.saturating_sub(sb.inodes_per_group() * per_flex / 4);
+            flexes(parent_flex).find(|&flex| {
+                let (free_inodes, free_blocks, dirs) = stats[flex as usize];
+                dirs < max_dirs
+                    && free_inodes > 0
+                    && free_inodes >= min_inodes
+                    && free_blocks > 0
+                    && free_blocks >= min_blocks
+            })
+        };
+        match chosen {
+            Some(flex) if flex == parent_flex => parent_bgid,
+            Some(flex) => flex * per_flex,
+            // Groups are then searched in order from the parent's
+            None => parent_bgid,
+        }
+    }
+
+    /// The default goal block of an inode. Groups are divided in
+    /// `BLOCK_GOAL_SLOTS` slots, and the goal is the start of a slot picked
+    /// by inode number in the goal group, so that files written at the same
+    /// time grow in different parts of the group instead of interleaving.
+    fn block_goal(&self, sb: &SuperBlock, inode: &InodeRef) -> PBlockId {
+        let bgid = self.shard_group(sb, self.block_goal_group(sb, inode));
+        let slot_size = sb.blocks_in_group(bgid) as PBlockId / BLOCK_GOAL_SLOTS;
+        let slot = inode.id as PBlockId % BLOCK_GOAL_SLOTS;
+        sb.group_first_block(bgid) + slot * slot_size
+    }
+
+    /// Map a goal group into the groups of the caller's allocation shard,
+    /// if sharding is enabled. The groups are split in as many contiguous
+    /// ranges as there are shards.
+    fn shard_group(&self, sb: &SuperBlock, bgid: BlockGroupId) -> BlockGroupId {
+        let Some(shards) = self.alloc_shards else {
+            return bgid;
+        };
+        let bg_count = sb.block_group_count() as u64;
+        let count = (shards.count as u64).min(bg_count);
+        let shard = (shards.current)() as u64 % count;
+        let first = shard * bg_count / count;
+        let len = (shard + 1) * bg_count / count - first;
+        (first + bgid as u64 % len) as BlockGroupId
+    }
+
+    /// Pick the block group to start searching for a free block for an inode.
+    ///
+    /// Blocks are kept in the flex group of the inode. With large enough flex
+    /// groups, directories start from the first group, which also holds the
+    /// bitmaps and inode tables of the flex group, and other files from the
+    /// second one.
+    fn block_goal_group(&self, sb: &SuperBlock, inode: &InodeRef) -> BlockGroupId {
+        let bg_count = sb.block_group_count();
+        let mut bgid = ((inode.id - 1) / sb.inodes_per_group()) % bg_count;
+        let per_flex = sb.groups_per_flex();
+        if per_flex >= 4 {
+            bgid &= !(per_flex - 1);
+            if !inode.inode.is_dir() && bgid + 1 < bg_count {
+                bgid += 1;
+            }
+        }
+        bgid
+    }
+}
+
+/// Find the free run in `[0, end)` that best fits `len` bits: the shortest
+/// run of at least `len` bits, or if there is none, the longest run. Among
+/// equally long runs, the one closest to `goal` wins.
+fn best_fit_run(bitmap: &Bitmap, goal: usize, end: usize, len: usize) -> Option<usize> {
+    let mut best: Option<(usize, usize)> = None;
+    let mut bit = 0;
+    while let Some(start) = bitmap.first_clear_bit(bit, end) {
+        let run_len = bitmap.clear_run_len(start, end);
+        bit = start + run_len;
+        let better = match best {
+            None => true,
+            Some((best_start, best_len)) => {
+                let (fits, best_fits) = (run_len >= len, best_len >= len);
+                if fits != best_fits {
+                    fits
+                } else if run_len != best_len {
+                    (run_len < best_len) == fits
+                } else {
+                    start.abs_diff(goal) < best_start.abs_diff(goal)
+                }
+            }
+        };
+        if better {
+            best = Some((start, run_len));
+        }
+    }
+    best.map(|(start, _)| start)
 }
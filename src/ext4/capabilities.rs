@@ -0,0 +1,215 @@
+//! Description of what this build of the crate supports.
+
+use super::Ext4;
+use crate::constants::*;
+use crate::ext4_defs::*;
+
+/// Cargo features compiled into this build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompiledFeatures {
+    /// `block_cache`: write-back block cache
+    pub block_cache: bool,
+    /// `fuser_root_inode`: root inode number is 1 instead of 2
+    pub fuser_root_inode: bool,
+    /// `casefold`: case-insensitive directories
+    pub casefold: bool,
+    /// `stats`: instrumentation counters
+    pub stats: bool,
+    /// `mem_device`: in-memory and copy-on-write block devices
+    pub mem_device: bool,
+    /// `std`: file-backed block device
+    pub std: bool,
+    /// `tar`: tar archive import and export
+    pub tar: bool,
+}
+
+/// The feature sets of a loaded image, returned by `Ext4::features`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageFeatures {
+    /// Compatible features
+    pub compat: FeatureCompat,
+    /// Incompatible features
+    pub incompat: FeatureIncompat,
+    /// Readonly-compatible features
+    pub ro_compat: FeatureRoCompat,
+}
+
+impl ImageFeatures {
+    fn of(sb: &SuperBlock) -> Self {
+        Self {
+            compat: sb.features_compatible(),
+            incompat: sb.features_incompatible(),
+            ro_compat: sb.features_read_only(),
+        }
+    }
+
+    /// Whether the image has a journal (`has_journal`).
+    pub fn has_journal(&self) -> bool {
+        self.compat.contains(FeatureCompat::HAS_JOURNAL)
+    }
+
+    /// Whether directories may have hash tree indexes (`dir_index`).
+    pub fn has_dir_index(&self) -> bool {
+        self.compat.contains(FeatureCompat::DIR_INDEX)
+    }
+
+    /// Whether directory entries record the file type (`filetype`).
+    pub fn has_filetype(&self) -> bool {
+        self.incompat.contains(FeatureIncompat::FILETYPE)
+    }
+
+    /// Whether files map their blocks with extent trees (`extents`).
+    pub fn has_extents(&self) -> bool {
+        self.incompat.contains(FeatureIncompat::EXTENTS)
+    }
+
+    /// Whether block addresses may be wider than 32 bits (`64bit`).
+    pub fn has_64bit(&self) -> bool {
+        self.incompat.contains(FeatureIncompat::BIT64)
+    }
+
+    /// Whether group metadata may be placed in other groups (`flex_bg`).
+    pub fn has_flex_bg(&self) -> bool {
+        self.incompat.contains(FeatureIncompat::FLEX_BG)
+    }
+
+    /// Whether files may be encrypted (`encrypt`).
+    pub fn has_encrypt(&self) -> bool {
+        self.incompat.contains(FeatureIncompat::ENCRYPT)
+    }
+
+    /// Whether directories may be case-insensitive (`casefold`).
+    pub fn has_casefold(&self) -> bool {
+        self.incompat.contains(FeatureIncompat::CASEFOLD)
+    }
+
+    /// Whether files may be larger than 2 GiB (`large_file`).
+    pub fn has_large_file(&self) -> bool {
+        self.ro_compat.contains(FeatureRoCompat::LARGE_FILE)
+    }
+
+    /// Whether blocks are allocated in clusters (`bigalloc`).
+    pub fn has_bigalloc(&self) -> bool {
+        self.ro_compat.contains(FeatureRoCompat::BIGALLOC)
+    }
+
+    /// Whether metadata blocks carry checksums (`metadata_csum`).
+    pub fn has_metadata_csum(&self) -> bool {
+        self.ro_compat.contains(FeatureRoCompat::METADATA_CSUM)
+    }
+
+    /// Whether quotas are tracked in hidden quota inodes (`quota`).
+    pub fn has_quota(&self) -> bool {
+        self.ro_compat.contains(FeatureRoCompat::QUOTA)
+    }
+
+    /// Whether files may be sealed by fs-verity (`verity`).
+    pub fn has_verity(&self) -> bool {
+        self.ro_compat.contains(FeatureRoCompat::VERITY)
+    }
+}
+
+/// Features of a loaded image that this build does not support, returned
+/// by `Ext4::unsupported_features`. An image with any of them is loaded
+/// read-only, so that files can still be extracted from it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UnsupportedFeatures {
+    /// Incompatible features. Reading is best effort: files using them may
+    /// read wrong
+    pub incompat: FeatureIncompat,
+    /// Readonly-compatible features, which only writing has to understand
+    pub ro_compat: FeatureRoCompat,
+}
+
+impl UnsupportedFeatures {
+    /// The features of `sb` missing from `Ext4::capabilities`. A journal
+    /// in need of recovery is not counted.
+    pub(super) fn of(sb: &SuperBlock) -> Self {
+        let caps = Ext4::capabilities();
+        Self {
+            incompat: sb.features_incompatible() - caps.incompat - FeatureIncompat::RECOVER,
+            ro_compat: sb.features_read_only() - caps.ro_compat,
+        }
+    }
+
+    /// Whether the image uses no unsupported feature.
+    pub fn is_empty(&self) -> bool {
+        self.incompat.is_empty() && self.ro_compat.is_empty()
+    }
+}
+
+/// Capabilities of this build, returned by `Ext4::capabilities`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Crate version
+    pub version: &'static str,
+    /// Cargo features compiled in
+    pub features: CompiledFeatures,
+    /// Compatible features that are understood and maintained
+    pub compat: FeatureCompat,
+    /// Incompatible features that are supported
+    pub incompat: FeatureIncompat,
+    /// Readonly-compatible features that are supported for writing
+    pub ro_compat: FeatureRoCompat,
+    /// Supported filesystem block sizes in bytes
+    pub block_sizes: &'static [usize],
+    /// Preferred on-disk inode size in bytes. Images with 128-byte ext2
+    /// inodes are supported as well
+    pub inode_size: usize,
+    /// Maximum file size in bytes
+    pub max_file_size: u64,
+    /// Maximum length of a file name in bytes
+    pub max_name_len: usize,
+}
+
+impl Ext4 {
+    /// Describe what this build of the crate supports.
+    pub fn capabilities() -> Capabilities {
+        let mut incompat = FeatureIncompat::FILETYPE
+            | FeatureIncompat::EXTENTS
+            | FeatureIncompat::BIT64
+            | FeatureIncompat::FLEX_BG
+            | FeatureIncompat::CSUM_SEED
+            | FeatureIncompat::ENCRYPT;
+        if cfg!(feature = "casefold") {
+            incompat |= FeatureIncompat::CASEFOLD;
+        }
+        Capabilities {
+            version: env!("CARGO_PKG_VERSION"),
+            features: CompiledFeatures {
+                block_cache: cfg!(feature = "block_cache"),
+                fuser_root_inode: cfg!(feature = "fuser_root_inode"),
+                casefold: cfg!(feature = "casefold"),
+                stats: cfg!(feature = "stats"),
+                mem_device: cfg!(feature = "mem_device"),
+                std: cfg!(feature = "std"),
+                tar: cfg!(feature = "tar"),
+            },
+            compat: FeatureCompat::EXT_ATTR,
+            incompat,
+            ro_compat: FeatureRoCompat::SPARSE_SUPER
+                | FeatureRoCompat::LARGE_FILE
+                | FeatureRoCompat::HUGE_FILE
+                | FeatureRoCompat::DIR_NLINK
+                | FeatureRoCompat::EXTRA_ISIZE
+                | FeatureRoCompat::QUOTA
+                | FeatureRoCompat::METADATA_CSUM
+                | FeatureRoCompat::VERITY,
+            block_sizes: &[BLOCK_SIZE],
+            inode_size: SB_GOOD_INODE_SIZE,
+            max_file_size: MAX_FILE_SIZE,
+            max_name_len: NAME_MAX,
+        }
+    }
+
+    /// Get the features of the loaded image.
+    pub fn features(&self) -> ImageFeatures {
+        ImageFeatures::of(&self.read_super_block())
+    }
+
+    /// Get the features of the image that this build does not support. If
+    /// there are any, the filesystem is read-only.
+    pub fn unsupported_features(&self) -> UnsupportedFeatures {
+        self.unsupported
+    }
+}
@@ -0,0 +1,263 @@
+//! Awareness of file-based encryption (fscrypt, the `encrypt` feature).
+//!
+//! Encrypted inodes carry the `ENCRYPT` flag and an encryption context,
+//! stored as the extended attribute "c" of index 9, that names the master
+//! key and the encryption modes. File contents, symlink targets and the
+//! entry names of encrypted directories are ciphertext on disk.
+//!
+//! Nothing is ever encrypted by this crate. Without a `CryptoProvider`,
+//! reading an encrypted file or symlink fails with `ENOKEY`, and the names
+//! in encrypted directories are listed and looked up as raw ciphertext.
+//! With one, contents and names are decrypted as they are read. Either
+//! way, encrypted files cannot be written or truncated, and entries cannot
+//! be added to or removed from encrypted directories.
+
+use super::Ext4;
+use crate::constants::*;
+use crate::ext4_defs::*;
+use crate::format_error;
+use crate::prelude::*;
+use crate::return_error;
+
+/// The xattr name index of encryption contexts.
+const XATTR_INDEX_ENCRYPTION: u8 = 9;
+/// The xattr name of encryption contexts.
+const XATTR_NAME_CONTEXT: &[u8] = b"c";
+
+/// The encryption policy of an inode, read from its encryption context and
+/// acquired by `Ext4::encryption_policy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptionPolicy {
+    /// Policy version, 1 or 2
+    pub version: u8,
+    /// Contents encryption mode, such as 1 for AES-256-XTS
+    pub contents_mode: u8,
+    /// Filenames encryption mode, such as 4 for AES-256-CTS
+    pub filenames_mode: u8,
+    /// Policy flags, such as the padding of filenames
+    pub flags: u8,
+    /// Master key descriptor (8 bytes, v1) or identifier (16 bytes, v2)
+    pub master_key: Vec<u8>,
+    /// Nonce the key of the inode is derived from
+    pub nonce: [u8; 16],
+}
+
+impl EncryptionPolicy {
+    /// Parse an encryption context, `None` if it is malformed.
+    fn from_context(context: &[u8]) -> Option<Self> {
+        // Key and nonce positions of each context version
+        let (key, nonce) = match context.first()? {
+            1 => (4..12, 12..28),
+            2 => (8..24, 24..40),
+            _ => return None,
+        };
+        if context.len() != nonce.end {
+            return None;
+        }
+        Some(Self {
+            version: context[0],
+            contents_mode: context[1],
+            filenames_mode: context[2],
+            flags: context[3],
+            master_key: context[key].to_vec(),
+            nonce: context[nonce].try_into().unwrap(),
+        })
+    }
+}
+
+/// Decryption hooks, installed with `Ext4::set_crypto_provider`. The
+/// provider holds the master keys and derives the keys of inodes from
+/// their policy.
+///
+/// Both methods fail with `ENOKEY` if the master key of the policy is not
+/// available.
+pub trait CryptoProvider: Send + Sync {
+    /// Decrypt in place the logical block `iblock` of the contents of
+    /// `inode`. Holes are not passed, they read as zeros.
+    fn decrypt_block(
+        &self,
+        policy: &EncryptionPolicy,
+        inode: InodeId,
+        iblock: LBlockId,
+        data: &mut [u8],
+    ) -> Result<()>;
+
+    /// Decrypt an entry name of the directory `inode`, or the target of
+    /// the symlink `inode`.
+    fn decrypt_name(
+        &self,
+        policy: &EncryptionPolicy,
+        inode: InodeId,
+        name: &[u8],
+    ) -> Result<Vec<u8>>;
+}
+
+/// The policy of an encrypted inode and the provider to decrypt it with.
+pub(super) struct Decryptor {
+    policy: EncryptionPolicy,
+    provider: Arc<dyn CryptoProvider>,
+}
+
+impl Decryptor {
+    /// Decrypt a block of the contents of `inode` in place.
+    pub(super) fn decrypt_block(
+        &self,
+        inode: InodeId,
+        iblock: LBlockId,
+        block: &mut Block,
+    ) -> Result<()> {
+        self.provider
+            .decrypt_block(&self.policy, inode, iblock, &mut block.data)
+    }
+
+    /// Decrypt a name of `inode`.
+    pub(super) fn decrypt_name(&self, inode: InodeId, name: &[u8]) -> Result<Vec<u8>> {
+        let name = self.provider.decrypt_name(&self.policy, inode, name)?;
+        if name.len() > NAME_MAX {
+            return_error!(
+                ErrCode::ENAMETOOLONG,
+                "Decrypted name of inode {} too long: {} bytes",
+                inode,
+                name.len()
+            );
+        }
+        Ok(name)
+    }
+}
+
+impl Ext4 {
+    /// Set the provider used to decrypt encrypted files and names. `None`
+    /// removes it, encrypted files and symlinks then cannot be read.
+    pub fn set_crypto_provider(&mut self, provider: Option<Arc<dyn CryptoProvider>>) {
+        self.crypto = provider;
+    }
+
+    /// Get the encryption policy of an inode, `None` if it is not
+    /// encrypted.
+    ///
+    /// # Error
+    ///
+    /// * `EINVAL` - the inode is invalid (mode == 0)
+    /// * `EFSCORRUPTED` - the inode is encrypted, but has no valid
+    ///   encryption context
+    pub fn encryption_policy(&self, inode: InodeId) -> Result<Option<EncryptionPolicy>> {
+        let inode_ref = self.read_inode(inode);
+        if inode_ref.inode.mode().bits() == 0 {
+            return_error!(ErrCode::EINVAL, "Invalid inode {}", inode);
+        }
+        self.inode_policy(&inode_ref)
+    }
+
+    /// Check that an inode is not encrypted before writing its contents,
+    /// or adding or removing entries of a directory, which would need the
+    /// key to encrypt.
+    ///
+    /// # Error
+    ///
+    /// `ENOKEY` if the inode is encrypted.
+    pub(super) fn check_unencrypted(inode: &InodeRef) -> Result<()> {
+        if inode.inode.is_encrypted() {
+            return_error!(
+                ErrCode::ENOKEY,
+                "Inode {} is encrypted and cannot be modified",
+                inode.id
+            );
+        }
+        Ok(())
+    }
+
+    /// Get the decryptor of the contents of an inode, `None` if it is not
+    /// encrypted.
+    ///
+    /// # Error
+    ///
+    /// `ENOKEY` if the inode is encrypted and no crypto provider is set.
+    pub(super) fn decryptor(&self, inode: &InodeRef) -> Result<Option<Decryptor>> {
+        let Some(policy) = self.inode_policy(inode)? else {
+            return Ok(None);
+        };
+        match &self.crypto {
+            Some(provider) => Ok(Some(Decryptor {
+                policy,
+                provider: provider.clone(),
+            })),
+            None => Err(format_error!(
+                ErrCode::ENOKEY,
+                "Inode {} is encrypted and no crypto provider is set",
+                inode.id
+            )),
+        }
+    }
+
+    /// Get the decryptor of the entry names of a directory, `None` if the
+    /// names are kept as they are: the directory is not encrypted, or no
+    /// crypto provider is set.
+    pub(super) fn name_decryptor(&self, dir: &InodeRef) -> Result<Option<Decryptor>> {
+        if self.crypto.is_none() {
+            return Ok(None);
+        }
+        self.decryptor(dir)
+    }
+
+    /// Decrypt the raw target of an encrypted symlink, a 2-byte length
+    /// followed by the encrypted name.
+    pub(super) fn decrypt_symlink(&self, link: &InodeRef, raw: &[u8]) -> Result<Vec<u8>> {
+        let decryptor = self.decryptor(link)?.unwrap();
+        let len = match raw.get(..2) {
+            Some(len) => u16::from_le_bytes(len.try_into().unwrap()) as usize,
+            None => 0,
+        };
+        match raw.get(2..2 + len) {
+            Some(name) if len > 0 => decryptor.decrypt_name(link.id, name),
+            _ => Err(format_error!(
+                ErrCode::EFSCORRUPTED,
+                "Invalid encrypted symlink target of inode {}",
+                link.id
+            )),
+        }
+    }
+
+    /// Get the encryption policy of an inode, `None` if it is not
+    /// encrypted.
+    fn inode_policy(&self, inode: &InodeRef) -> Result<Option<EncryptionPolicy>> {
+        if !inode.inode.is_encrypted() {
+            return Ok(None);
+        }
+        let context = self.read_encryption_context(inode);
+        match context.as_deref().and_then(EncryptionPolicy::from_context) {
+            Some(policy) => Ok(Some(policy)),
+            None => {
+                let err = format_error!(
+                    ErrCode::EFSCORRUPTED,
+                    "Inode {} has no valid encryption context",
+                    inode.id
+                );
+                Err(self.fs_error("inode_policy", inode.id, 0, err))
+            }
+        }
+    }
+
+    /// Read the encryption context of an inode, usually stored in the
+    /// inode, otherwise in its xattr block.
+    fn read_encryption_context(&self, inode: &InodeRef) -> Option<Vec<u8>> {
+        let context = self.with_ibody(inode, |record, extra_isize| {
+            find_ibody_xattr(
+                record,
+                extra_isize,
+                XATTR_INDEX_ENCRYPTION,
+                XATTR_NAME_CONTEXT,
+            )
+            .map(<[u8]>::to_vec)
+        });
+        if let Some(context) = context.flatten() {
+            return Some(context);
+        }
+        let xattr_block_id = inode.inode.xattr_block();
+        if xattr_block_id == 0 {
+            return None;
+        }
+        XattrBlock::new(self.read_block(xattr_block_id))
+            .get_raw(XATTR_INDEX_ENCRYPTION, XATTR_NAME_CONTEXT)
+            .map(<[u8]>::to_vec)
+    }
+}
@@ -0,0 +1,175 @@
+//! Defragmentation.
+//!
+//! A file grown one block at a time may end up with many single-block
+//! extents. `Ext4::defragment` moves the data of a file into as few
+//! contiguous runs as the free space allows and rebuilds its extent tree.
+
+use super::perm::Access;
+use super::Ext4;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+use crate::return_error;
+
+/// Fragmentation of a file, acquired by `Ext4::fragmentation`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragReport {
+    /// The number of extents mapping the file
+    pub extent_count: u32,
+    /// The number of blocks mapped by the extents
+    pub block_count: u64,
+}
+
+impl FragReport {
+    /// The average extent length in blocks, rounded down. 0 if the file
+    /// has no extents.
+    pub fn avg_extent_len(&self) -> u64 {
+        self.block_count
+            .checked_div(self.extent_count as u64)
+            .unwrap_or(0)
+    }
+
+    fn new(extents: &[Extent]) -> Self {
+        Self {
+            extent_count: extents.len() as u32,
+            block_count: extents.iter().map(|ex| ex.block_count() as u64).sum(),
+        }
+    }
+}
+
+impl Ext4 {
+    /// Get the fragmentation report of a file.
+    ///
+    /// # Error
+    ///
+    /// `EINVAL` if the inode does not use extents.
+    pub fn fragmentation(&self, inode: InodeId) -> Result<FragReport> {
+        let inode_ref = self.read_inode(inode);
+        if !inode_ref.inode.uses_extents() {
+            return_error!(ErrCode::EINVAL, "Inode {} does not use extents", inode);
+        }
+        Ok(FragReport::new(&self.extent_all_extents(&inode_ref)?))
+    }
+
+    /// Rewrite a regular file into as few extents as possible.
+    ///
+    /// Contiguous runs are allocated for the whole file and the data is
+    /// copied before the extent tree is replaced, then the old blocks are
+    /// freed. If the free space is too fragmented to reduce the number of
+    /// extents, the file is left untouched.
+    ///
+    /// # Return
+    ///
+    /// `Ok(FragReport)` - the fragmentation of the file afterwards
+    ///
+    /// # Error
+    ///
+    /// * `EINVAL` - the inode is not a regular file using extents, or it
+    ///   has holes or preallocated extents
+    /// * `EACCES` - no write permission
+    /// * `ENOSPC` - not enough free space for a second copy of the file
+    /// * `EBUSY` - a block of the file is pinned by `pin_block`
+    pub fn defragment(&self, inode: InodeId) -> Result<FragReport> {
+        self.check_writable()?;
+        let mut inode_ref = self.read_inode(inode);
+        if !inode_ref.inode.is_file() || !inode_ref.inode.uses_extents() {
+            return_error!(
+                ErrCode::EINVAL,
+                "Inode {} is not an extent-mapped file",
+                inode
+            );
+        }
+        self.check_access(&inode_ref, Access::WRITE)?;
+        self.check_unpinned(inode, 0)?;
+
+        let old_extents = self.extent_all_extents(&inode_ref)?;
+        // Only files fully mapped by written extents are supported
+        let mut block_count: LBlockId = 0;
+        for ex in &old_extents {
+            if ex.is_unwritten() || ex.start_lblock() != block_count {
+                return_error!(
+                    ErrCode::EINVAL,
+                    "Inode {} has holes or preallocated extents",
+                    inode
+                );
+            }
+            block_count += ex.block_count();
+        }
+        if old_extents.len() <= 1 {
+            return Ok(FragReport::new(&old_extents));
+        }
+
+        // Allocate new runs for the whole file
+        let mut new_extents: Vec<Extent> = Vec::new();
+        let mut lblock = 0;
+        while lblock < block_count {
+            let count = (block_count - lblock).min(Extent::INIT_MAX_LEN as u32);
+            let (pblock, len) = match self.alloc_blocks(&mut inode_ref, count) {
+                Ok(run) => run,
+                Err(e) => {
+                    self.defrag_free_extents(&mut inode_ref, &new_extents)?;
+                    return Err(e);
+                }
+            };
+            let ex = Extent::new(lblock, pblock, len as u16);
+            match new_extents.last_mut() {
+                Some(last) if Extent::can_append(last, &ex) => {
+                    last.set_block_count(last.block_count() + len);
+                }
+                _ => new_extents.push(ex),
+            }
+            lblock += len;
+        }
+        if new_extents.len() >= old_extents.len() {
+            // No improvement, keep the old layout
+            self.defrag_free_extents(&mut inode_ref, &new_extents)?;
+            return Ok(FragReport::new(&old_extents));
+        }
+
+        // Copy data to the new blocks
+        let pblocks = |extents: &[Extent]| -> Vec<PBlockId> {
+            extents
+                .iter()
+                .flat_map(|ex| (0..ex.block_count() as PBlockId).map(|i| ex.start_pblock() + i))
+                .collect()
+        };
+        for (src, dst) in pblocks(&old_extents).into_iter().zip(pblocks(&new_extents)) {
+            self.write_block(&Block::new(dst, self.read_block(src).data));
+        }
+
+        // Replace the extent tree
+        let old_tree_blocks = self.extent_all_tree_blocks(&inode_ref)?;
+        self.es_invalidate(inode_ref.id);
+        // The blocks of the new tree are counted as they are allocated
+        let block_count = inode_ref.inode.fs_block_count();
+        inode_ref
+            .inode
+            .set_fs_block_count(block_count.saturating_sub(old_tree_blocks.len() as u64));
+        inode_ref.inode.extent_root_mut().init(0, 0);
+        for ex in &new_extents {
+            self.extent_insert(&mut inode_ref, ex)?;
+        }
+        self.write_inode(&mut inode_ref);
+
+        // Free the old blocks
+        self.defrag_free_extents(&mut inode_ref, &old_extents)?;
+        for pblock in old_tree_blocks {
+            self.dealloc_blocks(&mut inode_ref, pblock, 1)?;
+        }
+        log_extent!(
+            debug,
+            "Defragment inode {}: {} -> {} extents",
+            inode,
+            old_extents.len(),
+            new_extents.len()
+        );
+        Ok(FragReport::new(&new_extents))
+    }
+
+    /// Free the blocks of extents that are not in the extent tree.
+    fn defrag_free_extents(&self, inode_ref: &mut InodeRef, extents: &[Extent]) -> Result<()> {
+        for ex in extents {
+            self.dealloc_blocks(inode_ref, ex.start_pblock(), ex.block_count() as u64)?;
+        }
+        Ok(())
+    }
+}
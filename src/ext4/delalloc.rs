@@ -0,0 +1,150 @@
+//! Delayed allocation.
+//!
+//! `Ext4::write` allocates blocks one by one as a file grows, so a file
+//! written in small pieces while other files grow gets scattered short
+//! extents. A
+//! `DelayedWriter` buffers sequential writes per inode and allocates the
+//! blocks of the whole buffered range at flush time, letting the allocator
+//! hand out long contiguous runs that map to few extents.
+//!
+//! Buffered data is not visible to `Ext4::read` and other operations until
+//! it is flushed. Flush the writer before touching the same inodes through
+//! other interfaces, e.g. reading, truncating or removing them.
+
+use super::perm::Access;
+use super::Ext4;
+use crate::constants::*;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+use crate::return_error;
+use core::cmp::max;
+
+/// A sequential write waiting for allocation.
+struct PendingWrite {
+    /// File offset of the first buffered byte
+    offset: u64,
+    /// Buffered data
+    data: Vec<u8>,
+}
+
+/// A write-back buffer with delayed allocation, acquired by
+/// `Ext4::delayed_writer`.
+///
+/// Pending writes are flushed when the writer is dropped. Errors at that
+/// point can only be logged, call `flush` to handle them.
+pub struct DelayedWriter<'a> {
+    fs: &'a Ext4,
+    /// Pending writes of each inode
+    pending: BTreeMap<InodeId, PendingWrite>,
+    /// Maximum number of bytes buffered per inode
+    limit: usize,
+}
+
+impl Ext4 {
+    /// Create a writer that buffers sequential writes and defers block
+    /// allocation until flush. At most `DELALLOC_LIMIT` bytes are buffered
+    /// per inode.
+    pub fn delayed_writer(&self) -> DelayedWriter<'_> {
+        DelayedWriter {
+            fs: self,
+            pending: BTreeMap::new(),
+            limit: DELALLOC_LIMIT,
+        }
+    }
+
+    /// Write buffered data to a regular file, allocating all new blocks
+    /// before writing.
+    fn write_delayed_data(&self, file: &mut InodeRef, offset: u64, data: &[u8]) -> Result<()> {
+        // Same block range as `write_inode_data` maps, past the last
+        // mapped block so that the new blocks follow each other
+        let offset = BlockOffset::new(offset);
+        let start_iblock = offset.iblock()?;
+        let end = offset.checked_add(data.len())?.get();
+        // At most `MAX_BLOCKS`, as `end` is at most `MAX_FILE_SIZE`
+        let end_iblock = end.div_ceil(BLOCK_SIZE as u64) as LBlockId;
+        let first = max(self.extent_end(file)?, start_iblock);
+        if end_iblock > first {
+            self.inode_map_blocks(file, first, end_iblock - first)?;
+        }
+        self.write_inode_data(file, offset.get(), IoVec::new(&[data]), false)?;
+        Ok(())
+    }
+}
+
+impl DelayedWriter<'_> {
+    /// Set the maximum number of bytes buffered per inode. An inode is
+    /// flushed once its buffer reaches the limit.
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit.max(1);
+    }
+
+    /// The number of bytes waiting to be flushed.
+    pub fn pending_bytes(&self) -> usize {
+        self.pending.values().map(|p| p.data.len()).sum()
+    }
+
+    /// Buffer a write to a regular file. A write that does not continue the
+    /// buffered range of the inode flushes it first.
+    ///
+    /// # Return
+    ///
+    /// `Ok(usize)` - the number of bytes accepted, always `data.len()`
+    ///
+    /// # Error
+    ///
+    /// * `EISDIR` - `file` is not a regular file
+    /// * `EACCES` - no write permission
+    /// * `ENOKEY` - `file` is encrypted
+    /// * `EPERM` - `file` is a verity file
+    /// * `ENOSPC` - no space left on device, when flushing
+    pub fn write(&mut self, file: InodeId, offset: u64, data: &[u8]) -> Result<usize> {
+        let inode_ref = self.fs.read_inode(file);
+        if !inode_ref.inode.is_file() {
+            return_error!(ErrCode::EISDIR, "Inode {} is not a file", file);
+        }
+        self.fs.check_access(&inode_ref, Access::WRITE)?;
+        Ext4::check_unencrypted(&inode_ref)?;
+        Ext4::check_not_verity(&inode_ref)?;
+        if let Some(p) = self.pending.get(&file) {
+            if p.offset + p.data.len() as u64 != offset {
+                self.flush_inode(file)?;
+            }
+        }
+        let p = self.pending.entry(file).or_insert(PendingWrite {
+            offset,
+            data: Vec::new(),
+        });
+        p.data.extend_from_slice(data);
+        if p.data.len() >= self.limit {
+            self.flush_inode(file)?;
+        }
+        Ok(data.len())
+    }
+
+    /// Allocate blocks for and write the pending data of an inode. On error
+    /// the pending data of the inode is dropped.
+    pub fn flush_inode(&mut self, file: InodeId) -> Result<()> {
+        let Some(p) = self.pending.remove(&file) else {
+            return Ok(());
+        };
+        let mut inode_ref = self.fs.read_inode(file);
+        self.fs
+            .write_delayed_data(&mut inode_ref, p.offset, &p.data)
+    }
+
+    /// Allocate blocks for and write all pending data.
+    pub fn flush(&mut self) -> Result<()> {
+        while let Some(&file) = self.pending.keys().next() {
+            self.flush_inode(file)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DelayedWriter<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Delayed writes lost on drop: {}", e);
+        }
+    }
+}
@@ -1,26 +1,120 @@
 use super::Ext4;
 use crate::constants::*;
 use crate::ext4_defs::*;
+use crate::format_error;
 use crate::prelude::*;
 use crate::return_error;
+use core::cmp::min;
+
+/// Entries added to a directory in a row, see `Ext4::dir_append_entry`.
+/// The block being filled is kept in memory and written back once, when
+/// it is full or the appender is finished.
+#[derive(Default)]
+pub(super) struct DirAppender {
+    /// The block to try first
+    iblock: LBlockId,
+    /// The block being filled, if loaded
+    block: Option<DirBlock>,
+    /// Whether the loaded block has entries not written back yet
+    dirty: bool,
+}
 
 impl Ext4 {
+    /// Check if a name can be used as a directory entry name. A valid name
+    /// is not empty, is at most `NAME_MAX` bytes long, and contains no '/'
+    /// or '\0'.
+    pub(super) fn dir_check_name(name: &str) -> Result<()> {
+        if name.is_empty() {
+            return_error!(ErrCode::EINVAL, "Empty directory entry name");
+        }
+        if name.len() > NAME_MAX {
+            return_error!(
+                ErrCode::ENAMETOOLONG,
+                "Directory entry name too long: {} bytes",
+                name.len()
+            );
+        }
+        if name.bytes().any(|b| b == b'/' || b == 0) {
+            return_error!(ErrCode::EINVAL, "Invalid directory entry name {:?}", name);
+        }
+        Ok(())
+    }
+
+    /// The number of blocks of a directory, whose size always covers
+    /// whole blocks. Unlike `i_blocks`, this leaves out indirect blocks.
+    pub(super) fn dir_block_count(dir: &InodeRef) -> LBlockId {
+        (dir.inode.size() / BLOCK_SIZE as u64) as LBlockId
+    }
+
+    /// The blocks of a directory that are mapped, as pairs of logical and
+    /// physical block numbers in logical order. Holes and unwritten extents
+    /// are skipped, and so are blocks past the directory size.
+    pub(super) fn dir_mapped_blocks(&self, dir: &InodeRef) -> Result<Vec<(LBlockId, PBlockId)>> {
+        let total_blocks = Self::dir_block_count(dir);
+        let mut blocks = Vec::new();
+        if dir.inode.uses_block_map() {
+            for iblock in 0..total_blocks {
+                if let Some(pblock) = self.indirect_lookup(dir, iblock)? {
+                    blocks.push((iblock, pblock));
+                }
+            }
+            return Ok(blocks);
+        }
+        self.extent_for_each(dir, |ex| {
+            if ex.is_unwritten() {
+                return Ok(());
+            }
+            let end = min(ex.start_lblock() + ex.block_count(), total_blocks);
+            for iblock in ex.start_lblock()..end {
+                let pblock = ex.start_pblock() + (iblock - ex.start_lblock()) as PBlockId;
+                blocks.push((iblock, pblock));
+            }
+            Ok(())
+        })?;
+        Ok(blocks)
+    }
+
+    /// The file type recorded in directory entries. Without the `filetype`
+    /// feature, entries use the old format whose type byte is 0.
+    fn dir_entry_type(&self, child: &InodeRef) -> FileType {
+        if self.read_super_block().has_filetype() {
+            child.inode.file_type()
+        } else {
+            FileType::Unknown
+        }
+    }
+
     /// Find a directory entry that matches a given name under a parent directory
     pub(super) fn dir_find_entry(&self, dir: &InodeRef, name: &str) -> Result<InodeId> {
-        trace!("Dir find entry: dir {}, name {}", dir.id, name);
-        let total_blocks = dir.inode.fs_block_count() as u32;
-        let mut iblock: LBlockId = 0;
-        while iblock < total_blocks {
-            // Get the fs block id
-            let fblock = self.extent_query(dir, iblock)?;
+        log_dir!(trace, "Dir find entry: dir {}, name {}", dir.id, name);
+        // Encrypted names are compared once decrypted
+        if self.name_decryptor(dir)?.is_some() {
+            let entries = self.dir_list_entries(dir)?;
+            if let Some(entry) = entries.iter().find(|e| e.name_bytes() == name.as_bytes()) {
+                return Ok(entry.inode());
+            }
+            return_error!(
+                ErrCode::ENOENT,
+                "Directory entry not found: dir {}, name {}",
+                dir.id,
+                name
+            );
+        }
+        for (iblock, pblock) in self.dir_mapped_blocks(dir)? {
             // Load block from disk
-            let dir_block = DirBlock::new(self.read_block(fblock));
+            let dir_block = self.dir_read_block(dir, iblock, pblock)?;
             // Find the entry in block
+            #[cfg(feature = "casefold")]
+            let res = if dir.inode.is_casefold() {
+                dir_block.get_casefold(name)
+            } else {
+                dir_block.get(name)
+            };
+            #[cfg(not(feature = "casefold"))]
             let res = dir_block.get(name);
             if let Some(r) = res {
                 return Ok(r);
             }
-            iblock += 1;
         }
         return_error!(
             ErrCode::ENOENT,
@@ -37,81 +131,141 @@ impl Ext4 {
         child: &InodeRef,
         name: &str,
     ) -> Result<()> {
-        trace!(
+        log_dir!(
+            trace,
             "Dir add entry: dir {}, child {}, name {}",
             dir.id,
             child.id,
             name
         );
-        let total_blocks = dir.inode.fs_block_count() as u32;
-        let mut iblock: LBlockId = 0;
+        Self::dir_check_name(name)?;
         // Try finding a block with enough space
-        while iblock < total_blocks {
-            // Get the parent physical block id
-            let fblock = self.extent_query(dir, iblock).unwrap();
+        for (iblock, pblock) in self.dir_mapped_blocks(dir)? {
             // Load the parent block from disk
-            let mut dir_block = DirBlock::new(self.read_block(fblock));
+            let mut dir_block = self.dir_read_block(dir, iblock, pblock)?;
             // Try inserting the entry to parent block
-            if dir_block.insert(name, child.id, child.inode.file_type()) {
-                // Update checksum
-                dir_block.set_checksum(
-                    &self.read_super_block().uuid(),
-                    dir.id,
-                    dir.inode.generation(),
-                );
-                // Write the block back to disk
-                self.write_block(dir_block.block());
+            if dir_block.insert(name, child.id, self.dir_entry_type(child)) {
+                // Update checksum and write the block back to disk
+                self.dir_write_block(dir, &mut dir_block);
                 return Ok(());
             }
-            // Current block has no enough space
-            iblock += 1;
         }
+        let total_blocks = Self::dir_block_count(dir);
         // No free block found - needed to allocate a new data block
         // Append a new data block
-        let (_, fblock) = self.inode_append_block(dir)?;
+        let (fblock, _) = self.map_block(dir, total_blocks)?;
         // Update inode size
         dir.inode.set_size(dir.inode.size() + BLOCK_SIZE as u64);
-        // Load new block
-        let mut new_dir_block = DirBlock::new(self.read_block(fblock));
+        self.write_inode(dir);
+        // Freed blocks keep their content, start from an empty one
+        let mut new_dir_block = DirBlock::new(Block::new(fblock, [0; BLOCK_SIZE]));
         // Write the entry to block
-        new_dir_block.init();
-        new_dir_block.insert(name, child.id, child.inode.file_type());
-        new_dir_block.set_checksum(
-            &self.read_super_block().uuid(),
-            dir.id,
-            dir.inode.generation(),
-        );
-        // Write the block back to disk
-        self.write_block(new_dir_block.block());
+        new_dir_block.init(self.read_super_block().has_metadata_csum());
+        new_dir_block.insert(name, child.id, self.dir_entry_type(child));
+        // Update checksum and write the block back to disk
+        self.dir_write_block(dir, &mut new_dir_block);
 
         Ok(())
     }
 
-    /// Remove a entry from a directory
-    pub(super) fn dir_remove_entry(&self, dir: &InodeRef, name: &str) -> Result<()> {
-        trace!("Dir remove entry: dir {}, name {}", dir.id, name);
-        let total_blocks = dir.inode.fs_block_count() as u32;
+    /// The number of blocks adding an entry named `name` to a directory
+    /// allocates, 0 if a block of the directory has room for it.
+    pub(super) fn dir_add_blocks_needed(&self, dir: &InodeRef, name: &str) -> Result<u64> {
+        for (iblock, pblock) in self.dir_mapped_blocks(dir)? {
+            if self.dir_read_block(dir, iblock, pblock)?.has_room(name) {
+                return Ok(0);
+            }
+        }
+        self.extent_blocks_needed(dir, Self::dir_block_count(dir))
+    }
+
+    /// Add an entry to a directory through an appender, which keeps the
+    /// block being filled in memory. Blocks before the one the previous
+    /// entry went to are not searched again. `dir_append_finish` must be
+    /// called once done, whether adding entries failed or not.
+    pub(super) fn dir_append_entry(
+        &self,
+        dir: &mut InodeRef,
+        appender: &mut DirAppender,
+        child: &InodeRef,
+        name: &str,
+    ) -> Result<()> {
+        Self::dir_check_name(name)?;
+        let file_type = self.dir_entry_type(child);
+        loop {
+            let mut dir_block = match appender.block.take() {
+                Some(dir_block) => dir_block,
+                None if appender.iblock < Self::dir_block_count(dir) => {
+                    match self.dir_block_lookup(dir, appender.iblock)? {
+                        Some(pblock) => self.dir_read_block(dir, appender.iblock, pblock)?,
+                        None => {
+                            // Skip holes
+                            appender.iblock += 1;
+                            continue;
+                        }
+                    }
+                }
+                None => {
+                    // Append a new data block
+                    let (fblock, _) = self.map_block(dir, appender.iblock)?;
+                    dir.inode.set_size(dir.inode.size() + BLOCK_SIZE as u64);
+                    let mut dir_block = DirBlock::new(Block::new(fblock, [0; BLOCK_SIZE]));
+                    dir_block.init(self.read_super_block().has_metadata_csum());
+                    appender.dirty = true;
+                    dir_block
+                }
+            };
+            let inserted = dir_block.insert(name, child.id, file_type);
+            appender.block = Some(dir_block);
+            if inserted {
+                appender.dirty = true;
+                return Ok(());
+            }
+            // Write back the full block and move on to the next one
+            self.dir_append_flush(dir, appender);
+            appender.iblock += 1;
+        }
+    }
+
+    /// Write back the block an appender is filling, and the directory.
+    pub(super) fn dir_append_finish(&self, dir: &mut InodeRef, appender: &mut DirAppender) {
+        self.dir_append_flush(dir, appender);
+        self.write_inode(dir);
+    }
+
+    /// Write back the block an appender is filling, if it changed.
+    fn dir_append_flush(&self, dir: &InodeRef, appender: &mut DirAppender) {
+        if let Some(mut dir_block) = appender.block.take() {
+            if appender.dirty {
+                self.dir_write_block(dir, &mut dir_block);
+            }
+        }
+        appender.dirty = false;
+    }
+
+    /// Remove a entry from a directory. If directory shrinking is enabled,
+    /// empty blocks left at the end of the directory are released.
+    pub(super) fn dir_remove_entry(&self, dir: &mut InodeRef, name: &str) -> Result<()> {
+        log_dir!(trace, "Dir remove entry: dir {}, name {}", dir.id, name);
+        let total_blocks = Self::dir_block_count(dir);
         // Check each block
-        let mut iblock: LBlockId = 0;
-        while iblock < total_blocks {
-            // Get the parent physical block id
-            let fblock = self.extent_query(dir, iblock).unwrap();
+        for (iblock, pblock) in self.dir_mapped_blocks(dir)? {
             // Load the block from disk
-            let mut dir_block = DirBlock::new(self.read_block(fblock));
+            let mut dir_block = self.dir_read_block(dir, iblock, pblock)?;
             // Try removing the entry
             if dir_block.remove(name) {
-                // Update checksum
-                dir_block.set_checksum(
-                    &self.read_super_block().uuid(),
-                    dir.id,
-                    dir.inode.generation(),
-                );
-                // Write the block back to disk
-                self.write_block(dir_block.block());
+                // Update checksum and write the block back to disk
+                self.dir_write_block(dir, &mut dir_block);
+                // Only extent trees can be shrunk
+                if self.dir_shrink
+                    && dir.inode.uses_extents()
+                    && iblock + 1 == total_blocks
+                    && dir_block.is_empty()
+                {
+                    self.dir_release_tail(dir)?;
+                }
                 return Ok(());
             }
-            // Current block has no enough space
-            iblock += 1;
         }
         // Not found the target entry
         return_error!(
@@ -122,20 +276,139 @@ impl Ext4 {
         );
     }
 
-    /// Get all entries under a directory
-    pub(super) fn dir_list_entries(&self, dir: &InodeRef) -> Vec<DirEntry> {
-        let total_blocks = dir.inode.fs_block_count() as u32;
+    /// Release the empty blocks at the end of a directory. The first block
+    /// is always kept.
+    fn dir_release_tail(&self, dir: &mut InodeRef) -> Result<()> {
+        let mut total_blocks = Self::dir_block_count(dir);
+        while total_blocks > 1 {
+            let iblock = total_blocks - 1;
+            let Some(pblock) = self.dir_block_lookup(dir, iblock)? else {
+                break;
+            };
+            if !self.dir_read_block(dir, iblock, pblock)?.is_empty() {
+                break;
+            }
+            let Some(pblock) = self.extent_remove_last(dir, iblock)? else {
+                break;
+            };
+            self.dealloc_blocks(dir, pblock, 1)?;
+            total_blocks -= 1;
+            dir.inode.set_fs_block_count(dir.inode.fs_block_count() - 1);
+            dir.inode.set_size(total_blocks as u64 * BLOCK_SIZE as u64);
+            self.write_inode(dir);
+            log_dir!(debug, "Dir release block {}: dir {}", iblock, dir.id);
+        }
+        Ok(())
+    }
+
+    /// Point an existing entry of a directory to another inode. The link
+    /// counts of both inodes are not changed.
+    pub(super) fn dir_replace_entry(
+        &self,
+        dir: &InodeRef,
+        name: &str,
+        child: &InodeRef,
+    ) -> Result<()> {
+        log_dir!(
+            trace,
+            "Dir replace entry: dir {}, name {}, child {}",
+            dir.id,
+            name,
+            child.id
+        );
+        for (iblock, pblock) in self.dir_mapped_blocks(dir)? {
+            let mut dir_block = self.dir_read_block(dir, iblock, pblock)?;
+            if dir_block.replace(name, child.id, self.dir_entry_type(child)) {
+                self.dir_write_block(dir, &mut dir_block);
+                return Ok(());
+            }
+        }
+        return_error!(
+            ErrCode::ENOENT,
+            "Directory entry not found: dir {}, name {}",
+            dir.id,
+            name
+        );
+    }
+
+    /// Get all entries under a directory. The names of an encrypted
+    /// directory are decrypted if a crypto provider is set.
+    pub(super) fn dir_list_entries(&self, dir: &InodeRef) -> Result<Vec<DirEntry>> {
         let mut entries: Vec<DirEntry> = Vec::new();
-        let mut iblock: LBlockId = 0;
-        while iblock < total_blocks {
-            // Get the fs block id
-            let fblock = self.extent_query(dir, iblock).unwrap();
+        for (iblock, pblock) in self.dir_mapped_blocks(dir)? {
             // Load block from disk
-            let dir_block = DirBlock::new(self.read_block(fblock));
+            let dir_block = self.dir_read_block(dir, iblock, pblock)?;
             // Get all entries from block
             dir_block.list(&mut entries);
-            iblock += 1;
         }
-        entries
+        if let Some(decryptor) = self.name_decryptor(dir)? {
+            // "." and ".." are not encrypted
+            for entry in entries.iter_mut() {
+                if entry.name_bytes() != b"." && entry.name_bytes() != b".." {
+                    let name = decryptor.decrypt_name(dir.id, entry.name_bytes())?;
+                    entry.set_name(&name);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Check if a directory has no entries but `.` and `..`, reading its
+    /// blocks one at a time until an entry is found.
+    pub(super) fn dir_is_empty(&self, dir: &InodeRef) -> Result<bool> {
+        let mut used = 0;
+        for (iblock, pblock) in self.dir_mapped_blocks(dir)? {
+            used += self.dir_read_block(dir, iblock, pblock)?.used_count();
+            if used > 2 {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// The physical block a block of a directory is mapped to, `None` if
+    /// it is a hole.
+    fn dir_block_lookup(&self, dir: &InodeRef, iblock: LBlockId) -> Result<Option<PBlockId>> {
+        match self.extent_query(dir, iblock) {
+            Ok(pblock) => Ok(Some(pblock)),
+            Err(e) if e.code() == ErrCode::ENOENT => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Load block `iblock` of a directory, mapped to `fblock`, verify its
+    /// checksum if `metadata_csum` is enabled.
+    fn dir_read_block(
+        &self,
+        dir: &InodeRef,
+        iblock: LBlockId,
+        fblock: PBlockId,
+    ) -> Result<DirBlock> {
+        let dir_block = DirBlock::new(self.read_block(fblock));
+        let sb = self.read_super_block();
+        if sb.has_metadata_csum()
+            && !dir_block.verify_checksum(sb.checksum_seed(), dir.id, dir.inode.generation())
+        {
+            let err = format_error!(
+                ErrCode::EFSBADCRC,
+                "Directory block checksum mismatch: dir {}, block {}",
+                dir.id,
+                iblock
+            );
+            return Err(self.fs_error("dir_read_block", dir.id, fblock, err));
+        }
+        Ok(dir_block)
+    }
+
+    /// Update the checksum of a directory block if `metadata_csum` is
+    /// enabled, and write the block to disk.
+    fn dir_write_block(&self, dir: &InodeRef, dir_block: &mut DirBlock) {
+        let sb = self.read_super_block();
+        if sb.has_metadata_csum()
+            && !dir_block.set_checksum(sb.checksum_seed(), dir.id, dir.inode.generation())
+        {
+            warn!("No room for directory block checksum: dir {}", dir.id);
+        }
+        self.write_block(dir_block.block());
     }
 }
@@ -0,0 +1,253 @@
+//! Structured dumps of on-disk metadata for debugging.
+//!
+//! `dump_tree` and `dump_dir` describe what is stored on disk rather than
+//! what the filesystem makes of it: checksum mismatches are reported, not
+//! returned as errors, and a malformed node or block is described as far
+//! as it can be parsed. `diff_devices` compares two images block by block.
+
+use super::Ext4;
+use crate::constants::*;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+use crate::return_error;
+use core::ops::Range;
+
+/// A node of an extent tree, acquired by `Ext4::dump_tree`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtentTreeNode {
+    /// The block holding the node, 0 for the root in the inode
+    pub pblock: PBlockId,
+    /// Whether the header has the extent magic number
+    pub magic_valid: bool,
+    /// Depth of the node, 0 for a leaf
+    pub depth: u16,
+    /// Number of entries in the header
+    pub entries_count: u16,
+    /// Maximum number of entries in the header
+    pub max_entries_count: u16,
+    /// Whether the tail checksum is valid, always true for the root and
+    /// without `metadata_csum`
+    pub checksum_valid: bool,
+    /// The entries, in order. Empty if the header is invalid.
+    pub entries: Vec<ExtentTreeEntry>,
+}
+
+/// An entry of an extent tree node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtentTreeEntry {
+    /// An index pointing to a child node
+    Index {
+        /// First logical block covered by the child
+        start_lblock: LBlockId,
+        /// The child node. Its entries are empty if its depth is not one
+        /// less than the parent's.
+        child: ExtentTreeNode,
+    },
+    /// An extent mapping data blocks
+    Extent {
+        /// First logical block
+        start_lblock: LBlockId,
+        /// First physical block
+        start_pblock: PBlockId,
+        /// Number of blocks
+        block_count: u32,
+        /// Whether the extent is unwritten (preallocated)
+        unwritten: bool,
+    },
+}
+
+/// A directory block, acquired by `Ext4::dump_dir`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirBlockDump {
+    /// Logical block id within the directory
+    pub lblock: LBlockId,
+    /// Physical block id
+    pub pblock: PBlockId,
+    /// All entries, including unused ones
+    pub entries: Vec<DirEntryDump>,
+    /// Offset of the entry that stopped the walk because its `rec_len` is
+    /// malformed, if any
+    pub malformed_at: Option<usize>,
+    /// The checksum in the dir entry tail, `None` if there is no tail
+    pub tail_checksum: Option<u32>,
+    /// Whether the tail checksum is valid, always true without
+    /// `metadata_csum`
+    pub checksum_valid: bool,
+}
+
+/// The raw fields of a directory entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntryDump {
+    /// Byte offset within the block
+    pub offset: usize,
+    /// Inode number, 0 for an unused entry
+    pub inode: InodeId,
+    /// Distance to the next entry
+    pub rec_len: u16,
+    /// File type code
+    pub file_type: FileType,
+    /// Raw name bytes
+    pub name: Vec<u8>,
+}
+
+/// A range of bytes that differ in a block, returned by `diff_devices`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockDiff {
+    /// The block id
+    pub block: PBlockId,
+    /// From the first to past the last differing byte
+    pub bytes: Range<usize>,
+}
+
+impl Ext4 {
+    /// Describe the extent tree of an inode.
+    ///
+    /// # Error
+    ///
+    /// `EINVAL` - the inode does not use extents
+    pub fn dump_tree(&self, inode: InodeId) -> Result<ExtentTreeNode> {
+        let inode_ref = self.read_inode(inode);
+        if !inode_ref.inode.uses_extents() {
+            return_error!(
+                ErrCode::EINVAL,
+                "Inode {} does not map blocks with extents",
+                inode
+            );
+        }
+        let root = inode_ref.inode.extent_root();
+        Ok(self.dump_extent_node(&inode_ref, &root, 0, None))
+    }
+
+    fn dump_extent_node(
+        &self,
+        inode_ref: &InodeRef,
+        node: &ExtentNode,
+        pblock: PBlockId,
+        parent_depth: Option<u16>,
+    ) -> ExtentTreeNode {
+        let header = *node.header();
+        let sb = self.read_super_block();
+        let checksum_valid = pblock == 0
+            || !sb.has_metadata_csum()
+            || node.verify_checksum(
+                sb.checksum_seed(),
+                inode_ref.id,
+                inode_ref.inode.generation(),
+            );
+        let mut dump = ExtentTreeNode {
+            pblock,
+            magic_valid: header.check_magic(),
+            depth: header.depth(),
+            entries_count: header.entries_count(),
+            max_entries_count: header.max_entries_count(),
+            checksum_valid,
+            entries: Vec::new(),
+        };
+        // Only descend when the depth strictly decreases, so that a
+        // corrupted tree cannot loop
+        if !node.is_valid(parent_depth) {
+            return dump;
+        }
+        for i in 0..dump.entries_count as usize {
+            let entry = if dump.depth == 0 {
+                let ex = node.extent_at(i);
+                ExtentTreeEntry::Extent {
+                    start_lblock: ex.start_lblock(),
+                    start_pblock: ex.start_pblock(),
+                    block_count: ex.block_count(),
+                    unwritten: ex.is_unwritten(),
+                }
+            } else {
+                let index = node.extent_index_at(i);
+                let block = self.read_block(index.leaf());
+                let child = ExtentNode::from_bytes(&block.data);
+                ExtentTreeEntry::Index {
+                    start_lblock: index.start_lblock(),
+                    child: self.dump_extent_node(inode_ref, &child, index.leaf(), Some(dump.depth)),
+                }
+            };
+            dump.entries.push(entry);
+        }
+        dump
+    }
+
+    /// Describe the raw entry layout of each mapped block of a directory,
+    /// holes are skipped.
+    ///
+    /// # Error
+    ///
+    /// * `ENOTDIR` - the inode is not a directory
+    /// * `EINVAL` - the directory does not use extents
+    pub fn dump_dir(&self, inode: InodeId) -> Result<Vec<DirBlockDump>> {
+        let dir = self.read_inode(inode);
+        if !dir.inode.is_dir() {
+            return_error!(ErrCode::ENOTDIR, "Inode {} is not a directory", inode);
+        }
+        if !dir.inode.uses_extents() {
+            return_error!(
+                ErrCode::EINVAL,
+                "Inode {} does not map blocks with extents",
+                inode
+            );
+        }
+        let sb = self.read_super_block();
+        let mut blocks = Vec::new();
+        for (lblock, pblock) in self.dir_mapped_blocks(&dir)? {
+            let dir_block = DirBlock::new(self.read_block(pblock));
+            let (entries, malformed_at) = dir_block.raw_entries();
+            blocks.push(DirBlockDump {
+                lblock,
+                pblock,
+                entries: entries
+                    .into_iter()
+                    .map(|(offset, de)| DirEntryDump {
+                        offset,
+                        inode: de.inode(),
+                        rec_len: de.rec_len(),
+                        file_type: de.file_type(),
+                        name: de.name_bytes().to_vec(),
+                    })
+                    .collect(),
+                malformed_at,
+                tail_checksum: dir_block.tail_checksum(),
+                checksum_valid: !sb.has_metadata_csum()
+                    || dir_block.verify_checksum(
+                        sb.checksum_seed(),
+                        dir.id,
+                        dir.inode.generation(),
+                    ),
+            });
+        }
+        Ok(blocks)
+    }
+}
+
+/// Compare two block devices block by block and list the differing bytes
+/// of each block that differs, in ascending block order.
+///
+/// The first `block_count` blocks are compared. If `None`, the size of
+/// the smaller device is used, ignoring devices of unknown size. Nothing is
+/// compared if neither size is known.
+pub fn diff_devices(
+    a: &dyn BlockDevice,
+    b: &dyn BlockDevice,
+    block_count: Option<u64>,
+) -> Vec<BlockDiff> {
+    let count = block_count.unwrap_or_else(|| match (a.block_count(), b.block_count()) {
+        (Some(x), Some(y)) => x.min(y),
+        (x, y) => x.or(y).unwrap_or(0),
+    });
+    let mut diffs = Vec::new();
+    for block in 0..count {
+        let (da, db) = (a.read_block(block).data, b.read_block(block).data);
+        let Some(first) = (0..BLOCK_SIZE).find(|&i| da[i] != db[i]) else {
+            continue;
+        };
+        let last = (0..BLOCK_SIZE).rfind(|&i| da[i] != db[i]).unwrap();
+        diffs.push(BlockDiff {
+            block,
+            bytes: first..last + 1,
+        });
+    }
+    diffs
+}
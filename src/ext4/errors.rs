@@ -0,0 +1,118 @@
+//! Handling of detected filesystem corruption.
+//!
+//! When the crate detects corruption, such as a bad checksum, a double
+//! free or an invalid extent, the error is recorded in the superblock like
+//! Linux does, so `dumpe2fs` shows it and `e2fsck` checks the filesystem.
+//! Then the instance continues, becomes read-only or panics, according to
+//! its `ErrorPolicy`.
+
+use super::Ext4;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+use crate::return_error;
+use core::sync::atomic::Ordering;
+
+/// What to do when corruption is detected, like the `errors=` mount option.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Return the error and keep going
+    #[default]
+    Continue,
+    /// Return the error and refuse all further modifications with `EROFS`
+    RemountRo,
+    /// Panic
+    Panic,
+}
+
+impl ErrorPolicy {
+    /// The policy stored in the superblock `s_errors` field.
+    pub(super) fn from_super_block(sb: &SuperBlock) -> Self {
+        match sb.errors() {
+            2 => ErrorPolicy::RemountRo,
+            3 => ErrorPolicy::Panic,
+            _ => ErrorPolicy::Continue,
+        }
+    }
+}
+
+impl Ext4 {
+    /// Get the error policy. Defaults to the behaviour recorded in the
+    /// superblock.
+    pub fn error_policy(&self) -> ErrorPolicy {
+        self.error_policy
+    }
+
+    /// Set the error policy, overriding the superblock default.
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) {
+        self.error_policy = policy;
+    }
+
+    /// Whether the instance refuses modifications because an error was
+    /// detected under `ErrorPolicy::RemountRo`, or because the image uses
+    /// unsupported features, see `Ext4::unsupported_features`.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// Fail with `EROFS` if the instance is read-only.
+    pub(super) fn check_writable(&self) -> Result<()> {
+        if !self.unsupported.is_empty() {
+            return_error!(
+                ErrCode::EROFS,
+                "Filesystem is read-only, unsupported features {:?}",
+                self.unsupported
+            );
+        }
+        if self.is_read_only() {
+            return_error!(ErrCode::EROFS, "Filesystem is read-only after an error");
+        }
+        Ok(())
+    }
+
+    /// Handle corruption detected in function `func`, on inode `ino` and
+    /// block `block` (0 if unknown): record it in the superblock and apply
+    /// the error policy. Return `err` to be passed on to the caller.
+    pub(super) fn fs_error(
+        &self,
+        func: &str,
+        ino: InodeId,
+        block: PBlockId,
+        err: Ext4Error,
+    ) -> Ext4Error {
+        log::error!("Filesystem error in {}: {}", func, err);
+        // An image with unsupported features is never written
+        if self.unsupported.is_empty() {
+            let time = self.wall_clock.map_or(0, |clock| clock());
+            let mut sb = self.read_super_block();
+            sb.record_error(time, func, ino, block, errcode(err.code()));
+            self.write_super_block(&sb);
+            self.flush_device();
+        }
+        match self.error_policy {
+            ErrorPolicy::Continue => {}
+            ErrorPolicy::RemountRo => self.read_only.store(true, Ordering::Relaxed),
+            ErrorPolicy::Panic => panic!("Filesystem error in {}: {}", func, err),
+        }
+        err
+    }
+}
+
+/// Map an error code to the `EXT4_ERR_*` value stored in the superblock.
+fn errcode(code: ErrCode) -> u8 {
+    match code {
+        ErrCode::EIO => 1,
+        ErrCode::ENOMEM => 2,
+        ErrCode::EFSBADCRC => 3,
+        ErrCode::EFSCORRUPTED => 4,
+        ErrCode::ENOSPC => 5,
+        ErrCode::EROFS => 7,
+        ErrCode::EFBIG => 8,
+        ErrCode::EEXIST => 9,
+        ErrCode::ERANGE => 10,
+        ErrCode::EBUSY => 12,
+        ErrCode::ENOTDIR => 13,
+        ErrCode::ENOTEMPTY => 14,
+        ErrCode::EFAULT => 16,
+        _ => 0,
+    }
+}
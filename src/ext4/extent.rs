@@ -3,6 +3,7 @@ use crate::constants::*;
 use crate::ext4_defs::*;
 use crate::format_error;
 use crate::prelude::*;
+use crate::return_error;
 use core::cmp::min;
 
 #[derive(Debug)]
@@ -23,44 +24,103 @@ impl ExtentSearchStep {
 }
 
 impl Ext4 {
-    /// Given a logic block id, find the corresponding fs block id.
+    /// Given a logic block id, find the corresponding fs block id. Inodes
+    /// without an extent tree are looked up in their block map.
     pub(super) fn extent_query(&self, inode_ref: &InodeRef, iblock: LBlockId) -> Result<PBlockId> {
-        let path = self.find_extent(inode_ref, iblock);
-        // Leaf is the last element of the path
-        let leaf = path.last().unwrap();
-        if let Ok(index) = leaf.index {
-            // Note: block data must be defined here to keep it alive
-            let block_data: Block;
-            let ex_node = if leaf.pblock != 0 {
-                // Load the extent node
-                block_data = self.read_block(leaf.pblock);
-                // Load the next extent header
-                ExtentNode::from_bytes(&block_data.data)
-            } else {
-                // Root node
-                inode_ref.inode.extent_root()
-            };
-            let ex = ex_node.extent_at(index);
-            Ok(ex.start_pblock() + (iblock - ex.start_lblock()) as PBlockId)
+        let pblock = if inode_ref.inode.uses_block_map() {
+            self.indirect_lookup(inode_ref, iblock)?
         } else {
-            Err(format_error!(
+            match self.es_lookup(inode_ref.id, iblock) {
+                Some(status) => status,
+                None => self.extent_lookup(inode_ref, iblock)?,
+            }
+        };
+        pblock.ok_or_else(|| {
+            format_error!(
                 ErrCode::ENOENT,
                 "extent_query: inode {} query iblock {} not found",
                 inode_ref.id,
                 iblock
-            ))
+            )
+        })
+    }
+
+    /// Look up a logic block in the extent tree, `None` if it is a hole.
+    /// The extent or hole found is added to the extent status cache.
+    fn extent_lookup(&self, inode_ref: &InodeRef, iblock: LBlockId) -> Result<Option<PBlockId>> {
+        let path = self.find_extent(inode_ref, iblock)?;
+        // Leaf is the last element of the path
+        let leaf = path.last().unwrap();
+        // Note: block data must be defined here to keep it alive
+        let block_data: Block;
+        let ex_node = if leaf.pblock != 0 {
+            // Load the extent node
+            block_data = self.read_block(leaf.pblock);
+            // Load the next extent header
+            ExtentNode::from_bytes(&block_data.data)
+        } else {
+            // Root node
+            inode_ref.inode.extent_root()
+        };
+        match leaf.index {
+            Ok(index) => {
+                let ex = ex_node.extent_at(index);
+                self.es_insert(
+                    inode_ref.id,
+                    ex.start_lblock(),
+                    ex.block_count(),
+                    Some(ex.start_pblock()),
+                );
+                let pblock = ex.start_pblock() + (iblock - ex.start_lblock()) as PBlockId;
+                Ok(Some(pblock))
+            }
+            Err(index)
+                if index < ex_node.header().entries_count() as usize
+                    && ex_node.extent_at(index).start_lblock() <= iblock =>
+            {
+                // An unwritten extent, which reads as a hole
+                let ex = ex_node.extent_at(index);
+                self.es_insert(inode_ref.id, ex.start_lblock(), ex.block_count(), None);
+                Ok(None)
+            }
+            Err(index) => {
+                // The hole spans the gap between the neighbouring extents.
+                // Beyond the leaf, other leaves may map blocks, unless the
+                // leaf is the root.
+                let is_root = leaf.pblock == 0;
+                let start = if index > 0 {
+                    let prev = ex_node.extent_at(index - 1);
+                    prev.start_lblock() + prev.block_count()
+                } else if is_root {
+                    0
+                } else {
+                    iblock
+                };
+                let end = if index < ex_node.header().entries_count() as usize {
+                    ex_node.extent_at(index).start_lblock()
+                } else if is_root {
+                    MAX_BLOCKS
+                } else {
+                    iblock + 1
+                };
+                self.es_insert(inode_ref.id, start, end - start, None);
+                Ok(None)
+            }
         }
     }
 
     /// Given a logic block id, find the corresponding fs block id.
-    /// Create a new extent if not found.
+    /// Map a new block if not found, extending the preceding extent if the
+    /// block continues it both logically and physically.
     pub(super) fn extent_query_or_create(
         &self,
         inode_ref: &mut InodeRef,
         iblock: LBlockId,
-        block_count: u32,
     ) -> Result<PBlockId> {
-        let path = self.find_extent(inode_ref, iblock);
+        if inode_ref.inode.uses_block_map() {
+            return self.indirect_map(inode_ref, iblock);
+        }
+        let path = self.find_extent(inode_ref, iblock)?;
         // Leaf is the last element of the path
         let leaf = path.last().unwrap();
         // Note: block data must be defined here to keep it alive
@@ -78,79 +138,416 @@ impl Ext4 {
                 let ex = ex_node.extent_at(index);
                 Ok(ex.start_pblock() + (iblock - ex.start_lblock()) as PBlockId)
             }
-            Err(_) => {
-                // Not found, create a new extent
-                let block_count = min(block_count, MAX_BLOCKS - iblock);
-                // Allocate physical block
-                let fblock = self.alloc_block(inode_ref)?;
-                // Create a new extent
-                let new_ext = Extent::new(iblock, fblock, block_count as u16);
-                // Insert the new extent
-                self.insert_extent(inode_ref, &path, &new_ext)?;
+            Err(index) => {
+                // Not found, allocate physical block following the
+                // preceding extent
+                let goal = Self::extent_goal(&ex_node.as_immut(), index, iblock);
+                let fblock = self.alloc_block_near(inode_ref, goal)?;
+                let new_ext = Extent::new(iblock, fblock, 1);
+                if let Err(err) = self.extend_or_insert_extent(inode_ref, &path, &new_ext) {
+                    // The block is not mapped, give it back
+                    self.dealloc_block(inode_ref, fblock)?;
+                    return Err(err);
+                }
                 Ok(fblock)
             }
         }
     }
 
-    /// Get all data blocks recorded in the extent tree
-    pub(super) fn extent_all_data_blocks(&self, inode_ref: &InodeRef) -> Vec<PBlockId> {
-        let mut pblocks = Vec::new();
-        let ex_node = inode_ref.inode.extent_root();
-        self.get_all_pblocks_recursive(&ex_node, &mut pblocks);
-        pblocks
+    /// The number of blocks mapping `iblock` may allocate: the data block
+    /// and the blocks of the extent tree or block map that it may take, 0
+    /// if it is mapped.
+    pub(super) fn extent_blocks_needed(
+        &self,
+        inode_ref: &InodeRef,
+        iblock: LBlockId,
+    ) -> Result<u64> {
+        if inode_ref.inode.uses_block_map() {
+            return self.indirect_blocks_needed(inode_ref, iblock);
+        }
+        let path = self.find_extent(inode_ref, iblock)?;
+        if path.last().unwrap().index.is_ok() {
+            return Ok(0);
+        }
+        Ok(1 + self.extent_split_blocks(inode_ref, &path))
+    }
+
+    /// Map at most `count` blocks starting at `iblock`, which must not be
+    /// mapped yet, to newly allocated contiguous physical blocks. The new
+    /// blocks extend the preceding extent if it ends right before them both
+    /// logically and physically, otherwise a new extent is inserted.
+    ///
+    /// Return the first physical block and the number of blocks mapped.
+    /// Inodes without an extent tree get a single block in their block map.
+    pub(super) fn extent_append(
+        &self,
+        inode_ref: &mut InodeRef,
+        iblock: LBlockId,
+        count: u32,
+    ) -> Result<(PBlockId, u32)> {
+        if inode_ref.inode.uses_block_map() {
+            if self.indirect_lookup(inode_ref, iblock)?.is_some() {
+                return_error!(
+                    ErrCode::EINVAL,
+                    "Block {} of inode {} is already mapped",
+                    iblock,
+                    inode_ref.id
+                );
+            }
+            return Ok((self.indirect_map(inode_ref, iblock)?, 1));
+        }
+        let path = self.find_extent(inode_ref, iblock)?;
+        // Leaf is the last element of the path
+        let leaf = path.last().unwrap();
+        let Err(index) = leaf.index else {
+            return_error!(
+                ErrCode::EINVAL,
+                "Block {} of inode {} is already mapped",
+                iblock,
+                inode_ref.id
+            );
+        };
+        let count = min(count, Extent::INIT_MAX_LEN as u32);
+        let count = min(count, MAX_BLOCKS - iblock);
+        // Aim right after the preceding extent
+        let goal = if leaf.pblock != 0 {
+            let leaf_block = self.read_block(leaf.pblock);
+            Self::extent_goal(&ExtentNode::from_bytes(&leaf_block.data), index, iblock)
+        } else {
+            Self::extent_goal(&inode_ref.inode.extent_root(), index, iblock)
+        };
+        let (fblock, len) = self.alloc_blocks_near(inode_ref, goal, count)?;
+        let new_ext = Extent::new(iblock, fblock, len as u16);
+        if let Err(err) = self.extend_or_insert_extent(inode_ref, &path, &new_ext) {
+            // The blocks are not mapped, give them back
+            self.dealloc_blocks(inode_ref, fblock, len as u64)?;
+            return Err(err);
+        }
+        Ok((fblock, len))
+    }
+
+    /// Map a new extent at the position `path` leads to. The preceding
+    /// extent in the leaf is extended if the new extent continues it both
+    /// logically and physically, otherwise the new extent is inserted.
+    fn extend_or_insert_extent(
+        &self,
+        inode_ref: &mut InodeRef,
+        path: &[ExtentSearchStep],
+        new_ext: &Extent,
+    ) -> Result<()> {
+        let leaf = path.last().unwrap();
+        let index = leaf.index.unwrap_err();
+        self.es_invalidate(inode_ref.id);
+        let extend = |prev: &mut Extent| {
+            let ok = !prev.is_unwritten() && Extent::can_append(prev, new_ext);
+            if ok {
+                prev.set_block_count(prev.block_count() + new_ext.block_count());
+            }
+            ok
+        };
+        if index > 0 {
+            if leaf.pblock != 0 {
+                let mut leaf_block = self.read_block(leaf.pblock);
+                let mut leaf_node = ExtentNodeMut::from_bytes(&mut leaf_block.data);
+                if extend(leaf_node.extent_mut_at(index - 1)) {
+                    self.write_extent_block(inode_ref, &mut leaf_block);
+                    return Ok(());
+                }
+            } else if extend(inode_ref.inode.extent_root_mut().extent_mut_at(index - 1)) {
+                self.write_inode(inode_ref);
+                return Ok(());
+            }
+        }
+        self.insert_extent(inode_ref, path, new_ext)
+    }
+
+    /// Get the logical block after the last mapped one, 0 if no block is
+    /// mapped. Only the rightmost path of the extent tree is read.
+    pub(super) fn extent_end(&self, inode_ref: &InodeRef) -> Result<LBlockId> {
+        if inode_ref.inode.uses_block_map() {
+            return Ok(self.indirect_end(inode_ref));
+        }
+        // Node to read next, the root first
+        let mut pblock = 0;
+        let mut parent_depth = None;
+        loop {
+            // Note: block data must be defined here to keep it alive
+            let block_data: Block;
+            let ex_node = if pblock != 0 {
+                block_data = self.read_block(pblock);
+                ExtentNode::from_bytes(&block_data.data)
+            } else {
+                inode_ref.inode.extent_root()
+            };
+            self.check_extent_node(inode_ref, &ex_node, pblock, parent_depth)?;
+            let count = ex_node.header().entries_count() as usize;
+            if count == 0 {
+                return Ok(0);
+            }
+            if ex_node.header().depth() == 0 {
+                let ex = ex_node.extent_at(count - 1);
+                return Ok(ex.start_lblock() + ex.block_count());
+            }
+            pblock = ex_node.extent_index_at(count - 1).leaf();
+            parent_depth = Some(ex_node.header().depth());
+        }
     }
 
     /// Get all physical blocks for saving the extent tree
-    pub(super) fn extent_all_tree_blocks(&self, inode_ref: &InodeRef) -> Vec<PBlockId> {
+    pub(super) fn extent_all_tree_blocks(&self, inode_ref: &InodeRef) -> Result<Vec<PBlockId>> {
         let mut pblocks = Vec::new();
-        let ex_node = inode_ref.inode.extent_root();
-        self.get_all_nodes_recursive(&ex_node, &mut pblocks);
-        pblocks
+        self.extent_for_each_tree_block(inode_ref, |pblock| {
+            pblocks.push(pblock);
+            Ok(())
+        })?;
+        Ok(pblocks)
+    }
+
+    /// Get all extents in the extent tree, in logical block order
+    pub(super) fn extent_all_extents(&self, inode_ref: &InodeRef) -> Result<Vec<Extent>> {
+        let mut extents = Vec::new();
+        self.extent_for_each(inode_ref, |ex| {
+            extents.push(*ex);
+            Ok(())
+        })?;
+        Ok(extents)
+    }
+
+    /// Call `f` on each extent of the extent tree in logical block order,
+    /// without collecting them. Stop at the first error.
+    pub(super) fn extent_for_each<F>(&self, inode_ref: &InodeRef, mut f: F) -> Result<()>
+    where
+        F: FnMut(&Extent) -> Result<()>,
+    {
+        let root = inode_ref.inode.extent_root();
+        self.check_extent_node(inode_ref, &root, 0, None)?;
+        self.extent_for_each_recursive(inode_ref, &root, &mut f)
+    }
+
+    fn extent_for_each_recursive(
+        &self,
+        inode_ref: &InodeRef,
+        ex_node: &ExtentNode,
+        f: &mut dyn FnMut(&Extent) -> Result<()>,
+    ) -> Result<()> {
+        for i in 0..ex_node.header().entries_count() as usize {
+            if ex_node.header().depth() == 0 {
+                // Leaf
+                f(ex_node.extent_at(i))?;
+            } else {
+                // Non-leaf
+                let leaf = ex_node.extent_index_at(i).leaf();
+                let child_block = self.read_block(leaf);
+                let child_node = ExtentNode::from_bytes(&child_block.data);
+                let depth = Some(ex_node.header().depth());
+                self.check_extent_node(inode_ref, &child_node, leaf, depth)?;
+                self.extent_for_each_recursive(inode_ref, &child_node, f)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Call `f` on each block of the extent tree, children before their
+    /// parent so that `f` may free them. Stop at the first error.
+    pub(super) fn extent_for_each_tree_block<F>(&self, inode_ref: &InodeRef, mut f: F) -> Result<()>
+    where
+        F: FnMut(PBlockId) -> Result<()>,
+    {
+        let root = inode_ref.inode.extent_root();
+        self.check_extent_node(inode_ref, &root, 0, None)?;
+        self.tree_block_for_each_recursive(inode_ref, &root, &mut f)
     }
 
-    fn get_all_pblocks_recursive(&self, ex_node: &ExtentNode, pblocks: &mut Vec<PBlockId>) {
+    fn tree_block_for_each_recursive(
+        &self,
+        inode_ref: &InodeRef,
+        ex_node: &ExtentNode,
+        f: &mut dyn FnMut(PBlockId) -> Result<()>,
+    ) -> Result<()> {
         if ex_node.header().depth() == 0 {
-            // Leaf
-            for i in 0..ex_node.header().entries_count() as usize {
-                let ex = ex_node.extent_at(i);
-                for j in 0..ex.block_count() {
-                    pblocks.push(ex.start_pblock() + j as PBlockId);
-                }
+            return Ok(());
+        }
+        for i in 0..ex_node.header().entries_count() as usize {
+            let leaf = ex_node.extent_index_at(i).leaf();
+            let child_block = self.read_block(leaf);
+            let child_node = ExtentNode::from_bytes(&child_block.data);
+            let depth = Some(ex_node.header().depth());
+            self.check_extent_node(inode_ref, &child_node, leaf, depth)?;
+            self.tree_block_for_each_recursive(inode_ref, &child_node, f)?;
+            f(leaf)?;
+        }
+        Ok(())
+    }
+
+    /// Insert an extent into the extent tree. The extent must not overlap
+    /// any existing one.
+    pub(super) fn extent_insert(&self, inode_ref: &mut InodeRef, new_ext: &Extent) -> Result<()> {
+        let path = self.find_extent(inode_ref, new_ext.start_lblock())?;
+        if path.last().unwrap().index.is_ok() {
+            return_error!(
+                ErrCode::EINVAL,
+                "Block {} of inode {} is already mapped",
+                new_ext.start_lblock(),
+                inode_ref.id
+            );
+        }
+        self.insert_extent(inode_ref, &path, new_ext)
+    }
+
+    /// The allocation goal for `iblock`, to be inserted at `index` of a leaf:
+    /// the physical block `iblock` would have if the preceding extent
+    /// covered it.
+    fn extent_goal(node: &ExtentNode, index: usize, iblock: LBlockId) -> Option<PBlockId> {
+        let prev = node.extent_at(index.checked_sub(1)?);
+        Some(prev.start_pblock() + (iblock - prev.start_lblock()) as PBlockId)
+    }
+
+    /// Unmap `iblock`, which must be the last block of an extent, and
+    /// return the physical block it was mapped to. Return `None` if the
+    /// block cannot be unmapped without freeing an extent tree node, which
+    /// is not supported.
+    pub(super) fn extent_remove_last(
+        &self,
+        inode_ref: &mut InodeRef,
+        iblock: LBlockId,
+    ) -> Result<Option<PBlockId>> {
+        let path = self.find_extent(inode_ref, iblock)?;
+        // Leaf is the last element of the path
+        let leaf = path.last().unwrap();
+        let Ok(index) = leaf.index else {
+            return_error!(
+                ErrCode::EINVAL,
+                "Block {} of inode {} is not mapped",
+                iblock,
+                inode_ref.id
+            );
+        };
+        self.es_invalidate(inode_ref.id);
+        let pblock = if leaf.pblock != 0 {
+            let mut leaf_block = self.read_block(leaf.pblock);
+            let mut leaf_node = ExtentNodeMut::from_bytes(&mut leaf_block.data);
+            let pblock = Self::unmap_last_block(&mut leaf_node, index, iblock, false);
+            if pblock.is_some() {
+                self.write_extent_block(inode_ref, &mut leaf_block);
             }
+            pblock
         } else {
-            // Non-leaf
-            for i in 0..ex_node.header().entries_count() as usize {
-                let ex_idx = ex_node.extent_index_at(i);
-                let child_block = self.read_block(ex_idx.leaf());
-                let child_node = ExtentNode::from_bytes(&child_block.data);
-                self.get_all_pblocks_recursive(&child_node, pblocks);
+            let mut root = inode_ref.inode.extent_root_mut();
+            let pblock = Self::unmap_last_block(&mut root, index, iblock, true);
+            if pblock.is_some() {
+                self.write_inode(inode_ref);
             }
-        }
+            pblock
+        };
+        Ok(pblock)
     }
 
-    fn get_all_nodes_recursive(&self, ex_node: &ExtentNode, pblocks: &mut Vec<PBlockId>) {
-        if ex_node.header().depth() != 0 {
-            // Non-leaf
-            for i in 0..ex_node.header().entries_count() as usize {
-                let ex_idx = ex_node.extent_index_at(i);
-                pblocks.push(ex_idx.leaf());
-                let child_block = self.read_block(ex_idx.leaf());
-                let child_node = ExtentNode::from_bytes(&child_block.data);
-                self.get_all_nodes_recursive(&child_node, pblocks);
+    /// Unmap and free every block from `end` on, counting the freed blocks
+    /// out of `i_blocks`. The inode is not written.
+    ///
+    /// The extent tree is rebuilt from the extents kept, freeing the old
+    /// tree blocks, so emptied leaves do not linger.
+    pub(super) fn extent_truncate(&self, inode_ref: &mut InodeRef, end: LBlockId) -> Result<()> {
+        if inode_ref.inode.uses_block_map() {
+            return self.indirect_truncate(inode_ref, end);
+        }
+        if !inode_ref.inode.uses_extents() {
+            return Ok(());
+        }
+        let mut kept = Vec::new();
+        let mut freed = Vec::new();
+        for ex in self.extent_all_extents(inode_ref)? {
+            let ex_end = ex.start_lblock() + ex.block_count();
+            if ex_end <= end {
+                kept.push(ex);
+            } else if ex.start_lblock() >= end {
+                freed.push((ex.start_pblock(), ex.block_count() as u64));
+            } else {
+                let keep = end - ex.start_lblock();
+                let mut head = ex;
+                head.set_block_count(keep);
+                if ex.is_unwritten() {
+                    head.mark_unwritten();
+                }
+                kept.push(head);
+                freed.push((ex.start_pblock() + keep as PBlockId, (ex_end - end) as u64));
             }
         }
+        if freed.is_empty() {
+            return Ok(());
+        }
+
+        // Free the old blocks first, so that rebuilding a tree no larger
+        // than the old one cannot run out of space
+        let old_tree_blocks = self.extent_all_tree_blocks(inode_ref)?;
+        self.es_invalidate(inode_ref.id);
+        for &(pblock, count) in &freed {
+            self.dealloc_blocks(inode_ref, pblock, count)?;
+        }
+        for &pblock in &old_tree_blocks {
+            self.dealloc_blocks(inode_ref, pblock, 1)?;
+        }
+        // The blocks of the new tree are counted as they are allocated
+        let freed_count = freed.iter().map(|(_, count)| count).sum::<u64>();
+        let block_count = inode_ref.inode.fs_block_count();
+        inode_ref.inode.set_fs_block_count(
+            block_count.saturating_sub(old_tree_blocks.len() as u64 + freed_count),
+        );
+        inode_ref.inode.extent_root_mut().init(0, 0);
+        for ex in &kept {
+            self.extent_insert(inode_ref, ex)?;
+        }
+        Ok(())
+    }
+
+    /// Shorten the extent at `index` of a leaf node by its last block
+    /// `iblock`, removing the extent if it becomes empty. The node is left
+    /// empty only if `may_empty` is set.
+    fn unmap_last_block(
+        node: &mut ExtentNodeMut,
+        index: usize,
+        iblock: LBlockId,
+        may_empty: bool,
+    ) -> Option<PBlockId> {
+        let ex = *node.extent_at(index);
+        if ex.start_lblock() + ex.block_count() != iblock + 1 {
+            return None;
+        }
+        if ex.block_count() > 1 {
+            node.extent_mut_at(index)
+                .set_block_count(ex.block_count() - 1);
+        } else if node.header().entries_count() > 1 || may_empty {
+            node.remove_extent(index);
+        } else {
+            return None;
+        }
+        Some(ex.start_pblock() + (iblock - ex.start_lblock()) as PBlockId)
     }
 
     /// Find the given logic block id in the extent tree, return the search path
-    fn find_extent(&self, inode_ref: &InodeRef, iblock: LBlockId) -> Vec<ExtentSearchStep> {
+    ///
+    /// # Error
+    ///
+    /// `EFSCORRUPTED` if a node on the path is malformed.
+    fn find_extent(&self, inode_ref: &InodeRef, iblock: LBlockId) -> Result<Vec<ExtentSearchStep>> {
         let mut path: Vec<ExtentSearchStep> = Vec::new();
         let mut ex_node = inode_ref.inode.extent_root();
         let mut pblock = 0;
         let mut block_data: Block;
+        self.check_extent_node(inode_ref, &ex_node, 0, None)?;
 
         // Go until leaf
         while ex_node.header().depth() > 0 {
-            let index = ex_node.search_extent_index(iblock).expect("Must succeed");
+            let depth = ex_node.header().depth();
+            let Ok(index) = ex_node.search_extent_index(iblock) else {
+                // Checked index nodes are not empty
+                return_error!(
+                    ErrCode::EFSCORRUPTED,
+                    "Empty extent index node in block {} of inode {}",
+                    pblock,
+                    inode_ref.id
+                );
+            };
             path.push(ExtentSearchStep::new(pblock, Ok(index)));
             // Get the target extent index
             let ex_idx = ex_node.extent_index_at(index);
@@ -160,16 +557,48 @@ impl Ext4 {
             block_data = self.read_block(next);
             // Load the next extent header
             ex_node = ExtentNode::from_bytes(&block_data.data);
+            self.check_extent_node(inode_ref, &ex_node, next, Some(depth))?;
             pblock = next;
         }
         // Leaf
         let index = ex_node.search_extent(iblock);
         path.push(ExtentSearchStep::new(pblock, index));
 
-        path
+        Ok(path)
     }
 
-    /// Insert a new extent into the extent tree.
+    /// Check a node of the extent tree of an inode, stored in `pblock` or
+    /// in the inode if 0, whose parent has depth `parent_depth`.
+    ///
+    /// # Error
+    ///
+    /// `EFSCORRUPTED` if the node is malformed, see `ExtentNode::is_valid`.
+    fn check_extent_node(
+        &self,
+        inode_ref: &InodeRef,
+        node: &ExtentNode,
+        pblock: PBlockId,
+        parent_depth: Option<u16>,
+    ) -> Result<()> {
+        if node.is_valid(parent_depth) {
+            return Ok(());
+        }
+        let err = format_error!(
+            ErrCode::EFSCORRUPTED,
+            "Invalid extent node in block {} of inode {}",
+            pblock,
+            inode_ref.id
+        );
+        Err(self.fs_error("check_extent_node", inode_ref.id, pblock, err))
+    }
+
+    /// Insert a new extent into the extent tree. The blocks that splitting
+    /// nodes may take are checked to be free first, so that running out of
+    /// space leaves the tree unchanged.
+    ///
+    /// # Error
+    ///
+    /// `ENOSPC` - not enough free blocks to split the full nodes
     fn insert_extent(
         &self,
         inode_ref: &mut InodeRef,
@@ -177,12 +606,17 @@ impl Ext4 {
         new_ext: &Extent,
     ) -> Result<()> {
         let leaf = path.last().unwrap();
+        self.check_free_blocks(self.extent_split_blocks(inode_ref, path))?;
+        self.es_invalidate(inode_ref.id);
+        self.lower_index_starts(inode_ref, path, new_ext.start_lblock());
         // 1. Check If leaf is root
         if leaf.pblock == 0 {
+            Self::extent_check_overlap(inode_ref, &inode_ref.inode.extent_root(), new_ext)
+                .map_err(|err| self.fs_error("insert_extent", inode_ref.id, 0, err))?;
             let mut leaf_node = inode_ref.inode.extent_root_mut();
             // Insert the extent
             let res = leaf_node.insert_extent(new_ext, leaf.index.unwrap_err());
-            self.write_inode_without_csum(inode_ref);
+            self.write_inode(inode_ref);
             // Handle split
             return if let Err(split) = res {
                 self.split_root(inode_ref, &split)
@@ -192,17 +626,23 @@ impl Ext4 {
         }
         // 2. Leaf is not root, load the leaf node
         let mut leaf_block = self.read_block(leaf.pblock);
+        Self::extent_check_overlap(
+            inode_ref,
+            &ExtentNode::from_bytes(&leaf_block.data),
+            new_ext,
+        )
+        .map_err(|err| self.fs_error("insert_extent", inode_ref.id, leaf.pblock, err))?;
         let mut leaf_node = ExtentNodeMut::from_bytes(&mut leaf_block.data);
         // Insert the extent
         let res = leaf_node.insert_extent(new_ext, leaf.index.unwrap_err());
-        self.write_block(&leaf_block);
+        self.write_extent_block(inode_ref, &mut leaf_block);
         // Handle split
         if let Err(mut split) = res {
             // Handle split until root
             for parent in path.iter().rev().skip(1) {
                 // The split node is at `parent.index.unwrap()`
                 // Call `self.split` to store the split part and update `parent`
-                let res = self.split(inode_ref, parent.pblock, parent.index.unwrap(), &split);
+                let res = self.split(inode_ref, parent.pblock, parent.index.unwrap(), &split)?;
                 // Handle split again
                 if let Err(split_again) = res {
                     // Insertion to parent also causes split, continue to solve
@@ -218,6 +658,116 @@ impl Ext4 {
         }
     }
 
+    /// The number of blocks inserting an entry at the end of `path` may
+    /// allocate: one for each full node from the leaf up, and two for the
+    /// root if all of them are full.
+    fn extent_split_blocks(&self, inode_ref: &InodeRef, path: &[ExtentSearchStep]) -> u64 {
+        let mut count = 0;
+        for step in path.iter().rev() {
+            // Note: block data must be defined here to keep it alive
+            let block_data: Block;
+            let node = if step.pblock != 0 {
+                block_data = self.read_block(step.pblock);
+                ExtentNode::from_bytes(&block_data.data)
+            } else {
+                inode_ref.inode.extent_root()
+            };
+            if node.header().entries_count() < node.header().max_entries_count() {
+                break;
+            }
+            count += if step.pblock != 0 { 1 } else { 2 };
+        }
+        count
+    }
+
+    /// Lower the start of the extent indices on `path` to `lblock` where
+    /// they start after it, as when mapping a block before the first
+    /// extent of the leftmost leaf.
+    fn lower_index_starts(
+        &self,
+        inode_ref: &mut InodeRef,
+        path: &[ExtentSearchStep],
+        lblock: LBlockId,
+    ) {
+        for step in &path[..path.len() - 1] {
+            let index = step.index.unwrap();
+            if step.pblock == 0 {
+                let mut root = inode_ref.inode.extent_root_mut();
+                let ex_idx = root.extent_index_mut_at(index);
+                if ex_idx.start_lblock() > lblock {
+                    ex_idx.set_start_lblock(lblock);
+                    self.write_inode(inode_ref);
+                }
+            } else {
+                let mut block = self.read_block(step.pblock);
+                let mut node = ExtentNodeMut::from_bytes(&mut block.data);
+                let ex_idx = node.extent_index_mut_at(index);
+                if ex_idx.start_lblock() > lblock {
+                    ex_idx.set_start_lblock(lblock);
+                    self.write_extent_block(inode_ref, &mut block);
+                }
+            }
+        }
+    }
+
+    /// Check that a new extent overlaps no extent in the leaf node it is
+    /// inserted into, neither in logical nor in physical blocks. Inserting
+    /// an overlapping extent would map blocks twice and corrupt data.
+    ///
+    /// # Error
+    ///
+    /// `EFSCORRUPTED` if the new extent overlaps an existing one.
+    fn extent_check_overlap(
+        inode_ref: &InodeRef,
+        leaf_node: &ExtentNode,
+        new_ext: &Extent,
+    ) -> Result<()> {
+        let new_lblock = new_ext.start_lblock() as u64;
+        let new_pblock = new_ext.start_pblock();
+        let len = new_ext.block_count() as u64;
+        for i in 0..leaf_node.header().entries_count() as usize {
+            let ex = leaf_node.extent_at(i);
+            if ex.is_unwritten() {
+                continue;
+            }
+            let ex_lblock = ex.start_lblock() as u64;
+            let ex_pblock = ex.start_pblock();
+            let ex_len = ex.block_count() as u64;
+            if new_lblock < ex_lblock + ex_len && ex_lblock < new_lblock + len {
+                return_error!(
+                    ErrCode::EFSCORRUPTED,
+                    "Inode {} extent (lblock {}, len {}) overlaps logical blocks of extent (lblock {}, len {})",
+                    inode_ref.id,
+                    new_lblock,
+                    len,
+                    ex_lblock,
+                    ex_len
+                );
+            }
+            if new_pblock < ex_pblock + ex_len && ex_pblock < new_pblock + len {
+                return_error!(
+                    ErrCode::EFSCORRUPTED,
+                    "Inode {} extent (pblock {}, len {}) overlaps physical blocks of extent (pblock {}, len {})",
+                    inode_ref.id,
+                    new_pblock,
+                    len,
+                    ex_pblock,
+                    ex_len
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocate a block for a new extent tree node. Like data blocks, it is
+    /// counted in the block count of the inode.
+    fn alloc_tree_block(&self, inode_ref: &mut InodeRef) -> Result<PBlockId> {
+        let pblock = self.alloc_block(inode_ref)?;
+        let block_count = inode_ref.inode.fs_block_count() + 1;
+        inode_ref.inode.set_fs_block_count(block_count);
+        Ok(pblock)
+    }
+
     /// Split an extent node. Given the block id where the parent node is
     /// stored, and the child position that `parent_node.extent_at(child_pos)`
     /// points to the child.
@@ -225,15 +775,18 @@ impl Ext4 {
     /// The child node has already been split by calling `insert_extent` or
     /// `insert_extent_index`, and the split part is stored in `split`.
     /// This function will create a new leaf node to store the split part.
+    ///
+    /// Return `Ok(Err(split))` if the parent node is split in turn.
     fn split(
         &self,
         inode_ref: &mut InodeRef,
         parent_pblock: PBlockId,
         child_pos: usize,
         split: &[FakeExtent],
-    ) -> core::result::Result<(), Vec<FakeExtent>> {
-        let right_bid = self.alloc_block(inode_ref).unwrap();
-        let mut right_block = self.read_block(right_bid);
+    ) -> Result<core::result::Result<(), Vec<FakeExtent>>> {
+        self.stats.extent_split();
+        let right_bid = self.alloc_tree_block(inode_ref)?;
+        let mut right_block = Block::new(right_bid, [0; BLOCK_SIZE]);
         let mut right_node = ExtentNodeMut::from_bytes(&mut right_block.data);
 
         // Insert the split half to right node
@@ -255,21 +808,21 @@ impl Ext4 {
             let mut parent_node = inode_ref.inode.extent_root_mut();
             parent_depth = parent_node.header().depth();
             res = parent_node.insert_extent_index(&extent_index, child_pos + 1);
-            self.write_inode_without_csum(inode_ref);
+            self.write_inode(inode_ref);
         } else {
             // Parent is not root
             let mut parent_block = self.read_block(parent_pblock);
             let mut parent_node = ExtentNodeMut::from_bytes(&mut parent_block.data);
             parent_depth = parent_node.header().depth();
             res = parent_node.insert_extent_index(&extent_index, child_pos + 1);
-            self.write_block(&parent_block);
+            self.write_extent_block(inode_ref, &mut parent_block);
         }
 
         // Right node is the child of parent, so its depth is 1 less than parent
         right_node.header_mut().set_depth(parent_depth - 1);
-        self.write_block(&right_block);
+        self.write_extent_block(inode_ref, &mut right_block);
 
-        res
+        Ok(res)
     }
 
     /// Split the root extent node. This function will create 2 new leaf
@@ -279,11 +832,12 @@ impl Ext4 {
     /// `insert_extent_index`, and the split part is stored in `split`.
     /// This function will create a new leaf node to store the split part.
     fn split_root(&self, inode_ref: &mut InodeRef, split: &[FakeExtent]) -> Result<()> {
+        self.stats.extent_split();
         // Create left and right blocks
-        let l_bid = self.alloc_block(inode_ref)?;
-        let r_bid = self.alloc_block(inode_ref)?;
-        let mut l_block = self.read_block(l_bid);
-        let mut r_block = self.read_block(r_bid);
+        let l_bid = self.alloc_tree_block(inode_ref)?;
+        let r_bid = self.alloc_tree_block(inode_ref)?;
+        let mut l_block = Block::new(l_bid, [0; BLOCK_SIZE]);
+        let mut r_block = Block::new(r_bid, [0; BLOCK_SIZE]);
 
         // Load root, left, right nodes
         let mut root = inode_ref.inode.extent_root_mut();
@@ -313,10 +867,24 @@ impl Ext4 {
         *root.extent_index_mut_at(1) = ExtentIndex::new(right.extent_at(0).start_lblock(), r_bid);
 
         // Sync to disk
-        self.write_block(&l_block);
-        self.write_block(&r_block);
-        self.write_inode_without_csum(inode_ref);
+        self.write_extent_block(inode_ref, &mut l_block);
+        self.write_extent_block(inode_ref, &mut r_block);
+        self.write_inode(inode_ref);
 
         Ok(())
     }
+
+    /// Write a non-root extent node to disk, with the tail checksum if
+    /// `metadata_csum` is enabled.
+    fn write_extent_block(&self, inode_ref: &InodeRef, block: &mut Block) {
+        let sb = self.read_super_block();
+        if sb.has_metadata_csum() {
+            ExtentNodeMut::from_bytes(&mut block.data).set_checksum(
+                sb.checksum_seed(),
+                inode_ref.id,
+                inode_ref.inode.generation(),
+            );
+        }
+        self.write_block(block);
+    }
 }
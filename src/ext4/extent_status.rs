@@ -0,0 +1,137 @@
+//! Extent status cache.
+//!
+//! The results of extent tree lookups are cached per inode, holes
+//! included, so that repeated random accesses to a file do not walk its
+//! extent tree again. Only mappings are cached here, the extent tree
+//! blocks themselves are left to the block cache.
+//!
+//! The entries of an inode are dropped whenever its extent tree changes,
+//! and all entries whenever blocks are reverted behind the filesystem's
+//! back (transaction abort, snapshot rollback). The mapping invalidator is
+//! told about both, see `Ext4::set_mapping_invalidator`.
+
+use super::Ext4;
+use crate::prelude::*;
+
+/// Default maximum number of cached extents and holes, across all inodes.
+const EXTENT_STATUS_MAX: usize = 4096;
+
+/// A cached range of logical blocks, keyed by its first block.
+#[derive(Debug, Clone, Copy)]
+struct ExtentStatus {
+    /// Number of blocks in the range
+    len: u32,
+    /// Physical block of the first block, `None` for a hole
+    pblock: Option<PBlockId>,
+}
+
+/// The extent status trees of all inodes.
+pub(super) struct ExtentStatusCache {
+    inodes: BTreeMap<InodeId, BTreeMap<LBlockId, ExtentStatus>>,
+    /// Total number of cached ranges
+    count: usize,
+    /// Maximum number of cached ranges
+    max: usize,
+}
+
+impl Default for ExtentStatusCache {
+    fn default() -> Self {
+        Self {
+            inodes: BTreeMap::new(),
+            count: 0,
+            max: EXTENT_STATUS_MAX,
+        }
+    }
+}
+
+impl ExtentStatusCache {
+    /// Approximate memory used by a cached range, including the tree node
+    /// overhead.
+    const ENTRY_SIZE: usize = 2 * (size_of::<LBlockId>() + size_of::<ExtentStatus>());
+
+    /// Look up a logical block. `Some(None)` is a cached hole, `None` a miss.
+    fn lookup(&self, inode: InodeId, iblock: LBlockId) -> Option<Option<PBlockId>> {
+        let (&start, es) = self.inodes.get(&inode)?.range(..=iblock).next_back()?;
+        if iblock - start >= es.len {
+            return None;
+        }
+        Some(
+            es.pblock
+                .map(|pblock| pblock + (iblock - start) as PBlockId),
+        )
+    }
+
+    fn insert(&mut self, inode: InodeId, start: LBlockId, es: ExtentStatus) {
+        if self.max == 0 {
+            return;
+        }
+        if self.count >= self.max {
+            // Cached ranges are cheap to rebuild, start over
+            self.clear();
+        }
+        if self
+            .inodes
+            .entry(inode)
+            .or_default()
+            .insert(start, es)
+            .is_none()
+        {
+            self.count += 1;
+        }
+    }
+
+    fn invalidate(&mut self, inode: InodeId) {
+        if let Some(tree) = self.inodes.remove(&inode) {
+            self.count -= tree.len();
+        }
+    }
+
+    fn clear(&mut self) {
+        self.inodes.clear();
+        self.count = 0;
+    }
+
+    /// Cap the memory of the cache at about `bytes`.
+    pub(super) fn set_limit(&mut self, bytes: usize) {
+        self.max = bytes / Self::ENTRY_SIZE;
+        if self.count > self.max {
+            self.clear();
+        }
+    }
+}
+
+impl Ext4 {
+    /// Look up a logical block of an inode in the extent status cache.
+    /// `Some(None)` means the block is known to be a hole.
+    pub(super) fn es_lookup(&self, inode: InodeId, iblock: LBlockId) -> Option<Option<PBlockId>> {
+        self.extent_status.lock().lookup(inode, iblock)
+    }
+
+    /// Cache `len` blocks of an inode starting at `start`, mapped from
+    /// `pblock` on, or a hole if `pblock` is `None`.
+    pub(super) fn es_insert(
+        &self,
+        inode: InodeId,
+        start: LBlockId,
+        len: u32,
+        pblock: Option<PBlockId>,
+    ) {
+        if len > 0 {
+            self.extent_status
+                .lock()
+                .insert(inode, start, ExtentStatus { len, pblock });
+        }
+    }
+
+    /// Drop the cached ranges of an inode, whose block mapping is changing.
+    pub(super) fn es_invalidate(&self, inode: InodeId) {
+        self.extent_status.lock().invalidate(inode);
+        self.invalidate_mappings(Some(inode));
+    }
+
+    /// Drop all cached ranges.
+    pub(super) fn es_clear(&self) {
+        self.extent_status.lock().clear();
+        self.invalidate_mappings(None);
+    }
+}
@@ -0,0 +1,29 @@
+//! Leaving an image in a state that Linux accepts.
+//!
+//! `Ext4::finalize` writes back everything and marks the filesystem clean,
+//! so `e2fsck -f -n` can be run on the image, e.g. in tests, to check that
+//! the crate wrote consistent metadata.
+
+use super::Ext4;
+use crate::ext4_defs::*;
+
+impl Ext4 {
+    /// Write back all pending updates, mark the filesystem as cleanly
+    /// unmounted in the superblock and flush the block device.
+    ///
+    /// The instance stays usable, but the state is not marked dirty again
+    /// by later writes, so call this only when no more writes follow,
+    /// e.g. before an external tool inspects the image. See also
+    /// `Ext4::unmount`.
+    pub fn finalize(&self) {
+        // An image with unsupported features is never written
+        if !self.unsupported.is_empty() {
+            return;
+        }
+        self.record_written_blocks();
+        let mut sb = self.read_super_block();
+        sb.set_state(SuperBlock::STATE_VALID | (sb.state() & SuperBlock::STATE_ERROR));
+        self.write_super_block(&sb);
+        self.flush_device();
+    }
+}
@@ -9,6 +9,7 @@
 //! implement more complex operations.
 
 use super::Ext4;
+use crate::constants::*;
 use crate::ext4_defs::*;
 use crate::prelude::*;
 use crate::return_error;
@@ -30,13 +31,12 @@ impl Ext4 {
     /// * `ENOTDIR` - Any parent along `path` is not a directory.
     /// * `ENOENT` - The object does not exist.
     pub fn generic_lookup(&self, root: InodeId, path: &str) -> Result<InodeId> {
-        trace!("generic_lookup({}, {})", root, path);
+        log_dir!(trace, "generic_lookup({}, {})", root, path);
         // Search from the given parent inode
         let mut cur = root;
-        let search_path = Self::split_path(path);
         // Search recursively
-        for path in search_path.iter() {
-            cur = self.lookup(cur, path)?;
+        for name in Self::split_path(path) {
+            cur = self.lookup(cur, name)?;
         }
         Ok(cur)
     }
@@ -60,20 +60,26 @@ impl Ext4 {
     ///
     /// * `ENOTDIR` - Any parent along `path` is not a directory.
     /// * `EEXIST` - The object already exists.
+    /// * `ENAMETOOLONG` - A component of `path` is longer than `NAME_MAX`.
     pub fn generic_create(&self, root: InodeId, path: &str, mode: InodeMode) -> Result<InodeId> {
         // Search from the given parent inode
         let mut cur = self.read_inode(root);
-        let search_path = Self::split_path(path);
+        // Validate all components before creating anything
+        for name in Self::split_path(path) {
+            Self::dir_check_name(name)?;
+        }
+        let mut components = Self::split_path(path).peekable();
         // Search recursively
-        for (i, path) in search_path.iter().enumerate() {
+        while let Some(name) = components.next() {
+            let is_last = components.peek().is_none();
             if !cur.inode.is_dir() {
                 return_error!(ErrCode::ENOTDIR, "Parent {} is not a directory", cur.id);
             }
-            match self.dir_find_entry(&cur, path) {
+            match self.dir_find_entry(&cur, name) {
                 Ok(id) => {
-                    if i == search_path.len() - 1 {
+                    if is_last {
                         // Reach the object and it already exists
-                        return_error!(ErrCode::EEXIST, "Object {}/{} already exists", root, path);
+                        return_error!(ErrCode::EEXIST, "Object {}/{} already exists", root, name);
                     }
                     cur = self.read_inode(id);
                 }
@@ -81,14 +87,25 @@ impl Ext4 {
                     if e.code() != ErrCode::ENOENT {
                         return_error!(e.code(), "Unexpected error: {:?}", e);
                     }
-                    let mut child = if i == search_path.len() - 1 {
+                    self.check_dir_write(&cur)?;
+                    let mut child = if is_last {
                         // Reach the object, create it
-                        self.create_inode(mode)?
+                        self.create_inode(cur.id, self.apply_umask(mode))?
                     } else {
                         // Create parent directory
-                        self.create_inode(InodeMode::DIRECTORY | InodeMode::ALL_RWX)?
+                        let mode = InodeMode::DIRECTORY | InodeMode::ALL_RWX;
+                        self.create_inode(cur.id, self.apply_umask(mode))?
                     };
-                    self.link_inode(&mut cur, &mut child, path)?;
+                    self.init_owner(&cur, &mut child);
+                    if child.inode.is_dir() {
+                        // Add "." entry
+                        let child_self = child.clone();
+                        self.dir_add_entry(&mut child, &child_self, ".")
+                            .map_err(|err| self.free_new_inode(&mut child, err))?;
+                        child.inode.set_link_count(1);
+                    }
+                    self.link_inode(&mut cur, &mut child, name)
+                        .map_err(|err| self.free_new_inode(&mut child, err))?;
                     cur = child;
                 }
             }
@@ -107,19 +124,25 @@ impl Ext4 {
     ///
     /// * `ENOENT` - The object does not exist.
     /// * `ENOTEMPTY` - The object is a non-empty directory.
+    /// * `EPERM` - The object or its parent is immutable or append-only.
+    /// * `ENOKEY` - The parent is encrypted.
+    /// * `EBUSY` - The last link of a file with pinned blocks is removed.
     pub fn generic_remove(&self, root: InodeId, path: &str) -> Result<()> {
         // Get the parent directory path and the file name
-        let mut search_path = Self::split_path(path);
-        let file_name = &search_path.split_off(search_path.len() - 1)[0];
-        let parent_path = search_path.join("/");
+        let (parent_path, file_name) = Self::split_parent(path);
         // Get the parent directory inode
-        let parent_id = self.generic_lookup(root, &parent_path)?;
+        let parent_id = self.generic_lookup(root, parent_path)?;
         // Get the child inode
         let child_id = self.lookup(parent_id, file_name)?;
         let mut parent = self.read_inode(parent_id);
         let mut child = self.read_inode(child_id);
+        self.check_dir_write(&parent)?;
+        self.check_sticky(&parent, &child)?;
+        Self::check_modify(&parent, false)?;
+        Self::check_modify(&child, false)?;
+        Self::check_unencrypted(&parent)?;
         // Check if child is a non-empty directory
-        if child.inode.is_dir() && self.dir_list_entries(&child).len() > 2 {
+        if child.inode.is_dir() && !self.dir_is_empty(&child)? {
             return_error!(ErrCode::ENOTEMPTY, "Directory {} not empty", path);
         }
         // Unlink the file
@@ -141,25 +164,368 @@ impl Ext4 {
     /// * `EEXIST` - The destination object already exists.
     pub fn generic_rename(&self, root: InodeId, src: &str, dst: &str) -> Result<()> {
         // Parse the directories and file names
-        let mut src_path = Self::split_path(src);
-        let src_file_name = &src_path.split_off(src_path.len() - 1)[0];
-        let src_parent_path = src_path.join("/");
-        let mut dst_path = Self::split_path(dst);
-        let dst_file_name = &dst_path.split_off(dst_path.len() - 1)[0];
-        let dst_parent_path = dst_path.join("/");
+        let (src_parent_path, src_file_name) = Self::split_parent(src);
+        let (dst_parent_path, dst_file_name) = Self::split_parent(dst);
         // Get source and des inodes
-        let src_parent_id = self.generic_lookup(root, &src_parent_path)?;
-        let dst_parent_id = self.generic_lookup(root, &dst_parent_path)?;
+        let src_parent_id = self.generic_lookup(root, src_parent_path)?;
+        let dst_parent_id = self.generic_lookup(root, dst_parent_path)?;
         // Move the file
         self.rename(src_parent_id, src_file_name, dst_parent_id, dst_file_name)
     }
 
-    /// A helper function to split a path by '/'
-    fn split_path(path: &str) -> Vec<String> {
-        let path = path.trim_start_matches("/");
-        if path.is_empty() {
-            return vec![]; // root
+    /// Replace the content of a file atomically. Readers either see the
+    /// old content or the new content, never a partially written file.
+    ///
+    /// The data is written to a new unlinked inode, and then the directory
+    /// entry is switched to the new inode with a single block update, in
+    /// one transaction if the filesystem has a journal. The new file
+    /// inherits the mode and owner of the old file, if any. If the file
+    /// does not exist, it is created. Handles open on the old file keep
+    /// reading the old content, which is freed when the last one is
+    /// released.
+    ///
+    /// # Params
+    ///
+    /// * `root` - The inode id of the starting directory for search.
+    /// * `path` - The relative path of the file to write.
+    /// * `data` - The new content of the file.
+    ///
+    /// # Return
+    ///
+    /// `Ok(inode)` - Inode id of the new file
+    ///
+    /// # Error
+    ///
+    /// * `ENOTDIR` - Any parent in the path is not a directory.
+    /// * `ENOENT` - The parent directory does not exist.
+    /// * `EISDIR` - The object is not a regular file.
+    /// * `EPERM` - The object is immutable or append-only, or it does not
+    ///   exist and the parent is immutable.
+    /// * `ENOSPC` - No space left on device.
+    /// * `ENOKEY` - The parent is encrypted.
+    /// * `EBUSY` - The object has pinned blocks and would be freed.
+    pub fn generic_write_atomic(&self, root: InodeId, path: &str, data: &[u8]) -> Result<InodeId> {
+        let (parent_path, file_name) = Self::split_parent(path);
+        let parent_id = self.generic_lookup(root, parent_path)?;
+        let mut parent = self.read_inode(parent_id);
+        if !parent.inode.is_dir() {
+            return_error!(ErrCode::ENOTDIR, "Inode {} is not a directory", parent.id);
+        }
+        Self::dir_check_name(file_name)?;
+        self.check_dir_write(&parent)?;
+        // Check the old file
+        let old = match self.dir_find_entry(&parent, file_name) {
+            Ok(id) => Some(self.read_inode(id)),
+            Err(e) if e.code() == ErrCode::ENOENT => None,
+            Err(e) => return Err(e),
+        };
+        let mode = match &old {
+            Some(old) if !old.inode.is_file() => {
+                return_error!(ErrCode::EISDIR, "Inode {} is not a file", old.id);
+            }
+            Some(old) => old.inode.mode(),
+            None => InodeMode::FILE | InodeMode::ALL_RW,
+        };
+        // Replacing a file modifies it, creating one appends to the parent
+        match &old {
+            Some(old) => Self::check_modify(old, false)?,
+            None => Self::check_modify(&parent, true)?,
+        }
+        Self::check_unencrypted(&parent)?;
+        if let Some(old) = old.as_ref().filter(|old| old.inode.link_count() <= 1) {
+            // The old file is freed once replaced
+            self.check_unpinned(old.id, 0)?;
+        }
+        // Write data to a new unlinked file
+        let tmp = self.create_unlinked(mode)?;
+        let mut child = self.read_inode(tmp);
+        let res = self.write_inode_data(&mut child, 0, IoVec::new(&[data]), false);
+        let res = res.and_then(|_| {
+            // Take the new file off the orphan list and link it in one
+            // transaction, so that a crash leaves either file in place
+            let swap = |fs: &Self| fs.write_atomic_swap(&mut parent, file_name, old, tmp);
+            if self.read_super_block().journal_inode() != 0 {
+                self.with_transaction(swap)
+            } else {
+                swap(self)
+            }
+        });
+        if let Err(e) = res {
+            let mut child = self.read_inode(tmp);
+            self.orphan_remove(&mut child)?;
+            self.free_inode(&mut child)?;
+            return Err(e);
+        }
+        Ok(tmp)
+    }
+
+    /// Link the new file `tmp` of `generic_write_atomic` in place of `old`,
+    /// or as a new entry if there is no old file.
+    ///
+    /// The old file is freed if this was its last link. If it is still
+    /// open, it is put on the orphan list instead and freed by the last
+    /// `release`, so that its readers keep seeing the old content.
+    fn write_atomic_swap(
+        &self,
+        parent: &mut InodeRef,
+        file_name: &str,
+        old: Option<InodeRef>,
+        tmp: InodeId,
+    ) -> Result<()> {
+        let mut child = self.read_inode(tmp);
+        self.orphan_remove(&mut child)?;
+        let Some(mut old) = old else {
+            return self.link_inode(parent, &mut child, file_name);
+        };
+        // The new file replaces the old one, keep its mode unmasked
+        child.inode.set_mode(old.inode.mode());
+        child.inode.set_uid(old.inode.uid());
+        child.inode.set_gid(old.inode.gid());
+        child.inode.set_link_count(1);
+        self.write_inode(&mut child);
+        // Switch the directory entry to the new file
+        self.dir_replace_entry(parent, file_name, &child)?;
+        // Drop the link of the old file
+        let link_count = old.inode.link_count();
+        if link_count > 1 {
+            old.inode.set_link_count(link_count - 1);
+            self.write_inode(&mut old);
+        } else if self.is_open(old.id) {
+            old.inode.set_link_count(0);
+            self.orphan_add(&mut old);
+        } else {
+            self.free_inode(&mut old)?;
+        }
+        Ok(())
+    }
+
+    /// Remove an object and, if it is a directory, everything under it.
+    ///
+    /// The tree is removed depth-first with an explicit stack, one entry
+    /// at a time through `unlink` and `rmdir`. Nothing is held across
+    /// entries, so the removal can be wrapped in `with_transaction` to make
+    /// it atomic, as long as it fits in the journal. Otherwise an error
+    /// leaves the tree partially removed.
+    ///
+    /// # Params
+    ///
+    /// * `root` - The inode id of the starting directory for search.
+    /// * `path` - The relative path of the object to remove.
+    ///
+    /// # Error
+    ///
+    /// * `ENOTDIR` - Any parent along `path` is not a directory.
+    /// * `ENOENT` - The object does not exist.
+    /// * `EPERM` - An object in the tree or its parent is immutable or
+    ///   append-only.
+    pub fn generic_remove_recursive(&self, root: InodeId, path: &str) -> Result<()> {
+        let (parent_path, name) = Self::split_parent(path);
+        let parent = self.generic_lookup(root, parent_path)?;
+        let target = self.lookup(parent, name)?;
+        if self.getattr(target)?.ftype != FileType::Directory {
+            return self.unlink(parent, name);
+        }
+        // Directories to remove, and whether their entries are removed or
+        // pushed above them
+        let mut stack = vec![(parent, name.to_string(), target, false)];
+        while let Some((parent, name, dir, expanded)) = stack.pop() {
+            if expanded {
+                self.rmdir(parent, &name)?;
+                continue;
+            }
+            let entries = self.listdir_attrs(dir)?;
+            stack.push((parent, name, dir, true));
+            for (entry, attr) in entries {
+                let entry_name = entry.name();
+                if entry_name == "." || entry_name == ".." {
+                    continue;
+                }
+                if attr.ftype == FileType::Directory {
+                    stack.push((dir, entry_name, attr.ino, false));
+                } else {
+                    self.unlink(dir, &entry_name)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy an object and, if it is a directory, everything under it.
+    ///
+    /// Copies get the permission bits of their source, masked by the
+    /// umask, and are owned by the caller. File data, symlink targets,
+    /// device numbers and extended attributes are copied, hard links
+    /// within the tree become separate files. As with `generic_remove_recursive`, the copy is made one
+    /// entry at a time and can be wrapped in `with_transaction`.
+    ///
+    /// # Params
+    ///
+    /// * `root` - The inode id of the starting directory for search.
+    /// * `src` - The relative path of the object to copy.
+    /// * `dst` - The relative path of the copy.
+    ///
+    /// # Return
+    ///
+    /// `Ok(inode)` - Inode id of the copy
+    ///
+    /// # Error
+    ///
+    /// * `ENOTDIR` - Any parent in the path is not a directory.
+    /// * `ENOENT` - The source object or the parent of `dst` does not exist.
+    /// * `EEXIST` - The destination object already exists.
+    /// * `EINVAL` - `dst` is inside the directory `src`.
+    /// * `ENOSPC` - No space left on device.
+    pub fn generic_copy(&self, root: InodeId, src: &str, dst: &str) -> Result<InodeId> {
+        let src_id = self.generic_lookup(root, src)?;
+        let (dst_parent_path, dst_name) = Self::split_parent(dst);
+        let dst_parent = self.generic_lookup(root, dst_parent_path)?;
+        match self.lookup(dst_parent, dst_name) {
+            Ok(_) => {
+                return_error!(ErrCode::EEXIST, "Object {} already exists", dst);
+            }
+            Err(e) if e.code() == ErrCode::ENOENT => {}
+            Err(e) => return Err(e),
+        }
+        let attr = self.getattr(src_id)?;
+        if attr.ftype == FileType::Directory && self.is_ancestor(src_id, dst_parent)? {
+            return_error!(ErrCode::EINVAL, "Cannot copy {} into itself", src);
+        }
+        let copy = self.copy_object(src_id, &attr, dst_parent, dst_name)?;
+        // Directories to copy the entries of, and their copies
+        let mut stack = Vec::new();
+        if attr.ftype == FileType::Directory {
+            stack.push((src_id, copy));
+        }
+        while let Some((from, to)) = stack.pop() {
+            for (entry, attr) in self.listdir_attrs(from)? {
+                let name = entry.name();
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let child = self.copy_object(attr.ino, &attr, to, &name)?;
+                if attr.ftype == FileType::Directory {
+                    stack.push((attr.ino, child));
+                }
+            }
+        }
+        Ok(copy)
+    }
+
+    /// Create `parent/name` as a copy of `src`, without the entries of a
+    /// directory.
+    fn copy_object(
+        &self,
+        src: InodeId,
+        attr: &FileAttr,
+        parent: InodeId,
+        name: &str,
+    ) -> Result<InodeId> {
+        let mode = InodeMode::from_type_and_perm(attr.ftype, attr.perm);
+        let copy = match attr.ftype {
+            FileType::Directory => self.mkdir(parent, name, mode)?,
+            FileType::SymLink => {
+                let target = self.readlink(src)?;
+                self.symlink(parent, name, &String::from_utf8_lossy(&target))?
+            }
+            _ => self.create(parent, name, mode)?,
+        };
+        if matches!(attr.ftype, FileType::CharacterDev | FileType::BlockDev) {
+            let (major, minor) = self.read_inode(src).inode.device();
+            let mut inode = self.read_inode(copy);
+            inode.inode.set_device(major, minor);
+            self.write_inode(&mut inode);
+        }
+        if attr.ftype == FileType::RegularFile {
+            // Copy the data a few blocks at a time
+            let mut buf = vec![0; BLOCK_SIZE * 16];
+            let mut offset = 0;
+            while offset < attr.size {
+                let len = self.read(src, offset, &mut buf)?;
+                if len == 0 {
+                    break;
+                }
+                self.write(copy, offset, &buf[..len])?;
+                offset += len as u64;
+            }
+        }
+        for xattr in self.listxattr(src)? {
+            let value = self.getxattr(src, &xattr)?;
+            self.setxattr(copy, &xattr, &value)?;
+        }
+        Ok(copy)
+    }
+
+    /// Check if directory `dir` is `ancestor` or below it, following the
+    /// `..` entries up to the root directory.
+    fn is_ancestor(&self, ancestor: InodeId, dir: InodeId) -> Result<bool> {
+        let mut cur = dir;
+        loop {
+            if cur == ancestor {
+                return Ok(true);
+            }
+            let parent = self.lookup(cur, "..")?;
+            if parent == cur || cur == EXT4_ROOT_INO {
+                return Ok(false);
+            }
+            cur = parent;
+        }
+    }
+
+    /// Split a path by '/' into its components without allocating.
+    /// Empty components are skipped, so leading, trailing and repeated
+    /// '/' are ignored.
+    fn split_path(path: &str) -> PathComponents<'_> {
+        PathComponents::new(path)
+    }
+
+    /// Split a path into the parent directory path and the last component.
+    /// The parent path is empty if the path has only one component.
+    fn split_parent(path: &str) -> (&str, &str) {
+        let path = path.trim_end_matches('/');
+        match path.rsplit_once('/') {
+            Some((parent, name)) => (parent, name),
+            None => ("", path),
+        }
+    }
+}
+
+/// An iterator over the components of a path, borrowing from the path.
+/// Empty components are skipped, so leading, trailing and repeated '/'
+/// are ignored.
+pub struct PathComponents<'a> {
+    /// The part of the path that has not been visited
+    rest: &'a str,
+}
+
+impl<'a> PathComponents<'a> {
+    /// Split a path by '/' into its components without allocating.
+    pub fn new(path: &'a str) -> Self {
+        Self { rest: path }
+    }
+
+    /// Collect the remaining components into owned strings, for callers
+    /// that keep them after the path is dropped.
+    pub fn into_owned(self) -> Vec<String> {
+        self.map(String::from).collect()
+    }
+}
+
+impl<'a> Iterator for PathComponents<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        loop {
+            if self.rest.is_empty() {
+                return None;
+            }
+            let (name, rest) = match self.rest.split_once('/') {
+                Some((name, rest)) => (name, rest),
+                None => (self.rest, ""),
+            };
+            self.rest = rest;
+            if !name.is_empty() {
+                return Some(name);
+            }
         }
-        path.split("/").map(|s| s.to_string()).collect()
     }
 }
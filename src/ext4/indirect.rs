@@ -0,0 +1,366 @@
+//! The ext2 block map, used by inodes without an extent tree on ext2 and
+//! ext3 images.
+//!
+//! The inode holds 12 direct block pointers, followed by the single,
+//! double and triple indirect blocks. An indirect block is an array of
+//! `BLOCK_SIZE / 4` little-endian block pointers. A zero pointer is a hole.
+//! Unlike extent tree blocks, indirect blocks are counted in `i_blocks`.
+
+use super::Ext4;
+use crate::constants::*;
+use crate::ext4_defs::*;
+use crate::format_error;
+use crate::prelude::*;
+
+/// The number of direct block pointers in the inode.
+const DIRECT_BLOCKS: usize = 12;
+
+/// The number of block pointers in an indirect block.
+const PTRS_PER_BLOCK: u64 = (BLOCK_SIZE / 4) as u64;
+
+/// The path to a logical block in the block map.
+struct IndirectPath {
+    /// The pointer slot in the inode
+    slot: usize,
+    /// The pointer index in each indirect block on the way, from the top
+    indices: [usize; 3],
+    /// The number of indirect blocks on the way
+    depth: usize,
+}
+
+impl IndirectPath {
+    /// Locate a logical block in the block map.
+    ///
+    /// # Error
+    ///
+    /// `EFBIG` if the block is past the triple indirect block.
+    fn new(iblock: LBlockId) -> Result<Self> {
+        let mut rel = iblock as u64;
+        if rel < DIRECT_BLOCKS as u64 {
+            return Ok(Self {
+                slot: rel as usize,
+                indices: [0; 3],
+                depth: 0,
+            });
+        }
+        rel -= DIRECT_BLOCKS as u64;
+        let mut span = PTRS_PER_BLOCK;
+        for depth in 1..=3 {
+            if rel < span {
+                let mut indices = [0; 3];
+                for index in indices[..depth].iter_mut().rev() {
+                    *index = (rel % PTRS_PER_BLOCK) as usize;
+                    rel /= PTRS_PER_BLOCK;
+                }
+                return Ok(Self {
+                    slot: DIRECT_BLOCKS + depth - 1,
+                    indices,
+                    depth,
+                });
+            }
+            rel -= span;
+            span *= PTRS_PER_BLOCK;
+        }
+        Err(format_error!(
+            ErrCode::EFBIG,
+            "Block {} is past the reach of the block map",
+            iblock
+        ))
+    }
+}
+
+/// Read the `index`-th pointer of an indirect block.
+fn read_ptr(block: &Block, index: usize) -> PBlockId {
+    u32::from_le_bytes(block.data[index * 4..index * 4 + 4].try_into().unwrap()) as PBlockId
+}
+
+/// Set the `index`-th pointer of an indirect block.
+fn write_ptr(block: &mut Block, index: usize, pblock: PBlockId) {
+    block.data[index * 4..index * 4 + 4].copy_from_slice(&(pblock as u32).to_le_bytes());
+}
+
+impl Ext4 {
+    /// Find the physical block mapped at `iblock`, `None` if it is a hole.
+    pub(super) fn indirect_lookup(
+        &self,
+        inode_ref: &InodeRef,
+        iblock: LBlockId,
+    ) -> Result<Option<PBlockId>> {
+        let path = IndirectPath::new(iblock)?;
+        let mut pblock = inode_ref.inode.block_ptr(path.slot) as PBlockId;
+        for &index in &path.indices[..path.depth] {
+            if pblock == 0 {
+                return Ok(None);
+            }
+            pblock = read_ptr(&self.read_block(pblock), index);
+        }
+        Ok((pblock != 0).then_some(pblock))
+    }
+
+    /// The number of blocks mapping `iblock` allocates: the data block and
+    /// the missing indirect blocks on the way, 0 if it is mapped.
+    pub(super) fn indirect_blocks_needed(
+        &self,
+        inode_ref: &InodeRef,
+        iblock: LBlockId,
+    ) -> Result<u64> {
+        let path = IndirectPath::new(iblock)?;
+        let mut missing = path.depth as u64 + 1;
+        let mut pblock = inode_ref.inode.block_ptr(path.slot) as PBlockId;
+        for &index in &path.indices[..path.depth] {
+            if pblock == 0 {
+                return Ok(missing);
+            }
+            missing -= 1;
+            pblock = read_ptr(&self.read_block(pblock), index);
+        }
+        Ok(if pblock != 0 { 0 } else { missing })
+    }
+
+    /// Map a new data block at `iblock`, allocating the missing indirect
+    /// blocks on the way, and return it. The mapped block is returned as is
+    /// if `iblock` is already mapped.
+    ///
+    /// New indirect blocks are zeroed and counted in `i_blocks`. The data
+    /// block is counted by the caller. The inode is not written. Nothing is
+    /// allocated if there is not enough space for all the blocks needed.
+    pub(super) fn indirect_map(
+        &self,
+        inode_ref: &mut InodeRef,
+        iblock: LBlockId,
+    ) -> Result<PBlockId> {
+        let path = IndirectPath::new(iblock)?;
+        // Allocate nothing unless all the missing blocks on the way fit
+        self.check_free_blocks(self.indirect_blocks_needed(inode_ref, iblock)?)?;
+        // Block maps are not cached, but mappings handed out are
+        self.es_invalidate(inode_ref.id);
+        // Aim right after the previous block, then after each new block
+        let mut goal = match iblock.checked_sub(1) {
+            Some(prev) => self
+                .indirect_lookup(inode_ref, prev)?
+                .map(|pblock| pblock + 1),
+            None => None,
+        };
+        let mut pblock = inode_ref.inode.block_ptr(path.slot) as PBlockId;
+        if pblock == 0 {
+            pblock = if path.depth == 0 {
+                self.alloc_block_near(inode_ref, goal)?
+            } else {
+                self.indirect_alloc(inode_ref, goal)?
+            };
+            inode_ref.inode.set_block_ptr(path.slot, pblock as u32);
+            goal = Some(pblock + 1);
+        }
+        for (level, &index) in path.indices[..path.depth].iter().enumerate() {
+            let mut block = self.read_block(pblock);
+            let mut child = read_ptr(&block, index);
+            if child == 0 {
+                child = if level + 1 == path.depth {
+                    self.alloc_block_near(inode_ref, goal)?
+                } else {
+                    self.indirect_alloc(inode_ref, goal)?
+                };
+                write_ptr(&mut block, index, child);
+                self.write_block(&block);
+                goal = Some(child + 1);
+            }
+            pblock = child;
+        }
+        Ok(pblock)
+    }
+
+    /// Get the logical block after the last mapped one, 0 if no block is
+    /// mapped.
+    pub(super) fn indirect_end(&self, inode_ref: &InodeRef) -> LBlockId {
+        for slot in (0..DIRECT_BLOCKS + 3).rev() {
+            let pblock = inode_ref.inode.block_ptr(slot) as PBlockId;
+            if pblock == 0 {
+                continue;
+            }
+            if slot < DIRECT_BLOCKS {
+                return slot as LBlockId + 1;
+            }
+            let depth = (slot - DIRECT_BLOCKS + 1) as u32;
+            // The first logical block under the slot
+            let base =
+                (1..depth).fold(DIRECT_BLOCKS as u64, |base, d| base + PTRS_PER_BLOCK.pow(d));
+            if let Some(last) = self.indirect_last(pblock, depth) {
+                return (base + last + 1).min(MAX_BLOCKS as u64) as LBlockId;
+            }
+        }
+        0
+    }
+
+    /// Free every data and indirect block of the block map. The pointers in
+    /// the inode are left as they are.
+    pub(super) fn indirect_free(&self, inode_ref: &mut InodeRef) -> Result<()> {
+        let tree = inode_ref.clone();
+        let mut run = None;
+        for slot in 0..DIRECT_BLOCKS + 3 {
+            let pblock = tree.inode.block_ptr(slot) as PBlockId;
+            if pblock == 0 {
+                continue;
+            }
+            if slot < DIRECT_BLOCKS {
+                self.indirect_free_data(inode_ref, &mut run, pblock)?;
+            } else {
+                let depth = (slot - DIRECT_BLOCKS + 1) as u32;
+                self.indirect_free_tree(inode_ref, &mut run, pblock, depth)?;
+            }
+        }
+        if let Some((start, count)) = run {
+            self.dealloc_blocks(inode_ref, start, count)?;
+        }
+        Ok(())
+    }
+
+    /// Free every data and indirect block mapping blocks from `end` on,
+    /// clear the pointers to them and count them out of `i_blocks`. The
+    /// inode is not written.
+    pub(super) fn indirect_truncate(&self, inode_ref: &mut InodeRef, end: LBlockId) -> Result<()> {
+        self.es_invalidate(inode_ref.id);
+        let mut run = None;
+        let mut freed = 0;
+        // The first logical block under the slot
+        let mut base = 0;
+        for slot in 0..DIRECT_BLOCKS + 3 {
+            let depth = slot.saturating_sub(DIRECT_BLOCKS - 1) as u32;
+            let span = PTRS_PER_BLOCK.pow(depth);
+            let pblock = inode_ref.inode.block_ptr(slot) as PBlockId;
+            if pblock != 0 && base + span > end as u64 {
+                let from = (end as u64).saturating_sub(base);
+                if self
+                    .indirect_truncate_tree(inode_ref, &mut run, &mut freed, pblock, depth, from)?
+                {
+                    inode_ref.inode.set_block_ptr(slot, 0);
+                }
+            }
+            base += span;
+        }
+        if let Some((start, count)) = run {
+            self.dealloc_blocks(inode_ref, start, count)?;
+        }
+        let block_count = inode_ref.inode.fs_block_count();
+        inode_ref
+            .inode
+            .set_fs_block_count(block_count.saturating_sub(freed));
+        Ok(())
+    }
+
+    /// Free the blocks from offset `from` on under an indirect block of
+    /// `depth` levels, a data block if `depth` is 0, then the block itself
+    /// if `from` is 0. Return whether the block was freed.
+    fn indirect_truncate_tree(
+        &self,
+        inode_ref: &mut InodeRef,
+        run: &mut Option<(PBlockId, u64)>,
+        freed: &mut u64,
+        pblock: PBlockId,
+        depth: u32,
+        from: u64,
+    ) -> Result<bool> {
+        if depth > 0 {
+            let span = PTRS_PER_BLOCK.pow(depth - 1);
+            let mut block = self.read_block(pblock);
+            let mut dirty = false;
+            for index in (from / span) as usize..PTRS_PER_BLOCK as usize {
+                let child = read_ptr(&block, index);
+                if child == 0 {
+                    continue;
+                }
+                let child_from = from.saturating_sub(index as u64 * span);
+                if self.indirect_truncate_tree(
+                    inode_ref,
+                    run,
+                    freed,
+                    child,
+                    depth - 1,
+                    child_from,
+                )? {
+                    write_ptr(&mut block, index, 0);
+                    dirty = true;
+                }
+            }
+            if from > 0 {
+                if dirty {
+                    self.write_block(&block);
+                }
+                return Ok(false);
+            }
+        }
+        self.indirect_free_data(inode_ref, run, pblock)?;
+        *freed += 1;
+        Ok(true)
+    }
+
+    /// Allocate a zeroed indirect block for an inode and count it.
+    fn indirect_alloc(&self, inode_ref: &mut InodeRef, goal: Option<PBlockId>) -> Result<PBlockId> {
+        let pblock = self.alloc_block_near(inode_ref, goal)?;
+        self.write_block(&Block::new(pblock, [0; BLOCK_SIZE]));
+        let block_count = inode_ref.inode.fs_block_count() + 1;
+        inode_ref.inode.set_fs_block_count(block_count);
+        Ok(pblock)
+    }
+
+    /// Get the offset of the last mapped block under an indirect block of
+    /// `depth` levels, `None` if it maps nothing.
+    fn indirect_last(&self, pblock: PBlockId, depth: u32) -> Option<u64> {
+        let block = self.read_block(pblock);
+        for index in (0..PTRS_PER_BLOCK as usize).rev() {
+            let child = read_ptr(&block, index);
+            if child == 0 {
+                continue;
+            }
+            if depth == 1 {
+                return Some(index as u64);
+            }
+            if let Some(last) = self.indirect_last(child, depth - 1) {
+                return Some(index as u64 * PTRS_PER_BLOCK.pow(depth - 1) + last);
+            }
+        }
+        None
+    }
+
+    /// Free the blocks under an indirect block of `depth` levels, then the
+    /// indirect block itself.
+    fn indirect_free_tree(
+        &self,
+        inode_ref: &mut InodeRef,
+        run: &mut Option<(PBlockId, u64)>,
+        pblock: PBlockId,
+        depth: u32,
+    ) -> Result<()> {
+        let block = self.read_block(pblock);
+        for index in 0..PTRS_PER_BLOCK as usize {
+            let child = read_ptr(&block, index);
+            if child == 0 {
+                continue;
+            }
+            if depth == 1 {
+                self.indirect_free_data(inode_ref, run, child)?;
+            } else {
+                self.indirect_free_tree(inode_ref, run, child, depth - 1)?;
+            }
+        }
+        self.indirect_free_data(inode_ref, run, pblock)
+    }
+
+    /// Queue a block to be freed, freeing the queued run of contiguous
+    /// blocks first if the block does not extend it.
+    fn indirect_free_data(
+        &self,
+        inode_ref: &mut InodeRef,
+        run: &mut Option<(PBlockId, u64)>,
+        pblock: PBlockId,
+    ) -> Result<()> {
+        match run {
+            Some((start, count)) if *start + *count == pblock => *count += 1,
+            _ => {
+                if let Some((start, count)) = run.replace((pblock, 1)) {
+                    self.dealloc_blocks(inode_ref, start, count)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
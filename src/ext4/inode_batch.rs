@@ -0,0 +1,118 @@
+//! Batched inode writes.
+//!
+//! An operation often writes the same inode several times, e.g. appending
+//! to a file updates its block count, then its size and timestamps, and
+//! each write is a read-modify-write of a whole inode table block. Within a
+//! batch, inode writes only update an in-memory copy, which `read_inode`
+//! returns. When the outermost batch ends, each dirty inode table block is
+//! written once.
+//!
+//! Transactions flush the batch when they start and before they commit, so
+//! that the inode writes of a transaction are part of it. Like block
+//! accesses, batch accesses of other threads wait while a transaction runs.
+
+use super::Ext4;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+
+/// Inode writes of the running batch.
+#[derive(Default)]
+pub(super) struct InodeBatch {
+    /// Number of nested batches
+    depth: usize,
+    /// Dirty inodes, checksummed when written
+    dirty: BTreeMap<InodeId, Inode>,
+}
+
+impl InodeBatch {
+    /// Whether no inode writes are pending.
+    pub(super) fn is_empty(&self) -> bool {
+        self.dirty.is_empty()
+    }
+}
+
+/// Ends a batch when dropped, see `Ext4::inode_batch`.
+pub(super) struct InodeBatchGuard<'a> {
+    fs: &'a Ext4,
+}
+
+impl Drop for InodeBatchGuard<'_> {
+    fn drop(&mut self) {
+        let outermost = {
+            let mut batch = self.fs.inode_batch.lock();
+            batch.depth -= 1;
+            batch.depth == 0
+        };
+        if outermost {
+            self.fs.inode_batch_flush();
+        }
+    }
+}
+
+impl Ext4 {
+    /// Start batching inode writes until the returned guard is dropped.
+    pub(super) fn inode_batch(&self) -> InodeBatchGuard<'_> {
+        self.inode_batch.lock().depth += 1;
+        InodeBatchGuard { fs: self }
+    }
+
+    /// Get the pending write of an inode in the running batch.
+    pub(super) fn inode_batch_read(&self, id: InodeId) -> Option<Inode> {
+        let _txn = self.txn_lock();
+        let batch = self.inode_batch.lock();
+        batch.dirty.get(&id).cloned()
+    }
+
+    /// Buffer an inode write in the running batch. Return `false` if no
+    /// batch is running.
+    pub(super) fn inode_batch_write(&self, inode_ref: &InodeRef) -> bool {
+        let _txn = self.txn_lock();
+        let mut batch = self.inode_batch.lock();
+        if batch.depth == 0 {
+            return false;
+        }
+        batch.dirty.insert(inode_ref.id, inode_ref.inode.clone());
+        true
+    }
+
+    /// Drop the pending inode writes, whose blocks are being reverted.
+    pub(super) fn inode_batch_discard(&self) {
+        self.inode_batch.lock().dirty.clear();
+    }
+
+    /// Write the pending inode writes, an inode table block at a time.
+    pub(super) fn inode_batch_flush(&self) {
+        let dirty = {
+            let _txn = self.txn_lock();
+            mem::take(&mut self.inode_batch.lock().dirty)
+        };
+        if dirty.is_empty() {
+            return;
+        }
+        let sb = self.read_super_block();
+        let mut charges = Vec::new();
+        let mut block: Option<Block> = None;
+        // Inodes in the same block have adjacent ids
+        for (id, inode) in dirty {
+            let (block_id, offset) = self.inode_disk_pos(id);
+            if let Some(prev) = block.take_if(|block| block.id != block_id) {
+                self.write_block(&prev);
+            }
+            let block = block.get_or_insert_with(|| self.read_block(block_id));
+            let mut inode_ref = InodeRef::new(id, inode);
+            if sb.has_metadata_csum() {
+                let extra = Self::inode_extra_range(offset, sb.inode_size());
+                inode_ref.set_checksum(sb.checksum_seed(), sb.inode_size(), &block.data[extra]);
+            }
+            let old: Inode = block.read_record_as(offset, sb.inode_size());
+            block.write_record_as(offset, sb.inode_size(), &inode_ref.inode);
+            charges.push((id, old, inode_ref.inode));
+        }
+        if let Some(block) = block {
+            self.write_block(&block);
+        }
+        for (id, old, new) in charges {
+            self.quota_update(id, &old, &new);
+        }
+    }
+}
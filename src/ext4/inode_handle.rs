@@ -0,0 +1,100 @@
+//! Inode handles for kernel integration.
+//!
+//! A kernel that builds its own VFS on top of this crate usually keeps an
+//! inode object per file and calls into the filesystem through it. An
+//! `InodeHandle` is such an object: it is bound to an inode id, and offers
+//! positional I/O without going through paths or raw-id methods.
+
+use super::Ext4;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+use crate::return_error;
+
+/// A handle to an inode, acquired by `Ext4::inode_handle`.
+#[derive(Clone, Copy)]
+pub struct InodeHandle<'a> {
+    fs: &'a Ext4,
+    id: InodeId,
+}
+
+impl Ext4 {
+    /// Get a handle to an inode.
+    ///
+    /// # Error
+    ///
+    /// `EINVAL` if the inode is invalid (mode == 0).
+    pub fn inode_handle(&self, id: InodeId) -> Result<InodeHandle<'_>> {
+        let inode = self.read_inode(id);
+        if inode.inode.mode().bits() == 0 {
+            return_error!(ErrCode::EINVAL, "Invalid inode {}", id);
+        }
+        Ok(InodeHandle { fs: self, id })
+    }
+}
+
+impl InodeHandle<'_> {
+    /// The inode id.
+    pub fn id(&self) -> InodeId {
+        self.id
+    }
+
+    /// Get the file attributes.
+    pub fn attr(&self) -> Result<FileAttr> {
+        self.fs.getattr(self.id)
+    }
+
+    /// Read from the file at `offset`. Reading at or past the end of file
+    /// returns 0.
+    ///
+    /// # Error
+    ///
+    /// `EISDIR` if the inode is not a regular file.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let inode = self.fs.read_inode(self.id);
+        if inode.inode.is_file() && offset >= inode.inode.size() {
+            return Ok(0);
+        }
+        self.fs.read(self.id, offset, buf)
+    }
+
+    /// Write to the file at `offset`, extending the file if needed.
+    ///
+    /// # Error
+    ///
+    /// * `EISDIR` - the inode is not a regular file
+    /// * `ENOSPC` - no space left on device
+    pub fn write_at(&self, offset: u64, data: &[u8]) -> Result<usize> {
+        self.fs.write(self.id, offset, data)
+    }
+
+    /// Set the file size. Growing the file leaves a hole, blocks are
+    /// mapped when written. Shrinking frees the blocks past the new end of
+    /// file.
+    ///
+    /// # Error
+    ///
+    /// * `EISDIR` - the inode is not a regular file
+    /// * `ENOSPC` - no space left on device
+    pub fn truncate(&self, size: u64) -> Result<()> {
+        if !self.fs.read_inode(self.id).inode.is_file() {
+            return_error!(ErrCode::EISDIR, "Inode {} is not a file", self.id);
+        }
+        self.fs.setattr(
+            self.id,
+            None,
+            None,
+            None,
+            Some(size),
+            None,
+            None,
+            None,
+            None,
+        )
+    }
+
+    /// Flush dirty data to the device. The block cache does not track
+    /// blocks per inode, so this flushes the whole filesystem.
+    pub fn flush(&self) {
+        self.fs.flush_all();
+    }
+}
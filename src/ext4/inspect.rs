@@ -0,0 +1,283 @@
+//! Read-only inspection of filesystem metadata.
+//!
+//! The superblock and block group descriptors are summarized in plain
+//! structs, so tools such as a `dumpe2fs` clone can be built without
+//! access to the on-disk structures. Nothing here writes to the device:
+//! uninitialized bitmaps are described as `dumpe2fs` would, not built.
+
+use super::Ext4;
+use crate::constants::*;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+use crate::return_error;
+use core::ops::Range;
+
+/// Superblock fields, acquired by `Ext4::super_block_info`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuperBlockInfo {
+    /// Volume label
+    pub volume_name: Vec<u8>,
+    /// Filesystem UUID
+    pub uuid: [u8; 16],
+    /// Filesystem state, see `SuperBlock::STATE_VALID` and `STATE_ERROR`
+    pub state: u16,
+    /// Behaviour when errors are detected, the raw `s_errors` value
+    pub errors: u16,
+    /// Compatible features
+    pub compat: FeatureCompat,
+    /// Incompatible features
+    pub incompat: FeatureIncompat,
+    /// Readonly-compatible features
+    pub ro_compat: FeatureRoCompat,
+    /// Block size in bytes
+    pub block_size: usize,
+    /// Allocation cluster size in bytes, the block size without `bigalloc`
+    pub cluster_size: usize,
+    /// Total number of blocks
+    pub block_count: u64,
+    /// Number of free blocks
+    pub free_blocks: u64,
+    /// Number of blocks reserved for the privileged user
+    pub reserved_blocks: u64,
+    /// Total number of inodes
+    pub inode_count: u32,
+    /// Number of free inodes
+    pub free_inodes: u32,
+    /// First data block
+    pub first_data_block: u32,
+    /// First non-reserved inode
+    pub first_inode: InodeId,
+    /// Size of an on-disk inode in bytes
+    pub inode_size: usize,
+    /// Number of blocks in each block group
+    pub blocks_per_group: u32,
+    /// Number of clusters in each block group
+    pub clusters_per_group: u32,
+    /// Number of inodes in each block group
+    pub inodes_per_group: u32,
+    /// Number of block groups
+    pub group_count: u32,
+    /// Number of block groups in a flex group
+    pub groups_per_flex: u32,
+    /// Number of blocks reserved for group descriptor table growth
+    pub reserved_gdt_blocks: u32,
+    /// Number of mounts since the last check
+    pub mount_count: u16,
+    /// Maximum number of mounts before a check, -1 if disabled
+    pub max_mount_count: i16,
+    /// Last mount time, in seconds since the epoch
+    pub mount_time: u64,
+    /// Last write time, in seconds since the epoch
+    pub write_time: u64,
+    /// Kilobytes written over the lifetime of the filesystem
+    pub kbytes_written: u64,
+    /// Number of errors recorded
+    pub error_count: u32,
+    /// Head of the orphan list, 0 if empty
+    pub last_orphan: InodeId,
+    /// Seed of metadata checksums
+    pub checksum_seed: u32,
+}
+
+/// A block group and its descriptor, acquired by `Ext4::group_info`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupInfo {
+    /// Block group id
+    pub id: BlockGroupId,
+    /// First block of the group
+    pub first_block: PBlockId,
+    /// Number of blocks in the group
+    pub block_count: u32,
+    /// Whether the group holds a superblock and descriptor table backup
+    pub has_super: bool,
+    /// Block group flags
+    pub flags: BlockGroupFlags,
+    /// Block bitmap location
+    pub block_bitmap: PBlockId,
+    /// Inode bitmap location
+    pub inode_bitmap: PBlockId,
+    /// First block of the inode table
+    pub inode_table: PBlockId,
+    /// Number of free blocks, whole free clusters with `bigalloc`
+    pub free_blocks: u64,
+    /// Number of free inodes
+    pub free_inodes: u32,
+    /// Number of directories
+    pub used_dirs: u32,
+    /// Number of never used inodes at the end of the inode table
+    pub itable_unused: u32,
+    /// Descriptor checksum
+    pub checksum: u16,
+    /// Whether the descriptor checksum is valid, always true without
+    /// `metadata_csum`
+    pub checksum_valid: bool,
+}
+
+impl Ext4 {
+    /// Get the superblock fields.
+    pub fn super_block_info(&self) -> SuperBlockInfo {
+        let sb = self.read_super_block();
+        SuperBlockInfo {
+            volume_name: sb.volume_name().to_vec(),
+            uuid: sb.uuid(),
+            state: sb.state(),
+            errors: sb.errors(),
+            compat: sb.features_compatible(),
+            incompat: sb.features_incompatible(),
+            ro_compat: sb.features_read_only(),
+            block_size: BLOCK_SIZE,
+            cluster_size: BLOCK_SIZE * sb.cluster_ratio() as usize,
+            block_count: sb.block_count(),
+            free_blocks: sb.free_blocks_count(),
+            reserved_blocks: sb.reserved_blocks_count(),
+            inode_count: sb.inode_count(),
+            free_inodes: sb.free_inodes_count(),
+            first_data_block: sb.first_data_block(),
+            first_inode: sb.first_inode(),
+            inode_size: sb.inode_size(),
+            blocks_per_group: sb.blocks_per_group(),
+            clusters_per_group: sb.clusters_per_group(),
+            inodes_per_group: sb.inodes_per_group(),
+            group_count: sb.block_group_count(),
+            groups_per_flex: sb.groups_per_flex(),
+            reserved_gdt_blocks: sb.reserved_gdt_blocks(),
+            mount_count: sb.mount_count(),
+            max_mount_count: sb.max_mount_count(),
+            mount_time: sb.mount_time(),
+            write_time: sb.write_time(),
+            kbytes_written: sb.kbytes_written(),
+            error_count: sb.error_count(),
+            last_orphan: sb.last_orphan(),
+            checksum_seed: sb.checksum_seed(),
+        }
+    }
+
+    /// Get a block group and its descriptor.
+    ///
+    /// # Error
+    ///
+    /// `EINVAL` - `bgid` is out of range
+    pub fn group_info(&self, bgid: BlockGroupId) -> Result<GroupInfo> {
+        let sb = self.read_super_block();
+        Self::check_group_id(&sb, bgid)?;
+        let bg = self.read_block_group(bgid);
+        Ok(GroupInfo {
+            id: bgid,
+            first_block: sb.group_first_block(bgid),
+            block_count: sb.blocks_in_group(bgid),
+            has_super: sb.group_has_super(bgid),
+            flags: bg.desc.flags(),
+            block_bitmap: bg.desc.block_bitmap_block(),
+            inode_bitmap: bg.desc.inode_bitmap_block(),
+            inode_table: bg.desc.inode_table_first_block(),
+            free_blocks: bg.desc.get_free_blocks_count() * sb.cluster_ratio() as u64,
+            free_inodes: bg.desc.free_inodes_count(),
+            used_dirs: bg.desc.used_dirs_count(),
+            itable_unused: bg.desc.itable_unused(),
+            checksum: bg.desc.checksum(),
+            checksum_valid: self.block_group_checksum_valid(&bg),
+        })
+    }
+
+    /// Iterate over all block groups.
+    pub fn groups(&self) -> impl Iterator<Item = GroupInfo> + '_ {
+        let count = self.read_super_block().block_group_count();
+        (0..count).map(move |bgid| self.group_info(bgid).unwrap())
+    }
+
+    /// Iterate over all allocated inodes with their attributes, in inode
+    /// number order, by scanning the inode bitmaps. Unlike a directory walk,
+    /// this also finds inodes that are not linked anywhere, such as orphans.
+    ///
+    /// Reserved inodes that were never initialized (with a zero mode) are
+    /// skipped, other reserved inodes such as the journal are included.
+    pub fn iter_inodes(&self) -> impl Iterator<Item = (InodeId, FileAttr)> + '_ {
+        let sb = self.read_super_block();
+        let inodes_per_group = sb.inodes_per_group();
+        (0..sb.block_group_count()).flat_map(move |bgid| {
+            let bg = self.read_block_group(bgid);
+            let mut ids = Vec::new();
+            if !bg.desc.flags().contains(BlockGroupFlags::INODE_UNINIT) {
+                let count = inodes_per_group as usize;
+                let mut bitmap_block = self.read_block(bg.desc.inode_bitmap_block());
+                let bitmap = Bitmap::new(&mut bitmap_block.data, count);
+                let mut bit = 0;
+                while let Some(set) = bitmap.first_set_bit(bit, count) {
+                    ids.push(bgid * inodes_per_group + set as InodeId + 1);
+                    bit = set + 1;
+                }
+            }
+            ids.into_iter().filter_map(move |id| {
+                let inode = self.read_inode(id);
+                (!inode.inode.mode().is_empty()).then(|| (id, Self::file_attr(&inode)))
+            })
+        })
+    }
+
+    /// Get the free blocks of a block group as ranges of physical block
+    /// ids, in ascending order. With `bigalloc`, the blocks of free clusters.
+    ///
+    /// # Error
+    ///
+    /// `EINVAL` - `bgid` is out of range
+    pub fn group_free_blocks(&self, bgid: BlockGroupId) -> Result<Vec<Range<PBlockId>>> {
+        let sb = self.read_super_block();
+        Self::check_group_id(&sb, bgid)?;
+        // Build the bitmap of an uninitialized group on a copy, so that
+        // nothing is written back
+        let mut bg = self.read_block_group(bgid);
+        let mut bitmap_block = self.load_block_bitmap(&sb, &mut bg);
+        let count = sb.clusters_in_group(bgid) as usize;
+        let bitmap = Bitmap::new(&mut bitmap_block.data, count);
+        let first = sb.group_first_block(bgid);
+        let ratio = sb.cluster_ratio() as PBlockId;
+        let end = first + sb.blocks_in_group(bgid) as PBlockId;
+        Ok(free_ranges(&bitmap, count)
+            .into_iter()
+            .map(|r| {
+                first + r.start as PBlockId * ratio..(first + r.end as PBlockId * ratio).min(end)
+            })
+            .collect())
+    }
+
+    /// Get the free inodes of a block group as ranges of inode ids, in
+    /// ascending order.
+    ///
+    /// # Error
+    ///
+    /// `EINVAL` - `bgid` is out of range
+    pub fn group_free_inodes(&self, bgid: BlockGroupId) -> Result<Vec<Range<InodeId>>> {
+        let sb = self.read_super_block();
+        Self::check_group_id(&sb, bgid)?;
+        let first = bgid * sb.inodes_per_group() + 1;
+        let count = sb.inodes_per_group() as usize;
+        let bg = self.read_block_group(bgid);
+        if bg.desc.flags().contains(BlockGroupFlags::INODE_UNINIT) {
+            return Ok(core::iter::once(first..first + count as InodeId).collect());
+        }
+        let mut bitmap_block = self.read_block(bg.desc.inode_bitmap_block());
+        let bitmap = Bitmap::new(&mut bitmap_block.data, count);
+        Ok(free_ranges(&bitmap, count)
+            .into_iter()
+            .map(|r| first + r.start as InodeId..first + r.end as InodeId)
+            .collect())
+    }
+
+    fn check_group_id(sb: &SuperBlock, bgid: BlockGroupId) -> Result<()> {
+        if bgid >= sb.block_group_count() {
+            return_error!(ErrCode::EINVAL, "Invalid block group {}", bgid);
+        }
+        Ok(())
+    }
+}
+
+/// Collect the runs of clear bits among the first `count` bits.
+fn free_ranges(bitmap: &Bitmap, count: usize) -> Vec<Range<usize>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    let mut bit = 0;
+    while let Some(start) = bitmap.first_clear_bit(bit, count) {
+        bit = start + bitmap.clear_run_len(start, count);
+        ranges.push(start..bit);
+    }
+    ranges
+}
@@ -1,11 +1,653 @@
+//! Transactions on the jbd2 journal.
+//!
+//! Operations grouped by `Ext4::with_transaction` buffer their block writes
+//! in memory. When the group succeeds, the blocks are logged to the journal
+//! and a commit block makes them durable as a unit. Only then are they
+//! written to their home locations. If the group fails, or the system
+//! crashes before the commit block is on disk, none of the changes reach
+//! the filesystem. A committed transaction that was not completely written
+//! home is replayed on the next load, by this crate or by `e2fsck`.
+//!
+//! Each transaction is written home before the next one starts, so the log
+//! always begins at the first journal block.
+//!
+//! A transaction belongs to the thread that started it. Until it is written
+//! home, block and inode accesses of other threads wait, so their writes are
+//! neither committed nor dropped with it, and they do not see its writes.
+//!
+//! A partial write to a block the transaction has not buffered yet does not
+//! read the block. Only the written bytes are tracked, and the rest of the
+//! block is read when the block is read back or when the transaction
+//! commits, if the writes have not covered it by then.
+
 use super::Ext4;
+use crate::constants::*;
+use crate::ext4_defs::*;
+use crate::format_error;
+use crate::prelude::*;
+use crate::return_error;
+use crate::sync::SpinGuard;
+use core::ops::Range;
+
+/// Block writes of the running transaction.
+#[derive(Default)]
+pub(super) struct Transaction {
+    /// Thread that owns the transaction until it is written home
+    owner: Option<u64>,
+    /// Whether a transaction is running
+    active: bool,
+    /// New content of the blocks written by the transaction
+    blocks: BTreeMap<PBlockId, Block>,
+    /// Written byte range of the buffered blocks whose other bytes are not
+    /// read yet
+    dirty: BTreeMap<PBlockId, Range<usize>>,
+    /// Block ranges discarded by the transaction, applied after commit
+    discards: Vec<(PBlockId, u64)>,
+    /// State at the start of each nested transaction
+    savepoints: Vec<Savepoint>,
+}
+
+/// Buffered blocks, dirty ranges and discard count of a transaction.
+type Savepoint = (
+    BTreeMap<PBlockId, Block>,
+    BTreeMap<PBlockId, Range<usize>>,
+    usize,
+);
+
+/// Put the bytes of `block` in `range` over the home content of the block.
+fn merge_home(block: &Block, mut home: Block, range: Range<usize>) -> Block {
+    home.data[range.clone()].copy_from_slice(&block.data[range]);
+    home
+}
+
+/// The journal inode and superblock.
+struct Journal {
+    inode: InodeRef,
+    sb: JournalSuperBlock,
+}
+
+/// A committed transaction found in the log.
+struct LoggedTransaction {
+    sequence: u32,
+    /// Home block, journal block and whether the block was escaped
+    blocks: Vec<(PBlockId, u32, bool)>,
+}
+
+/// Identify the calling thread, see `Ext4::set_thread_id`.
+#[cfg(feature = "std")]
+pub(super) fn default_thread_id() -> u64 {
+    use core::sync::atomic::{AtomicU64, Ordering};
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    std::thread_local!(static ID: u64 = NEXT.fetch_add(1, Ordering::Relaxed));
+    ID.with(|id| *id)
+}
+
+/// Identify the calling thread, see `Ext4::set_thread_id`.
+#[cfg(not(feature = "std"))]
+pub(super) fn default_thread_id() -> u64 {
+    0
+}
 
 impl Ext4 {
-    /// start transaction
-    #[allow(unused)]
-    pub(super) fn trans_start(&self) {}
+    /// Run `f` as one transaction: the block writes of all operations in
+    /// `f` become visible and durable together if `f` succeeds, and are
+    /// dropped if it fails. Nested calls are part of the outer transaction,
+    /// but a failing nested call only drops its own writes.
+    ///
+    /// The transaction must fit in the journal. Open file handles and other
+    /// in-memory state are not rolled back.
+    ///
+    /// Operations of other threads wait until the transaction is written
+    /// home, so `f` must not wait for them.
+    ///
+    /// # Error
+    ///
+    /// * `ENOTSUP` - the filesystem has no journal
+    /// * `ENOSPC` - the transaction does not fit in the journal
+    /// * any error returned by `f`
+    pub fn with_transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Self) -> Result<T>,
+    {
+        self.check_writable()?;
+        if self.read_super_block().journal_inode() == 0 {
+            return_error!(ErrCode::ENOTSUP, "Transactions need a journal");
+        }
+        self.trans_start();
+        match f(self) {
+            Ok(value) => {
+                self.trans_stop()?;
+                Ok(value)
+            }
+            Err(err) => {
+                self.trans_abort();
+                Err(err)
+            }
+        }
+    }
+
+    /// Set the function identifying the calling thread, which transactions
+    /// use to tell their own operations from those of other threads. With
+    /// `std`, each thread gets its own id by default. Without it, all
+    /// callers are taken as one thread until this is set.
+    pub fn set_thread_id(&mut self, thread_id: fn() -> u64) {
+        self.thread_id = thread_id;
+    }
+
+    /// Lock the transaction state, waiting while another thread owns a
+    /// transaction.
+    pub(super) fn txn_lock(&self) -> SpinGuard<'_, Transaction> {
+        let me = (self.thread_id)();
+        loop {
+            let txn = self.txn.lock();
+            if txn.owner.is_none_or(|owner| owner == me) {
+                return txn;
+            }
+            drop(txn);
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Start a transaction, or a nested one if a transaction is running.
+    pub(super) fn trans_start(&self) {
+        loop {
+            // Inode writes before the transaction are not part of it
+            self.inode_batch_flush();
+            let mut txn = self.txn_lock();
+            if txn.active {
+                let savepoint = (txn.blocks.clone(), txn.dirty.clone(), txn.discards.len());
+                txn.savepoints.push(savepoint);
+                return;
+            }
+            // Other threads may have batched inode writes since the flush
+            if self.inode_batch.lock().is_empty() {
+                txn.owner = Some((self.thread_id)());
+                txn.active = true;
+                return;
+            }
+        }
+    }
+
+    /// Stop a transaction. The outermost one is committed to the journal
+    /// and written home.
+    pub(super) fn trans_stop(&self) -> Result<()> {
+        // Inode writes of the transaction are part of it
+        self.inode_batch_flush();
+        let mut txn = self.txn_lock();
+        if txn.savepoints.pop().is_some() {
+            return Ok(());
+        }
+        let mut blocks = mem::take(&mut txn.blocks);
+        let dirty = mem::take(&mut txn.dirty);
+        let discards = mem::take(&mut txn.discards);
+        txn.active = false;
+        drop(txn);
+
+        // Fill in the bytes of partially written blocks
+        for (block_id, range) in dirty {
+            let block = blocks.get_mut(&block_id).unwrap();
+            *block = merge_home(block, self.read_block(block_id), range);
+        }
+        let res = if blocks.is_empty() {
+            Ok(())
+        } else {
+            self.journal_commit(blocks.into_values().collect())
+        };
+        if res.is_ok() {
+            for (start, count) in discards {
+                self.discard_blocks(start, count);
+            }
+        }
+        self.txn.lock().owner = None;
+        res
+    }
+
+    /// Abort a transaction, dropping its writes.
+    pub(super) fn trans_abort(&self) {
+        let mut txn = self.txn_lock();
+        let outermost = if let Some((blocks, dirty, discards)) = txn.savepoints.pop() {
+            txn.blocks = blocks;
+            txn.dirty = dirty;
+            txn.discards.truncate(discards);
+            false
+        } else {
+            *txn = Transaction {
+                owner: txn.owner,
+                ..Default::default()
+            };
+            true
+        };
+        drop(txn);
+        // Inode writes and mappings cached during the transaction are reverted
+        self.inode_batch_discard();
+        self.es_clear();
+        if outermost {
+            self.txn.lock().owner = None;
+        }
+    }
+
+    /// Get a block written by the running transaction.
+    pub(super) fn txn_read(&self, block_id: PBlockId) -> Option<Block> {
+        let txn = self.txn_lock();
+        if !txn.active {
+            return None;
+        }
+        let block = txn.blocks.get(&block_id).copied()?;
+        if !txn.dirty.contains_key(&block_id) {
+            return Some(block);
+        }
+        drop(txn);
+        let home = self.read_home_block(block_id);
+        let mut txn = self.txn_lock();
+        let txn = &mut *txn;
+        let block = txn.blocks.get_mut(&block_id)?;
+        if let Some(range) = txn.dirty.remove(&block_id) {
+            *block = merge_home(block, home, range);
+        }
+        Some(*block)
+    }
+
+    /// Buffer a block write in the running transaction. Return `false` if
+    /// no transaction is running.
+    pub(super) fn txn_write(&self, block: &Block) -> bool {
+        let mut txn = self.txn_lock();
+        if !txn.active {
+            return false;
+        }
+        txn.blocks.insert(block.id, *block);
+        txn.dirty.remove(&block.id);
+        true
+    }
+
+    /// Buffer a write of `data` at `offset` in a block in the running
+    /// transaction, without reading the block. Return `false` if no
+    /// transaction is running, or if the block is partially buffered and
+    /// the write does not touch the written range. The caller then writes
+    /// the whole block.
+    pub(super) fn txn_write_range(&self, block_id: PBlockId, offset: usize, data: &[u8]) -> bool {
+        let mut txn = self.txn_lock();
+        if !txn.active {
+            return false;
+        }
+        let txn = &mut *txn;
+        let range = offset..offset + data.len();
+        let Some(block) = txn.blocks.get_mut(&block_id) else {
+            let mut block = Block::new(block_id, [0; BLOCK_SIZE]);
+            block.write_offset(offset, data);
+            txn.blocks.insert(block_id, block);
+            if range.len() < BLOCK_SIZE {
+                txn.dirty.insert(block_id, range);
+            }
+            return true;
+        };
+        if let Some(dirty) = txn.dirty.get_mut(&block_id) {
+            if range.start > dirty.end || range.end < dirty.start {
+                return false;
+            }
+            *dirty = dirty.start.min(range.start)..dirty.end.max(range.end);
+            if dirty.len() == BLOCK_SIZE {
+                txn.dirty.remove(&block_id);
+            }
+        }
+        block.write_offset(offset, data);
+        true
+    }
+
+    /// Defer a discard to the commit of the running transaction. Return
+    /// `false` if no transaction is running.
+    pub(super) fn txn_discard(&self, start: PBlockId, count: u64) -> bool {
+        let mut txn = self.txn_lock();
+        if !txn.active {
+            return false;
+        }
+        txn.discards.push((start, count));
+        true
+    }
+
+    /// Whether the data of a file is written through the journal: with
+    /// the `data=journal` option or the `JOURNAL_DATA` flag, on a
+    /// filesystem with a journal.
+    pub(super) fn journals_data(&self, file: &InodeRef) -> bool {
+        (self.data_journal || file.inode.user_flags().contains(InodeFlags::JOURNAL_DATA))
+            && self.read_super_block().journal_inode() != 0
+    }
+
+    /// The number of data bytes to write in one data journaling
+    /// transaction. Half of the journal is left for metadata blocks and
+    /// descriptor blocks.
+    pub(super) fn journal_data_chunk(&self) -> Result<usize> {
+        let jsb = self.journal_open()?.sb;
+        let blocks = (jsb.max_len() - jsb.first()) as usize / 2;
+        Ok(blocks.max(1) * BLOCK_SIZE)
+    }
+
+    /// Replay the committed transactions left in the journal, e.g. after a
+    /// crash. Called on load.
+    pub(super) fn journal_recover(&self) -> Result<()> {
+        if self.read_super_block().journal_inode() == 0 {
+            return Ok(());
+        }
+        let mut journal = self.journal_open()?;
+        let mut next_sequence = journal.sb.sequence();
+        if journal.sb.start() != 0 {
+            let (transactions, revoked) = self.journal_scan(&journal)?;
+            for txn in &transactions {
+                for &(home, jblock, escaped) in &txn.blocks {
+                    let revoked = revoked
+                        .get(&home)
+                        .is_some_and(|&seq| seq.wrapping_sub(txn.sequence) as i32 >= 0);
+                    if revoked {
+                        continue;
+                    }
+                    let mut block = self.read_block(self.journal_block(&journal, jblock)?);
+                    if escaped {
+                        put_be32(&mut block.data, 0, JBD2_MAGIC);
+                    }
+                    block.id = home;
+                    self.write_block(&block);
+                }
+                next_sequence = txn.sequence.wrapping_add(1);
+            }
+            log_fs!(info, "Replayed {} journal transactions", transactions.len());
+            self.flush_device();
+            journal.sb.set_start(0);
+            journal.sb.set_sequence(next_sequence);
+            self.journal_write_super(&mut journal)?;
+        }
+        let mut sb = self.read_super_block();
+        if sb.needs_recovery() {
+            sb.set_needs_recovery(false);
+            self.write_super_block(&sb);
+        }
+        self.flush_device();
+        Ok(())
+    }
+
+    /// Log blocks to the journal as one transaction, then write them home.
+    fn journal_commit(&self, blocks: Vec<Block>) -> Result<()> {
+        let mut journal = self.journal_open()?;
+        let jsb = journal.sb;
+        let tag_size = jsb.tag_size();
+        let tail = if jsb.has_csum() { JBD2_TAIL_SIZE } else { 0 };
+        // The first tag of each descriptor block is followed by the UUID
+        let tags_per_desc = (BLOCK_SIZE - JBD2_HEADER_SIZE - tail - 16) / tag_size;
+        let needed = blocks.len().div_ceil(tags_per_desc) + blocks.len() + 1;
+        if needed as u64 > (jsb.max_len() - jsb.first()) as u64 {
+            return_error!(
+                ErrCode::ENOSPC,
+                "Transaction of {} blocks does not fit in the journal",
+                blocks.len()
+            );
+        }
+        let sequence = jsb.sequence();
+        let seed = jsb.checksum_seed();
+
+        // Log the blocks
+        let mut pos = jsb.first();
+        for chunk in blocks.chunks(tags_per_desc) {
+            let desc_pos = pos;
+            let mut desc = [0; BLOCK_SIZE];
+            put_jbd2_header(&mut desc, JBD2_DESCRIPTOR_BLOCK, sequence);
+            let mut offset = JBD2_HEADER_SIZE;
+            for (i, block) in chunk.iter().enumerate() {
+                pos += 1;
+                let mut data = block.data;
+                let mut flags = JournalTagFlags::empty();
+                if be32(&data, 0) == JBD2_MAGIC {
+                    put_be32(&mut data, 0, 0);
+                    flags |= JournalTagFlags::ESCAPE;
+                }
+                if i > 0 {
+                    flags |= JournalTagFlags::SAME_UUID;
+                }
+                if i == chunk.len() - 1 {
+                    flags |= JournalTagFlags::LAST_TAG;
+                }
+                let csum = jbd2_tag_checksum(seed, sequence, &data);
+                write_tag(&jsb, &mut desc[offset..], block.id, flags, csum);
+                offset += tag_size;
+                if i == 0 {
+                    desc[offset..offset + 16].copy_from_slice(&jsb.uuid());
+                    offset += 16;
+                }
+                self.write_block(&Block::new(self.journal_block(&journal, pos)?, data));
+            }
+            if jsb.has_csum() {
+                let csum = jbd2_block_checksum(seed, &desc, BLOCK_SIZE - JBD2_TAIL_SIZE);
+                put_be32(&mut desc, BLOCK_SIZE - JBD2_TAIL_SIZE, csum);
+            }
+            self.write_block(&Block::new(self.journal_block(&journal, desc_pos)?, desc));
+            pos += 1;
+        }
+        self.flush_device();
+        journal.sb.set_start(jsb.first());
+        journal.sb.set_sequence(sequence);
+        self.journal_write_super(&mut journal)?;
+        let mut sb = self.read_super_block();
+        sb.set_needs_recovery(true);
+        self.write_super_block(&sb);
+        self.flush_device();
+
+        // The commit block makes the transaction durable
+        let mut commit = [0; BLOCK_SIZE];
+        put_jbd2_header(&mut commit, JBD2_COMMIT_BLOCK, sequence);
+        let now = self.wall_clock.map_or(0, |clock| clock());
+        commit[48..56].copy_from_slice(&now.to_be_bytes());
+        if jsb.has_csum() {
+            let csum = jbd2_block_checksum(seed, &commit, 16);
+            put_be32(&mut commit, 16, csum);
+        }
+        self.write_block(&Block::new(self.journal_block(&journal, pos)?, commit));
+        self.flush_device();
+
+        // Write the blocks home. The superblock keeps the recovery flag
+        // until the journal is marked empty.
+        for block in blocks.iter().filter(|block| block.id != 0) {
+            self.write_block(block);
+        }
+        if let Some(block) = blocks.iter().find(|block| block.id == 0) {
+            let mut sb: SuperBlock = block.read_offset_as(BASE_OFFSET);
+            sb.set_needs_recovery(true);
+            self.write_super_block(&sb);
+        }
+        self.flush_device();
+        journal.sb.set_start(0);
+        journal.sb.set_sequence(sequence.wrapping_add(1));
+        self.journal_write_super(&mut journal)?;
+        let mut sb = self.read_super_block();
+        sb.set_needs_recovery(false);
+        self.write_super_block(&sb);
+        self.flush_device();
+        Ok(())
+    }
+
+    /// Find the committed transactions in the log, and the blocks revoked
+    /// with the last transaction revoking each.
+    fn journal_scan(
+        &self,
+        journal: &Journal,
+    ) -> Result<(Vec<LoggedTransaction>, BTreeMap<PBlockId, u32>)> {
+        let jsb = &journal.sb;
+        let seed = jsb.checksum_seed();
+        let bit64 = jsb.features_incompatible().contains(JournalIncompat::BIT64);
+        let tail = if jsb.has_csum() { JBD2_TAIL_SIZE } else { 0 };
+        let next = |pos: u32| {
+            if pos + 1 >= jsb.max_len() {
+                jsb.first()
+            } else {
+                pos + 1
+            }
+        };
+
+        let mut transactions = Vec::new();
+        let mut revoked = BTreeMap::new();
+        let mut sequence = jsb.sequence();
+        let mut current = LoggedTransaction {
+            sequence,
+            blocks: Vec::new(),
+        };
+        let mut current_revoked = Vec::new();
+        let mut pos = jsb.start();
+        // Every journal block is visited at most once. A transaction with
+        // a block that does not match its checksum is not replayed.
+        'scan: for _ in 0..jsb.max_len() {
+            let block = self.read_block(self.journal_block(journal, pos)?);
+            let data = &block.data;
+            if be32(data, 0) != JBD2_MAGIC || be32(data, 8) != sequence {
+                break;
+            }
+            match be32(data, 4) {
+                JBD2_DESCRIPTOR_BLOCK => {
+                    let csum_offset = BLOCK_SIZE - JBD2_TAIL_SIZE;
+                    if jsb.has_csum()
+                        && be32(data, csum_offset) != jbd2_block_checksum(seed, data, csum_offset)
+                    {
+                        break;
+                    }
+                    let mut offset = JBD2_HEADER_SIZE;
+                    while offset + jsb.tag_size() <= BLOCK_SIZE - tail {
+                        let (home, flags, csum) = read_tag(jsb, &data[offset..]);
+                        pos = next(pos);
+                        if !flags.contains(JournalTagFlags::DELETED) {
+                            if jsb.has_csum() {
+                                let logged = self.read_block(self.journal_block(journal, pos)?);
+                                let expected = jbd2_tag_checksum(seed, sequence, &logged.data);
+                                if csum != expected & csum_mask(jsb) {
+                                    break 'scan;
+                                }
+                            }
+                            current.blocks.push((
+                                home,
+                                pos,
+                                flags.contains(JournalTagFlags::ESCAPE),
+                            ));
+                        }
+                        offset += jsb.tag_size();
+                        if !flags.contains(JournalTagFlags::SAME_UUID) {
+                            offset += 16;
+                        }
+                        if flags.contains(JournalTagFlags::LAST_TAG) {
+                            break;
+                        }
+                    }
+                }
+                JBD2_COMMIT_BLOCK => {
+                    if jsb.has_csum() && be32(data, 16) != jbd2_block_checksum(seed, data, 16) {
+                        break;
+                    }
+                    for home in current_revoked.drain(..) {
+                        revoked.insert(home, sequence);
+                    }
+                    sequence = sequence.wrapping_add(1);
+                    let txn = mem::replace(
+                        &mut current,
+                        LoggedTransaction {
+                            sequence,
+                            blocks: Vec::new(),
+                        },
+                    );
+                    transactions.push(txn);
+                }
+                JBD2_REVOKE_BLOCK => {
+                    let size = if bit64 { 8 } else { 4 };
+                    let end = (be32(data, 12) as usize).min(BLOCK_SIZE - tail);
+                    let mut offset = JBD2_HEADER_SIZE + 4;
+                    while offset + size <= end {
+                        let home = if bit64 {
+                            (be32(data, offset) as u64) << 32 | be32(data, offset + 4) as u64
+                        } else {
+                            be32(data, offset) as u64
+                        };
+                        current_revoked.push(home);
+                        offset += size;
+                    }
+                }
+                _ => break,
+            }
+            pos = next(pos);
+        }
+        Ok((transactions, revoked))
+    }
+
+    /// Read the journal inode and superblock.
+    fn journal_open(&self) -> Result<Journal> {
+        let ino = self.read_super_block().journal_inode();
+        if ino == 0 {
+            return_error!(ErrCode::ENOTSUP, "The filesystem has no journal");
+        }
+        let inode = self.read_inode(ino);
+        let block = self.read_block(self.extent_query(&inode, 0)?);
+        let sb: JournalSuperBlock = block.read_offset_as(0);
+        if !sb.check_magic() || sb.block_size() != BLOCK_SIZE as u32 || sb.first() == 0 {
+            let err = format_error!(ErrCode::EFSCORRUPTED, "Invalid journal superblock");
+            return Err(self.fs_error("journal_open", ino, 0, err));
+        }
+        Ok(Journal { inode, sb })
+    }
+
+    /// Write the journal superblock.
+    fn journal_write_super(&self, journal: &mut Journal) -> Result<()> {
+        journal.sb.set_checksum();
+        let mut block = self.read_block(self.journal_block(journal, 0)?);
+        block.write_offset_as(0, &journal.sb);
+        self.write_block(&block);
+        Ok(())
+    }
+
+    /// Get the physical block of a journal block.
+    fn journal_block(&self, journal: &Journal, jblock: u32) -> Result<PBlockId> {
+        self.extent_query(&journal.inode, jblock)
+    }
+}
+
+/// Write a block tag into a descriptor block.
+fn write_tag(
+    jsb: &JournalSuperBlock,
+    tag: &mut [u8],
+    home: PBlockId,
+    flags: JournalTagFlags,
+    csum: u32,
+) {
+    let incompat = jsb.features_incompatible();
+    put_be32(tag, 0, home as u32);
+    if incompat.contains(JournalIncompat::CSUM_V3) {
+        put_be32(tag, 4, flags.bits());
+        put_be32(tag, 12, csum);
+    } else {
+        if incompat.contains(JournalIncompat::CSUM_V2) {
+            put_be16(tag, 4, csum as u16);
+        }
+        put_be16(tag, 6, flags.bits() as u16);
+    }
+    if incompat.contains(JournalIncompat::BIT64) {
+        put_be32(tag, 8, (home >> 32) as u32);
+    }
+}
+
+/// Read the home block, flags and data checksum of a block tag.
+fn read_tag(jsb: &JournalSuperBlock, tag: &[u8]) -> (PBlockId, JournalTagFlags, u32) {
+    let incompat = jsb.features_incompatible();
+    let mut home = be32(tag, 0) as PBlockId;
+    if incompat.contains(JournalIncompat::BIT64) {
+        home |= (be32(tag, 8) as PBlockId) << 32;
+    }
+    let (flags, csum) = if incompat.contains(JournalIncompat::CSUM_V3) {
+        (be32(tag, 4), be32(tag, 12))
+    } else {
+        (be16(tag, 6) as u32, be16(tag, 4) as u32)
+    };
+    (home, JournalTagFlags::from_bits_retain(flags), csum)
+}
 
-    /// stop transaction
-    #[allow(unused)]
-    pub(super) fn trans_abort(&self) {}
+/// The bits of a data checksum stored in block tags: v2 tags only keep the
+/// low 16 bits.
+fn csum_mask(jsb: &JournalSuperBlock) -> u32 {
+    if jsb
+        .features_incompatible()
+        .contains(JournalIncompat::CSUM_V3)
+    {
+        u32::MAX
+    } else {
+        u16::MAX as u32
+    }
 }
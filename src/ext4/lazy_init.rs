@@ -0,0 +1,98 @@
+//! Lazy initialization of inode tables.
+//!
+//! A filesystem created with lazy inode table initialization leaves the
+//! unused part of each inode table uninitialized. The tables are zeroed
+//! later in the background, and `ITABLE_ZEROED` is set in the block group
+//! flags once a group is done.
+//!
+//! The crate does not spawn threads. Instead, the embedder acquires a
+//! `LazyInitCursor` and calls `Ext4::lazy_init_step` whenever it decides to,
+//! e.g. in idle time, zeroing a bounded number of blocks per call.
+
+use super::Ext4;
+use crate::constants::*;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+
+/// Progress of inode table zeroing.
+///
+/// The cursor can be dropped at any time. Completed groups are recorded
+/// on disk, so a new cursor resumes from the first group not yet zeroed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LazyInitCursor {
+    /// The block group being zeroed
+    bgid: BlockGroupId,
+    /// The next block to zero, relative to the start of the inode table
+    block: u32,
+}
+
+impl LazyInitCursor {
+    /// The block group being zeroed.
+    pub fn block_group(&self) -> BlockGroupId {
+        self.bgid
+    }
+}
+
+impl Ext4 {
+    /// Create a cursor pointing to the first block group whose inode table
+    /// is not zeroed.
+    pub fn lazy_init_cursor(&self) -> LazyInitCursor {
+        let mut cursor = LazyInitCursor { bgid: 0, block: 0 };
+        self.lazy_init_skip_zeroed(&mut cursor);
+        cursor
+    }
+
+    /// Zero at most `max_blocks` blocks of inode tables, starting from the
+    /// cursor position.
+    ///
+    /// # Return
+    ///
+    /// `Ok(true)` if all inode tables are zeroed, `Ok(false)` if there is
+    /// more work to do.
+    pub fn lazy_init_step(&self, cursor: &mut LazyInitCursor, max_blocks: usize) -> Result<bool> {
+        let sb = self.read_super_block();
+        let inode_size = sb.inode_size();
+        let inodes_per_group = sb.inodes_per_group() as usize;
+        let table_blocks = sb.inode_table_blocks();
+        let mut budget = max_blocks;
+
+        while budget > 0 && cursor.bgid < sb.block_group_count() {
+            let mut bg = self.read_block_group(cursor.bgid);
+            // Never zero inodes in use. Recompute the used part every time,
+            // because inodes may have been allocated since the last step.
+            let used_inodes = inodes_per_group - bg.desc.itable_unused() as usize;
+            let used_blocks = (used_inodes * inode_size).div_ceil(BLOCK_SIZE) as u32;
+            cursor.block = cursor.block.max(used_blocks);
+            while budget > 0 && cursor.block < table_blocks {
+                let pblock = bg.desc.inode_table_first_block() + cursor.block as PBlockId;
+                self.write_block(&Block::new(pblock, [0; BLOCK_SIZE]));
+                cursor.block += 1;
+                budget -= 1;
+            }
+            if cursor.block >= table_blocks {
+                // Record the completed group
+                bg.desc
+                    .set_flags(bg.desc.flags() | BlockGroupFlags::ITABLE_ZEROED);
+                self.write_block_group(&mut bg);
+                log_fs!(debug, "Inode table of block group {} zeroed", cursor.bgid);
+                cursor.bgid += 1;
+                cursor.block = 0;
+                self.lazy_init_skip_zeroed(cursor);
+            }
+        }
+        Ok(cursor.bgid >= sb.block_group_count())
+    }
+
+    /// Move the cursor to the next block group whose inode table is not zeroed.
+    fn lazy_init_skip_zeroed(&self, cursor: &mut LazyInitCursor) {
+        let bg_count = self.read_super_block().block_group_count();
+        while cursor.bgid < bg_count {
+            let bg = self.read_block_group(cursor.bgid);
+            if !bg.desc.flags().contains(BlockGroupFlags::ITABLE_ZEROED) {
+                break;
+            }
+            cursor.bgid += 1;
+            cursor.block = 0;
+        }
+    }
+}
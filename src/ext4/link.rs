@@ -3,26 +3,29 @@ use crate::ext4_defs::*;
 use crate::prelude::*;
 
 impl Ext4 {
-    /// Link a child inode to a parent directory.
+    /// Link a child inode to a parent directory. The parent is unchanged if
+    /// linking fails, e.g. when no block is left for the entries.
     pub(super) fn link_inode(
         &self,
         parent: &mut InodeRef,
         child: &mut InodeRef,
         name: &str,
     ) -> Result<()> {
+        if child.inode.is_dir() {
+            // Link child/".." first, the parent entry is added last
+            self.dir_add_entry(child, parent, "..")?;
+        }
         // Add entry to parent directory
         self.dir_add_entry(parent, child, name)?;
 
         let child_link_count = child.inode.link_count();
         if child.inode.is_dir() {
-            // Link child/".."
-            self.dir_add_entry(child, parent, "..")?;
             parent.inode.set_link_count(parent.inode.link_count() + 1);
-            self.write_inode_with_csum(parent);
+            self.write_inode(parent);
         }
         // Link parent/child
         child.inode.set_link_count(child_link_count + 1);
-        self.write_inode_with_csum(child);
+        self.write_inode(child);
         Ok(())
     }
 
@@ -36,23 +39,28 @@ impl Ext4 {
         name: &str,
         free: bool, 
     ) -> Result<()> {
+        let child_link_cnt = child.inode.link_count();
+        let free = free && ((child.inode.is_dir() && child_link_cnt <= 2) || child_link_cnt <= 1);
+        if free {
+            // Pinned blocks must not be freed
+            self.check_unpinned(child.id, 0)?;
+        }
         // Remove entry from parent directory
         self.dir_remove_entry(parent, name)?;
 
-        let child_link_cnt = child.inode.link_count();
         if child.inode.is_dir() {
             // Child is a directory
             // Unlink "child/.."
             self.dir_remove_entry(child, "..")?;
             parent.inode.set_link_count(parent.inode.link_count() - 1);
-            self.write_inode_with_csum(parent);
+            self.write_inode(parent);
         }
-        if free && ((child.inode.is_dir() && child_link_cnt <= 2) || child_link_cnt <= 1) {
+        if free {
             // Remove file or directory
             return self.free_inode(child);
         }
         child.inode.set_link_count(child_link_cnt - 1);
-        self.write_inode_with_csum(child);
+        self.write_inode(child);
         Ok(())
     }
 }
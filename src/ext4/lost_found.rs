@@ -0,0 +1,113 @@
+//! The `lost+found` directory and reattachment of disconnected inodes.
+//!
+//! Like `e2fsck`, inodes that are in use but not reachable from the root
+//! are linked into `/lost+found` under the name `#<inode number>`, so that
+//! recovery tools built on this crate leave the filesystem in the state
+//! `e2fsck` would.
+
+use super::Ext4;
+use crate::constants::*;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+use crate::return_error;
+
+impl Ext4 {
+    /// The name of the `lost+found` directory under the root.
+    const LOST_AND_FOUND: &'static str = "lost+found";
+
+    /// Get the `lost+found` directory, creating it with mode 0700 if it
+    /// does not exist.
+    ///
+    /// # Error
+    ///
+    /// * `ENOTDIR` - `/lost+found` exists but is not a directory
+    /// * `ENOSPC` - no space left on device
+    pub fn lost_and_found(&self) -> Result<InodeId> {
+        let root = self.read_root_inode();
+        match self.dir_find_entry(&root, Self::LOST_AND_FOUND) {
+            Ok(ino) => {
+                if !self.read_inode(ino).inode.is_dir() {
+                    return_error!(
+                        ErrCode::ENOTDIR,
+                        "/{} is not a directory",
+                        Self::LOST_AND_FOUND
+                    );
+                }
+                Ok(ino)
+            }
+            Err(err) if err.code() == ErrCode::ENOENT => self.mkdir(
+                EXT4_ROOT_INO,
+                Self::LOST_AND_FOUND,
+                InodeMode::from_bits_retain(0o700),
+            ),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Link an inode that is in use but not reachable from the root into
+    /// `lost+found` as `#<inode number>`, creating the directory if needed.
+    ///
+    /// The new entry replaces the lost one: the link count of a file is
+    /// kept, or set to 1 if it has no links. An orphan is removed from the
+    /// orphan list. For a directory, `..` is pointed to `lost+found` and
+    /// the link counts of the old and new parent are updated.
+    ///
+    /// # Return
+    ///
+    /// `Ok(name)` - the name of the new entry in `lost+found`
+    ///
+    /// # Error
+    ///
+    /// * `EINVAL` - the inode is free or reserved
+    /// * `EROFS` - the filesystem is read-only
+    /// * `EEXIST` - the inode is already in `lost+found`
+    /// * `ENOSPC` - no space left on device
+    pub fn reattach(&self, inode: InodeId) -> Result<String> {
+        self.check_writable()?;
+        let sb = self.read_super_block();
+        if inode < sb.first_inode() || inode > sb.inode_count() {
+            return_error!(ErrCode::EINVAL, "Inode {} cannot be reattached", inode);
+        }
+        let mut child = self.read_inode(inode);
+        if child.inode.mode().bits() == 0 {
+            return_error!(ErrCode::EINVAL, "Inode {} is free", inode);
+        }
+        let mut lpf = self.read_inode(self.lost_and_found()?);
+        let name = format!("#{}", inode);
+        if self.dir_find_entry(&lpf, &name).is_ok() {
+            return_error!(ErrCode::EEXIST, "Inode {} is already in lost+found", inode);
+        }
+        // Take the inode off the orphan list, if it is on it
+        if child.inode.dtime() != 0 {
+            match self.orphan_remove(&mut child) {
+                Err(err) if err.code() != ErrCode::ENOENT => return Err(err),
+                _ => child.inode.set_dtime(0),
+            }
+        }
+        self.dir_add_entry(&mut lpf, &child, &name)?;
+        if child.inode.is_dir() {
+            // Point ".." to lost+found and move the parent link
+            let old_parent = self.dir_find_entry(&child, "..")?;
+            self.dir_replace_entry(&child, "..", &lpf)?;
+            lpf.inode.set_link_count(lpf.inode.link_count() + 1);
+            self.write_inode(&mut lpf);
+            if old_parent != lpf.id && old_parent != child.id {
+                let mut old_parent = self.read_inode(old_parent);
+                if old_parent.inode.is_dir() && old_parent.inode.link_count() > 2 {
+                    old_parent
+                        .inode
+                        .set_link_count(old_parent.inode.link_count() - 1);
+                    self.write_inode(&mut old_parent);
+                }
+            }
+        }
+        if child.inode.link_count() == 0 {
+            child
+                .inode
+                .set_link_count(if child.inode.is_dir() { 2 } else { 1 });
+        }
+        self.write_inode(&mut child);
+        log_fs!(info, "Reattach inode {} to lost+found as {}", inode, name);
+        Ok(name)
+    }
+}
@@ -3,6 +3,10 @@
 //! These interfaces are designed and arranged coresponding to FUSE low-level ops.
 //! Ref: https://libfuse.github.io/doxygen/structfuse__lowlevel__ops.html
 
+use super::crypt::Decryptor;
+use super::dir::DirAppender;
+use super::perm::Access;
+use super::stats::Op;
 use super::Ext4;
 use crate::constants::*;
 use crate::ext4_defs::*;
@@ -10,6 +14,7 @@ use crate::format_error;
 use crate::prelude::*;
 use crate::return_error;
 use core::cmp::min;
+use core::ops::{Deref, DerefMut};
 
 impl Ext4 {
     /// Get file attributes.
@@ -26,24 +31,46 @@ impl Ext4 {
     ///
     /// `EINVAL` if the inode is invalid (link count == 0).
     pub fn getattr(&self, id: InodeId) -> Result<FileAttr> {
+        let _timer = self.stats.op(Op::Getattr);
         let inode = self.read_inode(id);
         if inode.inode.link_count() == 0 {
             return_error!(ErrCode::EINVAL, "Invalid inode {}", id);
         }
-        Ok(FileAttr {
-            ino: id,
+        Ok(Self::file_attr(&inode))
+    }
+
+    /// Get the attributes of an inode.
+    pub(super) fn file_attr(inode: &InodeRef) -> FileAttr {
+        let rdev = match inode.inode.file_type() {
+            FileType::CharacterDev | FileType::BlockDev => inode.inode.device(),
+            _ => (0, 0),
+        };
+        let mut attributes_mask = AttrMask::BASIC_STATS;
+        if inode.inode.has_crtime() {
+            attributes_mask |= AttrMask::BTIME;
+        }
+        FileAttr {
+            ino: inode.id,
             size: inode.inode.size(),
             blocks: inode.inode.block_count(),
             atime: inode.inode.atime(),
             mtime: inode.inode.mtime(),
             ctime: inode.inode.ctime(),
             crtime: inode.inode.crtime(),
+            atime_nsec: inode.inode.atime_nsec(),
+            mtime_nsec: inode.inode.mtime_nsec(),
+            ctime_nsec: inode.inode.ctime_nsec(),
+            crtime_nsec: inode.inode.crtime_nsec(),
             ftype: inode.inode.file_type(),
             perm: inode.inode.perm(),
             links: inode.inode.link_count(),
             uid: inode.inode.uid(),
             gid: inode.inode.gid(),
-        })
+            generation: inode.inode.generation(),
+            rdev,
+            blksize: BLOCK_SIZE as u32,
+            attributes_mask,
+        }
     }
 
     /// Set file attributes.
@@ -51,18 +78,26 @@ impl Ext4 {
     /// # Params
     ///
     /// * `id` - inode id
-    /// * `mode` - file mode
+    /// * `mode` - permission bits, setuid, setgid and sticky included. The
+    ///   file type is kept, whatever the type bits of `mode`
     /// * `uid` - 32-bit user id
     /// * `gid` - 32-bit group id
-    /// * `size` - 64-bit file size
+    /// * `size` - 64-bit file size. Shrinking frees the blocks past the new
+    ///   end of file. Changing the size clears the setuid and setgid bits as
+    ///   a write does, see `write`
     /// * `atime` - 32-bit access time in seconds
     /// * `mtime` - 32-bit modify time in seconds
     /// * `ctime` - 32-bit change time in seconds
-    /// * `crtime` - 32-bit create time in seconds
+    /// * `crtime` - 32-bit create time in seconds, ignored if the inode is
+    ///   too small to store it
     ///
     /// # Error
     ///
-    /// `EINVAL` if the inode is invalid (mode == 0).
+    /// * `EINVAL` - the inode is invalid (mode == 0)
+    /// * `EPERM` - the inode is immutable or append-only
+    /// * `ENOKEY` - the size of an encrypted inode is changed
+    /// * `EPERM` - the size of a verity file is changed
+    /// * `EBUSY` - the file is shrunk below a block pinned by `pin_block`
     pub fn setattr(
         &self,
         id: InodeId,
@@ -75,12 +110,20 @@ impl Ext4 {
         ctime: Option<u32>,
         crtime: Option<u32>,
     ) -> Result<()> {
+        let _timer = self.stats.op(Op::Setattr);
+        let _batch = self.inode_batch();
+        self.check_writable()?;
         let mut inode = self.read_inode(id);
         if inode.inode.mode().bits() == 0 {
             return_error!(ErrCode::EINVAL, "Invalid inode {}", id);
         }
-        if let Some(mode) = mode {
-            inode.inode.set_mode(mode);
+        let changes = [atime, mtime, ctime, crtime, uid, gid];
+        if mode.is_some() || size.is_some() || changes.iter().any(Option::is_some) {
+            Self::check_modify(&inode, false)?;
+        }
+        if size.is_some() {
+            Self::check_unencrypted(&inode)?;
+            Self::check_not_verity(&inode)?;
         }
         if let Some(uid) = uid {
             inode.inode.set_uid(uid);
@@ -88,13 +131,37 @@ impl Ext4 {
         if let Some(gid) = gid {
             inode.inode.set_gid(gid);
         }
+        // Against the new group, after a chown
+        if let Some(mode) = mode {
+            let mode = self.chmod_mode(&inode, mode);
+            inode.inode.set_mode(mode);
+        }
         if let Some(size) = size {
-            // If size increases, allocate new blocks if needed.
-            let required_blocks = (size as usize).div_ceil(INODE_BLOCK_SIZE);
-            for _ in inode.inode.block_count()..required_blocks as u64 {
-                self.inode_append_block(&mut inode)?;
+            if size > MAX_FILE_SIZE {
+                return_error!(ErrCode::EFBIG, "Size {} of inode {} is too large", size, id);
+            }
+            let end = size.div_ceil(BLOCK_SIZE as u64) as LBlockId;
+            if size < inode.inode.size() {
+                // Blocks past the new end of file are freed
+                self.check_unpinned(id, end)?;
+            }
+            if self.zero_freed(&inode) {
+                // Securely deleted data must not survive past the end of file
+                self.zero_range(&inode, size, inode.inode.size());
+            }
+            let old_size = inode.inode.size();
+            if size > old_size {
+                // Growing leaves a hole, only blocks mapped past the old
+                // end of file may hold stale data
+                let mapped_end = self.extent_end(&inode)? as u64 * BLOCK_SIZE as u64;
+                self.zero_range(&inode, old_size, min(size, mapped_end));
+            } else if size < old_size {
+                self.extent_truncate(&mut inode, end)?;
+            }
+            self.set_file_size(&mut inode, size);
+            if mode.is_none() {
+                self.clear_setid(&mut inode);
             }
-            inode.inode.set_size(size);
         }
         if let Some(atime) = atime {
             inode.inode.set_atime(atime);
@@ -108,7 +175,7 @@ impl Ext4 {
         if let Some(crtime) = crtime {
             inode.inode.set_crtime(crtime);
         }
-        self.write_inode_with_csum(&mut inode);
+        self.write_inode(&mut inode);
         Ok(())
     }
 
@@ -129,20 +196,290 @@ impl Ext4 {
     /// # Error
     ///
     /// * `ENOTDIR` - `parent` is not a directory
+    /// * `EPERM` - `parent` is immutable
     /// * `ENOSPC` - No space left on device
+    /// * `ENAMETOOLONG` - `name` is longer than `NAME_MAX`
+    /// * `EINVAL` - `name` is empty or contains '/' or '\0'
+    /// * `ENOKEY` - `parent` is encrypted
     pub fn create(&self, parent: InodeId, name: &str, mode: InodeMode) -> Result<InodeId> {
+        let _timer = self.stats.op(Op::Create);
+        let _batch = self.inode_batch();
+        self.check_writable()?;
         let mut parent = self.read_inode(parent);
         // Can only create a file in a directory
         if !parent.inode.is_dir() {
             return_error!(ErrCode::ENOTDIR, "Inode {} is not a directory", parent.id);
         }
+        Self::dir_check_name(name)?;
+        self.check_dir_write(&parent)?;
+        Self::check_modify(&parent, true)?;
+        Self::check_unencrypted(&parent)?;
         // Create child inode and link it to parent directory
-        let mut child = self.create_inode(mode)?;
-        self.link_inode(&mut parent, &mut child, name)?;
+        let mut child = self.create_inode(parent.id, self.apply_umask(mode))?;
+        self.init_owner(&parent, &mut child);
+        self.link_inode(&mut parent, &mut child, name)
+            .map_err(|err| self.free_new_inode(&mut child, err))?;
         // Create file handler
         Ok(child.id)
     }
 
+    /// Create many files in a directory at once, faster than calling
+    /// `create` for each: the directory blocks are loaded and checksummed
+    /// once for all entries, and inode writes are batched. Entries with a
+    /// directory mode are created as empty directories, as by `mkdir`. This
+    /// function will not check the existence of the names.
+    ///
+    /// # Params
+    ///
+    /// * `parent` - parent directory inode id
+    /// * `entries` - names and modes of the files to create
+    ///
+    /// # Return
+    ///
+    /// `Ok(inodes)` - Inode ids of the new files, in the order of `entries`
+    ///
+    /// # Error
+    ///
+    /// The files created before an error are kept.
+    ///
+    /// * `ENOTDIR` - `parent` is not a directory
+    /// * `EPERM` - `parent` is immutable
+    /// * `ENOSPC` - No space left on device
+    /// * `ENAMETOOLONG` - a name is longer than `NAME_MAX`
+    /// * `EINVAL` - a name is empty or contains '/' or '\0'
+    /// * `ENOKEY` - `parent` is encrypted
+    pub fn create_many(
+        &self,
+        parent: InodeId,
+        entries: &[(&str, InodeMode)],
+    ) -> Result<Vec<InodeId>> {
+        let _timer = self.stats.op(Op::Create);
+        let _batch = self.inode_batch();
+        self.check_writable()?;
+        let mut parent = self.read_inode(parent);
+        // Can only create files in a directory
+        if !parent.inode.is_dir() {
+            return_error!(ErrCode::ENOTDIR, "Inode {} is not a directory", parent.id);
+        }
+        for (name, _) in entries {
+            Self::dir_check_name(name)?;
+        }
+        self.check_dir_write(&parent)?;
+        Self::check_modify(&parent, true)?;
+        Self::check_unencrypted(&parent)?;
+        let mut appender = DirAppender::default();
+        let mut created = Vec::with_capacity(entries.len());
+        let res = entries.iter().try_for_each(|&(name, mode)| {
+            created.push(self.create_appended(&mut parent, &mut appender, name, mode)?);
+            Ok(())
+        });
+        self.dir_append_finish(&mut parent, &mut appender);
+        res.map(|_| created)
+    }
+
+    /// Create a file, or an empty directory, and add its entry to `parent`
+    /// through `appender`, see `create_many`.
+    fn create_appended(
+        &self,
+        parent: &mut InodeRef,
+        appender: &mut DirAppender,
+        name: &str,
+        mode: InodeMode,
+    ) -> Result<InodeId> {
+        let mut child = self.create_inode(parent.id, self.apply_umask(mode))?;
+        self.init_owner(parent, &mut child);
+        let is_dir = child.inode.is_dir();
+        if is_dir {
+            // Add "." and ".." entries
+            let child_self = child.clone();
+            self.dir_add_entry(&mut child, &child_self, ".")
+                .map_err(|err| self.free_new_inode(&mut child, err))?;
+            self.dir_add_entry(&mut child, parent, "..")
+                .map_err(|err| self.free_new_inode(&mut child, err))?;
+        }
+        self.dir_append_entry(parent, appender, &child, name)
+            .map_err(|err| self.free_new_inode(&mut child, err))?;
+        if is_dir {
+            child.inode.set_link_count(2);
+            // Written when the appender is finished
+            parent.inode.set_link_count(parent.inode.link_count() + 1);
+        } else {
+            child.inode.set_link_count(1);
+        }
+        self.write_inode(&mut child);
+        Ok(child.id)
+    }
+
+    /// Open a file or directory and create a file handle for it.
+    ///
+    /// # Params
+    ///
+    /// * `inode` - the inode to open
+    /// * `flags` - open flags, saved in the file handle
+    ///
+    /// # Return
+    ///
+    /// `Ok(fh)` - the id of the new file handle
+    ///
+    /// # Error
+    ///
+    /// * `EINVAL` - the inode is out of range or invalid (mode == 0)
+    /// * `EMFILE` - too many open file handles
+    pub fn open(&self, inode: InodeId, flags: u32) -> Result<FileHandleId> {
+        if inode == 0 || inode > self.read_super_block().inode_count() {
+            return_error!(ErrCode::EINVAL, "Inode {} out of range", inode);
+        }
+        let inode_ref = self.read_inode(inode);
+        if inode_ref.inode.mode().bits() == 0 {
+            return_error!(ErrCode::EINVAL, "Invalid inode {}", inode);
+        }
+        self.handles.lock().insert(FileHandle { inode, flags })
+    }
+
+    /// Open an inode by an exported `(ino, generation)` pair, as obtained
+    /// from `getattr`. Used by NFS servers and by clients that cache inode
+    /// numbers across mounts.
+    ///
+    /// # Params
+    ///
+    /// * `inode` - the inode id
+    /// * `generation` - the generation number of the inode
+    /// * `flags` - open flags
+    ///
+    /// # Return
+    ///
+    /// `Ok(fh)` - the id of the new file handle
+    ///
+    /// # Error
+    ///
+    /// * `ESTALE` - the inode does not exist, is free, or has been reused
+    ///   since the pair was exported
+    /// * `EMFILE` - too many open file handles
+    pub fn open_by_handle(
+        &self,
+        inode: InodeId,
+        generation: u32,
+        flags: u32,
+    ) -> Result<FileHandleId> {
+        if inode == 0 || inode > self.read_super_block().inode_count() {
+            return_error!(ErrCode::ESTALE, "Inode {} out of range", inode);
+        }
+        let inode_ref = self.read_inode(inode);
+        let in_use = inode_ref.inode.link_count() > 0 || self.is_open(inode);
+        if inode_ref.inode.mode().bits() == 0 || !in_use {
+            return_error!(ErrCode::ESTALE, "Inode {} is free", inode);
+        }
+        if inode_ref.inode.generation() != generation {
+            return_error!(
+                ErrCode::ESTALE,
+                "Inode {} generation {} does not match {}",
+                inode,
+                inode_ref.inode.generation(),
+                generation
+            );
+        }
+        self.open(inode, flags)
+    }
+
+    /// Get the state of an open file handle.
+    ///
+    /// # Error
+    ///
+    /// `EBADF` - `fh` is not an open file handle
+    pub fn file_handle(&self, fh: FileHandleId) -> Result<FileHandle> {
+        self.handles.lock().get(fh)
+    }
+
+    /// Release a file handle acquired by `open`.
+    ///
+    /// If this is the last handle of an inode that has no links, i.e. an
+    /// unlinked file created by `create_unlinked`, the inode is freed.
+    ///
+    /// # Error
+    ///
+    /// `EBADF` - `fh` is not an open file handle
+    pub fn release(&self, fh: FileHandleId) -> Result<()> {
+        let handle = {
+            let mut handles = self.handles.lock();
+            let handle = handles.remove(fh)?;
+            if handles.is_open(handle.inode) {
+                return Ok(());
+            }
+            handle
+        };
+        let mut inode = self.read_inode(handle.inode);
+        if inode.inode.link_count() == 0 && inode.inode.mode().bits() != 0 {
+            self.orphan_remove(&mut inode)?;
+            self.free_inode(&mut inode)?;
+        }
+        Ok(())
+    }
+
+    /// Create a regular file without any link, like `O_TMPFILE`. The file
+    /// is added to the orphan list, so it will be freed if it is never
+    /// linked. Call `link_tmpfile` to link it to a directory.
+    ///
+    /// # Params
+    ///
+    /// * `mode` - file type and mode with which to create the new file
+    ///
+    /// # Return
+    ///
+    /// `Ok(inode)` - Inode id of the new file
+    ///
+    /// # Error
+    ///
+    /// * `EINVAL` - `mode` is not a regular file
+    /// * `ENOSPC` - No space left on device
+    pub fn create_unlinked(&self, mode: InodeMode) -> Result<InodeId> {
+        self.check_writable()?;
+        if mode.file_type() != FileType::RegularFile {
+            return_error!(ErrCode::EINVAL, "Unlinked inode must be a regular file");
+        }
+        let mut child = self.create_inode(EXT4_ROOT_INO, self.apply_umask(mode))?;
+        if let Some(cred) = &self.cred {
+            child.inode.set_uid(cred.uid);
+            child.inode.set_gid(cred.gid);
+        }
+        self.orphan_add(&mut child);
+        Ok(child.id)
+    }
+
+    /// Link a file created by `create_unlinked` to a directory. This
+    /// function will not check name conflict, call `lookup` to check
+    /// beforehand.
+    ///
+    /// # Params
+    ///
+    /// * `inode` - the unlinked file
+    /// * `parent` - the inode of the directory to link to
+    /// * `name` - the name of the new directory entry
+    ///
+    /// # Error
+    ///
+    /// * `ENOTDIR` - `parent` is not a directory
+    /// * `EINVAL` - `inode` is not an unlinked file
+    /// * `EPERM` - `parent` is immutable
+    /// * `ENOSPC` - no space left on device
+    /// * `ENOKEY` - `parent` is encrypted
+    pub fn link_tmpfile(&self, inode: InodeId, parent: InodeId, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let mut parent = self.read_inode(parent);
+        if !parent.inode.is_dir() {
+            return_error!(ErrCode::ENOTDIR, "Inode {} is not a directory", parent.id);
+        }
+        Self::dir_check_name(name)?;
+        self.check_dir_write(&parent)?;
+        Self::check_modify(&parent, true)?;
+        Self::check_unencrypted(&parent)?;
+        let mut child = self.read_inode(inode);
+        if !child.inode.is_file() || child.inode.link_count() != 0 {
+            return_error!(ErrCode::EINVAL, "Inode {} is not an unlinked file", inode);
+        }
+        self.orphan_remove(&mut child)?;
+        self.link_inode(&mut parent, &mut child, name)
+    }
+
     /// Read data from a file. This function will read exactly `buf.len()`
     /// bytes unless the end of the file is reached.
     ///
@@ -159,43 +496,134 @@ impl Ext4 {
     /// # Error
     ///
     /// * `EISDIR` - `file` is not a regular file
-    pub fn read(&self, file: InodeId, offset: usize, buf: &mut [u8]) -> Result<usize> {
+    /// * `ENOKEY` - `file` is encrypted and cannot be decrypted
+    /// * `EIO` - `file` is a verity file and its data does not match
+    /// * `EFSCORRUPTED` - the extent tree of `file` is malformed
+    pub fn read(&self, file: InodeId, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        self.read_data(file, offset, &mut [buf], false)
+    }
+
+    /// Read data from a file to several buffers, filled in order as if
+    /// they were one. The buffers can be `&mut [u8]`, or
+    /// `std::io::IoSliceMut` to implement `readv`. See `read`.
+    ///
+    /// # Return
+    ///
+    /// `Ok(usize)` - the actual number of bytes read
+    ///
+    /// # Error
+    ///
+    /// * `EISDIR` - `file` is not a regular file
+    /// * `ENOKEY` - `file` is encrypted and cannot be decrypted
+    /// * `EIO` - `file` is a verity file and its data does not match
+    /// * `EFSCORRUPTED` - the extent tree of `file` is malformed
+    pub fn read_vectored<B: DerefMut<Target = [u8]>>(
+        &self,
+        file: InodeId,
+        offset: u64,
+        bufs: &mut [B],
+    ) -> Result<usize> {
+        self.read_data(file, offset, bufs, false)
+    }
+
+    /// Read data from a file through an open file handle. Handles opened
+    /// with `O_DIRECT` read from the block device, bypassing the block
+    /// cache, and need `offset` and the length of `buf` to be multiples of
+    /// `BLOCK_SIZE`.
+    ///
+    /// # Params
+    ///
+    /// * `fh` - the file handle, acquired by `open`
+    /// * `offset` - offset to read from
+    /// * `buf` - the buffer to store the data
+    ///
+    /// # Return
+    ///
+    /// `Ok(usize)` - the actual number of bytes read
+    ///
+    /// # Error
+    ///
+    /// * `EBADF` - `fh` is not an open file handle
+    /// * `EISDIR` - the file is not a regular file
+    /// * `EINVAL` - direct I/O is not aligned to blocks
+    /// * `ENOKEY` - the file is encrypted and cannot be decrypted
+    /// * `EIO` - the file is a verity file and its data does not match
+    pub fn read_handle(&self, fh: FileHandleId, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let handle = self.handles.lock().get(fh)?;
+        let direct = handle.flags & O_DIRECT != 0;
+        if direct {
+            Self::check_direct_io(offset, buf.len())?;
+        }
+        self.read_data(handle.inode, offset, &mut [buf], direct)
+    }
+
+    /// Read data from a file to the buffers `bufs`, bypassing the block
+    /// cache if `direct`.
+    fn read_data<B: DerefMut<Target = [u8]>>(
+        &self,
+        file: InodeId,
+        offset: u64,
+        bufs: &mut [B],
+        direct: bool,
+    ) -> Result<usize> {
+        let _timer = self.stats.op(Op::Read);
         // Get the inode of the file
         let file = self.read_inode(file);
         if !file.inode.is_file() {
             return_error!(ErrCode::EISDIR, "Inode {} is not a file", file.id);
         }
+        self.check_access(&file, Access::READ)?;
+        let decryptor = self.decryptor(&file)?;
+        let verifier = self.verifier(&file, decryptor.as_ref())?;
+        // Read a block, verified against the Merkle tree of a verity file
+        let read_block = |iblock| -> Result<Block> {
+            let block = self.read_file_block(&file, iblock, direct, decryptor.as_ref())?;
+            if let Some(verifier) = &verifier {
+                self.verity_verify(&file, verifier, iblock, &block, decryptor.as_ref())?;
+            }
+            Ok(block)
+        };
 
         // Read no bytes
-        if buf.is_empty() {
+        let buf_len = bufs.iter().map(|buf| buf.len()).sum();
+        if buf_len == 0 {
+            return Ok(0);
+        }
+        // Calc the actual size to read, nothing at or past the end of file
+        let offset = BlockOffset::new(offset);
+        let read_size = offset.len_to(file.inode.size(), buf_len);
+        if read_size == 0 {
             return Ok(0);
         }
-        // Calc the actual size to read
-        let read_size = min(buf.len(), file.inode.size() as usize - offset);
         // Calc the start block of reading
-        let start_iblock = (offset / BLOCK_SIZE) as LBlockId;
+        let start_iblock = offset.iblock()?;
         // Calc the length that is not aligned to the block size
-        let misaligned = offset % BLOCK_SIZE;
+        let misaligned = offset.in_block();
+        // Prefetch the following blocks on sequential reads
+        let end = offset.checked_add(read_size)?.get();
+        // At most `MAX_BLOCKS`, as the end is within the file
+        let end_iblock = end.div_ceil(BLOCK_SIZE as u64) as LBlockId;
+        if !direct {
+            self.readahead_on_read(&file, start_iblock, end_iblock);
+        }
 
         let mut cursor = 0;
         let mut iblock = start_iblock;
         // Read first block
         if misaligned > 0 {
             let read_len = min(BLOCK_SIZE - misaligned, read_size);
-            let fblock = self.extent_query(&file, start_iblock).unwrap();
-            let block = self.read_block(fblock);
+            let block = read_block(start_iblock)?;
             // Copy data from block to the user buffer
-            buf[cursor..cursor + read_len].copy_from_slice(block.read_offset(misaligned, read_len));
+            scatter(bufs, cursor, block.read_offset(misaligned, read_len));
             cursor += read_len;
             iblock += 1;
         }
         // Continue with full block reads
         while cursor < read_size {
             let read_len = min(BLOCK_SIZE, read_size - cursor);
-            let fblock = self.extent_query(&file, iblock).unwrap();
-            let block = self.read_block(fblock);
+            let block = read_block(iblock)?;
             // Copy data from block to the user buffer
-            buf[cursor..cursor + read_len].copy_from_slice(block.read_offset(0, read_len));
+            scatter(bufs, cursor, block.read_offset(0, read_len));
             cursor += read_len;
             iblock += 1;
         }
@@ -203,8 +631,45 @@ impl Ext4 {
         Ok(cursor)
     }
 
+    /// Read a block of a file, bypassing the block cache if `direct`, and
+    /// decrypt it if a decryptor is given. Holes read as zeros.
+    pub(super) fn read_file_block(
+        &self,
+        file: &InodeRef,
+        iblock: LBlockId,
+        direct: bool,
+        decryptor: Option<&Decryptor>,
+    ) -> Result<Block> {
+        let mut block = match self.extent_query(file, iblock) {
+            Ok(fblock) if direct => self.read_block_direct(fblock),
+            Ok(fblock) => self.read_block(fblock),
+            Err(e) if e.code() == ErrCode::ENOENT => return Ok(Block::default()),
+            Err(e) => return Err(e),
+        };
+        if let Some(decryptor) = decryptor {
+            decryptor.decrypt_block(file.id, iblock, &mut block)?;
+        }
+        Ok(block)
+    }
+
+    /// Check that direct I/O covers whole blocks.
+    fn check_direct_io(offset: u64, len: usize) -> Result<()> {
+        if BlockOffset::new(offset).in_block() != 0 || !len.is_multiple_of(BLOCK_SIZE) {
+            return_error!(
+                ErrCode::EINVAL,
+                "Direct I/O at {} of {} bytes is not block aligned",
+                offset,
+                len
+            );
+        }
+        Ok(())
+    }
+
     /// Write data to a file. This function will write exactly `data.len()` bytes.
     ///
+    /// With credentials set, a non-empty write by anyone but the owner or
+    /// root clears the setuid and setgid bits of the file.
+    ///
     /// # Params
     ///
     /// * `file` - the file handler, acquired by `open` or `create`
@@ -218,45 +683,215 @@ impl Ext4 {
     /// # Error
     ///
     /// * `EISDIR` - `file` is not a regular file
+    /// * `EPERM` - `file` is immutable, a verity file, or append-only and
+    ///   `offset` is not the end of file
+    /// * `ENOSPC` - no space left on device. The data written before space
+    ///   ran out is kept, and the file size covers it.
+    /// * `ENOKEY` - `file` is encrypted
+    pub fn write(&self, file: InodeId, offset: u64, data: &[u8]) -> Result<usize> {
+        self.write_data(file, offset, IoVec::new(&[data]), false)
+    }
+
+    /// Write the data of several buffers to a file, in order as if they
+    /// were one. The buffers can be `&[u8]`, or `std::io::IoSlice` to
+    /// implement `writev`. See `write`.
+    ///
+    /// # Return
+    ///
+    /// `Ok(usize)` - the actual number of bytes written
+    ///
+    /// # Error
+    ///
+    /// * `EISDIR` - `file` is not a regular file
+    /// * `EPERM` - `file` is immutable, a verity file, or append-only and
+    ///   `offset` is not the end of file
+    /// * `ENOSPC` - no space left on device. The data written before space
+    ///   ran out is kept, and the file size covers it.
+    /// * `ENOKEY` - `file` is encrypted
+    pub fn write_vectored<B: Deref<Target = [u8]>>(
+        &self,
+        file: InodeId,
+        offset: u64,
+        bufs: &[B],
+    ) -> Result<usize> {
+        self.write_data(file, offset, IoVec::new(bufs), false)
+    }
+
+    /// Write data to a file through an open file handle. Handles opened
+    /// with `O_DIRECT` write to the block device, bypassing the block
+    /// cache, and need `offset` and the length of `data` to be multiples
+    /// of `BLOCK_SIZE`.
+    ///
+    /// # Params
+    ///
+    /// * `fh` - the file handle, acquired by `open`
+    /// * `offset` - offset to write to
+    /// * `data` - the data to write
+    ///
+    /// # Return
+    ///
+    /// `Ok(usize)` - the actual number of bytes written
+    ///
+    /// # Error
+    ///
+    /// * `EBADF` - `fh` is not an open file handle
+    /// * `EISDIR` - the file is not a regular file
+    /// * `EINVAL` - direct I/O is not aligned to blocks
+    /// * `EPERM` - the file is immutable, a verity file, or append-only and
+    ///   `offset` is not the end of file
     /// * `ENOSPC` - no space left on device
-    pub fn write(&self, file: InodeId, offset: usize, data: &[u8]) -> Result<usize> {
+    /// * `ENOKEY` - the file is encrypted
+    pub fn write_handle(&self, fh: FileHandleId, offset: u64, data: &[u8]) -> Result<usize> {
+        let handle = self.handles.lock().get(fh)?;
+        let direct = handle.flags & O_DIRECT != 0;
+        if direct {
+            Self::check_direct_io(offset, data.len())?;
+        }
+        self.write_data(handle.inode, offset, IoVec::new(&[data]), direct)
+    }
+
+    /// Write data to a file, bypassing the block cache if `direct`.
+    fn write_data<B: Deref<Target = [u8]>>(
+        &self,
+        file: InodeId,
+        offset: u64,
+        data: IoVec<B>,
+        direct: bool,
+    ) -> Result<usize> {
+        let _timer = self.stats.op(Op::Write);
+        let _batch = self.inode_batch();
         // Get the inode of the file
         let mut file = self.read_inode(file);
         if !file.inode.is_file() {
             return_error!(ErrCode::EISDIR, "Inode {} is not a file", file.id);
         }
+        self.check_access(&file, Access::WRITE)?;
+        self.write_inode_data(&mut file, offset, data, direct)
+    }
 
-        let write_size = data.len();
-        // Calc the start and end block of writing
-        let start_iblock = (offset / BLOCK_SIZE) as LBlockId;
-        let end_iblock = ((offset + write_size) / BLOCK_SIZE) as LBlockId;
-        // Append enough block for writing
-        let append_block_count = end_iblock as i64 + 1 - file.inode.fs_block_count() as i64;
-        for _ in 0..append_block_count {
-            self.inode_append_block(&mut file)?;
+    /// Write data to a regular file without permission checks. Inode flags
+    /// are still enforced.
+    ///
+    /// With data journaling, the data is written through the journal in
+    /// transactions that fit in it, each covering a part of the data, and
+    /// `direct` is ignored. Otherwise, `direct` writes whole blocks to the
+    /// block device, bypassing the block cache.
+    pub(super) fn write_inode_data<B: Deref<Target = [u8]>>(
+        &self,
+        file: &mut InodeRef,
+        offset: u64,
+        data: IoVec<B>,
+        direct: bool,
+    ) -> Result<usize> {
+        self.check_writable()?;
+        Self::check_modify(file, offset == file.inode.size())?;
+        Self::check_unencrypted(file)?;
+        Self::check_not_verity(file)?;
+        // Fail before writing anything if the data does not fit
+        let offset = BlockOffset::new(offset);
+        offset.checked_add(data.len())?;
+        if !data.is_empty() {
+            // Written along with the data
+            self.clear_setid(file);
+        }
+        if !self.journals_data(file) {
+            return self.write_inode_blocks(file, offset, data, direct);
+        }
+        let chunk = self.journal_data_chunk()?;
+        let mut cursor = 0;
+        while cursor < data.len() {
+            let end = min(data.len(), cursor + chunk);
+            let res = self.with_transaction(|fs| {
+                let chunk = data.slice(cursor, end);
+                fs.write_inode_blocks(file, offset.checked_add(cursor)?, chunk, false)
+            });
+            if let Err(e) = res {
+                // The inode on disk is the one before the failed transaction
+                *file = self.read_inode(file.id);
+                return Err(e);
+            }
+            cursor = end;
         }
+        Ok(cursor)
+    }
 
-        // Write data
+    /// Write data to the blocks of a regular file, mapping blocks in holes
+    /// and past the end of file as needed. `direct` writes bypass the block
+    /// cache, and must cover whole blocks.
+    ///
+    /// If mapping a block fails, e.g. when space runs out, nothing is
+    /// allocated for that block. The data written before it is kept, and
+    /// the size grows to cover it, so that no mapped block lies past the
+    /// end of file.
+    fn write_inode_blocks<B: Deref<Target = [u8]>>(
+        &self,
+        file: &mut InodeRef,
+        offset: BlockOffset,
+        data: IoVec<B>,
+        direct: bool,
+    ) -> Result<usize> {
+        let size = file.inode.size();
+        if offset.get() > size {
+            // Blocks mapped past the end of file may hold stale data
+            let mapped_end = BlockOffset::of_block(self.extent_end(file)?).get();
+            self.zero_range(file, size, min(offset.get(), mapped_end));
+        }
+        let write_size = data.len();
         let mut cursor = 0;
-        let mut iblock = start_iblock;
+        let mut iblock = offset.iblock()?;
+        let mut res = Ok(());
+        let mut scratch = [0; BLOCK_SIZE];
         while cursor < write_size {
-            let write_len = min(BLOCK_SIZE, write_size - cursor);
-            let fblock = self.extent_query(&file, iblock)?;
-            let mut block = self.read_block(fblock);
-            block.write_offset(
-                (offset + cursor) % BLOCK_SIZE,
-                &data[cursor..cursor + write_len],
-            );
-            self.write_block(&block);
+            let block_offset = offset.checked_add(cursor)?.in_block();
+            let write_len = min(BLOCK_SIZE - block_offset, write_size - cursor);
+            let (fblock, new) = match self.map_block(file, iblock) {
+                Ok(mapped) => mapped,
+                Err(err) => {
+                    res = Err(err);
+                    break;
+                }
+            };
+            let chunk = data.chunk(cursor, write_len, &mut scratch);
+            if direct || new || write_len == BLOCK_SIZE {
+                // Whole blocks are replaced and new blocks start zeroed,
+                // their old content is not read
+                let mut block = Block::new(fblock, [0; BLOCK_SIZE]);
+                block.write_offset(block_offset, chunk);
+                if direct {
+                    self.write_block_direct(&block);
+                } else {
+                    self.write_block(&block);
+                }
+            } else {
+                self.write_block_range(fblock, block_offset, chunk);
+            }
             cursor += write_len;
             iblock += 1;
         }
-        if offset + cursor > file.inode.size() as usize {
-            file.inode.set_size((offset + cursor) as u64);
+        let end = offset.checked_add(cursor)?.get();
+        // A failed write only extends the file over the data written
+        if end > file.inode.size() && (res.is_ok() || cursor > 0) {
+            self.set_file_size(file, end);
         }
-        self.write_inode_with_csum(&mut file);
+        self.write_inode(file);
 
-        Ok(cursor)
+        res.map(|_| cursor)
+    }
+
+    /// Set the size of a file, enabling the `large_file` feature for sizes
+    /// past 2 GiB.
+    fn set_file_size(&self, file: &mut InodeRef, size: u64) {
+        if size > i32::MAX as u64 {
+            let mut sb = self.read_super_block();
+            if !sb
+                .features_read_only()
+                .contains(FeatureRoCompat::LARGE_FILE)
+            {
+                sb.add_features_read_only(FeatureRoCompat::LARGE_FILE);
+                self.write_super_block(&sb);
+            }
+        }
+        file.inode.set_size(size);
     }
 
     /// Create a hard link. This function will not check name conflict,
@@ -270,18 +905,31 @@ impl Ext4 {
     /// # Error
     ///
     /// * `ENOTDIR` - `parent` is not a directory
+    /// * `EPERM` - `parent` is immutable, or `child` is immutable or
+    ///   append-only
     /// * `ENOSPC` - no space left on device
+    /// * `ENAMETOOLONG` - `name` is longer than `NAME_MAX`
+    /// * `EINVAL` - `name` is empty or contains '/' or '\0'
+    /// * `ENOKEY` - `parent` is encrypted
     pub fn link(&self, child: InodeId, parent: InodeId, name: &str) -> Result<()> {
+        let _timer = self.stats.op(Op::Link);
+        let _batch = self.inode_batch();
+        self.check_writable()?;
         let mut parent = self.read_inode(parent);
         // Can only link to a directory
         if !parent.inode.is_dir() {
             return_error!(ErrCode::ENOTDIR, "Inode {} is not a directory", parent.id);
         }
+        Self::dir_check_name(name)?;
+        self.check_dir_write(&parent)?;
         let mut child = self.read_inode(child);
         // Cannot link a directory
         if child.inode.is_dir() {
             return_error!(ErrCode::EISDIR, "Cannot link a directory");
         }
+        Self::check_modify(&parent, true)?;
+        Self::check_unencrypted(&parent)?;
+        Self::check_modify(&child, false)?;
         self.link_inode(&mut parent, &mut child, name)?;
         Ok(())
     }
@@ -298,18 +946,30 @@ impl Ext4 {
     /// * `ENOTDIR` - `parent` is not a directory
     /// * `ENOENT` - `name` does not exist in `parent`
     /// * `EISDIR` - `parent/name` is a directory
+    /// * `EPERM` - `parent` or `parent/name` is immutable or append-only
+    /// * `ENOKEY` - `parent` is encrypted
+    /// * `EBUSY` - the last link of a file with blocks pinned by
+    ///   `pin_block` is removed
     pub fn unlink(&self, parent: InodeId, name: &str) -> Result<()> {
+        let _timer = self.stats.op(Op::Unlink);
+        let _batch = self.inode_batch();
+        self.check_writable()?;
         let mut parent = self.read_inode(parent);
         // Can only unlink from a directory
         if !parent.inode.is_dir() {
             return_error!(ErrCode::ENOTDIR, "Inode {} is not a directory", parent.id);
         }
+        self.check_dir_write(&parent)?;
         // Cannot unlink directory
         let child_id = self.dir_find_entry(&parent, name)?;
         let mut child = self.read_inode(child_id);
         if child.inode.is_dir() {
             return_error!(ErrCode::EISDIR, "Cannot unlink a directory");
         }
+        self.check_sticky(&parent, &child)?;
+        Self::check_modify(&parent, false)?;
+        Self::check_modify(&child, false)?;
+        Self::check_unencrypted(&parent)?;
         self.unlink_inode(&mut parent, &mut child, name, true)
     }
 
@@ -327,7 +987,12 @@ impl Ext4 {
     /// * `ENOTDIR` - `parent` or `new_parent` is not a directory
     /// * `ENOENT` - `name` does not exist in `parent`
     /// * `EEXIST` - `new_parent/new_name` already exists
+    /// * `EPERM` - `parent` or `parent/name` is immutable or append-only, or
+    ///   `new_parent` is immutable
     /// * `ENOSPC` - no space left on device
+    /// * `ENAMETOOLONG` - `new_name` is longer than `NAME_MAX`
+    /// * `EINVAL` - `new_name` is empty or contains '/' or '\0'
+    /// * `ENOKEY` - `parent` or `new_parent` is encrypted
     pub fn rename(
         &self,
         parent: InodeId,
@@ -335,6 +1000,9 @@ impl Ext4 {
         new_parent: InodeId,
         new_name: &str,
     ) -> Result<()> {
+        let _timer = self.stats.op(Op::Rename);
+        let _batch = self.inode_batch();
+        self.check_writable()?;
         // Check parent
         let mut parent = self.read_inode(parent);
         if !parent.inode.is_dir() {
@@ -349,15 +1017,29 @@ impl Ext4 {
                 new_parent.id
             );
         }
+        // Check new name before anything is unlinked
+        Self::dir_check_name(new_name)?;
+        self.check_dir_write(&parent)?;
+        self.check_dir_write(&new_parent)?;
         // Check child existence
         let child_id = self.dir_find_entry(&parent, name)?;
         let mut child = self.read_inode(child_id);
+        self.check_sticky(&parent, &child)?;
+        Self::check_modify(&parent, false)?;
+        Self::check_modify(&child, false)?;
+        Self::check_modify(&new_parent, true)?;
+        Self::check_unencrypted(&parent)?;
+        Self::check_unencrypted(&new_parent)?;
         // Check name conflict
         if self.dir_find_entry(&new_parent, new_name).is_ok() {
             return_error!(ErrCode::EEXIST, "Dest name {} already exists", new_name);
         }
         // Move
         self.unlink_inode(&mut parent, &mut child, name, false)?;
+        if new_parent.id == parent.id {
+            // Unlinking may have updated the directory inode
+            new_parent = parent;
+        }
         self.link_inode(&mut new_parent, &mut child, new_name)
     }
 
@@ -377,25 +1059,142 @@ impl Ext4 {
     /// # Error
     ///
     /// * `ENOTDIR` - `parent` is not a directory
+    /// * `EPERM` - `parent` is immutable
     /// * `ENOSPC` - no space left on device
+    /// * `ENAMETOOLONG` - `name` is longer than `NAME_MAX`
+    /// * `EINVAL` - `name` is empty or contains '/' or '\0'
+    /// * `ENOKEY` - `parent` is encrypted
     pub fn mkdir(&self, parent: InodeId, name: &str, mode: InodeMode) -> Result<InodeId> {
+        let _timer = self.stats.op(Op::Mkdir);
+        let _batch = self.inode_batch();
+        self.check_writable()?;
         let mut parent = self.read_inode(parent);
         // Can only create a directory in a directory
         if !parent.inode.is_dir() {
             return_error!(ErrCode::ENOTDIR, "Inode {} is not a directory", parent.id);
         }
+        Self::dir_check_name(name)?;
+        self.check_dir_write(&parent)?;
+        Self::check_modify(&parent, true)?;
+        Self::check_unencrypted(&parent)?;
+        // Reserve the first block of the directory and the growth of the
+        // parent, so that running out of space leaves nothing behind
+        let _reservation = self.reserve_blocks(1 + self.dir_add_blocks_needed(&parent, name)?)?;
         // Create file/directory
-        let mode = mode & InodeMode::PERM_MASK | InodeMode::DIRECTORY;
-        let mut child = self.create_inode(mode)?;
+        let mode = self.apply_umask(mode & InodeMode::PERM_MASK) | InodeMode::DIRECTORY;
+        let mut child = self.create_inode(parent.id, mode)?;
+        self.init_owner(&parent, &mut child);
         // Add "." entry
         let child_self = child.clone();
-        self.dir_add_entry(&mut child, &child_self, ".")?;
+        self.dir_add_entry(&mut child, &child_self, ".")
+            .map_err(|err| self.free_new_inode(&mut child, err))?;
         child.inode.set_link_count(1);
         // Link the new inode
-        self.link_inode(&mut parent, &mut child, name)?;
+        self.link_inode(&mut parent, &mut child, name)
+            .map_err(|err| self.free_new_inode(&mut child, err))?;
         Ok(child.id)
     }
 
+    /// Create a symbolic link. Targets shorter than `FAST_SYMLINK_MAX`
+    /// bytes are stored in the inode, longer ones in a data block.
+    ///
+    /// # Params
+    ///
+    /// * `parent` - the inode of the directory to create the link in
+    /// * `name` - the name of the link
+    /// * `target` - the path the link points to
+    ///
+    /// # Return
+    ///
+    /// `Ok(child)` - the inode id of the created link
+    ///
+    /// # Error
+    ///
+    /// * `ENOTDIR` - `parent` is not a directory
+    /// * `EPERM` - `parent` is immutable
+    /// * `ENOENT` - `target` is empty
+    /// * `ENAMETOOLONG` - `name` is longer than `NAME_MAX`, or `target` is
+    ///   not shorter than a block
+    /// * `EINVAL` - `name` is empty or contains '/' or '\0'
+    /// * `ENOSPC` - no space left on device
+    /// * `ENOKEY` - `parent` is encrypted
+    pub fn symlink(&self, parent: InodeId, name: &str, target: &str) -> Result<InodeId> {
+        let _timer = self.stats.op(Op::Symlink);
+        let _batch = self.inode_batch();
+        self.check_writable()?;
+        let mut parent = self.read_inode(parent);
+        // Can only create a link in a directory
+        if !parent.inode.is_dir() {
+            return_error!(ErrCode::ENOTDIR, "Inode {} is not a directory", parent.id);
+        }
+        Self::dir_check_name(name)?;
+        if target.is_empty() {
+            return_error!(ErrCode::ENOENT, "Empty symlink target");
+        }
+        if target.len() >= BLOCK_SIZE {
+            return_error!(ErrCode::ENAMETOOLONG, "Symlink target too long");
+        }
+        self.check_dir_write(&parent)?;
+        Self::check_modify(&parent, true)?;
+        Self::check_unencrypted(&parent)?;
+        // Reserve the target block of a slow symlink and the growth of the
+        // parent
+        let target_blocks = (target.len() >= FAST_SYMLINK_MAX) as u64;
+        let _reservation =
+            self.reserve_blocks(target_blocks + self.dir_add_blocks_needed(&parent, name)?)?;
+        // Symlink permissions are not used, they are always 0777
+        let mode = InodeMode::SOFTLINK | InodeMode::ALL_RWX;
+        let mut child = self.create_inode(parent.id, mode)?;
+        self.init_owner(&parent, &mut child);
+        if target.len() < FAST_SYMLINK_MAX {
+            child.inode.set_fast_symlink(target.as_bytes());
+            self.write_inode(&mut child);
+        } else {
+            // The size tells a slow symlink from a fast one when there is
+            // no extent tree
+            child.inode.set_size(target.len() as u64);
+            let target = [target.as_bytes()];
+            self.write_inode_blocks(&mut child, BlockOffset::new(0), IoVec::new(&target), false)
+                .map_err(|err| self.free_new_inode(&mut child, err))?;
+        }
+        self.link_inode(&mut parent, &mut child, name)
+            .map_err(|err| self.free_new_inode(&mut child, err))?;
+        Ok(child.id)
+    }
+
+    /// Read the target of a symbolic link.
+    ///
+    /// # Params
+    ///
+    /// * `inode` - the inode of the link
+    ///
+    /// # Return
+    ///
+    /// `Ok(target)` - the path the link points to
+    ///
+    /// # Error
+    ///
+    /// * `EINVAL` - `inode` is not a symbolic link
+    /// * `ENOKEY` - the link is encrypted and cannot be decrypted
+    pub fn readlink(&self, inode: InodeId) -> Result<Vec<u8>> {
+        let _timer = self.stats.op(Op::Readlink);
+        let link = self.read_inode(inode);
+        if !link.inode.is_softlink() {
+            return_error!(ErrCode::EINVAL, "Inode {} is not a symlink", inode);
+        }
+        let target = if link.inode.is_fast_symlink() {
+            link.inode.fast_symlink().to_vec()
+        } else {
+            let size = BlockOffset::new(0).len_to(link.inode.size(), BLOCK_SIZE);
+            let block = self.read_file_block(&link, 0, false, None)?;
+            block.read_offset(0, size).to_vec()
+        };
+        if link.inode.is_encrypted() {
+            return self.decrypt_symlink(&link, &target);
+        }
+        Ok(target)
+    }
+
     /// Look up a directory entry by name.
     ///
     /// # Params
@@ -411,12 +1210,15 @@ impl Ext4 {
     ///
     /// * `ENOTDIR` - `parent` is not a directory
     /// * `ENOENT` - `name` does not exist in `parent`
+    /// * `ENOKEY` - the names of an encrypted directory cannot be decrypted
     pub fn lookup(&self, parent: InodeId, name: &str) -> Result<InodeId> {
+        let _timer = self.stats.op(Op::Lookup);
         let parent = self.read_inode(parent);
         // Can only lookup in a directory
         if !parent.inode.is_dir() {
             return_error!(ErrCode::ENOTDIR, "Inode {} is not a directory", parent.id);
         }
+        self.check_access(&parent, Access::EXEC)?;
         self.dir_find_entry(&parent, name)
     }
 
@@ -432,14 +1234,64 @@ impl Ext4 {
     ///
     /// # Error
     ///
-    /// `ENOTDIR` - `inode` is not a directory
+    /// * `ENOTDIR` - `inode` is not a directory
+    /// * `ENOKEY` - the names of an encrypted directory cannot be decrypted
     pub fn listdir(&self, inode: InodeId) -> Result<Vec<DirEntry>> {
+        let _timer = self.stats.op(Op::Listdir);
         let inode_ref = self.read_inode(inode);
         // Can only list a directory
         if inode_ref.inode.file_type() != FileType::Directory {
             return_error!(ErrCode::ENOTDIR, "Inode {} is not a directory", inode);
         }
-        Ok(self.dir_list_entries(&inode_ref))
+        self.check_access(&inode_ref, Access::READ)?;
+        self.dir_list_entries(&inode_ref)
+    }
+
+    /// List all directory entries in a directory with the attributes of
+    /// their inodes, like `listdir` followed by `getattr` on each entry.
+    /// Each inode table block is read once, however many of the inodes it
+    /// holds are listed.
+    ///
+    /// # Params
+    ///
+    /// * `inode` - the inode of the directory to list
+    ///
+    /// # Return
+    ///
+    /// `Ok(entries)` - the directory entries and their attributes
+    ///
+    /// # Error
+    ///
+    /// `ENOTDIR` - `inode` is not a directory
+    pub fn listdir_attrs(&self, inode: InodeId) -> Result<Vec<(DirEntry, FileAttr)>> {
+        let entries = self.listdir(inode)?;
+        // Read the inodes in inode table order
+        let mut order: Vec<usize> = (0..entries.len()).collect();
+        order.sort_by_key(|&i| entries[i].inode());
+        let mut attrs = vec![None; entries.len()];
+        let inode_size = self.read_super_block().inode_size();
+        let mut block: Option<Block> = None;
+        for i in order {
+            let id = entries[i].inode();
+            let inode = match self.inode_batch_read(id) {
+                Some(inode) => InodeRef::new(id, inode),
+                None => {
+                    let (block_id, offset) = self.inode_disk_pos(id);
+                    if block.as_ref().is_none_or(|block| block.id != block_id) {
+                        block = Some(self.read_block(block_id));
+                    }
+                    InodeRef::new(
+                        id,
+                        block.as_ref().unwrap().read_record_as(offset, inode_size),
+                    )
+                }
+            };
+            attrs[i] = Some(Self::file_attr(&inode));
+        }
+        Ok(entries
+            .into_iter()
+            .zip(attrs.into_iter().map(Option::unwrap))
+            .collect())
     }
 
     /// Remove an empty directory.
@@ -454,19 +1306,29 @@ impl Ext4 {
     /// * `ENOTDIR` - `parent` or `child` is not a directory
     /// * `ENOENT` - `name` does not exist in `parent`
     /// * `ENOTEMPTY` - `child` is not empty
+    /// * `EPERM` - `parent` or `parent/name` is immutable or append-only
+    /// * `ENOKEY` - `parent` is encrypted
     pub fn rmdir(&self, parent: InodeId, name: &str) -> Result<()> {
+        let _timer = self.stats.op(Op::Rmdir);
+        let _batch = self.inode_batch();
+        self.check_writable()?;
         let mut parent = self.read_inode(parent);
         // Can only remove a directory in a directory
         if !parent.inode.is_dir() {
             return_error!(ErrCode::ENOTDIR, "Inode {} is not a directory", parent.id);
         }
+        self.check_dir_write(&parent)?;
         let mut child = self.read_inode(self.dir_find_entry(&parent, name)?);
         // Child must be a directory
         if !child.inode.is_dir() {
             return_error!(ErrCode::ENOTDIR, "Inode {} is not a directory", child.id);
         }
+        self.check_sticky(&parent, &child)?;
+        Self::check_modify(&parent, false)?;
+        Self::check_modify(&child, false)?;
+        Self::check_unencrypted(&parent)?;
         // Child must be empty
-        if self.dir_list_entries(&child).len() > 2 {
+        if !self.dir_is_empty(&child)? {
             return_error!(ErrCode::ENOTEMPTY, "Directory {} is not empty", child.id);
         }
         // Remove directory entry
@@ -486,14 +1348,24 @@ impl Ext4 {
     ///
     /// # Error
     ///
-    /// `ENODATA` - the attribute does not exist
+    /// * `ENODATA` - the attribute does not exist
+    /// * `EFSCORRUPTED` - the xattr block is malformed
     pub fn getxattr(&self, inode: InodeId, name: &str) -> Result<Vec<u8>> {
+        let _timer = self.stats.op(Op::Getxattr);
         let inode_ref = self.read_inode(inode);
+        let (name_index, raw_name) = XattrEntry::match_name(name);
+        let ibody_value = self.with_ibody(&inode_ref, |record, extra_isize| {
+            find_ibody_xattr(record, extra_isize, name_index, raw_name.as_bytes())
+                .map(<[u8]>::to_vec)
+        });
+        if let Some(value) = ibody_value.flatten() {
+            return Ok(value);
+        }
         let xattr_block_id = inode_ref.inode.xattr_block();
         if xattr_block_id == 0 {
             return_error!(ErrCode::ENODATA, "Xattr {} does not exist", name);
         }
-        let xattr_block = XattrBlock::new(self.read_block(xattr_block_id));
+        let xattr_block = self.read_xattr_block(&inode_ref)?;
         match xattr_block.get(name) {
             Some(value) => Ok(value.to_owned()),
             None => Err(format_error!(
@@ -515,22 +1387,47 @@ impl Ext4 {
     ///
     /// # Error
     ///
-    /// `ENOSPC` - xattr block does not have enough space
+    /// * `ENOSPC` - xattr block does not have enough space
+    /// * `ENAMETOOLONG` - `name` is longer than `NAME_MAX`
+    /// * `EINVAL` - `name` is empty or contains '\0'
+    /// * `EFSCORRUPTED` - the xattr block is malformed
     pub fn setxattr(&self, inode: InodeId, name: &str, value: &[u8]) -> Result<()> {
+        let _timer = self.stats.op(Op::Setxattr);
+        let _batch = self.inode_batch();
+        self.check_writable()?;
+        // Check the attribute name
+        if name.is_empty() || name.contains('\0') {
+            return_error!(ErrCode::EINVAL, "Invalid xattr name {:?}", name);
+        }
+        if name.len() > NAME_MAX {
+            return_error!(
+                ErrCode::ENAMETOOLONG,
+                "Xattr name too long: {} bytes",
+                name.len()
+            );
+        }
         let mut inode_ref = self.read_inode(inode);
+        Self::check_modify(&inode_ref, false)?;
         let xattr_block_id = inode_ref.inode.xattr_block();
         if xattr_block_id == 0 {
             // lazy allocate xattr block
             let pblock = self.alloc_block(&mut inode_ref)?;
             inode_ref.inode.set_xattr_block(pblock);
-            self.write_inode_with_csum(&mut inode_ref);
+            let block_count = inode_ref.inode.fs_block_count() + 1;
+            inode_ref.inode.set_fs_block_count(block_count);
+            self.write_inode(&mut inode_ref);
         }
-        let mut xattr_block = XattrBlock::new(self.read_block(inode_ref.inode.xattr_block()));
-        if xattr_block_id == 0 {
+        let mut xattr_block = if xattr_block_id == 0 {
+            // Freed blocks keep their content, start from an empty one
+            let pblock = inode_ref.inode.xattr_block();
+            let mut xattr_block = XattrBlock::new(Block::new(pblock, [0; BLOCK_SIZE]));
             xattr_block.init();
-        }
+            xattr_block
+        } else {
+            self.read_xattr_block(&inode_ref)?
+        };
         if xattr_block.insert(name, value) {
-            self.write_block(&xattr_block.block());
+            self.write_xattr_block(xattr_block);
             Ok(())
         } else {
             return_error!(
@@ -550,16 +1447,21 @@ impl Ext4 {
     ///
     /// # Error
     ///
-    /// `ENODATA` - the attribute does not exist
+    /// * `ENODATA` - the attribute does not exist
+    /// * `EFSCORRUPTED` - the xattr block is malformed
     pub fn removexattr(&self, inode: InodeId, name: &str) -> Result<()> {
+        let _timer = self.stats.op(Op::Removexattr);
+        let _batch = self.inode_batch();
+        self.check_writable()?;
         let inode_ref = self.read_inode(inode);
+        Self::check_modify(&inode_ref, false)?;
         let xattr_block_id = inode_ref.inode.xattr_block();
         if xattr_block_id == 0 {
             return_error!(ErrCode::ENODATA, "Xattr {} does not exist", name);
         }
-        let mut xattr_block = XattrBlock::new(self.read_block(xattr_block_id));
+        let mut xattr_block = self.read_xattr_block(&inode_ref)?;
         if xattr_block.remove(name) {
-            self.write_block(&xattr_block.block());
+            self.write_xattr_block(xattr_block);
             Ok(())
         } else {
             return_error!(ErrCode::ENODATA, "Xattr {} does not exist", name);
@@ -576,22 +1478,166 @@ impl Ext4 {
     ///
     /// A list of extended attributes of the file.
     pub fn listxattr(&self, inode: InodeId) -> Result<Vec<String>> {
+        let _timer = self.stats.op(Op::Listxattr);
         let inode_ref = self.read_inode(inode);
-        let xattr_block_id = inode_ref.inode.xattr_block();
-        if xattr_block_id == 0 {
-            return Ok(Vec::new());
+        let mut names = self
+            .with_ibody(&inode_ref, list_ibody_xattrs)
+            .unwrap_or_default();
+        if inode_ref.inode.xattr_block() != 0 {
+            names.extend(self.read_xattr_block(&inode_ref)?.list());
+        }
+        Ok(names)
+    }
+
+    /// Call `f` on the on-disk record of an inode and the size of its
+    /// extended fields, to read the xattrs stored in the inode. `None` if
+    /// inodes have no room for them.
+    pub(super) fn with_ibody<T>(
+        &self,
+        inode_ref: &InodeRef,
+        f: impl FnOnce(&[u8], usize) -> T,
+    ) -> Option<T> {
+        let inode_size = self.read_super_block().inode_size();
+        if inode_size <= 128 {
+            return None;
+        }
+        let (block_id, offset) = self.inode_disk_pos(inode_ref.id);
+        let block = self.read_block(block_id);
+        let record = &block.data[offset..offset + inode_size];
+        Some(f(record, inode_ref.inode.extra_isize()))
+    }
+
+    /// Read the xattr block of an inode, which must have one.
+    ///
+    /// # Error
+    ///
+    /// `EFSCORRUPTED` if the block is malformed, see `XattrBlock::is_valid`.
+    fn read_xattr_block(&self, inode_ref: &InodeRef) -> Result<XattrBlock> {
+        let pblock = inode_ref.inode.xattr_block();
+        let xattr_block = XattrBlock::new(self.read_block(pblock));
+        if !xattr_block.is_valid() {
+            let err = format_error!(
+                ErrCode::EFSCORRUPTED,
+                "Invalid xattr block {} of inode {}",
+                pblock,
+                inode_ref.id
+            );
+            return Err(self.fs_error("read_xattr_block", inode_ref.id, pblock, err));
         }
-        let xattr_block = XattrBlock::new(self.read_block(xattr_block_id));
-        Ok(xattr_block.list())
+        Ok(xattr_block)
     }
 
-    /// Flush all dirty blocks in cache to disk.
+    /// Update the checksum of a xattr block if `metadata_csum` is enabled,
+    /// and write the block to disk.
+    fn write_xattr_block(&self, mut xattr_block: XattrBlock) {
+        let sb = self.read_super_block();
+        if sb.has_metadata_csum() {
+            xattr_block.set_checksum(sb.checksum_seed());
+        }
+        self.write_block(&xattr_block.block());
+    }
+
+    /// Get the inode flags of a file, like the `FS_IOC_GETFLAGS` ioctl.
+    ///
+    /// # Error
+    ///
+    /// `EINVAL` if the inode is invalid (mode == 0).
+    pub fn getflags(&self, inode: InodeId) -> Result<InodeFlags> {
+        let _timer = self.stats.op(Op::Getflags);
+        let inode_ref = self.read_inode(inode);
+        if inode_ref.inode.mode().bits() == 0 {
+            return_error!(ErrCode::EINVAL, "Invalid inode {}", inode);
+        }
+        Ok(inode_ref.inode.user_flags())
+    }
+
+    /// Set the inode flags of a file, like the `FS_IOC_SETFLAGS` ioctl.
+    ///
+    /// With credentials set, only the owner of the file or root may set
+    /// flags, and only root may change `IMMUTABLE`, `APPEND` or
+    /// `JOURNAL_DATA`.
+    ///
+    /// # Error
+    ///
+    /// * `EINVAL` - the inode is invalid (mode == 0)
+    /// * `EPERM` - the caller may not change the flags
+    pub fn setflags(&self, inode: InodeId, flags: InodeFlags) -> Result<()> {
+        let _timer = self.stats.op(Op::Setflags);
+        let _batch = self.inode_batch();
+        self.check_writable()?;
+        let mut inode_ref = self.read_inode(inode);
+        if inode_ref.inode.mode().bits() == 0 {
+            return_error!(ErrCode::EINVAL, "Invalid inode {}", inode);
+        }
+        if let Some(cred) = &self.cred {
+            let changed = inode_ref.inode.user_flags() ^ flags;
+            if !cred.is_root()
+                && (cred.uid != inode_ref.inode.uid()
+                    || changed.intersects(
+                        InodeFlags::IMMUTABLE | InodeFlags::APPEND | InodeFlags::JOURNAL_DATA,
+                    ))
+            {
+                return_error!(
+                    ErrCode::EPERM,
+                    "Uid {} cannot set flags {:?} of inode {}",
+                    cred.uid,
+                    flags,
+                    inode
+                );
+            }
+        }
+        inode_ref.inode.set_user_flags(flags);
+        self.write_inode(&mut inode_ref);
+        Ok(())
+    }
+
+    /// Check that the flags of an inode allow modifying it, as `chattr`
+    /// describes. Immutable inodes cannot be modified at all. Append-only
+    /// inodes only accept appends: writes at the end of a file, or new
+    /// entries in a directory. Set `append` if the modification is one.
+    ///
+    /// This applies regardless of credentials, even to root.
+    ///
+    /// # Error
+    ///
+    /// `EPERM` if the flags forbid the modification.
+    pub(super) fn check_modify(inode: &InodeRef, append: bool) -> Result<()> {
+        let flags = inode.inode.user_flags();
+        if flags.contains(InodeFlags::IMMUTABLE) {
+            return_error!(ErrCode::EPERM, "Inode {} is immutable", inode.id);
+        }
+        if flags.contains(InodeFlags::APPEND) && !append {
+            return_error!(ErrCode::EPERM, "Inode {} is append-only", inode.id);
+        }
+        Ok(())
+    }
+
+    /// Zero the bytes of a file in `start..end` that are in mapped blocks.
+    fn zero_range(&self, file: &InodeRef, start: u64, end: u64) {
+        let mut pos = BlockOffset::new(start);
+        while pos.get() < end {
+            // Nothing is mapped past the last block
+            let Ok(iblock) = pos.iblock() else {
+                break;
+            };
+            let offset = pos.in_block();
+            let len = pos.len_to(end, BLOCK_SIZE - offset);
+            if let Ok(fblock) = self.extent_query(file, iblock) {
+                let mut block = self.read_block(fblock);
+                block.write_offset(offset, &[0; BLOCK_SIZE][..len]);
+                self.write_block(&block);
+            }
+            pos = BlockOffset::of_block(iblock + 1);
+        }
+    }
+
+    /// Flush all dirty blocks in cache to disk, record the blocks written
+    /// so far in the superblock, and flush the device write cache with
+    /// `BlockDevice::flush`.
     ///
     /// This always succeeds.
     pub fn flush_all(&self) {
-        #[cfg(feature = "block_cache")]
-        {
-            self.block_cache.flush_all();
-        }
+        self.record_written_blocks();
+        self.flush_device();
     }
 }
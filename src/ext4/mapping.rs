@@ -0,0 +1,386 @@
+//! Block mappings for memory-mapped files.
+//!
+//! A page cache or DAX layer built on top of the filesystem asks for the
+//! physical blocks of a file range once, with `Ext4::get_block_mapping`,
+//! and keeps using them on page faults. The mappings stay valid until the
+//! invalidator set with `Ext4::set_mapping_invalidator` is called for the
+//! inode: whenever blocks are mapped into a hole, unmapped or moved, and
+//! for all inodes when blocks are reverted behind the filesystem's back.
+//!
+//! A page cache filling and writing back pages of `BLOCK_SIZE` bytes uses
+//! `Ext4::read_page` and `Ext4::write_page` instead, and
+//! `Ext4::get_writable_block` to map a block on a write fault.
+//!
+//! Swap files and the FUSE `bmap` request look up single blocks with
+//! `Ext4::bmap`.
+//!
+//! Blocks mapped writable into user space are pinned with
+//! `Ext4::pin_block`, so that they are not moved or freed under the
+//! mapping.
+
+use super::Ext4;
+use crate::constants::*;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+use crate::return_error;
+use core::cmp::{max, min};
+use core::ops::Range;
+
+/// A run of logical blocks of a file, acquired by
+/// `Ext4::get_block_mapping`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockMapping {
+    /// First logical block of the run
+    pub lblock: LBlockId,
+    /// Physical block of the first block, the others follow it. `None` for
+    /// a hole, which reads as zeros
+    pub pblock: Option<PBlockId>,
+    /// Number of blocks in the run
+    pub len: u32,
+}
+
+/// Append a run to `mappings`, merged with the last one if it continues it
+/// both logically and physically.
+fn push_mapping(
+    mappings: &mut Vec<BlockMapping>,
+    lblock: LBlockId,
+    pblock: Option<PBlockId>,
+    len: u32,
+) {
+    if let Some(last) = mappings.last_mut() {
+        let contiguous = match (last.pblock, pblock) {
+            (None, None) => true,
+            (Some(prev), Some(next)) => prev + last.len as PBlockId == next,
+            _ => false,
+        };
+        if contiguous && last.lblock + last.len == lblock {
+            last.len += len;
+            return;
+        }
+    }
+    mappings.push(BlockMapping {
+        lblock,
+        pblock,
+        len,
+    });
+}
+
+impl Ext4 {
+    /// Set the function called when block mappings handed out by
+    /// `get_block_mapping` become stale, with the inode whose mappings
+    /// changed, or `None` if the mappings of all inodes did. `None`
+    /// removes it.
+    ///
+    /// It is called while the filesystem is being modified, and must not
+    /// call back into it.
+    pub fn set_mapping_invalidator(&mut self, invalidator: Option<fn(Option<InodeId>)>) {
+        self.mapping_invalidator = invalidator;
+    }
+
+    /// Get the physical blocks of the logical blocks `iblocks` of a regular
+    /// file, as runs in logical block order that cover the whole range.
+    /// Unwritten extents are reported as holes, as they read as zeros.
+    ///
+    /// The runs stay valid until the mapping invalidator is called for the
+    /// file, see `set_mapping_invalidator`.
+    ///
+    /// # Error
+    ///
+    /// * `EINVAL` - the inode is invalid (mode == 0), or the range is
+    ///   reversed
+    /// * `EISDIR` - the inode is not a regular file
+    /// * `ENOTSUP` - the file is encrypted, its blocks hold ciphertext
+    pub fn get_block_mapping(
+        &self,
+        inode: InodeId,
+        iblocks: Range<LBlockId>,
+    ) -> Result<Vec<BlockMapping>> {
+        let file = self.read_inode(inode);
+        if file.inode.mode().bits() == 0 {
+            return_error!(ErrCode::EINVAL, "Invalid inode {}", inode);
+        }
+        if !file.inode.is_file() {
+            return_error!(ErrCode::EISDIR, "Inode {} is not a file", inode);
+        }
+        if file.inode.is_encrypted() {
+            return_error!(
+                ErrCode::ENOTSUP,
+                "Inode {} is encrypted and cannot be mapped",
+                inode
+            );
+        }
+        if iblocks.start > iblocks.end {
+            return_error!(
+                ErrCode::EINVAL,
+                "Invalid block range {}..{} of inode {}",
+                iblocks.start,
+                iblocks.end,
+                inode
+            );
+        }
+        let mut mappings = Vec::new();
+        // Nothing is mapped past the last mapped block
+        let end = min(iblocks.end, self.extent_end(&file)?);
+        // Start of the part of the range not covered yet
+        let mut next = iblocks.start;
+        if file.inode.uses_block_map() {
+            for iblock in iblocks.start..end {
+                let pblock = self.indirect_lookup(&file, iblock)?;
+                push_mapping(&mut mappings, iblock, pblock, 1);
+            }
+            next = max(next, end);
+        } else {
+            self.extent_for_each(&file, |ex| {
+                let ex_end = ex.start_lblock() + ex.block_count();
+                let start = max(ex.start_lblock(), iblocks.start);
+                let stop = min(ex_end, end);
+                if start >= stop {
+                    return Ok(());
+                }
+                if start > next {
+                    push_mapping(&mut mappings, next, None, start - next);
+                }
+                let pblock = (!ex.is_unwritten())
+                    .then(|| ex.start_pblock() + (start - ex.start_lblock()) as PBlockId);
+                push_mapping(&mut mappings, start, pblock, stop - start);
+                next = stop;
+                Ok(())
+            })?;
+        }
+        if next < iblocks.end {
+            push_mapping(&mut mappings, next, None, iblocks.end - next);
+        }
+        Ok(mappings)
+    }
+
+    /// Get the physical block of the logical block `iblock` of a regular
+    /// file, as the FUSE `bmap` request and swap files need. Return `None`
+    /// for a hole or an unwritten extent. Nothing is allocated or written.
+    ///
+    /// Unlike `get_block_mapping`, no invalidation is promised: the block
+    /// may move when the file is modified.
+    ///
+    /// # Error
+    ///
+    /// * `EINVAL` - the inode is invalid (mode == 0)
+    /// * `EISDIR` - the inode is not a regular file
+    /// * `ENOTSUP` - the file is encrypted, its blocks hold ciphertext
+    /// * `EFSCORRUPTED` - the extent tree of the file is malformed
+    pub fn bmap(&self, inode: InodeId, iblock: LBlockId) -> Result<Option<PBlockId>> {
+        let file = self.read_inode(inode);
+        if file.inode.mode().bits() == 0 {
+            return_error!(ErrCode::EINVAL, "Invalid inode {}", inode);
+        }
+        if !file.inode.is_file() {
+            return_error!(ErrCode::EISDIR, "Inode {} is not a file", inode);
+        }
+        if file.inode.is_encrypted() {
+            return_error!(
+                ErrCode::ENOTSUP,
+                "Inode {} is encrypted and cannot be mapped",
+                inode
+            );
+        }
+        match self.extent_query(&file, iblock) {
+            Ok(pblock) => Ok(Some(pblock)),
+            Err(e) if e.code() == ErrCode::ENOENT => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read page `page` of a regular file, i.e. its logical block of the
+    /// same index, as pages are `BLOCK_SIZE` bytes. Bytes past the end of
+    /// file read as zeros.
+    ///
+    /// Return whether the page holds data. Holes and unwritten extents are
+    /// zeroed without reading the device, so that the caller can map a
+    /// shared zero page instead.
+    ///
+    /// # Error
+    ///
+    /// * `EISDIR` - the inode is not a regular file
+    /// * `ENOKEY` - the file is encrypted and cannot be decrypted
+    /// * `EIO` - the file is a verity file and its data does not match
+    /// * `EFSCORRUPTED` - the extent tree of the file is malformed
+    pub fn read_page(
+        &self,
+        inode: InodeId,
+        page: LBlockId,
+        buf: &mut [u8; BLOCK_SIZE],
+    ) -> Result<bool> {
+        let file = self.read_inode(inode);
+        if !file.inode.is_file() {
+            return_error!(ErrCode::EISDIR, "Inode {} is not a file", inode);
+        }
+        let offset = BlockOffset::of_block(page);
+        let mapped = match self.extent_query(&file, page) {
+            Ok(_) => offset.get() < file.inode.size(),
+            Err(e) if e.code() == ErrCode::ENOENT => false,
+            Err(e) => return Err(e),
+        };
+        if !mapped {
+            buf.fill(0);
+            return Ok(false);
+        }
+        let read = self.read(inode, offset.get(), buf)?;
+        buf[read..].fill(0);
+        Ok(true)
+    }
+
+    /// Write page `page` of a regular file back, see `read_page`. Only the
+    /// part of the page before the end of file is written, the file size
+    /// is not changed. Whole pages replace their block without reading it.
+    ///
+    /// Return the number of bytes written.
+    ///
+    /// # Error
+    ///
+    /// * `EISDIR` - the inode is not a regular file
+    /// * `EPERM` - the file is immutable or a verity file
+    /// * `ENOSPC` - no space left on device
+    /// * `ENOKEY` - the file is encrypted
+    pub fn write_page(
+        &self,
+        inode: InodeId,
+        page: LBlockId,
+        buf: &[u8; BLOCK_SIZE],
+    ) -> Result<usize> {
+        let file = self.read_inode(inode);
+        if !file.inode.is_file() {
+            return_error!(ErrCode::EISDIR, "Inode {} is not a file", inode);
+        }
+        let offset = BlockOffset::of_block(page);
+        let len = offset.len_to(file.inode.size(), BLOCK_SIZE);
+        if len == 0 {
+            return Ok(0);
+        }
+        self.write(inode, offset.get(), &buf[..len])
+    }
+
+    /// Get the physical block of the logical block `iblock` of a regular
+    /// file, for a writable shared mapping to write to directly. A hole is
+    /// mapped to a new zeroed block, as on a write fault.
+    ///
+    /// The data written to the block bypasses the journal, and the block
+    /// stays valid until the mapping invalidator is called for the file,
+    /// see `set_mapping_invalidator`.
+    ///
+    /// # Error
+    ///
+    /// * `EISDIR` - the inode is not a regular file
+    /// * `EINVAL` - `iblock` is past the end of file
+    /// * `EPERM` - the file is immutable, append-only or a verity file
+    /// * `ENOTSUP` - the file is encrypted, its blocks hold ciphertext
+    /// * `ENOSPC` - no space left on device
+    pub fn get_writable_block(&self, inode: InodeId, iblock: LBlockId) -> Result<PBlockId> {
+        let _batch = self.inode_batch();
+        self.check_writable()?;
+        let mut file = self.read_inode(inode);
+        if !file.inode.is_file() {
+            return_error!(ErrCode::EISDIR, "Inode {} is not a file", inode);
+        }
+        if file.inode.is_encrypted() {
+            return_error!(
+                ErrCode::ENOTSUP,
+                "Inode {} is encrypted and cannot be mapped",
+                inode
+            );
+        }
+        Self::check_modify(&file, false)?;
+        Self::check_not_verity(&file)?;
+        if BlockOffset::of_block(iblock).get() >= file.inode.size() {
+            return_error!(
+                ErrCode::EINVAL,
+                "Block {} is past the end of inode {}",
+                iblock,
+                inode
+            );
+        }
+        let (pblock, new) = self.map_block(&mut file, iblock)?;
+        if new {
+            // The block may hold stale data
+            self.write_block(&Block::new(pblock, [0; BLOCK_SIZE]));
+            self.write_inode(&mut file);
+        }
+        Ok(pblock)
+    }
+
+    /// Tell the mapping invalidator that the block mappings of `inode`, or
+    /// of all inodes if `None`, changed.
+    pub(super) fn invalidate_mappings(&self, inode: Option<InodeId>) {
+        if let Some(invalidator) = self.mapping_invalidator {
+            invalidator(inode);
+        }
+    }
+
+    /// Pin the logical block `iblock` of a regular file while it is mapped
+    /// writable into user space. Until it is unpinned with `unpin_block`,
+    /// the block is neither moved by `defragment` nor freed: shrinking the
+    /// file below it and removing the last link of the file fail with
+    /// `EBUSY`. A block pinned several times stays pinned until it is
+    /// unpinned as many times.
+    ///
+    /// Return the physical block. Call `get_writable_block` first to map a
+    /// hole.
+    ///
+    /// # Error
+    ///
+    /// * `EINVAL` - the inode is invalid (mode == 0)
+    /// * `EISDIR` - the inode is not a regular file
+    /// * `ENOTSUP` - the file is encrypted, its blocks hold ciphertext
+    /// * `ENOENT` - the block is a hole or unwritten
+    pub fn pin_block(&self, inode: InodeId, iblock: LBlockId) -> Result<PBlockId> {
+        let Some(pblock) = self.bmap(inode, iblock)? else {
+            return_error!(
+                ErrCode::ENOENT,
+                "Block {} of inode {} is not mapped",
+                iblock,
+                inode
+            );
+        };
+        *self.pins.lock().entry((inode, iblock)).or_insert(0) += 1;
+        Ok(pblock)
+    }
+
+    /// Release a pin taken by `pin_block`.
+    ///
+    /// # Error
+    ///
+    /// `EINVAL` if the block is not pinned.
+    pub fn unpin_block(&self, inode: InodeId, iblock: LBlockId) -> Result<()> {
+        let mut pins = self.pins.lock();
+        let Some(count) = pins.get_mut(&(inode, iblock)) else {
+            return_error!(
+                ErrCode::EINVAL,
+                "Block {} of inode {} is not pinned",
+                iblock,
+                inode
+            );
+        };
+        *count -= 1;
+        if *count == 0 {
+            pins.remove(&(inode, iblock));
+        }
+        Ok(())
+    }
+
+    /// Check that no block of `inode` from `iblock` on is pinned, before
+    /// they are moved or freed.
+    ///
+    /// # Error
+    ///
+    /// `EBUSY` if one is.
+    pub(super) fn check_unpinned(&self, inode: InodeId, iblock: LBlockId) -> Result<()> {
+        let pins = self.pins.lock();
+        let mut range = pins.range((inode, iblock)..=(inode, LBlockId::MAX));
+        if let Some((&(_, pinned), _)) = range.next() {
+            return_error!(
+                ErrCode::EBUSY,
+                "Block {} of inode {} is pinned",
+                pinned,
+                inode
+            );
+        }
+        Ok(())
+    }
+}
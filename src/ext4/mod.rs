@@ -2,15 +2,76 @@ use crate::constants::*;
 use crate::ext4_defs::*;
 use crate::prelude::*;
 use crate::return_error;
+use crate::sync::SpinMutex;
+use core::sync::atomic::{AtomicBool, AtomicU64};
 
 mod alloc;
+mod capabilities;
+mod crypt;
+mod defrag;
+mod delalloc;
 mod dir;
+mod dump;
+mod errors;
 mod extent;
+mod extent_status;
+mod finalize;
 mod high_level;
+mod indirect;
+mod inode_batch;
+mod inode_handle;
+mod inspect;
 mod journal;
+mod lazy_init;
 mod link;
+mod lost_found;
 mod low_level;
+mod mapping;
+mod mount;
+mod open_files;
+mod orphan;
+mod perm;
+mod quota;
+mod raw;
+mod readahead;
+mod reserve;
 mod rw;
+#[cfg(feature = "mem_device")]
+mod snapshot;
+mod stats;
+#[cfg(feature = "tar")]
+mod tar;
+mod trim;
+mod verity;
+mod walk;
+mod wear;
+
+pub use capabilities::{Capabilities, CompiledFeatures, ImageFeatures, UnsupportedFeatures};
+pub use crypt::{CryptoProvider, EncryptionPolicy};
+pub use defrag::FragReport;
+pub use delalloc::DelayedWriter;
+pub use dump::{
+    diff_devices, BlockDiff, DirBlockDump, DirEntryDump, ExtentTreeEntry, ExtentTreeNode,
+};
+pub use errors::ErrorPolicy;
+pub use high_level::PathComponents;
+pub use inode_handle::InodeHandle;
+pub use inspect::{GroupInfo, SuperBlockInfo};
+pub use lazy_init::LazyInitCursor;
+pub use mapping::BlockMapping;
+pub use perm::Credentials;
+pub use quota::{QuotaEntry, QuotaLimits, QuotaType};
+pub use raw::RawAccess;
+pub use reserve::BlockReservation;
+pub use stats::{Ext4Stats, Op, OpStats};
+#[cfg(feature = "tar")]
+pub use tar::TarImporter;
+pub use verity::{VerityDescriptor, VerityHasher};
+pub use walk::{WalkControl, WalkOptions};
+pub use wear::WearStats;
+
+use readahead::Readahead;
+use stats::Stats;
 
 /// The Ext4 filesystem implementation.
 pub struct Ext4 {
@@ -18,6 +79,62 @@ pub struct Ext4 {
     block_cache: BlockCache,
     #[cfg(not(feature = "block_cache"))]
     block_device: Arc<dyn BlockDevice>,
+    /// Open file handles
+    handles: SpinMutex<open_files::OpenFiles>,
+    /// Instrumentation counters
+    stats: Stats,
+    /// Blocks written but not yet recorded in the superblock
+    pending_written_blocks: AtomicU64,
+    /// Wall clock for superblock timestamps, seconds since the epoch
+    wall_clock: Option<fn() -> u64>,
+    /// Whether allocations may use the blocks reserved for privileged users
+    reserved_access: bool,
+    /// Allocation shards, `None` if allocations are not sharded
+    alloc_shards: Option<alloc::AllocShards>,
+    /// Credentials for permission checks, `None` if checks are disabled
+    cred: Option<Credentials>,
+    /// Permission bits cleared from the mode of new inodes
+    umask: InodeMode,
+    /// Decryption of encrypted files and names, `None` if unavailable
+    crypto: Option<Arc<dyn CryptoProvider>>,
+    /// Verification of verity files, `None` if they are read unverified
+    verity: Option<Arc<dyn VerityHasher>>,
+    /// Called when handed out block mappings become stale
+    mapping_invalidator: Option<fn(Option<InodeId>)>,
+    /// Whether freed blocks are discarded on the block device
+    discard: bool,
+    /// Whether freed blocks of all inodes are zeroed, not only those of
+    /// `SECRM` inodes
+    zero_on_free: bool,
+    /// Whether file data is written through the journal (`data=journal`)
+    data_journal: bool,
+    /// Whether empty blocks at the end of directories are released
+    dir_shrink: bool,
+    /// Readahead state for sequential reads
+    readahead: Readahead,
+    /// What to do when corruption is detected
+    error_policy: ErrorPolicy,
+    /// Whether modifications are refused after an error, or because of
+    /// unsupported features
+    read_only: AtomicBool,
+    /// Features of the image this build does not support
+    unsupported: UnsupportedFeatures,
+    /// Block writes of the running transaction
+    txn: SpinMutex<journal::Transaction>,
+    /// Identifies the calling thread, see `set_thread_id`
+    thread_id: fn() -> u64,
+    /// Cached results of extent tree lookups
+    extent_status: SpinMutex<extent_status::ExtentStatusCache>,
+    /// Inode writes of the running batch
+    inode_batch: SpinMutex<inode_batch::InodeBatch>,
+    /// Blocks set aside by the held reservations
+    reservations: SpinMutex<reserve::Reservations>,
+    /// Pin counts of the blocks pinned by `pin_block`, by inode and
+    /// logical block
+    pins: SpinMutex<BTreeMap<(InodeId, LBlockId), u32>>,
+    /// The copy-on-write overlay the filesystem is loaded on, if any
+    #[cfg(feature = "mem_device")]
+    cow: Option<Arc<CowDevice>>,
 }
 
 impl Ext4 {
@@ -27,35 +144,327 @@ impl Ext4 {
         // TODO: if the main superblock is corrupted, should we load the backup?
         let block = block_device.read_block(0);
         let sb = block.read_offset_as::<SuperBlock>(BASE_OFFSET);
-        log::debug!("Load Ext4 Superblock: {:?}", sb);
+        log_fs!(debug, "Load Ext4 Superblock: {:?}", sb);
+        Self::check_super_block(&sb)?;
+        // Check device size
+        if let Some(dev_blocks) = block_device.block_count() {
+            if dev_blocks < sb.block_count() {
+                return_error!(
+                    ErrCode::EINVAL,
+                    "Device too small: {} blocks, filesystem has {} blocks",
+                    dev_blocks,
+                    sb.block_count()
+                );
+            }
+        }
+        // Unsupported features make the filesystem read-only
+        let unsupported = UnsupportedFeatures::of(&sb);
+        // Create Ext4 instance
+        let ext4 = Self {
+            #[cfg(feature = "block_cache")]
+            block_cache: BlockCache::new(block_device),
+            #[cfg(not(feature = "block_cache"))]
+            block_device,
+            handles: SpinMutex::new(open_files::OpenFiles::new(MAX_OPEN_HANDLES)),
+            stats: Default::default(),
+            pending_written_blocks: AtomicU64::new(0),
+            wall_clock: None,
+            reserved_access: true,
+            alloc_shards: None,
+            cred: None,
+            umask: InodeMode::empty(),
+            crypto: None,
+            verity: None,
+            mapping_invalidator: None,
+            discard: false,
+            zero_on_free: false,
+            data_journal: false,
+            dir_shrink: false,
+            readahead: Readahead::new(),
+            error_policy: ErrorPolicy::from_super_block(&sb),
+            read_only: AtomicBool::new(!unsupported.is_empty()),
+            unsupported,
+            txn: SpinMutex::default(),
+            thread_id: journal::default_thread_id,
+            extent_status: SpinMutex::default(),
+            inode_batch: SpinMutex::default(),
+            reservations: SpinMutex::default(),
+            pins: SpinMutex::default(),
+            #[cfg(feature = "mem_device")]
+            cow: None,
+        };
+        ext4.check_block_groups()?;
+        if !unsupported.is_empty() {
+            log::warn!("Unsupported features {:?}, loading read-only", unsupported);
+            if sb
+                .features_incompatible()
+                .contains(FeatureIncompat::RECOVER)
+            {
+                log::warn!("Journal not replayed, recent changes may be missing");
+            }
+            return Ok(ext4);
+        }
+        // Replay transactions left in the journal
+        ext4.journal_recover()?;
+        ext4.mark_mounted();
+        // Free inodes left on the orphan list
+        ext4.orphan_cleanup()?;
+        Ok(ext4)
+    }
+
+    /// Check that the fields of a superblock are consistent, so that the
+    /// geometry derived from them can be used without further checks.
+    ///
+    /// # Error
+    ///
+    /// `EINVAL` if the superblock is invalid.
+    pub(crate) fn check_super_block(sb: &SuperBlock) -> Result<()> {
         // Check magic number
         if !sb.check_magic() {
             return_error!(ErrCode::EINVAL, "Invalid magic number");
         }
-        // Check inode size
-        if sb.inode_size() != SB_GOOD_INODE_SIZE {
-            return_error!(ErrCode::EINVAL, "Invalid inode size {}", sb.inode_size());
+        // Check block size, only `BLOCK_SIZE` is supported
+        if sb.block_size() != BLOCK_SIZE as u32 {
+            return_error!(
+                ErrCode::EINVAL,
+                "Unsupported block size {}, expected {}",
+                sb.block_size(),
+                BLOCK_SIZE
+            );
         }
-        // Check block group desc size
-        if sb.desc_size() != SB_GOOD_DESC_SIZE {
+        // Check inode size. ext2 images may use 128-byte inodes.
+        let inode_size = sb.inode_size();
+        if !(SB_MIN_INODE_SIZE..=BLOCK_SIZE).contains(&inode_size) || !inode_size.is_power_of_two()
+        {
+            return_error!(ErrCode::EINVAL, "Invalid inode size {}", inode_size);
+        }
+        // Check block group desc size, always 32 bytes without `64bit`
+        let desc_size = sb.desc_size();
+        if sb.is_64bit()
+            && (!(SB_GOOD_DESC_SIZE..=SB_MAX_DESC_SIZE).contains(&desc_size)
+                || !desc_size.is_power_of_two())
+        {
             return_error!(
                 ErrCode::EINVAL,
                 "Invalid block group desc size {}",
-                sb.desc_size()
+                desc_size
             );
         }
-        // Create Ext4 instance
-        Ok(Self {
-            #[cfg(feature = "block_cache")]
-            block_cache: BlockCache::new(block_device),
-            #[cfg(not(feature = "block_cache"))]
-            block_device,
-        })
+        // Check group geometry, bitmaps are a block and the inode table
+        // fills at least one
+        let max_per_group = 8 * BLOCK_SIZE as u32;
+        let inodes_per_block = (BLOCK_SIZE / inode_size) as u32;
+        if !(inodes_per_block..=max_per_group).contains(&sb.inodes_per_group())
+            || sb.blocks_per_group() == 0
+            || (!sb.has_bigalloc() && sb.blocks_per_group() > max_per_group)
+        {
+            return_error!(
+                ErrCode::EINVAL,
+                "Invalid group geometry: {} blocks and {} inodes per group",
+                sb.blocks_per_group(),
+                sb.inodes_per_group()
+            );
+        }
+        // Each group starts within the filesystem, the first one at the
+        // block of the superblock
+        if sb.first_data_block() != (BLOCK_SIZE == 1024) as u32 {
+            return_error!(
+                ErrCode::EINVAL,
+                "Invalid first data block {}",
+                sb.first_data_block()
+            );
+        }
+        let groups = sb.block_count().div_ceil(sb.blocks_per_group() as u64);
+        if groups == 0
+            || groups > u32::MAX as u64
+            || sb.group_first_block(groups as u32 - 1) >= sb.block_count()
+        {
+            return_error!(ErrCode::EINVAL, "Invalid block count {}", sb.block_count());
+        }
+        let group_inodes = sb.block_group_count() as u64 * sb.inodes_per_group() as u64;
+        if sb.inode_count() as u64 != group_inodes {
+            return_error!(
+                ErrCode::EINVAL,
+                "Inode count {} does not match {} inodes in groups",
+                sb.inode_count(),
+                group_inodes
+            );
+        }
+        // Check cluster geometry, block bitmaps are in clusters
+        if sb.has_bigalloc()
+            && (sb.clusters_per_group() as u64 * sb.cluster_ratio() as u64
+                != sb.blocks_per_group() as u64
+                || sb.clusters_per_group() as usize > 8 * BLOCK_SIZE)
+        {
+            return_error!(
+                ErrCode::EINVAL,
+                "Invalid cluster geometry: {} clusters of {} blocks per group",
+                sb.clusters_per_group(),
+                sb.cluster_ratio()
+            );
+        }
+        // Check filename encoding of casefolded directories
+        if sb
+            .features_incompatible()
+            .contains(FeatureIncompat::CASEFOLD)
+        {
+            #[cfg(feature = "casefold")]
+            if sb.encoding() != SuperBlock::ENCODING_UTF8_12_1 {
+                return_error!(
+                    ErrCode::EINVAL,
+                    "Unsupported filename encoding {}",
+                    sb.encoding()
+                );
+            }
+            #[cfg(not(feature = "casefold"))]
+            log::warn!("Casefold is not enabled, names will be compared case-sensitively");
+        }
+        Ok(())
     }
     
-    /// Initializes the root directory.
+    /// Check that the block group descriptors, and the bitmaps and inode
+    /// table of each group, lie within the filesystem, so that reading them
+    /// stays on the device.
+    ///
+    /// # Error
+    ///
+    /// `EINVAL` if some of them are out of range.
+    fn check_block_groups(&self) -> Result<()> {
+        let sb = self.read_super_block();
+        // Blocks past the superblock
+        let blocks = sb.first_data_block() as PBlockId + 1..sb.block_count();
+        let groups = sb.block_group_count();
+        let (desc_end, _) = self.block_group_disk_pos(groups - 1);
+        if !blocks.contains(&desc_end) {
+            return_error!(
+                ErrCode::EINVAL,
+                "Descriptors of {} block groups exceed {} blocks",
+                groups,
+                sb.block_count()
+            );
+        }
+        let table_blocks = sb.inode_table_blocks() as PBlockId;
+        for bgid in 0..groups {
+            let desc = self.read_block_group(bgid).desc;
+            let table = desc.inode_table_first_block();
+            if !blocks.contains(&desc.block_bitmap_block())
+                || !blocks.contains(&desc.inode_bitmap_block())
+                || !blocks.contains(&table)
+                || table.saturating_add(table_blocks) > sb.block_count()
+            {
+                return_error!(
+                    ErrCode::EINVAL,
+                    "Metadata of block group {} out of range",
+                    bgid
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the maximum number of open file handles. Defaults to
+    /// `MAX_OPEN_HANDLES`.
+    pub fn set_max_handles(&mut self, max: usize) {
+        self.handles.lock().set_max(max);
+    }
+
+    /// Set whether allocations may use the blocks reserved for privileged
+    /// users. Defaults to `true`, i.e. the embedder acts as root. Set it to
+    /// `false` when serving unprivileged users, so they get `ENOSPC` once
+    /// only reserved blocks remain.
+    pub fn set_reserved_access(&mut self, allowed: bool) {
+        self.reserved_access = allowed;
+    }
+
+    /// Set whether freed blocks are discarded on the block device, see
+    /// `BlockDevice::discard`. Defaults to `false`.
+    pub fn set_discard(&mut self, enabled: bool) {
+        self.discard = enabled;
+    }
+
+    /// Set whether the blocks of all inodes are zeroed when they are freed,
+    /// by removing the inode, defragmenting it or shrinking a directory:
+    /// data, extent tree, indirect and xattr blocks alike. Defaults to
+    /// `false`, as it writes every freed block; blocks of inodes with the
+    /// `SECRM` flag are always zeroed.
+    pub fn set_zero_on_free(&mut self, enabled: bool) {
+        self.zero_on_free = enabled;
+    }
+
+    /// Set whether the data of all regular files is written through the
+    /// journal, like the `data=journal` mount option. Defaults to `false`,
+    /// in which case only files with the `JOURNAL_DATA` flag are. Has no
+    /// effect on filesystems without a journal.
+    pub fn set_data_journal(&mut self, enabled: bool) {
+        self.data_journal = enabled;
+    }
+
+    /// Set whether empty blocks at the end of a directory are released when
+    /// entries are removed, so that directories shrink again after mass
+    /// deletion. Defaults to `false`; the first block is always kept.
+    pub fn set_dir_shrink(&mut self, enabled: bool) {
+        self.dir_shrink = enabled;
+    }
+
+    /// Cap the memory used to cache extent tree lookups at about `bytes`.
+    /// Defaults to room for 4096 cached ranges; 0 disables the cache. The
+    /// block cache has a fixed size of `CACHE_SIZE * CACHE_ASSOC` blocks.
+    pub fn set_cache_limit(&mut self, bytes: usize) {
+        self.extent_status.lock().set_limit(bytes);
+    }
+
+    /// Compute the CRC32C of metadata checksums with `f`, such as an
+    /// implementation using the CRC instructions of the CPU (SSE4.2 on x86,
+    /// Zbc on riscv64), instead of the table driven one. `None` restores it.
+    ///
+    /// `f(crc, data)` continues the reflected CRC32C `crc` over `data`,
+    /// without the final inversion. The setting is process-wide, set it
+    /// before loading any filesystem.
+    pub fn set_crc32c(f: Option<fn(u32, &[u8]) -> u32>) {
+        crate::ext4_defs::set_crc32c(f);
+    }
+
+    /// Get the number of blocks reserved for privileged users.
+    pub fn reserved_blocks(&self) -> u64 {
+        self.read_super_block().reserved_blocks_count()
+    }
+
+    /// Set the number of blocks reserved for privileged users.
+    ///
+    /// # Error
+    ///
+    /// `EINVAL` if `count` is more than half of all blocks.
+    pub fn set_reserved_blocks(&self, count: u64) -> Result<()> {
+        self.check_writable()?;
+        let mut sb = self.read_super_block();
+        if count > sb.block_count() / 2 {
+            return_error!(
+                ErrCode::EINVAL,
+                "Reserved blocks {} exceed half of {} blocks",
+                count,
+                sb.block_count()
+            );
+        }
+        sb.set_reserved_blocks_count(count);
+        self.write_super_block(&sb);
+        Ok(())
+    }
+
+    /// Initializes the root directory of a blank filesystem. Does nothing
+    /// if the root directory exists, as on images made by mkfs, see
+    /// `has_root`.
     pub fn init(&mut self) -> Result<()> {
+        if self.has_root() {
+            return Ok(());
+        }
+        self.check_writable()?;
         // Create root directory
         self.create_root_inode().map(|_| ())
     }
+
+    /// Check if the root directory has been initialized.
+    pub fn has_root(&self) -> bool {
+        let root = self.read_inode(EXT4_ROOT_INO);
+        root.inode.is_dir() && root.inode.link_count() > 0
+    }
 }
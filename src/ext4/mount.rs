@@ -0,0 +1,37 @@
+//! Mount and unmount bookkeeping in the superblock.
+//!
+//! Like Linux, loading the filesystem clears the "cleanly unmounted" flag
+//! in `s_state` and bumps the mount count, and `Ext4::unmount` sets the flag
+//! again once everything is on disk. An image whose state is not clean was
+//! not unmounted properly, so `e2fsck` checks it on the next boot.
+
+use super::Ext4;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+
+impl Ext4 {
+    /// Record a mount in the superblock: mark the filesystem as not clean
+    /// and bump the mount count.
+    pub(super) fn mark_mounted(&self) {
+        let mut sb = self.read_super_block();
+        if sb.state() & SuperBlock::STATE_VALID == 0 {
+            warn!("Filesystem was not cleanly unmounted, running e2fsck is recommended");
+        }
+        if sb.state() & SuperBlock::STATE_ERROR != 0 {
+            warn!("Filesystem has errors, running e2fsck is recommended");
+        }
+        sb.set_state(sb.state() & !SuperBlock::STATE_VALID);
+        sb.set_mount_count(sb.mount_count().wrapping_add(1));
+        self.write_super_block(&sb);
+        self.flush_device();
+    }
+
+    /// Unmount the filesystem: write back all pending updates, mark the
+    /// filesystem as cleanly unmounted and flush the block device.
+    ///
+    /// Dropping an `Ext4` without calling this leaves the filesystem
+    /// marked as not cleanly unmounted.
+    pub fn unmount(self) {
+        self.finalize();
+    }
+}
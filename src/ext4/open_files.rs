@@ -0,0 +1,75 @@
+//! Open file handles.
+//!
+//! Handles are kept in a `HandleTable`, together with the number of open
+//! handles of each inode, so that `release` and the other places that
+//! need to know whether an inode is open do not scan all handles.
+
+use super::Ext4;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+
+/// The open file handles.
+pub(super) struct OpenFiles {
+    table: HandleTable<FileHandle>,
+    /// Number of open handles of each inode, inodes without any are absent
+    counts: BTreeMap<InodeId, u32>,
+}
+
+impl OpenFiles {
+    /// Create an empty table that holds at most `max` handles.
+    pub(super) fn new(max: usize) -> Self {
+        Self {
+            table: HandleTable::new(max),
+            counts: BTreeMap::new(),
+        }
+    }
+
+    /// Set the maximum number of open handles.
+    pub(super) fn set_max(&mut self, max: usize) {
+        self.table.set_max(max);
+    }
+
+    /// Close all handles, keeping the maximum.
+    #[cfg(feature = "mem_device")]
+    pub(super) fn clear(&mut self) {
+        *self = Self::new(self.table.max());
+    }
+
+    /// Insert a handle, return its id. Fails with `EMFILE` if the table is
+    /// full.
+    pub(super) fn insert(&mut self, handle: FileHandle) -> Result<FileHandleId> {
+        let fh = self.table.insert(handle)?;
+        *self.counts.entry(handle.inode).or_insert(0) += 1;
+        Ok(fh)
+    }
+
+    /// Get a handle by id. Fails with `EBADF` if it is not open.
+    pub(super) fn get(&self, fh: FileHandleId) -> Result<FileHandle> {
+        self.table.get(fh).copied()
+    }
+
+    /// Remove a handle by id, return the removed handle. Fails with `EBADF`
+    /// if it is not open.
+    pub(super) fn remove(&mut self, fh: FileHandleId) -> Result<FileHandle> {
+        let handle = self.table.remove(fh)?;
+        if let Some(count) = self.counts.get_mut(&handle.inode) {
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(&handle.inode);
+            }
+        }
+        Ok(handle)
+    }
+
+    /// Check if an inode has open handles.
+    pub(super) fn is_open(&self, inode: InodeId) -> bool {
+        self.counts.contains_key(&inode)
+    }
+}
+
+impl Ext4 {
+    /// Check if an inode has open file handles.
+    pub(super) fn is_open(&self, inode: InodeId) -> bool {
+        self.handles.lock().is_open(inode)
+    }
+}
@@ -0,0 +1,80 @@
+//! The orphan list links inodes that are still in use but no longer have
+//! any directory entry, so that they can be freed after a crash.
+//!
+//! The head of the list is `sb.last_orphan`, and each orphan inode stores
+//! the number of the next orphan in its `dtime` field.
+
+use super::Ext4;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+use crate::return_error;
+
+impl Ext4 {
+    /// Add an inode to the head of the orphan list.
+    pub(super) fn orphan_add(&self, inode: &mut InodeRef) {
+        let mut sb = self.read_super_block();
+        inode.inode.set_dtime(sb.last_orphan());
+        self.write_inode(inode);
+        sb.set_last_orphan(inode.id);
+        self.write_super_block(&sb);
+        log_fs!(trace, "Add orphan inode {}", inode.id);
+    }
+
+    /// Remove an inode from the orphan list.
+    ///
+    /// # Error
+    ///
+    /// `ENOENT` - the inode is not in the orphan list
+    pub(super) fn orphan_remove(&self, inode: &mut InodeRef) -> Result<()> {
+        let mut sb = self.read_super_block();
+        let next = inode.inode.dtime();
+        if sb.last_orphan() == inode.id {
+            sb.set_last_orphan(next);
+            self.write_super_block(&sb);
+        } else {
+            // Find the predecessor, bounded by the inode count to avoid looping
+            let mut cur = sb.last_orphan();
+            let mut found = false;
+            for _ in 0..sb.inode_count() {
+                if cur == 0 {
+                    break;
+                }
+                let mut cur_ref = self.read_inode(cur);
+                if cur_ref.inode.dtime() == inode.id {
+                    cur_ref.inode.set_dtime(next);
+                    self.write_inode(&mut cur_ref);
+                    found = true;
+                    break;
+                }
+                cur = cur_ref.inode.dtime();
+            }
+            if !found {
+                return_error!(ErrCode::ENOENT, "Inode {} is not an orphan", inode.id);
+            }
+        }
+        inode.inode.set_dtime(0);
+        self.write_inode(inode);
+        log_fs!(trace, "Remove orphan inode {}", inode.id);
+        Ok(())
+    }
+
+    /// Process the orphan list left by an unclean unmount. Orphans that
+    /// have no links are freed, the others are just removed from the list.
+    pub(super) fn orphan_cleanup(&self) -> Result<()> {
+        let sb = self.read_super_block();
+        let mut cur = sb.last_orphan();
+        for _ in 0..sb.inode_count() {
+            if cur == 0 {
+                return Ok(());
+            }
+            let mut inode = self.read_inode(cur);
+            cur = inode.inode.dtime();
+            self.orphan_remove(&mut inode)?;
+            if inode.inode.link_count() == 0 {
+                log_fs!(info, "Free orphan inode {}", inode.id);
+                self.free_inode(&mut inode)?;
+            }
+        }
+        return_error!(ErrCode::EINVAL, "Orphan list contains a loop");
+    }
+}
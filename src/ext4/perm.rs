@@ -0,0 +1,226 @@
+//! Permission checking and ownership of new inodes.
+//!
+//! By default the filesystem performs no permission checks, and the
+//! embedder is responsible for them. After `Ext4::set_credentials`, the
+//! low-level operations check permission bits against the given
+//! credentials, including sticky directories, and new inodes are owned by
+//! the caller.
+//!
+//! Independent of credentials, new inodes follow the setgid-directory
+//! rule, and their permission bits are masked by `Ext4::set_umask`.
+//!
+//! With credentials, a write or truncation by anyone but the owner or root
+//! clears the setuid and setgid bits of a regular file, and so does a
+//! `chmod` of the setgid bit by a caller outside the group of the file.
+
+use super::Ext4;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+use crate::return_error;
+
+/// The identity on whose behalf operations are performed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    /// User id
+    pub uid: u32,
+    /// Primary group id
+    pub gid: u32,
+    /// Supplementary group ids
+    pub groups: Vec<u32>,
+}
+
+impl Credentials {
+    /// Credentials of a user with no supplementary groups.
+    pub fn new(uid: u32, gid: u32) -> Self {
+        Self {
+            uid,
+            gid,
+            groups: Vec::new(),
+        }
+    }
+
+    /// Credentials of the superuser.
+    pub fn root() -> Self {
+        Self::new(0, 0)
+    }
+
+    /// Set supplementary groups.
+    pub fn with_groups(mut self, groups: Vec<u32>) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    /// Check if the credentials belong to the superuser.
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    /// Check if the credentials are in a group.
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+}
+
+bitflags! {
+    /// Access requested on an inode.
+    #[derive(PartialEq, Eq, Debug, Clone, Copy)]
+    pub(super) struct Access: u16 {
+        const READ = 0o4;
+        const WRITE = 0o2;
+        const EXEC = 0o1;
+    }
+}
+
+impl Ext4 {
+    /// Set the credentials used for permission checks and for the owner of
+    /// new inodes. `None` disables permission checks.
+    pub fn set_credentials(&mut self, cred: Option<Credentials>) {
+        self.cred = cred;
+    }
+
+    /// Get the credentials used for permission checks.
+    pub fn credentials(&self) -> Option<&Credentials> {
+        self.cred.as_ref()
+    }
+
+    /// Check if the current credentials are granted `access` on an inode.
+    ///
+    /// # Error
+    ///
+    /// `EACCES` if access is denied.
+    pub(super) fn check_access(&self, inode: &InodeRef, access: Access) -> Result<()> {
+        let Some(cred) = &self.cred else {
+            return Ok(());
+        };
+        let perm = inode.inode.perm().bits();
+        if cred.is_root() {
+            // Root may execute only if any execute bit is set, except on directories
+            let any_exec = perm & 0o111 != 0 || inode.inode.is_dir();
+            if !access.contains(Access::EXEC) || any_exec {
+                return Ok(());
+            }
+        } else {
+            let granted = if cred.uid == inode.inode.uid() {
+                perm >> 6
+            } else if cred.in_group(inode.inode.gid()) {
+                perm >> 3
+            } else {
+                perm
+            };
+            if Access::from_bits_truncate(granted & 0o7).contains(access) {
+                return Ok(());
+            }
+        }
+        return_error!(
+            ErrCode::EACCES,
+            "Access {:?} to inode {} denied for uid {}",
+            access,
+            inode.id,
+            cred.uid
+        );
+    }
+
+    /// Check if the current credentials may add or remove entries in a
+    /// directory.
+    pub(super) fn check_dir_write(&self, dir: &InodeRef) -> Result<()> {
+        self.check_access(dir, Access::WRITE | Access::EXEC)
+    }
+
+    /// Check if the current credentials may remove or rename `child` from
+    /// `dir`. In a sticky directory only the owner of the child or of the
+    /// directory may do so.
+    ///
+    /// # Error
+    ///
+    /// `EPERM` if `dir` is sticky and the caller owns neither.
+    pub(super) fn check_sticky(&self, dir: &InodeRef, child: &InodeRef) -> Result<()> {
+        let Some(cred) = &self.cred else {
+            return Ok(());
+        };
+        if dir.inode.mode().contains(InodeMode::STICKY)
+            && !cred.is_root()
+            && cred.uid != child.inode.uid()
+            && cred.uid != dir.inode.uid()
+        {
+            return_error!(
+                ErrCode::EPERM,
+                "Inode {} in sticky directory {} is not owned by uid {}",
+                child.id,
+                dir.id,
+                cred.uid
+            );
+        }
+        Ok(())
+    }
+
+    /// Set the umask applied to the mode of new inodes. Defaults to 0.
+    pub fn set_umask(&mut self, umask: u16) {
+        self.umask = InodeMode::from_bits_truncate(umask) & InodeMode::ALL_RWX;
+    }
+
+    /// Get the umask applied to the mode of new inodes.
+    pub fn umask(&self) -> u16 {
+        self.umask.bits()
+    }
+
+    /// Apply the umask to the mode of a new inode.
+    pub(super) fn apply_umask(&self, mode: InodeMode) -> InodeMode {
+        mode & !self.umask
+    }
+
+    /// Clear the setuid bit of a regular file, and its setgid bit if group
+    /// execution is allowed, when the caller is neither its owner nor root.
+    /// Without group execution the setgid bit marks mandatory locking and
+    /// is kept. The inode is not written.
+    pub(super) fn clear_setid(&self, file: &mut InodeRef) {
+        let Some(cred) = &self.cred else {
+            return;
+        };
+        if !file.inode.is_file() || cred.is_root() || cred.uid == file.inode.uid() {
+            return;
+        }
+        let mut mode = file.inode.mode() - InodeMode::SET_UID;
+        if mode.contains(InodeMode::GROUP_EXEC) {
+            mode -= InodeMode::SET_GID;
+        }
+        file.inode.set_mode(mode);
+    }
+
+    /// The mode set by `chmod` on an inode: its file type with all 12
+    /// permission bits of `perm`. The setgid bit is dropped if the caller
+    /// is neither root nor in the group of the inode.
+    pub(super) fn chmod_mode(&self, inode: &InodeRef, perm: InodeMode) -> InodeMode {
+        let mut mode = InodeMode::from_type_and_perm(inode.inode.file_type(), perm);
+        if let Some(cred) = &self.cred {
+            if !cred.is_root() && !cred.in_group(inode.inode.gid()) {
+                mode -= InodeMode::SET_GID;
+            }
+        }
+        mode
+    }
+
+    /// Set the owner of a new inode created in `parent`.
+    ///
+    /// The inode is owned by the current credentials, if any. In a setgid
+    /// directory the inode inherits the group of the directory, and new
+    /// directories inherit the setgid bit. A new file keeps its setgid bit
+    /// only if the caller is in the inherited group.
+    pub(super) fn init_owner(&self, parent: &InodeRef, child: &mut InodeRef) {
+        if let Some(cred) = &self.cred {
+            child.inode.set_uid(cred.uid);
+            child.inode.set_gid(cred.gid);
+        }
+        if !parent.inode.mode().contains(InodeMode::SET_GID) {
+            return;
+        }
+        child.inode.set_gid(parent.inode.gid());
+        let mode = child.inode.mode();
+        if child.inode.is_dir() {
+            child.inode.set_mode(mode | InodeMode::SET_GID);
+        } else if let Some(cred) = &self.cred {
+            if !cred.is_root() && !cred.in_group(parent.inode.gid()) {
+                child.inode.set_mode(mode - InodeMode::SET_GID);
+            }
+        }
+    }
+}
@@ -0,0 +1,583 @@
+//! Disk quota files (`quota` feature).
+//!
+//! With the `quota` feature, usage and limits are stored in hidden quota
+//! files, one per quota type, whose inodes are recorded in the superblock
+//! (inodes 3 and 4 for users and groups). The files use the Linux quota
+//! tree format v2 (vfsv1), so `repquota`, `setquota` and `e2fsck` read
+//! what this crate writes and the other way round.
+//!
+//! A quota file is divided into 1 KiB blocks. Block 0 holds the header
+//! and the quota info, block 1 is the root of a 4-level radix tree indexed
+//! by the bytes of the id. The last level points to data blocks, each
+//! holding up to 14 entries. Blocks with free entries form a linked list.
+//!
+//! Every inode write charges the difference in ownership and space to the
+//! quota files, like `e2fsck` counts them: reserved inodes other than the
+//! root are not accounted. Limits are stored but not enforced.
+
+use super::Ext4;
+use crate::constants::*;
+use crate::ext4_defs::*;
+use crate::format_error;
+use crate::prelude::*;
+use crate::return_error;
+
+/// Size of a quota file block.
+const QT_BLOCK_SIZE: usize = 1024;
+/// Quota file format version (vfsv1).
+const QT_VERSION: u32 = 1;
+/// Block of the tree root.
+const QT_TREE_ROOT: u32 = 1;
+/// Depth of the quota tree.
+const QT_TREE_DEPTH: usize = 4;
+/// Size of the header of a data block.
+const QT_DATA_HEADER_SIZE: usize = 16;
+/// Size of a quota entry.
+const QT_ENTRY_SIZE: usize = 72;
+/// Number of entries in a data block.
+const QT_ENTRIES_PER_BLOCK: usize = (QT_BLOCK_SIZE - QT_DATA_HEADER_SIZE) / QT_ENTRY_SIZE;
+/// Offset of the quota info in block 0.
+const QT_INFO_OFFSET: usize = 8;
+
+/// A quota file block.
+type QuotaBlock = [u8; QT_BLOCK_SIZE];
+
+/// The kind of owner a quota applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaType {
+    User = 0,
+    Group = 1,
+    Project = 2,
+}
+
+impl QuotaType {
+    const ALL: [QuotaType; 3] = [QuotaType::User, QuotaType::Group, QuotaType::Project];
+
+    /// The magic number in the quota file header.
+    fn magic(self) -> u32 {
+        match self {
+            QuotaType::User => 0xd9c0_1f11,
+            QuotaType::Group => 0xd9c0_1927,
+            QuotaType::Project => 0xd9c0_3f14,
+        }
+    }
+}
+
+/// Quota limits of an id. A limit of 0 means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaLimits {
+    /// Soft limit of space, in bytes, rounded up to KiB on disk
+    pub space_soft: u64,
+    /// Hard limit of space, in bytes, rounded up to KiB on disk
+    pub space_hard: u64,
+    /// Soft limit of inodes
+    pub inode_soft: u64,
+    /// Hard limit of inodes
+    pub inode_hard: u64,
+}
+
+/// Usage and limits of one user, group or project.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaEntry {
+    /// User, group or project id
+    pub id: u32,
+    /// Space in use, in bytes
+    pub space: u64,
+    /// Number of inodes in use
+    pub inodes: u64,
+    /// Limits of the id
+    pub limits: QuotaLimits,
+    /// End of the grace period of the space soft limit, 0 if not exceeded
+    pub space_grace_end: u64,
+    /// End of the grace period of the inode soft limit, 0 if not exceeded
+    pub inode_grace_end: u64,
+}
+
+impl QuotaEntry {
+    /// Decode entry `slot` of a data block.
+    fn decode(data: &QuotaBlock, slot: usize) -> Self {
+        let off = QT_DATA_HEADER_SIZE + slot * QT_ENTRY_SIZE;
+        let field = |i: usize| get_u64(data, off + 8 + i * 8);
+        let mut entry = Self {
+            id: get_u32(data, off),
+            inodes: field(2),
+            space: field(5),
+            limits: QuotaLimits {
+                inode_hard: field(0),
+                inode_soft: field(1),
+                space_hard: field(3) * QT_BLOCK_SIZE as u64,
+                space_soft: field(4) * QT_BLOCK_SIZE as u64,
+            },
+            space_grace_end: field(6),
+            inode_grace_end: field(7),
+        };
+        // An all-zero entry is stored with `itime` 1 to tell it from an unused one
+        if entry
+            == (Self {
+                inode_grace_end: 1,
+                ..Self::default()
+            })
+        {
+            entry.inode_grace_end = 0;
+        }
+        entry
+    }
+
+    /// Encode the entry into `slot` of a data block.
+    fn encode(&self, data: &mut QuotaBlock, slot: usize) {
+        let off = QT_DATA_HEADER_SIZE + slot * QT_ENTRY_SIZE;
+        let to_blocks = |bytes: u64| bytes.div_ceil(QT_BLOCK_SIZE as u64);
+        let fields = [
+            self.limits.inode_hard,
+            self.limits.inode_soft,
+            self.inodes,
+            to_blocks(self.limits.space_hard),
+            to_blocks(self.limits.space_soft),
+            self.space,
+            self.space_grace_end,
+            self.inode_grace_end,
+        ];
+        put_u32(data, off, self.id);
+        put_u32(data, off + 4, 0);
+        for (i, field) in fields.iter().enumerate() {
+            put_u64(data, off + 8 + i * 8, *field);
+        }
+        if entry_unused(data, slot) {
+            put_u64(data, off + 64, 1);
+        }
+    }
+}
+
+/// The quota info in block 0 of a quota file.
+struct QuotaInfo {
+    /// Grace period of the space soft limit, in seconds
+    space_grace: u32,
+    /// Grace period of the inode soft limit, in seconds
+    inode_grace: u32,
+    /// Number of blocks in the file
+    blocks: u32,
+    /// Head of the list of free blocks
+    free_blk: u32,
+    /// Head of the list of data blocks with free entries
+    free_entry: u32,
+}
+
+impl Ext4 {
+    /// Get the usage and limits of an id. An id without an entry in the
+    /// quota file has no usage and no limits.
+    ///
+    /// # Error
+    ///
+    /// * `ENOTSUP` - the quota type is not enabled on the filesystem
+    /// * `EFSCORRUPTED` - the quota file is corrupted
+    pub fn quota_get(&self, qtype: QuotaType, id: u32) -> Result<QuotaEntry> {
+        let file = self.quota_file(qtype)?;
+        match self.quota_find(&file, id)? {
+            Some((blk, slot)) => Ok(QuotaEntry::decode(&self.quota_read(&file, blk)?, slot)),
+            None => Ok(QuotaEntry {
+                id,
+                ..QuotaEntry::default()
+            }),
+        }
+    }
+
+    /// List all entries of a quota file, ordered by id.
+    ///
+    /// # Error
+    ///
+    /// * `ENOTSUP` - the quota type is not enabled on the filesystem
+    /// * `EFSCORRUPTED` - the quota file is corrupted
+    pub fn quota_list(&self, qtype: QuotaType) -> Result<Vec<QuotaEntry>> {
+        let file = self.quota_file(qtype)?;
+        let mut entries = Vec::new();
+        self.quota_walk(&file, QT_TREE_ROOT, 0, 0, &mut entries)?;
+        Ok(entries)
+    }
+
+    /// Set the limits of an id, creating its entry if needed. Only root
+    /// may set limits when credentials are set.
+    ///
+    /// # Error
+    ///
+    /// * `EPERM` - the caller is not root
+    /// * `ENOTSUP` - the quota type is not enabled on the filesystem
+    /// * `ENOSPC` - no space left to grow the quota file
+    pub fn quota_set_limits(&self, qtype: QuotaType, id: u32, limits: QuotaLimits) -> Result<()> {
+        self.check_writable()?;
+        if let Some(cred) = &self.cred {
+            if !cred.is_root() {
+                return_error!(ErrCode::EPERM, "Uid {} cannot set quota limits", cred.uid);
+            }
+        }
+        self.quota_modify(qtype, id, |entry| entry.limits = limits)
+    }
+
+    /// Charge the change of an inode from `old` to `new` to the quota
+    /// files. Called on every inode write.
+    pub(super) fn quota_update(&self, ino: InodeId, old: &Inode, new: &Inode) {
+        let (old, new) = (quota_charge(old), quota_charge(new));
+        if old == new {
+            return;
+        }
+        let sb = self.read_super_block();
+        if !sb.features_read_only().contains(FeatureRoCompat::QUOTA) {
+            return;
+        }
+        let files = sb.quota_inodes();
+        if (ino < sb.first_inode() && ino != EXT4_ROOT_INO) || files.contains(&ino) {
+            return;
+        }
+        for qtype in QuotaType::ALL {
+            let i = qtype as usize;
+            if files[i] == 0 {
+                continue;
+            }
+            let mut changes = Vec::new();
+            match (old, new) {
+                (Some((old_ids, old_space)), Some((new_ids, new_space)))
+                    if old_ids[i] == new_ids[i] =>
+                {
+                    changes.push((new_ids[i], new_space as i64 - old_space as i64, 0));
+                }
+                _ => {
+                    if let Some((ids, space)) = old {
+                        changes.push((ids[i], -(space as i64), -1));
+                    }
+                    if let Some((ids, space)) = new {
+                        changes.push((ids[i], space as i64, 1));
+                    }
+                }
+            }
+            for (id, space, inodes) in changes {
+                if let Err(e) = self.quota_modify(qtype, id, |entry| {
+                    entry.space = entry.space.saturating_add_signed(space);
+                    entry.inodes = entry.inodes.saturating_add_signed(inodes);
+                }) {
+                    warn!("Failed to update {:?} quota of id {}: {}", qtype, id, e);
+                }
+            }
+        }
+    }
+
+    /// Modify the entry of an id with `f`, creating the entry if needed,
+    /// and update its grace periods.
+    fn quota_modify<F>(&self, qtype: QuotaType, id: u32, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut QuotaEntry),
+    {
+        let mut file = self.quota_file(qtype)?;
+        let (blk, slot) = match self.quota_find(&file, id)? {
+            Some(pos) => pos,
+            None => self.quota_insert(&mut file, id)?,
+        };
+        let mut data = self.quota_read(&file, blk)?;
+        let mut entry = QuotaEntry::decode(&data, slot);
+        f(&mut entry);
+
+        let info = self.quota_info(&file)?;
+        let now = self.wall_clock.map(|clock| clock());
+        let grace_end = |used: u64, soft: u64, end: u64, grace: u32| {
+            if soft == 0 || used <= soft {
+                0
+            } else if end == 0 {
+                now.map_or(0, |now| now + grace as u64)
+            } else {
+                end
+            }
+        };
+        entry.space_grace_end = grace_end(
+            entry.space,
+            entry.limits.space_soft,
+            entry.space_grace_end,
+            info.space_grace,
+        );
+        entry.inode_grace_end = grace_end(
+            entry.inodes,
+            entry.limits.inode_soft,
+            entry.inode_grace_end,
+            info.inode_grace,
+        );
+
+        entry.encode(&mut data, slot);
+        self.quota_write(&mut file, blk, &data)
+    }
+
+    /// Read the quota file of a quota type and check its header.
+    fn quota_file(&self, qtype: QuotaType) -> Result<InodeRef> {
+        let sb = self.read_super_block();
+        let ino = sb.quota_inodes()[qtype as usize];
+        if !sb.features_read_only().contains(FeatureRoCompat::QUOTA) || ino == 0 {
+            return_error!(ErrCode::ENOTSUP, "{:?} quota is not enabled", qtype);
+        }
+        let file = self.read_inode(ino);
+        let header = self.quota_read(&file, 0)?;
+        if get_u32(&header, 0) != qtype.magic() || get_u32(&header, 4) != QT_VERSION {
+            let err = format_error!(ErrCode::EFSCORRUPTED, "Quota file {} has a bad header", ino);
+            return Err(self.fs_error("quota_file", ino, 0, err));
+        }
+        Ok(file)
+    }
+
+    /// Find the data block and slot of the entry of an id.
+    fn quota_find(&self, file: &InodeRef, id: u32) -> Result<Option<(u32, usize)>> {
+        let mut blk = QT_TREE_ROOT;
+        for depth in 0..QT_TREE_DEPTH {
+            blk = get_u32(&self.quota_read(file, blk)?, tree_index(id, depth) * 4);
+            if blk == 0 {
+                return Ok(None);
+            }
+        }
+        let data = self.quota_read(file, blk)?;
+        match (0..QT_ENTRIES_PER_BLOCK).find(|&slot| entry_is(&data, slot, id)) {
+            Some(slot) => Ok(Some((blk, slot))),
+            None => {
+                let err = format_error!(
+                    ErrCode::EFSCORRUPTED,
+                    "Quota entry of id {} is missing from block {} of quota file {}",
+                    id,
+                    blk,
+                    file.id
+                );
+                Err(self.fs_error("quota_find", file.id, 0, err))
+            }
+        }
+    }
+
+    /// Insert an empty entry for an id that has none. Return its data block
+    /// and slot.
+    fn quota_insert(&self, file: &mut InodeRef, id: u32) -> Result<(u32, usize)> {
+        let mut blk = QT_TREE_ROOT;
+        for depth in 0..QT_TREE_DEPTH {
+            let mut data = self.quota_read(file, blk)?;
+            let index = tree_index(id, depth) * 4;
+            let mut next = get_u32(&data, index);
+            if next == 0 {
+                next = if depth == QT_TREE_DEPTH - 1 {
+                    self.quota_free_entry_block(file)?
+                } else {
+                    self.quota_alloc_block(file)?
+                };
+                put_u32(&mut data, index, next);
+                self.quota_write(file, blk, &data)?;
+            } else if depth == QT_TREE_DEPTH - 1 {
+                let err = format_error!(
+                    ErrCode::EFSCORRUPTED,
+                    "Quota entry of id {} is already present in quota file {}",
+                    id,
+                    file.id
+                );
+                return Err(self.fs_error("quota_insert", file.id, 0, err));
+            }
+            blk = next;
+        }
+
+        let mut data = self.quota_read(file, blk)?;
+        let Some(slot) = (0..QT_ENTRIES_PER_BLOCK).find(|&slot| entry_unused(&data, slot)) else {
+            let err = format_error!(
+                ErrCode::EFSCORRUPTED,
+                "Block {} of quota file {} has no free entry",
+                blk,
+                file.id
+            );
+            return Err(self.fs_error("quota_insert", file.id, 0, err));
+        };
+        let entries = get_u16(&data, 8) + 1;
+        put_u16(&mut data, 8, entries);
+        if entries as usize == QT_ENTRIES_PER_BLOCK {
+            self.quota_unlink_free_entry(file, &mut data)?;
+        }
+        QuotaEntry {
+            id,
+            ..QuotaEntry::default()
+        }
+        .encode(&mut data, slot);
+        self.quota_write(file, blk, &data)?;
+        Ok((blk, slot))
+    }
+
+    /// Get a data block with a free entry, allocating one if there is none.
+    fn quota_free_entry_block(&self, file: &mut InodeRef) -> Result<u32> {
+        let info = self.quota_info(file)?;
+        if info.free_entry != 0 {
+            return Ok(info.free_entry);
+        }
+        let blk = self.quota_alloc_block(file)?;
+        let mut info = self.quota_info(file)?;
+        info.free_entry = blk;
+        self.quota_write_info(file, &info)?;
+        Ok(blk)
+    }
+
+    /// Remove a data block that became full from the list of data blocks
+    /// with free entries. The header of the block is updated in `data`.
+    fn quota_unlink_free_entry(&self, file: &mut InodeRef, data: &mut QuotaBlock) -> Result<()> {
+        let (next, prev) = (get_u32(data, 0), get_u32(data, 4));
+        if next != 0 {
+            let mut block = self.quota_read(file, next)?;
+            put_u32(&mut block, 4, prev);
+            self.quota_write(file, next, &block)?;
+        }
+        if prev != 0 {
+            let mut block = self.quota_read(file, prev)?;
+            put_u32(&mut block, 0, next);
+            self.quota_write(file, prev, &block)?;
+        } else {
+            let mut info = self.quota_info(file)?;
+            info.free_entry = next;
+            self.quota_write_info(file, &info)?;
+        }
+        put_u32(data, 0, 0);
+        put_u32(data, 4, 0);
+        Ok(())
+    }
+
+    /// Allocate a zeroed block in a quota file, reusing a free block or
+    /// growing the file.
+    fn quota_alloc_block(&self, file: &mut InodeRef) -> Result<u32> {
+        let mut info = self.quota_info(file)?;
+        let blk = if info.free_blk != 0 {
+            let blk = info.free_blk;
+            info.free_blk = get_u32(&self.quota_read(file, blk)?, 0);
+            blk
+        } else {
+            info.blocks += 1;
+            info.blocks - 1
+        };
+        self.quota_write(file, blk, &[0; QT_BLOCK_SIZE])?;
+        self.quota_write_info(file, &info)?;
+        Ok(blk)
+    }
+
+    /// Collect the entries under tree block `blk` at `depth`, whose ids
+    /// start with the bytes in `prefix`.
+    fn quota_walk(
+        &self,
+        file: &InodeRef,
+        blk: u32,
+        depth: usize,
+        prefix: u32,
+        entries: &mut Vec<QuotaEntry>,
+    ) -> Result<()> {
+        let data = self.quota_read(file, blk)?;
+        for index in 0..QT_BLOCK_SIZE / 4 {
+            let next = get_u32(&data, index * 4);
+            if next == 0 {
+                continue;
+            }
+            let id = (prefix << 8) | index as u32;
+            if depth < QT_TREE_DEPTH - 1 {
+                self.quota_walk(file, next, depth + 1, id, entries)?;
+            } else {
+                let block = self.quota_read(file, next)?;
+                if let Some(slot) = (0..QT_ENTRIES_PER_BLOCK).find(|&s| entry_is(&block, s, id)) {
+                    entries.push(QuotaEntry::decode(&block, slot));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the quota info of a quota file.
+    fn quota_info(&self, file: &InodeRef) -> Result<QuotaInfo> {
+        let data = self.quota_read(file, 0)?;
+        let field = |i: usize| get_u32(&data, QT_INFO_OFFSET + i * 4);
+        Ok(QuotaInfo {
+            space_grace: field(0),
+            inode_grace: field(1),
+            blocks: field(3),
+            free_blk: field(4),
+            free_entry: field(5),
+        })
+    }
+
+    /// Write the quota info of a quota file.
+    fn quota_write_info(&self, file: &mut InodeRef, info: &QuotaInfo) -> Result<()> {
+        let mut data = self.quota_read(file, 0)?;
+        put_u32(&mut data, QT_INFO_OFFSET + 12, info.blocks);
+        put_u32(&mut data, QT_INFO_OFFSET + 16, info.free_blk);
+        put_u32(&mut data, QT_INFO_OFFSET + 20, info.free_entry);
+        self.quota_write(file, 0, &data)
+    }
+
+    /// Read block `blk` of a quota file.
+    fn quota_read(&self, file: &InodeRef, blk: u32) -> Result<QuotaBlock> {
+        let pos = BlockOffset::new(blk as u64 * QT_BLOCK_SIZE as u64);
+        let pblock = self.extent_query(file, pos.iblock()?)?;
+        let block = self.read_block(pblock);
+        let offset = pos.in_block();
+        let mut data = [0; QT_BLOCK_SIZE];
+        data.copy_from_slice(&block.data[offset..offset + QT_BLOCK_SIZE]);
+        Ok(data)
+    }
+
+    /// Write block `blk` of a quota file, growing the file if needed.
+    fn quota_write(&self, file: &mut InodeRef, blk: u32, data: &QuotaBlock) -> Result<()> {
+        let pos = BlockOffset::new(blk as u64 * QT_BLOCK_SIZE as u64);
+        let lblock = pos.iblock()?;
+        let mut block = match self.map_block(file, lblock)? {
+            (pblock, true) => Block::new(pblock, [0; BLOCK_SIZE]),
+            (pblock, false) => self.read_block(pblock),
+        };
+        let offset = pos.in_block();
+        block.data[offset..offset + QT_BLOCK_SIZE].copy_from_slice(data);
+        self.write_block(&block);
+
+        let end = pos.checked_add(QT_BLOCK_SIZE)?.get();
+        if file.inode.size() < end {
+            file.inode.set_size(end);
+            self.write_inode(file);
+        }
+        Ok(())
+    }
+}
+
+/// The ids an inode is charged to, indexed by quota type, and the space
+/// it uses. `None` if the inode is not in use.
+fn quota_charge(inode: &Inode) -> Option<([u32; 3], u64)> {
+    if inode.mode().bits() == 0 {
+        return None;
+    }
+    let ids = [inode.uid(), inode.gid(), inode.projid()];
+    Some((ids, inode.block_count() * INODE_BLOCK_SIZE as u64))
+}
+
+/// Index into a tree block at `depth` for an id.
+fn tree_index(id: u32, depth: usize) -> usize {
+    ((id >> ((QT_TREE_DEPTH - 1 - depth) * 8)) & 0xff) as usize
+}
+
+/// Whether entry `slot` of a data block is unused, i.e. all zero.
+fn entry_unused(data: &QuotaBlock, slot: usize) -> bool {
+    let off = QT_DATA_HEADER_SIZE + slot * QT_ENTRY_SIZE;
+    data[off..off + QT_ENTRY_SIZE].iter().all(|&b| b == 0)
+}
+
+/// Whether entry `slot` of a data block belongs to an id.
+fn entry_is(data: &QuotaBlock, slot: usize, id: u32) -> bool {
+    let off = QT_DATA_HEADER_SIZE + slot * QT_ENTRY_SIZE;
+    !entry_unused(data, slot) && get_u32(data, off) == id
+}
+
+fn get_u16(data: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([data[off], data[off + 1]])
+}
+
+fn get_u32(data: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(data[off..off + 4].try_into().unwrap())
+}
+
+fn get_u64(data: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(data[off..off + 8].try_into().unwrap())
+}
+
+fn put_u16(data: &mut [u8], off: usize, value: u16) {
+    data[off..off + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(data: &mut [u8], off: usize, value: u32) {
+    data[off..off + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(data: &mut [u8], off: usize, value: u64) {
+    data[off..off + 8].copy_from_slice(&value.to_le_bytes());
+}
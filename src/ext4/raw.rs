@@ -0,0 +1,144 @@
+//! Raw block access to inode data.
+//!
+//! `read` and `write` only accept regular files and work on the byte
+//! stream. Backup and clone tools need to copy directories and special
+//! files block-for-block, including data the high-level interfaces never
+//! expose. These operations are grouped behind `Ext4::raw` so that callers
+//! opt in explicitly.
+//!
+//! Raw writes only touch the content of mapped blocks. Allocation goes
+//! through the extent tree, so block bitmaps, block counts and extents
+//! stay consistent. Checksums inside the written data (e.g. directory
+//! block tails) are the caller's responsibility.
+
+use super::Ext4;
+use crate::constants::*;
+use crate::ext4_defs::*;
+use crate::format_error;
+use crate::prelude::*;
+use crate::return_error;
+
+/// Raw block access to the data of any inode, acquired by `Ext4::raw`.
+pub struct RawAccess<'a> {
+    fs: &'a Ext4,
+}
+
+impl Ext4 {
+    /// Get the raw block access interface.
+    pub fn raw(&self) -> RawAccess<'_> {
+        RawAccess { fs: self }
+    }
+}
+
+impl RawAccess<'_> {
+    /// Get the number of logical blocks mapped by an inode. Unlike the
+    /// block count of the inode, this leaves out extent tree and xattr
+    /// blocks.
+    ///
+    /// # Error
+    ///
+    /// * `EINVAL` - the inode is invalid or does not use extents
+    /// * `EFSCORRUPTED` - the extent tree of the inode is malformed
+    pub fn mapped_block_count(&self, inode: InodeId) -> Result<LBlockId> {
+        let inode_ref = self.read_mapped_inode(inode)?;
+        let mut count: u64 = 0;
+        self.fs.extent_for_each(&inode_ref, |ex| {
+            count += ex.block_count() as u64;
+            Ok(())
+        })?;
+        LBlockId::try_from(count).map_err(|_| {
+            format_error!(
+                ErrCode::EFSCORRUPTED,
+                "Inode {} maps {} blocks",
+                inode,
+                count
+            )
+        })
+    }
+
+    /// Read a mapped block of an inode.
+    ///
+    /// # Params
+    ///
+    /// * `inode` - the inode to read, of any file type
+    /// * `lblock` - the logical block id within the inode
+    /// * `buf` - the buffer to fill with the block content
+    ///
+    /// # Error
+    ///
+    /// * `EINVAL` - the inode is invalid or does not use extents
+    /// * `ENOENT` - `lblock` is not mapped
+    pub fn read_mapped_block(
+        &self,
+        inode: InodeId,
+        lblock: LBlockId,
+        buf: &mut [u8; BLOCK_SIZE],
+    ) -> Result<()> {
+        let inode_ref = self.read_mapped_inode(inode)?;
+        let pblock = self.fs.extent_query(&inode_ref, lblock)?;
+        buf.copy_from_slice(&self.fs.read_block(pblock).data);
+        Ok(())
+    }
+
+    /// Write a mapped block of an inode. If `lblock` is a hole or the block
+    /// right after the last mapped one, a new block is mapped there.
+    ///
+    /// For directories the size grows to cover the written block. For other
+    /// file types the size is left unchanged, use `setattr` to set it.
+    ///
+    /// # Params
+    ///
+    /// * `inode` - the inode to write, of any file type
+    /// * `lblock` - the logical block id within the inode
+    /// * `data` - the new block content
+    ///
+    /// # Error
+    ///
+    /// * `EINVAL` - the inode is invalid or does not use extents, or
+    ///   `lblock` would leave a hole after the last mapped block
+    /// * `ENOSPC` - no space left on device
+    pub fn write_mapped_block(
+        &self,
+        inode: InodeId,
+        lblock: LBlockId,
+        data: &[u8; BLOCK_SIZE],
+    ) -> Result<()> {
+        self.fs.check_writable()?;
+        let mut inode_ref = self.read_mapped_inode(inode)?;
+        let end = self.fs.extent_end(&inode_ref)?;
+        if lblock > end {
+            return_error!(
+                ErrCode::EINVAL,
+                "Raw write to block {} of inode {} leaves a hole after block {}",
+                lblock,
+                inode,
+                end
+            );
+        }
+        let (pblock, _) = self.fs.map_block(&mut inode_ref, lblock)?;
+        self.fs.write_block(&Block::new(pblock, *data));
+
+        let end = (lblock as u64 + 1) * BLOCK_SIZE as u64;
+        if inode_ref.inode.is_dir() && inode_ref.inode.size() < end {
+            inode_ref.inode.set_size(end);
+        }
+        self.fs.write_inode(&mut inode_ref);
+        Ok(())
+    }
+
+    /// Read an inode whose blocks are mapped by an extent tree.
+    fn read_mapped_inode(&self, inode: InodeId) -> Result<InodeRef> {
+        let inode_ref = self.fs.read_inode(inode);
+        if inode_ref.inode.link_count() == 0 && inode_ref.inode.mode().bits() == 0 {
+            return_error!(ErrCode::EINVAL, "Invalid inode {}", inode);
+        }
+        if !inode_ref.inode.uses_extents() {
+            return_error!(
+                ErrCode::EINVAL,
+                "Inode {} does not map blocks with extents",
+                inode
+            );
+        }
+        Ok(inode_ref)
+    }
+}
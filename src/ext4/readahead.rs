@@ -0,0 +1,80 @@
+//! Readahead for sequential reads.
+//!
+//! When a read continues where the previous read of the same file ended,
+//! or starts at the beginning of a file, the blocks following it are passed
+//! to the block device (or the block cache) as a readahead hint, see
+//! `BlockDevice::readahead`. Only the most recent read is tracked, so
+//! interleaved sequential reads of several files are not detected.
+
+use super::Ext4;
+use crate::constants::*;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Readahead state.
+pub(super) struct Readahead {
+    /// Number of blocks to read ahead, 0 if readahead is disabled
+    window: u32,
+    /// Inode id (high 32 bits) and the block after the end (low 32 bits)
+    /// of the last read
+    last: AtomicU64,
+}
+
+impl Readahead {
+    /// Create a disabled readahead state.
+    pub fn new() -> Self {
+        Self {
+            window: 0,
+            last: AtomicU64::new(0),
+        }
+    }
+
+    fn key(inode: InodeId, lblock: LBlockId) -> u64 {
+        ((inode as u64) << 32) | lblock as u64
+    }
+}
+
+impl Ext4 {
+    /// Set the number of blocks read ahead on sequential reads. 0 disables
+    /// readahead, which is the default.
+    pub fn set_readahead(&mut self, blocks: u32) {
+        self.readahead.window = blocks;
+    }
+
+    /// Record a read of blocks `start..end` of a file, and read ahead the
+    /// blocks after it if the access is sequential.
+    pub(super) fn readahead_on_read(&self, file: &InodeRef, start: LBlockId, end: LBlockId) {
+        let window = self.readahead.window;
+        if window == 0 {
+            return;
+        }
+        let last = self
+            .readahead
+            .last
+            .swap(Readahead::key(file.id, end), Ordering::Relaxed);
+        if start != 0 && last != Readahead::key(file.id, start) {
+            return;
+        }
+        let file_blocks = file.inode.size().div_ceil(BLOCK_SIZE as u64);
+        let ra_end = (end as u64 + window as u64)
+            .min(file_blocks)
+            .min(MAX_BLOCKS as u64) as LBlockId;
+        // Issue a hint for each physically contiguous run
+        let mut lblock = end;
+        while lblock < ra_end {
+            let Ok(pblock) = self.extent_query(file, lblock) else {
+                lblock += 1;
+                continue;
+            };
+            let mut len = 1;
+            while lblock + len < ra_end
+                && self.extent_query(file, lblock + len).ok() == Some(pblock + len as PBlockId)
+            {
+                len += 1;
+            }
+            self.readahead_blocks(pblock, len as u64);
+            lblock += len;
+        }
+    }
+}
@@ -0,0 +1,195 @@
+//! Block reservations.
+//!
+//! A reservation sets free blocks aside for the operations that follow, so
+//! that a caller can make sure a large write fits before changing anything,
+//! and operations that allocate in several steps, such as `mkdir`, fail
+//! before the first step rather than midway. Reserved blocks stay free on
+//! disk, and other reservations cannot take them.
+//!
+//! Blocks allocated while reservations are held are taken from the
+//! innermost one first. Reservations nest: a reservation made while
+//! another is held takes what it can from it, and gives that back when it
+//! is released if it was not used.
+
+use super::Ext4;
+use crate::constants::*;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+use crate::return_error;
+use core::cmp::min;
+
+/// A held reservation.
+struct Held {
+    /// Reserved blocks not allocated yet
+    remaining: u64,
+    /// Blocks taken from the enclosing reservation
+    borrowed: u64,
+}
+
+/// The reservations held, innermost last.
+#[derive(Default)]
+pub(super) struct Reservations {
+    held: Vec<Held>,
+}
+
+impl Reservations {
+    /// The number of blocks set aside by all reservations.
+    fn total(&self) -> u64 {
+        self.held.iter().map(|held| held.remaining).sum()
+    }
+}
+
+/// Blocks set aside by `Ext4::reserve_blocks`, released when dropped.
+/// Reservations must be dropped in the reverse order they were made.
+pub struct BlockReservation<'a> {
+    fs: &'a Ext4,
+    /// Position in the reservation stack
+    depth: usize,
+}
+
+impl BlockReservation<'_> {
+    /// The number of reserved blocks not allocated yet.
+    pub fn remaining(&self) -> u64 {
+        let reservations = self.fs.reservations.lock();
+        reservations
+            .held
+            .get(self.depth)
+            .map_or(0, |held| held.remaining)
+    }
+}
+
+impl Drop for BlockReservation<'_> {
+    fn drop(&mut self) {
+        let mut reservations = self.fs.reservations.lock();
+        if reservations.held.len() <= self.depth {
+            return;
+        }
+        reservations.held.truncate(self.depth + 1);
+        let held = reservations.held.pop().unwrap();
+        // Give back the unused blocks taken from the enclosing reservation
+        if let Some(outer) = reservations.held.last_mut() {
+            outer.remaining += min(held.remaining, held.borrowed);
+        }
+    }
+}
+
+impl Ext4 {
+    /// Reserve `count` free blocks for the operations that follow, until
+    /// the returned reservation is dropped. Blocks allocated meanwhile are
+    /// taken from it. Only the reservations nested in it can take its
+    /// blocks: a nested reservation fails if it needs more than the blocks
+    /// of the enclosing one and the available ones.
+    ///
+    /// Use `write_blocks_needed` to get the number of blocks a write needs.
+    ///
+    /// # Error
+    ///
+    /// * `EROFS` - the filesystem is read-only
+    /// * `ENOSPC` - fewer blocks are available, see `available_blocks`
+    pub fn reserve_blocks(&self, count: u64) -> Result<BlockReservation<'_>> {
+        self.check_writable()?;
+        let usable = self.usable_blocks(&self.read_super_block());
+        let mut reservations = self.reservations.lock();
+        let available = usable.saturating_sub(reservations.total());
+        // Blocks already reserved by the enclosing reservation are used
+        // first
+        let borrowed = reservations
+            .held
+            .last()
+            .map_or(0, |outer| min(outer.remaining, count));
+        if count - borrowed > available {
+            return_error!(
+                ErrCode::ENOSPC,
+                "Cannot reserve {} blocks, {} available",
+                count,
+                available + borrowed
+            );
+        }
+        if let Some(outer) = reservations.held.last_mut() {
+            outer.remaining -= borrowed;
+        }
+        reservations.held.push(Held {
+            remaining: count,
+            borrowed,
+        });
+        Ok(BlockReservation {
+            fs: self,
+            depth: reservations.held.len() - 1,
+        })
+    }
+
+    /// The number of blocks that can be reserved: the free blocks, less
+    /// the blocks reserved for privileged users without reserved access,
+    /// and less the blocks held by reservations.
+    pub fn available_blocks(&self) -> u64 {
+        let usable = self.usable_blocks(&self.read_super_block());
+        usable.saturating_sub(self.reservations.lock().total())
+    }
+
+    /// The number of blocks writing `len` bytes at `offset` of a file
+    /// allocates: one for each block of the range that is not mapped, and
+    /// the extent tree or indirect blocks needed to map them, assuming the
+    /// free space is not fragmented. Pass it to `reserve_blocks` to make
+    /// sure the write does not run out of space.
+    ///
+    /// # Error
+    ///
+    /// * `EFBIG` - the range is past the maximum file size
+    /// * `EFSCORRUPTED` - the extent tree of the file is malformed
+    pub fn write_blocks_needed(&self, file: InodeId, offset: u64, len: usize) -> Result<u64> {
+        let inode_ref = self.read_inode(file);
+        if len == 0 {
+            return Ok(0);
+        }
+        let offset = BlockOffset::new(offset);
+        let start = offset.iblock()?;
+        let end = offset.checked_add(len)?.get().div_ceil(BLOCK_SIZE as u64) as LBlockId;
+        let block_map = inode_ref.inode.uses_block_map();
+        let mut needed = 0;
+        let mut hole_start = None;
+        for iblock in start..end {
+            let blocks = self.extent_blocks_needed(&inode_ref, iblock)?;
+            if blocks == 0 {
+                hole_start = None;
+                continue;
+            }
+            let first = *hole_start.get_or_insert(iblock);
+            // Mapping blocks are needed for the first block of a hole, and
+            // then for each new extent or indirect block
+            let new_mapping = if block_map {
+                iblock >= 12 && (iblock - 12) % (BLOCK_SIZE as u32 / 4) == 0
+            } else {
+                (iblock - first) % Extent::INIT_MAX_LEN as u32 == 0
+            };
+            needed += if iblock == first || new_mapping {
+                blocks
+            } else {
+                1
+            };
+        }
+        Ok(needed)
+    }
+
+    /// The number of free blocks allocations may use, the blocks reserved
+    /// for privileged users only with reserved access.
+    pub(super) fn usable_blocks(&self, sb: &SuperBlock) -> u64 {
+        let free = sb.free_blocks_count();
+        if self.reserved_access {
+            free
+        } else {
+            free.saturating_sub(sb.reserved_blocks_count())
+        }
+    }
+
+    /// Take `count` newly allocated blocks out of the held reservations,
+    /// the innermost first.
+    pub(super) fn reservation_take(&self, count: u64) {
+        let mut count = count;
+        let mut reservations = self.reservations.lock();
+        for held in reservations.held.iter_mut().rev() {
+            let taken = min(held.remaining, count);
+            held.remaining -= taken;
+            count -= taken;
+        }
+    }
+}
@@ -2,10 +2,23 @@ use super::Ext4;
 use crate::constants::*;
 use crate::ext4_defs::*;
 use crate::prelude::*;
+use core::cmp::min;
+use core::ops::Range;
+#[cfg(not(feature = "block_cache"))]
+use core::sync::atomic::Ordering;
 
 impl Ext4 {
-    /// Read a block from block device
+    /// Read a block from block device, or from the running transaction
     pub(super) fn read_block(&self, block_id: PBlockId) -> Block {
+        self.stats.block_read();
+        if let Some(block) = self.txn_read(block_id) {
+            return block;
+        }
+        self.read_home_block(block_id)
+    }
+
+    /// Read a block from block device, ignoring the running transaction
+    pub(super) fn read_home_block(&self, block_id: PBlockId) -> Block {
         #[cfg(feature = "block_cache")]
         {
             self.block_cache.read_block(block_id)
@@ -16,15 +29,128 @@ impl Ext4 {
         }
     }
 
-    /// Write a block to block device
+    /// Write `data` at `offset` in a block. In a transaction, the write is
+    /// buffered without reading the rest of the block when possible.
+    pub(super) fn write_block_range(&self, block_id: PBlockId, offset: usize, data: &[u8]) {
+        if self.txn_write_range(block_id, offset, data) {
+            self.stats.block_write();
+            return;
+        }
+        let mut block = self.read_block(block_id);
+        block.write_offset(offset, data);
+        self.write_block(&block);
+    }
+
+    /// Write a block to block device. In a transaction, the write is
+    /// buffered until the transaction commits.
     pub(super) fn write_block(&self, block: &Block) {
+        self.stats.block_write();
+        if self.txn_write(block) {
+            return;
+        }
         #[cfg(feature = "block_cache")]
         {
             self.block_cache.write_block(block)
         }
         #[cfg(not(feature = "block_cache"))]
         {
-            self.block_device.write_block(block)
+            self.block_device.write_block(block);
+            self.pending_written_blocks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Read a block from block device, bypassing the block cache
+    pub(super) fn read_block_direct(&self, block_id: PBlockId) -> Block {
+        self.stats.block_read();
+        if let Some(block) = self.txn_read(block_id) {
+            return block;
+        }
+        #[cfg(feature = "block_cache")]
+        {
+            self.block_cache.read_block_direct(block_id)
+        }
+        #[cfg(not(feature = "block_cache"))]
+        {
+            self.block_device.read_block(block_id)
+        }
+    }
+
+    /// Write a block to block device, bypassing the block cache. In a
+    /// transaction, the write is buffered until the transaction commits.
+    pub(super) fn write_block_direct(&self, block: &Block) {
+        self.stats.block_write();
+        if self.txn_write(block) {
+            return;
+        }
+        #[cfg(feature = "block_cache")]
+        {
+            self.block_cache.write_block_direct(block)
+        }
+        #[cfg(not(feature = "block_cache"))]
+        {
+            self.block_device.write_block(block);
+            self.pending_written_blocks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Write a block to block device and wait until it is on stable storage.
+    /// All writes issued before are persisted first.
+    #[allow(unused)]
+    pub(super) fn write_block_sync(&self, block: &Block) {
+        self.stats.block_write();
+        if self.txn_write(block) {
+            return;
+        }
+        #[cfg(feature = "block_cache")]
+        {
+            self.block_cache.write_block_sync(block)
+        }
+        #[cfg(not(feature = "block_cache"))]
+        {
+            self.block_device.flush();
+            self.block_device.write_block_sync(block);
+            self.pending_written_blocks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Write back cached blocks and issue a write barrier to the block device
+    pub(super) fn flush_device(&self) {
+        #[cfg(feature = "block_cache")]
+        {
+            self.block_cache.flush_all()
+        }
+        #[cfg(not(feature = "block_cache"))]
+        {
+            self.block_device.flush()
+        }
+    }
+
+    /// Discard a range of free blocks on the block device
+    pub(super) fn discard_blocks(&self, start: PBlockId, count: u64) {
+        log_alloc!(trace, "Discard blocks {}..{}", start, start + count);
+        if self.txn_discard(start, count) {
+            return;
+        }
+        #[cfg(feature = "block_cache")]
+        {
+            self.block_cache.discard(start, count)
+        }
+        #[cfg(not(feature = "block_cache"))]
+        {
+            self.block_device.discard(start, count)
+        }
+    }
+
+    /// Prefetch a range of blocks that are likely to be read soon
+    pub(super) fn readahead_blocks(&self, start: PBlockId, count: u64) {
+        log_cache!(trace, "Readahead blocks {}..{}", start, start + count);
+        #[cfg(feature = "block_cache")]
+        {
+            self.block_cache.readahead(start, count)
+        }
+        #[cfg(not(feature = "block_cache"))]
+        {
+            self.block_device.readahead(start, count)
         }
     }
 
@@ -35,20 +161,25 @@ impl Ext4 {
         block.read_offset_as(BASE_OFFSET)
     }
 
-    /// Write super block to block device
+    /// Write super block to block device with checksum
     pub(super) fn write_super_block(&self, sb: &SuperBlock) {
+        let mut sb = *sb;
+        sb.set_checksum();
         let mut block = Block::new(0, [0; BLOCK_SIZE]);
-        block.write_offset_as(BASE_OFFSET, sb);
+        block.write_offset_as(BASE_OFFSET, &sb);
         self.write_block(&block)
     }
 
     /// Read an inode from block device, return an `InodeRef` that
     /// combines the inode and its id.
     pub(super) fn read_inode(&self, inode_id: InodeId) -> InodeRef {
+        if let Some(inode) = self.inode_batch_read(inode_id) {
+            return InodeRef::new(inode_id, inode);
+        }
+        let inode_size = self.read_super_block().inode_size();
         let (block_id, offset) = self.inode_disk_pos(inode_id);
         let block = self.read_block(block_id);
-        
-        InodeRef::new(inode_id, block.read_offset_as(offset))
+        InodeRef::new(inode_id, block.read_record_as(offset, inode_size))
     }
 
     /// Read the root inode from block device
@@ -57,48 +188,71 @@ impl Ext4 {
         self.read_inode(EXT4_ROOT_INO)
     }
 
-    /// Write an inode to block device with checksum
-    pub(super) fn write_inode_with_csum(&self, inode_ref: &mut InodeRef) {
+    /// Write an inode to block device with checksum. The checksum covers
+    /// the whole on-disk inode, including the bytes past `Inode`, and is
+    /// only set with `metadata_csum`. Changes of ownership and space are
+    /// charged to the quota files. In a batch, the write is buffered until
+    /// the batch ends, and checksummed then.
+    pub(super) fn write_inode(&self, inode_ref: &mut InodeRef) {
+        if self.inode_batch_write(inode_ref) {
+            return;
+        }
         let super_block = self.read_super_block();
-        inode_ref.set_checksum(&super_block.uuid());
-        self.write_inode_without_csum(inode_ref);
-    }
-
-    /// Write an inode to block device without checksum
-    pub(super) fn write_inode_without_csum(&self, inode_ref: &InodeRef) {
+        let inode_size = super_block.inode_size();
         let (block_id, offset) = self.inode_disk_pos(inode_ref.id);
         let mut block = self.read_block(block_id);
-        block.write_offset_as(offset, &inode_ref.inode);
-        self.write_block(&block)
+        if super_block.has_metadata_csum() {
+            let extra = Self::inode_extra_range(offset, inode_size);
+            inode_ref.set_checksum(super_block.checksum_seed(), inode_size, &block.data[extra]);
+        }
+        let old: Inode = block.read_record_as(offset, inode_size);
+        block.write_record_as(offset, inode_size, &inode_ref.inode);
+        self.write_block(&block);
+        self.quota_update(inode_ref.id, &old, &inode_ref.inode);
     }
 
     /// Read a block group descriptor from block device, return an `BlockGroupRef`
     /// that combines the block group descriptor and its id.
     pub(super) fn read_block_group(&self, block_group_id: BlockGroupId) -> BlockGroupRef {
+        let desc_size = self.read_super_block().desc_size();
         let (block_id, offset) = self.block_group_disk_pos(block_group_id);
         let block = self.read_block(block_id as PBlockId);
         BlockGroupRef::new(
             block_group_id,
-            block.read_offset_as::<BlockGroupDesc>(offset),
+            block.read_record_as::<BlockGroupDesc>(offset, desc_size),
         )
     }
 
-    /// Write a block group descriptor to block device with checksum
-    pub(super) fn write_block_group_with_csum(&self, bg_ref: &mut BlockGroupRef) {
+    /// Write a block group descriptor to block device with checksum. The
+    /// checksum covers the whole on-disk descriptor, including the bytes
+    /// past `BlockGroupDesc`, and is only set with `metadata_csum`.
+    pub(super) fn write_block_group(&self, bg_ref: &mut BlockGroupRef) {
         let super_block = self.read_super_block();
-        bg_ref.set_checksum(&super_block.uuid());
-        self.write_block_group_without_csum(bg_ref);
-    }
-
-    /// Write a block group descriptor to block device without checksum
-    #[allow(unused)]
-    pub(super) fn write_block_group_without_csum(&self, bg_ref: &BlockGroupRef) {
+        let desc_size = super_block.desc_size();
         let (block_id, offset) = self.block_group_disk_pos(bg_ref.id);
         let mut block = self.read_block(block_id as PBlockId);
-        block.write_offset_as(offset, &bg_ref.desc);
+        if super_block.has_metadata_csum() {
+            let extra = Self::block_group_extra_range(offset, desc_size);
+            bg_ref.set_checksum(super_block.checksum_seed(), desc_size, &block.data[extra]);
+        }
+        block.write_record_as(offset, desc_size, &bg_ref.desc);
         self.write_block(&block);
     }
 
+    /// Check the checksum of a block group descriptor, always valid
+    /// without `metadata_csum`.
+    pub(super) fn block_group_checksum_valid(&self, bg_ref: &BlockGroupRef) -> bool {
+        let super_block = self.read_super_block();
+        if !super_block.has_metadata_csum() {
+            return true;
+        }
+        let desc_size = super_block.desc_size();
+        let (block_id, offset) = self.block_group_disk_pos(bg_ref.id);
+        let block = self.read_block(block_id as PBlockId);
+        let extra = Self::block_group_extra_range(offset, desc_size);
+        bg_ref.verify_checksum(super_block.checksum_seed(), desc_size, &block.data[extra])
+    }
+
     /// Get disk position of an inode. Return block id and offset within the block.
     ///
     /// Each block group contains `sb.inodes_per_group` inodes.
@@ -110,7 +264,7 @@ impl Ext4 {
     /// inode table at `index = (inode_id - 1) % sb.inodes_per_group`.
     /// To get the byte address within the inode table, use
     /// `offset = index * sb.inode_size`.
-    fn inode_disk_pos(&self, inode_id: InodeId) -> (PBlockId, usize) {
+    pub(super) fn inode_disk_pos(&self, inode_id: InodeId) -> (PBlockId, usize) {
         let super_block = self.read_super_block();
         let inodes_per_group = super_block.inodes_per_group();
 
@@ -125,13 +279,29 @@ impl Ext4 {
         (block_id, offset)
     }
 
+    /// The bytes of the on-disk inode at `offset` past `Inode`, covered by
+    /// the inode checksum. Empty for inodes of 256 bytes or less.
+    pub(super) fn inode_extra_range(offset: usize, inode_size: usize) -> Range<usize> {
+        offset + min(size_of::<Inode>(), inode_size)..offset + inode_size
+    }
+
+    /// The bytes of the on-disk block group descriptor at `offset` past
+    /// `BlockGroupDesc`, covered by its checksum. Empty for descriptors of
+    /// 64 bytes or less.
+    fn block_group_extra_range(offset: usize, desc_size: usize) -> Range<usize> {
+        offset + min(size_of::<BlockGroupDesc>(), desc_size)..offset + desc_size
+    }
+
     /// Get disk position of a block group. Return block id and offset within the block.
-    fn block_group_disk_pos(&self, block_group_id: BlockGroupId) -> (PBlockId, usize) {
+    pub(super) fn block_group_disk_pos(&self, block_group_id: BlockGroupId) -> (PBlockId, usize) {
         let super_block = self.read_super_block();
         let desc_per_block = BLOCK_SIZE as u32 / super_block.desc_size() as u32;
 
-        let block_id = super_block.first_data_block() + block_group_id / desc_per_block + 1;
-        let offset = (block_group_id % desc_per_block) * super_block.desc_size() as u32;
-        (block_id as PBlockId, offset as usize)
+        // Computed as `PBlockId` so that it cannot wrap
+        let block_id = super_block.first_data_block() as PBlockId
+            + (block_group_id / desc_per_block) as PBlockId
+            + 1;
+        let offset = (block_group_id % desc_per_block) as usize * super_block.desc_size();
+        (block_id, offset)
     }
 }
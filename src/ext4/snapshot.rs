@@ -0,0 +1,74 @@
+//! Copy-on-write snapshots of a mounted filesystem.
+//!
+//! Model checkers and test frameworks explore many filesystem states from
+//! a common one. Loading the filesystem with `Ext4::load_cow` puts a
+//! `CowDevice` between it and the block device, so a state is saved by
+//! copying the map of modified blocks instead of the whole device, and
+//! restored in place without reloading.
+
+use super::Ext4;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+use crate::return_error;
+
+impl Ext4 {
+    /// Open and load an Ext4 from `block_device` through a copy-on-write
+    /// overlay. Modifications stay in memory until `commit` is called.
+    pub fn load_cow(block_device: Arc<dyn BlockDevice>) -> Result<Self> {
+        let cow = Arc::new(CowDevice::new(block_device));
+        let mut ext4 = Self::load(cow.clone())?;
+        ext4.cow = Some(cow);
+        Ok(ext4)
+    }
+
+    /// Take a snapshot of the current filesystem state.
+    ///
+    /// # Error
+    ///
+    /// `ENOTSUP` if the filesystem is not loaded with `load_cow`.
+    pub fn snapshot(&self) -> Result<CowSnapshot> {
+        let cow = self.cow_device()?;
+        self.flush_device();
+        Ok(cow.snapshot())
+    }
+
+    /// Revert the filesystem to a snapshot taken by `snapshot`. Cached
+    /// blocks are dropped, and all open file handles are closed and block
+    /// pins released, since they may refer to inodes that do not exist in
+    /// the snapshot.
+    ///
+    /// # Error
+    ///
+    /// `ENOTSUP` if the filesystem is not loaded with `load_cow`.
+    pub fn rollback(&mut self, snapshot: &CowSnapshot) -> Result<()> {
+        let cow = self.cow_device()?.clone();
+        #[cfg(feature = "block_cache")]
+        self.block_cache.invalidate_all();
+        cow.rollback(snapshot);
+        self.es_clear();
+        self.handles.lock().clear();
+        self.pins.lock().clear();
+        Ok(())
+    }
+
+    /// Write all modifications to the underlying block device. Snapshots
+    /// taken before must not be rolled back to afterwards.
+    ///
+    /// # Error
+    ///
+    /// `ENOTSUP` if the filesystem is not loaded with `load_cow`.
+    pub fn commit(&self) -> Result<()> {
+        let cow = self.cow_device()?;
+        self.flush_device();
+        cow.commit();
+        Ok(())
+    }
+
+    /// Get the copy-on-write overlay the filesystem is loaded on.
+    fn cow_device(&self) -> Result<&Arc<CowDevice>> {
+        let Some(cow) = &self.cow else {
+            return_error!(ErrCode::ENOTSUP, "Not loaded on a copy-on-write device");
+        };
+        Ok(cow)
+    }
+}
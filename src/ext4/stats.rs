@@ -0,0 +1,247 @@
+//! Instrumentation counters.
+//!
+//! With the `stats` feature enabled, the filesystem counts block I/O,
+//! allocations, cache hits and extent splits, and measures the time spent
+//! in each low-level operation. `Ext4::stats` returns a snapshot of them.
+//!
+//! Without the feature, `Stats` is an empty struct whose hooks compile
+//! to nothing.
+
+#[cfg(feature = "stats")]
+use super::Ext4;
+
+/// Low-level operations whose latencies are measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum Op {
+    Getattr,
+    Setattr,
+    Create,
+    Read,
+    Write,
+    Link,
+    Unlink,
+    Rename,
+    Mkdir,
+    Lookup,
+    Listdir,
+    Rmdir,
+    Getxattr,
+    Setxattr,
+    Removexattr,
+    Listxattr,
+    Getflags,
+    Setflags,
+    Symlink,
+    Readlink,
+}
+
+impl Op {
+    /// Number of operation kinds.
+    pub const COUNT: usize = Op::Readlink as usize + 1;
+}
+
+/// Call count and accumulated time of an operation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpStats {
+    /// Number of calls
+    pub count: u64,
+    /// Total time spent, in units of the clock set by `Ext4::set_stats_clock`.
+    /// Always 0 if no clock is set.
+    pub total_time: u64,
+}
+
+/// A snapshot of the instrumentation counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ext4Stats {
+    /// Blocks read by the filesystem
+    pub block_reads: u64,
+    /// Blocks written by the filesystem
+    pub block_writes: u64,
+    /// Block reads served by the block cache
+    pub cache_hits: u64,
+    /// Block reads and writes that missed the block cache
+    pub cache_misses: u64,
+    /// Blocks allocated
+    pub block_allocs: u64,
+    /// Blocks freed
+    pub block_frees: u64,
+    /// Inodes allocated
+    pub inode_allocs: u64,
+    /// Inodes freed
+    pub inode_frees: u64,
+    /// Extent tree node splits
+    pub extent_splits: u64,
+    /// Per-operation statistics, indexed by `Op`
+    pub ops: [OpStats; Op::COUNT],
+}
+
+impl Ext4Stats {
+    /// Get the statistics of an operation.
+    pub fn op(&self, op: Op) -> OpStats {
+        self.ops[op as usize]
+    }
+}
+
+#[cfg(feature = "stats")]
+mod imp {
+    use super::{Ext4Stats, Op, OpStats};
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    /// Instrumentation counters of a filesystem instance.
+    #[derive(Default)]
+    pub struct Stats {
+        block_reads: AtomicU64,
+        block_writes: AtomicU64,
+        block_allocs: AtomicU64,
+        block_frees: AtomicU64,
+        inode_allocs: AtomicU64,
+        inode_frees: AtomicU64,
+        extent_splits: AtomicU64,
+        op_count: [AtomicU64; Op::COUNT],
+        op_time: [AtomicU64; Op::COUNT],
+        clock: Option<fn() -> u64>,
+    }
+
+    /// Measures an operation, recorded when dropped.
+    pub struct OpTimer<'a> {
+        stats: &'a Stats,
+        op: Op,
+        start: u64,
+    }
+
+    impl Drop for OpTimer<'_> {
+        fn drop(&mut self) {
+            let op = self.op as usize;
+            self.stats.op_count[op].fetch_add(1, Ordering::Relaxed);
+            if let Some(clock) = self.stats.clock {
+                let elapsed = clock().saturating_sub(self.start);
+                self.stats.op_time[op].fetch_add(elapsed, Ordering::Relaxed);
+            }
+        }
+    }
+
+    impl Stats {
+        pub fn set_clock(&mut self, clock: fn() -> u64) {
+            self.clock = Some(clock);
+        }
+
+        pub fn op(&self, op: Op) -> OpTimer<'_> {
+            OpTimer {
+                stats: self,
+                op,
+                start: self.clock.map_or(0, |clock| clock()),
+            }
+        }
+
+        pub fn block_read(&self) {
+            self.block_reads.fetch_add(1, Ordering::Relaxed);
+        }
+
+        pub fn block_write(&self) {
+            self.block_writes.fetch_add(1, Ordering::Relaxed);
+        }
+
+        pub fn block_alloc(&self) {
+            self.block_allocs.fetch_add(1, Ordering::Relaxed);
+        }
+
+        pub fn block_free(&self) {
+            self.block_frees.fetch_add(1, Ordering::Relaxed);
+        }
+
+        pub fn inode_alloc(&self) {
+            self.inode_allocs.fetch_add(1, Ordering::Relaxed);
+        }
+
+        pub fn inode_free(&self) {
+            self.inode_frees.fetch_add(1, Ordering::Relaxed);
+        }
+
+        pub fn extent_split(&self) {
+            self.extent_splits.fetch_add(1, Ordering::Relaxed);
+        }
+
+        pub fn snapshot(&self) -> Ext4Stats {
+            let mut ops = [OpStats::default(); Op::COUNT];
+            for (i, op) in ops.iter_mut().enumerate() {
+                op.count = self.op_count[i].load(Ordering::Relaxed);
+                op.total_time = self.op_time[i].load(Ordering::Relaxed);
+            }
+            Ext4Stats {
+                block_reads: self.block_reads.load(Ordering::Relaxed),
+                block_writes: self.block_writes.load(Ordering::Relaxed),
+                cache_hits: 0,
+                cache_misses: 0,
+                block_allocs: self.block_allocs.load(Ordering::Relaxed),
+                block_frees: self.block_frees.load(Ordering::Relaxed),
+                inode_allocs: self.inode_allocs.load(Ordering::Relaxed),
+                inode_frees: self.inode_frees.load(Ordering::Relaxed),
+                extent_splits: self.extent_splits.load(Ordering::Relaxed),
+                ops,
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "stats"))]
+mod imp {
+    use super::Op;
+
+    /// Instrumentation disabled, all hooks are no-ops.
+    #[derive(Default)]
+    pub struct Stats;
+
+    /// Instrumentation disabled, measures nothing.
+    pub struct OpTimer;
+
+    impl Stats {
+        #[inline(always)]
+        pub fn op(&self, _op: Op) -> OpTimer {
+            OpTimer
+        }
+
+        #[inline(always)]
+        pub fn block_read(&self) {}
+
+        #[inline(always)]
+        pub fn block_write(&self) {}
+
+        #[inline(always)]
+        pub fn block_alloc(&self) {}
+
+        #[inline(always)]
+        pub fn block_free(&self) {}
+
+        #[inline(always)]
+        pub fn inode_alloc(&self) {}
+
+        #[inline(always)]
+        pub fn inode_free(&self) {}
+
+        #[inline(always)]
+        pub fn extent_split(&self) {}
+    }
+}
+
+pub(super) use imp::Stats;
+
+#[cfg(feature = "stats")]
+impl Ext4 {
+    /// Get a snapshot of the instrumentation counters.
+    pub fn stats(&self) -> Ext4Stats {
+        #[allow(unused_mut)]
+        let mut stats = self.stats.snapshot();
+        #[cfg(feature = "block_cache")]
+        {
+            (stats.cache_hits, stats.cache_misses) = self.block_cache.hits_and_misses();
+        }
+        stats
+    }
+
+    /// Set the clock used to measure operation latencies. The clock returns
+    /// a monotonic timestamp in any unit, e.g. nanoseconds or CPU cycles.
+    pub fn set_stats_clock(&mut self, clock: fn() -> u64) {
+        self.stats.set_clock(clock);
+    }
+}
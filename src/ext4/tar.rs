@@ -0,0 +1,710 @@
+//! Tar archive import and export.
+//!
+//! A `TarImporter` populates a directory from a tar stream fed in pieces of
+//! any size, and `Ext4::tar_export` writes a directory tree as a tar
+//! stream, so images can be built and inspected without mounting them.
+//!
+//! Archives use the ustar format, with pax extended headers for long
+//! names, large numbers and extended attributes, stored as
+//! `SCHILY.xattr.<name>` records as GNU tar does. GNU long name entries are
+//! understood on import. Regular files, directories, hard and symbolic
+//! links, devices and FIFOs are supported, other entries are skipped.
+
+use super::walk::{WalkControl, WalkOptions};
+use super::Ext4;
+use crate::constants::*;
+use crate::ext4_defs::*;
+use crate::format_error;
+use crate::prelude::*;
+use crate::return_error;
+use core::cmp::min;
+
+/// Size of a tar header, entry data is padded to a multiple of it
+const TAR_BLOCK: usize = 512;
+
+/// Prefix of the pax records holding extended attributes
+const PAX_XATTR: &str = "SCHILY.xattr.";
+
+/// Entry types
+const TAR_FILE: u8 = b'0';
+const TAR_HARD_LINK: u8 = b'1';
+const TAR_SYMLINK: u8 = b'2';
+const TAR_CHARDEV: u8 = b'3';
+const TAR_BLOCKDEV: u8 = b'4';
+const TAR_DIR: u8 = b'5';
+const TAR_FIFO: u8 = b'6';
+const TAR_PAX: u8 = b'x';
+const TAR_PAX_GLOBAL: u8 = b'g';
+const TAR_GNU_LONG_NAME: u8 = b'L';
+const TAR_GNU_LONG_LINK: u8 = b'K';
+
+/// A tar entry, with its extended headers applied.
+#[derive(Default)]
+struct TarEntry {
+    path: String,
+    /// Target of a hard or symbolic link
+    link: String,
+    kind: u8,
+    perm: u32,
+    uid: u32,
+    gid: u32,
+    size: u64,
+    mtime: u32,
+    /// Major and minor number of a device
+    device: (u32, u32),
+    xattrs: Vec<(String, Vec<u8>)>,
+}
+
+impl TarEntry {
+    /// Check if the entry carries metadata for the next entry.
+    fn is_meta(&self) -> bool {
+        matches!(
+            self.kind,
+            TAR_PAX | TAR_PAX_GLOBAL | TAR_GNU_LONG_NAME | TAR_GNU_LONG_LINK
+        )
+    }
+}
+
+/// Populates a directory from a tar stream, acquired by
+/// `Ext4::tar_importer`.
+///
+/// Entries are created as their headers arrive and file data is written as
+/// it is fed, so the archive is never held in memory. Existing files are
+/// replaced, existing directories are kept and get the metadata of the
+/// archive. Parent directories missing from the archive are created.
+pub struct TarImporter<'a> {
+    fs: &'a Ext4,
+    /// Directory the archive is extracted in
+    root: InodeId,
+    /// Partially received header
+    header: Vec<u8>,
+    /// Entry whose data is being received
+    entry: Option<TarEntry>,
+    /// Inode created for `entry`, if any
+    inode: Option<InodeId>,
+    /// Data bytes of `entry` received so far
+    offset: u64,
+    /// Data of a metadata entry
+    meta: Vec<u8>,
+    /// Padding bytes still to skip
+    padding: usize,
+    /// Pax records and GNU long names for the next entry
+    pending: Vec<(String, Vec<u8>)>,
+    /// Directories and their modification times, set at the end since
+    /// adding entries changes them
+    dir_times: Vec<(InodeId, u32)>,
+    /// Zero blocks seen in a row, two end the archive
+    zero_blocks: usize,
+}
+
+impl Ext4 {
+    /// Create an importer extracting a tar stream into directory `root`.
+    pub fn tar_importer(&self, root: InodeId) -> TarImporter<'_> {
+        TarImporter {
+            fs: self,
+            root,
+            header: Vec::new(),
+            entry: None,
+            inode: None,
+            offset: 0,
+            meta: Vec::new(),
+            padding: 0,
+            pending: Vec::new(),
+            dir_times: Vec::new(),
+            zero_blocks: 0,
+        }
+    }
+
+    /// Extract a whole tar archive into directory `root`.
+    ///
+    /// # Error
+    ///
+    /// See `TarImporter::feed` and `TarImporter::finish`.
+    pub fn tar_import(&self, root: InodeId, archive: &[u8]) -> Result<()> {
+        let mut importer = self.tar_importer(root);
+        importer.feed(archive)?;
+        importer.finish()
+    }
+
+    /// Write the tree under directory `dir` as a tar stream, passing it to
+    /// `out` in pieces. Paths in the archive are relative to `dir`, which
+    /// itself is not archived. Inodes linked more than once are archived
+    /// once and then as hard links. Sockets are skipped.
+    ///
+    /// # Error
+    ///
+    /// * `ENOTDIR` - `dir` is not a directory
+    /// * `EACCES` - a directory or file cannot be read with the credentials
+    ///   set
+    /// * any error returned by `out`
+    pub fn tar_export<F>(&self, dir: InodeId, mut out: F) -> Result<()>
+    where
+        F: FnMut(&[u8]) -> Result<()>,
+    {
+        // First path of each inode linked more than once
+        let mut links = BTreeMap::new();
+        let mut res = Ok(());
+        self.walk(dir, WalkOptions::default(), |path, attr| {
+            res = self.tar_export_entry(path, attr, &mut links, &mut out);
+            if res.is_ok() {
+                WalkControl::Continue
+            } else {
+                WalkControl::Stop
+            }
+        })?;
+        res?;
+        // End of archive
+        out(&[0; TAR_BLOCK * 2])
+    }
+
+    /// Write the header and data of an entry to a tar stream.
+    fn tar_export_entry<F>(
+        &self,
+        path: &str,
+        attr: &FileAttr,
+        links: &mut BTreeMap<InodeId, String>,
+        out: &mut F,
+    ) -> Result<()>
+    where
+        F: FnMut(&[u8]) -> Result<()>,
+    {
+        let mut entry = TarEntry {
+            path: path.to_string(),
+            perm: u32::from(attr.perm),
+            uid: attr.uid,
+            gid: attr.gid,
+            mtime: attr.mtime,
+            ..Default::default()
+        };
+        entry.kind = match attr.ftype {
+            FileType::RegularFile => TAR_FILE,
+            FileType::Directory => TAR_DIR,
+            FileType::SymLink => TAR_SYMLINK,
+            FileType::CharacterDev => TAR_CHARDEV,
+            FileType::BlockDev => TAR_BLOCKDEV,
+            FileType::Fifo => TAR_FIFO,
+            _ => {
+                warn!("Skipping inode {} of type {:?}", attr.ino, attr.ftype);
+                return Ok(());
+            }
+        };
+        if attr.ftype != FileType::Directory && attr.links > 1 {
+            if let Some(first) = links.get(&attr.ino) {
+                entry.kind = TAR_HARD_LINK;
+                entry.link = first.clone();
+                return Self::tar_write_header(&entry, out);
+            }
+            links.insert(attr.ino, path.to_string());
+        }
+        match attr.ftype {
+            FileType::RegularFile => entry.size = attr.size,
+            FileType::Directory => entry.path.push('/'),
+            FileType::SymLink => {
+                entry.link = String::from_utf8_lossy(&self.readlink(attr.ino)?).into_owned();
+            }
+            FileType::CharacterDev | FileType::BlockDev => {
+                entry.device = self.read_inode(attr.ino).inode.device();
+            }
+            _ => {}
+        }
+        for name in self.listxattr(attr.ino)? {
+            let value = self.getxattr(attr.ino, &name)?;
+            entry.xattrs.push((name, value));
+        }
+        Self::tar_write_header(&entry, out)?;
+        if entry.size == 0 {
+            return Ok(());
+        }
+        // Write the data a few blocks at a time, padded to a whole tar block
+        let mut buf = vec![0; BLOCK_SIZE * 16];
+        let mut offset = 0;
+        while offset < entry.size {
+            let len = self.read(attr.ino, offset, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            out(&buf[..len])?;
+            offset += len as u64;
+        }
+        // Less than a tar block
+        let padding = (offset.next_multiple_of(TAR_BLOCK as u64) - offset) as usize;
+        out(&[0; TAR_BLOCK][..padding])
+    }
+
+    /// Write the headers of an entry, preceded by a pax header for the
+    /// fields that do not fit in a ustar header.
+    fn tar_write_header<F>(entry: &TarEntry, out: &mut F) -> Result<()>
+    where
+        F: FnMut(&[u8]) -> Result<()>,
+    {
+        let mut pax = Vec::new();
+        let (prefix, name) = match split_ustar_path(&entry.path) {
+            Some(split) => split,
+            None => {
+                pax_record(&mut pax, "path", entry.path.as_bytes());
+                ("", truncate(&entry.path, 100))
+            }
+        };
+        if entry.link.len() > 100 {
+            pax_record(&mut pax, "linkpath", entry.link.as_bytes());
+        }
+        // The largest values of 7 and 11 digit octal fields
+        let max7 = 0o7777777;
+        let max11 = 0o77777777777;
+        if entry.uid > max7 {
+            pax_record(&mut pax, "uid", entry.uid.to_string().as_bytes());
+        }
+        if entry.gid > max7 {
+            pax_record(&mut pax, "gid", entry.gid.to_string().as_bytes());
+        }
+        if entry.size > max11 {
+            pax_record(&mut pax, "size", entry.size.to_string().as_bytes());
+        }
+        for (name, value) in &entry.xattrs {
+            pax_record(&mut pax, &format!("{}{}", PAX_XATTR, name), value);
+        }
+        if !pax.is_empty() {
+            let pax_entry = TarEntry {
+                path: "PaxHeader".to_string(),
+                kind: TAR_PAX,
+                perm: 0o644,
+                size: pax.len() as u64,
+                ..Default::default()
+            };
+            out(&ustar_header(&pax_entry, "", "PaxHeader"))?;
+            let padding = pax.len().next_multiple_of(TAR_BLOCK) - pax.len();
+            pax.resize(pax.len() + padding, 0);
+            out(&pax)?;
+        }
+        out(&ustar_header(entry, prefix, name))
+    }
+}
+
+impl TarImporter<'_> {
+    /// Feed the next piece of the tar stream.
+    ///
+    /// # Error
+    ///
+    /// * `EINVAL` - the stream is not a valid tar archive, or a path in it
+    ///   contains a `..` component
+    /// * `EEXIST` - a non-directory entry replaces a directory
+    /// * any error of the operations creating the entries
+    pub fn feed(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() && self.zero_blocks < 2 {
+            if self.padding > 0 {
+                let len = min(self.padding, data.len());
+                self.padding -= len;
+                data = &data[len..];
+            } else if let Some(size) = self.entry.as_ref().map(|entry| entry.size) {
+                let len = min(size - self.offset, data.len() as u64) as usize;
+                self.entry_data(&data[..len])?;
+                self.offset += len as u64;
+                data = &data[len..];
+                if self.offset == size {
+                    self.end_entry()?;
+                }
+            } else {
+                let len = min(TAR_BLOCK - self.header.len(), data.len());
+                self.header.extend_from_slice(&data[..len]);
+                data = &data[len..];
+                if self.header.len() == TAR_BLOCK {
+                    let header = mem::take(&mut self.header);
+                    self.start_entry(&header)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// End the import, setting the modification times of the extracted
+    /// directories. The end-of-archive blocks may be missing.
+    ///
+    /// # Error
+    ///
+    /// `EINVAL` - the stream ends inside an entry
+    pub fn finish(self) -> Result<()> {
+        if self.zero_blocks < 2 && (self.entry.is_some() || !self.header.is_empty()) {
+            return_error!(ErrCode::EINVAL, "Truncated tar archive");
+        }
+        for &(dir, mtime) in self.dir_times.iter().rev() {
+            self.fs
+                .setattr(dir, None, None, None, None, None, Some(mtime), None, None)?;
+        }
+        Ok(())
+    }
+
+    /// Parse a header and create its entry.
+    fn start_entry(&mut self, header: &[u8]) -> Result<()> {
+        if header.iter().all(|&b| b == 0) {
+            self.zero_blocks += 1;
+            return Ok(());
+        }
+        self.zero_blocks = 0;
+        let mut entry = parse_header(header)?;
+        if !entry.is_meta() {
+            // Apply the extended headers of the previous entries
+            for (key, value) in mem::take(&mut self.pending) {
+                apply_pax_record(&mut entry, &key, value)?;
+            }
+            self.inode = self.create_entry(&entry)?;
+        }
+        self.offset = 0;
+        let size = entry.size;
+        self.entry = Some(entry);
+        if size == 0 {
+            self.end_entry()?;
+        }
+        Ok(())
+    }
+
+    /// Handle a piece of the data of the current entry.
+    fn entry_data(&mut self, data: &[u8]) -> Result<()> {
+        let entry = self.entry.as_ref().unwrap();
+        if entry.is_meta() {
+            self.meta.extend_from_slice(data);
+        } else if entry.kind == TAR_FILE {
+            if let Some(file) = self.inode {
+                self.fs.write(file, self.offset, data)?;
+            }
+        }
+        // The data of other entries is ignored
+        Ok(())
+    }
+
+    /// Finish the current entry, once all its data has been received.
+    fn end_entry(&mut self) -> Result<()> {
+        let entry = self.entry.take().unwrap();
+        let meta = mem::take(&mut self.meta);
+        // Less than a tar block, skipped after the data
+        self.padding = (entry.size.next_multiple_of(TAR_BLOCK as u64) - entry.size) as usize;
+        match entry.kind {
+            TAR_PAX => self.pending.extend(parse_pax(&meta)?),
+            TAR_GNU_LONG_NAME | TAR_GNU_LONG_LINK => {
+                let key = if entry.kind == TAR_GNU_LONG_NAME {
+                    "path"
+                } else {
+                    "linkpath"
+                };
+                let end = meta.iter().position(|&b| b == 0).unwrap_or(meta.len());
+                self.pending.push((key.to_string(), meta[..end].to_vec()));
+            }
+            _ => {
+                if let Some(inode) = self.inode.take() {
+                    self.set_metadata(inode, &entry)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Create the object of an entry, return its inode if its metadata is
+    /// to be set.
+    fn create_entry(&mut self, entry: &TarEntry) -> Result<Option<InodeId>> {
+        let fs = self.fs;
+        let mut components = Vec::new();
+        for name in entry.path.split('/') {
+            match name {
+                "" | "." => {}
+                ".." => {
+                    return_error!(ErrCode::EINVAL, "Tar entry {} escapes", entry.path);
+                }
+                name => components.push(name),
+            }
+        }
+        let Some(name) = components.pop() else {
+            // The extraction directory itself
+            if entry.kind != TAR_DIR {
+                return_error!(
+                    ErrCode::EINVAL,
+                    "Tar entry {} is not a directory",
+                    entry.path
+                );
+            }
+            return Ok(Some(self.root));
+        };
+        // Find or create the parent directory
+        let mut parent = self.root;
+        for dir in components {
+            parent = match fs.lookup(parent, dir) {
+                Ok(id) => id,
+                Err(e) if e.code() == ErrCode::ENOENT => {
+                    let mode = InodeMode::DIRECTORY | InodeMode::from_bits_retain(0o755);
+                    fs.mkdir(parent, dir, mode)?
+                }
+                Err(e) => return Err(e),
+            };
+        }
+        // Replace an existing non-directory
+        match fs.lookup(parent, name) {
+            Ok(id) if fs.getattr(id)?.ftype == FileType::Directory => {
+                if entry.kind == TAR_DIR {
+                    return Ok(Some(id));
+                }
+                return_error!(ErrCode::EEXIST, "Directory {} exists", entry.path);
+            }
+            Ok(_) => fs.unlink(parent, name)?,
+            Err(e) if e.code() == ErrCode::ENOENT => {}
+            Err(e) => return Err(e),
+        }
+        let perm = InodeMode::from(entry.perm).perm();
+        let id = match entry.kind {
+            TAR_FILE | b'7' => {
+                // Writable until the data is written, the mode is set after
+                let mode = InodeMode::FILE | perm | InodeMode::USER_WRITE;
+                fs.create(parent, name, mode)?
+            }
+            TAR_DIR => fs.mkdir(parent, name, InodeMode::DIRECTORY | perm)?,
+            TAR_HARD_LINK => {
+                let target = fs.generic_lookup(self.root, &entry.link)?;
+                fs.link(target, parent, name)?;
+                return Ok(None);
+            }
+            TAR_SYMLINK => fs.symlink(parent, name, &entry.link)?,
+            TAR_CHARDEV | TAR_BLOCKDEV | TAR_FIFO => {
+                let ftype = match entry.kind {
+                    TAR_CHARDEV => FileType::CharacterDev,
+                    TAR_BLOCKDEV => FileType::BlockDev,
+                    _ => FileType::Fifo,
+                };
+                let id = fs.create(parent, name, InodeMode::from_type_and_perm(ftype, perm))?;
+                if ftype != FileType::Fifo {
+                    let mut inode = fs.read_inode(id);
+                    inode.inode.set_device(entry.device.0, entry.device.1);
+                    fs.write_inode(&mut inode);
+                }
+                id
+            }
+            kind => {
+                warn!(
+                    "Skipping tar entry {} of type {:?}",
+                    entry.path, kind as char
+                );
+                return Ok(None);
+            }
+        };
+        Ok(Some(id))
+    }
+
+    /// Set the owner, mode, modification time and extended attributes of
+    /// an extracted inode.
+    fn set_metadata(&mut self, inode: InodeId, entry: &TarEntry) -> Result<()> {
+        let fs = self.fs;
+        for (name, value) in &entry.xattrs {
+            fs.setxattr(inode, name, value)?;
+        }
+        let attr = fs.getattr(inode)?;
+        // Symlinks are always 0777
+        let perm = InodeMode::from(entry.perm);
+        let mode = (attr.ftype != FileType::SymLink)
+            .then(|| InodeMode::from_type_and_perm(attr.ftype, perm));
+        let mtime = (attr.ftype != FileType::Directory).then_some(entry.mtime);
+        fs.setattr(
+            inode,
+            mode,
+            Some(entry.uid),
+            Some(entry.gid),
+            None,
+            None,
+            mtime,
+            None,
+            None,
+        )?;
+        if attr.ftype == FileType::Directory {
+            self.dir_times.push((inode, entry.mtime));
+        }
+        Ok(())
+    }
+}
+
+/// Parse a ustar header, which may also be a GNU or v7 tar header.
+fn parse_header(header: &[u8]) -> Result<TarEntry> {
+    // The checksum is computed with the checksum field as spaces
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| (if (148..156).contains(&i) { b' ' } else { b }) as u64)
+        .sum();
+    if parse_number(&header[148..156])? != sum {
+        return_error!(ErrCode::EINVAL, "Tar header checksum mismatch");
+    }
+    let mut path = c_str(&header[0..100]);
+    // Only ustar headers have a path prefix, GNU headers use it otherwise
+    if &header[257..263] == b"ustar\0" {
+        let prefix = c_str(&header[345..500]);
+        if !prefix.is_empty() {
+            path = format!("{}/{}", prefix, path);
+        }
+    }
+    let kind = header[156];
+    let mut entry = TarEntry {
+        path,
+        link: c_str(&header[157..257]),
+        kind: if kind == 0 { TAR_FILE } else { kind },
+        perm: parse_number(&header[100..108])? as u32,
+        uid: parse_number(&header[108..116])? as u32,
+        gid: parse_number(&header[116..124])? as u32,
+        size: parse_number(&header[124..136])?,
+        mtime: parse_number(&header[136..148])? as u32,
+        ..Default::default()
+    };
+    if matches!(entry.kind, TAR_CHARDEV | TAR_BLOCKDEV) {
+        entry.device = (
+            parse_number(&header[329..337])? as u32,
+            parse_number(&header[337..345])? as u32,
+        );
+    }
+    // Links have no data, whatever their size field says
+    if matches!(entry.kind, TAR_HARD_LINK | TAR_SYMLINK) {
+        entry.size = 0;
+    }
+    Ok(entry)
+}
+
+/// Parse a numeric header field: octal, or base-256 as GNU tar writes
+/// large numbers.
+fn parse_number(field: &[u8]) -> Result<u64> {
+    if field[0] & 0x80 != 0 {
+        let value = field[1..]
+            .iter()
+            .fold((field[0] & 0x7f) as u64, |acc, &b| (acc << 8) | b as u64);
+        return Ok(value);
+    }
+    let digits = field
+        .iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| b != 0 && b != b' ');
+    let mut value = 0u64;
+    for &b in digits {
+        if !(b'0'..=b'7').contains(&b) {
+            return_error!(ErrCode::EINVAL, "Invalid tar header number");
+        }
+        value = (value << 3) | (b - b'0') as u64;
+    }
+    Ok(value)
+}
+
+/// Read a NUL-terminated header string.
+fn c_str(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Parse the records of a pax extended header, `"<len> <key>=<value>\n"`.
+fn parse_pax(mut data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut records = Vec::new();
+    while !data.is_empty() {
+        let record = data.iter().position(|&b| b == b' ').and_then(|space| {
+            let len: usize = core::str::from_utf8(&data[..space]).ok()?.parse().ok()?;
+            // The length counts the whole record, newline included
+            let record = data.get(..len)?.strip_suffix(b"\n")?.get(space + 1..)?;
+            let eq = record.iter().position(|&b| b == b'=')?;
+            let key = core::str::from_utf8(&record[..eq]).ok()?;
+            Some((data.get(len..)?, key.to_string(), record[eq + 1..].to_vec()))
+        });
+        let Some((rest, key, value)) = record else {
+            return_error!(ErrCode::EINVAL, "Invalid pax record");
+        };
+        records.push((key, value));
+        data = rest;
+    }
+    Ok(records)
+}
+
+/// Apply a pax record to the entry it precedes.
+fn apply_pax_record(entry: &mut TarEntry, key: &str, value: Vec<u8>) -> Result<()> {
+    let number = |value: &[u8]| -> Result<u64> {
+        // Times may have a fraction, which is dropped
+        let text = String::from_utf8_lossy(value);
+        let int = text.split('.').next().unwrap_or("");
+        int.parse()
+            .map_err(|_| format_error!(ErrCode::EINVAL, "Invalid pax number {}", text))
+    };
+    match key {
+        "path" => entry.path = String::from_utf8_lossy(&value).into_owned(),
+        "linkpath" => entry.link = String::from_utf8_lossy(&value).into_owned(),
+        "uid" => entry.uid = number(&value)? as u32,
+        "gid" => entry.gid = number(&value)? as u32,
+        "size" if !matches!(entry.kind, TAR_HARD_LINK | TAR_SYMLINK) => {
+            entry.size = number(&value)?
+        }
+        "mtime" => entry.mtime = number(&value)? as u32,
+        key => {
+            if let Some(name) = key.strip_prefix(PAX_XATTR) {
+                entry.xattrs.push((name.to_string(), value));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Append a pax record, whose length field counts itself.
+fn pax_record(buf: &mut Vec<u8>, key: &str, value: &[u8]) {
+    // " key=value\n"
+    let body = key.len() + value.len() + 3;
+    let mut len = body;
+    loop {
+        let total = body + len.to_string().len();
+        if total == len {
+            break;
+        }
+        len = total;
+    }
+    buf.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+    buf.extend_from_slice(value);
+    buf.push(b'\n');
+}
+
+/// Split a path into the prefix and name fields of a ustar header, or
+/// return `None` if it does not fit.
+fn split_ustar_path(path: &str) -> Option<(&str, &str)> {
+    if path.len() <= 100 {
+        return Some(("", path));
+    }
+    // Split at a '/', keeping the name within 100 bytes
+    path.match_indices('/')
+        .map(|(i, _)| i)
+        .find(|&i| i <= 155 && path.len() - i - 1 <= 100 && i > 0)
+        .map(|i| (&path[..i], &path[i + 1..]))
+}
+
+/// Truncate a string to at most `len` bytes, at a character boundary.
+fn truncate(s: &str, len: usize) -> &str {
+    let mut end = min(len, s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Build a ustar header.
+fn ustar_header(entry: &TarEntry, prefix: &str, name: &str) -> [u8; TAR_BLOCK] {
+    let mut header = [0u8; TAR_BLOCK];
+    let put_str = |header: &mut [u8; TAR_BLOCK], start: usize, s: &str| {
+        header[start..start + s.len()].copy_from_slice(s.as_bytes());
+    };
+    // Octal digits, then a NUL
+    let put_octal = |header: &mut [u8; TAR_BLOCK], start: usize, width: usize, value: u64| {
+        let digits = format!("{:0width$o}", value, width = width - 1);
+        put_str(header, start, &digits[digits.len() - (width - 1)..]);
+    };
+    put_str(&mut header, 0, name);
+    put_octal(&mut header, 100, 8, entry.perm as u64 & 0o7777);
+    put_octal(&mut header, 108, 8, min(entry.uid, 0o7777777) as u64);
+    put_octal(&mut header, 116, 8, min(entry.gid, 0o7777777) as u64);
+    put_octal(&mut header, 124, 12, min(entry.size, 0o77777777777));
+    put_octal(&mut header, 136, 12, entry.mtime as u64);
+    header[156] = entry.kind;
+    put_str(&mut header, 157, truncate(&entry.link, 100));
+    put_str(&mut header, 257, "ustar\0");
+    put_str(&mut header, 263, "00");
+    if matches!(entry.kind, TAR_CHARDEV | TAR_BLOCKDEV) {
+        put_octal(&mut header, 329, 8, entry.device.0 as u64);
+        put_octal(&mut header, 337, 8, entry.device.1 as u64);
+    }
+    put_str(&mut header, 345, prefix);
+    // The checksum is computed with the checksum field as spaces
+    header[148..156].fill(b' ');
+    let sum: u64 = header.iter().map(|&b| b as u64).sum();
+    put_octal(&mut header, 148, 7, sum);
+    header
+}
@@ -0,0 +1,79 @@
+//! Batched discard of free space.
+//!
+//! Instead of discarding blocks as they are freed (see `Ext4::set_discard`),
+//! an embedder may call `Ext4::trim` periodically, like running `fstrim`
+//! on Linux, to discard all free ranges at once.
+
+use super::Ext4;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+use crate::return_error;
+
+impl Ext4 {
+    /// Discard the free blocks in `len` blocks starting from `start`, like
+    /// the `FITRIM` ioctl. Free ranges shorter than `min_len` blocks are
+    /// skipped. Discards are issued regardless of `set_discard`.
+    ///
+    /// # Return
+    ///
+    /// `Ok(u64)` - the number of blocks discarded
+    ///
+    /// # Error
+    ///
+    /// `EINVAL` if `start` is beyond the end of the filesystem.
+    pub fn trim(&self, start: PBlockId, len: u64, min_len: u64) -> Result<u64> {
+        let sb = self.read_super_block();
+        if start >= sb.block_count() {
+            return_error!(
+                ErrCode::EINVAL,
+                "Trim start {} beyond {} blocks",
+                start,
+                sb.block_count()
+            );
+        }
+        let start = start.max(sb.first_data_block() as PBlockId);
+        let end = start.saturating_add(len).min(sb.block_count());
+        let min_len = min_len.max(1);
+        let blocks_per_group = sb.blocks_per_group() as PBlockId;
+
+        let mut trimmed = 0;
+        let mut pblock = start;
+        while pblock < end {
+            let bgid =
+                ((pblock - sb.first_data_block() as PBlockId) / blocks_per_group) as BlockGroupId;
+            let first = sb.group_first_block(bgid);
+            let group_end = (first + sb.blocks_in_group(bgid) as PBlockId).min(end);
+            let mut bg = self.read_block_group(bgid);
+            if bg.desc.get_free_blocks_count() == 0 {
+                pblock = group_end;
+                continue;
+            }
+            // Uninitialized bitmaps are built in memory, not written back
+            let mut bitmap_block = self.load_block_bitmap(&sb, &mut bg);
+            let bitmap = Bitmap::new(&mut bitmap_block.data, blocks_per_group as usize);
+            // Discard each free range in the group
+            let group_len = (group_end - first) as usize;
+            while pblock < group_end {
+                let Some(bit) = bitmap.first_clear_bit((pblock - first) as usize, group_len) else {
+                    pblock = group_end;
+                    break;
+                };
+                let run_start = first + bit as PBlockId;
+                let run_len = bitmap.clear_run_len(bit, group_len) as PBlockId;
+                pblock = run_start + run_len;
+                if run_len >= min_len {
+                    self.discard_blocks(run_start, run_len);
+                    trimmed += run_len;
+                }
+            }
+        }
+        log_alloc!(
+            debug,
+            "Trim {}..{}: {} blocks discarded",
+            start,
+            end,
+            trimmed
+        );
+        Ok(trimmed)
+    }
+}
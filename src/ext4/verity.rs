@@ -0,0 +1,331 @@
+//! Awareness of fs-verity files (the `verity` feature).
+//!
+//! The contents of a verity file are sealed. A Merkle tree of the hashes of
+//! its data blocks is stored past the end of file, from the first 64 KiB
+//! boundary after it, followed by the verity descriptor that holds the root
+//! hash. The size of the descriptor is in the last 4 bytes of the last
+//! mapped block.
+//!
+//! Verity files cannot be written or resized, which would also clobber the
+//! tree. Their data is verified against the tree as it is read if a
+//! `VerityHasher` is set, and read as it is otherwise.
+
+use super::crypt::Decryptor;
+use super::Ext4;
+use crate::constants::*;
+use crate::ext4_defs::*;
+use crate::format_error;
+use crate::prelude::*;
+use crate::return_error;
+use core::cmp::min;
+
+/// Alignment of the start of the Merkle tree past the end of file.
+const VERITY_METADATA_ALIGN: u64 = 65536;
+/// Size of the fixed part of the verity descriptor.
+const VERITY_DESCRIPTOR_SIZE: usize = 256;
+/// Maximum salt size.
+const VERITY_MAX_SALT_SIZE: usize = 32;
+/// Hash algorithm SHA-256.
+const VERITY_HASH_SHA256: u8 = 1;
+/// Hash algorithm SHA-512.
+const VERITY_HASH_SHA512: u8 = 2;
+
+/// The verity descriptor of a file, acquired by `Ext4::verity_descriptor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerityDescriptor {
+    /// Hash algorithm of the Merkle tree, 1 for SHA-256 or 2 for SHA-512
+    pub hash_algorithm: u8,
+    /// Size in bytes of the hashed data blocks and of the tree blocks
+    pub block_size: usize,
+    /// Size of the data the tree is built over, the file size
+    pub data_size: u64,
+    /// Hash of the root block of the tree, zeros for an empty file
+    pub root_hash: Vec<u8>,
+    /// Salt prepended to each hashed block
+    pub salt: Vec<u8>,
+}
+
+impl VerityDescriptor {
+    /// Parse the fixed part of a verity descriptor, `None` if it is
+    /// malformed.
+    fn from_bytes(raw: &[u8]) -> Option<Self> {
+        if raw.len() < VERITY_DESCRIPTOR_SIZE || raw[0] != 1 {
+            return None;
+        }
+        let hash_algorithm = raw[1];
+        let digest_size = digest_size(hash_algorithm)?;
+        let log_blocksize = raw[2];
+        let salt_size = raw[3] as usize;
+        if log_blocksize >= 32 || salt_size > VERITY_MAX_SALT_SIZE {
+            return None;
+        }
+        Some(Self {
+            hash_algorithm,
+            block_size: 1 << log_blocksize,
+            data_size: u64::from_le_bytes(raw[8..16].try_into().unwrap()),
+            root_hash: raw[16..16 + digest_size].to_vec(),
+            salt: raw[80..80 + salt_size].to_vec(),
+        })
+    }
+}
+
+/// Size of the digests of a hash algorithm, `None` if it is unknown.
+fn digest_size(algorithm: u8) -> Option<usize> {
+    match algorithm {
+        VERITY_HASH_SHA256 => Some(32),
+        VERITY_HASH_SHA512 => Some(64),
+        _ => None,
+    }
+}
+
+/// Size of the input blocks of a hash algorithm, the salt is padded to it.
+fn hash_block_size(algorithm: u8) -> usize {
+    match algorithm {
+        VERITY_HASH_SHA512 => 128,
+        _ => 64,
+    }
+}
+
+/// Hashing for the verification of verity files, installed with
+/// `Ext4::set_verity_hasher`.
+pub trait VerityHasher: Send + Sync {
+    /// Hash `data` with `algorithm`, 1 for SHA-256 or 2 for SHA-512, and
+    /// return the digest.
+    ///
+    /// Fails with `ENOTSUP` if the algorithm is not supported.
+    fn hash(&self, algorithm: u8, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// The Merkle tree of a verity file and the hasher to verify it with.
+pub(super) struct Verifier {
+    desc: VerityDescriptor,
+    hasher: Arc<dyn VerityHasher>,
+    /// Salt padded to the hash block size, empty if there is no salt
+    padded_salt: Vec<u8>,
+    /// Number of hashes per tree block
+    hashes_per_block: u32,
+    /// Logical block of the first block of each level, the leaves first
+    level_start: Vec<LBlockId>,
+}
+
+impl Verifier {
+    /// Hash a data or tree block, preceded by the salt.
+    fn hash_block(&self, block: &[u8]) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.padded_salt.len() + block.len());
+        data.extend_from_slice(&self.padded_salt);
+        data.extend_from_slice(block);
+        self.hasher.hash(self.desc.hash_algorithm, &data)
+    }
+}
+
+impl Ext4 {
+    /// Set the hasher used to verify the data of verity files as it is
+    /// read. `None` removes it, verity files are then read unverified.
+    pub fn set_verity_hasher(&mut self, hasher: Option<Arc<dyn VerityHasher>>) {
+        self.verity = hasher;
+    }
+
+    /// Check if an inode is a verity file.
+    ///
+    /// # Error
+    ///
+    /// `EINVAL` if the inode is invalid (mode == 0).
+    pub fn is_verity(&self, inode: InodeId) -> Result<bool> {
+        let inode_ref = self.read_inode(inode);
+        if inode_ref.inode.mode().bits() == 0 {
+            return_error!(ErrCode::EINVAL, "Invalid inode {}", inode);
+        }
+        Ok(inode_ref.inode.is_verity())
+    }
+
+    /// Get the verity descriptor of a file, `None` if it is not a verity
+    /// file.
+    ///
+    /// # Error
+    ///
+    /// * `EINVAL` - the inode is invalid (mode == 0)
+    /// * `ENOKEY` - the file is also encrypted and cannot be decrypted
+    /// * `EFSCORRUPTED` - the file has no valid verity descriptor
+    pub fn verity_descriptor(&self, inode: InodeId) -> Result<Option<VerityDescriptor>> {
+        let inode_ref = self.read_inode(inode);
+        if inode_ref.inode.mode().bits() == 0 {
+            return_error!(ErrCode::EINVAL, "Invalid inode {}", inode);
+        }
+        if !inode_ref.inode.is_verity() {
+            return Ok(None);
+        }
+        let decryptor = self.decryptor(&inode_ref)?;
+        self.read_verity_descriptor(&inode_ref, decryptor.as_ref())
+            .map(Some)
+    }
+
+    /// Check that an inode is not a verity file before changing its
+    /// contents or size.
+    ///
+    /// # Error
+    ///
+    /// `EPERM` if the inode is a verity file.
+    pub(super) fn check_not_verity(inode: &InodeRef) -> Result<()> {
+        if inode.inode.is_verity() {
+            return_error!(
+                ErrCode::EPERM,
+                "Inode {} is a verity file and cannot be modified",
+                inode.id
+            );
+        }
+        Ok(())
+    }
+
+    /// Get the verifier of the data of a file, `None` if it is not a verity
+    /// file or no verity hasher is set.
+    ///
+    /// # Error
+    ///
+    /// * `ENOTSUP` - the tree block size is not `BLOCK_SIZE`
+    /// * `EFSCORRUPTED` - the file has no valid verity descriptor
+    pub(super) fn verifier(
+        &self,
+        file: &InodeRef,
+        decryptor: Option<&Decryptor>,
+    ) -> Result<Option<Verifier>> {
+        let Some(hasher) = &self.verity else {
+            return Ok(None);
+        };
+        if !file.inode.is_verity() {
+            return Ok(None);
+        }
+        let desc = self.read_verity_descriptor(file, decryptor)?;
+        if desc.block_size != BLOCK_SIZE {
+            return_error!(
+                ErrCode::ENOTSUP,
+                "Verity block size {} of inode {} is not supported",
+                desc.block_size,
+                file.id
+            );
+        }
+        let hashes_per_block = (BLOCK_SIZE / desc.root_hash.len()) as u32;
+        // Number of blocks of each level, the leaves first, up to the root
+        // block. There is at least one level.
+        let mut level_blocks = Vec::new();
+        let mut count = desc.data_size.div_ceil(BLOCK_SIZE as u64);
+        loop {
+            count = count.div_ceil(hashes_per_block as u64);
+            level_blocks.push(count as LBlockId);
+            if count <= 1 {
+                break;
+            }
+        }
+        // Levels are stored from the root down, past the end of file
+        let metadata_pos = desc.data_size.next_multiple_of(VERITY_METADATA_ALIGN);
+        let mut next = (metadata_pos / BLOCK_SIZE as u64) as LBlockId;
+        let mut level_start = vec![0; level_blocks.len()];
+        for (level, &blocks) in level_blocks.iter().enumerate().rev() {
+            level_start[level] = next;
+            next += blocks;
+        }
+        let padded_salt = if desc.salt.is_empty() {
+            Vec::new()
+        } else {
+            let mut salt = desc.salt.clone();
+            salt.resize(hash_block_size(desc.hash_algorithm), 0);
+            salt
+        };
+        Ok(Some(Verifier {
+            desc,
+            hasher: hasher.clone(),
+            padded_salt,
+            hashes_per_block,
+            level_start,
+        }))
+    }
+
+    /// Verify a data block of a verity file against its Merkle tree, from
+    /// the leaf level up to the root hash.
+    ///
+    /// # Error
+    ///
+    /// `EIO` if the data or the tree does not match.
+    pub(super) fn verity_verify(
+        &self,
+        file: &InodeRef,
+        verifier: &Verifier,
+        iblock: LBlockId,
+        block: &Block,
+        decryptor: Option<&Decryptor>,
+    ) -> Result<()> {
+        // The last block is hashed zero-padded past the end of file
+        let start = iblock as u64 * BLOCK_SIZE as u64;
+        let len = verifier.desc.data_size.saturating_sub(start);
+        let mut data = block.data.to_vec();
+        data[min(len, BLOCK_SIZE as u64) as usize..].fill(0);
+        let mut digest = verifier.hash_block(&data)?;
+        let mut index = iblock;
+        for &level_start in &verifier.level_start {
+            let hpb = verifier.hashes_per_block;
+            let tree_iblock = level_start + index / hpb;
+            let tree_block = self.read_file_block(file, tree_iblock, false, decryptor)?;
+            let offset = (index % hpb) as usize * digest.len();
+            if tree_block.data[offset..offset + digest.len()] != digest[..] {
+                return_error!(
+                    ErrCode::EIO,
+                    "Verity mismatch of block {} of inode {}",
+                    iblock,
+                    file.id
+                );
+            }
+            digest = verifier.hash_block(&tree_block.data)?;
+            index /= hpb;
+        }
+        if digest != verifier.desc.root_hash {
+            return_error!(
+                ErrCode::EIO,
+                "Verity root hash mismatch at block {} of inode {}",
+                iblock,
+                file.id
+            );
+        }
+        Ok(())
+    }
+
+    /// Read the verity descriptor of a verity file.
+    fn read_verity_descriptor(
+        &self,
+        file: &InodeRef,
+        decryptor: Option<&Decryptor>,
+    ) -> Result<VerityDescriptor> {
+        let corrupted = || {
+            let err = format_error!(
+                ErrCode::EFSCORRUPTED,
+                "Inode {} has no valid verity descriptor",
+                file.id
+            );
+            self.fs_error("read_verity_descriptor", file.id, 0, err)
+        };
+        // The descriptor size ends the last mapped block
+        let end = self.extent_end(file)?;
+        if end == 0 {
+            return Err(corrupted());
+        }
+        let last = self.read_file_block(file, end - 1, false, decryptor)?;
+        let desc_size = u32::from_le_bytes(last.data[BLOCK_SIZE - 4..].try_into().unwrap());
+        let desc_size_pos = end as u64 * BLOCK_SIZE as u64 - 4;
+        let metadata_pos = file.inode.size().next_multiple_of(VERITY_METADATA_ALIGN);
+        let Some(desc_pos) = desc_size_pos.checked_sub(desc_size as u64) else {
+            return Err(corrupted());
+        };
+        let desc_pos = desc_pos / BLOCK_SIZE as u64 * BLOCK_SIZE as u64;
+        if (desc_size as usize) < VERITY_DESCRIPTOR_SIZE || desc_pos < metadata_pos {
+            return Err(corrupted());
+        }
+        let block = self.read_file_block(
+            file,
+            (desc_pos / BLOCK_SIZE as u64) as LBlockId,
+            false,
+            decryptor,
+        )?;
+        match VerityDescriptor::from_bytes(&block.data[..VERITY_DESCRIPTOR_SIZE]) {
+            Some(desc) if desc.data_size == file.inode.size() => Ok(desc),
+            _ => Err(corrupted()),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Directory tree traversal.
+//!
+//! `Ext4::walk` visits a directory tree depth-first with an explicit stack
+//! rather than recursion, so deep trees cannot overflow the small stacks of
+//! `no_std` environments.
+
+use super::Ext4;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+
+/// Options of `Ext4::walk`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WalkOptions {
+    /// Whether the `.` and `..` entries of each directory are reported.
+    /// They are never descended into.
+    pub include_dots: bool,
+    /// Maximum depth to descend to, `None` for no limit. Entries of the
+    /// root directory have depth 1.
+    pub max_depth: Option<usize>,
+}
+
+/// What `Ext4::walk` does after visiting an entry, as returned by the
+/// visitor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkControl {
+    /// Go on, descending into the entry if it is a directory
+    Continue,
+    /// Go on, without descending into the entry
+    SkipDir,
+    /// Stop the walk
+    Stop,
+}
+
+/// An entry waiting to be visited by `Ext4::walk`.
+struct WalkEntry {
+    path: String,
+    attr: FileAttr,
+    depth: usize,
+    /// Whether the entry is `.` or `..`
+    is_dot: bool,
+}
+
+impl Ext4 {
+    /// Walk the directory tree under `root` depth-first, calling `visit`
+    /// with the path relative to `root` and the attributes of each entry.
+    /// Entries of a directory are visited in directory order, each
+    /// directory before its entries.
+    ///
+    /// A directory reached again, which only happens on a corrupted
+    /// filesystem, is reported but not descended into a second time.
+    ///
+    /// # Error
+    ///
+    /// * `ENOTDIR` - `root` is not a directory
+    /// * `EACCES` - a directory cannot be listed with the credentials set
+    pub fn walk<F>(&self, root: InodeId, options: WalkOptions, mut visit: F) -> Result<()>
+    where
+        F: FnMut(&str, &FileAttr) -> WalkControl,
+    {
+        let mut visited = BTreeSet::new();
+        visited.insert(root);
+        // Entries to visit, the next one on top
+        let mut stack = Vec::new();
+        self.walk_push(&mut stack, root, "", 1, options)?;
+        while let Some(entry) = stack.pop() {
+            let control = visit(&entry.path, &entry.attr);
+            if control == WalkControl::Stop {
+                break;
+            }
+            if control == WalkControl::Continue
+                && entry.attr.ftype == FileType::Directory
+                && !entry.is_dot
+                && options.max_depth.is_none_or(|max| entry.depth < max)
+                && visited.insert(entry.attr.ino)
+            {
+                self.walk_push(
+                    &mut stack,
+                    entry.attr.ino,
+                    &entry.path,
+                    entry.depth + 1,
+                    options,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Push the entries of a directory on the walk stack, so that they are
+    /// popped in directory order.
+    fn walk_push(
+        &self,
+        stack: &mut Vec<WalkEntry>,
+        dir: InodeId,
+        dir_path: &str,
+        depth: usize,
+        options: WalkOptions,
+    ) -> Result<()> {
+        let entries = self.listdir_attrs(dir)?;
+        for (entry, attr) in entries.into_iter().rev() {
+            let name = entry.name();
+            let is_dot = name == "." || name == "..";
+            if is_dot && !options.include_dots {
+                continue;
+            }
+            let path = if dir_path.is_empty() {
+                name
+            } else {
+                format!("{}/{}", dir_path, name)
+            };
+            stack.push(WalkEntry {
+                path,
+                attr,
+                depth,
+                is_dot,
+            });
+        }
+        Ok(())
+    }
+}
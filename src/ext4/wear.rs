@@ -0,0 +1,67 @@
+//! Lifetime write accounting.
+//!
+//! Blocks written to the device are counted in memory and folded into the
+//! superblock `s_kbytes_written` field when the filesystem is flushed. If a
+//! wall clock is set, the superblock write time is updated as well. These
+//! are the standard ext4 fields, so tools such as `dumpe2fs` report them.
+
+use super::Ext4;
+use crate::constants::*;
+use crate::prelude::*;
+use core::sync::atomic::Ordering;
+
+/// Flash wear statistics of the filesystem.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WearStats {
+    /// Kilobytes written over the lifetime of the filesystem, including
+    /// writes not yet recorded in the superblock
+    pub kbytes_written: u64,
+    /// Last time the superblock was updated by a flush, in seconds since
+    /// the epoch. 0 if never recorded.
+    pub write_time: u64,
+}
+
+impl Ext4 {
+    /// Get the flash wear statistics.
+    pub fn wear_stats(&self) -> WearStats {
+        let sb = self.read_super_block();
+        let pending = self.pending_written_blocks.load(Ordering::Relaxed);
+        WearStats {
+            kbytes_written: sb.kbytes_written() + blocks_to_kbytes(pending),
+            write_time: sb.write_time(),
+        }
+    }
+
+    /// Set the wall clock used to record the superblock write time. The
+    /// clock returns seconds since the epoch.
+    ///
+    /// The mount time is not known when the filesystem is loaded, so it is
+    /// recorded now.
+    pub fn set_wall_clock(&mut self, clock: fn() -> u64) {
+        self.wall_clock = Some(clock);
+        let mut sb = self.read_super_block();
+        sb.set_mount_time(clock());
+        self.write_super_block(&sb);
+    }
+
+    /// Fold the blocks written since the last call into the superblock.
+    pub(super) fn record_written_blocks(&self) {
+        #[cfg(feature = "block_cache")]
+        self.pending_written_blocks
+            .fetch_add(self.block_cache.take_written(), Ordering::Relaxed);
+        let written = self.pending_written_blocks.swap(0, Ordering::Relaxed);
+        if written == 0 {
+            return;
+        }
+        let mut sb = self.read_super_block();
+        sb.set_kbytes_written(sb.kbytes_written() + blocks_to_kbytes(written));
+        if let Some(clock) = self.wall_clock {
+            sb.set_write_time(clock());
+        }
+        self.write_super_block(&sb);
+    }
+}
+
+fn blocks_to_kbytes(blocks: u64) -> u64 {
+    blocks * (BLOCK_SIZE / 1024) as u64
+}
@@ -1,30 +1,52 @@
+//! Block and inode bitmaps.
+//!
+//! Bit `i` of the bitmap is bit `i % 8` of byte `i / 8`. Scans read the
+//! bitmap 64 bits at a time, since they are the hot loop of allocation.
+
+/// A bitmap over a byte slice, with bits numbered from 0.
 pub struct Bitmap<'a>(&'a mut [u8]);
 
 impl<'a> Bitmap<'a> {
+    /// Wrap the first `nbits` bits of `bmap`, rounded up to whole bytes.
     pub fn new(bmap: &'a mut [u8], nbits: usize) -> Self {
         Self(&mut bmap[..(nbits + 7) / 8])
     }
 
+    /// The underlying bytes.
     pub fn as_bytes(&self) -> &[u8] {
         self.0
     }
 
+    /// Check if a bit is clear.
     pub fn is_bit_clear(&self, bit: usize) -> bool {
         self.0[bit / 8] & (1 << (bit % 8)) == 0
     }
 
+    /// Set a bit.
     pub fn set_bit(&mut self, bit: usize) {
         self.0[bit / 8] |= 1 << (bit % 8);
     }
 
+    /// Clear a bit.
     pub fn clear_bit(&mut self, bit: usize) {
         self.0[bit / 8] &= !(1 << (bit % 8));
     }
 
+    /// Set the bits in the range `[start, start + len)`.
+    pub fn set_bits(&mut self, start: usize, len: usize) {
+        for bit in start..start + len {
+            self.set_bit(bit);
+        }
+    }
+
     /// Find the first clear bit in the range `[start, end)`
     pub fn first_clear_bit(&self, start: usize, end: usize) -> Option<usize> {
-        let end = core::cmp::min(end, self.0.len() * 8);
-        (start..end).find(|&i| self.is_bit_clear(i))
+        self.find_bit(start, end, false)
+    }
+
+    /// Find the first set bit in the range `[start, end)`
+    pub fn first_set_bit(&self, start: usize, end: usize) -> Option<usize> {
+        self.find_bit(start, end, true)
     }
 
     /// Find the first clear bit in the range `[start, end)` and set it if found
@@ -33,4 +55,61 @@ impl<'a> Bitmap<'a> {
             self.set_bit(bit);
         })
     }
+
+    /// The number of consecutive clear bits from `start` on, not counting
+    /// bits from `end` on.
+    pub fn clear_run_len(&self, start: usize, end: usize) -> usize {
+        let end = end.min(self.0.len() * 8);
+        if start >= end {
+            return 0;
+        }
+        self.first_set_bit(start, end).unwrap_or(end) - start
+    }
+
+    /// Find the first run of at least `len` clear bits in the range
+    /// `[start, end)`, return the first bit of the run.
+    pub fn find_clear_run(&self, start: usize, end: usize, len: usize) -> Option<usize> {
+        let mut pos = start;
+        loop {
+            let run_start = self.first_clear_bit(pos, end)?;
+            let run_len = self.clear_run_len(run_start, end);
+            if run_len >= len {
+                return Some(run_start);
+            }
+            pos = run_start + run_len;
+        }
+    }
+
+    /// Find the first bit equal to `set` in the range `[start, end)`,
+    /// scanning a word at a time.
+    fn find_bit(&self, start: usize, end: usize, set: bool) -> Option<usize> {
+        let end = end.min(self.0.len() * 8);
+        let mut bit = start;
+        while bit < end {
+            let index = bit / 64;
+            // Bits equal to `set` become ones, bits before `bit` are masked
+            let word = if set {
+                self.word(index)
+            } else {
+                !self.word(index)
+            };
+            let word = word & (u64::MAX << (bit % 64));
+            if word != 0 {
+                let found = index * 64 + word.trailing_zeros() as usize;
+                return (found < end).then_some(found);
+            }
+            bit = (index + 1) * 64;
+        }
+        None
+    }
+
+    /// Load the `index`-th 64-bit word. Bytes past the end of the bitmap
+    /// read as zero, callers bound the result by the bitmap length.
+    fn word(&self, index: usize) -> u64 {
+        let start = index * 8;
+        let end = (start + 8).min(self.0.len());
+        let mut bytes = [0u8; 8];
+        bytes[..end - start].copy_from_slice(&self.0[start..end]);
+        u64::from_le_bytes(bytes)
+    }
 }
@@ -8,19 +8,47 @@ use core::any::Any;
 ///
 /// This trait is unsafe because it allows arbitrary memory interpretation.
 /// Implementor should guarantee the object is saved in the way defined by
-/// functions `from_bytes` and `to_bytes`.
+/// functions `from_bytes` and `to_bytes`, and that every bit pattern of
+/// `size_of::<Self>()` bytes is a valid `Self` (no enums, bools or references),
+/// with no padding bytes.
 pub unsafe trait AsBytes
 where
     Self: Sized,
 {
     /// Default implementation that deserializes the object from a byte array.
+    ///
+    /// Panics if `bytes` is shorter than `Self`. `bytes` need not be aligned.
     fn from_bytes(bytes: &[u8]) -> Self {
-        unsafe { core::ptr::read(bytes.as_ptr() as *const Self) }
+        let bytes = &bytes[..size_of::<Self>()];
+        // SAFETY: `bytes` covers `size_of::<Self>()` readable bytes, the read
+        // is unaligned, and the implementor guarantees any bit pattern is valid.
+        unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const Self) }
     }
     /// Default implementation that serializes the object to a byte array.
     fn to_bytes(&self) -> &[u8] {
+        // SAFETY: `self` is a live object of `size_of::<Self>()` bytes without padding.
         unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, size_of::<Self>()) }
     }
+    /// Deserialize the object from an on-disk record, which is shorter than
+    /// `Self` in older layouts, e.g. 128-byte inodes. Missing bytes read
+    /// as zero.
+    fn from_record(record: &[u8]) -> Self {
+        let len = record.len().min(size_of::<Self>());
+        // SAFETY: the implementor guarantees any bit pattern, all-zero
+        // included, is valid.
+        let mut value: Self = unsafe { mem::zeroed() };
+        // SAFETY: `len` bytes are readable from `record` and writable to `value`.
+        unsafe {
+            core::ptr::copy_nonoverlapping(record.as_ptr(), &mut value as *mut Self as *mut u8, len)
+        };
+        value
+    }
+    /// Serialize the object to an on-disk record. Bytes that do not fit in
+    /// the record are dropped.
+    fn to_record(&self, record: &mut [u8]) {
+        let len = record.len().min(size_of::<Self>());
+        record[..len].copy_from_slice(&self.to_bytes()[..len]);
+    }
 }
 
 /// Common data block descriptor.
@@ -60,6 +88,24 @@ impl Block {
         T::from_bytes(&self.data[offset..])
     }
 
+    /// Read a `size`-byte on-disk record at `offset` and interpret it as
+    /// `T`. See `AsBytes::from_record`.
+    pub fn read_record_as<T>(&self, offset: usize, size: usize) -> T
+    where
+        T: AsBytes,
+    {
+        T::from_record(&self.data[offset..offset + size])
+    }
+
+    /// Write `T` as a `size`-byte on-disk record at `offset`. See
+    /// `AsBytes::to_record`.
+    pub fn write_record_as<T>(&mut self, offset: usize, size: usize, value: &T)
+    where
+        T: AsBytes,
+    {
+        value.to_record(&mut self.data[offset..offset + size]);
+    }
+
     /// Write block data to `offset` with `size`.
     pub fn write_offset(&mut self, offset: usize, data: &[u8]) {
         self.data[offset..offset + data.len()].copy_from_slice(data);
@@ -78,6 +124,37 @@ impl Block {
 pub trait BlockDevice: Send + Sync + Any {
     /// Read a block from disk.
     fn read_block(&self, block_id: PBlockId) -> Block;
-    /// Write a block to disk.
+    /// Write a block to disk. The write may stay in a volatile device
+    /// cache until `flush` is called.
     fn write_block(&self, block: &Block);
+    /// Wait until all completed writes are on stable storage, e.g. issue a
+    /// cache flush to the disk. Acts as a write barrier: writes issued
+    /// after `flush` returns are never persisted before writes issued
+    /// before it. Does nothing by default, which is only correct for
+    /// devices without a volatile write cache.
+    fn flush(&self) {}
+    /// Write a block and wait until it is on stable storage. Defaults to
+    /// `write_block` followed by `flush`, devices supporting FUA writes
+    /// may override it.
+    fn write_block_sync(&self, block: &Block) {
+        self.write_block(block);
+        self.flush();
+    }
+    /// The number of blocks on the device, `None` if unknown. Used to check
+    /// that the device is large enough for the filesystem. Returns `None`
+    /// by default.
+    fn block_count(&self) -> Option<u64> {
+        None
+    }
+    /// Discard `count` blocks starting from `start` that are no longer in
+    /// use, e.g. issue TRIM to an SSD. Does nothing by default.
+    fn discard(&self, start: PBlockId, count: u64) {
+        let _ = (start, count);
+    }
+    /// Hint that `count` blocks starting from `start` are likely to be read
+    /// soon, so the device may fetch them in the background. Does nothing
+    /// by default.
+    fn readahead(&self, start: PBlockId, count: u64) {
+        let _ = (start, count);
+    }
 }
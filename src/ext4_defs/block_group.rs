@@ -8,9 +8,21 @@
 use super::crc::*;
 use super::AsBytes;
 use super::Bitmap;
-use crate::constants::*;
 use crate::prelude::*;
 
+bitflags! {
+    /// Block group flags, stored in `BlockGroupDesc.flags`.
+    #[derive(PartialEq, Eq, Debug, Clone, Copy)]
+    pub struct BlockGroupFlags: u16 {
+        /// Inode table and bitmap are not initialized.
+        const INODE_UNINIT = 0x1;
+        /// Block bitmap is not initialized.
+        const BLOCK_UNINIT = 0x2;
+        /// Inode table is zeroed.
+        const ITABLE_ZEROED = 0x4;
+    }
+}
+
 /// The Block Group Descriptor.
 ///
 /// Each block group on the filesystem has one of these descriptors associated with it.
@@ -19,8 +31,11 @@ use crate::prelude::*;
 /// filesystem with the 64bit feature enabled, the block group descriptor expands to
 /// at least the 64 bytes described below; the size is stored in the superblock.
 ///
-/// We only implement the 64 bytes version for simplicity. Guarantee that `sb.desc_size`
-/// equals to 64. This value will be checked when loading the filesystem.
+/// Descriptors are read and written as records of `sb.desc_size` bytes. The
+/// `_hi` fields of 32-byte descriptors are dropped when written, they would
+/// be zero anyway: without `64bit`, block numbers fit in 32 bits and the
+/// counts of a group in 16 bits. Bytes of larger descriptors past these 64
+/// are kept as they are on disk.
 #[derive(Debug, Default, Clone, Copy)]
 #[repr(C, packed)]
 pub struct BlockGroupDesc {
@@ -66,6 +81,14 @@ impl BlockGroupDesc {
         ((self.inode_bitmap_hi as PBlockId) << 32) | self.inode_bitmap_lo as PBlockId
     }
 
+    pub fn flags(&self) -> BlockGroupFlags {
+        BlockGroupFlags::from_bits_retain(self.flags)
+    }
+
+    pub fn set_flags(&mut self, flags: BlockGroupFlags) {
+        self.flags = flags.bits();
+    }
+
     pub fn itable_unused(&self) -> u32 {
         ((self.itable_unused_hi as u32) << 16) | self.itable_unused_lo as u32
     }
@@ -93,6 +116,11 @@ impl BlockGroupDesc {
         ((self.free_inodes_count_hi as u32) << 16) | self.free_inodes_count_lo as u32
     }
 
+    /// The descriptor checksum, valid with `metadata_csum`.
+    pub fn checksum(&self) -> u16 {
+        self.checksum
+    }
+
     pub fn inode_table_first_block(&self) -> PBlockId {
         ((self.inode_table_first_block_hi as u64) << 32) | self.inode_table_first_block_lo as u64
     }
@@ -106,15 +134,15 @@ impl BlockGroupDesc {
         self.free_blocks_count_hi = (cnt >> 32) as u16;
     }
 
-    pub fn set_inode_bitmap_csum(&mut self, uuid: &[u8], bitmap: &Bitmap) {
-        let mut csum = crc32(CRC32_INIT, uuid);
+    pub fn set_inode_bitmap_csum(&mut self, csum_seed: u32, bitmap: &Bitmap) {
+        let mut csum = csum_seed;
         csum = crc32(csum, bitmap.as_bytes());
         self.inode_bitmap_csum_lo = csum as u16;
-        self.block_bitmap_csum_hi = (csum >> 16) as u16;
+        self.inode_bitmap_csum_hi = (csum >> 16) as u16;
     }
 
-    pub fn set_block_bitmap_csum(&mut self, uuid: &[u8], bitmap: &Bitmap) {
-        let mut csum = crc32(CRC32_INIT, uuid);
+    pub fn set_block_bitmap_csum(&mut self, csum_seed: u32, bitmap: &Bitmap) {
+        let mut csum = csum_seed;
         csum = crc32(csum, bitmap.as_bytes());
         self.block_bitmap_csum_lo = csum as u16;
         self.block_bitmap_csum_hi = (csum >> 16) as u16;
@@ -135,10 +163,27 @@ impl BlockGroupRef {
         Self { id, desc }
     }
 
-    pub fn set_checksum(&mut self, uuid: &[u8]) {
-        let mut checksum = crc32(CRC32_INIT, uuid);
+    /// Set the descriptor checksum, computed over the `desc_size` bytes
+    /// stored on disk. `extra` is the on-disk bytes of the descriptor past
+    /// `BlockGroupDesc`, empty for descriptors of 64 bytes or less.
+    pub fn set_checksum(&mut self, csum_seed: u32, desc_size: usize, extra: &[u8]) {
+        self.desc.checksum = self.compute_checksum(csum_seed, desc_size, extra);
+    }
+
+    /// Check the descriptor checksum stored on disk, see `set_checksum`.
+    pub fn verify_checksum(&self, csum_seed: u32, desc_size: usize, extra: &[u8]) -> bool {
+        self.compute_checksum(csum_seed, desc_size, extra) == self.desc.checksum
+    }
+
+    fn compute_checksum(&self, csum_seed: u32, desc_size: usize, extra: &[u8]) -> u16 {
+        // The checksum is computed with the checksum field zeroed
+        let mut desc = self.desc;
+        desc.checksum = 0;
+        let len = desc_size.min(size_of::<BlockGroupDesc>());
+        let mut checksum = csum_seed;
         checksum = crc32(checksum, &self.id.to_le_bytes());
-        checksum = crc32(checksum, self.desc.to_bytes());
-        self.desc.checksum = checksum as u16;
+        checksum = crc32(checksum, &desc.to_bytes()[..len]);
+        checksum = crc32(checksum, extra);
+        checksum as u16
     }
 }
@@ -5,6 +5,7 @@ use crate::prelude::*;
 use crate::Block;
 use crate::BlockDevice;
 use axsync::Mutex;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 /// Write-back cache slot.
 #[derive(Debug, Clone, Copy, Default)]
@@ -80,6 +81,14 @@ pub struct BlockCache {
     cache: Arc<Mutex<[CacheSet; CACHE_SIZE]>>,
     /// The underlying block device.
     block_dev: Arc<dyn BlockDevice>,
+    /// Number of blocks written back to the device.
+    written: AtomicU64,
+    /// Number of cache hits.
+    #[cfg(feature = "stats")]
+    hits: AtomicU64,
+    /// Number of cache misses.
+    #[cfg(feature = "stats")]
+    misses: AtomicU64,
 }
 
 impl BlockCache {
@@ -90,12 +99,17 @@ impl BlockCache {
         Self {
             cache: Arc::new(Mutex::new(cache.try_into().unwrap())),
             block_dev,
+            written: AtomicU64::new(0),
+            #[cfg(feature = "stats")]
+            hits: AtomicU64::new(0),
+            #[cfg(feature = "stats")]
+            misses: AtomicU64::new(0),
         }
     }
 
     /// Read a block.
     pub fn read_block(&self, block_id: PBlockId) -> Block {
-        debug!("Reading block {}", block_id);
+        log_cache!(debug, "Reading block {}", block_id);
         let set_id = block_id as usize % CACHE_SIZE;
         let mut cache = self.cache.lock();
         let slot_id = cache[set_id].access(block_id) as usize;
@@ -103,16 +117,20 @@ impl BlockCache {
         // Check block id
         if slot.valid && slot.block.id == block_id {
             // Cache hit
+            #[cfg(feature = "stats")]
+            self.hits.fetch_add(1, Ordering::Relaxed);
             return slot.block.clone();
         } else {
             // Cache miss
+            #[cfg(feature = "stats")]
+            self.misses.fetch_add(1, Ordering::Relaxed);
             if slot.valid && slot.dirty {
                 // Write back Dirty block
-                self.block_dev.write_block(&slot.block);
+                self.write_back(&slot.block);
                 slot.dirty = false;
             }
             // Read block from disk
-            debug!("Loading block {} from disk", block_id);
+            log_cache!(debug, "Loading block {} from disk", block_id);
             let block = self.block_dev.read_block(block_id);
             slot.block = block.clone();
             slot.valid = true;
@@ -122,7 +140,7 @@ impl BlockCache {
 
     /// Write a block. (Write-Allocate)
     pub fn write_block(&self, block: &Block) {
-        debug!("Writing block {}", block.id);
+        log_cache!(debug, "Writing block {}", block.id);
         let set_id = block.id as usize % CACHE_SIZE;
         let mut cache = self.cache.lock();
         let slot_id = cache[set_id].access(block.id) as usize;
@@ -130,13 +148,17 @@ impl BlockCache {
         // Check block id
         if slot.valid && slot.block.id == block.id {
             // Cache hit
+            #[cfg(feature = "stats")]
+            self.hits.fetch_add(1, Ordering::Relaxed);
             slot.block = block.clone();
             slot.dirty = true;
         } else {
             // Cache miss
+            #[cfg(feature = "stats")]
+            self.misses.fetch_add(1, Ordering::Relaxed);
             if slot.valid && slot.dirty {
                 // Write back Dirty block
-                self.block_dev.write_block(&slot.block);
+                self.write_back(&slot.block);
                 slot.dirty = false;
             }
             // Write allocate
@@ -147,6 +169,78 @@ impl BlockCache {
         }
     }
 
+    /// Write a block through to the device and wait until it is on stable
+    /// storage. Dirty blocks in the cache are flushed first, so that the
+    /// block is never persisted before writes issued earlier, and the
+    /// block stays cached clean.
+    pub fn write_block_sync(&self, block: &Block) {
+        self.flush_all();
+        let set_id = block.id as usize % CACHE_SIZE;
+        let mut cache = self.cache.lock();
+        let slot_id = cache[set_id].access(block.id) as usize;
+        let slot = &mut cache[set_id].slots[slot_id];
+        slot.block = block.clone();
+        slot.valid = true;
+        slot.dirty = false;
+        self.block_dev.write_block_sync(block);
+        self.written.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Read a block from the device, bypassing the cache. A dirty cached
+    /// copy is written back first, the cache is not filled.
+    pub fn read_block_direct(&self, block_id: PBlockId) -> Block {
+        log_cache!(debug, "Reading block {} directly", block_id);
+        let set_id = block_id as usize % CACHE_SIZE;
+        let mut cache = self.cache.lock();
+        let cached = cache[set_id]
+            .slots
+            .iter_mut()
+            .find(|slot| slot.valid && slot.block.id == block_id);
+        if let Some(slot) = cached {
+            if slot.dirty {
+                self.write_back(&slot.block);
+                slot.dirty = false;
+            }
+        }
+        self.block_dev.read_block(block_id)
+    }
+
+    /// Write a block to the device, bypassing the cache. A cached copy is
+    /// dropped, so that it is neither read nor written back later.
+    pub fn write_block_direct(&self, block: &Block) {
+        log_cache!(debug, "Writing block {} directly", block.id);
+        let set_id = block.id as usize % CACHE_SIZE;
+        let mut cache = self.cache.lock();
+        for slot in cache[set_id].slots.iter_mut() {
+            if slot.valid && slot.block.id == block.id {
+                slot.valid = false;
+                slot.dirty = false;
+            }
+        }
+        self.write_back(block);
+    }
+
+    /// Write a block back to the device.
+    fn write_back(&self, block: &Block) {
+        self.block_dev.write_block(block);
+        self.written.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take the number of blocks written back to the device since the
+    /// last call.
+    pub fn take_written(&self) -> u64 {
+        self.written.swap(0, Ordering::Relaxed)
+    }
+
+    /// Get the number of cache hits and misses.
+    #[cfg(feature = "stats")]
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
     /// Flush a block to disk.
     #[allow(unused)]
     pub fn flush(&self, block_id: PBlockId) {
@@ -155,22 +249,62 @@ impl BlockCache {
         let slot_id = cache[set_id].access(block_id) as usize;
         let slot = &mut cache[set_id].slots[slot_id];
         if slot.valid && slot.dirty {
-            self.block_dev.write_block(&slot.block);
+            self.write_back(&slot.block);
             slot.dirty = false;
         }
     }
 
-    /// Flush all blocks to disk.
+    /// Drop the cached blocks in a range without writing them back, and
+    /// discard the range on the device.
+    pub fn discard(&self, start: PBlockId, count: u64) {
+        let mut cache = self.cache.lock();
+        for set in cache.iter_mut() {
+            for slot in set.slots.iter_mut() {
+                if slot.valid && (start..start + count).contains(&slot.block.id) {
+                    slot.valid = false;
+                    slot.dirty = false;
+                }
+            }
+        }
+        self.block_dev.discard(start, count);
+    }
+
+    /// Pass a readahead hint to the device, and load the first blocks of
+    /// the range into the cache. At most half of the cache is filled, so
+    /// that blocks in use are not all evicted.
+    pub fn readahead(&self, start: PBlockId, count: u64) {
+        self.block_dev.readahead(start, count);
+        let count = count.min((CACHE_SIZE * CACHE_ASSOC / 2) as u64);
+        for block_id in start..start + count {
+            self.read_block(block_id);
+        }
+    }
+
+    /// Drop all cached blocks without writing them back, e.g. after the
+    /// device contents were replaced.
+    #[cfg(feature = "mem_device")]
+    pub fn invalidate_all(&self) {
+        let mut cache = self.cache.lock();
+        for set in cache.iter_mut() {
+            for slot in set.slots.iter_mut() {
+                slot.valid = false;
+                slot.dirty = false;
+            }
+        }
+    }
+
+    /// Flush all blocks to disk, and flush the device write cache.
     pub fn flush_all(&self) {
         let mut cache = self.cache.lock();
         for set in cache.iter_mut() {
             for slot in set.slots.iter_mut() {
                 if slot.valid && slot.dirty {
-                    info!("Flushing block {} to disk", slot.block.id);
-                    self.block_dev.write_block(&slot.block);
+                    log_cache!(info, "Flushing block {} to disk", slot.block.id);
+                    self.write_back(&slot.block);
                     slot.dirty = false;
                 }
             }
         }
+        self.block_dev.flush();
     }
 }
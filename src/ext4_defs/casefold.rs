@@ -0,0 +1,85 @@
+//! Case-insensitive name comparison for casefolded directories.
+//!
+//! Names are compared like the kernel's `utf8` encoding does: each
+//! character is replaced by the canonical decomposition (NFD) of its full
+//! Unicode case folding, so that `ß` matches `SS` and a precomposed `é`
+//! matches `e` followed by a combining acute accent. Combining marks are
+//! then put in canonical order, and default ignorable code points, such as
+//! zero width spaces, are dropped.
+
+use super::casefold_tables::{COMBINING_CLASS, FOLD, IGNORABLE};
+use crate::prelude::*;
+
+/// Hangul syllables, decomposed algorithmically
+const HANGUL_BASE: u32 = 0xAC00;
+const HANGUL_COUNT: u32 = 11172;
+const HANGUL_L_BASE: u32 = 0x1100;
+const HANGUL_V_BASE: u32 = 0x1161;
+const HANGUL_T_BASE: u32 = 0x11A7;
+const HANGUL_V_COUNT: u32 = 21;
+const HANGUL_T_COUNT: u32 = 28;
+
+/// Fold a name to the form names in casefolded directories are compared
+/// in.
+pub fn casefold(name: &str) -> Vec<char> {
+    let mut folded = Vec::with_capacity(name.len());
+    for c in name.chars() {
+        fold_char(c, &mut folded);
+    }
+    reorder_marks(&mut folded);
+    folded
+}
+
+/// Append the folded form of a character.
+fn fold_char(c: char, folded: &mut Vec<char>) {
+    let index = (c as u32).wrapping_sub(HANGUL_BASE);
+    if index < HANGUL_COUNT {
+        let t = index % HANGUL_T_COUNT;
+        let lv = index / HANGUL_T_COUNT;
+        // Valid code points by construction
+        let jamo = |cp: u32| char::from_u32(cp).unwrap();
+        folded.push(jamo(HANGUL_L_BASE + lv / HANGUL_V_COUNT));
+        folded.push(jamo(HANGUL_V_BASE + lv % HANGUL_V_COUNT));
+        if t != 0 {
+            folded.push(jamo(HANGUL_T_BASE + t));
+        }
+        return;
+    }
+    if find_range(IGNORABLE, c, |&range| range).is_some() {
+        return;
+    }
+    match FOLD.binary_search_by_key(&c, |&(from, _)| from) {
+        Ok(pos) => folded.extend(FOLD[pos].1.chars()),
+        Err(_) => folded.push(c),
+    }
+}
+
+/// The canonical combining class of a character, 0 for starters.
+fn combining_class(c: char) -> u8 {
+    find_range(COMBINING_CLASS, c, |&(lo, hi, _)| (lo, hi)).map_or(0, |&(_, _, class)| class)
+}
+
+/// Find the entry of a sorted table of disjoint ranges whose range, given
+/// by `bounds`, holds `c`.
+fn find_range<T>(table: &[T], c: char, bounds: impl Fn(&T) -> (char, char)) -> Option<&T> {
+    let pos = table.partition_point(|entry| bounds(entry).1 < c);
+    table.get(pos).filter(|entry| bounds(entry).0 <= c)
+}
+
+/// Sort each run of combining marks by combining class, keeping the
+/// order of marks of the same class.
+fn reorder_marks(chars: &mut [char]) {
+    let mut start = 0;
+    while start < chars.len() {
+        if combining_class(chars[start]) == 0 {
+            start += 1;
+            continue;
+        }
+        let len = chars[start..]
+            .iter()
+            .position(|&c| combining_class(c) == 0)
+            .unwrap_or(chars.len() - start);
+        chars[start..start + len].sort_by_key(|&c| combining_class(c));
+        start += len;
+    }
+}
@@ -0,0 +1,3567 @@
+//! Unicode tables for comparing names in casefolded directories,
+//! generated by `tools/casefold_tables.py` from Unicode 14.0.0. Do not
+//! edit.
+
+/// Ranges of default ignorable code points, dropped from names. Sorted.
+pub(super) static IGNORABLE: &[(char, char)] = &[
+    ('\u{ad}', '\u{ad}'),
+    ('\u{34f}', '\u{34f}'),
+    ('\u{61c}', '\u{61c}'),
+    ('\u{115f}', '\u{1160}'),
+    ('\u{17b4}', '\u{17b5}'),
+    ('\u{180b}', '\u{180f}'),
+    ('\u{200b}', '\u{200f}'),
+    ('\u{202a}', '\u{202e}'),
+    ('\u{2060}', '\u{206f}'),
+    ('\u{3164}', '\u{3164}'),
+    ('\u{fe00}', '\u{fe0f}'),
+    ('\u{feff}', '\u{feff}'),
+    ('\u{ffa0}', '\u{ffa0}'),
+    ('\u{fff0}', '\u{fff8}'),
+    ('\u{1bca0}', '\u{1bca3}'),
+    ('\u{1d173}', '\u{1d17a}'),
+    ('\u{e0000}', '\u{e0fff}'),
+];
+
+/// Code points folded to something else: the canonical decomposition
+/// of their full case folding. Sorted by code point, Hangul syllables
+/// and default ignorable code points are not included.
+pub(super) static FOLD: &[(char, &str)] = &[
+    ('A', "a"),
+    ('B', "b"),
+    ('C', "c"),
+    ('D', "d"),
+    ('E', "e"),
+    ('F', "f"),
+    ('G', "g"),
+    ('H', "h"),
+    ('I', "i"),
+    ('J', "j"),
+    ('K', "k"),
+    ('L', "l"),
+    ('M', "m"),
+    ('N', "n"),
+    ('O', "o"),
+    ('P', "p"),
+    ('Q', "q"),
+    ('R', "r"),
+    ('S', "s"),
+    ('T', "t"),
+    ('U', "u"),
+    ('V', "v"),
+    ('W', "w"),
+    ('X', "x"),
+    ('Y', "y"),
+    ('Z', "z"),
+    ('\u{b5}', "\u{3bc}"),
+    ('\u{c0}', "a\u{300}"),
+    ('\u{c1}', "a\u{301}"),
+    ('\u{c2}', "a\u{302}"),
+    ('\u{c3}', "a\u{303}"),
+    ('\u{c4}', "a\u{308}"),
+    ('\u{c5}', "a\u{30a}"),
+    ('\u{c6}', "\u{e6}"),
+    ('\u{c7}', "c\u{327}"),
+    ('\u{c8}', "e\u{300}"),
+    ('\u{c9}', "e\u{301}"),
+    ('\u{ca}', "e\u{302}"),
+    ('\u{cb}', "e\u{308}"),
+    ('\u{cc}', "i\u{300}"),
+    ('\u{cd}', "i\u{301}"),
+    ('\u{ce}', "i\u{302}"),
+    ('\u{cf}', "i\u{308}"),
+    ('\u{d0}', "\u{f0}"),
+    ('\u{d1}', "n\u{303}"),
+    ('\u{d2}', "o\u{300}"),
+    ('\u{d3}', "o\u{301}"),
+    ('\u{d4}', "o\u{302}"),
+    ('\u{d5}', "o\u{303}"),
+    ('\u{d6}', "o\u{308}"),
+    ('\u{d8}', "\u{f8}"),
+    ('\u{d9}', "u\u{300}"),
+    ('\u{da}', "u\u{301}"),
+    ('\u{db}', "u\u{302}"),
+    ('\u{dc}', "u\u{308}"),
+    ('\u{dd}', "y\u{301}"),
+    ('\u{de}', "\u{fe}"),
+    ('\u{df}', "ss"),
+    ('\u{e0}', "a\u{300}"),
+    ('\u{e1}', "a\u{301}"),
+    ('\u{e2}', "a\u{302}"),
+    ('\u{e3}', "a\u{303}"),
+    ('\u{e4}', "a\u{308}"),
+    ('\u{e5}', "a\u{30a}"),
+    ('\u{e7}', "c\u{327}"),
+    ('\u{e8}', "e\u{300}"),
+    ('\u{e9}', "e\u{301}"),
+    ('\u{ea}', "e\u{302}"),
+    ('\u{eb}', "e\u{308}"),
+    ('\u{ec}', "i\u{300}"),
+    ('\u{ed}', "i\u{301}"),
+    ('\u{ee}', "i\u{302}"),
+    ('\u{ef}', "i\u{308}"),
+    ('\u{f1}', "n\u{303}"),
+    ('\u{f2}', "o\u{300}"),
+    ('\u{f3}', "o\u{301}"),
+    ('\u{f4}', "o\u{302}"),
+    ('\u{f5}', "o\u{303}"),
+    ('\u{f6}', "o\u{308}"),
+    ('\u{f9}', "u\u{300}"),
+    ('\u{fa}', "u\u{301}"),
+    ('\u{fb}', "u\u{302}"),
+    ('\u{fc}', "u\u{308}"),
+    ('\u{fd}', "y\u{301}"),
+    ('\u{ff}', "y\u{308}"),
+    ('\u{100}', "a\u{304}"),
+    ('\u{101}', "a\u{304}"),
+    ('\u{102}', "a\u{306}"),
+    ('\u{103}', "a\u{306}"),
+    ('\u{104}', "a\u{328}"),
+    ('\u{105}', "a\u{328}"),
+    ('\u{106}', "c\u{301}"),
+    ('\u{107}', "c\u{301}"),
+    ('\u{108}', "c\u{302}"),
+    ('\u{109}', "c\u{302}"),
+    ('\u{10a}', "c\u{307}"),
+    ('\u{10b}', "c\u{307}"),
+    ('\u{10c}', "c\u{30c}"),
+    ('\u{10d}', "c\u{30c}"),
+    ('\u{10e}', "d\u{30c}"),
+    ('\u{10f}', "d\u{30c}"),
+    ('\u{110}', "\u{111}"),
+    ('\u{112}', "e\u{304}"),
+    ('\u{113}', "e\u{304}"),
+    ('\u{114}', "e\u{306}"),
+    ('\u{115}', "e\u{306}"),
+    ('\u{116}', "e\u{307}"),
+    ('\u{117}', "e\u{307}"),
+    ('\u{118}', "e\u{328}"),
+    ('\u{119}', "e\u{328}"),
+    ('\u{11a}', "e\u{30c}"),
+    ('\u{11b}', "e\u{30c}"),
+    ('\u{11c}', "g\u{302}"),
+    ('\u{11d}', "g\u{302}"),
+    ('\u{11e}', "g\u{306}"),
+    ('\u{11f}', "g\u{306}"),
+    ('\u{120}', "g\u{307}"),
+    ('\u{121}', "g\u{307}"),
+    ('\u{122}', "g\u{327}"),
+    ('\u{123}', "g\u{327}"),
+    ('\u{124}', "h\u{302}"),
+    ('\u{125}', "h\u{302}"),
+    ('\u{126}', "\u{127}"),
+    ('\u{128}', "i\u{303}"),
+    ('\u{129}', "i\u{303}"),
+    ('\u{12a}', "i\u{304}"),
+    ('\u{12b}', "i\u{304}"),
+    ('\u{12c}', "i\u{306}"),
+    ('\u{12d}', "i\u{306}"),
+    ('\u{12e}', "i\u{328}"),
+    ('\u{12f}', "i\u{328}"),
+    ('\u{130}', "i\u{307}"),
+    ('\u{132}', "\u{133}"),
+    ('\u{134}', "j\u{302}"),
+    ('\u{135}', "j\u{302}"),
+    ('\u{136}', "k\u{327}"),
+    ('\u{137}', "k\u{327}"),
+    ('\u{139}', "l\u{301}"),
+    ('\u{13a}', "l\u{301}"),
+    ('\u{13b}', "l\u{327}"),
+    ('\u{13c}', "l\u{327}"),
+    ('\u{13d}', "l\u{30c}"),
+    ('\u{13e}', "l\u{30c}"),
+    ('\u{13f}', "\u{140}"),
+    ('\u{141}', "\u{142}"),
+    ('\u{143}', "n\u{301}"),
+    ('\u{144}', "n\u{301}"),
+    ('\u{145}', "n\u{327}"),
+    ('\u{146}', "n\u{327}"),
+    ('\u{147}', "n\u{30c}"),
+    ('\u{148}', "n\u{30c}"),
+    ('\u{149}', "\u{2bc}n"),
+    ('\u{14a}', "\u{14b}"),
+    ('\u{14c}', "o\u{304}"),
+    ('\u{14d}', "o\u{304}"),
+    ('\u{14e}', "o\u{306}"),
+    ('\u{14f}', "o\u{306}"),
+    ('\u{150}', "o\u{30b}"),
+    ('\u{151}', "o\u{30b}"),
+    ('\u{152}', "\u{153}"),
+    ('\u{154}', "r\u{301}"),
+    ('\u{155}', "r\u{301}"),
+    ('\u{156}', "r\u{327}"),
+    ('\u{157}', "r\u{327}"),
+    ('\u{158}', "r\u{30c}"),
+    ('\u{159}', "r\u{30c}"),
+    ('\u{15a}', "s\u{301}"),
+    ('\u{15b}', "s\u{301}"),
+    ('\u{15c}', "s\u{302}"),
+    ('\u{15d}', "s\u{302}"),
+    ('\u{15e}', "s\u{327}"),
+    ('\u{15f}', "s\u{327}"),
+    ('\u{160}', "s\u{30c}"),
+    ('\u{161}', "s\u{30c}"),
+    ('\u{162}', "t\u{327}"),
+    ('\u{163}', "t\u{327}"),
+    ('\u{164}', "t\u{30c}"),
+    ('\u{165}', "t\u{30c}"),
+    ('\u{166}', "\u{167}"),
+    ('\u{168}', "u\u{303}"),
+    ('\u{169}', "u\u{303}"),
+    ('\u{16a}', "u\u{304}"),
+    ('\u{16b}', "u\u{304}"),
+    ('\u{16c}', "u\u{306}"),
+    ('\u{16d}', "u\u{306}"),
+    ('\u{16e}', "u\u{30a}"),
+    ('\u{16f}', "u\u{30a}"),
+    ('\u{170}', "u\u{30b}"),
+    ('\u{171}', "u\u{30b}"),
+    ('\u{172}', "u\u{328}"),
+    ('\u{173}', "u\u{328}"),
+    ('\u{174}', "w\u{302}"),
+    ('\u{175}', "w\u{302}"),
+    ('\u{176}', "y\u{302}"),
+    ('\u{177}', "y\u{302}"),
+    ('\u{178}', "y\u{308}"),
+    ('\u{179}', "z\u{301}"),
+    ('\u{17a}', "z\u{301}"),
+    ('\u{17b}', "z\u{307}"),
+    ('\u{17c}', "z\u{307}"),
+    ('\u{17d}', "z\u{30c}"),
+    ('\u{17e}', "z\u{30c}"),
+    ('\u{17f}', "s"),
+    ('\u{181}', "\u{253}"),
+    ('\u{182}', "\u{183}"),
+    ('\u{184}', "\u{185}"),
+    ('\u{186}', "\u{254}"),
+    ('\u{187}', "\u{188}"),
+    ('\u{189}', "\u{256}"),
+    ('\u{18a}', "\u{257}"),
+    ('\u{18b}', "\u{18c}"),
+    ('\u{18e}', "\u{1dd}"),
+    ('\u{18f}', "\u{259}"),
+    ('\u{190}', "\u{25b}"),
+    ('\u{191}', "\u{192}"),
+    ('\u{193}', "\u{260}"),
+    ('\u{194}', "\u{263}"),
+    ('\u{196}', "\u{269}"),
+    ('\u{197}', "\u{268}"),
+    ('\u{198}', "\u{199}"),
+    ('\u{19c}', "\u{26f}"),
+    ('\u{19d}', "\u{272}"),
+    ('\u{19f}', "\u{275}"),
+    ('\u{1a0}', "o\u{31b}"),
+    ('\u{1a1}', "o\u{31b}"),
+    ('\u{1a2}', "\u{1a3}"),
+    ('\u{1a4}', "\u{1a5}"),
+    ('\u{1a6}', "\u{280}"),
+    ('\u{1a7}', "\u{1a8}"),
+    ('\u{1a9}', "\u{283}"),
+    ('\u{1ac}', "\u{1ad}"),
+    ('\u{1ae}', "\u{288}"),
+    ('\u{1af}', "u\u{31b}"),
+    ('\u{1b0}', "u\u{31b}"),
+    ('\u{1b1}', "\u{28a}"),
+    ('\u{1b2}', "\u{28b}"),
+    ('\u{1b3}', "\u{1b4}"),
+    ('\u{1b5}', "\u{1b6}"),
+    ('\u{1b7}', "\u{292}"),
+    ('\u{1b8}', "\u{1b9}"),
+    ('\u{1bc}', "\u{1bd}"),
+    ('\u{1c4}', "\u{1c6}"),
+    ('\u{1c5}', "\u{1c6}"),
+    ('\u{1c7}', "\u{1c9}"),
+    ('\u{1c8}', "\u{1c9}"),
+    ('\u{1ca}', "\u{1cc}"),
+    ('\u{1cb}', "\u{1cc}"),
+    ('\u{1cd}', "a\u{30c}"),
+    ('\u{1ce}', "a\u{30c}"),
+    ('\u{1cf}', "i\u{30c}"),
+    ('\u{1d0}', "i\u{30c}"),
+    ('\u{1d1}', "o\u{30c}"),
+    ('\u{1d2}', "o\u{30c}"),
+    ('\u{1d3}', "u\u{30c}"),
+    ('\u{1d4}', "u\u{30c}"),
+    ('\u{1d5}', "u\u{308}\u{304}"),
+    ('\u{1d6}', "u\u{308}\u{304}"),
+    ('\u{1d7}', "u\u{308}\u{301}"),
+    ('\u{1d8}', "u\u{308}\u{301}"),
+    ('\u{1d9}', "u\u{308}\u{30c}"),
+    ('\u{1da}', "u\u{308}\u{30c}"),
+    ('\u{1db}', "u\u{308}\u{300}"),
+    ('\u{1dc}', "u\u{308}\u{300}"),
+    ('\u{1de}', "a\u{308}\u{304}"),
+    ('\u{1df}', "a\u{308}\u{304}"),
+    ('\u{1e0}', "a\u{307}\u{304}"),
+    ('\u{1e1}', "a\u{307}\u{304}"),
+    ('\u{1e2}', "\u{e6}\u{304}"),
+    ('\u{1e3}', "\u{e6}\u{304}"),
+    ('\u{1e4}', "\u{1e5}"),
+    ('\u{1e6}', "g\u{30c}"),
+    ('\u{1e7}', "g\u{30c}"),
+    ('\u{1e8}', "k\u{30c}"),
+    ('\u{1e9}', "k\u{30c}"),
+    ('\u{1ea}', "o\u{328}"),
+    ('\u{1eb}', "o\u{328}"),
+    ('\u{1ec}', "o\u{328}\u{304}"),
+    ('\u{1ed}', "o\u{328}\u{304}"),
+    ('\u{1ee}', "\u{292}\u{30c}"),
+    ('\u{1ef}', "\u{292}\u{30c}"),
+    ('\u{1f0}', "j\u{30c}"),
+    ('\u{1f1}', "\u{1f3}"),
+    ('\u{1f2}', "\u{1f3}"),
+    ('\u{1f4}', "g\u{301}"),
+    ('\u{1f5}', "g\u{301}"),
+    ('\u{1f6}', "\u{195}"),
+    ('\u{1f7}', "\u{1bf}"),
+    ('\u{1f8}', "n\u{300}"),
+    ('\u{1f9}', "n\u{300}"),
+    ('\u{1fa}', "a\u{30a}\u{301}"),
+    ('\u{1fb}', "a\u{30a}\u{301}"),
+    ('\u{1fc}', "\u{e6}\u{301}"),
+    ('\u{1fd}', "\u{e6}\u{301}"),
+    ('\u{1fe}', "\u{f8}\u{301}"),
+    ('\u{1ff}', "\u{f8}\u{301}"),
+    ('\u{200}', "a\u{30f}"),
+    ('\u{201}', "a\u{30f}"),
+    ('\u{202}', "a\u{311}"),
+    ('\u{203}', "a\u{311}"),
+    ('\u{204}', "e\u{30f}"),
+    ('\u{205}', "e\u{30f}"),
+    ('\u{206}', "e\u{311}"),
+    ('\u{207}', "e\u{311}"),
+    ('\u{208}', "i\u{30f}"),
+    ('\u{209}', "i\u{30f}"),
+    ('\u{20a}', "i\u{311}"),
+    ('\u{20b}', "i\u{311}"),
+    ('\u{20c}', "o\u{30f}"),
+    ('\u{20d}', "o\u{30f}"),
+    ('\u{20e}', "o\u{311}"),
+    ('\u{20f}', "o\u{311}"),
+    ('\u{210}', "r\u{30f}"),
+    ('\u{211}', "r\u{30f}"),
+    ('\u{212}', "r\u{311}"),
+    ('\u{213}', "r\u{311}"),
+    ('\u{214}', "u\u{30f}"),
+    ('\u{215}', "u\u{30f}"),
+    ('\u{216}', "u\u{311}"),
+    ('\u{217}', "u\u{311}"),
+    ('\u{218}', "s\u{326}"),
+    ('\u{219}', "s\u{326}"),
+    ('\u{21a}', "t\u{326}"),
+    ('\u{21b}', "t\u{326}"),
+    ('\u{21c}', "\u{21d}"),
+    ('\u{21e}', "h\u{30c}"),
+    ('\u{21f}', "h\u{30c}"),
+    ('\u{220}', "\u{19e}"),
+    ('\u{222}', "\u{223}"),
+    ('\u{224}', "\u{225}"),
+    ('\u{226}', "a\u{307}"),
+    ('\u{227}', "a\u{307}"),
+    ('\u{228}', "e\u{327}"),
+    ('\u{229}', "e\u{327}"),
+    ('\u{22a}', "o\u{308}\u{304}"),
+    ('\u{22b}', "o\u{308}\u{304}"),
+    ('\u{22c}', "o\u{303}\u{304}"),
+    ('\u{22d}', "o\u{303}\u{304}"),
+    ('\u{22e}', "o\u{307}"),
+    ('\u{22f}', "o\u{307}"),
+    ('\u{230}', "o\u{307}\u{304}"),
+    ('\u{231}', "o\u{307}\u{304}"),
+    ('\u{232}', "y\u{304}"),
+    ('\u{233}', "y\u{304}"),
+    ('\u{23a}', "\u{2c65}"),
+    ('\u{23b}', "\u{23c}"),
+    ('\u{23d}', "\u{19a}"),
+    ('\u{23e}', "\u{2c66}"),
+    ('\u{241}', "\u{242}"),
+    ('\u{243}', "\u{180}"),
+    ('\u{244}', "\u{289}"),
+    ('\u{245}', "\u{28c}"),
+    ('\u{246}', "\u{247}"),
+    ('\u{248}', "\u{249}"),
+    ('\u{24a}', "\u{24b}"),
+    ('\u{24c}', "\u{24d}"),
+    ('\u{24e}', "\u{24f}"),
+    ('\u{340}', "\u{300}"),
+    ('\u{341}', "\u{301}"),
+    ('\u{343}', "\u{313}"),
+    ('\u{344}', "\u{308}\u{301}"),
+    ('\u{345}', "\u{3b9}"),
+    ('\u{370}', "\u{371}"),
+    ('\u{372}', "\u{373}"),
+    ('\u{374}', "\u{2b9}"),
+    ('\u{376}', "\u{377}"),
+    ('\u{37e}', "\u{3b}"),
+    ('\u{37f}', "\u{3f3}"),
+    ('\u{385}', "\u{a8}\u{301}"),
+    ('\u{386}', "\u{3b1}\u{301}"),
+    ('\u{387}', "\u{b7}"),
+    ('\u{388}', "\u{3b5}\u{301}"),
+    ('\u{389}', "\u{3b7}\u{301}"),
+    ('\u{38a}', "\u{3b9}\u{301}"),
+    ('\u{38c}', "\u{3bf}\u{301}"),
+    ('\u{38e}', "\u{3c5}\u{301}"),
+    ('\u{38f}', "\u{3c9}\u{301}"),
+    ('\u{390}', "\u{3b9}\u{308}\u{301}"),
+    ('\u{391}', "\u{3b1}"),
+    ('\u{392}', "\u{3b2}"),
+    ('\u{393}', "\u{3b3}"),
+    ('\u{394}', "\u{3b4}"),
+    ('\u{395}', "\u{3b5}"),
+    ('\u{396}', "\u{3b6}"),
+    ('\u{397}', "\u{3b7}"),
+    ('\u{398}', "\u{3b8}"),
+    ('\u{399}', "\u{3b9}"),
+    ('\u{39a}', "\u{3ba}"),
+    ('\u{39b}', "\u{3bb}"),
+    ('\u{39c}', "\u{3bc}"),
+    ('\u{39d}', "\u{3bd}"),
+    ('\u{39e}', "\u{3be}"),
+    ('\u{39f}', "\u{3bf}"),
+    ('\u{3a0}', "\u{3c0}"),
+    ('\u{3a1}', "\u{3c1}"),
+    ('\u{3a3}', "\u{3c3}"),
+    ('\u{3a4}', "\u{3c4}"),
+    ('\u{3a5}', "\u{3c5}"),
+    ('\u{3a6}', "\u{3c6}"),
+    ('\u{3a7}', "\u{3c7}"),
+    ('\u{3a8}', "\u{3c8}"),
+    ('\u{3a9}', "\u{3c9}"),
+    ('\u{3aa}', "\u{3b9}\u{308}"),
+    ('\u{3ab}', "\u{3c5}\u{308}"),
+    ('\u{3ac}', "\u{3b1}\u{301}"),
+    ('\u{3ad}', "\u{3b5}\u{301}"),
+    ('\u{3ae}', "\u{3b7}\u{301}"),
+    ('\u{3af}', "\u{3b9}\u{301}"),
+    ('\u{3b0}', "\u{3c5}\u{308}\u{301}"),
+    ('\u{3c2}', "\u{3c3}"),
+    ('\u{3ca}', "\u{3b9}\u{308}"),
+    ('\u{3cb}', "\u{3c5}\u{308}"),
+    ('\u{3cc}', "\u{3bf}\u{301}"),
+    ('\u{3cd}', "\u{3c5}\u{301}"),
+    ('\u{3ce}', "\u{3c9}\u{301}"),
+    ('\u{3cf}', "\u{3d7}"),
+    ('\u{3d0}', "\u{3b2}"),
+    ('\u{3d1}', "\u{3b8}"),
+    ('\u{3d3}', "\u{3d2}\u{301}"),
+    ('\u{3d4}', "\u{3d2}\u{308}"),
+    ('\u{3d5}', "\u{3c6}"),
+    ('\u{3d6}', "\u{3c0}"),
+    ('\u{3d8}', "\u{3d9}"),
+    ('\u{3da}', "\u{3db}"),
+    ('\u{3dc}', "\u{3dd}"),
+    ('\u{3de}', "\u{3df}"),
+    ('\u{3e0}', "\u{3e1}"),
+    ('\u{3e2}', "\u{3e3}"),
+    ('\u{3e4}', "\u{3e5}"),
+    ('\u{3e6}', "\u{3e7}"),
+    ('\u{3e8}', "\u{3e9}"),
+    ('\u{3ea}', "\u{3eb}"),
+    ('\u{3ec}', "\u{3ed}"),
+    ('\u{3ee}', "\u{3ef}"),
+    ('\u{3f0}', "\u{3ba}"),
+    ('\u{3f1}', "\u{3c1}"),
+    ('\u{3f4}', "\u{3b8}"),
+    ('\u{3f5}', "\u{3b5}"),
+    ('\u{3f7}', "\u{3f8}"),
+    ('\u{3f9}', "\u{3f2}"),
+    ('\u{3fa}', "\u{3fb}"),
+    ('\u{3fd}', "\u{37b}"),
+    ('\u{3fe}', "\u{37c}"),
+    ('\u{3ff}', "\u{37d}"),
+    ('\u{400}', "\u{435}\u{300}"),
+    ('\u{401}', "\u{435}\u{308}"),
+    ('\u{402}', "\u{452}"),
+    ('\u{403}', "\u{433}\u{301}"),
+    ('\u{404}', "\u{454}"),
+    ('\u{405}', "\u{455}"),
+    ('\u{406}', "\u{456}"),
+    ('\u{407}', "\u{456}\u{308}"),
+    ('\u{408}', "\u{458}"),
+    ('\u{409}', "\u{459}"),
+    ('\u{40a}', "\u{45a}"),
+    ('\u{40b}', "\u{45b}"),
+    ('\u{40c}', "\u{43a}\u{301}"),
+    ('\u{40d}', "\u{438}\u{300}"),
+    ('\u{40e}', "\u{443}\u{306}"),
+    ('\u{40f}', "\u{45f}"),
+    ('\u{410}', "\u{430}"),
+    ('\u{411}', "\u{431}"),
+    ('\u{412}', "\u{432}"),
+    ('\u{413}', "\u{433}"),
+    ('\u{414}', "\u{434}"),
+    ('\u{415}', "\u{435}"),
+    ('\u{416}', "\u{436}"),
+    ('\u{417}', "\u{437}"),
+    ('\u{418}', "\u{438}"),
+    ('\u{419}', "\u{438}\u{306}"),
+    ('\u{41a}', "\u{43a}"),
+    ('\u{41b}', "\u{43b}"),
+    ('\u{41c}', "\u{43c}"),
+    ('\u{41d}', "\u{43d}"),
+    ('\u{41e}', "\u{43e}"),
+    ('\u{41f}', "\u{43f}"),
+    ('\u{420}', "\u{440}"),
+    ('\u{421}', "\u{441}"),
+    ('\u{422}', "\u{442}"),
+    ('\u{423}', "\u{443}"),
+    ('\u{424}', "\u{444}"),
+    ('\u{425}', "\u{445}"),
+    ('\u{426}', "\u{446}"),
+    ('\u{427}', "\u{447}"),
+    ('\u{428}', "\u{448}"),
+    ('\u{429}', "\u{449}"),
+    ('\u{42a}', "\u{44a}"),
+    ('\u{42b}', "\u{44b}"),
+    ('\u{42c}', "\u{44c}"),
+    ('\u{42d}', "\u{44d}"),
+    ('\u{42e}', "\u{44e}"),
+    ('\u{42f}', "\u{44f}"),
+    ('\u{439}', "\u{438}\u{306}"),
+    ('\u{450}', "\u{435}\u{300}"),
+    ('\u{451}', "\u{435}\u{308}"),
+    ('\u{453}', "\u{433}\u{301}"),
+    ('\u{457}', "\u{456}\u{308}"),
+    ('\u{45c}', "\u{43a}\u{301}"),
+    ('\u{45d}', "\u{438}\u{300}"),
+    ('\u{45e}', "\u{443}\u{306}"),
+    ('\u{460}', "\u{461}"),
+    ('\u{462}', "\u{463}"),
+    ('\u{464}', "\u{465}"),
+    ('\u{466}', "\u{467}"),
+    ('\u{468}', "\u{469}"),
+    ('\u{46a}', "\u{46b}"),
+    ('\u{46c}', "\u{46d}"),
+    ('\u{46e}', "\u{46f}"),
+    ('\u{470}', "\u{471}"),
+    ('\u{472}', "\u{473}"),
+    ('\u{474}', "\u{475}"),
+    ('\u{476}', "\u{475}\u{30f}"),
+    ('\u{477}', "\u{475}\u{30f}"),
+    ('\u{478}', "\u{479}"),
+    ('\u{47a}', "\u{47b}"),
+    ('\u{47c}', "\u{47d}"),
+    ('\u{47e}', "\u{47f}"),
+    ('\u{480}', "\u{481}"),
+    ('\u{48a}', "\u{48b}"),
+    ('\u{48c}', "\u{48d}"),
+    ('\u{48e}', "\u{48f}"),
+    ('\u{490}', "\u{491}"),
+    ('\u{492}', "\u{493}"),
+    ('\u{494}', "\u{495}"),
+    ('\u{496}', "\u{497}"),
+    ('\u{498}', "\u{499}"),
+    ('\u{49a}', "\u{49b}"),
+    ('\u{49c}', "\u{49d}"),
+    ('\u{49e}', "\u{49f}"),
+    ('\u{4a0}', "\u{4a1}"),
+    ('\u{4a2}', "\u{4a3}"),
+    ('\u{4a4}', "\u{4a5}"),
+    ('\u{4a6}', "\u{4a7}"),
+    ('\u{4a8}', "\u{4a9}"),
+    ('\u{4aa}', "\u{4ab}"),
+    ('\u{4ac}', "\u{4ad}"),
+    ('\u{4ae}', "\u{4af}"),
+    ('\u{4b0}', "\u{4b1}"),
+    ('\u{4b2}', "\u{4b3}"),
+    ('\u{4b4}', "\u{4b5}"),
+    ('\u{4b6}', "\u{4b7}"),
+    ('\u{4b8}', "\u{4b9}"),
+    ('\u{4ba}', "\u{4bb}"),
+    ('\u{4bc}', "\u{4bd}"),
+    ('\u{4be}', "\u{4bf}"),
+    ('\u{4c0}', "\u{4cf}"),
+    ('\u{4c1}', "\u{436}\u{306}"),
+    ('\u{4c2}', "\u{436}\u{306}"),
+    ('\u{4c3}', "\u{4c4}"),
+    ('\u{4c5}', "\u{4c6}"),
+    ('\u{4c7}', "\u{4c8}"),
+    ('\u{4c9}', "\u{4ca}"),
+    ('\u{4cb}', "\u{4cc}"),
+    ('\u{4cd}', "\u{4ce}"),
+    ('\u{4d0}', "\u{430}\u{306}"),
+    ('\u{4d1}', "\u{430}\u{306}"),
+    ('\u{4d2}', "\u{430}\u{308}"),
+    ('\u{4d3}', "\u{430}\u{308}"),
+    ('\u{4d4}', "\u{4d5}"),
+    ('\u{4d6}', "\u{435}\u{306}"),
+    ('\u{4d7}', "\u{435}\u{306}"),
+    ('\u{4d8}', "\u{4d9}"),
+    ('\u{4da}', "\u{4d9}\u{308}"),
+    ('\u{4db}', "\u{4d9}\u{308}"),
+    ('\u{4dc}', "\u{436}\u{308}"),
+    ('\u{4dd}', "\u{436}\u{308}"),
+    ('\u{4de}', "\u{437}\u{308}"),
+    ('\u{4df}', "\u{437}\u{308}"),
+    ('\u{4e0}', "\u{4e1}"),
+    ('\u{4e2}', "\u{438}\u{304}"),
+    ('\u{4e3}', "\u{438}\u{304}"),
+    ('\u{4e4}', "\u{438}\u{308}"),
+    ('\u{4e5}', "\u{438}\u{308}"),
+    ('\u{4e6}', "\u{43e}\u{308}"),
+    ('\u{4e7}', "\u{43e}\u{308}"),
+    ('\u{4e8}', "\u{4e9}"),
+    ('\u{4ea}', "\u{4e9}\u{308}"),
+    ('\u{4eb}', "\u{4e9}\u{308}"),
+    ('\u{4ec}', "\u{44d}\u{308}"),
+    ('\u{4ed}', "\u{44d}\u{308}"),
+    ('\u{4ee}', "\u{443}\u{304}"),
+    ('\u{4ef}', "\u{443}\u{304}"),
+    ('\u{4f0}', "\u{443}\u{308}"),
+    ('\u{4f1}', "\u{443}\u{308}"),
+    ('\u{4f2}', "\u{443}\u{30b}"),
+    ('\u{4f3}', "\u{443}\u{30b}"),
+    ('\u{4f4}', "\u{447}\u{308}"),
+    ('\u{4f5}', "\u{447}\u{308}"),
+    ('\u{4f6}', "\u{4f7}"),
+    ('\u{4f8}', "\u{44b}\u{308}"),
+    ('\u{4f9}', "\u{44b}\u{308}"),
+    ('\u{4fa}', "\u{4fb}"),
+    ('\u{4fc}', "\u{4fd}"),
+    ('\u{4fe}', "\u{4ff}"),
+    ('\u{500}', "\u{501}"),
+    ('\u{502}', "\u{503}"),
+    ('\u{504}', "\u{505}"),
+    ('\u{506}', "\u{507}"),
+    ('\u{508}', "\u{509}"),
+    ('\u{50a}', "\u{50b}"),
+    ('\u{50c}', "\u{50d}"),
+    ('\u{50e}', "\u{50f}"),
+    ('\u{510}', "\u{511}"),
+    ('\u{512}', "\u{513}"),
+    ('\u{514}', "\u{515}"),
+    ('\u{516}', "\u{517}"),
+    ('\u{518}', "\u{519}"),
+    ('\u{51a}', "\u{51b}"),
+    ('\u{51c}', "\u{51d}"),
+    ('\u{51e}', "\u{51f}"),
+    ('\u{520}', "\u{521}"),
+    ('\u{522}', "\u{523}"),
+    ('\u{524}', "\u{525}"),
+    ('\u{526}', "\u{527}"),
+    ('\u{528}', "\u{529}"),
+    ('\u{52a}', "\u{52b}"),
+    ('\u{52c}', "\u{52d}"),
+    ('\u{52e}', "\u{52f}"),
+    ('\u{531}', "\u{561}"),
+    ('\u{532}', "\u{562}"),
+    ('\u{533}', "\u{563}"),
+    ('\u{534}', "\u{564}"),
+    ('\u{535}', "\u{565}"),
+    ('\u{536}', "\u{566}"),
+    ('\u{537}', "\u{567}"),
+    ('\u{538}', "\u{568}"),
+    ('\u{539}', "\u{569}"),
+    ('\u{53a}', "\u{56a}"),
+    ('\u{53b}', "\u{56b}"),
+    ('\u{53c}', "\u{56c}"),
+    ('\u{53d}', "\u{56d}"),
+    ('\u{53e}', "\u{56e}"),
+    ('\u{53f}', "\u{56f}"),
+    ('\u{540}', "\u{570}"),
+    ('\u{541}', "\u{571}"),
+    ('\u{542}', "\u{572}"),
+    ('\u{543}', "\u{573}"),
+    ('\u{544}', "\u{574}"),
+    ('\u{545}', "\u{575}"),
+    ('\u{546}', "\u{576}"),
+    ('\u{547}', "\u{577}"),
+    ('\u{548}', "\u{578}"),
+    ('\u{549}', "\u{579}"),
+    ('\u{54a}', "\u{57a}"),
+    ('\u{54b}', "\u{57b}"),
+    ('\u{54c}', "\u{57c}"),
+    ('\u{54d}', "\u{57d}"),
+    ('\u{54e}', "\u{57e}"),
+    ('\u{54f}', "\u{57f}"),
+    ('\u{550}', "\u{580}"),
+    ('\u{551}', "\u{581}"),
+    ('\u{552}', "\u{582}"),
+    ('\u{553}', "\u{583}"),
+    ('\u{554}', "\u{584}"),
+    ('\u{555}', "\u{585}"),
+    ('\u{556}', "\u{586}"),
+    ('\u{587}', "\u{565}\u{582}"),
+    ('\u{622}', "\u{627}\u{653}"),
+    ('\u{623}', "\u{627}\u{654}"),
+    ('\u{624}', "\u{648}\u{654}"),
+    ('\u{625}', "\u{627}\u{655}"),
+    ('\u{626}', "\u{64a}\u{654}"),
+    ('\u{6c0}', "\u{6d5}\u{654}"),
+    ('\u{6c2}', "\u{6c1}\u{654}"),
+    ('\u{6d3}', "\u{6d2}\u{654}"),
+    ('\u{929}', "\u{928}\u{93c}"),
+    ('\u{931}', "\u{930}\u{93c}"),
+    ('\u{934}', "\u{933}\u{93c}"),
+    ('\u{958}', "\u{915}\u{93c}"),
+    ('\u{959}', "\u{916}\u{93c}"),
+    ('\u{95a}', "\u{917}\u{93c}"),
+    ('\u{95b}', "\u{91c}\u{93c}"),
+    ('\u{95c}', "\u{921}\u{93c}"),
+    ('\u{95d}', "\u{922}\u{93c}"),
+    ('\u{95e}', "\u{92b}\u{93c}"),
+    ('\u{95f}', "\u{92f}\u{93c}"),
+    ('\u{9cb}', "\u{9c7}\u{9be}"),
+    ('\u{9cc}', "\u{9c7}\u{9d7}"),
+    ('\u{9dc}', "\u{9a1}\u{9bc}"),
+    ('\u{9dd}', "\u{9a2}\u{9bc}"),
+    ('\u{9df}', "\u{9af}\u{9bc}"),
+    ('\u{a33}', "\u{a32}\u{a3c}"),
+    ('\u{a36}', "\u{a38}\u{a3c}"),
+    ('\u{a59}', "\u{a16}\u{a3c}"),
+    ('\u{a5a}', "\u{a17}\u{a3c}"),
+    ('\u{a5b}', "\u{a1c}\u{a3c}"),
+    ('\u{a5e}', "\u{a2b}\u{a3c}"),
+    ('\u{b48}', "\u{b47}\u{b56}"),
+    ('\u{b4b}', "\u{b47}\u{b3e}"),
+    ('\u{b4c}', "\u{b47}\u{b57}"),
+    ('\u{b5c}', "\u{b21}\u{b3c}"),
+    ('\u{b5d}', "\u{b22}\u{b3c}"),
+    ('\u{b94}', "\u{b92}\u{bd7}"),
+    ('\u{bca}', "\u{bc6}\u{bbe}"),
+    ('\u{bcb}', "\u{bc7}\u{bbe}"),
+    ('\u{bcc}', "\u{bc6}\u{bd7}"),
+    ('\u{c48}', "\u{c46}\u{c56}"),
+    ('\u{cc0}', "\u{cbf}\u{cd5}"),
+    ('\u{cc7}', "\u{cc6}\u{cd5}"),
+    ('\u{cc8}', "\u{cc6}\u{cd6}"),
+    ('\u{cca}', "\u{cc6}\u{cc2}"),
+    ('\u{ccb}', "\u{cc6}\u{cc2}\u{cd5}"),
+    ('\u{d4a}', "\u{d46}\u{d3e}"),
+    ('\u{d4b}', "\u{d47}\u{d3e}"),
+    ('\u{d4c}', "\u{d46}\u{d57}"),
+    ('\u{dda}', "\u{dd9}\u{dca}"),
+    ('\u{ddc}', "\u{dd9}\u{dcf}"),
+    ('\u{ddd}', "\u{dd9}\u{dcf}\u{dca}"),
+    ('\u{dde}', "\u{dd9}\u{ddf}"),
+    ('\u{f43}', "\u{f42}\u{fb7}"),
+    ('\u{f4d}', "\u{f4c}\u{fb7}"),
+    ('\u{f52}', "\u{f51}\u{fb7}"),
+    ('\u{f57}', "\u{f56}\u{fb7}"),
+    ('\u{f5c}', "\u{f5b}\u{fb7}"),
+    ('\u{f69}', "\u{f40}\u{fb5}"),
+    ('\u{f73}', "\u{f71}\u{f72}"),
+    ('\u{f75}', "\u{f71}\u{f74}"),
+    ('\u{f76}', "\u{fb2}\u{f80}"),
+    ('\u{f78}', "\u{fb3}\u{f80}"),
+    ('\u{f81}', "\u{f71}\u{f80}"),
+    ('\u{f93}', "\u{f92}\u{fb7}"),
+    ('\u{f9d}', "\u{f9c}\u{fb7}"),
+    ('\u{fa2}', "\u{fa1}\u{fb7}"),
+    ('\u{fa7}', "\u{fa6}\u{fb7}"),
+    ('\u{fac}', "\u{fab}\u{fb7}"),
+    ('\u{fb9}', "\u{f90}\u{fb5}"),
+    ('\u{1026}', "\u{1025}\u{102e}"),
+    ('\u{10a0}', "\u{2d00}"),
+    ('\u{10a1}', "\u{2d01}"),
+    ('\u{10a2}', "\u{2d02}"),
+    ('\u{10a3}', "\u{2d03}"),
+    ('\u{10a4}', "\u{2d04}"),
+    ('\u{10a5}', "\u{2d05}"),
+    ('\u{10a6}', "\u{2d06}"),
+    ('\u{10a7}', "\u{2d07}"),
+    ('\u{10a8}', "\u{2d08}"),
+    ('\u{10a9}', "\u{2d09}"),
+    ('\u{10aa}', "\u{2d0a}"),
+    ('\u{10ab}', "\u{2d0b}"),
+    ('\u{10ac}', "\u{2d0c}"),
+    ('\u{10ad}', "\u{2d0d}"),
+    ('\u{10ae}', "\u{2d0e}"),
+    ('\u{10af}', "\u{2d0f}"),
+    ('\u{10b0}', "\u{2d10}"),
+    ('\u{10b1}', "\u{2d11}"),
+    ('\u{10b2}', "\u{2d12}"),
+    ('\u{10b3}', "\u{2d13}"),
+    ('\u{10b4}', "\u{2d14}"),
+    ('\u{10b5}', "\u{2d15}"),
+    ('\u{10b6}', "\u{2d16}"),
+    ('\u{10b7}', "\u{2d17}"),
+    ('\u{10b8}', "\u{2d18}"),
+    ('\u{10b9}', "\u{2d19}"),
+    ('\u{10ba}', "\u{2d1a}"),
+    ('\u{10bb}', "\u{2d1b}"),
+    ('\u{10bc}', "\u{2d1c}"),
+    ('\u{10bd}', "\u{2d1d}"),
+    ('\u{10be}', "\u{2d1e}"),
+    ('\u{10bf}', "\u{2d1f}"),
+    ('\u{10c0}', "\u{2d20}"),
+    ('\u{10c1}', "\u{2d21}"),
+    ('\u{10c2}', "\u{2d22}"),
+    ('\u{10c3}', "\u{2d23}"),
+    ('\u{10c4}', "\u{2d24}"),
+    ('\u{10c5}', "\u{2d25}"),
+    ('\u{10c7}', "\u{2d27}"),
+    ('\u{10cd}', "\u{2d2d}"),
+    ('\u{13f8}', "\u{13f0}"),
+    ('\u{13f9}', "\u{13f1}"),
+    ('\u{13fa}', "\u{13f2}"),
+    ('\u{13fb}', "\u{13f3}"),
+    ('\u{13fc}', "\u{13f4}"),
+    ('\u{13fd}', "\u{13f5}"),
+    ('\u{1b06}', "\u{1b05}\u{1b35}"),
+    ('\u{1b08}', "\u{1b07}\u{1b35}"),
+    ('\u{1b0a}', "\u{1b09}\u{1b35}"),
+    ('\u{1b0c}', "\u{1b0b}\u{1b35}"),
+    ('\u{1b0e}', "\u{1b0d}\u{1b35}"),
+    ('\u{1b12}', "\u{1b11}\u{1b35}"),
+    ('\u{1b3b}', "\u{1b3a}\u{1b35}"),
+    ('\u{1b3d}', "\u{1b3c}\u{1b35}"),
+    ('\u{1b40}', "\u{1b3e}\u{1b35}"),
+    ('\u{1b41}', "\u{1b3f}\u{1b35}"),
+    ('\u{1b43}', "\u{1b42}\u{1b35}"),
+    ('\u{1c80}', "\u{432}"),
+    ('\u{1c81}', "\u{434}"),
+    ('\u{1c82}', "\u{43e}"),
+    ('\u{1c83}', "\u{441}"),
+    ('\u{1c84}', "\u{442}"),
+    ('\u{1c85}', "\u{442}"),
+    ('\u{1c86}', "\u{44a}"),
+    ('\u{1c87}', "\u{463}"),
+    ('\u{1c88}', "\u{a64b}"),
+    ('\u{1c90}', "\u{10d0}"),
+    ('\u{1c91}', "\u{10d1}"),
+    ('\u{1c92}', "\u{10d2}"),
+    ('\u{1c93}', "\u{10d3}"),
+    ('\u{1c94}', "\u{10d4}"),
+    ('\u{1c95}', "\u{10d5}"),
+    ('\u{1c96}', "\u{10d6}"),
+    ('\u{1c97}', "\u{10d7}"),
+    ('\u{1c98}', "\u{10d8}"),
+    ('\u{1c99}', "\u{10d9}"),
+    ('\u{1c9a}', "\u{10da}"),
+    ('\u{1c9b}', "\u{10db}"),
+    ('\u{1c9c}', "\u{10dc}"),
+    ('\u{1c9d}', "\u{10dd}"),
+    ('\u{1c9e}', "\u{10de}"),
+    ('\u{1c9f}', "\u{10df}"),
+    ('\u{1ca0}', "\u{10e0}"),
+    ('\u{1ca1}', "\u{10e1}"),
+    ('\u{1ca2}', "\u{10e2}"),
+    ('\u{1ca3}', "\u{10e3}"),
+    ('\u{1ca4}', "\u{10e4}"),
+    ('\u{1ca5}', "\u{10e5}"),
+    ('\u{1ca6}', "\u{10e6}"),
+    ('\u{1ca7}', "\u{10e7}"),
+    ('\u{1ca8}', "\u{10e8}"),
+    ('\u{1ca9}', "\u{10e9}"),
+    ('\u{1caa}', "\u{10ea}"),
+    ('\u{1cab}', "\u{10eb}"),
+    ('\u{1cac}', "\u{10ec}"),
+    ('\u{1cad}', "\u{10ed}"),
+    ('\u{1cae}', "\u{10ee}"),
+    ('\u{1caf}', "\u{10ef}"),
+    ('\u{1cb0}', "\u{10f0}"),
+    ('\u{1cb1}', "\u{10f1}"),
+    ('\u{1cb2}', "\u{10f2}"),
+    ('\u{1cb3}', "\u{10f3}"),
+    ('\u{1cb4}', "\u{10f4}"),
+    ('\u{1cb5}', "\u{10f5}"),
+    ('\u{1cb6}', "\u{10f6}"),
+    ('\u{1cb7}', "\u{10f7}"),
+    ('\u{1cb8}', "\u{10f8}"),
+    ('\u{1cb9}', "\u{10f9}"),
+    ('\u{1cba}', "\u{10fa}"),
+    ('\u{1cbd}', "\u{10fd}"),
+    ('\u{1cbe}', "\u{10fe}"),
+    ('\u{1cbf}', "\u{10ff}"),
+    ('\u{1e00}', "a\u{325}"),
+    ('\u{1e01}', "a\u{325}"),
+    ('\u{1e02}', "b\u{307}"),
+    ('\u{1e03}', "b\u{307}"),
+    ('\u{1e04}', "b\u{323}"),
+    ('\u{1e05}', "b\u{323}"),
+    ('\u{1e06}', "b\u{331}"),
+    ('\u{1e07}', "b\u{331}"),
+    ('\u{1e08}', "c\u{327}\u{301}"),
+    ('\u{1e09}', "c\u{327}\u{301}"),
+    ('\u{1e0a}', "d\u{307}"),
+    ('\u{1e0b}', "d\u{307}"),
+    ('\u{1e0c}', "d\u{323}"),
+    ('\u{1e0d}', "d\u{323}"),
+    ('\u{1e0e}', "d\u{331}"),
+    ('\u{1e0f}', "d\u{331}"),
+    ('\u{1e10}', "d\u{327}"),
+    ('\u{1e11}', "d\u{327}"),
+    ('\u{1e12}', "d\u{32d}"),
+    ('\u{1e13}', "d\u{32d}"),
+    ('\u{1e14}', "e\u{304}\u{300}"),
+    ('\u{1e15}', "e\u{304}\u{300}"),
+    ('\u{1e16}', "e\u{304}\u{301}"),
+    ('\u{1e17}', "e\u{304}\u{301}"),
+    ('\u{1e18}', "e\u{32d}"),
+    ('\u{1e19}', "e\u{32d}"),
+    ('\u{1e1a}', "e\u{330}"),
+    ('\u{1e1b}', "e\u{330}"),
+    ('\u{1e1c}', "e\u{327}\u{306}"),
+    ('\u{1e1d}', "e\u{327}\u{306}"),
+    ('\u{1e1e}', "f\u{307}"),
+    ('\u{1e1f}', "f\u{307}"),
+    ('\u{1e20}', "g\u{304}"),
+    ('\u{1e21}', "g\u{304}"),
+    ('\u{1e22}', "h\u{307}"),
+    ('\u{1e23}', "h\u{307}"),
+    ('\u{1e24}', "h\u{323}"),
+    ('\u{1e25}', "h\u{323}"),
+    ('\u{1e26}', "h\u{308}"),
+    ('\u{1e27}', "h\u{308}"),
+    ('\u{1e28}', "h\u{327}"),
+    ('\u{1e29}', "h\u{327}"),
+    ('\u{1e2a}', "h\u{32e}"),
+    ('\u{1e2b}', "h\u{32e}"),
+    ('\u{1e2c}', "i\u{330}"),
+    ('\u{1e2d}', "i\u{330}"),
+    ('\u{1e2e}', "i\u{308}\u{301}"),
+    ('\u{1e2f}', "i\u{308}\u{301}"),
+    ('\u{1e30}', "k\u{301}"),
+    ('\u{1e31}', "k\u{301}"),
+    ('\u{1e32}', "k\u{323}"),
+    ('\u{1e33}', "k\u{323}"),
+    ('\u{1e34}', "k\u{331}"),
+    ('\u{1e35}', "k\u{331}"),
+    ('\u{1e36}', "l\u{323}"),
+    ('\u{1e37}', "l\u{323}"),
+    ('\u{1e38}', "l\u{323}\u{304}"),
+    ('\u{1e39}', "l\u{323}\u{304}"),
+    ('\u{1e3a}', "l\u{331}"),
+    ('\u{1e3b}', "l\u{331}"),
+    ('\u{1e3c}', "l\u{32d}"),
+    ('\u{1e3d}', "l\u{32d}"),
+    ('\u{1e3e}', "m\u{301}"),
+    ('\u{1e3f}', "m\u{301}"),
+    ('\u{1e40}', "m\u{307}"),
+    ('\u{1e41}', "m\u{307}"),
+    ('\u{1e42}', "m\u{323}"),
+    ('\u{1e43}', "m\u{323}"),
+    ('\u{1e44}', "n\u{307}"),
+    ('\u{1e45}', "n\u{307}"),
+    ('\u{1e46}', "n\u{323}"),
+    ('\u{1e47}', "n\u{323}"),
+    ('\u{1e48}', "n\u{331}"),
+    ('\u{1e49}', "n\u{331}"),
+    ('\u{1e4a}', "n\u{32d}"),
+    ('\u{1e4b}', "n\u{32d}"),
+    ('\u{1e4c}', "o\u{303}\u{301}"),
+    ('\u{1e4d}', "o\u{303}\u{301}"),
+    ('\u{1e4e}', "o\u{303}\u{308}"),
+    ('\u{1e4f}', "o\u{303}\u{308}"),
+    ('\u{1e50}', "o\u{304}\u{300}"),
+    ('\u{1e51}', "o\u{304}\u{300}"),
+    ('\u{1e52}', "o\u{304}\u{301}"),
+    ('\u{1e53}', "o\u{304}\u{301}"),
+    ('\u{1e54}', "p\u{301}"),
+    ('\u{1e55}', "p\u{301}"),
+    ('\u{1e56}', "p\u{307}"),
+    ('\u{1e57}', "p\u{307}"),
+    ('\u{1e58}', "r\u{307}"),
+    ('\u{1e59}', "r\u{307}"),
+    ('\u{1e5a}', "r\u{323}"),
+    ('\u{1e5b}', "r\u{323}"),
+    ('\u{1e5c}', "r\u{323}\u{304}"),
+    ('\u{1e5d}', "r\u{323}\u{304}"),
+    ('\u{1e5e}', "r\u{331}"),
+    ('\u{1e5f}', "r\u{331}"),
+    ('\u{1e60}', "s\u{307}"),
+    ('\u{1e61}', "s\u{307}"),
+    ('\u{1e62}', "s\u{323}"),
+    ('\u{1e63}', "s\u{323}"),
+    ('\u{1e64}', "s\u{301}\u{307}"),
+    ('\u{1e65}', "s\u{301}\u{307}"),
+    ('\u{1e66}', "s\u{30c}\u{307}"),
+    ('\u{1e67}', "s\u{30c}\u{307}"),
+    ('\u{1e68}', "s\u{323}\u{307}"),
+    ('\u{1e69}', "s\u{323}\u{307}"),
+    ('\u{1e6a}', "t\u{307}"),
+    ('\u{1e6b}', "t\u{307}"),
+    ('\u{1e6c}', "t\u{323}"),
+    ('\u{1e6d}', "t\u{323}"),
+    ('\u{1e6e}', "t\u{331}"),
+    ('\u{1e6f}', "t\u{331}"),
+    ('\u{1e70}', "t\u{32d}"),
+    ('\u{1e71}', "t\u{32d}"),
+    ('\u{1e72}', "u\u{324}"),
+    ('\u{1e73}', "u\u{324}"),
+    ('\u{1e74}', "u\u{330}"),
+    ('\u{1e75}', "u\u{330}"),
+    ('\u{1e76}', "u\u{32d}"),
+    ('\u{1e77}', "u\u{32d}"),
+    ('\u{1e78}', "u\u{303}\u{301}"),
+    ('\u{1e79}', "u\u{303}\u{301}"),
+    ('\u{1e7a}', "u\u{304}\u{308}"),
+    ('\u{1e7b}', "u\u{304}\u{308}"),
+    ('\u{1e7c}', "v\u{303}"),
+    ('\u{1e7d}', "v\u{303}"),
+    ('\u{1e7e}', "v\u{323}"),
+    ('\u{1e7f}', "v\u{323}"),
+    ('\u{1e80}', "w\u{300}"),
+    ('\u{1e81}', "w\u{300}"),
+    ('\u{1e82}', "w\u{301}"),
+    ('\u{1e83}', "w\u{301}"),
+    ('\u{1e84}', "w\u{308}"),
+    ('\u{1e85}', "w\u{308}"),
+    ('\u{1e86}', "w\u{307}"),
+    ('\u{1e87}', "w\u{307}"),
+    ('\u{1e88}', "w\u{323}"),
+    ('\u{1e89}', "w\u{323}"),
+    ('\u{1e8a}', "x\u{307}"),
+    ('\u{1e8b}', "x\u{307}"),
+    ('\u{1e8c}', "x\u{308}"),
+    ('\u{1e8d}', "x\u{308}"),
+    ('\u{1e8e}', "y\u{307}"),
+    ('\u{1e8f}', "y\u{307}"),
+    ('\u{1e90}', "z\u{302}"),
+    ('\u{1e91}', "z\u{302}"),
+    ('\u{1e92}', "z\u{323}"),
+    ('\u{1e93}', "z\u{323}"),
+    ('\u{1e94}', "z\u{331}"),
+    ('\u{1e95}', "z\u{331}"),
+    ('\u{1e96}', "h\u{331}"),
+    ('\u{1e97}', "t\u{308}"),
+    ('\u{1e98}', "w\u{30a}"),
+    ('\u{1e99}', "y\u{30a}"),
+    ('\u{1e9a}', "a\u{2be}"),
+    ('\u{1e9b}', "s\u{307}"),
+    ('\u{1e9e}', "ss"),
+    ('\u{1ea0}', "a\u{323}"),
+    ('\u{1ea1}', "a\u{323}"),
+    ('\u{1ea2}', "a\u{309}"),
+    ('\u{1ea3}', "a\u{309}"),
+    ('\u{1ea4}', "a\u{302}\u{301}"),
+    ('\u{1ea5}', "a\u{302}\u{301}"),
+    ('\u{1ea6}', "a\u{302}\u{300}"),
+    ('\u{1ea7}', "a\u{302}\u{300}"),
+    ('\u{1ea8}', "a\u{302}\u{309}"),
+    ('\u{1ea9}', "a\u{302}\u{309}"),
+    ('\u{1eaa}', "a\u{302}\u{303}"),
+    ('\u{1eab}', "a\u{302}\u{303}"),
+    ('\u{1eac}', "a\u{323}\u{302}"),
+    ('\u{1ead}', "a\u{323}\u{302}"),
+    ('\u{1eae}', "a\u{306}\u{301}"),
+    ('\u{1eaf}', "a\u{306}\u{301}"),
+    ('\u{1eb0}', "a\u{306}\u{300}"),
+    ('\u{1eb1}', "a\u{306}\u{300}"),
+    ('\u{1eb2}', "a\u{306}\u{309}"),
+    ('\u{1eb3}', "a\u{306}\u{309}"),
+    ('\u{1eb4}', "a\u{306}\u{303}"),
+    ('\u{1eb5}', "a\u{306}\u{303}"),
+    ('\u{1eb6}', "a\u{323}\u{306}"),
+    ('\u{1eb7}', "a\u{323}\u{306}"),
+    ('\u{1eb8}', "e\u{323}"),
+    ('\u{1eb9}', "e\u{323}"),
+    ('\u{1eba}', "e\u{309}"),
+    ('\u{1ebb}', "e\u{309}"),
+    ('\u{1ebc}', "e\u{303}"),
+    ('\u{1ebd}', "e\u{303}"),
+    ('\u{1ebe}', "e\u{302}\u{301}"),
+    ('\u{1ebf}', "e\u{302}\u{301}"),
+    ('\u{1ec0}', "e\u{302}\u{300}"),
+    ('\u{1ec1}', "e\u{302}\u{300}"),
+    ('\u{1ec2}', "e\u{302}\u{309}"),
+    ('\u{1ec3}', "e\u{302}\u{309}"),
+    ('\u{1ec4}', "e\u{302}\u{303}"),
+    ('\u{1ec5}', "e\u{302}\u{303}"),
+    ('\u{1ec6}', "e\u{323}\u{302}"),
+    ('\u{1ec7}', "e\u{323}\u{302}"),
+    ('\u{1ec8}', "i\u{309}"),
+    ('\u{1ec9}', "i\u{309}"),
+    ('\u{1eca}', "i\u{323}"),
+    ('\u{1ecb}', "i\u{323}"),
+    ('\u{1ecc}', "o\u{323}"),
+    ('\u{1ecd}', "o\u{323}"),
+    ('\u{1ece}', "o\u{309}"),
+    ('\u{1ecf}', "o\u{309}"),
+    ('\u{1ed0}', "o\u{302}\u{301}"),
+    ('\u{1ed1}', "o\u{302}\u{301}"),
+    ('\u{1ed2}', "o\u{302}\u{300}"),
+    ('\u{1ed3}', "o\u{302}\u{300}"),
+    ('\u{1ed4}', "o\u{302}\u{309}"),
+    ('\u{1ed5}', "o\u{302}\u{309}"),
+    ('\u{1ed6}', "o\u{302}\u{303}"),
+    ('\u{1ed7}', "o\u{302}\u{303}"),
+    ('\u{1ed8}', "o\u{323}\u{302}"),
+    ('\u{1ed9}', "o\u{323}\u{302}"),
+    ('\u{1eda}', "o\u{31b}\u{301}"),
+    ('\u{1edb}', "o\u{31b}\u{301}"),
+    ('\u{1edc}', "o\u{31b}\u{300}"),
+    ('\u{1edd}', "o\u{31b}\u{300}"),
+    ('\u{1ede}', "o\u{31b}\u{309}"),
+    ('\u{1edf}', "o\u{31b}\u{309}"),
+    ('\u{1ee0}', "o\u{31b}\u{303}"),
+    ('\u{1ee1}', "o\u{31b}\u{303}"),
+    ('\u{1ee2}', "o\u{31b}\u{323}"),
+    ('\u{1ee3}', "o\u{31b}\u{323}"),
+    ('\u{1ee4}', "u\u{323}"),
+    ('\u{1ee5}', "u\u{323}"),
+    ('\u{1ee6}', "u\u{309}"),
+    ('\u{1ee7}', "u\u{309}"),
+    ('\u{1ee8}', "u\u{31b}\u{301}"),
+    ('\u{1ee9}', "u\u{31b}\u{301}"),
+    ('\u{1eea}', "u\u{31b}\u{300}"),
+    ('\u{1eeb}', "u\u{31b}\u{300}"),
+    ('\u{1eec}', "u\u{31b}\u{309}"),
+    ('\u{1eed}', "u\u{31b}\u{309}"),
+    ('\u{1eee}', "u\u{31b}\u{303}"),
+    ('\u{1eef}', "u\u{31b}\u{303}"),
+    ('\u{1ef0}', "u\u{31b}\u{323}"),
+    ('\u{1ef1}', "u\u{31b}\u{323}"),
+    ('\u{1ef2}', "y\u{300}"),
+    ('\u{1ef3}', "y\u{300}"),
+    ('\u{1ef4}', "y\u{323}"),
+    ('\u{1ef5}', "y\u{323}"),
+    ('\u{1ef6}', "y\u{309}"),
+    ('\u{1ef7}', "y\u{309}"),
+    ('\u{1ef8}', "y\u{303}"),
+    ('\u{1ef9}', "y\u{303}"),
+    ('\u{1efa}', "\u{1efb}"),
+    ('\u{1efc}', "\u{1efd}"),
+    ('\u{1efe}', "\u{1eff}"),
+    ('\u{1f00}', "\u{3b1}\u{313}"),
+    ('\u{1f01}', "\u{3b1}\u{314}"),
+    ('\u{1f02}', "\u{3b1}\u{313}\u{300}"),
+    ('\u{1f03}', "\u{3b1}\u{314}\u{300}"),
+    ('\u{1f04}', "\u{3b1}\u{313}\u{301}"),
+    ('\u{1f05}', "\u{3b1}\u{314}\u{301}"),
+    ('\u{1f06}', "\u{3b1}\u{313}\u{342}"),
+    ('\u{1f07}', "\u{3b1}\u{314}\u{342}"),
+    ('\u{1f08}', "\u{3b1}\u{313}"),
+    ('\u{1f09}', "\u{3b1}\u{314}"),
+    ('\u{1f0a}', "\u{3b1}\u{313}\u{300}"),
+    ('\u{1f0b}', "\u{3b1}\u{314}\u{300}"),
+    ('\u{1f0c}', "\u{3b1}\u{313}\u{301}"),
+    ('\u{1f0d}', "\u{3b1}\u{314}\u{301}"),
+    ('\u{1f0e}', "\u{3b1}\u{313}\u{342}"),
+    ('\u{1f0f}', "\u{3b1}\u{314}\u{342}"),
+    ('\u{1f10}', "\u{3b5}\u{313}"),
+    ('\u{1f11}', "\u{3b5}\u{314}"),
+    ('\u{1f12}', "\u{3b5}\u{313}\u{300}"),
+    ('\u{1f13}', "\u{3b5}\u{314}\u{300}"),
+    ('\u{1f14}', "\u{3b5}\u{313}\u{301}"),
+    ('\u{1f15}', "\u{3b5}\u{314}\u{301}"),
+    ('\u{1f18}', "\u{3b5}\u{313}"),
+    ('\u{1f19}', "\u{3b5}\u{314}"),
+    ('\u{1f1a}', "\u{3b5}\u{313}\u{300}"),
+    ('\u{1f1b}', "\u{3b5}\u{314}\u{300}"),
+    ('\u{1f1c}', "\u{3b5}\u{313}\u{301}"),
+    ('\u{1f1d}', "\u{3b5}\u{314}\u{301}"),
+    ('\u{1f20}', "\u{3b7}\u{313}"),
+    ('\u{1f21}', "\u{3b7}\u{314}"),
+    ('\u{1f22}', "\u{3b7}\u{313}\u{300}"),
+    ('\u{1f23}', "\u{3b7}\u{314}\u{300}"),
+    ('\u{1f24}', "\u{3b7}\u{313}\u{301}"),
+    ('\u{1f25}', "\u{3b7}\u{314}\u{301}"),
+    ('\u{1f26}', "\u{3b7}\u{313}\u{342}"),
+    ('\u{1f27}', "\u{3b7}\u{314}\u{342}"),
+    ('\u{1f28}', "\u{3b7}\u{313}"),
+    ('\u{1f29}', "\u{3b7}\u{314}"),
+    ('\u{1f2a}', "\u{3b7}\u{313}\u{300}"),
+    ('\u{1f2b}', "\u{3b7}\u{314}\u{300}"),
+    ('\u{1f2c}', "\u{3b7}\u{313}\u{301}"),
+    ('\u{1f2d}', "\u{3b7}\u{314}\u{301}"),
+    ('\u{1f2e}', "\u{3b7}\u{313}\u{342}"),
+    ('\u{1f2f}', "\u{3b7}\u{314}\u{342}"),
+    ('\u{1f30}', "\u{3b9}\u{313}"),
+    ('\u{1f31}', "\u{3b9}\u{314}"),
+    ('\u{1f32}', "\u{3b9}\u{313}\u{300}"),
+    ('\u{1f33}', "\u{3b9}\u{314}\u{300}"),
+    ('\u{1f34}', "\u{3b9}\u{313}\u{301}"),
+    ('\u{1f35}', "\u{3b9}\u{314}\u{301}"),
+    ('\u{1f36}', "\u{3b9}\u{313}\u{342}"),
+    ('\u{1f37}', "\u{3b9}\u{314}\u{342}"),
+    ('\u{1f38}', "\u{3b9}\u{313}"),
+    ('\u{1f39}', "\u{3b9}\u{314}"),
+    ('\u{1f3a}', "\u{3b9}\u{313}\u{300}"),
+    ('\u{1f3b}', "\u{3b9}\u{314}\u{300}"),
+    ('\u{1f3c}', "\u{3b9}\u{313}\u{301}"),
+    ('\u{1f3d}', "\u{3b9}\u{314}\u{301}"),
+    ('\u{1f3e}', "\u{3b9}\u{313}\u{342}"),
+    ('\u{1f3f}', "\u{3b9}\u{314}\u{342}"),
+    ('\u{1f40}', "\u{3bf}\u{313}"),
+    ('\u{1f41}', "\u{3bf}\u{314}"),
+    ('\u{1f42}', "\u{3bf}\u{313}\u{300}"),
+    ('\u{1f43}', "\u{3bf}\u{314}\u{300}"),
+    ('\u{1f44}', "\u{3bf}\u{313}\u{301}"),
+    ('\u{1f45}', "\u{3bf}\u{314}\u{301}"),
+    ('\u{1f48}', "\u{3bf}\u{313}"),
+    ('\u{1f49}', "\u{3bf}\u{314}"),
+    ('\u{1f4a}', "\u{3bf}\u{313}\u{300}"),
+    ('\u{1f4b}', "\u{3bf}\u{314}\u{300}"),
+    ('\u{1f4c}', "\u{3bf}\u{313}\u{301}"),
+    ('\u{1f4d}', "\u{3bf}\u{314}\u{301}"),
+    ('\u{1f50}', "\u{3c5}\u{313}"),
+    ('\u{1f51}', "\u{3c5}\u{314}"),
+    ('\u{1f52}', "\u{3c5}\u{313}\u{300}"),
+    ('\u{1f53}', "\u{3c5}\u{314}\u{300}"),
+    ('\u{1f54}', "\u{3c5}\u{313}\u{301}"),
+    ('\u{1f55}', "\u{3c5}\u{314}\u{301}"),
+    ('\u{1f56}', "\u{3c5}\u{313}\u{342}"),
+    ('\u{1f57}', "\u{3c5}\u{314}\u{342}"),
+    ('\u{1f59}', "\u{3c5}\u{314}"),
+    ('\u{1f5b}', "\u{3c5}\u{314}\u{300}"),
+    ('\u{1f5d}', "\u{3c5}\u{314}\u{301}"),
+    ('\u{1f5f}', "\u{3c5}\u{314}\u{342}"),
+    ('\u{1f60}', "\u{3c9}\u{313}"),
+    ('\u{1f61}', "\u{3c9}\u{314}"),
+    ('\u{1f62}', "\u{3c9}\u{313}\u{300}"),
+    ('\u{1f63}', "\u{3c9}\u{314}\u{300}"),
+    ('\u{1f64}', "\u{3c9}\u{313}\u{301}"),
+    ('\u{1f65}', "\u{3c9}\u{314}\u{301}"),
+    ('\u{1f66}', "\u{3c9}\u{313}\u{342}"),
+    ('\u{1f67}', "\u{3c9}\u{314}\u{342}"),
+    ('\u{1f68}', "\u{3c9}\u{313}"),
+    ('\u{1f69}', "\u{3c9}\u{314}"),
+    ('\u{1f6a}', "\u{3c9}\u{313}\u{300}"),
+    ('\u{1f6b}', "\u{3c9}\u{314}\u{300}"),
+    ('\u{1f6c}', "\u{3c9}\u{313}\u{301}"),
+    ('\u{1f6d}', "\u{3c9}\u{314}\u{301}"),
+    ('\u{1f6e}', "\u{3c9}\u{313}\u{342}"),
+    ('\u{1f6f}', "\u{3c9}\u{314}\u{342}"),
+    ('\u{1f70}', "\u{3b1}\u{300}"),
+    ('\u{1f71}', "\u{3b1}\u{301}"),
+    ('\u{1f72}', "\u{3b5}\u{300}"),
+    ('\u{1f73}', "\u{3b5}\u{301}"),
+    ('\u{1f74}', "\u{3b7}\u{300}"),
+    ('\u{1f75}', "\u{3b7}\u{301}"),
+    ('\u{1f76}', "\u{3b9}\u{300}"),
+    ('\u{1f77}', "\u{3b9}\u{301}"),
+    ('\u{1f78}', "\u{3bf}\u{300}"),
+    ('\u{1f79}', "\u{3bf}\u{301}"),
+    ('\u{1f7a}', "\u{3c5}\u{300}"),
+    ('\u{1f7b}', "\u{3c5}\u{301}"),
+    ('\u{1f7c}', "\u{3c9}\u{300}"),
+    ('\u{1f7d}', "\u{3c9}\u{301}"),
+    ('\u{1f80}', "\u{3b1}\u{313}\u{3b9}"),
+    ('\u{1f81}', "\u{3b1}\u{314}\u{3b9}"),
+    ('\u{1f82}', "\u{3b1}\u{313}\u{300}\u{3b9}"),
+    ('\u{1f83}', "\u{3b1}\u{314}\u{300}\u{3b9}"),
+    ('\u{1f84}', "\u{3b1}\u{313}\u{301}\u{3b9}"),
+    ('\u{1f85}', "\u{3b1}\u{314}\u{301}\u{3b9}"),
+    ('\u{1f86}', "\u{3b1}\u{313}\u{342}\u{3b9}"),
+    ('\u{1f87}', "\u{3b1}\u{314}\u{342}\u{3b9}"),
+    ('\u{1f88}', "\u{3b1}\u{313}\u{3b9}"),
+    ('\u{1f89}', "\u{3b1}\u{314}\u{3b9}"),
+    ('\u{1f8a}', "\u{3b1}\u{313}\u{300}\u{3b9}"),
+    ('\u{1f8b}', "\u{3b1}\u{314}\u{300}\u{3b9}"),
+    ('\u{1f8c}', "\u{3b1}\u{313}\u{301}\u{3b9}"),
+    ('\u{1f8d}', "\u{3b1}\u{314}\u{301}\u{3b9}"),
+    ('\u{1f8e}', "\u{3b1}\u{313}\u{342}\u{3b9}"),
+    ('\u{1f8f}', "\u{3b1}\u{314}\u{342}\u{3b9}"),
+    ('\u{1f90}', "\u{3b7}\u{313}\u{3b9}"),
+    ('\u{1f91}', "\u{3b7}\u{314}\u{3b9}"),
+    ('\u{1f92}', "\u{3b7}\u{313}\u{300}\u{3b9}"),
+    ('\u{1f93}', "\u{3b7}\u{314}\u{300}\u{3b9}"),
+    ('\u{1f94}', "\u{3b7}\u{313}\u{301}\u{3b9}"),
+    ('\u{1f95}', "\u{3b7}\u{314}\u{301}\u{3b9}"),
+    ('\u{1f96}', "\u{3b7}\u{313}\u{342}\u{3b9}"),
+    ('\u{1f97}', "\u{3b7}\u{314}\u{342}\u{3b9}"),
+    ('\u{1f98}', "\u{3b7}\u{313}\u{3b9}"),
+    ('\u{1f99}', "\u{3b7}\u{314}\u{3b9}"),
+    ('\u{1f9a}', "\u{3b7}\u{313}\u{300}\u{3b9}"),
+    ('\u{1f9b}', "\u{3b7}\u{314}\u{300}\u{3b9}"),
+    ('\u{1f9c}', "\u{3b7}\u{313}\u{301}\u{3b9}"),
+    ('\u{1f9d}', "\u{3b7}\u{314}\u{301}\u{3b9}"),
+    ('\u{1f9e}', "\u{3b7}\u{313}\u{342}\u{3b9}"),
+    ('\u{1f9f}', "\u{3b7}\u{314}\u{342}\u{3b9}"),
+    ('\u{1fa0}', "\u{3c9}\u{313}\u{3b9}"),
+    ('\u{1fa1}', "\u{3c9}\u{314}\u{3b9}"),
+    ('\u{1fa2}', "\u{3c9}\u{313}\u{300}\u{3b9}"),
+    ('\u{1fa3}', "\u{3c9}\u{314}\u{300}\u{3b9}"),
+    ('\u{1fa4}', "\u{3c9}\u{313}\u{301}\u{3b9}"),
+    ('\u{1fa5}', "\u{3c9}\u{314}\u{301}\u{3b9}"),
+    ('\u{1fa6}', "\u{3c9}\u{313}\u{342}\u{3b9}"),
+    ('\u{1fa7}', "\u{3c9}\u{314}\u{342}\u{3b9}"),
+    ('\u{1fa8}', "\u{3c9}\u{313}\u{3b9}"),
+    ('\u{1fa9}', "\u{3c9}\u{314}\u{3b9}"),
+    ('\u{1faa}', "\u{3c9}\u{313}\u{300}\u{3b9}"),
+    ('\u{1fab}', "\u{3c9}\u{314}\u{300}\u{3b9}"),
+    ('\u{1fac}', "\u{3c9}\u{313}\u{301}\u{3b9}"),
+    ('\u{1fad}', "\u{3c9}\u{314}\u{301}\u{3b9}"),
+    ('\u{1fae}', "\u{3c9}\u{313}\u{342}\u{3b9}"),
+    ('\u{1faf}', "\u{3c9}\u{314}\u{342}\u{3b9}"),
+    ('\u{1fb0}', "\u{3b1}\u{306}"),
+    ('\u{1fb1}', "\u{3b1}\u{304}"),
+    ('\u{1fb2}', "\u{3b1}\u{300}\u{3b9}"),
+    ('\u{1fb3}', "\u{3b1}\u{3b9}"),
+    ('\u{1fb4}', "\u{3b1}\u{301}\u{3b9}"),
+    ('\u{1fb6}', "\u{3b1}\u{342}"),
+    ('\u{1fb7}', "\u{3b1}\u{342}\u{3b9}"),
+    ('\u{1fb8}', "\u{3b1}\u{306}"),
+    ('\u{1fb9}', "\u{3b1}\u{304}"),
+    ('\u{1fba}', "\u{3b1}\u{300}"),
+    ('\u{1fbb}', "\u{3b1}\u{301}"),
+    ('\u{1fbc}', "\u{3b1}\u{3b9}"),
+    ('\u{1fbe}', "\u{3b9}"),
+    ('\u{1fc1}', "\u{a8}\u{342}"),
+    ('\u{1fc2}', "\u{3b7}\u{300}\u{3b9}"),
+    ('\u{1fc3}', "\u{3b7}\u{3b9}"),
+    ('\u{1fc4}', "\u{3b7}\u{301}\u{3b9}"),
+    ('\u{1fc6}', "\u{3b7}\u{342}"),
+    ('\u{1fc7}', "\u{3b7}\u{342}\u{3b9}"),
+    ('\u{1fc8}', "\u{3b5}\u{300}"),
+    ('\u{1fc9}', "\u{3b5}\u{301}"),
+    ('\u{1fca}', "\u{3b7}\u{300}"),
+    ('\u{1fcb}', "\u{3b7}\u{301}"),
+    ('\u{1fcc}', "\u{3b7}\u{3b9}"),
+    ('\u{1fcd}', "\u{1fbf}\u{300}"),
+    ('\u{1fce}', "\u{1fbf}\u{301}"),
+    ('\u{1fcf}', "\u{1fbf}\u{342}"),
+    ('\u{1fd0}', "\u{3b9}\u{306}"),
+    ('\u{1fd1}', "\u{3b9}\u{304}"),
+    ('\u{1fd2}', "\u{3b9}\u{308}\u{300}"),
+    ('\u{1fd3}', "\u{3b9}\u{308}\u{301}"),
+    ('\u{1fd6}', "\u{3b9}\u{342}"),
+    ('\u{1fd7}', "\u{3b9}\u{308}\u{342}"),
+    ('\u{1fd8}', "\u{3b9}\u{306}"),
+    ('\u{1fd9}', "\u{3b9}\u{304}"),
+    ('\u{1fda}', "\u{3b9}\u{300}"),
+    ('\u{1fdb}', "\u{3b9}\u{301}"),
+    ('\u{1fdd}', "\u{1ffe}\u{300}"),
+    ('\u{1fde}', "\u{1ffe}\u{301}"),
+    ('\u{1fdf}', "\u{1ffe}\u{342}"),
+    ('\u{1fe0}', "\u{3c5}\u{306}"),
+    ('\u{1fe1}', "\u{3c5}\u{304}"),
+    ('\u{1fe2}', "\u{3c5}\u{308}\u{300}"),
+    ('\u{1fe3}', "\u{3c5}\u{308}\u{301}"),
+    ('\u{1fe4}', "\u{3c1}\u{313}"),
+    ('\u{1fe5}', "\u{3c1}\u{314}"),
+    ('\u{1fe6}', "\u{3c5}\u{342}"),
+    ('\u{1fe7}', "\u{3c5}\u{308}\u{342}"),
+    ('\u{1fe8}', "\u{3c5}\u{306}"),
+    ('\u{1fe9}', "\u{3c5}\u{304}"),
+    ('\u{1fea}', "\u{3c5}\u{300}"),
+    ('\u{1feb}', "\u{3c5}\u{301}"),
+    ('\u{1fec}', "\u{3c1}\u{314}"),
+    ('\u{1fed}', "\u{a8}\u{300}"),
+    ('\u{1fee}', "\u{a8}\u{301}"),
+    ('\u{1fef}', "\u{60}"),
+    ('\u{1ff2}', "\u{3c9}\u{300}\u{3b9}"),
+    ('\u{1ff3}', "\u{3c9}\u{3b9}"),
+    ('\u{1ff4}', "\u{3c9}\u{301}\u{3b9}"),
+    ('\u{1ff6}', "\u{3c9}\u{342}"),
+    ('\u{1ff7}', "\u{3c9}\u{342}\u{3b9}"),
+    ('\u{1ff8}', "\u{3bf}\u{300}"),
+    ('\u{1ff9}', "\u{3bf}\u{301}"),
+    ('\u{1ffa}', "\u{3c9}\u{300}"),
+    ('\u{1ffb}', "\u{3c9}\u{301}"),
+    ('\u{1ffc}', "\u{3c9}\u{3b9}"),
+    ('\u{1ffd}', "\u{b4}"),
+    ('\u{2000}', "\u{2002}"),
+    ('\u{2001}', "\u{2003}"),
+    ('\u{2126}', "\u{3c9}"),
+    ('\u{212a}', "k"),
+    ('\u{212b}', "a\u{30a}"),
+    ('\u{2132}', "\u{214e}"),
+    ('\u{2160}', "\u{2170}"),
+    ('\u{2161}', "\u{2171}"),
+    ('\u{2162}', "\u{2172}"),
+    ('\u{2163}', "\u{2173}"),
+    ('\u{2164}', "\u{2174}"),
+    ('\u{2165}', "\u{2175}"),
+    ('\u{2166}', "\u{2176}"),
+    ('\u{2167}', "\u{2177}"),
+    ('\u{2168}', "\u{2178}"),
+    ('\u{2169}', "\u{2179}"),
+    ('\u{216a}', "\u{217a}"),
+    ('\u{216b}', "\u{217b}"),
+    ('\u{216c}', "\u{217c}"),
+    ('\u{216d}', "\u{217d}"),
+    ('\u{216e}', "\u{217e}"),
+    ('\u{216f}', "\u{217f}"),
+    ('\u{2183}', "\u{2184}"),
+    ('\u{219a}', "\u{2190}\u{338}"),
+    ('\u{219b}', "\u{2192}\u{338}"),
+    ('\u{21ae}', "\u{2194}\u{338}"),
+    ('\u{21cd}', "\u{21d0}\u{338}"),
+    ('\u{21ce}', "\u{21d4}\u{338}"),
+    ('\u{21cf}', "\u{21d2}\u{338}"),
+    ('\u{2204}', "\u{2203}\u{338}"),
+    ('\u{2209}', "\u{2208}\u{338}"),
+    ('\u{220c}', "\u{220b}\u{338}"),
+    ('\u{2224}', "\u{2223}\u{338}"),
+    ('\u{2226}', "\u{2225}\u{338}"),
+    ('\u{2241}', "\u{223c}\u{338}"),
+    ('\u{2244}', "\u{2243}\u{338}"),
+    ('\u{2247}', "\u{2245}\u{338}"),
+    ('\u{2249}', "\u{2248}\u{338}"),
+    ('\u{2260}', "\u{3d}\u{338}"),
+    ('\u{2262}', "\u{2261}\u{338}"),
+    ('\u{226d}', "\u{224d}\u{338}"),
+    ('\u{226e}', "\u{3c}\u{338}"),
+    ('\u{226f}', "\u{3e}\u{338}"),
+    ('\u{2270}', "\u{2264}\u{338}"),
+    ('\u{2271}', "\u{2265}\u{338}"),
+    ('\u{2274}', "\u{2272}\u{338}"),
+    ('\u{2275}', "\u{2273}\u{338}"),
+    ('\u{2278}', "\u{2276}\u{338}"),
+    ('\u{2279}', "\u{2277}\u{338}"),
+    ('\u{2280}', "\u{227a}\u{338}"),
+    ('\u{2281}', "\u{227b}\u{338}"),
+    ('\u{2284}', "\u{2282}\u{338}"),
+    ('\u{2285}', "\u{2283}\u{338}"),
+    ('\u{2288}', "\u{2286}\u{338}"),
+    ('\u{2289}', "\u{2287}\u{338}"),
+    ('\u{22ac}', "\u{22a2}\u{338}"),
+    ('\u{22ad}', "\u{22a8}\u{338}"),
+    ('\u{22ae}', "\u{22a9}\u{338}"),
+    ('\u{22af}', "\u{22ab}\u{338}"),
+    ('\u{22e0}', "\u{227c}\u{338}"),
+    ('\u{22e1}', "\u{227d}\u{338}"),
+    ('\u{22e2}', "\u{2291}\u{338}"),
+    ('\u{22e3}', "\u{2292}\u{338}"),
+    ('\u{22ea}', "\u{22b2}\u{338}"),
+    ('\u{22eb}', "\u{22b3}\u{338}"),
+    ('\u{22ec}', "\u{22b4}\u{338}"),
+    ('\u{22ed}', "\u{22b5}\u{338}"),
+    ('\u{2329}', "\u{3008}"),
+    ('\u{232a}', "\u{3009}"),
+    ('\u{24b6}', "\u{24d0}"),
+    ('\u{24b7}', "\u{24d1}"),
+    ('\u{24b8}', "\u{24d2}"),
+    ('\u{24b9}', "\u{24d3}"),
+    ('\u{24ba}', "\u{24d4}"),
+    ('\u{24bb}', "\u{24d5}"),
+    ('\u{24bc}', "\u{24d6}"),
+    ('\u{24bd}', "\u{24d7}"),
+    ('\u{24be}', "\u{24d8}"),
+    ('\u{24bf}', "\u{24d9}"),
+    ('\u{24c0}', "\u{24da}"),
+    ('\u{24c1}', "\u{24db}"),
+    ('\u{24c2}', "\u{24dc}"),
+    ('\u{24c3}', "\u{24dd}"),
+    ('\u{24c4}', "\u{24de}"),
+    ('\u{24c5}', "\u{24df}"),
+    ('\u{24c6}', "\u{24e0}"),
+    ('\u{24c7}', "\u{24e1}"),
+    ('\u{24c8}', "\u{24e2}"),
+    ('\u{24c9}', "\u{24e3}"),
+    ('\u{24ca}', "\u{24e4}"),
+    ('\u{24cb}', "\u{24e5}"),
+    ('\u{24cc}', "\u{24e6}"),
+    ('\u{24cd}', "\u{24e7}"),
+    ('\u{24ce}', "\u{24e8}"),
+    ('\u{24cf}', "\u{24e9}"),
+    ('\u{2adc}', "\u{2add}\u{338}"),
+    ('\u{2c00}', "\u{2c30}"),
+    ('\u{2c01}', "\u{2c31}"),
+    ('\u{2c02}', "\u{2c32}"),
+    ('\u{2c03}', "\u{2c33}"),
+    ('\u{2c04}', "\u{2c34}"),
+    ('\u{2c05}', "\u{2c35}"),
+    ('\u{2c06}', "\u{2c36}"),
+    ('\u{2c07}', "\u{2c37}"),
+    ('\u{2c08}', "\u{2c38}"),
+    ('\u{2c09}', "\u{2c39}"),
+    ('\u{2c0a}', "\u{2c3a}"),
+    ('\u{2c0b}', "\u{2c3b}"),
+    ('\u{2c0c}', "\u{2c3c}"),
+    ('\u{2c0d}', "\u{2c3d}"),
+    ('\u{2c0e}', "\u{2c3e}"),
+    ('\u{2c0f}', "\u{2c3f}"),
+    ('\u{2c10}', "\u{2c40}"),
+    ('\u{2c11}', "\u{2c41}"),
+    ('\u{2c12}', "\u{2c42}"),
+    ('\u{2c13}', "\u{2c43}"),
+    ('\u{2c14}', "\u{2c44}"),
+    ('\u{2c15}', "\u{2c45}"),
+    ('\u{2c16}', "\u{2c46}"),
+    ('\u{2c17}', "\u{2c47}"),
+    ('\u{2c18}', "\u{2c48}"),
+    ('\u{2c19}', "\u{2c49}"),
+    ('\u{2c1a}', "\u{2c4a}"),
+    ('\u{2c1b}', "\u{2c4b}"),
+    ('\u{2c1c}', "\u{2c4c}"),
+    ('\u{2c1d}', "\u{2c4d}"),
+    ('\u{2c1e}', "\u{2c4e}"),
+    ('\u{2c1f}', "\u{2c4f}"),
+    ('\u{2c20}', "\u{2c50}"),
+    ('\u{2c21}', "\u{2c51}"),
+    ('\u{2c22}', "\u{2c52}"),
+    ('\u{2c23}', "\u{2c53}"),
+    ('\u{2c24}', "\u{2c54}"),
+    ('\u{2c25}', "\u{2c55}"),
+    ('\u{2c26}', "\u{2c56}"),
+    ('\u{2c27}', "\u{2c57}"),
+    ('\u{2c28}', "\u{2c58}"),
+    ('\u{2c29}', "\u{2c59}"),
+    ('\u{2c2a}', "\u{2c5a}"),
+    ('\u{2c2b}', "\u{2c5b}"),
+    ('\u{2c2c}', "\u{2c5c}"),
+    ('\u{2c2d}', "\u{2c5d}"),
+    ('\u{2c2e}', "\u{2c5e}"),
+    ('\u{2c2f}', "\u{2c5f}"),
+    ('\u{2c60}', "\u{2c61}"),
+    ('\u{2c62}', "\u{26b}"),
+    ('\u{2c63}', "\u{1d7d}"),
+    ('\u{2c64}', "\u{27d}"),
+    ('\u{2c67}', "\u{2c68}"),
+    ('\u{2c69}', "\u{2c6a}"),
+    ('\u{2c6b}', "\u{2c6c}"),
+    ('\u{2c6d}', "\u{251}"),
+    ('\u{2c6e}', "\u{271}"),
+    ('\u{2c6f}', "\u{250}"),
+    ('\u{2c70}', "\u{252}"),
+    ('\u{2c72}', "\u{2c73}"),
+    ('\u{2c75}', "\u{2c76}"),
+    ('\u{2c7e}', "\u{23f}"),
+    ('\u{2c7f}', "\u{240}"),
+    ('\u{2c80}', "\u{2c81}"),
+    ('\u{2c82}', "\u{2c83}"),
+    ('\u{2c84}', "\u{2c85}"),
+    ('\u{2c86}', "\u{2c87}"),
+    ('\u{2c88}', "\u{2c89}"),
+    ('\u{2c8a}', "\u{2c8b}"),
+    ('\u{2c8c}', "\u{2c8d}"),
+    ('\u{2c8e}', "\u{2c8f}"),
+    ('\u{2c90}', "\u{2c91}"),
+    ('\u{2c92}', "\u{2c93}"),
+    ('\u{2c94}', "\u{2c95}"),
+    ('\u{2c96}', "\u{2c97}"),
+    ('\u{2c98}', "\u{2c99}"),
+    ('\u{2c9a}', "\u{2c9b}"),
+    ('\u{2c9c}', "\u{2c9d}"),
+    ('\u{2c9e}', "\u{2c9f}"),
+    ('\u{2ca0}', "\u{2ca1}"),
+    ('\u{2ca2}', "\u{2ca3}"),
+    ('\u{2ca4}', "\u{2ca5}"),
+    ('\u{2ca6}', "\u{2ca7}"),
+    ('\u{2ca8}', "\u{2ca9}"),
+    ('\u{2caa}', "\u{2cab}"),
+    ('\u{2cac}', "\u{2cad}"),
+    ('\u{2cae}', "\u{2caf}"),
+    ('\u{2cb0}', "\u{2cb1}"),
+    ('\u{2cb2}', "\u{2cb3}"),
+    ('\u{2cb4}', "\u{2cb5}"),
+    ('\u{2cb6}', "\u{2cb7}"),
+    ('\u{2cb8}', "\u{2cb9}"),
+    ('\u{2cba}', "\u{2cbb}"),
+    ('\u{2cbc}', "\u{2cbd}"),
+    ('\u{2cbe}', "\u{2cbf}"),
+    ('\u{2cc0}', "\u{2cc1}"),
+    ('\u{2cc2}', "\u{2cc3}"),
+    ('\u{2cc4}', "\u{2cc5}"),
+    ('\u{2cc6}', "\u{2cc7}"),
+    ('\u{2cc8}', "\u{2cc9}"),
+    ('\u{2cca}', "\u{2ccb}"),
+    ('\u{2ccc}', "\u{2ccd}"),
+    ('\u{2cce}', "\u{2ccf}"),
+    ('\u{2cd0}', "\u{2cd1}"),
+    ('\u{2cd2}', "\u{2cd3}"),
+    ('\u{2cd4}', "\u{2cd5}"),
+    ('\u{2cd6}', "\u{2cd7}"),
+    ('\u{2cd8}', "\u{2cd9}"),
+    ('\u{2cda}', "\u{2cdb}"),
+    ('\u{2cdc}', "\u{2cdd}"),
+    ('\u{2cde}', "\u{2cdf}"),
+    ('\u{2ce0}', "\u{2ce1}"),
+    ('\u{2ce2}', "\u{2ce3}"),
+    ('\u{2ceb}', "\u{2cec}"),
+    ('\u{2ced}', "\u{2cee}"),
+    ('\u{2cf2}', "\u{2cf3}"),
+    ('\u{304c}', "\u{304b}\u{3099}"),
+    ('\u{304e}', "\u{304d}\u{3099}"),
+    ('\u{3050}', "\u{304f}\u{3099}"),
+    ('\u{3052}', "\u{3051}\u{3099}"),
+    ('\u{3054}', "\u{3053}\u{3099}"),
+    ('\u{3056}', "\u{3055}\u{3099}"),
+    ('\u{3058}', "\u{3057}\u{3099}"),
+    ('\u{305a}', "\u{3059}\u{3099}"),
+    ('\u{305c}', "\u{305b}\u{3099}"),
+    ('\u{305e}', "\u{305d}\u{3099}"),
+    ('\u{3060}', "\u{305f}\u{3099}"),
+    ('\u{3062}', "\u{3061}\u{3099}"),
+    ('\u{3065}', "\u{3064}\u{3099}"),
+    ('\u{3067}', "\u{3066}\u{3099}"),
+    ('\u{3069}', "\u{3068}\u{3099}"),
+    ('\u{3070}', "\u{306f}\u{3099}"),
+    ('\u{3071}', "\u{306f}\u{309a}"),
+    ('\u{3073}', "\u{3072}\u{3099}"),
+    ('\u{3074}', "\u{3072}\u{309a}"),
+    ('\u{3076}', "\u{3075}\u{3099}"),
+    ('\u{3077}', "\u{3075}\u{309a}"),
+    ('\u{3079}', "\u{3078}\u{3099}"),
+    ('\u{307a}', "\u{3078}\u{309a}"),
+    ('\u{307c}', "\u{307b}\u{3099}"),
+    ('\u{307d}', "\u{307b}\u{309a}"),
+    ('\u{3094}', "\u{3046}\u{3099}"),
+    ('\u{309e}', "\u{309d}\u{3099}"),
+    ('\u{30ac}', "\u{30ab}\u{3099}"),
+    ('\u{30ae}', "\u{30ad}\u{3099}"),
+    ('\u{30b0}', "\u{30af}\u{3099}"),
+    ('\u{30b2}', "\u{30b1}\u{3099}"),
+    ('\u{30b4}', "\u{30b3}\u{3099}"),
+    ('\u{30b6}', "\u{30b5}\u{3099}"),
+    ('\u{30b8}', "\u{30b7}\u{3099}"),
+    ('\u{30ba}', "\u{30b9}\u{3099}"),
+    ('\u{30bc}', "\u{30bb}\u{3099}"),
+    ('\u{30be}', "\u{30bd}\u{3099}"),
+    ('\u{30c0}', "\u{30bf}\u{3099}"),
+    ('\u{30c2}', "\u{30c1}\u{3099}"),
+    ('\u{30c5}', "\u{30c4}\u{3099}"),
+    ('\u{30c7}', "\u{30c6}\u{3099}"),
+    ('\u{30c9}', "\u{30c8}\u{3099}"),
+    ('\u{30d0}', "\u{30cf}\u{3099}"),
+    ('\u{30d1}', "\u{30cf}\u{309a}"),
+    ('\u{30d3}', "\u{30d2}\u{3099}"),
+    ('\u{30d4}', "\u{30d2}\u{309a}"),
+    ('\u{30d6}', "\u{30d5}\u{3099}"),
+    ('\u{30d7}', "\u{30d5}\u{309a}"),
+    ('\u{30d9}', "\u{30d8}\u{3099}"),
+    ('\u{30da}', "\u{30d8}\u{309a}"),
+    ('\u{30dc}', "\u{30db}\u{3099}"),
+    ('\u{30dd}', "\u{30db}\u{309a}"),
+    ('\u{30f4}', "\u{30a6}\u{3099}"),
+    ('\u{30f7}', "\u{30ef}\u{3099}"),
+    ('\u{30f8}', "\u{30f0}\u{3099}"),
+    ('\u{30f9}', "\u{30f1}\u{3099}"),
+    ('\u{30fa}', "\u{30f2}\u{3099}"),
+    ('\u{30fe}', "\u{30fd}\u{3099}"),
+    ('\u{a640}', "\u{a641}"),
+    ('\u{a642}', "\u{a643}"),
+    ('\u{a644}', "\u{a645}"),
+    ('\u{a646}', "\u{a647}"),
+    ('\u{a648}', "\u{a649}"),
+    ('\u{a64a}', "\u{a64b}"),
+    ('\u{a64c}', "\u{a64d}"),
+    ('\u{a64e}', "\u{a64f}"),
+    ('\u{a650}', "\u{a651}"),
+    ('\u{a652}', "\u{a653}"),
+    ('\u{a654}', "\u{a655}"),
+    ('\u{a656}', "\u{a657}"),
+    ('\u{a658}', "\u{a659}"),
+    ('\u{a65a}', "\u{a65b}"),
+    ('\u{a65c}', "\u{a65d}"),
+    ('\u{a65e}', "\u{a65f}"),
+    ('\u{a660}', "\u{a661}"),
+    ('\u{a662}', "\u{a663}"),
+    ('\u{a664}', "\u{a665}"),
+    ('\u{a666}', "\u{a667}"),
+    ('\u{a668}', "\u{a669}"),
+    ('\u{a66a}', "\u{a66b}"),
+    ('\u{a66c}', "\u{a66d}"),
+    ('\u{a680}', "\u{a681}"),
+    ('\u{a682}', "\u{a683}"),
+    ('\u{a684}', "\u{a685}"),
+    ('\u{a686}', "\u{a687}"),
+    ('\u{a688}', "\u{a689}"),
+    ('\u{a68a}', "\u{a68b}"),
+    ('\u{a68c}', "\u{a68d}"),
+    ('\u{a68e}', "\u{a68f}"),
+    ('\u{a690}', "\u{a691}"),
+    ('\u{a692}', "\u{a693}"),
+    ('\u{a694}', "\u{a695}"),
+    ('\u{a696}', "\u{a697}"),
+    ('\u{a698}', "\u{a699}"),
+    ('\u{a69a}', "\u{a69b}"),
+    ('\u{a722}', "\u{a723}"),
+    ('\u{a724}', "\u{a725}"),
+    ('\u{a726}', "\u{a727}"),
+    ('\u{a728}', "\u{a729}"),
+    ('\u{a72a}', "\u{a72b}"),
+    ('\u{a72c}', "\u{a72d}"),
+    ('\u{a72e}', "\u{a72f}"),
+    ('\u{a732}', "\u{a733}"),
+    ('\u{a734}', "\u{a735}"),
+    ('\u{a736}', "\u{a737}"),
+    ('\u{a738}', "\u{a739}"),
+    ('\u{a73a}', "\u{a73b}"),
+    ('\u{a73c}', "\u{a73d}"),
+    ('\u{a73e}', "\u{a73f}"),
+    ('\u{a740}', "\u{a741}"),
+    ('\u{a742}', "\u{a743}"),
+    ('\u{a744}', "\u{a745}"),
+    ('\u{a746}', "\u{a747}"),
+    ('\u{a748}', "\u{a749}"),
+    ('\u{a74a}', "\u{a74b}"),
+    ('\u{a74c}', "\u{a74d}"),
+    ('\u{a74e}', "\u{a74f}"),
+    ('\u{a750}', "\u{a751}"),
+    ('\u{a752}', "\u{a753}"),
+    ('\u{a754}', "\u{a755}"),
+    ('\u{a756}', "\u{a757}"),
+    ('\u{a758}', "\u{a759}"),
+    ('\u{a75a}', "\u{a75b}"),
+    ('\u{a75c}', "\u{a75d}"),
+    ('\u{a75e}', "\u{a75f}"),
+    ('\u{a760}', "\u{a761}"),
+    ('\u{a762}', "\u{a763}"),
+    ('\u{a764}', "\u{a765}"),
+    ('\u{a766}', "\u{a767}"),
+    ('\u{a768}', "\u{a769}"),
+    ('\u{a76a}', "\u{a76b}"),
+    ('\u{a76c}', "\u{a76d}"),
+    ('\u{a76e}', "\u{a76f}"),
+    ('\u{a779}', "\u{a77a}"),
+    ('\u{a77b}', "\u{a77c}"),
+    ('\u{a77d}', "\u{1d79}"),
+    ('\u{a77e}', "\u{a77f}"),
+    ('\u{a780}', "\u{a781}"),
+    ('\u{a782}', "\u{a783}"),
+    ('\u{a784}', "\u{a785}"),
+    ('\u{a786}', "\u{a787}"),
+    ('\u{a78b}', "\u{a78c}"),
+    ('\u{a78d}', "\u{265}"),
+    ('\u{a790}', "\u{a791}"),
+    ('\u{a792}', "\u{a793}"),
+    ('\u{a796}', "\u{a797}"),
+    ('\u{a798}', "\u{a799}"),
+    ('\u{a79a}', "\u{a79b}"),
+    ('\u{a79c}', "\u{a79d}"),
+    ('\u{a79e}', "\u{a79f}"),
+    ('\u{a7a0}', "\u{a7a1}"),
+    ('\u{a7a2}', "\u{a7a3}"),
+    ('\u{a7a4}', "\u{a7a5}"),
+    ('\u{a7a6}', "\u{a7a7}"),
+    ('\u{a7a8}', "\u{a7a9}"),
+    ('\u{a7aa}', "\u{266}"),
+    ('\u{a7ab}', "\u{25c}"),
+    ('\u{a7ac}', "\u{261}"),
+    ('\u{a7ad}', "\u{26c}"),
+    ('\u{a7ae}', "\u{26a}"),
+    ('\u{a7b0}', "\u{29e}"),
+    ('\u{a7b1}', "\u{287}"),
+    ('\u{a7b2}', "\u{29d}"),
+    ('\u{a7b3}', "\u{ab53}"),
+    ('\u{a7b4}', "\u{a7b5}"),
+    ('\u{a7b6}', "\u{a7b7}"),
+    ('\u{a7b8}', "\u{a7b9}"),
+    ('\u{a7ba}', "\u{a7bb}"),
+    ('\u{a7bc}', "\u{a7bd}"),
+    ('\u{a7be}', "\u{a7bf}"),
+    ('\u{a7c0}', "\u{a7c1}"),
+    ('\u{a7c2}', "\u{a7c3}"),
+    ('\u{a7c4}', "\u{a794}"),
+    ('\u{a7c5}', "\u{282}"),
+    ('\u{a7c6}', "\u{1d8e}"),
+    ('\u{a7c7}', "\u{a7c8}"),
+    ('\u{a7c9}', "\u{a7ca}"),
+    ('\u{a7d0}', "\u{a7d1}"),
+    ('\u{a7d6}', "\u{a7d7}"),
+    ('\u{a7d8}', "\u{a7d9}"),
+    ('\u{a7f5}', "\u{a7f6}"),
+    ('\u{ab70}', "\u{13a0}"),
+    ('\u{ab71}', "\u{13a1}"),
+    ('\u{ab72}', "\u{13a2}"),
+    ('\u{ab73}', "\u{13a3}"),
+    ('\u{ab74}', "\u{13a4}"),
+    ('\u{ab75}', "\u{13a5}"),
+    ('\u{ab76}', "\u{13a6}"),
+    ('\u{ab77}', "\u{13a7}"),
+    ('\u{ab78}', "\u{13a8}"),
+    ('\u{ab79}', "\u{13a9}"),
+    ('\u{ab7a}', "\u{13aa}"),
+    ('\u{ab7b}', "\u{13ab}"),
+    ('\u{ab7c}', "\u{13ac}"),
+    ('\u{ab7d}', "\u{13ad}"),
+    ('\u{ab7e}', "\u{13ae}"),
+    ('\u{ab7f}', "\u{13af}"),
+    ('\u{ab80}', "\u{13b0}"),
+    ('\u{ab81}', "\u{13b1}"),
+    ('\u{ab82}', "\u{13b2}"),
+    ('\u{ab83}', "\u{13b3}"),
+    ('\u{ab84}', "\u{13b4}"),
+    ('\u{ab85}', "\u{13b5}"),
+    ('\u{ab86}', "\u{13b6}"),
+    ('\u{ab87}', "\u{13b7}"),
+    ('\u{ab88}', "\u{13b8}"),
+    ('\u{ab89}', "\u{13b9}"),
+    ('\u{ab8a}', "\u{13ba}"),
+    ('\u{ab8b}', "\u{13bb}"),
+    ('\u{ab8c}', "\u{13bc}"),
+    ('\u{ab8d}', "\u{13bd}"),
+    ('\u{ab8e}', "\u{13be}"),
+    ('\u{ab8f}', "\u{13bf}"),
+    ('\u{ab90}', "\u{13c0}"),
+    ('\u{ab91}', "\u{13c1}"),
+    ('\u{ab92}', "\u{13c2}"),
+    ('\u{ab93}', "\u{13c3}"),
+    ('\u{ab94}', "\u{13c4}"),
+    ('\u{ab95}', "\u{13c5}"),
+    ('\u{ab96}', "\u{13c6}"),
+    ('\u{ab97}', "\u{13c7}"),
+    ('\u{ab98}', "\u{13c8}"),
+    ('\u{ab99}', "\u{13c9}"),
+    ('\u{ab9a}', "\u{13ca}"),
+    ('\u{ab9b}', "\u{13cb}"),
+    ('\u{ab9c}', "\u{13cc}"),
+    ('\u{ab9d}', "\u{13cd}"),
+    ('\u{ab9e}', "\u{13ce}"),
+    ('\u{ab9f}', "\u{13cf}"),
+    ('\u{aba0}', "\u{13d0}"),
+    ('\u{aba1}', "\u{13d1}"),
+    ('\u{aba2}', "\u{13d2}"),
+    ('\u{aba3}', "\u{13d3}"),
+    ('\u{aba4}', "\u{13d4}"),
+    ('\u{aba5}', "\u{13d5}"),
+    ('\u{aba6}', "\u{13d6}"),
+    ('\u{aba7}', "\u{13d7}"),
+    ('\u{aba8}', "\u{13d8}"),
+    ('\u{aba9}', "\u{13d9}"),
+    ('\u{abaa}', "\u{13da}"),
+    ('\u{abab}', "\u{13db}"),
+    ('\u{abac}', "\u{13dc}"),
+    ('\u{abad}', "\u{13dd}"),
+    ('\u{abae}', "\u{13de}"),
+    ('\u{abaf}', "\u{13df}"),
+    ('\u{abb0}', "\u{13e0}"),
+    ('\u{abb1}', "\u{13e1}"),
+    ('\u{abb2}', "\u{13e2}"),
+    ('\u{abb3}', "\u{13e3}"),
+    ('\u{abb4}', "\u{13e4}"),
+    ('\u{abb5}', "\u{13e5}"),
+    ('\u{abb6}', "\u{13e6}"),
+    ('\u{abb7}', "\u{13e7}"),
+    ('\u{abb8}', "\u{13e8}"),
+    ('\u{abb9}', "\u{13e9}"),
+    ('\u{abba}', "\u{13ea}"),
+    ('\u{abbb}', "\u{13eb}"),
+    ('\u{abbc}', "\u{13ec}"),
+    ('\u{abbd}', "\u{13ed}"),
+    ('\u{abbe}', "\u{13ee}"),
+    ('\u{abbf}', "\u{13ef}"),
+    ('\u{f900}', "\u{8c48}"),
+    ('\u{f901}', "\u{66f4}"),
+    ('\u{f902}', "\u{8eca}"),
+    ('\u{f903}', "\u{8cc8}"),
+    ('\u{f904}', "\u{6ed1}"),
+    ('\u{f905}', "\u{4e32}"),
+    ('\u{f906}', "\u{53e5}"),
+    ('\u{f907}', "\u{9f9c}"),
+    ('\u{f908}', "\u{9f9c}"),
+    ('\u{f909}', "\u{5951}"),
+    ('\u{f90a}', "\u{91d1}"),
+    ('\u{f90b}', "\u{5587}"),
+    ('\u{f90c}', "\u{5948}"),
+    ('\u{f90d}', "\u{61f6}"),
+    ('\u{f90e}', "\u{7669}"),
+    ('\u{f90f}', "\u{7f85}"),
+    ('\u{f910}', "\u{863f}"),
+    ('\u{f911}', "\u{87ba}"),
+    ('\u{f912}', "\u{88f8}"),
+    ('\u{f913}', "\u{908f}"),
+    ('\u{f914}', "\u{6a02}"),
+    ('\u{f915}', "\u{6d1b}"),
+    ('\u{f916}', "\u{70d9}"),
+    ('\u{f917}', "\u{73de}"),
+    ('\u{f918}', "\u{843d}"),
+    ('\u{f919}', "\u{916a}"),
+    ('\u{f91a}', "\u{99f1}"),
+    ('\u{f91b}', "\u{4e82}"),
+    ('\u{f91c}', "\u{5375}"),
+    ('\u{f91d}', "\u{6b04}"),
+    ('\u{f91e}', "\u{721b}"),
+    ('\u{f91f}', "\u{862d}"),
+    ('\u{f920}', "\u{9e1e}"),
+    ('\u{f921}', "\u{5d50}"),
+    ('\u{f922}', "\u{6feb}"),
+    ('\u{f923}', "\u{85cd}"),
+    ('\u{f924}', "\u{8964}"),
+    ('\u{f925}', "\u{62c9}"),
+    ('\u{f926}', "\u{81d8}"),
+    ('\u{f927}', "\u{881f}"),
+    ('\u{f928}', "\u{5eca}"),
+    ('\u{f929}', "\u{6717}"),
+    ('\u{f92a}', "\u{6d6a}"),
+    ('\u{f92b}', "\u{72fc}"),
+    ('\u{f92c}', "\u{90ce}"),
+    ('\u{f92d}', "\u{4f86}"),
+    ('\u{f92e}', "\u{51b7}"),
+    ('\u{f92f}', "\u{52de}"),
+    ('\u{f930}', "\u{64c4}"),
+    ('\u{f931}', "\u{6ad3}"),
+    ('\u{f932}', "\u{7210}"),
+    ('\u{f933}', "\u{76e7}"),
+    ('\u{f934}', "\u{8001}"),
+    ('\u{f935}', "\u{8606}"),
+    ('\u{f936}', "\u{865c}"),
+    ('\u{f937}', "\u{8def}"),
+    ('\u{f938}', "\u{9732}"),
+    ('\u{f939}', "\u{9b6f}"),
+    ('\u{f93a}', "\u{9dfa}"),
+    ('\u{f93b}', "\u{788c}"),
+    ('\u{f93c}', "\u{797f}"),
+    ('\u{f93d}', "\u{7da0}"),
+    ('\u{f93e}', "\u{83c9}"),
+    ('\u{f93f}', "\u{9304}"),
+    ('\u{f940}', "\u{9e7f}"),
+    ('\u{f941}', "\u{8ad6}"),
+    ('\u{f942}', "\u{58df}"),
+    ('\u{f943}', "\u{5f04}"),
+    ('\u{f944}', "\u{7c60}"),
+    ('\u{f945}', "\u{807e}"),
+    ('\u{f946}', "\u{7262}"),
+    ('\u{f947}', "\u{78ca}"),
+    ('\u{f948}', "\u{8cc2}"),
+    ('\u{f949}', "\u{96f7}"),
+    ('\u{f94a}', "\u{58d8}"),
+    ('\u{f94b}', "\u{5c62}"),
+    ('\u{f94c}', "\u{6a13}"),
+    ('\u{f94d}', "\u{6dda}"),
+    ('\u{f94e}', "\u{6f0f}"),
+    ('\u{f94f}', "\u{7d2f}"),
+    ('\u{f950}', "\u{7e37}"),
+    ('\u{f951}', "\u{964b}"),
+    ('\u{f952}', "\u{52d2}"),
+    ('\u{f953}', "\u{808b}"),
+    ('\u{f954}', "\u{51dc}"),
+    ('\u{f955}', "\u{51cc}"),
+    ('\u{f956}', "\u{7a1c}"),
+    ('\u{f957}', "\u{7dbe}"),
+    ('\u{f958}', "\u{83f1}"),
+    ('\u{f959}', "\u{9675}"),
+    ('\u{f95a}', "\u{8b80}"),
+    ('\u{f95b}', "\u{62cf}"),
+    ('\u{f95c}', "\u{6a02}"),
+    ('\u{f95d}', "\u{8afe}"),
+    ('\u{f95e}', "\u{4e39}"),
+    ('\u{f95f}', "\u{5be7}"),
+    ('\u{f960}', "\u{6012}"),
+    ('\u{f961}', "\u{7387}"),
+    ('\u{f962}', "\u{7570}"),
+    ('\u{f963}', "\u{5317}"),
+    ('\u{f964}', "\u{78fb}"),
+    ('\u{f965}', "\u{4fbf}"),
+    ('\u{f966}', "\u{5fa9}"),
+    ('\u{f967}', "\u{4e0d}"),
+    ('\u{f968}', "\u{6ccc}"),
+    ('\u{f969}', "\u{6578}"),
+    ('\u{f96a}', "\u{7d22}"),
+    ('\u{f96b}', "\u{53c3}"),
+    ('\u{f96c}', "\u{585e}"),
+    ('\u{f96d}', "\u{7701}"),
+    ('\u{f96e}', "\u{8449}"),
+    ('\u{f96f}', "\u{8aaa}"),
+    ('\u{f970}', "\u{6bba}"),
+    ('\u{f971}', "\u{8fb0}"),
+    ('\u{f972}', "\u{6c88}"),
+    ('\u{f973}', "\u{62fe}"),
+    ('\u{f974}', "\u{82e5}"),
+    ('\u{f975}', "\u{63a0}"),
+    ('\u{f976}', "\u{7565}"),
+    ('\u{f977}', "\u{4eae}"),
+    ('\u{f978}', "\u{5169}"),
+    ('\u{f979}', "\u{51c9}"),
+    ('\u{f97a}', "\u{6881}"),
+    ('\u{f97b}', "\u{7ce7}"),
+    ('\u{f97c}', "\u{826f}"),
+    ('\u{f97d}', "\u{8ad2}"),
+    ('\u{f97e}', "\u{91cf}"),
+    ('\u{f97f}', "\u{52f5}"),
+    ('\u{f980}', "\u{5442}"),
+    ('\u{f981}', "\u{5973}"),
+    ('\u{f982}', "\u{5eec}"),
+    ('\u{f983}', "\u{65c5}"),
+    ('\u{f984}', "\u{6ffe}"),
+    ('\u{f985}', "\u{792a}"),
+    ('\u{f986}', "\u{95ad}"),
+    ('\u{f987}', "\u{9a6a}"),
+    ('\u{f988}', "\u{9e97}"),
+    ('\u{f989}', "\u{9ece}"),
+    ('\u{f98a}', "\u{529b}"),
+    ('\u{f98b}', "\u{66c6}"),
+    ('\u{f98c}', "\u{6b77}"),
+    ('\u{f98d}', "\u{8f62}"),
+    ('\u{f98e}', "\u{5e74}"),
+    ('\u{f98f}', "\u{6190}"),
+    ('\u{f990}', "\u{6200}"),
+    ('\u{f991}', "\u{649a}"),
+    ('\u{f992}', "\u{6f23}"),
+    ('\u{f993}', "\u{7149}"),
+    ('\u{f994}', "\u{7489}"),
+    ('\u{f995}', "\u{79ca}"),
+    ('\u{f996}', "\u{7df4}"),
+    ('\u{f997}', "\u{806f}"),
+    ('\u{f998}', "\u{8f26}"),
+    ('\u{f999}', "\u{84ee}"),
+    ('\u{f99a}', "\u{9023}"),
+    ('\u{f99b}', "\u{934a}"),
+    ('\u{f99c}', "\u{5217}"),
+    ('\u{f99d}', "\u{52a3}"),
+    ('\u{f99e}', "\u{54bd}"),
+    ('\u{f99f}', "\u{70c8}"),
+    ('\u{f9a0}', "\u{88c2}"),
+    ('\u{f9a1}', "\u{8aaa}"),
+    ('\u{f9a2}', "\u{5ec9}"),
+    ('\u{f9a3}', "\u{5ff5}"),
+    ('\u{f9a4}', "\u{637b}"),
+    ('\u{f9a5}', "\u{6bae}"),
+    ('\u{f9a6}', "\u{7c3e}"),
+    ('\u{f9a7}', "\u{7375}"),
+    ('\u{f9a8}', "\u{4ee4}"),
+    ('\u{f9a9}', "\u{56f9}"),
+    ('\u{f9aa}', "\u{5be7}"),
+    ('\u{f9ab}', "\u{5dba}"),
+    ('\u{f9ac}', "\u{601c}"),
+    ('\u{f9ad}', "\u{73b2}"),
+    ('\u{f9ae}', "\u{7469}"),
+    ('\u{f9af}', "\u{7f9a}"),
+    ('\u{f9b0}', "\u{8046}"),
+    ('\u{f9b1}', "\u{9234}"),
+    ('\u{f9b2}', "\u{96f6}"),
+    ('\u{f9b3}', "\u{9748}"),
+    ('\u{f9b4}', "\u{9818}"),
+    ('\u{f9b5}', "\u{4f8b}"),
+    ('\u{f9b6}', "\u{79ae}"),
+    ('\u{f9b7}', "\u{91b4}"),
+    ('\u{f9b8}', "\u{96b8}"),
+    ('\u{f9b9}', "\u{60e1}"),
+    ('\u{f9ba}', "\u{4e86}"),
+    ('\u{f9bb}', "\u{50da}"),
+    ('\u{f9bc}', "\u{5bee}"),
+    ('\u{f9bd}', "\u{5c3f}"),
+    ('\u{f9be}', "\u{6599}"),
+    ('\u{f9bf}', "\u{6a02}"),
+    ('\u{f9c0}', "\u{71ce}"),
+    ('\u{f9c1}', "\u{7642}"),
+    ('\u{f9c2}', "\u{84fc}"),
+    ('\u{f9c3}', "\u{907c}"),
+    ('\u{f9c4}', "\u{9f8d}"),
+    ('\u{f9c5}', "\u{6688}"),
+    ('\u{f9c6}', "\u{962e}"),
+    ('\u{f9c7}', "\u{5289}"),
+    ('\u{f9c8}', "\u{677b}"),
+    ('\u{f9c9}', "\u{67f3}"),
+    ('\u{f9ca}', "\u{6d41}"),
+    ('\u{f9cb}', "\u{6e9c}"),
+    ('\u{f9cc}', "\u{7409}"),
+    ('\u{f9cd}', "\u{7559}"),
+    ('\u{f9ce}', "\u{786b}"),
+    ('\u{f9cf}', "\u{7d10}"),
+    ('\u{f9d0}', "\u{985e}"),
+    ('\u{f9d1}', "\u{516d}"),
+    ('\u{f9d2}', "\u{622e}"),
+    ('\u{f9d3}', "\u{9678}"),
+    ('\u{f9d4}', "\u{502b}"),
+    ('\u{f9d5}', "\u{5d19}"),
+    ('\u{f9d6}', "\u{6dea}"),
+    ('\u{f9d7}', "\u{8f2a}"),
+    ('\u{f9d8}', "\u{5f8b}"),
+    ('\u{f9d9}', "\u{6144}"),
+    ('\u{f9da}', "\u{6817}"),
+    ('\u{f9db}', "\u{7387}"),
+    ('\u{f9dc}', "\u{9686}"),
+    ('\u{f9dd}', "\u{5229}"),
+    ('\u{f9de}', "\u{540f}"),
+    ('\u{f9df}', "\u{5c65}"),
+    ('\u{f9e0}', "\u{6613}"),
+    ('\u{f9e1}', "\u{674e}"),
+    ('\u{f9e2}', "\u{68a8}"),
+    ('\u{f9e3}', "\u{6ce5}"),
+    ('\u{f9e4}', "\u{7406}"),
+    ('\u{f9e5}', "\u{75e2}"),
+    ('\u{f9e6}', "\u{7f79}"),
+    ('\u{f9e7}', "\u{88cf}"),
+    ('\u{f9e8}', "\u{88e1}"),
+    ('\u{f9e9}', "\u{91cc}"),
+    ('\u{f9ea}', "\u{96e2}"),
+    ('\u{f9eb}', "\u{533f}"),
+    ('\u{f9ec}', "\u{6eba}"),
+    ('\u{f9ed}', "\u{541d}"),
+    ('\u{f9ee}', "\u{71d0}"),
+    ('\u{f9ef}', "\u{7498}"),
+    ('\u{f9f0}', "\u{85fa}"),
+    ('\u{f9f1}', "\u{96a3}"),
+    ('\u{f9f2}', "\u{9c57}"),
+    ('\u{f9f3}', "\u{9e9f}"),
+    ('\u{f9f4}', "\u{6797}"),
+    ('\u{f9f5}', "\u{6dcb}"),
+    ('\u{f9f6}', "\u{81e8}"),
+    ('\u{f9f7}', "\u{7acb}"),
+    ('\u{f9f8}', "\u{7b20}"),
+    ('\u{f9f9}', "\u{7c92}"),
+    ('\u{f9fa}', "\u{72c0}"),
+    ('\u{f9fb}', "\u{7099}"),
+    ('\u{f9fc}', "\u{8b58}"),
+    ('\u{f9fd}', "\u{4ec0}"),
+    ('\u{f9fe}', "\u{8336}"),
+    ('\u{f9ff}', "\u{523a}"),
+    ('\u{fa00}', "\u{5207}"),
+    ('\u{fa01}', "\u{5ea6}"),
+    ('\u{fa02}', "\u{62d3}"),
+    ('\u{fa03}', "\u{7cd6}"),
+    ('\u{fa04}', "\u{5b85}"),
+    ('\u{fa05}', "\u{6d1e}"),
+    ('\u{fa06}', "\u{66b4}"),
+    ('\u{fa07}', "\u{8f3b}"),
+    ('\u{fa08}', "\u{884c}"),
+    ('\u{fa09}', "\u{964d}"),
+    ('\u{fa0a}', "\u{898b}"),
+    ('\u{fa0b}', "\u{5ed3}"),
+    ('\u{fa0c}', "\u{5140}"),
+    ('\u{fa0d}', "\u{55c0}"),
+    ('\u{fa10}', "\u{585a}"),
+    ('\u{fa12}', "\u{6674}"),
+    ('\u{fa15}', "\u{51de}"),
+    ('\u{fa16}', "\u{732a}"),
+    ('\u{fa17}', "\u{76ca}"),
+    ('\u{fa18}', "\u{793c}"),
+    ('\u{fa19}', "\u{795e}"),
+    ('\u{fa1a}', "\u{7965}"),
+    ('\u{fa1b}', "\u{798f}"),
+    ('\u{fa1c}', "\u{9756}"),
+    ('\u{fa1d}', "\u{7cbe}"),
+    ('\u{fa1e}', "\u{7fbd}"),
+    ('\u{fa20}', "\u{8612}"),
+    ('\u{fa22}', "\u{8af8}"),
+    ('\u{fa25}', "\u{9038}"),
+    ('\u{fa26}', "\u{90fd}"),
+    ('\u{fa2a}', "\u{98ef}"),
+    ('\u{fa2b}', "\u{98fc}"),
+    ('\u{fa2c}', "\u{9928}"),
+    ('\u{fa2d}', "\u{9db4}"),
+    ('\u{fa2e}', "\u{90de}"),
+    ('\u{fa2f}', "\u{96b7}"),
+    ('\u{fa30}', "\u{4fae}"),
+    ('\u{fa31}', "\u{50e7}"),
+    ('\u{fa32}', "\u{514d}"),
+    ('\u{fa33}', "\u{52c9}"),
+    ('\u{fa34}', "\u{52e4}"),
+    ('\u{fa35}', "\u{5351}"),
+    ('\u{fa36}', "\u{559d}"),
+    ('\u{fa37}', "\u{5606}"),
+    ('\u{fa38}', "\u{5668}"),
+    ('\u{fa39}', "\u{5840}"),
+    ('\u{fa3a}', "\u{58a8}"),
+    ('\u{fa3b}', "\u{5c64}"),
+    ('\u{fa3c}', "\u{5c6e}"),
+    ('\u{fa3d}', "\u{6094}"),
+    ('\u{fa3e}', "\u{6168}"),
+    ('\u{fa3f}', "\u{618e}"),
+    ('\u{fa40}', "\u{61f2}"),
+    ('\u{fa41}', "\u{654f}"),
+    ('\u{fa42}', "\u{65e2}"),
+    ('\u{fa43}', "\u{6691}"),
+    ('\u{fa44}', "\u{6885}"),
+    ('\u{fa45}', "\u{6d77}"),
+    ('\u{fa46}', "\u{6e1a}"),
+    ('\u{fa47}', "\u{6f22}"),
+    ('\u{fa48}', "\u{716e}"),
+    ('\u{fa49}', "\u{722b}"),
+    ('\u{fa4a}', "\u{7422}"),
+    ('\u{fa4b}', "\u{7891}"),
+    ('\u{fa4c}', "\u{793e}"),
+    ('\u{fa4d}', "\u{7949}"),
+    ('\u{fa4e}', "\u{7948}"),
+    ('\u{fa4f}', "\u{7950}"),
+    ('\u{fa50}', "\u{7956}"),
+    ('\u{fa51}', "\u{795d}"),
+    ('\u{fa52}', "\u{798d}"),
+    ('\u{fa53}', "\u{798e}"),
+    ('\u{fa54}', "\u{7a40}"),
+    ('\u{fa55}', "\u{7a81}"),
+    ('\u{fa56}', "\u{7bc0}"),
+    ('\u{fa57}', "\u{7df4}"),
+    ('\u{fa58}', "\u{7e09}"),
+    ('\u{fa59}', "\u{7e41}"),
+    ('\u{fa5a}', "\u{7f72}"),
+    ('\u{fa5b}', "\u{8005}"),
+    ('\u{fa5c}', "\u{81ed}"),
+    ('\u{fa5d}', "\u{8279}"),
+    ('\u{fa5e}', "\u{8279}"),
+    ('\u{fa5f}', "\u{8457}"),
+    ('\u{fa60}', "\u{8910}"),
+    ('\u{fa61}', "\u{8996}"),
+    ('\u{fa62}', "\u{8b01}"),
+    ('\u{fa63}', "\u{8b39}"),
+    ('\u{fa64}', "\u{8cd3}"),
+    ('\u{fa65}', "\u{8d08}"),
+    ('\u{fa66}', "\u{8fb6}"),
+    ('\u{fa67}', "\u{9038}"),
+    ('\u{fa68}', "\u{96e3}"),
+    ('\u{fa69}', "\u{97ff}"),
+    ('\u{fa6a}', "\u{983b}"),
+    ('\u{fa6b}', "\u{6075}"),
+    ('\u{fa6c}', "\u{242ee}"),
+    ('\u{fa6d}', "\u{8218}"),
+    ('\u{fa70}', "\u{4e26}"),
+    ('\u{fa71}', "\u{51b5}"),
+    ('\u{fa72}', "\u{5168}"),
+    ('\u{fa73}', "\u{4f80}"),
+    ('\u{fa74}', "\u{5145}"),
+    ('\u{fa75}', "\u{5180}"),
+    ('\u{fa76}', "\u{52c7}"),
+    ('\u{fa77}', "\u{52fa}"),
+    ('\u{fa78}', "\u{559d}"),
+    ('\u{fa79}', "\u{5555}"),
+    ('\u{fa7a}', "\u{5599}"),
+    ('\u{fa7b}', "\u{55e2}"),
+    ('\u{fa7c}', "\u{585a}"),
+    ('\u{fa7d}', "\u{58b3}"),
+    ('\u{fa7e}', "\u{5944}"),
+    ('\u{fa7f}', "\u{5954}"),
+    ('\u{fa80}', "\u{5a62}"),
+    ('\u{fa81}', "\u{5b28}"),
+    ('\u{fa82}', "\u{5ed2}"),
+    ('\u{fa83}', "\u{5ed9}"),
+    ('\u{fa84}', "\u{5f69}"),
+    ('\u{fa85}', "\u{5fad}"),
+    ('\u{fa86}', "\u{60d8}"),
+    ('\u{fa87}', "\u{614e}"),
+    ('\u{fa88}', "\u{6108}"),
+    ('\u{fa89}', "\u{618e}"),
+    ('\u{fa8a}', "\u{6160}"),
+    ('\u{fa8b}', "\u{61f2}"),
+    ('\u{fa8c}', "\u{6234}"),
+    ('\u{fa8d}', "\u{63c4}"),
+    ('\u{fa8e}', "\u{641c}"),
+    ('\u{fa8f}', "\u{6452}"),
+    ('\u{fa90}', "\u{6556}"),
+    ('\u{fa91}', "\u{6674}"),
+    ('\u{fa92}', "\u{6717}"),
+    ('\u{fa93}', "\u{671b}"),
+    ('\u{fa94}', "\u{6756}"),
+    ('\u{fa95}', "\u{6b79}"),
+    ('\u{fa96}', "\u{6bba}"),
+    ('\u{fa97}', "\u{6d41}"),
+    ('\u{fa98}', "\u{6edb}"),
+    ('\u{fa99}', "\u{6ecb}"),
+    ('\u{fa9a}', "\u{6f22}"),
+    ('\u{fa9b}', "\u{701e}"),
+    ('\u{fa9c}', "\u{716e}"),
+    ('\u{fa9d}', "\u{77a7}"),
+    ('\u{fa9e}', "\u{7235}"),
+    ('\u{fa9f}', "\u{72af}"),
+    ('\u{faa0}', "\u{732a}"),
+    ('\u{faa1}', "\u{7471}"),
+    ('\u{faa2}', "\u{7506}"),
+    ('\u{faa3}', "\u{753b}"),
+    ('\u{faa4}', "\u{761d}"),
+    ('\u{faa5}', "\u{761f}"),
+    ('\u{faa6}', "\u{76ca}"),
+    ('\u{faa7}', "\u{76db}"),
+    ('\u{faa8}', "\u{76f4}"),
+    ('\u{faa9}', "\u{774a}"),
+    ('\u{faaa}', "\u{7740}"),
+    ('\u{faab}', "\u{78cc}"),
+    ('\u{faac}', "\u{7ab1}"),
+    ('\u{faad}', "\u{7bc0}"),
+    ('\u{faae}', "\u{7c7b}"),
+    ('\u{faaf}', "\u{7d5b}"),
+    ('\u{fab0}', "\u{7df4}"),
+    ('\u{fab1}', "\u{7f3e}"),
+    ('\u{fab2}', "\u{8005}"),
+    ('\u{fab3}', "\u{8352}"),
+    ('\u{fab4}', "\u{83ef}"),
+    ('\u{fab5}', "\u{8779}"),
+    ('\u{fab6}', "\u{8941}"),
+    ('\u{fab7}', "\u{8986}"),
+    ('\u{fab8}', "\u{8996}"),
+    ('\u{fab9}', "\u{8abf}"),
+    ('\u{faba}', "\u{8af8}"),
+    ('\u{fabb}', "\u{8acb}"),
+    ('\u{fabc}', "\u{8b01}"),
+    ('\u{fabd}', "\u{8afe}"),
+    ('\u{fabe}', "\u{8aed}"),
+    ('\u{fabf}', "\u{8b39}"),
+    ('\u{fac0}', "\u{8b8a}"),
+    ('\u{fac1}', "\u{8d08}"),
+    ('\u{fac2}', "\u{8f38}"),
+    ('\u{fac3}', "\u{9072}"),
+    ('\u{fac4}', "\u{9199}"),
+    ('\u{fac5}', "\u{9276}"),
+    ('\u{fac6}', "\u{967c}"),
+    ('\u{fac7}', "\u{96e3}"),
+    ('\u{fac8}', "\u{9756}"),
+    ('\u{fac9}', "\u{97db}"),
+    ('\u{faca}', "\u{97ff}"),
+    ('\u{facb}', "\u{980b}"),
+    ('\u{facc}', "\u{983b}"),
+    ('\u{facd}', "\u{9b12}"),
+    ('\u{face}', "\u{9f9c}"),
+    ('\u{facf}', "\u{2284a}"),
+    ('\u{fad0}', "\u{22844}"),
+    ('\u{fad1}', "\u{233d5}"),
+    ('\u{fad2}', "\u{3b9d}"),
+    ('\u{fad3}', "\u{4018}"),
+    ('\u{fad4}', "\u{4039}"),
+    ('\u{fad5}', "\u{25249}"),
+    ('\u{fad6}', "\u{25cd0}"),
+    ('\u{fad7}', "\u{27ed3}"),
+    ('\u{fad8}', "\u{9f43}"),
+    ('\u{fad9}', "\u{9f8e}"),
+    ('\u{fb00}', "ff"),
+    ('\u{fb01}', "fi"),
+    ('\u{fb02}', "fl"),
+    ('\u{fb03}', "ffi"),
+    ('\u{fb04}', "ffl"),
+    ('\u{fb05}', "st"),
+    ('\u{fb06}', "st"),
+    ('\u{fb13}', "\u{574}\u{576}"),
+    ('\u{fb14}', "\u{574}\u{565}"),
+    ('\u{fb15}', "\u{574}\u{56b}"),
+    ('\u{fb16}', "\u{57e}\u{576}"),
+    ('\u{fb17}', "\u{574}\u{56d}"),
+    ('\u{fb1d}', "\u{5d9}\u{5b4}"),
+    ('\u{fb1f}', "\u{5f2}\u{5b7}"),
+    ('\u{fb2a}', "\u{5e9}\u{5c1}"),
+    ('\u{fb2b}', "\u{5e9}\u{5c2}"),
+    ('\u{fb2c}', "\u{5e9}\u{5bc}\u{5c1}"),
+    ('\u{fb2d}', "\u{5e9}\u{5bc}\u{5c2}"),
+    ('\u{fb2e}', "\u{5d0}\u{5b7}"),
+    ('\u{fb2f}', "\u{5d0}\u{5b8}"),
+    ('\u{fb30}', "\u{5d0}\u{5bc}"),
+    ('\u{fb31}', "\u{5d1}\u{5bc}"),
+    ('\u{fb32}', "\u{5d2}\u{5bc}"),
+    ('\u{fb33}', "\u{5d3}\u{5bc}"),
+    ('\u{fb34}', "\u{5d4}\u{5bc}"),
+    ('\u{fb35}', "\u{5d5}\u{5bc}"),
+    ('\u{fb36}', "\u{5d6}\u{5bc}"),
+    ('\u{fb38}', "\u{5d8}\u{5bc}"),
+    ('\u{fb39}', "\u{5d9}\u{5bc}"),
+    ('\u{fb3a}', "\u{5da}\u{5bc}"),
+    ('\u{fb3b}', "\u{5db}\u{5bc}"),
+    ('\u{fb3c}', "\u{5dc}\u{5bc}"),
+    ('\u{fb3e}', "\u{5de}\u{5bc}"),
+    ('\u{fb40}', "\u{5e0}\u{5bc}"),
+    ('\u{fb41}', "\u{5e1}\u{5bc}"),
+    ('\u{fb43}', "\u{5e3}\u{5bc}"),
+    ('\u{fb44}', "\u{5e4}\u{5bc}"),
+    ('\u{fb46}', "\u{5e6}\u{5bc}"),
+    ('\u{fb47}', "\u{5e7}\u{5bc}"),
+    ('\u{fb48}', "\u{5e8}\u{5bc}"),
+    ('\u{fb49}', "\u{5e9}\u{5bc}"),
+    ('\u{fb4a}', "\u{5ea}\u{5bc}"),
+    ('\u{fb4b}', "\u{5d5}\u{5b9}"),
+    ('\u{fb4c}', "\u{5d1}\u{5bf}"),
+    ('\u{fb4d}', "\u{5db}\u{5bf}"),
+    ('\u{fb4e}', "\u{5e4}\u{5bf}"),
+    ('\u{ff21}', "\u{ff41}"),
+    ('\u{ff22}', "\u{ff42}"),
+    ('\u{ff23}', "\u{ff43}"),
+    ('\u{ff24}', "\u{ff44}"),
+    ('\u{ff25}', "\u{ff45}"),
+    ('\u{ff26}', "\u{ff46}"),
+    ('\u{ff27}', "\u{ff47}"),
+    ('\u{ff28}', "\u{ff48}"),
+    ('\u{ff29}', "\u{ff49}"),
+    ('\u{ff2a}', "\u{ff4a}"),
+    ('\u{ff2b}', "\u{ff4b}"),
+    ('\u{ff2c}', "\u{ff4c}"),
+    ('\u{ff2d}', "\u{ff4d}"),
+    ('\u{ff2e}', "\u{ff4e}"),
+    ('\u{ff2f}', "\u{ff4f}"),
+    ('\u{ff30}', "\u{ff50}"),
+    ('\u{ff31}', "\u{ff51}"),
+    ('\u{ff32}', "\u{ff52}"),
+    ('\u{ff33}', "\u{ff53}"),
+    ('\u{ff34}', "\u{ff54}"),
+    ('\u{ff35}', "\u{ff55}"),
+    ('\u{ff36}', "\u{ff56}"),
+    ('\u{ff37}', "\u{ff57}"),
+    ('\u{ff38}', "\u{ff58}"),
+    ('\u{ff39}', "\u{ff59}"),
+    ('\u{ff3a}', "\u{ff5a}"),
+    ('\u{10400}', "\u{10428}"),
+    ('\u{10401}', "\u{10429}"),
+    ('\u{10402}', "\u{1042a}"),
+    ('\u{10403}', "\u{1042b}"),
+    ('\u{10404}', "\u{1042c}"),
+    ('\u{10405}', "\u{1042d}"),
+    ('\u{10406}', "\u{1042e}"),
+    ('\u{10407}', "\u{1042f}"),
+    ('\u{10408}', "\u{10430}"),
+    ('\u{10409}', "\u{10431}"),
+    ('\u{1040a}', "\u{10432}"),
+    ('\u{1040b}', "\u{10433}"),
+    ('\u{1040c}', "\u{10434}"),
+    ('\u{1040d}', "\u{10435}"),
+    ('\u{1040e}', "\u{10436}"),
+    ('\u{1040f}', "\u{10437}"),
+    ('\u{10410}', "\u{10438}"),
+    ('\u{10411}', "\u{10439}"),
+    ('\u{10412}', "\u{1043a}"),
+    ('\u{10413}', "\u{1043b}"),
+    ('\u{10414}', "\u{1043c}"),
+    ('\u{10415}', "\u{1043d}"),
+    ('\u{10416}', "\u{1043e}"),
+    ('\u{10417}', "\u{1043f}"),
+    ('\u{10418}', "\u{10440}"),
+    ('\u{10419}', "\u{10441}"),
+    ('\u{1041a}', "\u{10442}"),
+    ('\u{1041b}', "\u{10443}"),
+    ('\u{1041c}', "\u{10444}"),
+    ('\u{1041d}', "\u{10445}"),
+    ('\u{1041e}', "\u{10446}"),
+    ('\u{1041f}', "\u{10447}"),
+    ('\u{10420}', "\u{10448}"),
+    ('\u{10421}', "\u{10449}"),
+    ('\u{10422}', "\u{1044a}"),
+    ('\u{10423}', "\u{1044b}"),
+    ('\u{10424}', "\u{1044c}"),
+    ('\u{10425}', "\u{1044d}"),
+    ('\u{10426}', "\u{1044e}"),
+    ('\u{10427}', "\u{1044f}"),
+    ('\u{104b0}', "\u{104d8}"),
+    ('\u{104b1}', "\u{104d9}"),
+    ('\u{104b2}', "\u{104da}"),
+    ('\u{104b3}', "\u{104db}"),
+    ('\u{104b4}', "\u{104dc}"),
+    ('\u{104b5}', "\u{104dd}"),
+    ('\u{104b6}', "\u{104de}"),
+    ('\u{104b7}', "\u{104df}"),
+    ('\u{104b8}', "\u{104e0}"),
+    ('\u{104b9}', "\u{104e1}"),
+    ('\u{104ba}', "\u{104e2}"),
+    ('\u{104bb}', "\u{104e3}"),
+    ('\u{104bc}', "\u{104e4}"),
+    ('\u{104bd}', "\u{104e5}"),
+    ('\u{104be}', "\u{104e6}"),
+    ('\u{104bf}', "\u{104e7}"),
+    ('\u{104c0}', "\u{104e8}"),
+    ('\u{104c1}', "\u{104e9}"),
+    ('\u{104c2}', "\u{104ea}"),
+    ('\u{104c3}', "\u{104eb}"),
+    ('\u{104c4}', "\u{104ec}"),
+    ('\u{104c5}', "\u{104ed}"),
+    ('\u{104c6}', "\u{104ee}"),
+    ('\u{104c7}', "\u{104ef}"),
+    ('\u{104c8}', "\u{104f0}"),
+    ('\u{104c9}', "\u{104f1}"),
+    ('\u{104ca}', "\u{104f2}"),
+    ('\u{104cb}', "\u{104f3}"),
+    ('\u{104cc}', "\u{104f4}"),
+    ('\u{104cd}', "\u{104f5}"),
+    ('\u{104ce}', "\u{104f6}"),
+    ('\u{104cf}', "\u{104f7}"),
+    ('\u{104d0}', "\u{104f8}"),
+    ('\u{104d1}', "\u{104f9}"),
+    ('\u{104d2}', "\u{104fa}"),
+    ('\u{104d3}', "\u{104fb}"),
+    ('\u{10570}', "\u{10597}"),
+    ('\u{10571}', "\u{10598}"),
+    ('\u{10572}', "\u{10599}"),
+    ('\u{10573}', "\u{1059a}"),
+    ('\u{10574}', "\u{1059b}"),
+    ('\u{10575}', "\u{1059c}"),
+    ('\u{10576}', "\u{1059d}"),
+    ('\u{10577}', "\u{1059e}"),
+    ('\u{10578}', "\u{1059f}"),
+    ('\u{10579}', "\u{105a0}"),
+    ('\u{1057a}', "\u{105a1}"),
+    ('\u{1057c}', "\u{105a3}"),
+    ('\u{1057d}', "\u{105a4}"),
+    ('\u{1057e}', "\u{105a5}"),
+    ('\u{1057f}', "\u{105a6}"),
+    ('\u{10580}', "\u{105a7}"),
+    ('\u{10581}', "\u{105a8}"),
+    ('\u{10582}', "\u{105a9}"),
+    ('\u{10583}', "\u{105aa}"),
+    ('\u{10584}', "\u{105ab}"),
+    ('\u{10585}', "\u{105ac}"),
+    ('\u{10586}', "\u{105ad}"),
+    ('\u{10587}', "\u{105ae}"),
+    ('\u{10588}', "\u{105af}"),
+    ('\u{10589}', "\u{105b0}"),
+    ('\u{1058a}', "\u{105b1}"),
+    ('\u{1058c}', "\u{105b3}"),
+    ('\u{1058d}', "\u{105b4}"),
+    ('\u{1058e}', "\u{105b5}"),
+    ('\u{1058f}', "\u{105b6}"),
+    ('\u{10590}', "\u{105b7}"),
+    ('\u{10591}', "\u{105b8}"),
+    ('\u{10592}', "\u{105b9}"),
+    ('\u{10594}', "\u{105bb}"),
+    ('\u{10595}', "\u{105bc}"),
+    ('\u{10c80}', "\u{10cc0}"),
+    ('\u{10c81}', "\u{10cc1}"),
+    ('\u{10c82}', "\u{10cc2}"),
+    ('\u{10c83}', "\u{10cc3}"),
+    ('\u{10c84}', "\u{10cc4}"),
+    ('\u{10c85}', "\u{10cc5}"),
+    ('\u{10c86}', "\u{10cc6}"),
+    ('\u{10c87}', "\u{10cc7}"),
+    ('\u{10c88}', "\u{10cc8}"),
+    ('\u{10c89}', "\u{10cc9}"),
+    ('\u{10c8a}', "\u{10cca}"),
+    ('\u{10c8b}', "\u{10ccb}"),
+    ('\u{10c8c}', "\u{10ccc}"),
+    ('\u{10c8d}', "\u{10ccd}"),
+    ('\u{10c8e}', "\u{10cce}"),
+    ('\u{10c8f}', "\u{10ccf}"),
+    ('\u{10c90}', "\u{10cd0}"),
+    ('\u{10c91}', "\u{10cd1}"),
+    ('\u{10c92}', "\u{10cd2}"),
+    ('\u{10c93}', "\u{10cd3}"),
+    ('\u{10c94}', "\u{10cd4}"),
+    ('\u{10c95}', "\u{10cd5}"),
+    ('\u{10c96}', "\u{10cd6}"),
+    ('\u{10c97}', "\u{10cd7}"),
+    ('\u{10c98}', "\u{10cd8}"),
+    ('\u{10c99}', "\u{10cd9}"),
+    ('\u{10c9a}', "\u{10cda}"),
+    ('\u{10c9b}', "\u{10cdb}"),
+    ('\u{10c9c}', "\u{10cdc}"),
+    ('\u{10c9d}', "\u{10cdd}"),
+    ('\u{10c9e}', "\u{10cde}"),
+    ('\u{10c9f}', "\u{10cdf}"),
+    ('\u{10ca0}', "\u{10ce0}"),
+    ('\u{10ca1}', "\u{10ce1}"),
+    ('\u{10ca2}', "\u{10ce2}"),
+    ('\u{10ca3}', "\u{10ce3}"),
+    ('\u{10ca4}', "\u{10ce4}"),
+    ('\u{10ca5}', "\u{10ce5}"),
+    ('\u{10ca6}', "\u{10ce6}"),
+    ('\u{10ca7}', "\u{10ce7}"),
+    ('\u{10ca8}', "\u{10ce8}"),
+    ('\u{10ca9}', "\u{10ce9}"),
+    ('\u{10caa}', "\u{10cea}"),
+    ('\u{10cab}', "\u{10ceb}"),
+    ('\u{10cac}', "\u{10cec}"),
+    ('\u{10cad}', "\u{10ced}"),
+    ('\u{10cae}', "\u{10cee}"),
+    ('\u{10caf}', "\u{10cef}"),
+    ('\u{10cb0}', "\u{10cf0}"),
+    ('\u{10cb1}', "\u{10cf1}"),
+    ('\u{10cb2}', "\u{10cf2}"),
+    ('\u{1109a}', "\u{11099}\u{110ba}"),
+    ('\u{1109c}', "\u{1109b}\u{110ba}"),
+    ('\u{110ab}', "\u{110a5}\u{110ba}"),
+    ('\u{1112e}', "\u{11131}\u{11127}"),
+    ('\u{1112f}', "\u{11132}\u{11127}"),
+    ('\u{1134b}', "\u{11347}\u{1133e}"),
+    ('\u{1134c}', "\u{11347}\u{11357}"),
+    ('\u{114bb}', "\u{114b9}\u{114ba}"),
+    ('\u{114bc}', "\u{114b9}\u{114b0}"),
+    ('\u{114be}', "\u{114b9}\u{114bd}"),
+    ('\u{115ba}', "\u{115b8}\u{115af}"),
+    ('\u{115bb}', "\u{115b9}\u{115af}"),
+    ('\u{118a0}', "\u{118c0}"),
+    ('\u{118a1}', "\u{118c1}"),
+    ('\u{118a2}', "\u{118c2}"),
+    ('\u{118a3}', "\u{118c3}"),
+    ('\u{118a4}', "\u{118c4}"),
+    ('\u{118a5}', "\u{118c5}"),
+    ('\u{118a6}', "\u{118c6}"),
+    ('\u{118a7}', "\u{118c7}"),
+    ('\u{118a8}', "\u{118c8}"),
+    ('\u{118a9}', "\u{118c9}"),
+    ('\u{118aa}', "\u{118ca}"),
+    ('\u{118ab}', "\u{118cb}"),
+    ('\u{118ac}', "\u{118cc}"),
+    ('\u{118ad}', "\u{118cd}"),
+    ('\u{118ae}', "\u{118ce}"),
+    ('\u{118af}', "\u{118cf}"),
+    ('\u{118b0}', "\u{118d0}"),
+    ('\u{118b1}', "\u{118d1}"),
+    ('\u{118b2}', "\u{118d2}"),
+    ('\u{118b3}', "\u{118d3}"),
+    ('\u{118b4}', "\u{118d4}"),
+    ('\u{118b5}', "\u{118d5}"),
+    ('\u{118b6}', "\u{118d6}"),
+    ('\u{118b7}', "\u{118d7}"),
+    ('\u{118b8}', "\u{118d8}"),
+    ('\u{118b9}', "\u{118d9}"),
+    ('\u{118ba}', "\u{118da}"),
+    ('\u{118bb}', "\u{118db}"),
+    ('\u{118bc}', "\u{118dc}"),
+    ('\u{118bd}', "\u{118dd}"),
+    ('\u{118be}', "\u{118de}"),
+    ('\u{118bf}', "\u{118df}"),
+    ('\u{11938}', "\u{11935}\u{11930}"),
+    ('\u{16e40}', "\u{16e60}"),
+    ('\u{16e41}', "\u{16e61}"),
+    ('\u{16e42}', "\u{16e62}"),
+    ('\u{16e43}', "\u{16e63}"),
+    ('\u{16e44}', "\u{16e64}"),
+    ('\u{16e45}', "\u{16e65}"),
+    ('\u{16e46}', "\u{16e66}"),
+    ('\u{16e47}', "\u{16e67}"),
+    ('\u{16e48}', "\u{16e68}"),
+    ('\u{16e49}', "\u{16e69}"),
+    ('\u{16e4a}', "\u{16e6a}"),
+    ('\u{16e4b}', "\u{16e6b}"),
+    ('\u{16e4c}', "\u{16e6c}"),
+    ('\u{16e4d}', "\u{16e6d}"),
+    ('\u{16e4e}', "\u{16e6e}"),
+    ('\u{16e4f}', "\u{16e6f}"),
+    ('\u{16e50}', "\u{16e70}"),
+    ('\u{16e51}', "\u{16e71}"),
+    ('\u{16e52}', "\u{16e72}"),
+    ('\u{16e53}', "\u{16e73}"),
+    ('\u{16e54}', "\u{16e74}"),
+    ('\u{16e55}', "\u{16e75}"),
+    ('\u{16e56}', "\u{16e76}"),
+    ('\u{16e57}', "\u{16e77}"),
+    ('\u{16e58}', "\u{16e78}"),
+    ('\u{16e59}', "\u{16e79}"),
+    ('\u{16e5a}', "\u{16e7a}"),
+    ('\u{16e5b}', "\u{16e7b}"),
+    ('\u{16e5c}', "\u{16e7c}"),
+    ('\u{16e5d}', "\u{16e7d}"),
+    ('\u{16e5e}', "\u{16e7e}"),
+    ('\u{16e5f}', "\u{16e7f}"),
+    ('\u{1d15e}', "\u{1d157}\u{1d165}"),
+    ('\u{1d15f}', "\u{1d158}\u{1d165}"),
+    ('\u{1d160}', "\u{1d158}\u{1d165}\u{1d16e}"),
+    ('\u{1d161}', "\u{1d158}\u{1d165}\u{1d16f}"),
+    ('\u{1d162}', "\u{1d158}\u{1d165}\u{1d170}"),
+    ('\u{1d163}', "\u{1d158}\u{1d165}\u{1d171}"),
+    ('\u{1d164}', "\u{1d158}\u{1d165}\u{1d172}"),
+    ('\u{1d1bb}', "\u{1d1b9}\u{1d165}"),
+    ('\u{1d1bc}', "\u{1d1ba}\u{1d165}"),
+    ('\u{1d1bd}', "\u{1d1b9}\u{1d165}\u{1d16e}"),
+    ('\u{1d1be}', "\u{1d1ba}\u{1d165}\u{1d16e}"),
+    ('\u{1d1bf}', "\u{1d1b9}\u{1d165}\u{1d16f}"),
+    ('\u{1d1c0}', "\u{1d1ba}\u{1d165}\u{1d16f}"),
+    ('\u{1e900}', "\u{1e922}"),
+    ('\u{1e901}', "\u{1e923}"),
+    ('\u{1e902}', "\u{1e924}"),
+    ('\u{1e903}', "\u{1e925}"),
+    ('\u{1e904}', "\u{1e926}"),
+    ('\u{1e905}', "\u{1e927}"),
+    ('\u{1e906}', "\u{1e928}"),
+    ('\u{1e907}', "\u{1e929}"),
+    ('\u{1e908}', "\u{1e92a}"),
+    ('\u{1e909}', "\u{1e92b}"),
+    ('\u{1e90a}', "\u{1e92c}"),
+    ('\u{1e90b}', "\u{1e92d}"),
+    ('\u{1e90c}', "\u{1e92e}"),
+    ('\u{1e90d}', "\u{1e92f}"),
+    ('\u{1e90e}', "\u{1e930}"),
+    ('\u{1e90f}', "\u{1e931}"),
+    ('\u{1e910}', "\u{1e932}"),
+    ('\u{1e911}', "\u{1e933}"),
+    ('\u{1e912}', "\u{1e934}"),
+    ('\u{1e913}', "\u{1e935}"),
+    ('\u{1e914}', "\u{1e936}"),
+    ('\u{1e915}', "\u{1e937}"),
+    ('\u{1e916}', "\u{1e938}"),
+    ('\u{1e917}', "\u{1e939}"),
+    ('\u{1e918}', "\u{1e93a}"),
+    ('\u{1e919}', "\u{1e93b}"),
+    ('\u{1e91a}', "\u{1e93c}"),
+    ('\u{1e91b}', "\u{1e93d}"),
+    ('\u{1e91c}', "\u{1e93e}"),
+    ('\u{1e91d}', "\u{1e93f}"),
+    ('\u{1e91e}', "\u{1e940}"),
+    ('\u{1e91f}', "\u{1e941}"),
+    ('\u{1e920}', "\u{1e942}"),
+    ('\u{1e921}', "\u{1e943}"),
+    ('\u{2f800}', "\u{4e3d}"),
+    ('\u{2f801}', "\u{4e38}"),
+    ('\u{2f802}', "\u{4e41}"),
+    ('\u{2f803}', "\u{20122}"),
+    ('\u{2f804}', "\u{4f60}"),
+    ('\u{2f805}', "\u{4fae}"),
+    ('\u{2f806}', "\u{4fbb}"),
+    ('\u{2f807}', "\u{5002}"),
+    ('\u{2f808}', "\u{507a}"),
+    ('\u{2f809}', "\u{5099}"),
+    ('\u{2f80a}', "\u{50e7}"),
+    ('\u{2f80b}', "\u{50cf}"),
+    ('\u{2f80c}', "\u{349e}"),
+    ('\u{2f80d}', "\u{2063a}"),
+    ('\u{2f80e}', "\u{514d}"),
+    ('\u{2f80f}', "\u{5154}"),
+    ('\u{2f810}', "\u{5164}"),
+    ('\u{2f811}', "\u{5177}"),
+    ('\u{2f812}', "\u{2051c}"),
+    ('\u{2f813}', "\u{34b9}"),
+    ('\u{2f814}', "\u{5167}"),
+    ('\u{2f815}', "\u{518d}"),
+    ('\u{2f816}', "\u{2054b}"),
+    ('\u{2f817}', "\u{5197}"),
+    ('\u{2f818}', "\u{51a4}"),
+    ('\u{2f819}', "\u{4ecc}"),
+    ('\u{2f81a}', "\u{51ac}"),
+    ('\u{2f81b}', "\u{51b5}"),
+    ('\u{2f81c}', "\u{291df}"),
+    ('\u{2f81d}', "\u{51f5}"),
+    ('\u{2f81e}', "\u{5203}"),
+    ('\u{2f81f}', "\u{34df}"),
+    ('\u{2f820}', "\u{523b}"),
+    ('\u{2f821}', "\u{5246}"),
+    ('\u{2f822}', "\u{5272}"),
+    ('\u{2f823}', "\u{5277}"),
+    ('\u{2f824}', "\u{3515}"),
+    ('\u{2f825}', "\u{52c7}"),
+    ('\u{2f826}', "\u{52c9}"),
+    ('\u{2f827}', "\u{52e4}"),
+    ('\u{2f828}', "\u{52fa}"),
+    ('\u{2f829}', "\u{5305}"),
+    ('\u{2f82a}', "\u{5306}"),
+    ('\u{2f82b}', "\u{5317}"),
+    ('\u{2f82c}', "\u{5349}"),
+    ('\u{2f82d}', "\u{5351}"),
+    ('\u{2f82e}', "\u{535a}"),
+    ('\u{2f82f}', "\u{5373}"),
+    ('\u{2f830}', "\u{537d}"),
+    ('\u{2f831}', "\u{537f}"),
+    ('\u{2f832}', "\u{537f}"),
+    ('\u{2f833}', "\u{537f}"),
+    ('\u{2f834}', "\u{20a2c}"),
+    ('\u{2f835}', "\u{7070}"),
+    ('\u{2f836}', "\u{53ca}"),
+    ('\u{2f837}', "\u{53df}"),
+    ('\u{2f838}', "\u{20b63}"),
+    ('\u{2f839}', "\u{53eb}"),
+    ('\u{2f83a}', "\u{53f1}"),
+    ('\u{2f83b}', "\u{5406}"),
+    ('\u{2f83c}', "\u{549e}"),
+    ('\u{2f83d}', "\u{5438}"),
+    ('\u{2f83e}', "\u{5448}"),
+    ('\u{2f83f}', "\u{5468}"),
+    ('\u{2f840}', "\u{54a2}"),
+    ('\u{2f841}', "\u{54f6}"),
+    ('\u{2f842}', "\u{5510}"),
+    ('\u{2f843}', "\u{5553}"),
+    ('\u{2f844}', "\u{5563}"),
+    ('\u{2f845}', "\u{5584}"),
+    ('\u{2f846}', "\u{5584}"),
+    ('\u{2f847}', "\u{5599}"),
+    ('\u{2f848}', "\u{55ab}"),
+    ('\u{2f849}', "\u{55b3}"),
+    ('\u{2f84a}', "\u{55c2}"),
+    ('\u{2f84b}', "\u{5716}"),
+    ('\u{2f84c}', "\u{5606}"),
+    ('\u{2f84d}', "\u{5717}"),
+    ('\u{2f84e}', "\u{5651}"),
+    ('\u{2f84f}', "\u{5674}"),
+    ('\u{2f850}', "\u{5207}"),
+    ('\u{2f851}', "\u{58ee}"),
+    ('\u{2f852}', "\u{57ce}"),
+    ('\u{2f853}', "\u{57f4}"),
+    ('\u{2f854}', "\u{580d}"),
+    ('\u{2f855}', "\u{578b}"),
+    ('\u{2f856}', "\u{5832}"),
+    ('\u{2f857}', "\u{5831}"),
+    ('\u{2f858}', "\u{58ac}"),
+    ('\u{2f859}', "\u{214e4}"),
+    ('\u{2f85a}', "\u{58f2}"),
+    ('\u{2f85b}', "\u{58f7}"),
+    ('\u{2f85c}', "\u{5906}"),
+    ('\u{2f85d}', "\u{591a}"),
+    ('\u{2f85e}', "\u{5922}"),
+    ('\u{2f85f}', "\u{5962}"),
+    ('\u{2f860}', "\u{216a8}"),
+    ('\u{2f861}', "\u{216ea}"),
+    ('\u{2f862}', "\u{59ec}"),
+    ('\u{2f863}', "\u{5a1b}"),
+    ('\u{2f864}', "\u{5a27}"),
+    ('\u{2f865}', "\u{59d8}"),
+    ('\u{2f866}', "\u{5a66}"),
+    ('\u{2f867}', "\u{36ee}"),
+    ('\u{2f868}', "\u{36fc}"),
+    ('\u{2f869}', "\u{5b08}"),
+    ('\u{2f86a}', "\u{5b3e}"),
+    ('\u{2f86b}', "\u{5b3e}"),
+    ('\u{2f86c}', "\u{219c8}"),
+    ('\u{2f86d}', "\u{5bc3}"),
+    ('\u{2f86e}', "\u{5bd8}"),
+    ('\u{2f86f}', "\u{5be7}"),
+    ('\u{2f870}', "\u{5bf3}"),
+    ('\u{2f871}', "\u{21b18}"),
+    ('\u{2f872}', "\u{5bff}"),
+    ('\u{2f873}', "\u{5c06}"),
+    ('\u{2f874}', "\u{5f53}"),
+    ('\u{2f875}', "\u{5c22}"),
+    ('\u{2f876}', "\u{3781}"),
+    ('\u{2f877}', "\u{5c60}"),
+    ('\u{2f878}', "\u{5c6e}"),
+    ('\u{2f879}', "\u{5cc0}"),
+    ('\u{2f87a}', "\u{5c8d}"),
+    ('\u{2f87b}', "\u{21de4}"),
+    ('\u{2f87c}', "\u{5d43}"),
+    ('\u{2f87d}', "\u{21de6}"),
+    ('\u{2f87e}', "\u{5d6e}"),
+    ('\u{2f87f}', "\u{5d6b}"),
+    ('\u{2f880}', "\u{5d7c}"),
+    ('\u{2f881}', "\u{5de1}"),
+    ('\u{2f882}', "\u{5de2}"),
+    ('\u{2f883}', "\u{382f}"),
+    ('\u{2f884}', "\u{5dfd}"),
+    ('\u{2f885}', "\u{5e28}"),
+    ('\u{2f886}', "\u{5e3d}"),
+    ('\u{2f887}', "\u{5e69}"),
+    ('\u{2f888}', "\u{3862}"),
+    ('\u{2f889}', "\u{22183}"),
+    ('\u{2f88a}', "\u{387c}"),
+    ('\u{2f88b}', "\u{5eb0}"),
+    ('\u{2f88c}', "\u{5eb3}"),
+    ('\u{2f88d}', "\u{5eb6}"),
+    ('\u{2f88e}', "\u{5eca}"),
+    ('\u{2f88f}', "\u{2a392}"),
+    ('\u{2f890}', "\u{5efe}"),
+    ('\u{2f891}', "\u{22331}"),
+    ('\u{2f892}', "\u{22331}"),
+    ('\u{2f893}', "\u{8201}"),
+    ('\u{2f894}', "\u{5f22}"),
+    ('\u{2f895}', "\u{5f22}"),
+    ('\u{2f896}', "\u{38c7}"),
+    ('\u{2f897}', "\u{232b8}"),
+    ('\u{2f898}', "\u{261da}"),
+    ('\u{2f899}', "\u{5f62}"),
+    ('\u{2f89a}', "\u{5f6b}"),
+    ('\u{2f89b}', "\u{38e3}"),
+    ('\u{2f89c}', "\u{5f9a}"),
+    ('\u{2f89d}', "\u{5fcd}"),
+    ('\u{2f89e}', "\u{5fd7}"),
+    ('\u{2f89f}', "\u{5ff9}"),
+    ('\u{2f8a0}', "\u{6081}"),
+    ('\u{2f8a1}', "\u{393a}"),
+    ('\u{2f8a2}', "\u{391c}"),
+    ('\u{2f8a3}', "\u{6094}"),
+    ('\u{2f8a4}', "\u{226d4}"),
+    ('\u{2f8a5}', "\u{60c7}"),
+    ('\u{2f8a6}', "\u{6148}"),
+    ('\u{2f8a7}', "\u{614c}"),
+    ('\u{2f8a8}', "\u{614e}"),
+    ('\u{2f8a9}', "\u{614c}"),
+    ('\u{2f8aa}', "\u{617a}"),
+    ('\u{2f8ab}', "\u{618e}"),
+    ('\u{2f8ac}', "\u{61b2}"),
+    ('\u{2f8ad}', "\u{61a4}"),
+    ('\u{2f8ae}', "\u{61af}"),
+    ('\u{2f8af}', "\u{61de}"),
+    ('\u{2f8b0}', "\u{61f2}"),
+    ('\u{2f8b1}', "\u{61f6}"),
+    ('\u{2f8b2}', "\u{6210}"),
+    ('\u{2f8b3}', "\u{621b}"),
+    ('\u{2f8b4}', "\u{625d}"),
+    ('\u{2f8b5}', "\u{62b1}"),
+    ('\u{2f8b6}', "\u{62d4}"),
+    ('\u{2f8b7}', "\u{6350}"),
+    ('\u{2f8b8}', "\u{22b0c}"),
+    ('\u{2f8b9}', "\u{633d}"),
+    ('\u{2f8ba}', "\u{62fc}"),
+    ('\u{2f8bb}', "\u{6368}"),
+    ('\u{2f8bc}', "\u{6383}"),
+    ('\u{2f8bd}', "\u{63e4}"),
+    ('\u{2f8be}', "\u{22bf1}"),
+    ('\u{2f8bf}', "\u{6422}"),
+    ('\u{2f8c0}', "\u{63c5}"),
+    ('\u{2f8c1}', "\u{63a9}"),
+    ('\u{2f8c2}', "\u{3a2e}"),
+    ('\u{2f8c3}', "\u{6469}"),
+    ('\u{2f8c4}', "\u{647e}"),
+    ('\u{2f8c5}', "\u{649d}"),
+    ('\u{2f8c6}', "\u{6477}"),
+    ('\u{2f8c7}', "\u{3a6c}"),
+    ('\u{2f8c8}', "\u{654f}"),
+    ('\u{2f8c9}', "\u{656c}"),
+    ('\u{2f8ca}', "\u{2300a}"),
+    ('\u{2f8cb}', "\u{65e3}"),
+    ('\u{2f8cc}', "\u{66f8}"),
+    ('\u{2f8cd}', "\u{6649}"),
+    ('\u{2f8ce}', "\u{3b19}"),
+    ('\u{2f8cf}', "\u{6691}"),
+    ('\u{2f8d0}', "\u{3b08}"),
+    ('\u{2f8d1}', "\u{3ae4}"),
+    ('\u{2f8d2}', "\u{5192}"),
+    ('\u{2f8d3}', "\u{5195}"),
+    ('\u{2f8d4}', "\u{6700}"),
+    ('\u{2f8d5}', "\u{669c}"),
+    ('\u{2f8d6}', "\u{80ad}"),
+    ('\u{2f8d7}', "\u{43d9}"),
+    ('\u{2f8d8}', "\u{6717}"),
+    ('\u{2f8d9}', "\u{671b}"),
+    ('\u{2f8da}', "\u{6721}"),
+    ('\u{2f8db}', "\u{675e}"),
+    ('\u{2f8dc}', "\u{6753}"),
+    ('\u{2f8dd}', "\u{233c3}"),
+    ('\u{2f8de}', "\u{3b49}"),
+    ('\u{2f8df}', "\u{67fa}"),
+    ('\u{2f8e0}', "\u{6785}"),
+    ('\u{2f8e1}', "\u{6852}"),
+    ('\u{2f8e2}', "\u{6885}"),
+    ('\u{2f8e3}', "\u{2346d}"),
+    ('\u{2f8e4}', "\u{688e}"),
+    ('\u{2f8e5}', "\u{681f}"),
+    ('\u{2f8e6}', "\u{6914}"),
+    ('\u{2f8e7}', "\u{3b9d}"),
+    ('\u{2f8e8}', "\u{6942}"),
+    ('\u{2f8e9}', "\u{69a3}"),
+    ('\u{2f8ea}', "\u{69ea}"),
+    ('\u{2f8eb}', "\u{6aa8}"),
+    ('\u{2f8ec}', "\u{236a3}"),
+    ('\u{2f8ed}', "\u{6adb}"),
+    ('\u{2f8ee}', "\u{3c18}"),
+    ('\u{2f8ef}', "\u{6b21}"),
+    ('\u{2f8f0}', "\u{238a7}"),
+    ('\u{2f8f1}', "\u{6b54}"),
+    ('\u{2f8f2}', "\u{3c4e}"),
+    ('\u{2f8f3}', "\u{6b72}"),
+    ('\u{2f8f4}', "\u{6b9f}"),
+    ('\u{2f8f5}', "\u{6bba}"),
+    ('\u{2f8f6}', "\u{6bbb}"),
+    ('\u{2f8f7}', "\u{23a8d}"),
+    ('\u{2f8f8}', "\u{21d0b}"),
+    ('\u{2f8f9}', "\u{23afa}"),
+    ('\u{2f8fa}', "\u{6c4e}"),
+    ('\u{2f8fb}', "\u{23cbc}"),
+    ('\u{2f8fc}', "\u{6cbf}"),
+    ('\u{2f8fd}', "\u{6ccd}"),
+    ('\u{2f8fe}', "\u{6c67}"),
+    ('\u{2f8ff}', "\u{6d16}"),
+    ('\u{2f900}', "\u{6d3e}"),
+    ('\u{2f901}', "\u{6d77}"),
+    ('\u{2f902}', "\u{6d41}"),
+    ('\u{2f903}', "\u{6d69}"),
+    ('\u{2f904}', "\u{6d78}"),
+    ('\u{2f905}', "\u{6d85}"),
+    ('\u{2f906}', "\u{23d1e}"),
+    ('\u{2f907}', "\u{6d34}"),
+    ('\u{2f908}', "\u{6e2f}"),
+    ('\u{2f909}', "\u{6e6e}"),
+    ('\u{2f90a}', "\u{3d33}"),
+    ('\u{2f90b}', "\u{6ecb}"),
+    ('\u{2f90c}', "\u{6ec7}"),
+    ('\u{2f90d}', "\u{23ed1}"),
+    ('\u{2f90e}', "\u{6df9}"),
+    ('\u{2f90f}', "\u{6f6e}"),
+    ('\u{2f910}', "\u{23f5e}"),
+    ('\u{2f911}', "\u{23f8e}"),
+    ('\u{2f912}', "\u{6fc6}"),
+    ('\u{2f913}', "\u{7039}"),
+    ('\u{2f914}', "\u{701e}"),
+    ('\u{2f915}', "\u{701b}"),
+    ('\u{2f916}', "\u{3d96}"),
+    ('\u{2f917}', "\u{704a}"),
+    ('\u{2f918}', "\u{707d}"),
+    ('\u{2f919}', "\u{7077}"),
+    ('\u{2f91a}', "\u{70ad}"),
+    ('\u{2f91b}', "\u{20525}"),
+    ('\u{2f91c}', "\u{7145}"),
+    ('\u{2f91d}', "\u{24263}"),
+    ('\u{2f91e}', "\u{719c}"),
+    ('\u{2f91f}', "\u{243ab}"),
+    ('\u{2f920}', "\u{7228}"),
+    ('\u{2f921}', "\u{7235}"),
+    ('\u{2f922}', "\u{7250}"),
+    ('\u{2f923}', "\u{24608}"),
+    ('\u{2f924}', "\u{7280}"),
+    ('\u{2f925}', "\u{7295}"),
+    ('\u{2f926}', "\u{24735}"),
+    ('\u{2f927}', "\u{24814}"),
+    ('\u{2f928}', "\u{737a}"),
+    ('\u{2f929}', "\u{738b}"),
+    ('\u{2f92a}', "\u{3eac}"),
+    ('\u{2f92b}', "\u{73a5}"),
+    ('\u{2f92c}', "\u{3eb8}"),
+    ('\u{2f92d}', "\u{3eb8}"),
+    ('\u{2f92e}', "\u{7447}"),
+    ('\u{2f92f}', "\u{745c}"),
+    ('\u{2f930}', "\u{7471}"),
+    ('\u{2f931}', "\u{7485}"),
+    ('\u{2f932}', "\u{74ca}"),
+    ('\u{2f933}', "\u{3f1b}"),
+    ('\u{2f934}', "\u{7524}"),
+    ('\u{2f935}', "\u{24c36}"),
+    ('\u{2f936}', "\u{753e}"),
+    ('\u{2f937}', "\u{24c92}"),
+    ('\u{2f938}', "\u{7570}"),
+    ('\u{2f939}', "\u{2219f}"),
+    ('\u{2f93a}', "\u{7610}"),
+    ('\u{2f93b}', "\u{24fa1}"),
+    ('\u{2f93c}', "\u{24fb8}"),
+    ('\u{2f93d}', "\u{25044}"),
+    ('\u{2f93e}', "\u{3ffc}"),
+    ('\u{2f93f}', "\u{4008}"),
+    ('\u{2f940}', "\u{76f4}"),
+    ('\u{2f941}', "\u{250f3}"),
+    ('\u{2f942}', "\u{250f2}"),
+    ('\u{2f943}', "\u{25119}"),
+    ('\u{2f944}', "\u{25133}"),
+    ('\u{2f945}', "\u{771e}"),
+    ('\u{2f946}', "\u{771f}"),
+    ('\u{2f947}', "\u{771f}"),
+    ('\u{2f948}', "\u{774a}"),
+    ('\u{2f949}', "\u{4039}"),
+    ('\u{2f94a}', "\u{778b}"),
+    ('\u{2f94b}', "\u{4046}"),
+    ('\u{2f94c}', "\u{4096}"),
+    ('\u{2f94d}', "\u{2541d}"),
+    ('\u{2f94e}', "\u{784e}"),
+    ('\u{2f94f}', "\u{788c}"),
+    ('\u{2f950}', "\u{78cc}"),
+    ('\u{2f951}', "\u{40e3}"),
+    ('\u{2f952}', "\u{25626}"),
+    ('\u{2f953}', "\u{7956}"),
+    ('\u{2f954}', "\u{2569a}"),
+    ('\u{2f955}', "\u{256c5}"),
+    ('\u{2f956}', "\u{798f}"),
+    ('\u{2f957}', "\u{79eb}"),
+    ('\u{2f958}', "\u{412f}"),
+    ('\u{2f959}', "\u{7a40}"),
+    ('\u{2f95a}', "\u{7a4a}"),
+    ('\u{2f95b}', "\u{7a4f}"),
+    ('\u{2f95c}', "\u{2597c}"),
+    ('\u{2f95d}', "\u{25aa7}"),
+    ('\u{2f95e}', "\u{25aa7}"),
+    ('\u{2f95f}', "\u{7aee}"),
+    ('\u{2f960}', "\u{4202}"),
+    ('\u{2f961}', "\u{25bab}"),
+    ('\u{2f962}', "\u{7bc6}"),
+    ('\u{2f963}', "\u{7bc9}"),
+    ('\u{2f964}', "\u{4227}"),
+    ('\u{2f965}', "\u{25c80}"),
+    ('\u{2f966}', "\u{7cd2}"),
+    ('\u{2f967}', "\u{42a0}"),
+    ('\u{2f968}', "\u{7ce8}"),
+    ('\u{2f969}', "\u{7ce3}"),
+    ('\u{2f96a}', "\u{7d00}"),
+    ('\u{2f96b}', "\u{25f86}"),
+    ('\u{2f96c}', "\u{7d63}"),
+    ('\u{2f96d}', "\u{4301}"),
+    ('\u{2f96e}', "\u{7dc7}"),
+    ('\u{2f96f}', "\u{7e02}"),
+    ('\u{2f970}', "\u{7e45}"),
+    ('\u{2f971}', "\u{4334}"),
+    ('\u{2f972}', "\u{26228}"),
+    ('\u{2f973}', "\u{26247}"),
+    ('\u{2f974}', "\u{4359}"),
+    ('\u{2f975}', "\u{262d9}"),
+    ('\u{2f976}', "\u{7f7a}"),
+    ('\u{2f977}', "\u{2633e}"),
+    ('\u{2f978}', "\u{7f95}"),
+    ('\u{2f979}', "\u{7ffa}"),
+    ('\u{2f97a}', "\u{8005}"),
+    ('\u{2f97b}', "\u{264da}"),
+    ('\u{2f97c}', "\u{26523}"),
+    ('\u{2f97d}', "\u{8060}"),
+    ('\u{2f97e}', "\u{265a8}"),
+    ('\u{2f97f}', "\u{8070}"),
+    ('\u{2f980}', "\u{2335f}"),
+    ('\u{2f981}', "\u{43d5}"),
+    ('\u{2f982}', "\u{80b2}"),
+    ('\u{2f983}', "\u{8103}"),
+    ('\u{2f984}', "\u{440b}"),
+    ('\u{2f985}', "\u{813e}"),
+    ('\u{2f986}', "\u{5ab5}"),
+    ('\u{2f987}', "\u{267a7}"),
+    ('\u{2f988}', "\u{267b5}"),
+    ('\u{2f989}', "\u{23393}"),
+    ('\u{2f98a}', "\u{2339c}"),
+    ('\u{2f98b}', "\u{8201}"),
+    ('\u{2f98c}', "\u{8204}"),
+    ('\u{2f98d}', "\u{8f9e}"),
+    ('\u{2f98e}', "\u{446b}"),
+    ('\u{2f98f}', "\u{8291}"),
+    ('\u{2f990}', "\u{828b}"),
+    ('\u{2f991}', "\u{829d}"),
+    ('\u{2f992}', "\u{52b3}"),
+    ('\u{2f993}', "\u{82b1}"),
+    ('\u{2f994}', "\u{82b3}"),
+    ('\u{2f995}', "\u{82bd}"),
+    ('\u{2f996}', "\u{82e6}"),
+    ('\u{2f997}', "\u{26b3c}"),
+    ('\u{2f998}', "\u{82e5}"),
+    ('\u{2f999}', "\u{831d}"),
+    ('\u{2f99a}', "\u{8363}"),
+    ('\u{2f99b}', "\u{83ad}"),
+    ('\u{2f99c}', "\u{8323}"),
+    ('\u{2f99d}', "\u{83bd}"),
+    ('\u{2f99e}', "\u{83e7}"),
+    ('\u{2f99f}', "\u{8457}"),
+    ('\u{2f9a0}', "\u{8353}"),
+    ('\u{2f9a1}', "\u{83ca}"),
+    ('\u{2f9a2}', "\u{83cc}"),
+    ('\u{2f9a3}', "\u{83dc}"),
+    ('\u{2f9a4}', "\u{26c36}"),
+    ('\u{2f9a5}', "\u{26d6b}"),
+    ('\u{2f9a6}', "\u{26cd5}"),
+    ('\u{2f9a7}', "\u{452b}"),
+    ('\u{2f9a8}', "\u{84f1}"),
+    ('\u{2f9a9}', "\u{84f3}"),
+    ('\u{2f9aa}', "\u{8516}"),
+    ('\u{2f9ab}', "\u{273ca}"),
+    ('\u{2f9ac}', "\u{8564}"),
+    ('\u{2f9ad}', "\u{26f2c}"),
+    ('\u{2f9ae}', "\u{455d}"),
+    ('\u{2f9af}', "\u{4561}"),
+    ('\u{2f9b0}', "\u{26fb1}"),
+    ('\u{2f9b1}', "\u{270d2}"),
+    ('\u{2f9b2}', "\u{456b}"),
+    ('\u{2f9b3}', "\u{8650}"),
+    ('\u{2f9b4}', "\u{865c}"),
+    ('\u{2f9b5}', "\u{8667}"),
+    ('\u{2f9b6}', "\u{8669}"),
+    ('\u{2f9b7}', "\u{86a9}"),
+    ('\u{2f9b8}', "\u{8688}"),
+    ('\u{2f9b9}', "\u{870e}"),
+    ('\u{2f9ba}', "\u{86e2}"),
+    ('\u{2f9bb}', "\u{8779}"),
+    ('\u{2f9bc}', "\u{8728}"),
+    ('\u{2f9bd}', "\u{876b}"),
+    ('\u{2f9be}', "\u{8786}"),
+    ('\u{2f9bf}', "\u{45d7}"),
+    ('\u{2f9c0}', "\u{87e1}"),
+    ('\u{2f9c1}', "\u{8801}"),
+    ('\u{2f9c2}', "\u{45f9}"),
+    ('\u{2f9c3}', "\u{8860}"),
+    ('\u{2f9c4}', "\u{8863}"),
+    ('\u{2f9c5}', "\u{27667}"),
+    ('\u{2f9c6}', "\u{88d7}"),
+    ('\u{2f9c7}', "\u{88de}"),
+    ('\u{2f9c8}', "\u{4635}"),
+    ('\u{2f9c9}', "\u{88fa}"),
+    ('\u{2f9ca}', "\u{34bb}"),
+    ('\u{2f9cb}', "\u{278ae}"),
+    ('\u{2f9cc}', "\u{27966}"),
+    ('\u{2f9cd}', "\u{46be}"),
+    ('\u{2f9ce}', "\u{46c7}"),
+    ('\u{2f9cf}', "\u{8aa0}"),
+    ('\u{2f9d0}', "\u{8aed}"),
+    ('\u{2f9d1}', "\u{8b8a}"),
+    ('\u{2f9d2}', "\u{8c55}"),
+    ('\u{2f9d3}', "\u{27ca8}"),
+    ('\u{2f9d4}', "\u{8cab}"),
+    ('\u{2f9d5}', "\u{8cc1}"),
+    ('\u{2f9d6}', "\u{8d1b}"),
+    ('\u{2f9d7}', "\u{8d77}"),
+    ('\u{2f9d8}', "\u{27f2f}"),
+    ('\u{2f9d9}', "\u{20804}"),
+    ('\u{2f9da}', "\u{8dcb}"),
+    ('\u{2f9db}', "\u{8dbc}"),
+    ('\u{2f9dc}', "\u{8df0}"),
+    ('\u{2f9dd}', "\u{208de}"),
+    ('\u{2f9de}', "\u{8ed4}"),
+    ('\u{2f9df}', "\u{8f38}"),
+    ('\u{2f9e0}', "\u{285d2}"),
+    ('\u{2f9e1}', "\u{285ed}"),
+    ('\u{2f9e2}', "\u{9094}"),
+    ('\u{2f9e3}', "\u{90f1}"),
+    ('\u{2f9e4}', "\u{9111}"),
+    ('\u{2f9e5}', "\u{2872e}"),
+    ('\u{2f9e6}', "\u{911b}"),
+    ('\u{2f9e7}', "\u{9238}"),
+    ('\u{2f9e8}', "\u{92d7}"),
+    ('\u{2f9e9}', "\u{92d8}"),
+    ('\u{2f9ea}', "\u{927c}"),
+    ('\u{2f9eb}', "\u{93f9}"),
+    ('\u{2f9ec}', "\u{9415}"),
+    ('\u{2f9ed}', "\u{28bfa}"),
+    ('\u{2f9ee}', "\u{958b}"),
+    ('\u{2f9ef}', "\u{4995}"),
+    ('\u{2f9f0}', "\u{95b7}"),
+    ('\u{2f9f1}', "\u{28d77}"),
+    ('\u{2f9f2}', "\u{49e6}"),
+    ('\u{2f9f3}', "\u{96c3}"),
+    ('\u{2f9f4}', "\u{5db2}"),
+    ('\u{2f9f5}', "\u{9723}"),
+    ('\u{2f9f6}', "\u{29145}"),
+    ('\u{2f9f7}', "\u{2921a}"),
+    ('\u{2f9f8}', "\u{4a6e}"),
+    ('\u{2f9f9}', "\u{4a76}"),
+    ('\u{2f9fa}', "\u{97e0}"),
+    ('\u{2f9fb}', "\u{2940a}"),
+    ('\u{2f9fc}', "\u{4ab2}"),
+    ('\u{2f9fd}', "\u{29496}"),
+    ('\u{2f9fe}', "\u{980b}"),
+    ('\u{2f9ff}', "\u{980b}"),
+    ('\u{2fa00}', "\u{9829}"),
+    ('\u{2fa01}', "\u{295b6}"),
+    ('\u{2fa02}', "\u{98e2}"),
+    ('\u{2fa03}', "\u{4b33}"),
+    ('\u{2fa04}', "\u{9929}"),
+    ('\u{2fa05}', "\u{99a7}"),
+    ('\u{2fa06}', "\u{99c2}"),
+    ('\u{2fa07}', "\u{99fe}"),
+    ('\u{2fa08}', "\u{4bce}"),
+    ('\u{2fa09}', "\u{29b30}"),
+    ('\u{2fa0a}', "\u{9b12}"),
+    ('\u{2fa0b}', "\u{9c40}"),
+    ('\u{2fa0c}', "\u{9cfd}"),
+    ('\u{2fa0d}', "\u{4cce}"),
+    ('\u{2fa0e}', "\u{4ced}"),
+    ('\u{2fa0f}', "\u{9d67}"),
+    ('\u{2fa10}', "\u{2a0ce}"),
+    ('\u{2fa11}', "\u{4cf8}"),
+    ('\u{2fa12}', "\u{2a105}"),
+    ('\u{2fa13}', "\u{2a20e}"),
+    ('\u{2fa14}', "\u{2a291}"),
+    ('\u{2fa15}', "\u{9ebb}"),
+    ('\u{2fa16}', "\u{4d56}"),
+    ('\u{2fa17}', "\u{9ef9}"),
+    ('\u{2fa18}', "\u{9efe}"),
+    ('\u{2fa19}', "\u{9f05}"),
+    ('\u{2fa1a}', "\u{9f0f}"),
+    ('\u{2fa1b}', "\u{9f16}"),
+    ('\u{2fa1c}', "\u{9f3b}"),
+    ('\u{2fa1d}', "\u{2a600}"),
+];
+
+/// Ranges of code points with a non-zero canonical combining class,
+/// sorted.
+pub(super) static COMBINING_CLASS: &[(char, char, u8)] = &[
+    ('\u{300}', '\u{314}', 230),
+    ('\u{315}', '\u{315}', 232),
+    ('\u{316}', '\u{319}', 220),
+    ('\u{31a}', '\u{31a}', 232),
+    ('\u{31b}', '\u{31b}', 216),
+    ('\u{31c}', '\u{320}', 220),
+    ('\u{321}', '\u{322}', 202),
+    ('\u{323}', '\u{326}', 220),
+    ('\u{327}', '\u{328}', 202),
+    ('\u{329}', '\u{333}', 220),
+    ('\u{334}', '\u{338}', 1),
+    ('\u{339}', '\u{33c}', 220),
+    ('\u{33d}', '\u{344}', 230),
+    ('\u{345}', '\u{345}', 240),
+    ('\u{346}', '\u{346}', 230),
+    ('\u{347}', '\u{349}', 220),
+    ('\u{34a}', '\u{34c}', 230),
+    ('\u{34d}', '\u{34e}', 220),
+    ('\u{350}', '\u{352}', 230),
+    ('\u{353}', '\u{356}', 220),
+    ('\u{357}', '\u{357}', 230),
+    ('\u{358}', '\u{358}', 232),
+    ('\u{359}', '\u{35a}', 220),
+    ('\u{35b}', '\u{35b}', 230),
+    ('\u{35c}', '\u{35c}', 233),
+    ('\u{35d}', '\u{35e}', 234),
+    ('\u{35f}', '\u{35f}', 233),
+    ('\u{360}', '\u{361}', 234),
+    ('\u{362}', '\u{362}', 233),
+    ('\u{363}', '\u{36f}', 230),
+    ('\u{483}', '\u{487}', 230),
+    ('\u{591}', '\u{591}', 220),
+    ('\u{592}', '\u{595}', 230),
+    ('\u{596}', '\u{596}', 220),
+    ('\u{597}', '\u{599}', 230),
+    ('\u{59a}', '\u{59a}', 222),
+    ('\u{59b}', '\u{59b}', 220),
+    ('\u{59c}', '\u{5a1}', 230),
+    ('\u{5a2}', '\u{5a7}', 220),
+    ('\u{5a8}', '\u{5a9}', 230),
+    ('\u{5aa}', '\u{5aa}', 220),
+    ('\u{5ab}', '\u{5ac}', 230),
+    ('\u{5ad}', '\u{5ad}', 222),
+    ('\u{5ae}', '\u{5ae}', 228),
+    ('\u{5af}', '\u{5af}', 230),
+    ('\u{5b0}', '\u{5b0}', 10),
+    ('\u{5b1}', '\u{5b1}', 11),
+    ('\u{5b2}', '\u{5b2}', 12),
+    ('\u{5b3}', '\u{5b3}', 13),
+    ('\u{5b4}', '\u{5b4}', 14),
+    ('\u{5b5}', '\u{5b5}', 15),
+    ('\u{5b6}', '\u{5b6}', 16),
+    ('\u{5b7}', '\u{5b7}', 17),
+    ('\u{5b8}', '\u{5b8}', 18),
+    ('\u{5b9}', '\u{5ba}', 19),
+    ('\u{5bb}', '\u{5bb}', 20),
+    ('\u{5bc}', '\u{5bc}', 21),
+    ('\u{5bd}', '\u{5bd}', 22),
+    ('\u{5bf}', '\u{5bf}', 23),
+    ('\u{5c1}', '\u{5c1}', 24),
+    ('\u{5c2}', '\u{5c2}', 25),
+    ('\u{5c4}', '\u{5c4}', 230),
+    ('\u{5c5}', '\u{5c5}', 220),
+    ('\u{5c7}', '\u{5c7}', 18),
+    ('\u{610}', '\u{617}', 230),
+    ('\u{618}', '\u{618}', 30),
+    ('\u{619}', '\u{619}', 31),
+    ('\u{61a}', '\u{61a}', 32),
+    ('\u{64b}', '\u{64b}', 27),
+    ('\u{64c}', '\u{64c}', 28),
+    ('\u{64d}', '\u{64d}', 29),
+    ('\u{64e}', '\u{64e}', 30),
+    ('\u{64f}', '\u{64f}', 31),
+    ('\u{650}', '\u{650}', 32),
+    ('\u{651}', '\u{651}', 33),
+    ('\u{652}', '\u{652}', 34),
+    ('\u{653}', '\u{654}', 230),
+    ('\u{655}', '\u{656}', 220),
+    ('\u{657}', '\u{65b}', 230),
+    ('\u{65c}', '\u{65c}', 220),
+    ('\u{65d}', '\u{65e}', 230),
+    ('\u{65f}', '\u{65f}', 220),
+    ('\u{670}', '\u{670}', 35),
+    ('\u{6d6}', '\u{6dc}', 230),
+    ('\u{6df}', '\u{6e2}', 230),
+    ('\u{6e3}', '\u{6e3}', 220),
+    ('\u{6e4}', '\u{6e4}', 230),
+    ('\u{6e7}', '\u{6e8}', 230),
+    ('\u{6ea}', '\u{6ea}', 220),
+    ('\u{6eb}', '\u{6ec}', 230),
+    ('\u{6ed}', '\u{6ed}', 220),
+    ('\u{711}', '\u{711}', 36),
+    ('\u{730}', '\u{730}', 230),
+    ('\u{731}', '\u{731}', 220),
+    ('\u{732}', '\u{733}', 230),
+    ('\u{734}', '\u{734}', 220),
+    ('\u{735}', '\u{736}', 230),
+    ('\u{737}', '\u{739}', 220),
+    ('\u{73a}', '\u{73a}', 230),
+    ('\u{73b}', '\u{73c}', 220),
+    ('\u{73d}', '\u{73d}', 230),
+    ('\u{73e}', '\u{73e}', 220),
+    ('\u{73f}', '\u{741}', 230),
+    ('\u{742}', '\u{742}', 220),
+    ('\u{743}', '\u{743}', 230),
+    ('\u{744}', '\u{744}', 220),
+    ('\u{745}', '\u{745}', 230),
+    ('\u{746}', '\u{746}', 220),
+    ('\u{747}', '\u{747}', 230),
+    ('\u{748}', '\u{748}', 220),
+    ('\u{749}', '\u{74a}', 230),
+    ('\u{7eb}', '\u{7f1}', 230),
+    ('\u{7f2}', '\u{7f2}', 220),
+    ('\u{7f3}', '\u{7f3}', 230),
+    ('\u{7fd}', '\u{7fd}', 220),
+    ('\u{816}', '\u{819}', 230),
+    ('\u{81b}', '\u{823}', 230),
+    ('\u{825}', '\u{827}', 230),
+    ('\u{829}', '\u{82d}', 230),
+    ('\u{859}', '\u{85b}', 220),
+    ('\u{898}', '\u{898}', 230),
+    ('\u{899}', '\u{89b}', 220),
+    ('\u{89c}', '\u{89f}', 230),
+    ('\u{8ca}', '\u{8ce}', 230),
+    ('\u{8cf}', '\u{8d3}', 220),
+    ('\u{8d4}', '\u{8e1}', 230),
+    ('\u{8e3}', '\u{8e3}', 220),
+    ('\u{8e4}', '\u{8e5}', 230),
+    ('\u{8e6}', '\u{8e6}', 220),
+    ('\u{8e7}', '\u{8e8}', 230),
+    ('\u{8e9}', '\u{8e9}', 220),
+    ('\u{8ea}', '\u{8ec}', 230),
+    ('\u{8ed}', '\u{8ef}', 220),
+    ('\u{8f0}', '\u{8f0}', 27),
+    ('\u{8f1}', '\u{8f1}', 28),
+    ('\u{8f2}', '\u{8f2}', 29),
+    ('\u{8f3}', '\u{8f5}', 230),
+    ('\u{8f6}', '\u{8f6}', 220),
+    ('\u{8f7}', '\u{8f8}', 230),
+    ('\u{8f9}', '\u{8fa}', 220),
+    ('\u{8fb}', '\u{8ff}', 230),
+    ('\u{93c}', '\u{93c}', 7),
+    ('\u{94d}', '\u{94d}', 9),
+    ('\u{951}', '\u{951}', 230),
+    ('\u{952}', '\u{952}', 220),
+    ('\u{953}', '\u{954}', 230),
+    ('\u{9bc}', '\u{9bc}', 7),
+    ('\u{9cd}', '\u{9cd}', 9),
+    ('\u{9fe}', '\u{9fe}', 230),
+    ('\u{a3c}', '\u{a3c}', 7),
+    ('\u{a4d}', '\u{a4d}', 9),
+    ('\u{abc}', '\u{abc}', 7),
+    ('\u{acd}', '\u{acd}', 9),
+    ('\u{b3c}', '\u{b3c}', 7),
+    ('\u{b4d}', '\u{b4d}', 9),
+    ('\u{bcd}', '\u{bcd}', 9),
+    ('\u{c3c}', '\u{c3c}', 7),
+    ('\u{c4d}', '\u{c4d}', 9),
+    ('\u{c55}', '\u{c55}', 84),
+    ('\u{c56}', '\u{c56}', 91),
+    ('\u{cbc}', '\u{cbc}', 7),
+    ('\u{ccd}', '\u{ccd}', 9),
+    ('\u{d3b}', '\u{d3c}', 9),
+    ('\u{d4d}', '\u{d4d}', 9),
+    ('\u{dca}', '\u{dca}', 9),
+    ('\u{e38}', '\u{e39}', 103),
+    ('\u{e3a}', '\u{e3a}', 9),
+    ('\u{e48}', '\u{e4b}', 107),
+    ('\u{eb8}', '\u{eb9}', 118),
+    ('\u{eba}', '\u{eba}', 9),
+    ('\u{ec8}', '\u{ecb}', 122),
+    ('\u{f18}', '\u{f19}', 220),
+    ('\u{f35}', '\u{f35}', 220),
+    ('\u{f37}', '\u{f37}', 220),
+    ('\u{f39}', '\u{f39}', 216),
+    ('\u{f71}', '\u{f71}', 129),
+    ('\u{f72}', '\u{f72}', 130),
+    ('\u{f74}', '\u{f74}', 132),
+    ('\u{f7a}', '\u{f7d}', 130),
+    ('\u{f80}', '\u{f80}', 130),
+    ('\u{f82}', '\u{f83}', 230),
+    ('\u{f84}', '\u{f84}', 9),
+    ('\u{f86}', '\u{f87}', 230),
+    ('\u{fc6}', '\u{fc6}', 220),
+    ('\u{1037}', '\u{1037}', 7),
+    ('\u{1039}', '\u{103a}', 9),
+    ('\u{108d}', '\u{108d}', 220),
+    ('\u{135d}', '\u{135f}', 230),
+    ('\u{1714}', '\u{1715}', 9),
+    ('\u{1734}', '\u{1734}', 9),
+    ('\u{17d2}', '\u{17d2}', 9),
+    ('\u{17dd}', '\u{17dd}', 230),
+    ('\u{18a9}', '\u{18a9}', 228),
+    ('\u{1939}', '\u{1939}', 222),
+    ('\u{193a}', '\u{193a}', 230),
+    ('\u{193b}', '\u{193b}', 220),
+    ('\u{1a17}', '\u{1a17}', 230),
+    ('\u{1a18}', '\u{1a18}', 220),
+    ('\u{1a60}', '\u{1a60}', 9),
+    ('\u{1a75}', '\u{1a7c}', 230),
+    ('\u{1a7f}', '\u{1a7f}', 220),
+    ('\u{1ab0}', '\u{1ab4}', 230),
+    ('\u{1ab5}', '\u{1aba}', 220),
+    ('\u{1abb}', '\u{1abc}', 230),
+    ('\u{1abd}', '\u{1abd}', 220),
+    ('\u{1abf}', '\u{1ac0}', 220),
+    ('\u{1ac1}', '\u{1ac2}', 230),
+    ('\u{1ac3}', '\u{1ac4}', 220),
+    ('\u{1ac5}', '\u{1ac9}', 230),
+    ('\u{1aca}', '\u{1aca}', 220),
+    ('\u{1acb}', '\u{1ace}', 230),
+    ('\u{1b34}', '\u{1b34}', 7),
+    ('\u{1b44}', '\u{1b44}', 9),
+    ('\u{1b6b}', '\u{1b6b}', 230),
+    ('\u{1b6c}', '\u{1b6c}', 220),
+    ('\u{1b6d}', '\u{1b73}', 230),
+    ('\u{1baa}', '\u{1bab}', 9),
+    ('\u{1be6}', '\u{1be6}', 7),
+    ('\u{1bf2}', '\u{1bf3}', 9),
+    ('\u{1c37}', '\u{1c37}', 7),
+    ('\u{1cd0}', '\u{1cd2}', 230),
+    ('\u{1cd4}', '\u{1cd4}', 1),
+    ('\u{1cd5}', '\u{1cd9}', 220),
+    ('\u{1cda}', '\u{1cdb}', 230),
+    ('\u{1cdc}', '\u{1cdf}', 220),
+    ('\u{1ce0}', '\u{1ce0}', 230),
+    ('\u{1ce2}', '\u{1ce8}', 1),
+    ('\u{1ced}', '\u{1ced}', 220),
+    ('\u{1cf4}', '\u{1cf4}', 230),
+    ('\u{1cf8}', '\u{1cf9}', 230),
+    ('\u{1dc0}', '\u{1dc1}', 230),
+    ('\u{1dc2}', '\u{1dc2}', 220),
+    ('\u{1dc3}', '\u{1dc9}', 230),
+    ('\u{1dca}', '\u{1dca}', 220),
+    ('\u{1dcb}', '\u{1dcc}', 230),
+    ('\u{1dcd}', '\u{1dcd}', 234),
+    ('\u{1dce}', '\u{1dce}', 214),
+    ('\u{1dcf}', '\u{1dcf}', 220),
+    ('\u{1dd0}', '\u{1dd0}', 202),
+    ('\u{1dd1}', '\u{1df5}', 230),
+    ('\u{1df6}', '\u{1df6}', 232),
+    ('\u{1df7}', '\u{1df8}', 228),
+    ('\u{1df9}', '\u{1df9}', 220),
+    ('\u{1dfa}', '\u{1dfa}', 218),
+    ('\u{1dfb}', '\u{1dfb}', 230),
+    ('\u{1dfc}', '\u{1dfc}', 233),
+    ('\u{1dfd}', '\u{1dfd}', 220),
+    ('\u{1dfe}', '\u{1dfe}', 230),
+    ('\u{1dff}', '\u{1dff}', 220),
+    ('\u{20d0}', '\u{20d1}', 230),
+    ('\u{20d2}', '\u{20d3}', 1),
+    ('\u{20d4}', '\u{20d7}', 230),
+    ('\u{20d8}', '\u{20da}', 1),
+    ('\u{20db}', '\u{20dc}', 230),
+    ('\u{20e1}', '\u{20e1}', 230),
+    ('\u{20e5}', '\u{20e6}', 1),
+    ('\u{20e7}', '\u{20e7}', 230),
+    ('\u{20e8}', '\u{20e8}', 220),
+    ('\u{20e9}', '\u{20e9}', 230),
+    ('\u{20ea}', '\u{20eb}', 1),
+    ('\u{20ec}', '\u{20ef}', 220),
+    ('\u{20f0}', '\u{20f0}', 230),
+    ('\u{2cef}', '\u{2cf1}', 230),
+    ('\u{2d7f}', '\u{2d7f}', 9),
+    ('\u{2de0}', '\u{2dff}', 230),
+    ('\u{302a}', '\u{302a}', 218),
+    ('\u{302b}', '\u{302b}', 228),
+    ('\u{302c}', '\u{302c}', 232),
+    ('\u{302d}', '\u{302d}', 222),
+    ('\u{302e}', '\u{302f}', 224),
+    ('\u{3099}', '\u{309a}', 8),
+    ('\u{a66f}', '\u{a66f}', 230),
+    ('\u{a674}', '\u{a67d}', 230),
+    ('\u{a69e}', '\u{a69f}', 230),
+    ('\u{a6f0}', '\u{a6f1}', 230),
+    ('\u{a806}', '\u{a806}', 9),
+    ('\u{a82c}', '\u{a82c}', 9),
+    ('\u{a8c4}', '\u{a8c4}', 9),
+    ('\u{a8e0}', '\u{a8f1}', 230),
+    ('\u{a92b}', '\u{a92d}', 220),
+    ('\u{a953}', '\u{a953}', 9),
+    ('\u{a9b3}', '\u{a9b3}', 7),
+    ('\u{a9c0}', '\u{a9c0}', 9),
+    ('\u{aab0}', '\u{aab0}', 230),
+    ('\u{aab2}', '\u{aab3}', 230),
+    ('\u{aab4}', '\u{aab4}', 220),
+    ('\u{aab7}', '\u{aab8}', 230),
+    ('\u{aabe}', '\u{aabf}', 230),
+    ('\u{aac1}', '\u{aac1}', 230),
+    ('\u{aaf6}', '\u{aaf6}', 9),
+    ('\u{abed}', '\u{abed}', 9),
+    ('\u{fb1e}', '\u{fb1e}', 26),
+    ('\u{fe20}', '\u{fe26}', 230),
+    ('\u{fe27}', '\u{fe2d}', 220),
+    ('\u{fe2e}', '\u{fe2f}', 230),
+    ('\u{101fd}', '\u{101fd}', 220),
+    ('\u{102e0}', '\u{102e0}', 220),
+    ('\u{10376}', '\u{1037a}', 230),
+    ('\u{10a0d}', '\u{10a0d}', 220),
+    ('\u{10a0f}', '\u{10a0f}', 230),
+    ('\u{10a38}', '\u{10a38}', 230),
+    ('\u{10a39}', '\u{10a39}', 1),
+    ('\u{10a3a}', '\u{10a3a}', 220),
+    ('\u{10a3f}', '\u{10a3f}', 9),
+    ('\u{10ae5}', '\u{10ae5}', 230),
+    ('\u{10ae6}', '\u{10ae6}', 220),
+    ('\u{10d24}', '\u{10d27}', 230),
+    ('\u{10eab}', '\u{10eac}', 230),
+    ('\u{10f46}', '\u{10f47}', 220),
+    ('\u{10f48}', '\u{10f4a}', 230),
+    ('\u{10f4b}', '\u{10f4b}', 220),
+    ('\u{10f4c}', '\u{10f4c}', 230),
+    ('\u{10f4d}', '\u{10f50}', 220),
+    ('\u{10f82}', '\u{10f82}', 230),
+    ('\u{10f83}', '\u{10f83}', 220),
+    ('\u{10f84}', '\u{10f84}', 230),
+    ('\u{10f85}', '\u{10f85}', 220),
+    ('\u{11046}', '\u{11046}', 9),
+    ('\u{11070}', '\u{11070}', 9),
+    ('\u{1107f}', '\u{1107f}', 9),
+    ('\u{110b9}', '\u{110b9}', 9),
+    ('\u{110ba}', '\u{110ba}', 7),
+    ('\u{11100}', '\u{11102}', 230),
+    ('\u{11133}', '\u{11134}', 9),
+    ('\u{11173}', '\u{11173}', 7),
+    ('\u{111c0}', '\u{111c0}', 9),
+    ('\u{111ca}', '\u{111ca}', 7),
+    ('\u{11235}', '\u{11235}', 9),
+    ('\u{11236}', '\u{11236}', 7),
+    ('\u{112e9}', '\u{112e9}', 7),
+    ('\u{112ea}', '\u{112ea}', 9),
+    ('\u{1133b}', '\u{1133c}', 7),
+    ('\u{1134d}', '\u{1134d}', 9),
+    ('\u{11366}', '\u{1136c}', 230),
+    ('\u{11370}', '\u{11374}', 230),
+    ('\u{11442}', '\u{11442}', 9),
+    ('\u{11446}', '\u{11446}', 7),
+    ('\u{1145e}', '\u{1145e}', 230),
+    ('\u{114c2}', '\u{114c2}', 9),
+    ('\u{114c3}', '\u{114c3}', 7),
+    ('\u{115bf}', '\u{115bf}', 9),
+    ('\u{115c0}', '\u{115c0}', 7),
+    ('\u{1163f}', '\u{1163f}', 9),
+    ('\u{116b6}', '\u{116b6}', 9),
+    ('\u{116b7}', '\u{116b7}', 7),
+    ('\u{1172b}', '\u{1172b}', 9),
+    ('\u{11839}', '\u{11839}', 9),
+    ('\u{1183a}', '\u{1183a}', 7),
+    ('\u{1193d}', '\u{1193e}', 9),
+    ('\u{11943}', '\u{11943}', 7),
+    ('\u{119e0}', '\u{119e0}', 9),
+    ('\u{11a34}', '\u{11a34}', 9),
+    ('\u{11a47}', '\u{11a47}', 9),
+    ('\u{11a99}', '\u{11a99}', 9),
+    ('\u{11c3f}', '\u{11c3f}', 9),
+    ('\u{11d42}', '\u{11d42}', 7),
+    ('\u{11d44}', '\u{11d45}', 9),
+    ('\u{11d97}', '\u{11d97}', 9),
+    ('\u{16af0}', '\u{16af4}', 1),
+    ('\u{16b30}', '\u{16b36}', 230),
+    ('\u{16ff0}', '\u{16ff1}', 6),
+    ('\u{1bc9e}', '\u{1bc9e}', 1),
+    ('\u{1d165}', '\u{1d166}', 216),
+    ('\u{1d167}', '\u{1d169}', 1),
+    ('\u{1d16d}', '\u{1d16d}', 226),
+    ('\u{1d16e}', '\u{1d172}', 216),
+    ('\u{1d17b}', '\u{1d182}', 220),
+    ('\u{1d185}', '\u{1d189}', 230),
+    ('\u{1d18a}', '\u{1d18b}', 220),
+    ('\u{1d1aa}', '\u{1d1ad}', 230),
+    ('\u{1d242}', '\u{1d244}', 230),
+    ('\u{1e000}', '\u{1e006}', 230),
+    ('\u{1e008}', '\u{1e018}', 230),
+    ('\u{1e01b}', '\u{1e021}', 230),
+    ('\u{1e023}', '\u{1e024}', 230),
+    ('\u{1e026}', '\u{1e02a}', 230),
+    ('\u{1e130}', '\u{1e136}', 230),
+    ('\u{1e2ae}', '\u{1e2ae}', 230),
+    ('\u{1e2ec}', '\u{1e2ef}', 230),
+    ('\u{1e8d0}', '\u{1e8d6}', 220),
+    ('\u{1e944}', '\u{1e949}', 230),
+    ('\u{1e94a}', '\u{1e94a}', 7),
+];
@@ -0,0 +1,94 @@
+use super::{Block, BlockDevice};
+use crate::constants::*;
+use crate::prelude::*;
+use axsync::Mutex;
+
+/// Block data shared between a device and its snapshots.
+type BlockData = Arc<[u8; BLOCK_SIZE]>;
+
+/// A copy-on-write overlay on a block device.
+///
+/// Writes never reach the base device: each written block is copied into
+/// an in-memory overlay, and reads prefer the overlay. Snapshots share
+/// block data with the overlay until it is overwritten, so taking one
+/// costs a copy of the block map only, however large the base device is.
+pub struct CowDevice {
+    /// The read-only base device
+    base: Arc<dyn BlockDevice>,
+    /// Blocks written since the overlay was created
+    overlay: Mutex<BTreeMap<PBlockId, BlockData>>,
+}
+
+/// A snapshot of the contents of a `CowDevice`, acquired by
+/// `CowDevice::snapshot`.
+#[derive(Clone)]
+pub struct CowSnapshot {
+    overlay: BTreeMap<PBlockId, BlockData>,
+}
+
+impl CowSnapshot {
+    /// The number of blocks that differ from the base device.
+    pub fn block_count(&self) -> u64 {
+        self.overlay.len() as u64
+    }
+}
+
+impl CowDevice {
+    /// Create an empty overlay on `base`.
+    pub fn new(base: Arc<dyn BlockDevice>) -> Self {
+        Self {
+            base,
+            overlay: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Take a snapshot of the current contents.
+    pub fn snapshot(&self) -> CowSnapshot {
+        CowSnapshot {
+            overlay: self.overlay.lock().clone(),
+        }
+    }
+
+    /// Revert the contents to a snapshot. Anything cached above the device
+    /// must be dropped, see `Ext4::rollback`.
+    pub fn rollback(&self, snapshot: &CowSnapshot) {
+        *self.overlay.lock() = snapshot.overlay.clone();
+    }
+
+    /// Write the overlay to the base device and clear it. Snapshots taken
+    /// before are relative to the old base and must not be rolled back to.
+    pub fn commit(&self) {
+        let mut overlay = self.overlay.lock();
+        for (&id, data) in overlay.iter() {
+            self.base.write_block(&Block::new(id, **data));
+        }
+        self.base.flush();
+        overlay.clear();
+    }
+
+    /// The number of blocks that differ from the base device.
+    pub fn overlay_blocks(&self) -> u64 {
+        self.overlay.lock().len() as u64
+    }
+}
+
+impl BlockDevice for CowDevice {
+    fn read_block(&self, block_id: PBlockId) -> Block {
+        if let Some(data) = self.overlay.lock().get(&block_id) {
+            return Block::new(block_id, **data);
+        }
+        self.base.read_block(block_id)
+    }
+
+    fn write_block(&self, block: &Block) {
+        self.overlay.lock().insert(block.id, Arc::new(block.data));
+    }
+
+    fn block_count(&self) -> Option<u64> {
+        self.base.block_count()
+    }
+
+    fn readahead(&self, start: PBlockId, count: u64) {
+        self.base.readahead(start, count)
+    }
+}
@@ -1,3 +1,7 @@
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
 /* */
 /* CRC LOOKUP TABLE */
 /* ================ */
@@ -50,13 +54,36 @@ const CRC32C_TAB: [u32; 256] = [
     0x79B737BA, 0x8BDCB4B9, 0x988C474D, 0x6AE7C44E, 0xBE2DA0A5, 0x4C4623A6, 0x5F16D052, 0xAD7D5351,
 ];
 
+/// The CRC32C implementation set with `set_crc32c`, null for the table
+/// driven one.
+static CRC32C_IMPL: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+/// Compute CRC32C checksums with `f`, such as a hardware accelerated
+/// implementation, instead of the table driven one. `None` restores it.
+///
+/// `f` is called like `crc32` and must return the same values.
+pub(crate) fn set_crc32c(f: Option<fn(u32, &[u8]) -> u32>) {
+    let f = f.map_or(ptr::null_mut(), |f| f as *mut ());
+    CRC32C_IMPL.store(f, Ordering::Release);
+}
+
 /// Calc CRC32 checksum on a byte slice
 ///
+/// The reflected CRC32C (Castagnoli) of `data`, continuing from
+/// `crc_init`, without the final inversion.
+///
 /// # Params
 ///
 /// * `crc_init`: initial CRC value
 /// * `data`: data to calculate CRC32 checksum
 pub fn crc32(crc_init: u32, data: &[u8]) -> u32 {
+    let f = CRC32C_IMPL.load(Ordering::Acquire);
+    if !f.is_null() {
+        // SAFETY: only `set_crc32c` stores non-null pointers, all of them
+        // to functions of this type
+        let f = unsafe { mem::transmute::<*mut (), fn(u32, &[u8]) -> u32>(f) };
+        return f(crc_init, data);
+    }
     let mut crc = crc_init;
     for byte in data {
         crc = CRC32C_TAB[(crc as u8 ^ byte) as usize] ^ (crc >> 8);
@@ -1,6 +1,8 @@
 //! A directory is a series of data blocks and that each block contains a
 //! linear array of directory entries.
 
+#[cfg(feature = "casefold")]
+use super::casefold::casefold;
 use super::crc::*;
 use super::AsBytes;
 use super::FileType;
@@ -18,8 +20,8 @@ pub struct DirEntry {
     rec_len: u16,
     /// Length of the file name.
     name_len: u8,
-    /// File type code.
-    file_type: FileType,
+    /// File type code, see `FileType`.
+    file_type: u8,
     /// File name.
     name: [u8; 255],
 }
@@ -30,7 +32,7 @@ pub struct FakeDirEntry {
     inode: u32,
     rec_len: u16,
     name_len: u8,
-    file_type: FileType,
+    file_type: u8,
 }
 unsafe impl AsBytes for FakeDirEntry {}
 
@@ -53,6 +55,8 @@ unsafe impl AsBytes for DirEntry {
     }
     fn to_bytes(&self) -> &[u8] {
         let name_len = self.name_len as usize;
+        // SAFETY: `name` directly follows the fake entry fields in `repr(C)`
+        // layout, and `name_len` is at most the length of `name`.
         unsafe {
             core::slice::from_raw_parts(
                 self as *const Self as *const u8,
@@ -72,7 +76,7 @@ impl DirEntry {
             inode,
             rec_len,
             name_len: name_len as u8,
-            file_type,
+            file_type: file_type as u8,
             name: name_bytes,
         }
     }
@@ -82,17 +86,56 @@ impl DirEntry {
         self.inode
     }
 
+    /// Get the distance to the next directory entry
+    pub fn rec_len(&self) -> u16 {
+        self.rec_len
+    }
+
+    /// Get the raw name bytes of the directory entry
+    pub fn name_bytes(&self) -> &[u8] {
+        &self.name[..self.name_len as usize]
+    }
+
     /// Get the name of the directory entry
+    ///
+    /// Names that are not valid UTF-8 are converted lossily.
     pub fn name(&self) -> String {
         let name = &self.name[..self.name_len as usize];
-        unsafe {
-            String::from_utf8_unchecked(name.to_vec())
-        }
+        String::from_utf8_lossy(name).into_owned()
     }
 
-    /// Compare the name of the directory entry with a given name
+    /// Replace the name of an entry read from a directory block, such as
+    /// by its decrypted name. `name` is at most 255 bytes long.
+    pub(crate) fn set_name(&mut self, name: &[u8]) {
+        self.name[..name.len()].copy_from_slice(name);
+        self.name_len = name.len() as u8;
+    }
+
+    /// Compare the name of the directory entry with a given name. Both the
+    /// length and the bytes must match, a prefix is not a match.
     pub fn compare_name(&self, name: &str) -> bool {
-        &self.name[..name.len()] == name.as_bytes()
+        self.name[..self.name_len as usize] == *name.as_bytes()
+    }
+
+    /// Compare the name of the directory entry with a given name, ignoring
+    /// case. Names that are not valid UTF-8 are compared byte by byte.
+    ///
+    /// Names are compared by their full case folding in canonical
+    /// decomposition, see `casefold`.
+    #[cfg(feature = "casefold")]
+    pub fn compare_name_casefold(&self, name: &str) -> bool {
+        self.compare_name_folded(name, &casefold(name))
+    }
+
+    /// Compare the name of the directory entry with `name`, whose folded
+    /// form is `folded`, ignoring case.
+    #[cfg(feature = "casefold")]
+    fn compare_name_folded(&self, name: &str, folded: &[char]) -> bool {
+        let self_name = &self.name[..self.name_len as usize];
+        match core::str::from_utf8(self_name) {
+            Ok(self_name) => casefold(self_name) == folded,
+            Err(_) => self_name == name.as_bytes(),
+        }
     }
 
     /// Check if the directory entry is unused (inode = 0)
@@ -107,12 +150,12 @@ impl DirEntry {
 
     /// Get the dir entry's file type
     pub fn file_type(&self) -> FileType {
-        self.file_type
+        FileType::from_u8(self.file_type)
     }
 
     /// Set the dir entry's file type
     pub fn set_type(&mut self, file_type: FileType) {
-        self.file_type = file_type;
+        self.file_type = file_type as u8;
     }
 
     /// Get the required size to save a directory entry, 4-byte aligned
@@ -127,6 +170,11 @@ impl DirEntry {
     }
 }
 
+/// Offset of the dir entry tail in a directory block.
+const TAIL_OFFSET: usize = BLOCK_SIZE - size_of::<DirEntryTail>();
+
+/// A fake directory entry at the end of a block that holds the checksum
+/// of the block. It only exists when `metadata_csum` is enabled.
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct DirEntryTail {
@@ -149,11 +197,25 @@ impl DirEntryTail {
         }
     }
 
-    pub fn set_checksum(&mut self, uuid: &[u8], ino: InodeId, ino_gen: u32, block: &Block) {
-        let mut csum = crc32(CRC32_INIT, uuid);
+    /// Check if the fields look like a dir entry tail rather than a
+    /// normal entry.
+    fn is_valid(&self) -> bool {
+        self.reserved_zero1 == 0
+            && self.rec_len as usize == size_of::<DirEntryTail>()
+            && self.reserved_zero2 == 0
+            && self.reserved_ft == 0xDE
+    }
+
+    /// Compute the checksum of the entries before the tail.
+    fn calc_checksum(csum_seed: u32, ino: InodeId, ino_gen: u32, block: &Block) -> u32 {
+        let mut csum = csum_seed;
         csum = crc32(csum, &ino.to_le_bytes());
         csum = crc32(csum, &ino_gen.to_le_bytes());
-        self.checksum = crc32(csum, &block.data[..size_of::<DirEntryTail>()]);
+        crc32(csum, &block.data[..TAIL_OFFSET])
+    }
+
+    pub fn set_checksum(&mut self, csum_seed: u32, ino: InodeId, ino_gen: u32, block: &Block) {
+        self.checksum = Self::calc_checksum(csum_seed, ino, ino_gen, block);
     }
 }
 
@@ -171,96 +233,342 @@ impl DirBlock {
         &self.0
     }
 
-    /// Initialize a directory block, create an unused entry
-    /// and the dir entry tail.
-    pub fn init(&mut self) {
-        let tail_offset = BLOCK_SIZE - size_of::<DirEntryTail>();
-        let entry = DirEntry::new(0, tail_offset as u16, "", FileType::Unknown);
+    /// Initialize a directory block, create an unused entry and, if
+    /// `with_tail` is set, the dir entry tail.
+    pub fn init(&mut self, with_tail: bool) {
+        let end = if with_tail { TAIL_OFFSET } else { BLOCK_SIZE };
+        let entry = DirEntry::new(0, end as u16, "", FileType::Unknown);
         self.0.write_offset_as(0, &entry);
-        let tail = DirEntryTail::new();
-        self.0.write_offset_as(tail_offset, &tail);
+        if with_tail {
+            self.0.write_offset_as(TAIL_OFFSET, &DirEntryTail::new());
+        }
+    }
+
+    /// Check if the block ends with a dir entry tail.
+    pub fn has_tail(&self) -> bool {
+        self.0
+            .read_offset_as::<DirEntryTail>(TAIL_OFFSET)
+            .is_valid()
+    }
+
+    /// The end offset of the normal entries, excluding the tail.
+    fn end(&self) -> usize {
+        if self.has_tail() {
+            TAIL_OFFSET
+        } else {
+            BLOCK_SIZE
+        }
+    }
+
+    /// Iterate over all entries (including unused ones) with their offsets.
+    fn entries(&self) -> DirBlockIter<'_> {
+        DirBlockIter {
+            block: &self.0,
+            offset: 0,
+            end: self.end(),
+        }
+    }
+
+    /// Get all entries (including unused ones) with their offsets, and
+    /// the offset where a malformed entry stopped the walk, if any.
+    pub fn raw_entries(&self) -> (Vec<(usize, DirEntry)>, Option<usize>) {
+        let entries: Vec<_> = self.entries().collect();
+        let next = entries
+            .last()
+            .map_or(0, |(offset, de)| offset + de.rec_len as usize);
+        let malformed = (next != self.end()).then_some(next);
+        (entries, malformed)
+    }
+
+    /// Get the checksum stored in the dir entry tail, if the block has one.
+    pub fn tail_checksum(&self) -> Option<u32> {
+        self.has_tail()
+            .then(|| self.0.read_offset_as::<DirEntryTail>(TAIL_OFFSET).checksum)
     }
 
     /// Get a directory entry by name, return the inode id of the entry.
     pub fn get(&self, name: &str) -> Option<InodeId> {
-        let mut offset = 0;
-        while offset < BLOCK_SIZE {
-            let de: DirEntry = self.0.read_offset_as(offset);
-            if !de.unused() && de.compare_name(name) {
-                return Some(de.inode);
-            }
-            offset += de.rec_len as usize;
-        }
-        None
+        self.entries()
+            .find(|(_, de)| !de.unused() && de.compare_name(name))
+            .map(|(_, de)| de.inode)
+    }
+
+    /// Get a directory entry by name ignoring case, return the inode id of the entry.
+    #[cfg(feature = "casefold")]
+    pub fn get_casefold(&self, name: &str) -> Option<InodeId> {
+        let folded = casefold(name);
+        self.entries()
+            .find(|(_, de)| !de.unused() && de.compare_name_folded(name, &folded))
+            .map(|(_, de)| de.inode)
     }
 
     /// Get all directory entries in the block.
     pub fn list(&self, entries: &mut Vec<DirEntry>) {
-        let mut offset = 0;
-        while offset < BLOCK_SIZE {
-            let de: DirEntry = self.0.read_offset_as(offset);
-            offset += de.rec_len as usize;
+        for (_, de) in self.entries() {
             if !de.unused() {
-                trace!("Dir entry: {:?} {}", de.name(), de.inode);
+                log_dir!(trace, "Dir entry: {:?} {}", de.name(), de.inode);
                 entries.push(de);
             }
         }
     }
 
-    /// Insert a directory entry to the block. Return true if success or false
-    /// if the block doesn't have enough space.
-    pub fn insert(&mut self, name: &str, inode: InodeId, file_type: FileType) -> bool {
+    /// The number of used entries in the block.
+    pub fn used_count(&self) -> usize {
+        self.entries().filter(|(_, de)| !de.unused()).count()
+    }
+
+    /// The free space of an entry: all of it if unused, or else the space
+    /// past its name.
+    fn free_size(de: &DirEntry) -> usize {
+        let used_size = if de.unused() { 0 } else { de.used_size() };
+        (de.rec_len as usize).saturating_sub(used_size)
+    }
+
+    /// Find room for an entry named `name`, with its offset: an unused
+    /// entry large enough, or failing that, an entry with enough free space
+    /// to split.
+    fn find_room(&self, name: &str) -> Option<(usize, DirEntry)> {
         let required_size = DirEntry::required_size(name.len());
+        self.entries()
+            .find(|(_, de)| de.unused() && de.rec_len as usize >= required_size)
+            .or_else(|| {
+                self.entries()
+                    .find(|(_, de)| Self::free_size(de) >= required_size)
+            })
+    }
+
+    /// The free space of all entries together, `None` if the block is
+    /// malformed.
+    fn total_free_size(&self) -> Option<usize> {
+        let (entries, malformed) = self.raw_entries();
+        if malformed.is_some() {
+            return None;
+        }
+        Some(entries.iter().map(|(_, de)| Self::free_size(de)).sum())
+    }
+
+    /// Whether an entry named `name` can be inserted to the block.
+    pub fn has_room(&self, name: &str) -> bool {
+        self.find_room(name).is_some()
+            || self
+                .total_free_size()
+                .is_some_and(|free| free >= DirEntry::required_size(name.len()))
+    }
+
+    /// Insert a directory entry to the block. Unused entries are reused,
+    /// and entries are split only when none fits. If the free space is
+    /// large enough but scattered, it is coalesced first, see `compact`.
+    /// Return true if success or false if the block doesn't have enough
+    /// space.
+    pub fn insert(&mut self, name: &str, inode: InodeId, file_type: FileType) -> bool {
+        if self.find_room(name).is_none() && self.has_room(name) {
+            self.compact();
+        }
+        let Some((offset, mut de)) = self.find_room(name) else {
+            return false;
+        };
+        if de.unused() {
+            // Take over the unused entry
+            let new_entry = DirEntry::new(inode, de.rec_len, name, file_type);
+            self.0.write_offset_as(offset, &new_entry);
+            return true;
+        }
+        let used_size = de.used_size();
+        let free_size = de.rec_len as usize - used_size;
+        // Update the old entry
+        de.rec_len = used_size as u16;
+        self.0.write_offset_as(offset, &de);
+        // Insert the new entry
+        let new_entry = DirEntry::new(inode, free_size as u16, name, file_type);
+        self.0.write_offset_as(offset + used_size, &new_entry);
+        true
+    }
+
+    /// Coalesce the free space of the block at its end, moving the used
+    /// entries to its start in the same order, each taking its used size.
+    /// Does nothing if the block is malformed.
+    pub fn compact(&mut self) {
+        let (entries, malformed) = self.raw_entries();
+        if malformed.is_some() {
+            return;
+        }
+        let end = self.end();
+        let mut used: Vec<DirEntry> = entries
+            .into_iter()
+            .map(|(_, de)| de)
+            .filter(|de| !de.unused())
+            .collect();
+        let Some(mut last) = used.pop() else {
+            // A single unused entry spans the block
+            let entry = DirEntry::new(0, end as u16, "", FileType::Unknown);
+            self.0.write_offset_as(0, &entry);
+            return;
+        };
         let mut offset = 0;
-        while offset < BLOCK_SIZE {
-            // Read a dir entry
-            let mut de: DirEntry = self.0.read_offset_as(offset);
-            let rec_len = de.rec_len as usize;
-            // The size that `de` actually uses
-            let used_size = de.used_size();
-            // The rest size
-            let free_size = rec_len - used_size;
-            // Try splitting dir entry
-            // Compare size
-            if free_size < required_size {
-                // No enough space, try next dir ent
-                offset += rec_len;
-                continue;
-            }
-            // Has enough space
-            // Update the old entry
-            de.rec_len = used_size as u16;
+        for mut de in used {
+            de.rec_len = de.used_size() as u16;
             self.0.write_offset_as(offset, &de);
-            // Insert the new entry
-            let new_entry = DirEntry::new(inode, free_size as u16, name, file_type);
-            self.0.write_offset_as(offset + used_size, &new_entry);
-            return true;
+            offset += de.used_size();
         }
-        false
+        last.rec_len = (end - offset) as u16;
+        self.0.write_offset_as(offset, &last);
     }
 
-    /// Remove a directory entry from the block. Return true if success or false
-    /// if the entry doesn't exist.
+    /// Remove a directory entry from the block. Its space is merged into the
+    /// previous entry, or if it is the first entry of the block, it is
+    /// marked unused. Return true if success or false if the entry doesn't
+    /// exist.
     pub fn remove(&mut self, name: &str) -> bool {
-        let mut offset = 0;
-        while offset < BLOCK_SIZE {
-            let mut de: DirEntry = self.0.read_offset_as(offset);
+        let mut prev = None;
+        let mut found = None;
+        for (offset, de) in self.entries() {
             if !de.unused() && de.compare_name(name) {
-                // Mark the target entry as unused
-                de.set_unused();
-                self.0.write_offset_as(offset, &de);
-                return true;
+                found = Some((offset, de));
+                break;
             }
-            offset += de.rec_len as usize;
+            prev = Some((offset, de));
+        }
+        let Some((offset, mut de)) = found else {
+            return false;
+        };
+        if let Some((prev_offset, mut prev)) = prev {
+            // Coalesce the entry into the previous one
+            prev.rec_len += de.rec_len;
+            self.0.write_offset_as(prev_offset, &prev);
+        } else {
+            // Mark the target entry as unused
+            de.set_unused();
+            self.0.write_offset_as(offset, &de);
+        }
+        true
+    }
+
+    /// Check if the block is well-formed and has no used entry.
+    pub fn is_empty(&self) -> bool {
+        let (entries, malformed) = self.raw_entries();
+        malformed.is_none() && entries.iter().all(|(_, de)| de.unused())
+    }
+
+    /// Point an existing directory entry to another inode. Return true if
+    /// success or false if the entry doesn't exist.
+    pub fn replace(&mut self, name: &str, inode: InodeId, file_type: FileType) -> bool {
+        let found = self
+            .entries()
+            .find(|(_, de)| !de.unused() && de.compare_name(name));
+        let Some((offset, mut de)) = found else {
+            return false;
+        };
+        de.inode = inode;
+        de.set_type(file_type);
+        self.0.write_offset_as(offset, &de);
+        true
+    }
+
+    /// Verify the block checksum. Return false if the checksum mismatches
+    /// or the block has neither a dir entry tail nor an htree index tail.
+    pub fn verify_checksum(&self, csum_seed: u32, ino: InodeId, ino_gen: u32) -> bool {
+        if !self.has_tail() {
+            return self.verify_dx_checksum(csum_seed, ino, ino_gen);
+        }
+        let tail: DirEntryTail = self.0.read_offset_as(TAIL_OFFSET);
+        tail.checksum == DirEntryTail::calc_checksum(csum_seed, ino, ino_gen, &self.0)
+    }
+
+    /// Get the offset of the count and limit of an htree index block,
+    /// `None` if the block is not one. Index blocks hide from a linear
+    /// walk: the root behind the `.` and `..` entries, the other nodes
+    /// behind an unused entry spanning the block.
+    fn dx_count_offset(&self) -> Option<usize> {
+        let first: FakeDirEntry = self.0.read_offset_as(0);
+        if first.rec_len as usize == BLOCK_SIZE {
+            return Some(8);
+        }
+        let second: FakeDirEntry = self.0.read_offset_as(12);
+        if first.rec_len != 12 || second.rec_len as usize != BLOCK_SIZE - 12 {
+            return None;
+        }
+        // `dx_root_info`: reserved_zero, hash_version, info_length
+        let info = &self.0.data[24..32];
+        (info[..4] == [0; 4] && info[5] == 8).then_some(32)
+    }
+
+    /// Verify the checksum in the tail of an htree index block, which
+    /// covers the entries in use and the tail.
+    fn verify_dx_checksum(&self, csum_seed: u32, ino: InodeId, ino_gen: u32) -> bool {
+        let Some(count_offset) = self.dx_count_offset() else {
+            return false;
+        };
+        let data = &self.0.data;
+        let limit = u16::from_le_bytes([data[count_offset], data[count_offset + 1]]) as usize;
+        let count = u16::from_le_bytes([data[count_offset + 2], data[count_offset + 3]]) as usize;
+        let tail = count_offset + limit * 8;
+        if count > limit || tail + 8 > BLOCK_SIZE {
+            return false;
+        }
+        let mut csum = csum_seed;
+        csum = crc32(csum, &ino.to_le_bytes());
+        csum = crc32(csum, &ino_gen.to_le_bytes());
+        csum = crc32(csum, &data[..count_offset + count * 8]);
+        csum = crc32(csum, &data[tail..tail + 4]);
+        csum = crc32(csum, &[0; 4]);
+        csum.to_le_bytes() == data[tail + 4..tail + 8]
+    }
+
+    /// Calc and set block checksum. If the block has no dir entry tail,
+    /// try making room for one by shrinking the last entry. Return false
+    /// if there is no room for the tail.
+    pub fn set_checksum(&mut self, csum_seed: u32, ino: InodeId, ino_gen: u32) -> bool {
+        if !self.has_tail() && !self.make_tail() {
+            return false;
+        }
+        let mut tail: DirEntryTail = self.0.read_offset_as(TAIL_OFFSET);
+        tail.set_checksum(csum_seed, ino, ino_gen, &self.0);
+        self.0.write_offset_as(TAIL_OFFSET, &tail);
+        true
+    }
+
+    /// Shrink the last entry that spans to the end of the block and
+    /// write a dir entry tail in the freed space.
+    fn make_tail(&mut self) -> bool {
+        let Some((offset, mut de)) = self.entries().last() else {
+            return false;
+        };
+        if offset + de.rec_len as usize != BLOCK_SIZE || offset + de.used_size() > TAIL_OFFSET {
+            return false;
         }
-        false
+        de.rec_len = (TAIL_OFFSET - offset) as u16;
+        self.0.write_offset_as(offset, &de);
+        self.0.write_offset_as(TAIL_OFFSET, &DirEntryTail::new());
+        true
     }
+}
+
+/// Iterator over the entries of a directory block, yields the offset and
+/// the entry. Stops at the dir entry tail or a malformed `rec_len`.
+struct DirBlockIter<'a> {
+    block: &'a Block,
+    offset: usize,
+    end: usize,
+}
 
-    /// Calc and set block checksum
-    pub fn set_checksum(&mut self, uuid: &[u8], ino: InodeId, ino_gen: u32) {
-        let tail_offset = BLOCK_SIZE - size_of::<DirEntryTail>();
-        let mut tail: DirEntryTail = self.0.read_offset_as(tail_offset);
-        tail.set_checksum(uuid, ino, ino_gen, &self.0);
-        self.0.write_offset_as(tail_offset, &tail);
+impl Iterator for DirBlockIter<'_> {
+    type Item = (usize, DirEntry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset + size_of::<FakeDirEntry>() > self.end {
+            return None;
+        }
+        let offset = self.offset;
+        let fake: FakeDirEntry = self.block.read_offset_as(offset);
+        let rec_len = fake.rec_len as usize;
+        if rec_len < size_of::<FakeDirEntry>() + fake.name_len as usize
+            || offset + rec_len > self.end
+        {
+            // Malformed entry, stop iterating
+            self.offset = self.end;
+            return None;
+        }
+        self.offset += rec_len;
+        Some((offset, self.block.read_offset_as(offset)))
     }
 }
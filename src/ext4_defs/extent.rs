@@ -13,10 +13,12 @@
 //! inode.i_block, which allows for the first four extents to be recorded without
 //! the use of extra metadata blocks.
 
+use super::crc::*;
+use crate::constants::*;
 use crate::prelude::*;
 
 #[derive(Debug, Default, Clone, Copy)]
-#[repr(C)]
+#[repr(C, packed)]
 pub struct ExtentHeader {
     /// Magic number, 0xF30A.
     magic: u16,
@@ -41,6 +43,8 @@ pub struct ExtentHeader {
 
 impl ExtentHeader {
     const EXTENT_MAGIC: u16 = 0xF30A;
+    /// Maximum depth of the extent tree.
+    const MAX_DEPTH: u16 = 5;
 
     pub fn new(entries_count: u16, max_entries_count: u16, depth: u16, generation: u32) -> Self {
         Self {
@@ -52,6 +56,11 @@ impl ExtentHeader {
         }
     }
 
+    /// Check if the header has the extent magic number.
+    pub fn check_magic(&self) -> bool {
+        self.magic == Self::EXTENT_MAGIC
+    }
+
     /// 获取extent header的条目数
     pub fn entries_count(&self) -> u16 {
         self.entries_count
@@ -89,7 +98,7 @@ impl ExtentHeader {
 }
 
 #[derive(Debug, Default, Clone, Copy)]
-#[repr(C)]
+#[repr(C, packed)]
 pub struct ExtentIndex {
     /// This index node covers file blocks from ‘block’ onward.
     pub first_block: u32,
@@ -122,6 +131,11 @@ impl ExtentIndex {
         self.first_block
     }
 
+    /// Set the start logic block number that this extent index covers
+    pub fn set_start_lblock(&mut self, start_lblock: LBlockId) {
+        self.first_block = start_lblock;
+    }
+
     /// The physical block number of the extent node that is the next level lower in the tree
     pub fn leaf(&self) -> PBlockId {
         ((self.leaf_hi as PBlockId) << 32) | self.leaf_lo as PBlockId
@@ -129,7 +143,7 @@ impl ExtentIndex {
 }
 
 #[derive(Debug, Default, Clone, Copy)]
-#[repr(C)]
+#[repr(C, packed)]
 pub struct Extent {
     /// First file block number that this extent covers.
     first_block: u32,
@@ -151,7 +165,7 @@ pub struct Extent {
 
 impl Extent {
     /// Extent with `block_count` greater than 32768 is considered unwritten.
-    const INIT_MAX_LEN: u16 = 32768;
+    pub const INIT_MAX_LEN: u16 = 32768;
 
     /// Create a new extent with start logic block number, start physical block number, and block count
     pub fn new(start_lblock: LBlockId, start_pblock: PBlockId, block_count: u16) -> Self {
@@ -230,7 +244,7 @@ impl Extent {
 /// both be interpreted as the common type `FakeExtent`. This provides convenience
 /// to some tree operations.
 #[derive(Debug, Clone, Copy)]
-#[repr(C)]
+#[repr(C, packed)]
 pub struct FakeExtent {
     /// The `first_block` field in `Extent` and `ExtentIndex`
     first_block: u32,
@@ -240,16 +254,55 @@ pub struct FakeExtent {
 
 impl From<Extent> for FakeExtent {
     fn from(extent: Extent) -> Self {
+        // SAFETY: both are 12-byte packed structs made of integers
         unsafe { mem::transmute(extent) }
     }
 }
 
 impl From<ExtentIndex> for FakeExtent {
     fn from(extent_index: ExtentIndex) -> Self {
+        // SAFETY: both are 12-byte packed structs made of integers
         unsafe { mem::transmute(extent_index) }
     }
 }
 
+/// The byte offset of the entry at `pos` in an extent node. Entries follow
+/// the header, and `Extent`, `ExtentIndex` and `FakeExtent` have the same size.
+fn entry_offset(pos: usize) -> usize {
+    size_of::<ExtentHeader>() + pos * size_of::<Extent>()
+}
+
+/// Compute the checksum of the bytes of an extent node before the tail.
+fn tail_checksum(raw_data: &[u8], tail: usize, csum_seed: u32, ino: InodeId, ino_gen: u32) -> u32 {
+    let mut csum = csum_seed;
+    csum = crc32(csum, &ino.to_le_bytes());
+    csum = crc32(csum, &ino_gen.to_le_bytes());
+    crc32(csum, &raw_data[..tail])
+}
+
+/// Interpret the bytes at `offset` of an extent node as a reference to `T`.
+///
+/// Panics if the bytes are out of bounds. `T` must be one of the extent
+/// node types, which are packed and valid for any bit pattern, so any
+/// in-bounds offset gives a valid reference.
+fn entry_ref<T>(raw_data: &[u8], offset: usize) -> &T {
+    debug_assert_eq!(mem::align_of::<T>(), 1);
+    let bytes = &raw_data[offset..offset + size_of::<T>()];
+    // SAFETY: `bytes` is in bounds and borrowed for the returned lifetime,
+    // and `T` needs no alignment
+    unsafe { &*(bytes.as_ptr() as *const T) }
+}
+
+/// Interpret the bytes at `offset` of an extent node as a mutable reference
+/// to `T`. See `entry_ref`.
+fn entry_mut<T>(raw_data: &mut [u8], offset: usize) -> &mut T {
+    debug_assert_eq!(mem::align_of::<T>(), 1);
+    let bytes = &mut raw_data[offset..offset + size_of::<T>()];
+    // SAFETY: `bytes` is in bounds and mutably borrowed for the returned
+    // lifetime, and `T` needs no alignment
+    unsafe { &mut *(bytes.as_mut_ptr() as *mut T) }
+}
+
 /// Interpret an immutable byte slice as an extent node. Provide methods to
 /// access the extent header and the following extents or extent indices.
 ///
@@ -267,17 +320,54 @@ impl<'a> ExtentNode<'a> {
 
     /// Get a immutable reference to the extent header
     pub fn header(&self) -> &ExtentHeader {
-        unsafe { &*(self.raw_data.as_ptr() as *const ExtentHeader) }
+        entry_ref(self.raw_data, 0)
     }
 
     /// Get a immutable reference to the extent at a given position
     pub fn extent_at(&self, pos: usize) -> &Extent {
-        unsafe { &*((self.header() as *const ExtentHeader).add(1) as *const Extent).add(pos) }
+        entry_ref(self.raw_data, entry_offset(pos))
     }
 
     /// Get a immmutable reference to the extent indexat a given position
     pub fn extent_index_at(&self, pos: usize) -> &ExtentIndex {
-        unsafe { &*((self.header() as *const ExtentHeader).add(1) as *const ExtentIndex).add(pos) }
+        entry_ref(self.raw_data, entry_offset(pos))
+    }
+
+    /// Check that the node is well formed: the header has the extent magic,
+    /// the entries fit in the node, and the depth is at most 5 and one less
+    /// than `parent_depth`, if the node has a parent. The entries must be
+    /// sorted, index nodes must not be empty, and extents must not overlap
+    /// or end past `MAX_BLOCKS`. The other methods may panic on a node that
+    /// is not.
+    pub fn is_valid(&self, parent_depth: Option<u16>) -> bool {
+        let header = self.header();
+        let capacity = (self.raw_data.len() - size_of::<ExtentHeader>()) / size_of::<Extent>();
+        if !header.check_magic()
+            || header.entries_count > header.max_entries_count
+            || header.max_entries_count as usize > capacity
+            || header.depth > ExtentHeader::MAX_DEPTH
+            || parent_depth.is_some_and(|depth| header.depth + 1 != depth)
+        {
+            return false;
+        }
+        let count = header.entries_count as usize;
+        if header.depth == 0 {
+            // End of the previous extent
+            let mut end = 0;
+            (0..count).all(|i| {
+                let ex = self.extent_at(i);
+                let start = ex.start_lblock() as u64;
+                let valid = start >= end;
+                end = start + ex.block_count() as u64;
+                valid && end <= MAX_BLOCKS as u64
+            })
+        } else {
+            count > 0
+                && (1..count).all(|i| {
+                    self.extent_index_at(i - 1).start_lblock()
+                        < self.extent_index_at(i).start_lblock()
+                })
+        }
     }
 
     /// Find the extent that covers the given logical block number.
@@ -286,14 +376,14 @@ impl<'a> ExtentNode<'a> {
     /// the given logical block number. Return `Err(index)` if not found, and `index` is the
     /// position where the new extent should be inserted.
     pub fn search_extent(&self, lblock: LBlockId) -> core::result::Result<usize, usize> {
-        // debug!("Search extent: {}", lblock);
+        // log_extent!(debug, "Search extent: {}", lblock);
         let mut i = 0;
         while i < self.header().entries_count as usize {
             let extent = self.extent_at(i);
             if extent.start_lblock() <= lblock {
                 if extent.start_lblock() + (extent.block_count() as LBlockId) > lblock {
                     let res = if extent.is_unwritten() { Err(i) } else { Ok(i) };
-                    // debug!("Search res: {:?}", res);
+                    // log_extent!(debug, "Search res: {:?}", res);
                     return res;
                 }
                 i += 1;
@@ -302,7 +392,7 @@ impl<'a> ExtentNode<'a> {
             }
         }
         
-        // debug!("Search res: {:?}", res);
+        // log_extent!(debug, "Search res: {:?}", res);
         Err(i)
     }
 
@@ -310,43 +400,51 @@ impl<'a> ExtentNode<'a> {
     /// gives the next lower node to search.
     ///
     /// Return `Ok(index)` if found, and `eh.extent_index_at(index)` is the target extent index.
-    /// Return `Err(index)` if not found, and `index` is the position where the new extent index
-    /// should be inserted.
+    /// A block before the first extent index belongs to the first one, whose start moves down
+    /// when the block gets mapped. Return `Err(0)` if the node has no extent index.
     pub fn search_extent_index(&self, lblock: LBlockId) -> core::result::Result<usize, usize> {
-        // debug!("Search extent index: {}", lblock);
-        let mut i = 0;
-        while i < self.header().entries_count as usize {
-            let extent_index = self.extent_index_at(i);
-            if extent_index.start_lblock() > lblock {
-                break;
-            }
+        let count = self.header().entries_count as usize;
+        if count == 0 {
+            return Err(0);
+        }
+        let mut i = 1;
+        while i < count && self.extent_index_at(i).start_lblock() <= lblock {
             i += 1;
         }
-        
-        // debug!("Search res: {:?}", res);
         Ok(i - 1)
     }
 
+    /// Verify the checksum in the extent tail of a non-root node. Return
+    /// false if it mismatches or the tail does not fit in the node.
+    pub fn verify_checksum(&self, csum_seed: u32, ino: InodeId, ino_gen: u32) -> bool {
+        let tail = entry_offset(self.header().max_entries_count() as usize);
+        if tail + 4 > self.raw_data.len() {
+            return false;
+        }
+        let stored = u32::from_le_bytes(self.raw_data[tail..tail + 4].try_into().unwrap());
+        stored == tail_checksum(self.raw_data, tail, csum_seed, ino, ino_gen)
+    }
+
     pub fn print(&self) {
-        debug!("Extent header {:?}", self.header());
+        log_extent!(debug, "Extent header {:?}", self.header());
         let mut i = 0;
         while i < self.header().entries_count() as usize {
             if self.header().depth == 0 {
-                let ext = self.extent_at(i);
-                debug!(
+                log_extent!(
+                    debug,
                     "extent[{}] start_lblock={}, start_pblock={}, len={}",
                     i,
-                    ext.start_lblock(),
-                    ext.start_pblock(),
-                    ext.block_count()
+                    self.extent_at(i).start_lblock(),
+                    self.extent_at(i).start_pblock(),
+                    self.extent_at(i).block_count()
                 );
             } else {
-                let ext_idx = self.extent_index_at(i);
-                debug!(
+                log_extent!(
+                    debug,
                     "extent_index[{}] start_lblock={}, leaf={}",
                     i,
-                    ext_idx.start_lblock(),
-                    ext_idx.leaf()
+                    self.extent_index_at(i).start_lblock(),
+                    self.extent_index_at(i).leaf()
                 )
             }
             i += 1;
@@ -378,48 +476,52 @@ impl<'a> ExtentNodeMut<'a> {
 
     /// Get a immutable reference to the extent header
     pub fn header(&self) -> &ExtentHeader {
-        unsafe { &*(self.raw_data.as_ptr() as *const ExtentHeader) }
+        entry_ref(self.raw_data, 0)
     }
 
     /// Get a mutable reference to the extent header
     pub fn header_mut(&mut self) -> &mut ExtentHeader {
-        unsafe { &mut *(self.raw_data.as_mut_ptr() as *mut ExtentHeader) }
+        entry_mut(self.raw_data, 0)
     }
 
     /// Get a immutable reference to the extent at a given position
     pub fn extent_at(&self, pos: usize) -> &Extent {
-        unsafe { &*((self.header() as *const ExtentHeader).add(1) as *const Extent).add(pos) }
+        entry_ref(self.raw_data, entry_offset(pos))
     }
 
     /// Get a mutable reference to the extent at a given position
     pub fn extent_mut_at(&mut self, pos: usize) -> &mut Extent {
-        unsafe { &mut *((self.header_mut() as *mut ExtentHeader).add(1) as *mut Extent).add(pos) }
+        entry_mut(self.raw_data, entry_offset(pos))
     }
 
     /// Get an immutable reference to the extent pos at a given position
     pub fn extent_index_at(&self, pos: usize) -> &ExtentIndex {
-        unsafe { &*((self.header() as *const ExtentHeader).add(1) as *const ExtentIndex).add(pos) }
+        entry_ref(self.raw_data, entry_offset(pos))
     }
 
     /// Get a mutable reference to the extent pos at a given position
     pub fn extent_index_mut_at(&mut self, pos: usize) -> &mut ExtentIndex {
-        unsafe {
-            &mut *((self.header_mut() as *mut ExtentHeader).add(1) as *mut ExtentIndex).add(pos)
-        }
+        entry_mut(self.raw_data, entry_offset(pos))
     }
 
     /// Get an immutable reference to the extent or extent index at a given position,
     /// ignore the detailed type information
     pub fn fake_extent_at(&self, pos: usize) -> &FakeExtent {
-        unsafe { &*((self.header() as *const ExtentHeader).add(1) as *const FakeExtent).add(pos) }
+        entry_ref(self.raw_data, entry_offset(pos))
     }
 
     /// Get a mutable reference to the extent or extent index at a given position,
     /// ignore the detailed type information
     pub fn fake_extent_mut_at(&mut self, pos: usize) -> &mut FakeExtent {
-        unsafe {
-            &mut *((self.header_mut() as *mut ExtentHeader).add(1) as *mut FakeExtent).add(pos)
-        }
+        entry_mut(self.raw_data, entry_offset(pos))
+    }
+
+    /// Set the checksum in the extent tail that follows the last possible
+    /// entry of a non-root node. It covers everything before the tail.
+    pub fn set_checksum(&mut self, csum_seed: u32, ino: InodeId, ino_gen: u32) {
+        let tail = entry_offset(self.header().max_entries_count() as usize);
+        let csum = tail_checksum(self.raw_data, tail, csum_seed, ino, ino_gen);
+        self.raw_data[tail..tail + 4].copy_from_slice(&csum.to_le_bytes());
     }
 
     /// Initialize the extent node
@@ -441,57 +543,20 @@ impl<'a> ExtentNodeMut<'a> {
         extent: &Extent,
         pos: usize,
     ) -> core::result::Result<(), Vec<FakeExtent>> {
-        if self.extent_at(pos).is_unwritten() {
-            // The position has an uninitialized extent
-            *self.extent_mut_at(pos) = *extent;
-            self.header_mut().entries_count += 1;
-            return Ok(());
-        }
-        // The position has a valid extent
         if self.header().entries_count() < self.header().max_entries_count() {
             // The extent node is not full
-            // Insert the extent and move the following extents
-            let mut i = pos;
-            while i < self.header().entries_count() as usize {
-                *self.extent_mut_at(i + 1) = *self.extent_at(i);
-                i += 1;
+            // Insert the extent and move the following extents, from the
+            // last one so that none is overwritten before it is moved
+            let mut i = self.header().entries_count() as usize;
+            while i > pos {
+                *self.extent_mut_at(i) = *self.extent_at(i - 1);
+                i -= 1;
             }
             *self.extent_mut_at(pos) = *extent;
             self.header_mut().entries_count += 1;
             return Ok(());
         }
         // The extent node is full
-        // There may be some unwritten extents, we could find the first
-        // unwritten extent and adjust the extents.
-        let mut unwritten = None;
-        for i in 0..self.header().entries_count() as usize {
-            if self.extent_at(i).is_unwritten() {
-                unwritten = Some(i);
-                break;
-            }
-        }
-        if let Some(unwritten) = unwritten {
-            // There is an uninitialized extent, we could adjust the extents.
-            if unwritten < pos {
-                // Move the extents from `unwritten` to `pos`
-                let mut i = unwritten;
-                while i < pos {
-                    *self.extent_mut_at(i) = *self.extent_at(i + 1);
-                    i += 1;
-                }
-            } else {
-                // Move the extents from `pos` to `unwritten`
-                let mut i = pos;
-                while i < unwritten {
-                    *self.extent_mut_at(i + 1) = *self.extent_at(i);
-                    i += 1;
-                }
-            }
-            *self.extent_mut_at(pos) = *extent;
-            self.header_mut().entries_count += 1;
-            return Ok(());
-        }
-        // The extent node is full and all extents are valid
         // Split the node, return the extents in the right half
         let mut split = Vec::new();
         let mid = self.header().entries_count() as usize * 2 / 3;
@@ -515,6 +580,18 @@ impl<'a> ExtentNodeMut<'a> {
         Err(split)
     }
 
+    /// Remove the extent at `pos` from current node, moving the following
+    /// extents forward.
+    ///
+    /// This function requires this extent node to be a leaf node.
+    pub fn remove_extent(&mut self, pos: usize) {
+        let count = self.header().entries_count() as usize;
+        for i in pos..count - 1 {
+            *self.extent_mut_at(i) = *self.extent_at(i + 1);
+        }
+        self.header_mut().entries_count -= 1;
+    }
+
     /// Insert a new extent index into current node.
     ///
     /// Return `Ok(())` if the insertion is successful. Return `Err(extent_indexs)` if
@@ -529,11 +606,12 @@ impl<'a> ExtentNodeMut<'a> {
     ) -> core::result::Result<(), Vec<FakeExtent>> {
         if self.header().entries_count() < self.header().max_entries_count() {
             // The extent node is not full
-            // Insert the extent index and move the following extent indexs
-            let mut i = pos;
-            while i < self.header().entries_count() as usize {
-                *self.extent_index_mut_at(i + 1) = *self.extent_index_at(i);
-                i += 1;
+            // Insert the extent index and move the following extent indexs,
+            // from the last one
+            let mut i = self.header().entries_count() as usize;
+            while i > pos {
+                *self.extent_index_mut_at(i) = *self.extent_index_at(i - 1);
+                i -= 1;
             }
             *self.extent_index_mut_at(pos) = *extent_index;
             self.header_mut().entries_count += 1;
@@ -0,0 +1,90 @@
+use super::{Block, BlockDevice};
+use crate::constants::*;
+use crate::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// A block-aligned buffer, as required by `O_DIRECT` I/O.
+#[repr(C, align(4096))]
+struct AlignedBuf([u8; BLOCK_SIZE]);
+
+/// A block device backed by a disk image file or a raw device node,
+/// accessed with positioned reads and writes.
+///
+/// Only available on Unix. I/O errors cannot be reported through
+/// `BlockDevice`, they are logged and failed reads return zeros.
+#[derive(Debug)]
+pub struct FileBlockDevice {
+    file: File,
+    /// Number of blocks on the device
+    block_count: u64,
+}
+
+impl FileBlockDevice {
+    /// Open an image file or device for reading and writing.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Self::from_file(file)
+    }
+
+    /// Open an image file or device with `O_DIRECT`, bypassing the host
+    /// page cache. Fails if the filesystem of `path` does not support it.
+    #[cfg(target_os = "linux")]
+    pub fn open_direct(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)?;
+        Self::from_file(file)
+    }
+
+    /// Use an opened file, which may have been opened with `O_DIRECT`.
+    pub fn from_file(mut file: File) -> io::Result<Self> {
+        // The metadata length of a device node is 0, seek to its end instead
+        let size = file.seek(SeekFrom::End(0))?;
+        Ok(Self {
+            file,
+            block_count: size / BLOCK_SIZE as u64,
+        })
+    }
+
+    /// Get the underlying file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl BlockDevice for FileBlockDevice {
+    fn read_block(&self, block_id: PBlockId) -> Block {
+        let offset = block_id * BLOCK_SIZE as u64;
+        let mut buf = AlignedBuf([0; BLOCK_SIZE]);
+        if let Err(e) = self.file.read_exact_at(&mut buf.0, offset) {
+            warn!("Failed to read block {}: {}", block_id, e);
+            return Block::new(block_id, [0; BLOCK_SIZE]);
+        }
+        Block::new(block_id, buf.0)
+    }
+
+    fn write_block(&self, block: &Block) {
+        let offset = block.id * BLOCK_SIZE as u64;
+        let buf = AlignedBuf(block.data);
+        if let Err(e) = self.file.write_all_at(&buf.0, offset) {
+            warn!("Failed to write block {}: {}", block.id, e);
+        }
+    }
+
+    fn block_count(&self) -> Option<u64> {
+        Some(self.block_count)
+    }
+
+    fn flush(&self) {
+        if let Err(e) = self.file.sync_data() {
+            warn!("Failed to flush device: {}", e);
+        }
+    }
+}
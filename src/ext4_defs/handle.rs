@@ -0,0 +1,167 @@
+//! A table of open file handles.
+//!
+//! Handle ids are never reused directly: each slot carries a generation
+//! counter that is bumped when the slot is freed, and the id encodes both
+//! the slot index and the generation. A stale id is rejected with `EBADF`
+//! instead of silently referring to a newly opened file.
+
+use crate::prelude::*;
+use crate::return_error;
+
+/// An opaque handle id, acquired by `Ext4::open`.
+///
+/// The low 32 bits are the slot index, the high 32 bits the slot generation.
+pub type FileHandleId = u64;
+
+/// The state of an open file or directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHandle {
+    /// The inode that is opened.
+    pub inode: InodeId,
+    /// Open flags passed by the caller.
+    pub flags: u32,
+}
+
+/// A slot in the handle table.
+#[derive(Debug)]
+struct HandleSlot<T> {
+    /// Generation of the slot, bumped every time the slot is freed.
+    generation: u32,
+    /// The handle stored in the slot, `None` if the slot is free.
+    value: Option<T>,
+}
+
+/// A table of open handles with a maximum capacity.
+#[derive(Debug)]
+pub struct HandleTable<T> {
+    /// All slots ever allocated.
+    slots: Vec<HandleSlot<T>>,
+    /// Indices of free slots.
+    free: Vec<u32>,
+    /// Number of handles in use.
+    used: usize,
+    /// Maximum number of handles in use.
+    max: usize,
+}
+
+impl<T> HandleTable<T> {
+    /// Create an empty handle table that holds at most `max` handles.
+    pub fn new(max: usize) -> Self {
+        Self {
+            slots: Vec::new(),
+            free: Vec::new(),
+            used: 0,
+            max,
+        }
+    }
+
+    /// The number of handles in use.
+    pub fn len(&self) -> usize {
+        self.used
+    }
+
+    /// Check if no handle is in use.
+    pub fn is_empty(&self) -> bool {
+        self.used == 0
+    }
+
+    /// The maximum number of handles in use.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Set the maximum number of handles. Handles already open are kept
+    /// even if they exceed the new limit.
+    pub fn set_max(&mut self, max: usize) {
+        self.max = max;
+    }
+
+    /// Insert a handle, return its id.
+    ///
+    /// # Error
+    ///
+    /// `EMFILE` - the table is full
+    pub fn insert(&mut self, value: T) -> Result<FileHandleId> {
+        if self.used >= self.max {
+            return_error!(ErrCode::EMFILE, "Too many open handles ({})", self.used);
+        }
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.slots.push(HandleSlot {
+                    generation: 0,
+                    value: None,
+                });
+                self.slots.len() as u32 - 1
+            }
+        };
+        let slot = &mut self.slots[index as usize];
+        slot.value = Some(value);
+        self.used += 1;
+        Ok(Self::make_id(index, slot.generation))
+    }
+
+    /// Get a handle by id.
+    ///
+    /// # Error
+    ///
+    /// `EBADF` - `id` is invalid or has been removed
+    pub fn get(&self, id: FileHandleId) -> Result<&T> {
+        let (index, generation) = Self::split_id(id);
+        match self.slots.get(index as usize) {
+            Some(slot) if slot.generation == generation => slot.value.as_ref(),
+            _ => None,
+        }
+        .ok_or(Ext4Error::new(ErrCode::EBADF))
+    }
+
+    /// Get a mutable handle by id.
+    ///
+    /// # Error
+    ///
+    /// `EBADF` - `id` is invalid or has been removed
+    pub fn get_mut(&mut self, id: FileHandleId) -> Result<&mut T> {
+        let (index, generation) = Self::split_id(id);
+        match self.slots.get_mut(index as usize) {
+            Some(slot) if slot.generation == generation => slot.value.as_mut(),
+            _ => None,
+        }
+        .ok_or(Ext4Error::new(ErrCode::EBADF))
+    }
+
+    /// Remove a handle by id, return the removed handle.
+    ///
+    /// # Error
+    ///
+    /// `EBADF` - `id` is invalid or has been removed
+    pub fn remove(&mut self, id: FileHandleId) -> Result<T> {
+        let (index, generation) = Self::split_id(id);
+        let value = match self.slots.get_mut(index as usize) {
+            Some(slot) if slot.generation == generation => slot.value.take(),
+            _ => None,
+        }
+        .ok_or(Ext4Error::new(ErrCode::EBADF))?;
+        let slot = &mut self.slots[index as usize];
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(index);
+        self.used -= 1;
+        Ok(value)
+    }
+
+    /// Iterate over all handles in use and their ids.
+    pub fn iter(&self) -> impl Iterator<Item = (FileHandleId, &T)> {
+        self.slots.iter().enumerate().filter_map(|(i, slot)| {
+            slot.value
+                .as_ref()
+                .map(|v| (Self::make_id(i as u32, slot.generation), v))
+        })
+    }
+
+    fn make_id(index: u32, generation: u32) -> FileHandleId {
+        ((generation as u64) << 32) | index as u64
+    }
+
+    fn split_id(id: FileHandleId) -> (u32, u32) {
+        (id as u32, (id >> 32) as u32)
+    }
+}
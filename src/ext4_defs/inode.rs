@@ -26,6 +26,9 @@ bitflags! {
         const OTHER_READ = 0x4;
         const OTHER_WRITE = 0x2;
         const OTHER_EXEC = 0x1;
+        const SET_UID = 0x800;
+        const SET_GID = 0x400;
+        const STICKY = 0x200;
         // File type
         const TYPE_MASK = 0xF000;
         const FIFO = 0x1000;
@@ -44,18 +47,14 @@ impl InodeMode {
     /// Enable read and write for all users.
     pub const ALL_RW: InodeMode = InodeMode::from_bits_retain(0o666);
 
-    /// Set an inode mode from a file type and permission bits.
+    /// Set an inode mode from a file type and permission bits. An unknown
+    /// file type makes a regular file.
     pub fn from_type_and_perm(file_type: FileType, perm: InodeMode) -> Self {
-        (match file_type {
-            FileType::RegularFile => InodeMode::FILE,
-            FileType::Directory => InodeMode::DIRECTORY,
-            FileType::CharacterDev => InodeMode::CHARDEV,
-            FileType::BlockDev => InodeMode::BLOCKDEV,
-            FileType::Fifo => InodeMode::FIFO,
-            FileType::Socket => InodeMode::SOCKET,
-            FileType::SymLink => InodeMode::SOFTLINK,
-            _ => InodeMode::FILE,
-        }) | (perm & InodeMode::PERM_MASK)
+        let file_type = match file_type {
+            FileType::Unknown => FileType::RegularFile,
+            file_type => file_type,
+        };
+        InodeMode::from(file_type) | (perm & InodeMode::PERM_MASK)
     }
     /// Get permission bits of an inode mode.
     pub fn perm(&self) -> InodeMode {
@@ -76,6 +75,70 @@ impl InodeMode {
     }
 }
 
+/// Convert a full `st_mode` value, as used by `libc` and FUSE: the
+/// `S_IFMT` file type and all 12 permission bits, setuid, setgid and sticky
+/// included. Linux modes fit in the 16 bits stored in the inode, higher
+/// bits are dropped.
+impl From<u32> for InodeMode {
+    fn from(mode: u32) -> Self {
+        InodeMode::from_bits_retain(mode as u16)
+    }
+}
+
+/// Convert to a full `st_mode` value, see `From<u32> for InodeMode`.
+impl From<InodeMode> for u32 {
+    fn from(mode: InodeMode) -> Self {
+        mode.bits() as u32
+    }
+}
+
+/// The `S_IFMT` bits of a file type, empty for `FileType::Unknown`.
+impl From<FileType> for InodeMode {
+    fn from(file_type: FileType) -> Self {
+        match file_type {
+            FileType::RegularFile => InodeMode::FILE,
+            FileType::Directory => InodeMode::DIRECTORY,
+            FileType::CharacterDev => InodeMode::CHARDEV,
+            FileType::BlockDev => InodeMode::BLOCKDEV,
+            FileType::Fifo => InodeMode::FIFO,
+            FileType::Socket => InodeMode::SOCKET,
+            FileType::SymLink => InodeMode::SOFTLINK,
+            FileType::Unknown => InodeMode::empty(),
+        }
+    }
+}
+
+/// The file type given by the `S_IFMT` bits of a mode.
+impl From<InodeMode> for FileType {
+    fn from(mode: InodeMode) -> Self {
+        mode.file_type()
+    }
+}
+
+bitflags! {
+    /// Inode flags that users may get and set, as `chattr` does through
+    /// `FS_IOC_GETFLAGS` and `FS_IOC_SETFLAGS`. The values are the ones
+    /// stored in `Inode.flags`.
+    #[derive(PartialEq, Eq, Debug, Clone, Copy)]
+    pub struct InodeFlags: u32 {
+        /// Freed blocks are zeroed on stable storage (secure deletion)
+        const SECRM = 0x1;
+        /// The file cannot be modified, linked, renamed or removed
+        const IMMUTABLE = 0x10;
+        /// The file can only be opened for appending
+        const APPEND = 0x20;
+        /// The file is skipped by `dump`
+        const NODUMP = 0x40;
+        /// The access time is not updated
+        const NOATIME = 0x80;
+        /// File data is written through the journal (`data=journal`)
+        const JOURNAL_DATA = 0x4000;
+        /// The directory is the top of a hierarchy: its subdirectories are
+        /// spread across block groups like those of the root
+        const TOPDIR = 0x20000;
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Clone)]
 pub struct Linux2 {
@@ -100,9 +163,9 @@ pub struct Linux2 {
 /// 128 bytes. By default, ext4 inode records are 256 bytes, and (as of
 /// October 2013) the inode structure is 156 bytes (i_extra_isize = 28).
 ///
-/// We only implement the larger version for simplicity. Guarantee that
-/// `sb.inode_size` equals to 256. This value will be checked when
-/// loading the filesystem.
+/// The structure holds the larger version. On filesystems with 128-byte
+/// inodes only the original ext2 fields are read and written, see
+/// `AsBytes::from_record`.
 #[repr(C)]
 #[derive(Debug, Clone)]
 pub struct Inode {
@@ -166,6 +229,7 @@ pub struct Inode {
 /// Because `[u8; 60]` cannot derive `Default`, we implement it manually.
 impl Default for Inode {
     fn default() -> Self {
+        // SAFETY: `Inode` consists of integers only, all-zero is a valid value
         let mut inode: Self = unsafe { mem::zeroed() };
         inode.extra_isize = (size_of::<Inode>() - 128) as u16;
         inode
@@ -175,7 +239,11 @@ impl Default for Inode {
 unsafe impl AsBytes for Inode {}
 
 impl Inode {
+    const FLAG_HUGE_FILE: u32 = 0x00040000;
     const FLAG_EXTENTS: u32 = 0x00080000;
+    const FLAG_ENCRYPT: u32 = 0x00000800;
+    const FLAG_VERITY: u32 = 0x00100000;
+    const FLAG_CASEFOLD: u32 = 0x40000000;
 
     pub fn mode(&self) -> InodeMode {
         InodeMode::from_bits_truncate(self.mode)
@@ -231,6 +299,36 @@ impl Inode {
         self.osd2.l_gid_hi = (gid >> 16) as u16;
     }
 
+    /// Set the size of the extended fields past the original 128-byte
+    /// inode, the fields past it are not used. In-inode extended attributes
+    /// start right after them, so it is only set on new inodes, before the
+    /// extended fields are.
+    pub fn set_extra_isize(&mut self, extra_isize: u16) {
+        self.extra_isize = extra_isize;
+    }
+
+    /// Check if the extended fields include the field ending `end` bytes
+    /// past the original 128-byte inode.
+    fn has_extra(&self, end: usize) -> bool {
+        self.extra_isize as usize >= end
+    }
+
+    /// Drop the extended fields that do not fit in an on-disk inode of
+    /// `inode_size` bytes.
+    pub fn fit_extra_isize(&mut self, inode_size: usize) {
+        let max = inode_size.saturating_sub(128).min(u16::MAX as usize) as u16;
+        self.extra_isize = self.extra_isize.min(max);
+    }
+
+    /// The project id, 0 if the inode is too small to store one.
+    pub fn projid(&self) -> u32 {
+        if self.has_extra(size_of::<Inode>() - 128) {
+            self.projid
+        } else {
+            0
+        }
+    }
+
     pub fn size(&self) -> u64 {
         self.size as u64 | ((self.size_hi as u64) << 32)
     }
@@ -240,28 +338,63 @@ impl Inode {
         self.size_hi = (size >> 32) as u32;
     }
 
+    /// Get the nanoseconds of a time from its extra field, which holds them
+    /// above 2 epoch bits. 0 if the inode is too small for the field, which
+    /// ends `end` bytes past the original 128-byte inode.
+    fn extra_nsec(&self, extra: u32, end: usize) -> u32 {
+        if self.has_extra(end) {
+            extra >> 2
+        } else {
+            0
+        }
+    }
+
     pub fn atime(&self) -> u32 {
         self.atime
     }
 
+    /// Set the access time in seconds, clearing its nanoseconds.
     pub fn set_atime(&mut self, atime: u32) {
         self.atime = atime;
+        if self.has_extra(16) {
+            self.atime_extra &= 0b11;
+        }
+    }
+
+    pub fn atime_nsec(&self) -> u32 {
+        self.extra_nsec(self.atime_extra, 16)
     }
 
     pub fn ctime(&self) -> u32 {
         self.ctime
     }
 
+    /// Set the change time in seconds, clearing its nanoseconds.
     pub fn set_ctime(&mut self, ctime: u32) {
         self.ctime = ctime;
+        if self.has_extra(8) {
+            self.ctime_extra &= 0b11;
+        }
+    }
+
+    pub fn ctime_nsec(&self) -> u32 {
+        self.extra_nsec(self.ctime_extra, 8)
     }
 
     pub fn mtime(&self) -> u32 {
         self.mtime
     }
 
+    /// Set the modification time in seconds, clearing its nanoseconds.
     pub fn set_mtime(&mut self, mtime: u32) {
         self.mtime = mtime;
+        if self.has_extra(12) {
+            self.mtime_extra &= 0b11;
+        }
+    }
+
+    pub fn mtime_nsec(&self) -> u32 {
+        self.extra_nsec(self.mtime_extra, 12)
     }
 
     pub fn dtime(&self) -> u32 {
@@ -272,19 +405,40 @@ impl Inode {
         self.dtime = dtime;
     }
 
+    /// Check if the inode is large enough to store its creation time.
+    pub fn has_crtime(&self) -> bool {
+        self.has_extra(24)
+    }
+
     pub fn crtime(&self) -> u32 {
         self.crtime
     }
 
+    /// Set the creation time in seconds, clearing its nanoseconds. Ignored
+    /// if the inode is too small to store it.
     pub fn set_crtime(&mut self, crtime: u32) {
-        self.crtime = crtime;
+        if self.has_crtime() {
+            self.crtime = crtime;
+            self.crtime_extra &= 0b11;
+        }
+    }
+
+    pub fn crtime_nsec(&self) -> u32 {
+        self.extra_nsec(self.crtime_extra, 24)
     }
 
     /// Get the number of 512-byte blocks (`INODE_BLOCK_SIZE`) used by the inode.
+    /// Inodes with the `HUGE_FILE` flag count filesystem blocks on disk,
+    /// which is converted here.
     ///
     /// WARN: This is different from filesystem block (`BLOCK_SIZE`)!
     pub fn block_count(&self) -> u64 {
-        self.block_count as u64 | ((self.osd2.l_blocks_hi as u64) << 32)
+        let count = self.block_count as u64 | ((self.osd2.l_blocks_hi as u64) << 32);
+        if self.flags & Self::FLAG_HUGE_FILE != 0 {
+            count * (BLOCK_SIZE / INODE_BLOCK_SIZE) as u64
+        } else {
+            count
+        }
     }
 
     /// Get the number of filesystem blocks (`BLOCK_SIZE`) used by the inode.
@@ -293,9 +447,19 @@ impl Inode {
     }
 
     /// Set the number of 512-byte blocks (`INODE_BLOCK_SIZE`) used by the inode.
+    /// Counts beyond 48 bits are stored in filesystem blocks with the
+    /// `HUGE_FILE` flag, which needs the `huge_file` feature, as do counts
+    /// beyond 32 bits.
     ///
     /// WARN: This is different from filesystem block (`BLOCK_SIZE`)!
     pub fn set_block_count(&mut self, cnt: u64) {
+        let cnt = if cnt >> 48 != 0 {
+            self.flags |= Self::FLAG_HUGE_FILE;
+            cnt / (BLOCK_SIZE / INODE_BLOCK_SIZE) as u64
+        } else {
+            self.flags &= !Self::FLAG_HUGE_FILE;
+            cnt
+        };
         self.block_count = cnt as u32;
         self.osd2.l_blocks_hi = (cnt >> 32) as u16;
     }
@@ -321,6 +485,63 @@ impl Inode {
         self.flags |= f;
     }
 
+    /// Get the user-settable inode flags.
+    pub fn user_flags(&self) -> InodeFlags {
+        InodeFlags::from_bits_truncate(self.flags)
+    }
+
+    /// Replace the user-settable inode flags, keeping the others.
+    pub fn set_user_flags(&mut self, flags: InodeFlags) {
+        self.flags = (self.flags & !InodeFlags::all().bits()) | flags.bits();
+    }
+
+    /// Check if the inode maps its blocks with an extent tree.
+    pub fn uses_extents(&self) -> bool {
+        self.flags & Self::FLAG_EXTENTS != 0
+    }
+
+    /// Check if the inode maps its blocks with the ext2 block map: 12
+    /// direct pointers followed by single, double and triple indirect
+    /// blocks. Devices, fifos, sockets and fast symlinks have no blocks.
+    pub fn uses_block_map(&self) -> bool {
+        if self.uses_extents() {
+            return false;
+        }
+        match self.file_type() {
+            FileType::RegularFile | FileType::Directory => true,
+            FileType::SymLink => !self.is_fast_symlink(),
+            _ => false,
+        }
+    }
+
+    /// Check if the inode is a symlink whose target is stored inline.
+    pub fn is_fast_symlink(&self) -> bool {
+        self.is_softlink() && !self.uses_extents() && self.size() < FAST_SYMLINK_MAX as u64
+    }
+
+    /// Check if names in this directory are compared case-insensitively.
+    pub fn is_casefold(&self) -> bool {
+        self.flags & Self::FLAG_CASEFOLD != 0
+    }
+
+    /// Check if the contents, or the entry names of a directory, are
+    /// encrypted (fscrypt).
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & Self::FLAG_ENCRYPT != 0
+    }
+
+    /// Check if the contents are sealed by fs-verity, with a Merkle tree
+    /// stored past the end of file.
+    pub fn is_verity(&self) -> bool {
+        self.flags & Self::FLAG_VERITY != 0
+    }
+
+    /// The size of the extended fields past the original 128-byte inode.
+    /// In-inode extended attributes follow them.
+    pub fn extra_isize(&self) -> usize {
+        self.extra_isize as usize
+    }
+
     pub fn xattr_block(&self) -> PBlockId {
         ((self.osd2.l_file_acl_hi as u64) << 32) | self.file_acl as u64
     }
@@ -333,17 +554,13 @@ impl Inode {
     /* Extent methods */
 
     /// Get the immutable extent root node
-    pub fn extent_root(&self) -> ExtentNode {
-        ExtentNode::from_bytes(unsafe {
-            core::slice::from_raw_parts(self.block.as_ptr(), 60)
-        })
+    pub fn extent_root(&self) -> ExtentNode<'_> {
+        ExtentNode::from_bytes(&self.block)
     }
 
     /// Get the mutable extent root node
-    pub fn extent_root_mut(&mut self) -> ExtentNodeMut {
-        ExtentNodeMut::from_bytes(unsafe {
-            core::slice::from_raw_parts_mut(self.block.as_mut_ptr(), 60)
-        })
+    pub fn extent_root_mut(&mut self) -> ExtentNodeMut<'_> {
+        ExtentNodeMut::from_bytes(&mut self.block)
     }
 
     /// Initialize the `flags` and `block` field of inode. Mark the
@@ -353,9 +570,69 @@ impl Inode {
         self.set_flags(Self::FLAG_EXTENTS);
         self.extent_root_mut().init(0, 0);
     }
+
+    /* Block map methods */
+
+    /// Get the `i`-th pointer of the block map. 0 means a hole.
+    pub fn block_ptr(&self, i: usize) -> u32 {
+        u32::from_le_bytes(self.block[i * 4..i * 4 + 4].try_into().unwrap())
+    }
+
+    /// Set the `i`-th pointer of the block map.
+    pub fn set_block_ptr(&mut self, i: usize, block: u32) {
+        self.block[i * 4..i * 4 + 4].copy_from_slice(&block.to_le_bytes());
+    }
+
+    /* Special file methods */
+
+    /// Get the target of a fast symlink, stored in place of the extent tree.
+    pub fn fast_symlink(&self) -> &[u8] {
+        &self.block[..(self.size() as usize).min(self.block.len())]
+    }
+
+    /// Store the target of a fast symlink in place of the extent tree, and
+    /// set the size to its length. The target must be shorter than
+    /// `FAST_SYMLINK_MAX` bytes.
+    pub fn set_fast_symlink(&mut self, target: &[u8]) {
+        self.flags &= !Self::FLAG_EXTENTS;
+        self.block = [0; 60];
+        self.block[..target.len()].copy_from_slice(target);
+        self.set_size(target.len() as u64);
+    }
+
+    /// Get the `(major, minor)` device number of a device inode.
+    pub fn device(&self) -> (u32, u32) {
+        let word = |i: usize| u32::from_le_bytes(self.block[i * 4..i * 4 + 4].try_into().unwrap());
+        // Small numbers use the old encoding in the first word
+        let old = word(0);
+        if old != 0 {
+            return ((old >> 8) & 0xff, old & 0xff);
+        }
+        let new = word(1);
+        ((new & 0xfff00) >> 8, (new & 0xff) | ((new >> 12) & 0xfff00))
+    }
+
+    /// Set the `(major, minor)` device number of a device inode, encoded as
+    /// Linux does.
+    pub fn set_device(&mut self, major: u32, minor: u32) {
+        self.flags &= !Self::FLAG_EXTENTS;
+        self.block = [0; 60];
+        if major < 256 && minor < 256 {
+            let old = (major << 8) | minor;
+            self.block[..4].copy_from_slice(&old.to_le_bytes());
+        } else {
+            let new = (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12);
+            self.block[4..8].copy_from_slice(&new.to_le_bytes());
+        }
+    }
 }
 
-/// A combination of an `Inode` and its id
+/// A combination of an `Inode` and its id, the in-memory copy of an inode.
+///
+/// The filesystem reads and writes back inodes as `InodeRef`s, checksummed
+/// when written. Repeated writes within an operation are batched and reach
+/// the inode table once. Embedders that want an object bound to an inode
+/// use `InodeHandle` instead.
 #[derive(Clone, Debug)]
 pub struct InodeRef {
     pub id: InodeId,
@@ -367,13 +644,50 @@ impl InodeRef {
         Self { id, inode }
     }
 
-    pub fn set_checksum(&mut self, uuid: &[u8]) {
-        let mut checksum = crc32(CRC32_INIT, uuid);
+    /// Set the inode checksum over an on-disk inode of `inode_size` bytes.
+    /// `extra` is the rest of the on-disk inode past `Inode`, which is
+    /// covered by the checksum as well. Inodes too small for the upper 16
+    /// bits, such as 128-byte inodes, only keep the lower 16 bits.
+    pub fn set_checksum(&mut self, csum_seed: u32, inode_size: usize, extra: &[u8]) {
+        // The upper bits follow `extra_isize`
+        let has_hi = inode_size > 128 && self.inode.has_extra(4);
+        self.inode.osd2.l_checksum_lo = 0;
+        if has_hi {
+            self.inode.checksum_hi = 0;
+        }
+        let bytes = self.inode.to_bytes();
+        let mut checksum = csum_seed;
         checksum = crc32(checksum, &self.id.to_le_bytes());
         checksum = crc32(checksum, &self.inode.generation.to_le_bytes());
-        checksum = crc32(checksum, self.inode.to_bytes());
+        checksum = crc32(checksum, &bytes[..inode_size.min(bytes.len())]);
+        checksum = crc32(checksum, extra);
         self.inode.osd2.l_checksum_lo = checksum as u16;
-        self.inode.checksum_hi = (checksum >> 16) as u16;
+        if has_hi {
+            self.inode.checksum_hi = (checksum >> 16) as u16;
+        }
+    }
+}
+
+bitflags! {
+    /// The fields of `FileAttr` that hold valid values. The values match
+    /// the `STATX_*` flags of `statx`.
+    #[derive(PartialEq, Eq, Debug, Clone, Copy)]
+    pub struct AttrMask: u32 {
+        const TYPE = 0x1;
+        const MODE = 0x2;
+        const NLINK = 0x4;
+        const UID = 0x8;
+        const GID = 0x10;
+        const ATIME = 0x20;
+        const MTIME = 0x40;
+        const CTIME = 0x80;
+        const INO = 0x100;
+        const SIZE = 0x200;
+        const BLOCKS = 0x400;
+        /// All of the above, what `stat` returns
+        const BASIC_STATS = 0x7ff;
+        /// Creation time
+        const BTIME = 0x800;
     }
 }
 
@@ -385,10 +699,27 @@ pub struct FileAttr {
     pub mtime: u32,
     pub ctime: u32,
     pub crtime: u32,
+    /// Nanoseconds of `atime`, 0 on inodes without extended fields.
+    pub atime_nsec: u32,
+    /// Nanoseconds of `mtime`, 0 on inodes without extended fields.
+    pub mtime_nsec: u32,
+    /// Nanoseconds of `ctime`, 0 on inodes without extended fields.
+    pub ctime_nsec: u32,
+    /// Nanoseconds of `crtime`, 0 on inodes without extended fields.
+    pub crtime_nsec: u32,
     pub blocks: u64,
     pub ftype: FileType,
     pub perm: InodeMode,
     pub links: u16,
     pub uid: u32,
     pub gid: u32,
+    /// Generation number, changes whenever the inode number is reused.
+    pub generation: u32,
+    /// `(major, minor)` device number of device inodes, `(0, 0)` otherwise.
+    pub rdev: (u32, u32),
+    /// Preferred I/O size in bytes, the filesystem block size.
+    pub blksize: u32,
+    /// The fields holding valid values. `crtime` is only valid with
+    /// `AttrMask::BTIME`.
+    pub attributes_mask: AttrMask,
 }
@@ -0,0 +1,95 @@
+use crate::constants::*;
+use core::cmp::min;
+use core::ops::{Deref, DerefMut};
+
+/// The buffers of a vectored write, seen as one buffer of their total
+/// length. Any `Deref<Target = [u8]>` buffer works, such as `&[u8]` or
+/// `std::io::IoSlice`.
+pub(crate) struct IoVec<'a, B> {
+    bufs: &'a [B],
+    /// Offset of the view in the buffers
+    start: usize,
+    len: usize,
+}
+
+impl<'a, B: Deref<Target = [u8]>> IoVec<'a, B> {
+    /// View all the bytes of `bufs`.
+    pub fn new(bufs: &'a [B]) -> Self {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        Self {
+            bufs,
+            start: 0,
+            len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// View the bytes from `start` to `end` of this view.
+    pub fn slice(&self, start: usize, end: usize) -> Self {
+        assert!(start <= end && end <= self.len);
+        Self {
+            bufs: self.bufs,
+            start: self.start + start,
+            len: end - start,
+        }
+    }
+
+    /// The `len` bytes at `pos`, at most a block. Bytes spanning several
+    /// buffers are gathered in `scratch`.
+    pub fn chunk<'s>(
+        &'s self,
+        pos: usize,
+        len: usize,
+        scratch: &'s mut [u8; BLOCK_SIZE],
+    ) -> &'s [u8] {
+        assert!(pos + len <= self.len && len <= BLOCK_SIZE);
+        let mut pos = self.start + pos;
+        let mut gathered = 0;
+        for buf in self.bufs {
+            if pos >= buf.len() {
+                pos -= buf.len();
+                continue;
+            }
+            let copy_len = min(buf.len() - pos, len - gathered);
+            if gathered == 0 && copy_len == len {
+                // Within a single buffer
+                return &buf[pos..pos + len];
+            }
+            scratch[gathered..gathered + copy_len].copy_from_slice(&buf[pos..pos + copy_len]);
+            gathered += copy_len;
+            pos = 0;
+            if gathered == len {
+                break;
+            }
+        }
+        &scratch[..len]
+    }
+}
+
+/// Copy `src` to the buffers of a vectored read, at `pos` of the buffer
+/// they make up together. Any `DerefMut<Target = [u8]>` buffer works, such
+/// as `&mut [u8]` or `std::io::IoSliceMut`.
+pub(crate) fn scatter<B: DerefMut<Target = [u8]>>(bufs: &mut [B], pos: usize, src: &[u8]) {
+    let mut pos = pos;
+    let mut copied = 0;
+    for buf in bufs.iter_mut() {
+        if copied == src.len() {
+            break;
+        }
+        if pos >= buf.len() {
+            pos -= buf.len();
+            continue;
+        }
+        let copy_len = min(buf.len() - pos, src.len() - copied);
+        buf[pos..pos + copy_len].copy_from_slice(&src[copied..copied + copy_len]);
+        copied += copy_len;
+        pos = 0;
+    }
+}
@@ -0,0 +1,213 @@
+//! The jbd2 journal.
+//!
+//! The journal is a circular log stored in a reserved inode. Block 0 of the
+//! journal holds the journal superblock. Each transaction is written as
+//! descriptor blocks, each followed by the data blocks it describes, and
+//! ends with a commit block. Unlike the rest of the filesystem, all journal
+//! fields are big-endian.
+
+use super::crc::*;
+use super::AsBytes;
+use crate::constants::{BLOCK_SIZE, CRC32_INIT};
+use crate::prelude::*;
+
+/// Magic number of journal blocks.
+pub const JBD2_MAGIC: u32 = 0xC03B_3998;
+
+/// Size of the common header of journal blocks.
+pub const JBD2_HEADER_SIZE: usize = 12;
+
+/// Size of the checksum tail of descriptor and revoke blocks.
+pub const JBD2_TAIL_SIZE: usize = 4;
+
+/// Descriptor block type, listing the data blocks that follow.
+pub const JBD2_DESCRIPTOR_BLOCK: u32 = 1;
+/// Commit block type, ending a transaction.
+pub const JBD2_COMMIT_BLOCK: u32 = 2;
+/// Journal superblock (v2) type.
+pub const JBD2_SUPER_BLOCK_V2: u32 = 4;
+/// Revoke block type, listing blocks not to replay.
+pub const JBD2_REVOKE_BLOCK: u32 = 5;
+
+bitflags! {
+    /// Incompatible journal features.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct JournalIncompat: u32 {
+        const REVOKE = 0x1;
+        const BIT64 = 0x2;
+        const ASYNC_COMMIT = 0x4;
+        const CSUM_V2 = 0x8;
+        const CSUM_V3 = 0x10;
+        const FAST_COMMIT = 0x20;
+    }
+}
+
+bitflags! {
+    /// Flags of a block tag in a descriptor block.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct JournalTagFlags: u32 {
+        /// The block started with the journal magic, which was zeroed
+        const ESCAPE = 0x1;
+        /// The tag is not followed by a UUID
+        const SAME_UUID = 0x2;
+        /// The block was deleted by this transaction
+        const DELETED = 0x4;
+        /// The last tag of the descriptor block
+        const LAST_TAG = 0x8;
+    }
+}
+
+/// The journal superblock (v2), stored in block 0 of the journal.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct JournalSuperBlock {
+    magic: u32,
+    block_type: u32,
+    header_sequence: u32,
+    /// Journal device block size
+    block_size: u32,
+    /// Total number of blocks in the journal
+    max_len: u32,
+    /// First block of log information
+    first: u32,
+    /// First commit id expected in the log
+    sequence: u32,
+    /// Block of the start of the log, 0 if the log is empty
+    start: u32,
+    /// Error value, as set by `jbd2_journal_abort`
+    errno: u32,
+    feature_compat: u32,
+    feature_incompat: u32,
+    feature_ro_compat: u32,
+    uuid: [u8; 16],
+    nr_users: u32,
+    dyn_super: u32,
+    max_transaction: u32,
+    max_trans_data: u32,
+    checksum_type: u8,
+    padding2: [u8; 3],
+    num_fc_blocks: u32,
+    head: u32,
+    padding: [u32; 40],
+    checksum: u32,
+    users: [u8; 16 * 48],
+}
+
+unsafe impl AsBytes for JournalSuperBlock {}
+
+impl JournalSuperBlock {
+    /// Whether the block is a journal superblock.
+    pub fn check_magic(&self) -> bool {
+        u32::from_be(self.magic) == JBD2_MAGIC
+            && u32::from_be(self.block_type) == JBD2_SUPER_BLOCK_V2
+    }
+
+    pub fn block_size(&self) -> u32 {
+        u32::from_be(self.block_size)
+    }
+
+    pub fn max_len(&self) -> u32 {
+        u32::from_be(self.max_len)
+    }
+
+    pub fn first(&self) -> u32 {
+        u32::from_be(self.first)
+    }
+
+    pub fn sequence(&self) -> u32 {
+        u32::from_be(self.sequence)
+    }
+
+    pub fn set_sequence(&mut self, sequence: u32) {
+        self.sequence = sequence.to_be();
+    }
+
+    /// The block where the log starts, 0 if the log is empty.
+    pub fn start(&self) -> u32 {
+        u32::from_be(self.start)
+    }
+
+    pub fn set_start(&mut self, start: u32) {
+        self.start = start.to_be();
+    }
+
+    pub fn uuid(&self) -> [u8; 16] {
+        self.uuid
+    }
+
+    pub fn features_incompatible(&self) -> JournalIncompat {
+        JournalIncompat::from_bits_retain(u32::from_be(self.feature_incompat))
+    }
+
+    /// Whether journal blocks carry checksums (v2 or v3).
+    pub fn has_csum(&self) -> bool {
+        self.features_incompatible()
+            .intersects(JournalIncompat::CSUM_V2 | JournalIncompat::CSUM_V3)
+    }
+
+    /// The size of a block tag in descriptor blocks, without the UUID.
+    pub fn tag_size(&self) -> usize {
+        let incompat = self.features_incompatible();
+        if incompat.contains(JournalIncompat::CSUM_V3) {
+            16
+        } else if incompat.contains(JournalIncompat::BIT64) {
+            12
+        } else {
+            8
+        }
+    }
+
+    /// The seed of journal checksums, `crc32c(~0, uuid)`.
+    pub fn checksum_seed(&self) -> u32 {
+        crc32(CRC32_INIT, &self.uuid)
+    }
+
+    /// Set the superblock checksum, if checksums are enabled.
+    pub fn set_checksum(&mut self) {
+        if self.has_csum() {
+            self.checksum = 0;
+            self.checksum = crc32(CRC32_INIT, self.to_bytes()).to_be();
+        }
+    }
+}
+
+/// Read a big-endian `u32` at `offset`.
+pub fn be32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Read a big-endian `u16` at `offset`.
+pub fn be16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+/// Write a big-endian `u16` at `offset`.
+pub fn put_be16(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Write a big-endian `u32` at `offset`.
+pub fn put_be32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Write the common header of a journal block.
+pub fn put_jbd2_header(data: &mut [u8], block_type: u32, sequence: u32) {
+    put_be32(data, 0, JBD2_MAGIC);
+    put_be32(data, 4, block_type);
+    put_be32(data, 8, sequence);
+}
+
+/// Checksum of a whole journal block, with the checksum field at `offset`
+/// taken as zero.
+pub fn jbd2_block_checksum(seed: u32, data: &[u8; BLOCK_SIZE], offset: usize) -> u32 {
+    let mut copy = *data;
+    put_be32(&mut copy, offset, 0);
+    crc32(seed, &copy)
+}
+
+/// Checksum of a data block logged by transaction `sequence`, as stored in
+/// its block tag.
+pub fn jbd2_tag_checksum(seed: u32, sequence: u32, data: &[u8; BLOCK_SIZE]) -> u32 {
+    crc32(crc32(seed, &sequence.to_be_bytes()), data)
+}
@@ -0,0 +1,128 @@
+use super::{Block, BlockDevice};
+use crate::constants::*;
+use crate::prelude::*;
+use axsync::Mutex;
+
+/// Block data shared between a device and its checkpoints.
+type BlockData = Arc<[u8; BLOCK_SIZE]>;
+
+/// An in-memory block device.
+///
+/// Blocks are stored sparsely: blocks never written read as zeros and take
+/// no memory, and discarded blocks are dropped. Checkpoints share block
+/// data with the device until it is overwritten, so taking one is cheap.
+pub struct MemBlockDevice {
+    /// Written blocks
+    blocks: Mutex<BTreeMap<PBlockId, BlockData>>,
+    /// Number of blocks on the device
+    block_count: u64,
+}
+
+/// A snapshot of the contents of a `MemBlockDevice`, acquired by
+/// `MemBlockDevice::checkpoint`.
+#[derive(Clone)]
+pub struct MemCheckpoint {
+    blocks: BTreeMap<PBlockId, BlockData>,
+}
+
+impl MemBlockDevice {
+    /// Create a zero-filled device of `block_count` blocks.
+    pub fn new(block_count: u64) -> Self {
+        Self {
+            blocks: Mutex::new(BTreeMap::new()),
+            block_count,
+        }
+    }
+
+    /// Create a device holding a copy of a disk image. A trailing partial
+    /// block is zero-padded.
+    pub fn from_image(image: &[u8]) -> Self {
+        let mut blocks = BTreeMap::new();
+        for (i, chunk) in image.chunks(BLOCK_SIZE).enumerate() {
+            if chunk.iter().all(|&b| b == 0) {
+                continue;
+            }
+            let mut data = [0; BLOCK_SIZE];
+            data[..chunk.len()].copy_from_slice(chunk);
+            blocks.insert(i as PBlockId, Arc::new(data));
+        }
+        Self {
+            blocks: Mutex::new(blocks),
+            block_count: image.len().div_ceil(BLOCK_SIZE) as u64,
+        }
+    }
+
+    /// Copy the contents of the device into a disk image.
+    pub fn to_image(&self) -> Vec<u8> {
+        let mut image = vec![0; self.block_count as usize * BLOCK_SIZE];
+        for (&id, data) in self.blocks.lock().iter() {
+            let offset = id as usize * BLOCK_SIZE;
+            image[offset..offset + BLOCK_SIZE].copy_from_slice(data.as_ref());
+        }
+        image
+    }
+
+    /// Take a snapshot of the current contents.
+    pub fn checkpoint(&self) -> MemCheckpoint {
+        MemCheckpoint {
+            blocks: self.blocks.lock().clone(),
+        }
+    }
+
+    /// Revert the contents to a snapshot. Anything cached above the device,
+    /// e.g. an `Ext4` loaded on it, must be dropped and reloaded.
+    pub fn restore(&self, checkpoint: &MemCheckpoint) {
+        *self.blocks.lock() = checkpoint.blocks.clone();
+    }
+
+    /// The number of blocks holding data, i.e. written and not discarded.
+    pub fn used_blocks(&self) -> u64 {
+        self.blocks.lock().len() as u64
+    }
+}
+
+impl Clone for MemBlockDevice {
+    /// Create an independent device with the same contents.
+    fn clone(&self) -> Self {
+        Self {
+            blocks: Mutex::new(self.blocks.lock().clone()),
+            block_count: self.block_count,
+        }
+    }
+}
+
+impl BlockDevice for MemBlockDevice {
+    fn read_block(&self, block_id: PBlockId) -> Block {
+        let data = match self.blocks.lock().get(&block_id) {
+            Some(data) => **data,
+            None => [0; BLOCK_SIZE],
+        };
+        Block::new(block_id, data)
+    }
+
+    fn write_block(&self, block: &Block) {
+        if block.id >= self.block_count {
+            warn!(
+                "Write to block {} beyond device of {} blocks",
+                block.id, self.block_count
+            );
+            return;
+        }
+        self.blocks.lock().insert(block.id, Arc::new(block.data));
+    }
+
+    fn block_count(&self) -> Option<u64> {
+        Some(self.block_count)
+    }
+
+    fn discard(&self, start: PBlockId, count: u64) {
+        let mut blocks = self.blocks.lock();
+        let ids: Vec<PBlockId> = blocks
+            .range(start..start.saturating_add(count))
+            .map(|(&id, _)| id)
+            .collect();
+        for id in ids {
+            blocks.remove(&id);
+        }
+    }
+}
@@ -20,25 +20,53 @@ mod block_group;
 mod crc;
 mod dir;
 mod extent;
+mod handle;
 mod inode;
+mod io_vec;
+mod journal;
 mod mount_point;
+mod offset;
+mod sector;
 mod super_block;
 mod xattr;
 
 #[cfg(feature = "block_cache")]
 mod cache;
+#[cfg(feature = "casefold")]
+mod casefold;
+#[cfg(feature = "casefold")]
+mod casefold_tables;
+#[cfg(feature = "mem_device")]
+mod cow_device;
+#[cfg(all(feature = "std", unix))]
+mod file_device;
+#[cfg(feature = "mem_device")]
+mod mem_device;
 
 pub use bitmap::*;
 pub use block::*;
 pub use block_group::*;
 pub use dir::*;
 pub use extent::*;
+pub use handle::*;
 pub use inode::*;
+pub use journal::*;
+pub use offset::*;
+pub use sector::*;
 pub use super_block::*;
 pub use xattr::*;
 
+pub(crate) use crc::set_crc32c;
+pub(crate) use io_vec::{scatter, IoVec};
+
 #[cfg(feature = "block_cache")]
 pub use cache::*;
+#[cfg(feature = "mem_device")]
+pub use cow_device::*;
+#[cfg(all(feature = "std", unix))]
+pub use file_device::*;
+#[cfg(feature = "mem_device")]
+pub use mem_device::*;
 
 /// All file types. Also matches the defination in directory entries.
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -53,3 +81,20 @@ pub enum FileType {
     Socket,
     SymLink,
 }
+
+impl FileType {
+    /// Convert a file type code in directory entries to `FileType`.
+    /// Unknown codes are mapped to `FileType::Unknown`.
+    pub fn from_u8(code: u8) -> Self {
+        match code {
+            1 => FileType::RegularFile,
+            2 => FileType::Directory,
+            3 => FileType::CharacterDev,
+            4 => FileType::BlockDev,
+            5 => FileType::Fifo,
+            6 => FileType::Socket,
+            7 => FileType::SymLink,
+            _ => FileType::Unknown,
+        }
+    }
+}
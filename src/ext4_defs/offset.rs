@@ -0,0 +1,93 @@
+use crate::constants::*;
+use crate::format_error;
+use crate::prelude::*;
+use core::cmp::min;
+
+/// A byte offset in a file.
+///
+/// Offsets are kept as `u64` whatever the width of `usize`, and every
+/// conversion to a logical block or a buffer index is checked, so that
+/// offsets past 4 GiB cannot silently truncate on 32-bit targets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct BlockOffset(u64);
+
+impl BlockOffset {
+    pub const fn new(offset: u64) -> Self {
+        Self(offset)
+    }
+
+    /// The offset of the first byte of a logical block.
+    pub const fn of_block(iblock: LBlockId) -> Self {
+        Self(iblock as u64 * BLOCK_SIZE as u64)
+    }
+
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+
+    /// The logical block holding the byte at this offset.
+    ///
+    /// # Error
+    ///
+    /// `EFBIG` if the offset is past the last logical block.
+    pub fn iblock(self) -> Result<LBlockId> {
+        checked_lblock(self.0 / BLOCK_SIZE as u64)
+    }
+
+    /// The offset within its block.
+    pub const fn in_block(self) -> usize {
+        // Less than `BLOCK_SIZE`, fits in any `usize`
+        (self.0 % BLOCK_SIZE as u64) as usize
+    }
+
+    /// The offset `len` bytes further.
+    ///
+    /// # Error
+    ///
+    /// `EFBIG` if the result is past `MAX_FILE_SIZE`.
+    pub fn checked_add(self, len: usize) -> Result<Self> {
+        self.0
+            .checked_add(len as u64)
+            .filter(|&end| end <= MAX_FILE_SIZE)
+            .map(Self)
+            .ok_or_else(|| {
+                format_error!(
+                    ErrCode::EFBIG,
+                    "{} bytes at offset {} exceed the maximum file size",
+                    len,
+                    self.0
+                )
+            })
+    }
+
+    /// The number of bytes from this offset to `end`, at most `max`. 0 if
+    /// `end` is not past the offset.
+    pub fn len_to(self, end: u64, max: usize) -> usize {
+        // Bounded by `max`, so it fits in `usize`
+        min(end.saturating_sub(self.0), max as u64) as usize
+    }
+}
+
+impl From<u64> for BlockOffset {
+    fn from(offset: u64) -> Self {
+        Self(offset)
+    }
+}
+
+impl From<BlockOffset> for u64 {
+    fn from(offset: BlockOffset) -> Self {
+        offset.0
+    }
+}
+
+/// Convert a block count read from disk to a logical block id.
+///
+/// # Error
+///
+/// `EFBIG` if it is past the last logical block.
+pub fn checked_lblock(value: u64) -> Result<LBlockId> {
+    LBlockId::try_from(value)
+        .ok()
+        .filter(|&iblock| iblock < MAX_BLOCKS)
+        .ok_or_else(|| format_error!(ErrCode::EFBIG, "Block {} is past the last block", value))
+}
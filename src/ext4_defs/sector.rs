@@ -0,0 +1,136 @@
+use super::{Block, BlockDevice};
+use crate::constants::*;
+use crate::prelude::*;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Interface for devices addressed by sectors of a size other than
+/// `BLOCK_SIZE`, e.g. 512-byte disk sectors. Wrap it in a `SectorAdapter`
+/// to use it as a `BlockDevice`.
+pub trait SectorDevice: Send + Sync + Any {
+    /// The sector size in bytes, a power of two.
+    fn sector_size(&self) -> usize;
+    /// Read consecutive sectors starting from `start`. The length of `buf`
+    /// is a multiple of the sector size.
+    fn read_sectors(&self, start: u64, buf: &mut [u8]);
+    /// Write consecutive sectors starting from `start`. The length of `buf`
+    /// is a multiple of the sector size.
+    fn write_sectors(&self, start: u64, buf: &[u8]);
+    /// The number of sectors on the device, `None` if unknown.
+    fn sector_count(&self) -> Option<u64> {
+        None
+    }
+    /// Wait until all completed writes are on stable storage, see
+    /// `BlockDevice::flush`. Does nothing by default.
+    fn flush(&self) {}
+}
+
+/// Adapter that presents a `SectorDevice` as a `BlockDevice`.
+///
+/// Sectors smaller than a block are aggregated into blocks. Sectors larger
+/// than a block are accessed with read-modify-write, serialized so that
+/// concurrent writes to blocks sharing a sector are not lost.
+pub struct SectorAdapter<D: SectorDevice> {
+    device: D,
+    /// Sector size in bytes
+    sector_size: usize,
+    /// First sector of the filesystem, e.g. the start of a partition
+    start: u64,
+    /// Lock for read-modify-write of large sectors
+    rmw_lock: AtomicBool,
+}
+
+impl<D: SectorDevice> SectorAdapter<D> {
+    /// Create an adapter for a filesystem that starts at sector 0.
+    pub fn new(device: D) -> Self {
+        Self::with_offset(device, 0)
+    }
+
+    /// Create an adapter for a filesystem that starts at sector `start`,
+    /// e.g. on a partition.
+    ///
+    /// Panics if the sector size is not a power of two.
+    pub fn with_offset(device: D, start: u64) -> Self {
+        let sector_size = device.sector_size();
+        assert!(
+            sector_size.is_power_of_two(),
+            "Invalid sector size {}",
+            sector_size
+        );
+        Self {
+            device,
+            sector_size,
+            start,
+            rmw_lock: AtomicBool::new(false),
+        }
+    }
+
+    /// Get the underlying sector device.
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    /// Read the sector containing block `block_id` and run `f` on it, with
+    /// the sector id and the offset of the block in the sector. Only used
+    /// when sectors are larger than blocks.
+    fn with_sector<T>(&self, block_id: PBlockId, f: impl FnOnce(u64, &mut [u8], usize) -> T) -> T {
+        let byte = block_id * BLOCK_SIZE as u64;
+        let sector = self.start + byte / self.sector_size as u64;
+        let offset = (byte % self.sector_size as u64) as usize;
+        while self
+            .rmw_lock
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        let mut buf = vec![0; self.sector_size];
+        self.device.read_sectors(sector, &mut buf);
+        let res = f(sector, &mut buf, offset);
+        self.rmw_lock.store(false, Ordering::Release);
+        res
+    }
+
+    /// The first sector of block `block_id`. Only used when sectors are not
+    /// larger than blocks.
+    fn block_sector(&self, block_id: PBlockId) -> u64 {
+        self.start + block_id * (BLOCK_SIZE / self.sector_size) as u64
+    }
+}
+
+impl<D: SectorDevice> BlockDevice for SectorAdapter<D> {
+    fn read_block(&self, block_id: PBlockId) -> Block {
+        let mut block = Block::new(block_id, [0; BLOCK_SIZE]);
+        if self.sector_size <= BLOCK_SIZE {
+            self.device
+                .read_sectors(self.block_sector(block_id), &mut block.data);
+        } else {
+            self.with_sector(block_id, |_, buf, offset| {
+                block
+                    .data
+                    .copy_from_slice(&buf[offset..offset + BLOCK_SIZE])
+            });
+        }
+        block
+    }
+
+    fn write_block(&self, block: &Block) {
+        if self.sector_size <= BLOCK_SIZE {
+            self.device
+                .write_sectors(self.block_sector(block.id), &block.data);
+        } else {
+            self.with_sector(block.id, |sector, buf, offset| {
+                buf[offset..offset + BLOCK_SIZE].copy_from_slice(&block.data);
+                self.device.write_sectors(sector, buf);
+            });
+        }
+    }
+
+    fn block_count(&self) -> Option<u64> {
+        let sectors = self.device.sector_count()?.saturating_sub(self.start);
+        Some(sectors * self.sector_size as u64 / BLOCK_SIZE as u64)
+    }
+
+    fn flush(&self) {
+        self.device.flush();
+    }
+}
@@ -2,9 +2,75 @@
 //!
 //! See [`super::block_group`] for details.
 
+use super::crc::*;
 use super::AsBytes;
+use super::Inode;
+use crate::constants::{BLOCK_SIZE, CRC32_INIT, SB_MIN_DESC_SIZE};
 use crate::prelude::*;
 
+bitflags! {
+    /// Compatible feature set. The filesystem can be mounted even if
+    /// these features are not understood.
+    #[derive(PartialEq, Eq, Debug, Clone, Copy)]
+    pub struct FeatureCompat: u32 {
+        const DIR_PREALLOC = 0x1;
+        const IMAGIC_INODES = 0x2;
+        const HAS_JOURNAL = 0x4;
+        const EXT_ATTR = 0x8;
+        const RESIZE_INODE = 0x10;
+        const DIR_INDEX = 0x20;
+        const SPARSE_SUPER2 = 0x200;
+        const FAST_COMMIT = 0x400;
+        const STABLE_INODES = 0x800;
+        const ORPHAN_FILE = 0x1000;
+    }
+
+    /// Incompatible feature set. The filesystem should not be mounted
+    /// if any of these features is not understood.
+    #[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+    pub struct FeatureIncompat: u32 {
+        const COMPRESSION = 0x1;
+        const FILETYPE = 0x2;
+        const RECOVER = 0x4;
+        const JOURNAL_DEV = 0x8;
+        const META_BG = 0x10;
+        const EXTENTS = 0x40;
+        const BIT64 = 0x80;
+        const MMP = 0x100;
+        const FLEX_BG = 0x200;
+        const EA_INODE = 0x400;
+        const DIRDATA = 0x1000;
+        const CSUM_SEED = 0x2000;
+        const LARGEDIR = 0x4000;
+        const INLINE_DATA = 0x8000;
+        const ENCRYPT = 0x10000;
+        const CASEFOLD = 0x20000;
+    }
+
+    /// Readonly-compatible feature set. The filesystem can be mounted
+    /// readonly if any of these features is not understood.
+    #[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+    pub struct FeatureRoCompat: u32 {
+        const SPARSE_SUPER = 0x1;
+        const LARGE_FILE = 0x2;
+        const BTREE_DIR = 0x4;
+        const HUGE_FILE = 0x8;
+        const GDT_CSUM = 0x10;
+        const DIR_NLINK = 0x20;
+        const EXTRA_ISIZE = 0x40;
+        const HAS_SNAPSHOT = 0x80;
+        const QUOTA = 0x100;
+        const BIGALLOC = 0x200;
+        const METADATA_CSUM = 0x400;
+        const REPLICA = 0x800;
+        const READONLY = 0x1000;
+        const PROJECT = 0x2000;
+        const SHARED_BLOCKS = 0x4000;
+        const VERITY = 0x8000;
+        const ORPHAN_PRESENT = 0x10000;
+    }
+}
+
 // 结构体表示超级块
 #[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -16,9 +82,9 @@ pub struct SuperBlock {
     free_inode_count: u32,        // 空闲节点数
     first_data_block: u32,        // 第一个数据块
     log_block_size: u32,          // Block size is 2 ^ (10 + s_log_block_size).
-    log_cluster_size: u32,        // 废弃的片段大小
+    log_cluster_size: u32,        // Cluster size is 2 ^ (10 + s_log_cluster_size) with bigalloc.
     blocks_per_group: u32,        // 每组块数
-    frags_per_group: u32,         // 废弃的每组片段数
+    clusters_per_group: u32,      // 每组簇数 (bigalloc)
     inodes_per_group: u32,        // 每组节点数
     mount_time: u32,              // 挂载时间
     write_time: u32,              // 写入时间
@@ -104,7 +170,20 @@ pub struct SuperBlock {
     encrypt_algos: [u8; 4],    // 使用的加密算法
     encrypt_pw_salt: [u8; 16], // 用于string2key算法的盐
     lpf_ino: u32,              // lost+found节点的位置
-    padding: [u32; 100],       // 块的末尾的填充
+    prj_quota_inum: u32,       // 用于跟踪项目配额的节点
+    checksum_seed: u32,        // crc32c(uuid)，用于元数据校验和
+    wtime_hi: u8,              // 写入时间的高8位
+    mtime_hi: u8,              // 挂载时间的高8位
+    mkfs_time_hi: u8,          // 文件系统创建时间的高8位
+    lastcheck_hi: u8,          // 最后检查时间的高8位
+    first_error_time_hi: u8,   // 第一次发生错误时间的高8位
+    last_error_time_hi: u8,    // 最近一次发生错误时间的高8位
+    first_error_errcode: u8,   // 第一次发生错误的错误码
+    last_error_errcode: u8,    // 最近一次发生错误的错误码
+    encoding: u16,             // 文件名字符集编码
+    encoding_flags: u16,       // 文件名字符集编码标志
+    orphan_file_inum: u32,     // 孤儿文件的节点号
+    padding: [u32; 94],        // 块的末尾的填充
     checksum: u32,             // crc32c(superblock)
 }
 
@@ -112,6 +191,13 @@ unsafe impl AsBytes for SuperBlock {}
 
 impl SuperBlock {
     const SB_MAGIC: u16 = 0xEF53;
+    /// `s_state`: the filesystem was cleanly unmounted.
+    pub const STATE_VALID: u16 = 0x1;
+    /// `s_state`: errors were detected.
+    pub const STATE_ERROR: u16 = 0x2;
+    /// The only filename encoding defined by ext4, UTF-8 12.1.
+    #[allow(unused)]
+    pub const ENCODING_UTF8_12_1: u16 = 1;
 
     pub fn check_magic(&self) -> bool {
         self.magic == Self::SB_MAGIC
@@ -125,27 +211,268 @@ impl SuperBlock {
         self.free_inode_count
     }
 
+    #[allow(unused)]
     pub fn uuid(&self) -> [u8; 16] {
         self.uuid
     }
 
+    /// The volume label, without trailing NUL bytes.
+    pub fn volume_name(&self) -> &[u8] {
+        let len = self
+            .volume_name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(self.volume_name.len());
+        &self.volume_name[..len]
+    }
+
+    /// The seed of metadata checksums.
+    ///
+    /// With the `csum_seed` feature the seed is stored in the superblock, so
+    /// the UUID can be changed without rewriting all checksums. Otherwise it
+    /// is `crc32c(~0, uuid)`, which also holds for an all-zero UUID.
+    pub fn checksum_seed(&self) -> u32 {
+        if self
+            .features_incompatible()
+            .contains(FeatureIncompat::CSUM_SEED)
+        {
+            self.checksum_seed
+        } else {
+            crc32(CRC32_INIT, &self.uuid)
+        }
+    }
+
+    /// The inode of the journal, 0 if the filesystem has no internal
+    /// journal.
+    pub fn journal_inode(&self) -> InodeId {
+        if self
+            .features_compatible()
+            .contains(FeatureCompat::HAS_JOURNAL)
+        {
+            self.journal_inode_number
+        } else {
+            0
+        }
+    }
+
+    /// Whether the journal must be replayed before the filesystem is used.
+    pub fn needs_recovery(&self) -> bool {
+        self.features_incompatible()
+            .contains(FeatureIncompat::RECOVER)
+    }
+
+    /// Set whether the journal must be replayed before the filesystem is
+    /// used.
+    pub fn set_needs_recovery(&mut self, recover: bool) {
+        let mut incompat = self.features_incompatible();
+        incompat.set(FeatureIncompat::RECOVER, recover);
+        self.features_incompatible = incompat.bits();
+    }
+
+    /// The compatible feature set.
+    pub fn features_compatible(&self) -> FeatureCompat {
+        FeatureCompat::from_bits_retain(self.features_compatible)
+    }
+
+    /// The incompatible feature set.
+    pub fn features_incompatible(&self) -> FeatureIncompat {
+        FeatureIncompat::from_bits_retain(self.features_incompatible)
+    }
+
+    /// The readonly-compatible feature set.
+    pub fn features_read_only(&self) -> FeatureRoCompat {
+        FeatureRoCompat::from_bits_retain(self.features_read_only)
+    }
+
+    /// Add readonly-compatible features, e.g. `LARGE_FILE` once a file
+    /// grows past 2 GiB.
+    pub fn add_features_read_only(&mut self, features: FeatureRoCompat) {
+        self.features_read_only |= features.bits();
+    }
+
+    /// The inodes of the user, group and project quota files, in that
+    /// order. 0 if the quota type is not tracked.
+    pub fn quota_inodes(&self) -> [InodeId; 3] {
+        [
+            self.usr_quota_inum,
+            self.grp_quota_inum,
+            self.prj_quota_inum,
+        ]
+    }
+
+    /// Whether metadata blocks carry checksums (`metadata_csum` feature).
+    pub fn has_metadata_csum(&self) -> bool {
+        self.features_read_only()
+            .contains(FeatureRoCompat::METADATA_CSUM)
+    }
+
+    /// Whether block addresses may be wider than 32 bits (`64bit` feature).
+    pub fn is_64bit(&self) -> bool {
+        self.features_incompatible()
+            .contains(FeatureIncompat::BIT64)
+    }
+
+    /// Whether new inodes map their blocks with extent trees (`extents`
+    /// feature). ext2 and ext3 images use indirect blocks instead.
+    pub fn has_extents(&self) -> bool {
+        self.features_incompatible()
+            .contains(FeatureIncompat::EXTENTS)
+    }
+
+    /// Whether blocks are allocated in clusters of several blocks
+    /// (`bigalloc` feature).
+    pub fn has_bigalloc(&self) -> bool {
+        self.features_read_only()
+            .contains(FeatureRoCompat::BIGALLOC)
+    }
+
+    /// The number of blocks in an allocation cluster, 1 without `bigalloc`.
+    /// Block bitmaps and group free counts are in clusters.
+    pub fn cluster_ratio(&self) -> u32 {
+        if self.has_bigalloc() {
+            1 << self
+                .log_cluster_size
+                .saturating_sub(self.log_block_size)
+                .min(31)
+        } else {
+            1
+        }
+    }
+
+    /// The number of clusters in each block group, the bits used in a block
+    /// bitmap. The same as `blocks_per_group` without `bigalloc`.
+    pub fn clusters_per_group(&self) -> u32 {
+        if self.has_bigalloc() {
+            self.clusters_per_group
+        } else {
+            self.blocks_per_group
+        }
+    }
+
+    /// The number of clusters in a block group. The last group may be
+    /// smaller than `clusters_per_group`.
+    pub fn clusters_in_group(&self, bgid: BlockGroupId) -> u32 {
+        self.blocks_in_group(bgid).div_ceil(self.cluster_ratio())
+    }
+
+    /// Whether directory entries record the file type (`filetype` feature).
+    pub fn has_filetype(&self) -> bool {
+        self.features_incompatible()
+            .contains(FeatureIncompat::FILETYPE)
+    }
+
+    /// The filename charset encoding, valid if the casefold feature is set.
+    #[allow(unused)]
+    pub fn encoding(&self) -> u16 {
+        self.encoding
+    }
+
+    /// The filename charset encoding flags.
+    #[allow(unused)]
+    pub fn encoding_flags(&self) -> u16 {
+        self.encoding_flags
+    }
+
     /// Total number of inodes.
     #[allow(unused)]
     pub fn inode_count(&self) -> u32 {
         self.inode_count
     }
 
+    /// The first inode that is not reserved for special use.
+    pub fn first_inode(&self) -> InodeId {
+        self.first_inode
+    }
+
+    /// The block size in bytes, 0 if it does not fit in 32 bits.
+    pub fn block_size(&self) -> u32 {
+        1024u32
+            .checked_shl(self.log_block_size)
+            .filter(|size| size >> self.log_block_size == 1024)
+            .unwrap_or(0)
+    }
+
     /// Total number of blocks.
     pub fn block_count(&self) -> u64 {
-        self.block_count_lo as u64 | ((self.block_count_hi as u64) << 32)
+        if self.is_64bit() {
+            self.block_count_lo as u64 | ((self.block_count_hi as u64) << 32)
+        } else {
+            self.block_count_lo as u64
+        }
     }
 
     /// The number of blocks in each block group.
-    #[allow(unused)]
     pub fn blocks_per_group(&self) -> u32 {
         self.blocks_per_group
     }
 
+    /// The number of block groups in a flex group, 1 if `flex_bg` is
+    /// disabled.
+    pub fn groups_per_flex(&self) -> u32 {
+        if self
+            .features_incompatible()
+            .contains(FeatureIncompat::FLEX_BG)
+        {
+            1 << self.log_groups_per_flex.min(31)
+        } else {
+            1
+        }
+    }
+
+    /// The first block of a block group.
+    pub fn group_first_block(&self, bgid: BlockGroupId) -> PBlockId {
+        self.first_data_block as PBlockId + bgid as PBlockId * self.blocks_per_group as PBlockId
+    }
+
+    /// The number of blocks in a block group. The last group may be
+    /// smaller than `blocks_per_group`.
+    pub fn blocks_in_group(&self, bgid: BlockGroupId) -> u32 {
+        let remaining = self.block_count() - self.group_first_block(bgid);
+        remaining.min(self.blocks_per_group as u64) as u32
+    }
+
+    /// Whether a block group holds a backup of the superblock and the
+    /// group descriptors.
+    pub fn group_has_super(&self, bgid: BlockGroupId) -> bool {
+        if bgid == 0 {
+            return true;
+        }
+        if self
+            .features_compatible()
+            .contains(FeatureCompat::SPARSE_SUPER2)
+        {
+            return self.backup_bgs.contains(&bgid);
+        }
+        if !self
+            .features_read_only()
+            .contains(FeatureRoCompat::SPARSE_SUPER)
+        {
+            return true;
+        }
+        // Groups 0, 1 and powers of 3, 5 and 7
+        let is_power_of = |base: u32| {
+            let mut n = base;
+            while n < bgid {
+                n = match n.checked_mul(base) {
+                    Some(n) => n,
+                    None => return false,
+                };
+            }
+            n == bgid
+        };
+        bgid == 1 || is_power_of(3) || is_power_of(5) || is_power_of(7)
+    }
+
+    /// The number of blocks used by the group descriptor table.
+    pub fn gdt_block_count(&self) -> u32 {
+        (self.block_group_count() as usize * self.desc_size()).div_ceil(BLOCK_SIZE) as u32
+    }
+
+    /// The number of blocks reserved for group descriptor table growth.
+    pub fn reserved_gdt_blocks(&self) -> u32 {
+        self.s_reserved_gdt_blocks as u32
+    }
+
     /// The number of inodes in each block group.
     pub fn inodes_per_group(&self) -> u32 {
         self.inodes_per_group
@@ -162,14 +489,28 @@ impl SuperBlock {
         self.inode_size as usize
     }
 
-    /// The size of block group descriptor.
+    /// The number of blocks of the inode table in each block group.
+    pub fn inode_table_blocks(&self) -> u32 {
+        (self.inodes_per_group as usize * self.inode_size()).div_ceil(BLOCK_SIZE) as u32
+    }
+
+    /// The size of block group descriptor. Without the `64bit` feature
+    /// the descriptors have the 32-byte ext2 layout, whatever the field.
     pub fn desc_size(&self) -> usize {
-        self.desc_size as usize
+        if self.is_64bit() {
+            self.desc_size as usize
+        } else {
+            SB_MIN_DESC_SIZE
+        }
     }
 
-    #[allow(unused)]
-    pub fn extra_size(&self) -> u16 {
-        self.want_extra_isize
+    /// The size of the extended fields of new inodes, past the original
+    /// 128-byte inode. All the fields `Inode` knows of when unset.
+    pub fn want_extra_isize(&self) -> u16 {
+        match self.want_extra_isize {
+            0 => (size_of::<Inode>() - 128) as u16,
+            size => size,
+        }
     }
 
     pub fn inode_count_in_group(&self, bgid: u32) -> u32 {
@@ -182,6 +523,37 @@ impl SuperBlock {
         }
     }
 
+    /// The last write time, in seconds since the epoch.
+    pub fn write_time(&self) -> u64 {
+        self.write_time as u64 | ((self.wtime_hi as u64) << 32)
+    }
+
+    /// Set the last write time, in seconds since the epoch.
+    pub fn set_write_time(&mut self, time: u64) {
+        self.write_time = time as u32;
+        self.wtime_hi = (time >> 32) as u8;
+    }
+
+    /// The number of kilobytes written over the lifetime of the filesystem.
+    pub fn kbytes_written(&self) -> u64 {
+        self.kbytes_written
+    }
+
+    /// Set the number of kilobytes written over the lifetime of the filesystem.
+    pub fn set_kbytes_written(&mut self, kbytes: u64) {
+        self.kbytes_written = kbytes;
+    }
+
+    /// The head of the orphan inode list.
+    pub fn last_orphan(&self) -> InodeId {
+        self.last_orphan
+    }
+
+    /// Set the head of the orphan inode list.
+    pub fn set_last_orphan(&mut self, ino: InodeId) {
+        self.last_orphan = ino;
+    }
+
     pub fn set_free_inodes_count(&mut self, count: u32) {
         self.free_inode_count = count;
     }
@@ -190,8 +562,122 @@ impl SuperBlock {
         self.free_block_count_lo as u64 | ((self.free_blocks_count_hi as u64) << 32).to_le()
     }
 
+    /// The number of blocks reserved for privileged users.
+    pub fn reserved_blocks_count(&self) -> u64 {
+        self.reserved_block_count_lo as u64 | ((self.reserved_blocks_count_hi as u64) << 32)
+    }
+
+    /// Set the number of blocks reserved for privileged users.
+    pub fn set_reserved_blocks_count(&mut self, count: u64) {
+        self.reserved_block_count_lo = count as u32;
+        self.reserved_blocks_count_hi = (count >> 32) as u32;
+    }
+
+    /// The default user id that may use reserved blocks.
+    #[allow(unused)]
+    pub fn reserved_uid(&self) -> u16 {
+        self.def_resuid
+    }
+
+    /// The default group id that may use reserved blocks.
+    #[allow(unused)]
+    pub fn reserved_gid(&self) -> u16 {
+        self.def_resgid
+    }
+
     pub fn set_free_blocks_count(&mut self, free_blocks: u64) {
         self.free_block_count_lo = ((free_blocks << 32) >> 32).to_le() as u32;
         self.free_blocks_count_hi = (free_blocks >> 32) as u32;
     }
+
+    /// The number of mounts since the last check.
+    pub fn mount_count(&self) -> u16 {
+        self.mount_count
+    }
+
+    /// Set the number of mounts since the last check.
+    pub fn set_mount_count(&mut self, count: u16) {
+        self.mount_count = count;
+    }
+
+    /// The number of mounts after which a check is due, -1 if disabled.
+    #[allow(unused)]
+    pub fn max_mount_count(&self) -> i16 {
+        self.max_mount_count as i16
+    }
+
+    /// The last mount time, in seconds since the epoch.
+    pub fn mount_time(&self) -> u64 {
+        self.mount_time as u64 | ((self.mtime_hi as u64) << 32)
+    }
+
+    /// Set the last mount time, in seconds since the epoch.
+    pub fn set_mount_time(&mut self, time: u64) {
+        self.mount_time = time as u32;
+        self.mtime_hi = (time >> 32) as u8;
+    }
+
+    /// The behaviour when errors are detected (`s_errors`): 1 to continue,
+    /// 2 to remount read-only, 3 to panic.
+    pub fn errors(&self) -> u16 {
+        self.errors
+    }
+
+    /// The number of errors recorded.
+    pub fn error_count(&self) -> u32 {
+        self.error_count
+    }
+
+    /// Record an error detected in function `func`, on inode `ino` and
+    /// block `block` (0 if unknown). The first error is kept, the last one
+    /// is overwritten, and the error flag is set in the state.
+    pub fn record_error(
+        &mut self,
+        time: u64,
+        func: &str,
+        ino: InodeId,
+        block: PBlockId,
+        errcode: u8,
+    ) {
+        let mut func_bytes = [0; 32];
+        let len = func.len().min(func_bytes.len());
+        func_bytes[..len].copy_from_slice(&func.as_bytes()[..len]);
+        if self.error_count == 0 {
+            self.first_error_time = time as u32;
+            self.first_error_time_hi = (time >> 32) as u8;
+            self.first_error_ino = ino;
+            self.first_error_block = block;
+            self.first_error_func = func_bytes;
+            self.first_error_line = 0;
+            self.first_error_errcode = errcode;
+        }
+        self.last_error_time = time as u32;
+        self.last_error_time_hi = (time >> 32) as u8;
+        self.last_error_ino = ino;
+        self.last_error_block = block;
+        self.last_error_func = func_bytes;
+        self.last_error_line = 0;
+        self.last_error_errcode = errcode;
+        self.error_count = self.error_count.saturating_add(1);
+        self.state |= Self::STATE_ERROR;
+    }
+
+    /// The filesystem state flags, see `STATE_VALID` and `STATE_ERROR`.
+    pub fn state(&self) -> u16 {
+        self.state
+    }
+
+    /// Set the filesystem state flags.
+    pub fn set_state(&mut self, state: u16) {
+        self.state = state;
+    }
+
+    /// Set the superblock checksum if `metadata_csum` is enabled. It
+    /// covers everything before the checksum field.
+    pub fn set_checksum(&mut self) {
+        if self.has_metadata_csum() {
+            let len = size_of::<SuperBlock>() - size_of::<u32>();
+            self.checksum = crc32(CRC32_INIT, &self.to_bytes()[..len]);
+        }
+    }
 }
@@ -7,7 +7,10 @@
 //! pointed to by `inode.file_acl`.
 //!
 //! We only implement the seperate data block storage of extended attributes.
+//! Attributes stored in the inode can be read, see `find_ibody_xattr` and
+//! `list_ibody_xattrs`.
 
+use super::crc::*;
 use super::{AsBytes, Block};
 use crate::constants::*;
 use crate::prelude::*;
@@ -23,7 +26,7 @@ pub struct XattrHeader {
     refcount: u32,
     /// Number of disk blocks used.
     blocks: u32,
-    /// Hash value of all attributes. (UNUSED by now)
+    /// Hash value of all attributes.
     hash: u32,
     /// Checksum of the extended attribute block.
     checksum: u32,
@@ -71,7 +74,7 @@ pub struct XattrEntry {
     value_inum: u32,
     /// Length of attribute value.
     value_size: u32,
-    /// Hash value of attribute name and attribute value (UNUSED by now)
+    /// Hash value of attribute name and attribute value
     hash: u32,
     /// Attribute name, max 255 bytes.
     name: [u8; 255],
@@ -110,6 +113,8 @@ unsafe impl AsBytes for XattrEntry {
     }
     fn to_bytes(&self) -> &[u8] {
         let name_len = self.name_len as usize;
+        // SAFETY: `name` directly follows the fake entry fields in `repr(C)`
+        // layout, and `name_len` is at most the length of `name`.
         unsafe {
             core::slice::from_raw_parts(
                 self as *const Self as *const u8,
@@ -149,7 +154,7 @@ impl XattrEntry {
             _ => "",
         };
         let name_bytes = &self.name[..self.name_len as usize];
-        let name = unsafe { String::from_utf8_unchecked(name_bytes.to_vec()) };
+        let name = String::from_utf8_lossy(name_bytes);
         prefix.to_string() + &name
     }
 
@@ -161,6 +166,27 @@ impl XattrEntry {
         (core::mem::size_of::<FakeXattrEntry>() + name_len + 3) / 4 * 4
     }
 
+    /// Get the size a value takes in the block, 4-byte aligned
+    pub fn value_required_size(value_size: usize) -> usize {
+        value_size.next_multiple_of(4)
+    }
+
+    /// Compute the hash of the entry from its name and `value`, as the
+    /// kernel does.
+    fn calc_hash(&self, value: &[u8]) -> u32 {
+        let mut hash: u32 = 0;
+        for &b in &self.name[..self.name_len as usize] {
+            hash = (hash << 5) ^ (hash >> 27) ^ b as u32;
+        }
+        // The value is hashed as little-endian words, padded with zeros
+        for chunk in value.chunks(4) {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            hash = (hash << 16) ^ (hash >> 16) ^ u32::from_le_bytes(word);
+        }
+        hash
+    }
+
     /// Get the used size of this xattr entry, 4-bytes alighed
     pub fn used_size(&self) -> usize {
         (core::mem::size_of::<FakeXattrEntry>() + self.name_len as usize + 3) / 4 * 4
@@ -182,7 +208,7 @@ impl XattrEntry {
 
     /// Match the attribute name prefix to get name index. If one is found,
     /// return the name index and the string with the prefix removed.
-    fn match_name(name: &str) -> (u8, &str) {
+    pub fn match_name(name: &str) -> (u8, &str) {
         let prefixes = [
             ("user.", 1),
             ("system.posix_acl_access.", 2),
@@ -228,6 +254,38 @@ impl XattrBlock {
         self.0.write_offset_as(0, &header);
     }
 
+    /// Check that the block is well formed: it has the xattr magic, and the
+    /// entries and the values they point to lie in the block, the values
+    /// past the entry table. The other methods may panic on a block that is
+    /// not.
+    pub fn is_valid(&self) -> bool {
+        let header: XattrHeader = self.0.read_offset_as(0);
+        if header.magic != XattrHeader::XATTR_MAGIC {
+            return false;
+        }
+        let mut entry_start = size_of::<XattrHeader>();
+        // Start of the first value
+        let mut values_start = BLOCK_SIZE;
+        // `name_len` 0 indicates the end of the entry table
+        while entry_start < BLOCK_SIZE && self.0.data[entry_start] != 0 {
+            if entry_start + size_of::<FakeXattrEntry>() > BLOCK_SIZE {
+                return false;
+            }
+            let entry: FakeXattrEntry = self.0.read_offset_as(entry_start);
+            entry_start +=
+                (size_of::<FakeXattrEntry>() + entry.name_len as usize).next_multiple_of(4);
+            // Values in a separate inode (`ea_inode`) are not in the block
+            if entry.value_inum == 0 {
+                let offset = entry.value_offset as usize;
+                if offset + entry.value_size as usize > BLOCK_SIZE {
+                    return false;
+                }
+                values_start = values_start.min(offset);
+            }
+        }
+        entry_start <= values_start
+    }
+
     /// Get a xattr by name, return the value.
     pub fn get(&self, name: &str) -> Option<&[u8]> {
         let mut entry_start = size_of::<XattrHeader>();
@@ -239,7 +297,8 @@ impl XattrBlock {
                 break;
             }
             let entry: XattrEntry = self.0.read_offset_as(entry_start);
-            if entry.compare_name(name).is_eq() {
+            // Values in a separate inode (`ea_inode`) are not supported
+            if entry.compare_name(name).is_eq() && entry.value_inum == 0 {
                 let offset = entry.value_offset as usize;
                 let size = entry.value_size as usize;
                 return Some(&self.0.data[offset..offset + size]);
@@ -249,6 +308,12 @@ impl XattrBlock {
         None
     }
 
+    /// Get a xattr by name index and name without prefix, for the indexes
+    /// that have no name prefix.
+    pub fn get_raw(&self, name_index: u8, name: &[u8]) -> Option<&[u8]> {
+        find_raw(&self.0.data, size_of::<XattrHeader>(), 0, name_index, name)
+    }
+
     /// List all xattr names
     pub fn list(&self) -> Vec<String> {
         let mut entry_start = size_of::<XattrHeader>();
@@ -275,7 +340,7 @@ impl XattrBlock {
         let mut ins_entry_pos = p_entry;
         let mut ins_value_pos = p_value;
         let ins_entry_size = XattrEntry::required_size(name);
-        let ins_value_size = value.len();
+        let ins_value_size = XattrEntry::value_required_size(value.len());
 
         // Iterate over entry table, find the position to insert entry
         // and the end of entry table
@@ -339,11 +404,14 @@ impl XattrBlock {
         }
 
         // Insert entry to `[ins_entry_pos, ins_entry_pos+ins_entry_size)`
-        let entry = XattrEntry::new(name, value.len(), ins_value_pos - ins_value_size);
+        let mut entry = XattrEntry::new(name, value.len(), ins_value_pos - ins_value_size);
+        entry.hash = entry.calc_hash(value);
         self.0.write_offset_as(ins_entry_pos, &entry);
-        // Insert value to `[ins_value_pos-ins_value_size, ins_value_pos)`
+        // Insert value to `[ins_value_pos-ins_value_size, ins_value_pos)`,
+        // the padding is already zeroed
         self.0.write_offset(ins_value_pos - ins_value_size, value);
 
+        self.rehash();
         true
     }
 
@@ -371,7 +439,7 @@ impl XattrBlock {
                 rem_entry_pos = p_entry;
                 rem_value_pos = p_value;
                 rem_entry_size = entry.used_size();
-                rem_value_size = entry.value_size as usize;
+                rem_value_size = XattrEntry::value_required_size(entry.value_size as usize);
                 is_rem_pos_found = true;
             }
             p_entry += entry.used_size();
@@ -412,6 +480,109 @@ impl XattrBlock {
             p_entry2 += entry.used_size();
         }
 
+        self.rehash();
         true
     }
+
+    /// Update the hash of the block from the hashes of its entries, 0 if
+    /// any entry has no hash.
+    fn rehash(&mut self) {
+        let mut hash: u32 = 0;
+        let mut entry_start = size_of::<XattrHeader>();
+        while entry_start < BLOCK_SIZE && self.0.data[entry_start] != 0 {
+            let entry: FakeXattrEntry = self.0.read_offset_as(entry_start);
+            if entry.hash == 0 {
+                hash = 0;
+                break;
+            }
+            hash = (hash << 16) ^ (hash >> 16) ^ entry.hash;
+            entry_start +=
+                (size_of::<FakeXattrEntry>() + entry.name_len as usize).next_multiple_of(4);
+        }
+        let mut header: XattrHeader = self.0.read_offset_as(0);
+        header.hash = hash;
+        self.0.write_offset_as(0, &header);
+    }
+
+    /// Set the checksum of the block, computed over its block number and
+    /// content.
+    pub fn set_checksum(&mut self, csum_seed: u32) {
+        let mut header: XattrHeader = self.0.read_offset_as(0);
+        header.checksum = 0;
+        self.0.write_offset_as(0, &header);
+        let csum = crc32(csum_seed, &self.0.id.to_le_bytes());
+        header.checksum = crc32(csum, &self.0.data);
+        self.0.write_offset_as(0, &header);
+    }
+}
+
+/// Find a xattr by name index and name without prefix among the extended
+/// attributes stored in an on-disk inode `record`, past the `extra_isize`
+/// bytes of extended fields.
+pub fn find_ibody_xattr<'a>(
+    record: &'a [u8],
+    extra_isize: usize,
+    name_index: u8,
+    name: &[u8],
+) -> Option<&'a [u8]> {
+    let start = ibody_table_start(record, extra_isize)?;
+    // Values are placed relative to the first entry
+    find_raw(record, start, start, name_index, name)
+}
+
+/// List the names of the extended attributes stored in an on-disk inode
+/// `record`, past the `extra_isize` bytes of extended fields. A malformed
+/// table is listed up to the first malformed entry.
+pub fn list_ibody_xattrs(record: &[u8], extra_isize: usize) -> Vec<String> {
+    let mut names = Vec::new();
+    let Some(mut entry_start) = ibody_table_start(record, extra_isize) else {
+        return names;
+    };
+    // `name_len` 0 indicates the end of the entry table
+    while record.len() >= entry_start + size_of::<FakeXattrEntry>() && record[entry_start] != 0 {
+        let name_end = entry_start + size_of::<FakeXattrEntry>() + record[entry_start] as usize;
+        if name_end > record.len() {
+            break;
+        }
+        let entry = XattrEntry::from_bytes(&record[entry_start..]);
+        names.push(entry.name());
+        entry_start += entry.used_size();
+    }
+    names
+}
+
+/// Get the offset of the entry table in an on-disk inode `record`, `None`
+/// if the inode stores no extended attribute.
+fn ibody_table_start(record: &[u8], extra_isize: usize) -> Option<usize> {
+    let start = 128 + extra_isize;
+    let magic = record.get(start..start + 4)?;
+    if u32::from_le_bytes(magic.try_into().unwrap()) != XattrHeader::XATTR_MAGIC {
+        return None;
+    }
+    Some(start + 4)
+}
+
+/// Find a xattr in the entry table at `data[start..]`, whose values are
+/// placed relative to `data[base..]`. A malformed table finds nothing.
+fn find_raw<'a>(
+    data: &'a [u8],
+    start: usize,
+    base: usize,
+    name_index: u8,
+    name: &[u8],
+) -> Option<&'a [u8]> {
+    let mut entry_start = start;
+    // `name_len` 0 indicates the end of the entry table
+    while data.len() >= entry_start + size_of::<FakeXattrEntry>() && data[entry_start] != 0 {
+        let entry = FakeXattrEntry::from_bytes(&data[entry_start..]);
+        let name_start = entry_start + size_of::<FakeXattrEntry>();
+        let entry_name = data.get(name_start..name_start + entry.name_len as usize)?;
+        // Values in a separate inode (`ea_inode`) are not supported
+        if entry.name_index == name_index && entry_name == name && entry.value_inum == 0 {
+            let offset = base + entry.value_offset as usize;
+            return data.get(offset..offset + entry.value_size as usize);
+        }
+        entry_start += (size_of::<FakeXattrEntry>() + entry.name_len as usize).next_multiple_of(4);
+    }
+    None
 }
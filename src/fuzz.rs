@@ -0,0 +1,106 @@
+//! Entry points for fuzzing the parsing of on-disk structures, enabled by
+//! the `fuzzing` feature and driven by the targets in `fuzz/`.
+//!
+//! Each function takes arbitrary bytes as a structure read from a hostile
+//! image, checks it the way the filesystem does when reading it, and goes
+//! through it if it is accepted. None of them may panic.
+
+use crate::constants::*;
+use crate::ext4::Ext4;
+use crate::ext4_defs::*;
+use crate::prelude::*;
+
+/// Copy `data` into a block, zero padded or truncated. Blocks are aligned
+/// for the on-disk structures.
+fn to_block(data: &[u8]) -> Block {
+    let mut block = Block::default();
+    let len = data.len().min(BLOCK_SIZE);
+    block.data[..len].copy_from_slice(&data[..len]);
+    block
+}
+
+/// Parse a superblock, and derive the geometry of the filesystem from it
+/// if it is accepted by `Ext4::load`.
+pub fn super_block(data: &[u8]) {
+    let sb = SuperBlock::from_record(data);
+    if Ext4::check_super_block(&sb).is_err() {
+        return;
+    }
+    let groups = sb.block_group_count();
+    for bgid in (0..groups.min(16)).chain([groups - 1]) {
+        let _ = sb.group_first_block(bgid);
+        let _ = sb.blocks_in_group(bgid);
+        let _ = sb.inode_count_in_group(bgid);
+    }
+    let _ = sb.groups_per_flex();
+    let _ = sb.inode_table_blocks();
+    let _ = sb.clusters_per_group();
+    let _ = sb.desc_size();
+    let _ = sb.volume_name();
+}
+
+/// Parse an extent tree node, both as the root stored in an inode and as
+/// a tree block, and search it if it is valid.
+pub fn extent_node(data: &[u8]) {
+    let block = to_block(data);
+    // The 60 bytes of `i_block`, the same bytes at an odd address, as the
+    // nodes are not aligned in memory, then a whole block
+    let mut shifted = [0; 61];
+    shifted[1..].copy_from_slice(&block.data[..60]);
+    for raw in [&block.data[..60], &shifted[1..], &block.data[..]] {
+        let node = ExtentNode::from_bytes(raw);
+        if !node.is_valid(None) {
+            continue;
+        }
+        let header = node.header();
+        for i in 0..header.entries_count() as usize {
+            if header.depth() == 0 {
+                let ex = node.extent_at(i);
+                let _ = (ex.start_lblock(), ex.start_pblock(), ex.block_count());
+            } else {
+                let _ = node.extent_index_at(i).leaf();
+            }
+        }
+        for lblock in [0, 1, 4096, LBlockId::MAX] {
+            let _ = node.search_extent(lblock);
+            let _ = node.search_extent_index(lblock);
+        }
+        let _ = node.verify_checksum(0, 0, 0);
+    }
+}
+
+/// Parse a directory block, look up and list its entries, then add and
+/// remove one as the filesystem does.
+pub fn dir_block(data: &[u8]) {
+    let mut dir = DirBlock::new(to_block(data));
+    let _ = dir.raw_entries();
+    let _ = dir.tail_checksum();
+    let _ = dir.get("name");
+    #[cfg(feature = "casefold")]
+    let _ = dir.get_casefold("NAME");
+    let mut entries = Vec::new();
+    dir.list(&mut entries);
+    for entry in &entries {
+        let _ = entry.name();
+    }
+    let _ = dir.used_count();
+    let _ = dir.verify_checksum(0, 0, 0);
+    dir.insert("name", 12, FileType::RegularFile);
+    dir.replace("name", 13, FileType::Directory);
+    dir.remove("name");
+    dir.set_checksum(0, 0, 0);
+}
+
+/// Parse an extended attribute block, or the attributes stored in an
+/// inode, and look them up if they are accepted.
+pub fn xattr_block(data: &[u8]) {
+    let extra_isize = data.first().map_or(0, |&len| len as usize);
+    let _ = find_ibody_xattr(data, extra_isize, 9, b"c");
+    let xattr = XattrBlock::new(to_block(data));
+    let _ = xattr.get_raw(9, b"c");
+    if !xattr.is_valid() {
+        return;
+    }
+    let _ = xattr.get("user.name");
+    let _ = xattr.list();
+}
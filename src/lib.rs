@@ -1,15 +1,49 @@
 //! The Ext4 filesystem implementation in Rust.
+//!
+//! The crate does not panic on malformed images or when the device runs
+//! out of space: corrupted metadata is reported as `EFSCORRUPTED`,
+//! `EFSBADCRC` or `EINVAL`, and exhausted space as `ENOSPC`. The only
+//! exception is `ErrorPolicy::Panic`, which panics on filesystem errors
+//! when the image or the embedder asks for it.
 #![no_std]
 
+#[cfg(feature = "std")]
+extern crate std;
+
 mod constants;
 mod error;
 mod ext4;
 mod ext4_defs;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzz;
 mod jbd2;
+mod logging;
 mod prelude;
+mod sync;
 
-pub use constants::{BLOCK_SIZE, EXT4_ROOT_INO, INODE_BLOCK_SIZE};
+pub use constants::{
+    BLOCK_SIZE, DELALLOC_LIMIT, EXT4_ROOT_INO, INODE_BLOCK_SIZE, MAX_FILE_SIZE, MAX_OPEN_HANDLES,
+    O_DIRECT,
+};
 pub use error::{ErrCode, Ext4Error};
-pub use ext4::Ext4;
-pub use ext4_defs::{Block, BlockDevice, DirEntry, FileAttr, FileType, Inode, InodeMode, InodeRef};
+#[cfg(feature = "tar")]
+pub use ext4::TarImporter;
+pub use ext4::{
+    diff_devices, BlockDiff, BlockMapping, BlockReservation, Capabilities, CompiledFeatures,
+    Credentials, CryptoProvider, DelayedWriter, DirBlockDump, DirEntryDump, EncryptionPolicy,
+    ErrorPolicy, Ext4, Ext4Stats, ExtentTreeEntry, ExtentTreeNode, FragReport, GroupInfo,
+    ImageFeatures, InodeHandle, LazyInitCursor, Op, OpStats, PathComponents, QuotaEntry,
+    QuotaLimits, QuotaType, RawAccess, SuperBlockInfo, UnsupportedFeatures, VerityDescriptor,
+    VerityHasher, WalkControl, WalkOptions, WearStats,
+};
+#[cfg(all(feature = "std", unix))]
+pub use ext4_defs::FileBlockDevice;
+pub use ext4_defs::{
+    AttrMask, Bitmap, Block, BlockDevice, BlockGroupFlags, DirEntry, FeatureCompat,
+    FeatureIncompat, FeatureRoCompat, FileAttr, FileHandle, FileHandleId, FileType, HandleTable,
+    Inode, InodeFlags, InodeMode, InodeRef, SectorAdapter, SectorDevice,
+};
+#[cfg(feature = "mem_device")]
+pub use ext4_defs::{CowDevice, CowSnapshot, MemBlockDevice, MemCheckpoint};
 pub use prelude::{Result, LBlockId, PBlockId, InodeId, BlockGroupId};
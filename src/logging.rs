@@ -0,0 +1,38 @@
+//! Per-subsystem logging.
+//!
+//! Trace, debug and info messages are grouped by subsystem, and each
+//! subsystem is compiled in only when its feature is enabled:
+//!
+//! * `log_alloc` - block and inode allocation
+//! * `log_dir` - directory entry operations
+//! * `log_extent` - extent tree operations
+//! * `log_cache` - block cache
+//! * `log_fs` - mount, orphan list and lazy initialization
+//!
+//! `log_all` enables all of them. With a subsystem disabled, its messages
+//! cost nothing at runtime, not even a level check. Messages go to the
+//! `log` crate with target `another_ext4::<subsystem>`, so enabled
+//! subsystems can be filtered further by the logger.
+//!
+//! Warnings and errors are always logged through `log` directly.
+
+#[rustfmt::skip]
+macro_rules! define_subsystem_log {
+    ($d:tt $name:ident, $feature:literal, $target:literal) => {
+        #[allow(unused_macros)]
+        macro_rules! $name {
+            ($d level:ident, $d ($d arg:tt)+) => {{
+                #[cfg(feature = $feature)]
+                log::$d level!(target: $target, $d ($d arg)+);
+            }};
+        }
+        #[allow(unused_imports)]
+        pub(crate) use $name;
+    };
+}
+
+define_subsystem_log!($ log_alloc, "log_alloc", "another_ext4::alloc");
+define_subsystem_log!($ log_dir, "log_dir", "another_ext4::dir");
+define_subsystem_log!($ log_extent, "log_extent", "another_ext4::extent");
+define_subsystem_log!($ log_cache, "log_cache", "another_ext4::cache");
+define_subsystem_log!($ log_fs, "log_fs", "another_ext4::fs");
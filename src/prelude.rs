@@ -24,7 +24,9 @@ pub(crate) use core::fmt::Debug;
 pub(crate) use core::mem::{self, size_of};
 pub(crate) use core::ptr;
 
-pub(crate) use log::{debug, info, trace, warn};
+pub(crate) use log::warn;
+
+pub(crate) use crate::logging::{log_alloc, log_cache, log_dir, log_extent, log_fs};
 
 pub(crate) use crate::error::*;
 pub type Result<T> = core::result::Result<T, Ext4Error>;
@@ -0,0 +1,891 @@
+//! Block and inode allocation across block groups.
+
+mod common;
+
+use another_ext4::{Bitmap, ErrCode, InodeFlags, InodeMode, QuotaLimits, QuotaType, BLOCK_SIZE};
+use common::*;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+#[test]
+fn reserved_blocks() {
+    let image = TestImage::new("reserved_blocks", 64, &[]);
+    let mut ext4 = image.load();
+    let reserved = ext4.reserved_blocks();
+    ext4.set_reserved_blocks(u64::MAX)
+        .expect_err("too many reserved blocks");
+    ext4.set_reserved_blocks(reserved + 1)
+        .expect("set reserved blocks failed");
+    assert_eq!(ext4.reserved_blocks(), reserved + 1);
+    // Plenty of unreserved blocks left
+    ext4.set_reserved_access(false);
+    ext4.generic_write_atomic(ROOT_INO, "reserved", &[1; 4096])
+        .expect("write failed");
+    ext4.set_reserved_access(true);
+    ext4.set_reserved_blocks(reserved)
+        .expect("set reserved blocks failed");
+}
+
+#[test]
+fn lazy_init() {
+    let image = TestImage::new("lazy_init", 64, &["-E", "lazy_itable_init=1"]);
+    let ext4 = image.load();
+    ext4.generic_create(ROOT_INO, "f1", FILE_MODE)
+        .expect("create failed");
+    let mut cursor = ext4.lazy_init_cursor();
+    while !ext4
+        .lazy_init_step(&mut cursor, 64)
+        .expect("lazy init failed")
+    {}
+    // All groups are recorded as zeroed
+    let mut cursor = ext4.lazy_init_cursor();
+    assert!(ext4
+        .lazy_init_step(&mut cursor, 0)
+        .expect("lazy init failed"));
+    // Inodes in use are kept
+    ext4.generic_lookup(ROOT_INO, "f1").expect("open failed");
+}
+
+#[test]
+fn delalloc() {
+    let image = TestImage::new("delalloc", 64, &[]);
+    let ext4 = image.load();
+    let file = ext4
+        .generic_create(ROOT_INO, "delalloc", FILE_MODE)
+        .expect("create failed");
+    let data: Vec<u8> = (0..100 * 1000).map(|i| (i % 251) as u8).collect();
+    {
+        let mut writer = ext4.delayed_writer();
+        let mut offset = 0;
+        for chunk in data.chunks(1000) {
+            writer.write(file, offset, chunk).expect("write failed");
+            offset += chunk.len() as u64;
+        }
+        assert_eq!(writer.pending_bytes(), data.len());
+        writer.flush().expect("flush failed");
+        assert_eq!(writer.pending_bytes(), 0);
+    }
+    let mut buf = vec![0; data.len()];
+    ext4.read(file, 0, &mut buf).expect("read failed");
+    assert_eq!(buf, data);
+    assert_eq!(
+        ext4.fragmentation(file)
+            .expect("fragmentation failed")
+            .extent_count,
+        1
+    );
+}
+
+#[test]
+fn defrag() {
+    let image = TestImage::new("defrag", 64, &[]);
+    let ext4 = image.load();
+    let a = ext4
+        .generic_create(ROOT_INO, "frag_a", FILE_MODE)
+        .expect("create failed");
+    let b = ext4
+        .generic_create(ROOT_INO, "frag_b", FILE_MODE)
+        .expect("create failed");
+    // Fill every other block first, so that both files are fragmented
+    let block = |i: usize| vec![i as u8; BLOCK_SIZE];
+    for i in (0..16).step_by(2).chain((1..16).step_by(2)) {
+        ext4.write(a, (i * BLOCK_SIZE) as u64, &block(i))
+            .expect("write failed");
+        ext4.write(b, (i * BLOCK_SIZE) as u64, &block(i))
+            .expect("write failed");
+    }
+    let before = ext4.fragmentation(a).expect("fragmentation failed");
+    assert!(before.extent_count > 1);
+    let after = ext4.defragment(a).expect("defragment failed");
+    assert_eq!(after.extent_count, 1);
+    assert_eq!(after.block_count, before.block_count);
+    assert_eq!(ext4.fragmentation(a).expect("fragmentation failed"), after);
+    for i in 0..16 {
+        let mut buf = vec![0; BLOCK_SIZE];
+        ext4.read(a, (i * BLOCK_SIZE) as u64, &mut buf)
+            .expect("read failed");
+        assert_eq!(buf, block(i));
+    }
+    ext4.generic_remove(ROOT_INO, "frag_a")
+        .expect("remove failed");
+    ext4.generic_remove(ROOT_INO, "frag_b")
+        .expect("remove failed");
+}
+
+#[test]
+fn interleaved_writes() {
+    let image = TestImage::new("interleaved_writes", 64, &[]);
+    let ext4 = image.load();
+    let a = ext4
+        .generic_create(ROOT_INO, "a", FILE_MODE)
+        .expect("create failed");
+    let b = ext4
+        .generic_create(ROOT_INO, "b", FILE_MODE)
+        .expect("create failed");
+    // Files appended alternately each stay contiguous
+    for i in 0..32 {
+        ext4.write(a, (i * BLOCK_SIZE) as u64, &[1; BLOCK_SIZE])
+            .expect("write failed");
+        ext4.write(b, (i * BLOCK_SIZE) as u64, &[2; BLOCK_SIZE])
+            .expect("write failed");
+    }
+    assert_eq!(
+        ext4.fragmentation(a)
+            .expect("fragmentation failed")
+            .extent_count,
+        1
+    );
+    assert_eq!(
+        ext4.fragmentation(b)
+            .expect("fragmentation failed")
+            .extent_count,
+        1
+    );
+    // A file written with holes keeps room to fill them in place
+    let c = ext4
+        .generic_create(ROOT_INO, "c", FILE_MODE)
+        .expect("create failed");
+    for i in (0..8).chain(16..24).chain(8..16) {
+        ext4.write(c, (i * BLOCK_SIZE) as u64, &[3; BLOCK_SIZE])
+            .expect("write failed");
+    }
+    ext4.flush_all();
+    let blocks = image.debugfs(false, "blocks /c");
+    let blocks: Vec<u64> = blocks
+        .split_whitespace()
+        .map(|s| s.parse().unwrap())
+        .collect();
+    assert!(
+        blocks.windows(2).all(|w| w[1] == w[0] + 1),
+        "blocks {:?}",
+        blocks
+    );
+    image.finish(ext4);
+}
+
+#[test]
+fn alloc_shards() {
+    static SHARD: AtomicU32 = AtomicU32::new(0);
+    // 4 block groups, one per shard
+    let image = TestImage::new("alloc_shards", 64, &["-g", "4096"]);
+    let mut ext4 = image.load();
+    ext4.set_alloc_shards(4, || SHARD.load(Ordering::Relaxed));
+    let files: Vec<_> = (0..4)
+        .map(|i| {
+            ext4.generic_create(ROOT_INO, &format!("f{}", i), FILE_MODE)
+                .unwrap()
+        })
+        .collect();
+    // Writers take turns, each in its own shard
+    for round in 0..4 {
+        for (shard, &file) in files.iter().enumerate() {
+            SHARD.store(shard as u32, Ordering::Relaxed);
+            ext4.write(
+                file,
+                (round * BLOCK_SIZE) as u64,
+                &[shard as u8; BLOCK_SIZE],
+            )
+            .expect("write failed");
+        }
+    }
+    ext4.flush_all();
+    for shard in 0..4u64 {
+        let blocks = image.debugfs(false, &format!("blocks /f{}", shard));
+        for block in blocks.split_whitespace().map(|s| s.parse::<u64>().unwrap()) {
+            assert_eq!(block / 4096, shard, "block {} of f{}", block, shard);
+        }
+    }
+    image.finish(ext4);
+}
+
+#[test]
+fn csum_seed() {
+    // Golden images may have a null UUID and a stored checksum seed
+    let image = TestImage::new(
+        "csum_seed",
+        64,
+        &["-U", "clear", "-O", "metadata_csum_seed"],
+    );
+    {
+        let ext4 = image.load();
+        ext4.generic_create(ROOT_INO, "d1", DIR_MODE)
+            .expect("mkdir failed");
+        ext4.generic_write_atomic(ROOT_INO, "d1/f1", b"seed")
+            .expect("write failed");
+        ext4.flush_all();
+    }
+    let ext4 = image.load();
+    let file = ext4.generic_lookup(ROOT_INO, "d1/f1").expect("open failed");
+    let mut buf = [0; 4];
+    ext4.read(file, 0, &mut buf).expect("read failed");
+    assert_eq!(&buf, b"seed");
+}
+
+#[test]
+fn uninit_group() {
+    // 16 inodes and 4096 blocks per group, so that later groups are used
+    let image = TestImage::new(
+        "uninit_group",
+        64,
+        &["-N", "64", "-g", "4096", "-E", "lazy_itable_init=1"],
+    );
+    {
+        let ext4 = image.load();
+        for i in 0..12 {
+            let path = format!("f{}", i);
+            ext4.generic_write_atomic(ROOT_INO, &path, path.as_bytes())
+                .expect("write failed");
+        }
+        let ino = ext4.generic_lookup(ROOT_INO, "f11").expect("lookup failed");
+        assert!(ino > 16, "inode {} not in an uninit group", ino);
+        ext4.flush_all();
+    }
+    let ext4 = image.load();
+    for i in 0..12 {
+        let path = format!("f{}", i);
+        let ino = ext4.generic_lookup(ROOT_INO, &path).expect("open failed");
+        let mut buf = vec![0; path.len()];
+        ext4.read(ino, 0, &mut buf).expect("read failed");
+        assert_eq!(buf, path.as_bytes());
+    }
+}
+
+#[test]
+fn flex_bg() {
+    // 2 flex groups of 2 block groups, 16 inodes per group
+    let image = TestImage::new("flex_bg", 64, &["-N", "64", "-g", "4096", "-G", "2"]);
+    let ext4 = image.load();
+    let flex_group = |ino: u32| (ino - 1) / 16 / 2;
+    // New directories go to the flex group with fewer directories
+    let dir = ext4
+        .mkdir(ROOT_INO, "d", InodeMode::ALL_RWX)
+        .expect("mkdir failed");
+    assert_ne!(flex_group(dir), flex_group(ROOT_INO));
+    // Files stay near their parent
+    let file = ext4.create(dir, "f", FILE_MODE).expect("create failed");
+    assert_eq!(flex_group(file), flex_group(dir));
+    ext4.write(file, 0, b"flex").expect("write failed");
+    let mut buf = [0; 4];
+    ext4.read(file, 0, &mut buf).expect("read failed");
+    assert_eq!(&buf, b"flex");
+}
+
+#[test]
+fn orlov() {
+    // 4 block groups of 16 inodes, without flex groups
+    let image = TestImage::new("orlov", 64, &["-N", "64", "-g", "4096", "-O", "^flex_bg"]);
+    let ext4 = image.load();
+    let group = |ino: u32| (ino - 1) / 16;
+    // Top-level directories are spread, away from the crowded first group
+    let dirs: Vec<u32> = ["a", "b", "c"]
+        .iter()
+        .map(|name| {
+            ext4.mkdir(ROOT_INO, name, InodeMode::ALL_RWX)
+                .expect("mkdir failed")
+        })
+        .collect();
+    let mut groups: Vec<u32> = dirs.iter().map(|&dir| group(dir)).collect();
+    groups.sort();
+    groups.dedup();
+    assert_eq!(groups.len(), 3, "groups {:?}", groups);
+    assert!(!groups.contains(&0));
+    // Other directories and files stay with their parent
+    let sub = ext4
+        .mkdir(dirs[0], "sub", InodeMode::ALL_RWX)
+        .expect("mkdir failed");
+    assert_eq!(group(sub), group(dirs[0]));
+    let file = ext4.create(sub, "f", FILE_MODE).expect("create failed");
+    assert_eq!(group(file), group(sub));
+    // Subdirectories of a TOPDIR directory are spread
+    ext4.setflags(dirs[1], InodeFlags::TOPDIR)
+        .expect("setflags failed");
+    let top = ext4
+        .mkdir(dirs[1], "t", InodeMode::ALL_RWX)
+        .expect("mkdir failed");
+    assert_ne!(group(top), group(dirs[1]));
+    image.finish(ext4);
+}
+
+#[test]
+fn multi_group() {
+    // 16 MiB groups, the files below span all of them
+    let image = TestImage::new("multi_group", 64, &["-g", "4096"]);
+    let content = |i: usize| -> Vec<u8> {
+        (0..6 * 1024 * 1024)
+            .map(|j| ((i * 7 + j) % 253) as u8)
+            .collect()
+    };
+    {
+        let ext4 = image.load();
+        for i in 0..7 {
+            ext4.generic_write_atomic(ROOT_INO, &format!("f{}", i), &content(i))
+                .expect("write failed");
+        }
+        ext4.flush_all();
+    }
+    // Blocks were allocated beyond the first group
+    let last_block = image
+        .debugfs(false, "blocks /f6")
+        .split_whitespace()
+        .filter_map(|s| s.parse::<u64>().ok())
+        .max()
+        .unwrap();
+    assert!(last_block >= 4096 * 2, "last block {}", last_block);
+    let ext4 = image.load();
+    for i in 0..7 {
+        let file = ext4
+            .generic_lookup(ROOT_INO, &format!("f{}", i))
+            .expect("open failed");
+        let mut buf = vec![0; 6 * 1024 * 1024];
+        ext4.read(file, 0, &mut buf).expect("read failed");
+        assert!(buf == content(i), "f{} corrupted", i);
+    }
+    // Freed blocks can be reused
+    for i in 0..7 {
+        ext4.generic_remove(ROOT_INO, &format!("f{}", i))
+            .expect("remove failed");
+    }
+    ext4.generic_write_atomic(ROOT_INO, "big", &vec![1; 40 * 1024 * 1024])
+        .expect("write failed");
+}
+
+#[test]
+fn free_guards() {
+    let image = TestImage::new("free_guards", 64, &[]);
+    let inode_table = {
+        let ext4 = image.load();
+        ext4.generic_write_atomic(ROOT_INO, "f", &[1; BLOCK_SIZE])
+            .expect("write failed");
+        ext4.generic_write_atomic(ROOT_INO, "g", &[1; 2 * BLOCK_SIZE])
+            .expect("write failed");
+        ext4.flush_all();
+        let group = ext4.group_info(0).expect("group info failed");
+        assert_ne!(group.inode_bitmap, group.inode_table - 1);
+        group.inode_table
+    };
+    // Map the file onto the group descriptor table, the second block of a
+    // two-block extent onto an inode table, and link the reserved resize
+    // inode into the root directory
+    image.debugfs(true, "sif /f block[5] 1");
+    image.debugfs(true, &format!("sif /g block[5] {}", inode_table - 1));
+    image.debugfs(true, "ln <7> resize");
+    let ext4 = image.load();
+    let err = ext4
+        .generic_remove(ROOT_INO, "f")
+        .expect_err("metadata freed");
+    assert_eq!(err.code(), ErrCode::EFSCORRUPTED);
+    let err = ext4
+        .generic_remove(ROOT_INO, "g")
+        .expect_err("metadata freed");
+    assert_eq!(err.code(), ErrCode::EFSCORRUPTED);
+    let err = ext4
+        .generic_remove(ROOT_INO, "resize")
+        .expect_err("reserved inode freed");
+    assert_eq!(err.code(), ErrCode::EFSCORRUPTED);
+    // The group descriptors are intact
+    drop(ext4);
+    image
+        .load()
+        .generic_lookup(ROOT_INO, "lost+found")
+        .expect("lookup failed");
+}
+
+#[test]
+fn reserved_inodes() {
+    let image = TestImage::new("reserved_inodes", 64, &[]);
+    // Reserved inodes are not allocated even if their bits are clear
+    image.debugfs(true, "freei <5>");
+    let mut ext4 = image.load();
+    // The root of an image made by mkfs is kept
+    assert!(ext4.has_root());
+    ext4.init().expect("init failed");
+    ext4.generic_lookup(ROOT_INO, "lost+found")
+        .expect("lookup failed");
+    let first_inode = ext4.super_block_info().first_inode;
+    for i in 0..4 {
+        let file = ext4
+            .generic_create(ROOT_INO, &format!("f{}", i), FILE_MODE)
+            .expect("create failed");
+        assert!(file >= first_inode);
+    }
+    ext4.flush_all();
+    drop(ext4);
+    image.debugfs(true, "seti <5>");
+    image.fsck();
+}
+
+#[test]
+fn inspect() {
+    // 4 groups, with uninitialized bitmaps in the later ones
+    let image = TestImage::new("inspect", 64, &["-g", "4096", "-L", "inspect"]);
+    let ext4 = image.load();
+    ext4.generic_write_atomic(ROOT_INO, "f", &vec![1; 8 * BLOCK_SIZE])
+        .expect("write failed");
+    let info = ext4.super_block_info();
+    assert_eq!(info.volume_name, b"inspect");
+    assert_eq!(info.group_count, 4);
+    assert_eq!(info.blocks_per_group, 4096);
+    let groups: Vec<_> = ext4.groups().collect();
+    assert_eq!(groups.len(), 4);
+    assert!(groups.iter().all(|g| g.checksum_valid));
+    let free: u64 = groups.iter().map(|g| g.free_blocks).sum();
+    assert_eq!(free, info.free_blocks);
+    ext4.group_info(4).expect_err("group out of range");
+    // Free ranges are reported as `dumpe2fs` does
+    let fmt = |ranges: Vec<std::ops::Range<u64>>| {
+        ranges
+            .iter()
+            .map(|r| match r.end - r.start {
+                1 => format!("{}", r.start),
+                _ => format!("{}-{}", r.start, r.end - 1),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut expected = String::new();
+    for g in &groups {
+        let blocks = ext4.group_free_blocks(g.id).expect("free blocks failed");
+        let inodes = ext4.group_free_inodes(g.id).expect("free inodes failed");
+        let inodes = inodes
+            .into_iter()
+            .map(|r| r.start as u64..r.end as u64)
+            .collect();
+        expected += &format!(
+            "{} free blocks, {} free inodes, {} directories\n  Free blocks: {}\n  Free inodes: {}\n",
+            g.free_blocks,
+            g.free_inodes,
+            g.used_dirs,
+            fmt(blocks),
+            fmt(inodes)
+        );
+    }
+    image.finish(ext4);
+    let mut actual = String::new();
+    let dump = image.dumpe2fs();
+    let mut lines = dump.lines().map(str::trim);
+    while let Some(line) = lines.next() {
+        if let Some((counts, _)) = line.split_once(" directories") {
+            actual += &format!(
+                "{} directories\n  {}\n  {}\n",
+                counts,
+                lines.next().unwrap(),
+                lines.next().unwrap()
+            );
+        }
+    }
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn desc_sizes() {
+    // 32-byte descriptors without `64bit`, and descriptors larger than 64
+    // bytes, both checksummed with `metadata_csum`
+    let cases: [(&str, &[&str]); 2] = [
+        ("desc_size_32", &["-g", "4096", "-O", "^64bit"]),
+        ("desc_size_128", &["-g", "4096", "-E", "desc_size=128"]),
+    ];
+    for (name, args) in cases {
+        let image = TestImage::new(name, 64, args);
+        let ext4 = image.load();
+        assert!(ext4.groups().all(|g| g.checksum_valid), "{}", name);
+        for i in 0..4 {
+            let dir = format!("d{}", i);
+            ext4.generic_create(ROOT_INO, &dir, DIR_MODE)
+                .expect("mkdir failed");
+            ext4.generic_write_atomic(ROOT_INO, &format!("{}/f", dir), &vec![i; 5 << 20])
+                .expect("write failed");
+        }
+        assert!(ext4.groups().all(|g| g.checksum_valid), "{}", name);
+        image.finish(ext4);
+    }
+}
+
+#[test]
+fn bigalloc() {
+    // 4 blocks per cluster
+    let image = TestImage::new("bigalloc", 64, &["-O", "bigalloc", "-C", "16384"]);
+    let dir = TestDir::new("bigalloc");
+    let data: Vec<u8> = (0..37 * BLOCK_SIZE).map(|i| (i % 251) as u8 + 1).collect();
+    let path = dir.path().join("data");
+    std::fs::write(&path, &data).unwrap();
+    image.debugfs(true, &format!("write {} f", path.display()));
+    let ext4 = image.load();
+    // Mapped blocks are read as they are
+    let file = ext4.lookup(ROOT_INO, "f").expect("lookup failed");
+    let mut buf = vec![0; data.len()];
+    let n = ext4.read(file, 0, &mut buf).expect("read failed");
+    assert_eq!(n, data.len());
+    assert!(buf == data);
+    // Free space is reported in blocks, from bitmaps in clusters
+    let info = ext4.super_block_info();
+    assert_eq!(info.cluster_size, 4 * BLOCK_SIZE);
+    assert_eq!(info.clusters_per_group * 4, info.blocks_per_group);
+    let free: u64 = ext4.groups().map(|g| g.free_blocks).sum();
+    assert_eq!(free, info.free_blocks);
+    let mut free_ranges = 0;
+    for g in ext4.groups() {
+        let ranges = ext4.group_free_blocks(g.id).expect("free blocks failed");
+        assert!(ranges.iter().all(|r| (r.start - g.first_block) % 4 == 0));
+        free_ranges += ranges.iter().map(|r| r.end - r.start).sum::<u64>();
+    }
+    assert_eq!(free_ranges, info.free_blocks);
+    let dump = image.dumpe2fs();
+    assert!(dump.contains(&format!("Free blocks:              {}\n", info.free_blocks)));
+    // Writes are rejected
+    let err = ext4.write(file, 0, b"x").expect_err("write with bigalloc");
+    assert_eq!(err.code(), ErrCode::EROFS);
+    image.finish(ext4);
+}
+
+#[test]
+fn iter_inodes() {
+    // 4 groups, so that new directories go to another group
+    let image = TestImage::new("iter_inodes", 64, &["-g", "4096"]);
+    let ext4 = image.load();
+    let dir = ext4
+        .mkdir(ROOT_INO, "d", InodeMode::ALL_RWX)
+        .expect("mkdir failed");
+    let files: Vec<_> = (0..5)
+        .map(|i| {
+            ext4.generic_write_atomic(ROOT_INO, &format!("d/f{}", i), &vec![1; i * 1000])
+                .expect("write failed")
+        })
+        .collect();
+    ext4.generic_remove(ROOT_INO, "d/f0")
+        .expect("remove failed");
+    let inodes: Vec<_> = ext4.iter_inodes().collect();
+    assert!(inodes.windows(2).all(|w| w[0].0 < w[1].0));
+    let find = |id: u32| {
+        inodes
+            .iter()
+            .find(|(ino, _)| *ino == id)
+            .map(|(_, attr)| attr)
+    };
+    assert!(find(ROOT_INO).is_some());
+    assert!(find(dir).is_some());
+    assert!(find(files[0]).is_none());
+    for (i, &file) in files.iter().enumerate().skip(1) {
+        assert_eq!(find(file).expect("inode not found").size, i as u64 * 1000);
+    }
+    image.finish(ext4);
+}
+
+#[test]
+fn quota() {
+    let image = TestImage::new("quota", 64, &["-O", "quota"]);
+    let ext4 = image.load();
+    let root = ext4
+        .quota_get(QuotaType::User, 0)
+        .expect("quota get failed");
+    assert_eq!(
+        ext4.quota_get(QuotaType::Project, 0).unwrap_err().code(),
+        ErrCode::ENOTSUP
+    );
+
+    let file = ext4
+        .generic_create(ROOT_INO, "f1", FILE_MODE)
+        .expect("create failed");
+    ext4.write(file, 0, &[1; 3 * BLOCK_SIZE])
+        .expect("write failed");
+    ext4.setattr(
+        file,
+        None,
+        Some(1000),
+        Some(2000),
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .expect("setattr failed");
+    let user = ext4
+        .quota_get(QuotaType::User, 1000)
+        .expect("quota get failed");
+    assert_eq!((user.space, user.inodes), (3 * BLOCK_SIZE as u64, 1));
+    let group = ext4
+        .quota_get(QuotaType::Group, 2000)
+        .expect("quota get failed");
+    assert_eq!((group.space, group.inodes), (3 * BLOCK_SIZE as u64, 1));
+    assert_eq!(ext4.quota_get(QuotaType::User, 0).unwrap(), root);
+
+    // Enough ids to fill more than one data block
+    for i in 0..20 {
+        let file = ext4
+            .generic_create(ROOT_INO, &format!("u{}", i), FILE_MODE)
+            .expect("create failed");
+        ext4.setattr(
+            file,
+            None,
+            Some(3000 + i),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .expect("setattr failed");
+    }
+    ext4.generic_remove(ROOT_INO, "u0").expect("remove failed");
+    let users = ext4.quota_list(QuotaType::User).expect("quota list failed");
+    assert_eq!(users.len(), 22);
+    assert_eq!((users[2].id, users[2].inodes), (3000, 0));
+    assert_eq!((users[3].id, users[3].inodes), (3001, 1));
+
+    let limits = QuotaLimits {
+        space_soft: 1 << 20,
+        space_hard: 2 << 20,
+        inode_soft: 10,
+        inode_hard: 20,
+    };
+    ext4.quota_set_limits(QuotaType::User, 1000, limits)
+        .expect("set limits failed");
+    assert_eq!(
+        ext4.quota_get(QuotaType::User, 1000).unwrap().limits,
+        limits
+    );
+    image.finish(ext4);
+
+    // Persisted usage matches what the Linux tools read
+    let ext4 = image.load();
+    assert_eq!(
+        ext4.quota_get(QuotaType::User, 1000).unwrap().space,
+        user.space
+    );
+    drop(ext4);
+    let output = image.debugfs(false, "get_quota user 1000");
+    let line = output
+        .lines()
+        .find(|line| line.trim_start().starts_with("1000"))
+        .expect("quota not listed");
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    assert_eq!(fields[1], (3 * BLOCK_SIZE).to_string());
+    assert_eq!(fields[4], "1");
+}
+
+#[test]
+fn bitmap_scan() {
+    let mut bytes = [0xffu8; 32];
+    // Clear bits 70..75 and 130..200, the second run crossing words
+    for bit in (70..75).chain(130..200) {
+        bytes[bit / 8] &= !(1 << (bit % 8));
+    }
+    let mut bitmap = Bitmap::new(&mut bytes, 250);
+    assert_eq!(bitmap.first_clear_bit(0, 250), Some(70));
+    assert_eq!(bitmap.first_clear_bit(75, 250), Some(130));
+    assert_eq!(bitmap.first_clear_bit(75, 130), None);
+    assert_eq!(bitmap.first_set_bit(130, 250), Some(200));
+    assert_eq!(bitmap.clear_run_len(70, 250), 5);
+    assert_eq!(bitmap.clear_run_len(140, 150), 10);
+    assert_eq!(bitmap.find_clear_run(0, 250, 5), Some(70));
+    assert_eq!(bitmap.find_clear_run(0, 250, 6), Some(130));
+    assert_eq!(bitmap.find_clear_run(0, 250, 71), None);
+    assert_eq!(bitmap.find_clear_run(0, 180, 60), None);
+    bitmap.set_bits(130, 70);
+    assert_eq!(bitmap.find_and_set_first_clear_bit(71, 250), Some(71));
+    assert_eq!(bitmap.first_clear_bit(75, 256), None);
+}
+
+#[test]
+fn free_fragmented() {
+    let image = TestImage::new("free_fragmented", 64, &[]);
+    let mut ext4 = image.load();
+    // Without the extent status cache, lookups walk the extent tree
+    ext4.set_cache_limit(0);
+    let free = ext4.super_block_info().free_blocks;
+    let file = ext4
+        .generic_create(ROOT_INO, "sparse", FILE_MODE)
+        .expect("create failed");
+    // Every other block, more extents than fit in the inode
+    for i in (0..64).step_by(2) {
+        ext4.write(file, (i * BLOCK_SIZE) as u64, &[i as u8; BLOCK_SIZE])
+            .expect("write failed");
+    }
+    assert!(
+        ext4.fragmentation(file)
+            .expect("fragmentation failed")
+            .extent_count
+            > 4
+    );
+    let mut buf = vec![0; BLOCK_SIZE];
+    ext4.read(file, 62 * BLOCK_SIZE as u64, &mut buf)
+        .expect("read failed");
+    assert_eq!(buf, [62; BLOCK_SIZE]);
+    let dir = ext4
+        .generic_create(ROOT_INO, "dir", DIR_MODE)
+        .expect("create failed");
+    ext4.generic_create(dir, "child", FILE_MODE)
+        .expect("create failed");
+    ext4.generic_remove(ROOT_INO, "dir")
+        .expect_err("directory not empty");
+    ext4.generic_remove(dir, "child").expect("remove failed");
+    ext4.generic_remove(ROOT_INO, "dir").expect("remove failed");
+    // Data and extent tree blocks are all freed
+    ext4.generic_remove(ROOT_INO, "sparse")
+        .expect("remove failed");
+    assert_eq!(ext4.super_block_info().free_blocks, free);
+    image.finish(ext4);
+}
+
+#[test]
+fn enospc() {
+    let image = TestImage::new("enospc", 16, &["-m", "0"]);
+    let mut ext4 = image.load();
+    // Leave fewer blocks free than can be reserved, which limits the space
+    // left to unprivileged writers
+    let free = ext4.super_block_info().free_blocks as usize;
+    ext4.generic_write_atomic(ROOT_INO, "filler", &vec![1; free / 2 * BLOCK_SIZE])
+        .expect("write failed");
+    // The extent root of "sparse" is full, so that mapping another extent
+    // splits it
+    let sparse = ext4
+        .generic_create(ROOT_INO, "sparse", FILE_MODE)
+        .expect("create failed");
+    for i in (0..8).step_by(2) {
+        ext4.write(sparse, (i * BLOCK_SIZE) as u64, &[i as u8; BLOCK_SIZE])
+            .expect("write failed");
+    }
+    assert_eq!(
+        ext4.fragmentation(sparse)
+            .expect("fragmentation failed")
+            .extent_count,
+        4
+    );
+    let free = ext4.super_block_info().free_blocks;
+    ext4.set_reserved_access(false);
+    let limit = |left: u64| {
+        ext4.set_reserved_blocks(free - left)
+            .expect("set reserved blocks failed");
+    };
+    // One block is not enough for a new extent and the split root
+    limit(1);
+    let err = ext4
+        .write(sparse, 8 * BLOCK_SIZE as u64, &[8; BLOCK_SIZE])
+        .expect_err("write fit");
+    assert_eq!(err.code(), ErrCode::ENOSPC);
+    assert_eq!(ext4.super_block_info().free_blocks, free);
+    assert_eq!(
+        ext4.fragmentation(sparse)
+            .expect("fragmentation failed")
+            .extent_count,
+        4
+    );
+    // Mapped blocks can still be written
+    ext4.write(sparse, 0, &[9; BLOCK_SIZE])
+        .expect("write failed");
+    // A new directory gets no block, and its inode is freed
+    limit(0);
+    let free_inodes = ext4.super_block_info().free_inodes;
+    let err = ext4
+        .generic_create(ROOT_INO, "d", DIR_MODE)
+        .expect_err("mkdir without space");
+    assert_eq!(err.code(), ErrCode::ENOSPC);
+    assert_eq!(ext4.super_block_info().free_inodes, free_inodes);
+    ext4.generic_lookup(ROOT_INO, "d")
+        .expect_err("failed mkdir linked");
+    // The data written before space runs out is kept, and the size covers it
+    limit(3);
+    let big = ext4
+        .generic_create(ROOT_INO, "big", FILE_MODE)
+        .expect("create failed");
+    let err = ext4
+        .write(big, 0, &[2; 8 * BLOCK_SIZE])
+        .expect_err("write fit");
+    assert_eq!(err.code(), ErrCode::ENOSPC);
+    assert_eq!(ext4.super_block_info().free_blocks, free - 3);
+    assert_eq!(
+        ext4.getattr(big).expect("getattr failed").size,
+        3 * BLOCK_SIZE as u64
+    );
+    ext4.set_reserved_access(true);
+    ext4.set_reserved_blocks(0)
+        .expect("set reserved blocks failed");
+    image.finish(ext4);
+}
+
+#[test]
+fn reserve_blocks() {
+    let image = TestImage::new("reserve_blocks", 16, &["-m", "0"]);
+    let mut ext4 = image.load();
+    // Leave fewer blocks free than can be reserved for privileged users
+    let free = ext4.super_block_info().free_blocks as usize;
+    ext4.generic_write_atomic(ROOT_INO, "filler", &vec![1; free / 2 * BLOCK_SIZE])
+        .expect("write failed");
+    ext4.set_reserved_blocks(16)
+        .expect("set reserved blocks failed");
+    ext4.set_reserved_access(false);
+    let available = ext4.available_blocks();
+    let info = ext4.super_block_info();
+    assert_eq!(available, info.free_blocks - 16);
+    let err = ext4
+        .reserve_blocks(available + 1)
+        .err()
+        .expect("reserved more than available");
+    assert_eq!(err.code(), ErrCode::ENOSPC);
+    // A fresh file needs a block per data block, the extent root has room
+    let file = ext4
+        .generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    let needed = ext4
+        .write_blocks_needed(file, 0, 8 * BLOCK_SIZE)
+        .expect("write_blocks_needed failed");
+    assert_eq!(needed, 8);
+    assert_eq!(
+        ext4.write_blocks_needed(file, 100, 10)
+            .expect("write_blocks_needed failed"),
+        1
+    );
+    {
+        let reservation = ext4.reserve_blocks(needed).expect("reserve failed");
+        assert_eq!(ext4.available_blocks(), available - needed);
+        // A nested reservation can take the blocks of the enclosing one,
+        // but no more than those and the available ones
+        let err = ext4
+            .reserve_blocks(available + 1)
+            .err()
+            .expect("reserved reserved blocks");
+        assert_eq!(err.code(), ErrCode::ENOSPC);
+        {
+            let _all = ext4
+                .reserve_blocks(available)
+                .expect("nested reserve failed");
+            assert_eq!(reservation.remaining(), 0);
+            assert_eq!(ext4.available_blocks(), 0);
+        }
+        assert_eq!(reservation.remaining(), needed);
+        // A nested reservation takes from the enclosing one first
+        {
+            let nested = ext4
+                .reserve_blocks(needed + 2)
+                .expect("nested reserve failed");
+            assert_eq!(reservation.remaining(), 0);
+            assert_eq!(nested.remaining(), needed + 2);
+            assert_eq!(ext4.available_blocks(), available - needed - 2);
+        }
+        assert_eq!(reservation.remaining(), needed);
+        ext4.write(file, 0, &[1; 8 * BLOCK_SIZE])
+            .expect("write failed");
+        assert_eq!(reservation.remaining(), 0);
+        assert_eq!(ext4.available_blocks(), available - needed);
+    }
+    assert_eq!(ext4.available_blocks(), available - needed);
+    assert_eq!(
+        ext4.write_blocks_needed(file, 0, 8 * BLOCK_SIZE)
+            .expect("write_blocks_needed failed"),
+        0
+    );
+    // A directory reserves its block and the growth of its parent up front
+    let info = ext4.super_block_info();
+    ext4.set_reserved_blocks(info.free_blocks)
+        .expect("set reserved blocks failed");
+    let free_inodes = info.free_inodes;
+    let err = ext4
+        .mkdir(ROOT_INO, "d", DIR_MODE)
+        .expect_err("mkdir without space");
+    assert_eq!(err.code(), ErrCode::ENOSPC);
+    assert_eq!(ext4.super_block_info().free_inodes, free_inodes);
+    ext4.set_reserved_access(true);
+    ext4.set_reserved_blocks(0)
+        .expect("set reserved blocks failed");
+    image.finish(ext4);
+}
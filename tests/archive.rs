@@ -0,0 +1,187 @@
+//! Tar archive import and export.
+
+mod common;
+
+use another_ext4::{ErrCode, Ext4, FileType, InodeMode};
+use common::*;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::process::{Command, Stdio};
+
+fn read_file(ext4: &Ext4, path: &str) -> Vec<u8> {
+    let file = ext4.generic_lookup(ROOT_INO, path).expect("lookup failed");
+    let size = ext4.getattr(file).expect("getattr failed").size as usize;
+    let mut buf = vec![0; size];
+    assert_eq!(ext4.read(file, 0, &mut buf).expect("read failed"), size);
+    buf
+}
+
+fn readlink(ext4: &Ext4, path: &str) -> Vec<u8> {
+    let link = ext4.generic_lookup(ROOT_INO, path).expect("lookup failed");
+    ext4.readlink(link).expect("readlink failed")
+}
+
+#[test]
+fn import() {
+    let dir = TestDir::new("tar_import_src");
+    let root = dir.path();
+    std::fs::create_dir_all(root.join("etc/init.d")).unwrap();
+    std::fs::write(root.join("etc/hostname"), b"box\n").unwrap();
+    std::fs::set_permissions(root.join("etc/hostname"), PermissionsExt::from_mode(0o640)).unwrap();
+    let big: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(root.join("etc/init.d/rc"), &big).unwrap();
+    std::fs::hard_link(root.join("etc/hostname"), root.join("etc/hostname.bak")).unwrap();
+    std::os::unix::fs::symlink("hostname", root.join("etc/short")).unwrap();
+    let long_target = "x/".repeat(60);
+    std::os::unix::fs::symlink(&long_target, root.join("etc/long")).unwrap();
+    // Longer than a ustar name and prefix can hold
+    let deep = format!("{}/{}/{}", "d".repeat(90), "e".repeat(90), "f".repeat(90));
+    std::fs::create_dir_all(root.join(&deep)).unwrap();
+    std::fs::write(root.join(&deep).join("file"), b"deep").unwrap();
+    let output = Command::new("tar")
+        .args(["--format=pax", "-cf", "-", "-C"])
+        .arg(root)
+        .arg(".")
+        .output()
+        .expect("tar not found");
+    assert!(output.status.success());
+
+    let image = TestImage::new("tar_import", 64, &[]);
+    let ext4 = image.load();
+    // Feed the archive in pieces that split headers
+    let mut importer = ext4.tar_importer(ROOT_INO);
+    for chunk in output.stdout.chunks(1000) {
+        importer.feed(chunk).expect("feed failed");
+    }
+    importer.finish().expect("import failed");
+    assert_eq!(read_file(&ext4, "etc/hostname"), b"box\n");
+    assert_eq!(read_file(&ext4, "etc/init.d/rc"), big);
+    assert_eq!(read_file(&ext4, &format!("{}/file", deep)), b"deep");
+    assert_eq!(readlink(&ext4, "etc/short"), b"hostname");
+    assert_eq!(readlink(&ext4, "etc/long"), long_target.as_bytes());
+    let hostname = ext4.generic_lookup(ROOT_INO, "etc/hostname").unwrap();
+    let attr = ext4.getattr(hostname).unwrap();
+    assert_eq!(attr.perm, InodeMode::from_bits_retain(0o640));
+    assert_eq!(attr.links, 2);
+    assert_eq!(
+        ext4.generic_lookup(ROOT_INO, "etc/hostname.bak").unwrap(),
+        hostname
+    );
+    // Extracting again replaces the files
+    ext4.tar_import(ROOT_INO, &output.stdout)
+        .expect("import failed");
+    assert_eq!(read_file(&ext4, "etc/init.d/rc"), big);
+    image.finish(ext4);
+}
+
+#[test]
+fn export() {
+    let image = TestImage::new("tar_export", 64, &[]);
+    let ext4 = image.load();
+    let src = ext4.generic_create(ROOT_INO, "src", DIR_MODE).unwrap();
+    let mut rng = Rng::new(3);
+    let data: Vec<u8> = (0..50_000).map(|_| rng.next() as u8).collect();
+    let file = ext4.generic_create(src, "a/b/file", FILE_MODE).unwrap();
+    ext4.write(file, 0, &data).expect("write failed");
+    ext4.setxattr(file, "user.origin", b"export")
+        .expect("setxattr failed");
+    let a = ext4.generic_lookup(src, "a").unwrap();
+    ext4.link(file, a, "hard").expect("link failed");
+    ext4.symlink(a, "sym", "b/file").expect("symlink failed");
+    let long_name = "n".repeat(200);
+    ext4.generic_create(src, &format!("a/{}", long_name), FILE_MODE)
+        .unwrap();
+    let fifo = InodeMode::from_type_and_perm(FileType::Fifo, InodeMode::ALL_RW);
+    ext4.create(a, "fifo", fifo).expect("create failed");
+    let mut archive = Vec::new();
+    ext4.tar_export(src, |piece| {
+        archive.extend_from_slice(piece);
+        Ok(())
+    })
+    .expect("export failed");
+    assert_eq!(archive.len() % 512, 0);
+
+    // The host tar extracts it
+    let dir = TestDir::new("tar_export_dst");
+    let mut tar = Command::new("tar")
+        .args(["-xf", "-", "-C"])
+        .arg(dir.path())
+        .stdin(Stdio::piped())
+        .spawn()
+        .expect("tar not found");
+    tar.stdin.take().unwrap().write_all(&archive).unwrap();
+    assert!(tar.wait().unwrap().success());
+    assert_eq!(std::fs::read(dir.path().join("a/b/file")).unwrap(), data);
+    assert_eq!(std::fs::read(dir.path().join("a/hard")).unwrap(), data);
+    let target = std::fs::read_link(dir.path().join("a/sym")).unwrap();
+    assert_eq!(target.to_str().unwrap(), "b/file");
+    assert!(dir.path().join("a").join(&long_name).exists());
+
+    // Importing it back keeps hard links and extended attributes
+    let dst = ext4.generic_create(ROOT_INO, "dst", DIR_MODE).unwrap();
+    ext4.tar_import(dst, &archive).expect("import failed");
+    let copy = ext4.generic_lookup(dst, "a/b/file").unwrap();
+    assert_eq!(read_file(&ext4, "dst/a/b/file"), data);
+    assert_eq!(ext4.generic_lookup(dst, "a/hard").unwrap(), copy);
+    assert_eq!(ext4.getxattr(copy, "user.origin").unwrap(), b"export");
+    assert_eq!(readlink(&ext4, "dst/a/sym"), b"b/file");
+    let fifo = ext4.generic_lookup(dst, "a/fifo").unwrap();
+    assert_eq!(ext4.getattr(fifo).unwrap().ftype, FileType::Fifo);
+    image.finish(ext4);
+}
+
+#[test]
+fn invalid_archive() {
+    let image = TestImage::new("tar_invalid", 64, &[]);
+    let ext4 = image.load();
+    let dir = ext4.generic_create(ROOT_INO, "dir", DIR_MODE).unwrap();
+    ext4.generic_create(dir, "file", FILE_MODE).unwrap();
+    let mut archive = Vec::new();
+    ext4.tar_export(dir, |piece| {
+        archive.extend_from_slice(piece);
+        Ok(())
+    })
+    .unwrap();
+    // Set the checksum of a header, computed with the field as spaces
+    let set_checksum = |header: &mut [u8]| {
+        let sum: u32 = header[..512]
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| {
+                if (148..156).contains(&i) {
+                    32
+                } else {
+                    b as u32
+                }
+            })
+            .sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+    };
+    // Corrupted header
+    let mut corrupted = archive.clone();
+    corrupted[0] ^= 1;
+    let err = ext4.tar_import(ROOT_INO, &corrupted).unwrap_err();
+    assert_eq!(err.code(), ErrCode::EINVAL);
+    // Truncated archive
+    let err = ext4.tar_import(ROOT_INO, &archive[..100]).unwrap_err();
+    assert_eq!(err.code(), ErrCode::EINVAL);
+    // Paths escaping the extraction directory
+    let mut escaping = archive.clone();
+    escaping[..7].copy_from_slice(b"../file");
+    set_checksum(&mut escaping);
+    let err = ext4.tar_import(dir, &escaping).unwrap_err();
+    assert_eq!(err.code(), ErrCode::EINVAL);
+    // Pax record longer than the extended header
+    let mut pax = vec![0; 1024];
+    pax[..3].copy_from_slice(b"pax");
+    pax[124..135].copy_from_slice(b"00000000010");
+    pax[156] = b'x';
+    pax[257..263].copy_from_slice(b"ustar\0");
+    pax[263..265].copy_from_slice(b"00");
+    set_checksum(&mut pax);
+    pax[512..520].copy_from_slice(b"9 a=bcd\n");
+    pax.extend_from_slice(&archive);
+    let err = ext4.tar_import(dir, &pax).unwrap_err();
+    assert_eq!(err.code(), ErrCode::EINVAL);
+    image.finish(ext4);
+}
@@ -0,0 +1,2666 @@
+//! Basic file and directory operations.
+
+mod common;
+
+use another_ext4::{
+    AttrMask, BlockDevice, BlockMapping, Credentials, CryptoProvider, EncryptionPolicy, ErrCode,
+    ErrorPolicy, Ext4, ExtentTreeEntry, FeatureRoCompat, FileType, InodeFlags, InodeMode,
+    MemBlockDevice, PathComponents, Result, VerityHasher, WalkControl, WalkOptions, BLOCK_SIZE,
+    MAX_FILE_SIZE,
+};
+use common::*;
+use std::io::{IoSlice, IoSliceMut};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+#[test]
+fn mkdir() {
+    let image = TestImage::new("mkdir", 64, &[]);
+    let ext4 = image.load();
+    for path in [
+        "d1",
+        "d1/d2",
+        "d1/d2/d3",
+        "d1/d2/d3/d4",
+        "d2",
+        "d2/d3",
+        "d2/d3/d4",
+        "d3",
+    ] {
+        ext4.generic_create(ROOT_INO, path, DIR_MODE)
+            .expect("mkdir failed");
+    }
+    ext4.generic_lookup(ROOT_INO, "d1/d2/d3/d4")
+        .expect("lookup failed");
+    image.finish(ext4);
+}
+
+#[test]
+fn create() {
+    let image = TestImage::new("create", 64, &[]);
+    let ext4 = image.load();
+    ext4.generic_create(ROOT_INO, "d1/d2", DIR_MODE)
+        .expect("mkdir failed");
+    ext4.generic_create(ROOT_INO, "d1/d2/f1", FILE_MODE)
+        .expect("create failed");
+    ext4.generic_create(ROOT_INO, "f1", FILE_MODE)
+        .expect("create failed");
+    ext4.generic_create(ROOT_INO, &"f".repeat(256), FILE_MODE)
+        .expect_err("name too long");
+}
+
+#[test]
+fn path_components() {
+    let components: Vec<&str> = PathComponents::new("/d1//d2/f1/").collect();
+    assert_eq!(components, ["d1", "d2", "f1"]);
+    assert_eq!(PathComponents::new("d1/f1").into_owned(), ["d1", "f1"]);
+    assert_eq!(PathComponents::new("//").count(), 0);
+}
+
+#[test]
+fn read_write() {
+    let image = TestImage::new("read_write", 64, &[]);
+    let ext4 = image.load();
+    let wbuffer = "hello world".as_bytes();
+    let file = ext4
+        .generic_create(ROOT_INO, "f0", FILE_MODE)
+        .expect("create failed");
+    ext4.write(file, 0, wbuffer).expect("write failed");
+    let mut rbuffer = vec![0u8; wbuffer.len() + 100]; // Test end of file
+    let rcount = ext4.read(file, 0, &mut rbuffer).expect("read failed");
+    assert_eq!(wbuffer, &rbuffer[..rcount]);
+}
+
+#[test]
+fn large_read_write() {
+    let image = TestImage::new("large_read_write", 64, &[]);
+    let ext4 = image.load();
+    let wbuffer = vec![99u8; 1024 * 1024 * 16];
+    let file = ext4
+        .generic_create(ROOT_INO, "f1", FILE_MODE)
+        .expect("create failed");
+    ext4.write(file, 0, &wbuffer).expect("write failed");
+    let mut rbuffer = vec![0u8; wbuffer.len()];
+    let rcount = ext4.read(file, 0, &mut rbuffer).expect("read failed");
+    assert_eq!(wbuffer, &rbuffer[..rcount]);
+}
+
+#[test]
+fn vectored_read_write() {
+    let image = TestImage::new("vectored_read_write", 64, &[]);
+    let ext4 = image.load();
+    let file = ext4
+        .generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    // Buffers of all sizes, spanning blocks and splitting them
+    let mut rng = Rng::new(1);
+    let data: Vec<Vec<u8>> = (0..40)
+        .map(|i| vec![i as u8; rng.below(2 * BLOCK_SIZE as u64) as usize])
+        .collect();
+    let expected = data.concat();
+    let offset = 1000;
+    let bufs: Vec<IoSlice> = data.iter().map(|buf| IoSlice::new(buf)).collect();
+    let written = ext4
+        .write_vectored(file, offset, &bufs)
+        .expect("write_vectored failed");
+    assert_eq!(written, expected.len());
+    let mut contents = vec![0; expected.len()];
+    ext4.read(file, offset, &mut contents).expect("read failed");
+    assert_eq!(contents, expected);
+    // Read back in buffers of other sizes, past the end of file
+    let mut rbuffers: Vec<Vec<u8>> = (0..40)
+        .map(|_| vec![0; rng.below(2 * BLOCK_SIZE as u64) as usize + 1])
+        .collect();
+    let mut bufs: Vec<IoSliceMut> = rbuffers
+        .iter_mut()
+        .map(|buf| IoSliceMut::new(buf))
+        .collect();
+    let read = ext4
+        .read_vectored(file, offset, &mut bufs)
+        .expect("read_vectored failed");
+    assert_eq!(
+        read,
+        expected
+            .len()
+            .min(rbuffers.iter().map(Vec::len).sum::<usize>())
+    );
+    assert_eq!(rbuffers.concat()[..read], expected[..read]);
+    // Plain slices work too
+    let mut first = [0; 10];
+    let mut second = [0; 10];
+    let read = ext4
+        .read_vectored(file, offset, &mut [&mut first[..], &mut second[..]])
+        .expect("read_vectored failed");
+    assert_eq!(read, 20);
+    assert_eq!([first, second].concat(), expected[..20]);
+    image.finish(ext4);
+}
+
+#[test]
+fn handle() {
+    let image = TestImage::new("handle", 64, &[]);
+    let ext4 = image.load();
+    let file = ext4
+        .generic_create(ROOT_INO, "f0", FILE_MODE)
+        .expect("create failed");
+    let fh = ext4.open(file, 0).expect("open handle failed");
+    assert_eq!(ext4.file_handle(fh).expect("get handle failed").inode, file);
+    ext4.release(fh).expect("release handle failed");
+    ext4.file_handle(fh).expect_err("stale handle accepted");
+    ext4.release(fh).expect_err("double release accepted");
+    let fh2 = ext4.open(file, 0).expect("open handle failed");
+    assert_ne!(fh, fh2);
+    ext4.release(fh2).expect("release handle failed");
+    let inode_count = ext4.super_block_info().inode_count;
+    for ino in [0, inode_count + 1] {
+        let err = ext4.open(ino, 0).expect_err("out of range inode opened");
+        assert_eq!(err.code(), ErrCode::EINVAL);
+    }
+}
+
+#[test]
+fn tmpfile() {
+    let image = TestImage::new("tmpfile", 64, &[]);
+    let ext4 = image.load();
+    let d3 = ext4
+        .generic_create(ROOT_INO, "d3", DIR_MODE)
+        .expect("mkdir failed");
+    // Linked tmpfile
+    let file = ext4
+        .create_unlinked(FILE_MODE)
+        .expect("create tmpfile failed");
+    ext4.write(file, 0, "tmpfile".as_bytes())
+        .expect("write failed");
+    ext4.link_tmpfile(file, d3, "tmp")
+        .expect("link tmpfile failed");
+    assert_eq!(
+        ext4.generic_lookup(ROOT_INO, "d3/tmp")
+            .expect("open failed"),
+        file
+    );
+    ext4.link_tmpfile(file, d3, "tmp2")
+        .expect_err("tmpfile linked twice");
+    ext4.generic_remove(ROOT_INO, "d3/tmp")
+        .expect("remove file failed");
+    // Released tmpfile
+    let file = ext4
+        .create_unlinked(FILE_MODE)
+        .expect("create tmpfile failed");
+    let fh = ext4.open(file, 0).expect("open handle failed");
+    ext4.release(fh).expect("release handle failed");
+    ext4.open(file, 0).expect_err("tmpfile not freed");
+}
+
+#[test]
+fn write_atomic() {
+    let image = TestImage::new("write_atomic", 64, &[]);
+    let ext4 = image.load();
+    ext4.generic_create(ROOT_INO, "d2", DIR_MODE)
+        .expect("mkdir failed");
+    let old = ext4
+        .generic_write_atomic(ROOT_INO, "d2/config", "old".as_bytes())
+        .expect("atomic write failed");
+    let new = ext4
+        .generic_write_atomic(ROOT_INO, "d2/config", "new content".as_bytes())
+        .expect("atomic write failed");
+    assert_ne!(old, new);
+    let file = ext4
+        .generic_lookup(ROOT_INO, "d2/config")
+        .expect("open failed");
+    assert_eq!(file, new);
+    let mut rbuffer = vec![0u8; 100];
+    let rcount = ext4.read(file, 0, &mut rbuffer).expect("read failed");
+    assert_eq!("new content".as_bytes(), &rbuffer[..rcount]);
+    ext4.getattr(old).expect_err("old file not freed");
+    ext4.generic_remove(ROOT_INO, "d2/config")
+        .expect("remove file failed");
+}
+
+#[test]
+fn write_atomic_open_file() {
+    for (name, args) in [
+        ("write_atomic_open", &[][..]),
+        ("write_atomic_open_nojournal", &["-O", "^has_journal"]),
+    ] {
+        let image = TestImage::new(name, 64, args);
+        let ext4 = image.load();
+        let old = ext4
+            .generic_write_atomic(ROOT_INO, "config", &[1; 3 * BLOCK_SIZE])
+            .expect("atomic write failed");
+        let fh = ext4.open(old, 0).expect("open failed");
+        ext4.generic_write_atomic(ROOT_INO, "config", b"new content")
+            .expect("atomic write failed");
+        // The old file is kept for its open handle
+        let mut buf = vec![0; 4 * BLOCK_SIZE];
+        let count = ext4.read_handle(fh, 0, &mut buf).expect("read failed");
+        assert_eq!(&buf[..count], &[1; 3 * BLOCK_SIZE][..]);
+        ext4.release(fh).expect("release failed");
+        ext4.getattr(old).expect_err("old file not freed");
+        image.finish(ext4);
+    }
+}
+
+#[test]
+fn inode_handle() {
+    let image = TestImage::new("inode_handle", 64, &[]);
+    let ext4 = image.load();
+    let file = ext4
+        .generic_create(ROOT_INO, "f1", FILE_MODE)
+        .expect("create failed");
+    let handle = ext4.inode_handle(file).expect("get handle failed");
+    handle.write_at(0, b"inode handle").expect("write failed");
+    handle.truncate(5).expect("truncate failed");
+    assert_eq!(handle.attr().expect("getattr failed").size, 5);
+    let mut buf = [0; 16];
+    assert_eq!(handle.read_at(0, &mut buf).expect("read failed"), 5);
+    assert_eq!(&buf[..5], b"inode");
+    assert_eq!(handle.read_at(5, &mut buf).expect("read failed"), 0);
+    ext4.inode_handle(ROOT_INO)
+        .expect("get handle failed")
+        .truncate(0)
+        .expect_err("truncate dir should fail");
+}
+
+#[test]
+fn generation() {
+    let image = TestImage::new("generation", 64, &[]);
+    let ext4 = image.load();
+    let ino = ext4
+        .generic_create(ROOT_INO, "gen", FILE_MODE)
+        .expect("create failed");
+    let old_gen = ext4.getattr(ino).expect("getattr failed").generation;
+    ext4.generic_remove(ROOT_INO, "gen").expect("remove failed");
+    let new_ino = ext4
+        .generic_create(ROOT_INO, "gen", FILE_MODE)
+        .expect("create failed");
+    let new_gen = ext4.getattr(new_ino).expect("getattr failed").generation;
+    if new_ino == ino {
+        assert_ne!(old_gen, new_gen);
+        ext4.open_by_handle(ino, old_gen, 0)
+            .expect_err("stale handle opened");
+    }
+    let fh = ext4
+        .open_by_handle(new_ino, new_gen, 0)
+        .expect("open by handle failed");
+    ext4.release(fh).expect("release failed");
+    ext4.generic_remove(ROOT_INO, "gen").expect("remove failed");
+    ext4.open_by_handle(new_ino, new_gen, 0)
+        .expect_err("freed inode opened");
+}
+
+#[test]
+fn credentials() {
+    let image = TestImage::new("credentials", 64, &[]);
+    let mut ext4 = image.load();
+    let dir_mode = InodeMode::DIRECTORY | InodeMode::ALL_RWX | InodeMode::STICKY;
+    let dir = ext4
+        .mkdir(ROOT_INO, "shared", dir_mode)
+        .expect("mkdir failed");
+    ext4.set_credentials(Some(Credentials::new(1000, 1000)));
+    let file_mode = InodeMode::FILE | InodeMode::from_bits_truncate(0o644);
+    let file = ext4.create(dir, "f", file_mode).expect("create failed");
+    let attr = ext4.getattr(file).expect("getattr failed");
+    assert_eq!((attr.uid, attr.gid), (1000, 1000));
+    ext4.write(file, 0, b"owner").expect("write failed");
+    // Another user may read but not write or remove the file
+    ext4.set_credentials(Some(Credentials::new(1001, 1001)));
+    let mut buf = [0; 5];
+    ext4.read(file, 0, &mut buf).expect("read failed");
+    ext4.write(file, 0, b"other")
+        .expect_err("write should be denied");
+    ext4.unlink(dir, "f")
+        .expect_err("unlink in sticky dir should be denied");
+    ext4.create(ROOT_INO, "f", file_mode)
+        .expect_err("create should be denied");
+    // The owner may remove it
+    ext4.set_credentials(Some(Credentials::new(1000, 1000)));
+    ext4.unlink(dir, "f").expect("unlink failed");
+    ext4.set_credentials(None);
+    ext4.rmdir(ROOT_INO, "shared").expect("rmdir failed");
+}
+
+#[test]
+fn umask() {
+    let image = TestImage::new("umask", 64, &[]);
+    let mut ext4 = image.load();
+    let dir_mode = InodeMode::DIRECTORY | InodeMode::ALL_RWX | InodeMode::SET_GID;
+    let dir = ext4
+        .mkdir(ROOT_INO, "sgid", dir_mode)
+        .expect("mkdir failed");
+    ext4.setattr(dir, None, None, Some(100), None, None, None, None, None)
+        .expect("setattr failed");
+    ext4.set_umask(0o022);
+    let file = ext4
+        .create(dir, "f", InodeMode::FILE | InodeMode::ALL_RW)
+        .expect("create failed");
+    let attr = ext4.getattr(file).expect("getattr failed");
+    assert_eq!(attr.perm.bits() & 0o777, 0o644);
+    assert_eq!(attr.gid, 100);
+    let sub = ext4
+        .mkdir(dir, "d", InodeMode::ALL_RWX)
+        .expect("mkdir failed");
+    let attr = ext4.getattr(sub).expect("getattr failed");
+    assert_eq!(attr.perm.bits() & 0o777, 0o755);
+    assert!(attr.perm.contains(InodeMode::SET_GID));
+    assert_eq!(attr.gid, 100);
+    ext4.set_umask(0);
+    ext4.generic_remove(dir, "f").expect("remove failed");
+    ext4.generic_remove(dir, "d").expect("remove failed");
+    ext4.generic_remove(ROOT_INO, "sgid")
+        .expect("remove failed");
+}
+
+#[test]
+fn dir_csum() {
+    let image = TestImage::new("dir_csum", 64, &[]);
+    let ext4 = image.load();
+    let dir = ext4
+        .mkdir(ROOT_INO, "dcsum", InodeMode::ALL_RWX)
+        .expect("mkdir failed");
+    ext4.create(dir, "f", FILE_MODE).expect("create failed");
+    ext4.lookup(dir, "f").expect("lookup failed");
+    // Corrupt the entry block behind the checksum
+    let mut block = [0; BLOCK_SIZE];
+    ext4.raw()
+        .read_mapped_block(dir, 0, &mut block)
+        .expect("raw read failed");
+    let good = block;
+    block[30] ^= 0xff;
+    ext4.raw()
+        .write_mapped_block(dir, 0, &block)
+        .expect("raw write failed");
+    let err = ext4.lookup(dir, "f").expect_err("corruption not detected");
+    assert_eq!(err.code(), ErrCode::EFSBADCRC);
+    ext4.raw()
+        .write_mapped_block(dir, 0, &good)
+        .expect("raw write failed");
+    ext4.generic_remove(dir, "f").expect("remove failed");
+    ext4.generic_remove(ROOT_INO, "dcsum")
+        .expect("remove failed");
+}
+
+#[test]
+fn wear_stats() {
+    let image = TestImage::new("wear_stats", 64, &[]);
+    let mut ext4 = image.load();
+    ext4.set_wall_clock(|| 1_700_000_000);
+    let before = ext4.wear_stats();
+    ext4.generic_write_atomic(ROOT_INO, "wear", &[0xab; 8192])
+        .expect("write failed");
+    ext4.flush_all();
+    let after = ext4.wear_stats();
+    assert!(after.kbytes_written >= before.kbytes_written + 8);
+    assert_eq!(after.write_time, 1_700_000_000);
+}
+
+#[test]
+fn remove_file() {
+    let image = TestImage::new("remove_file", 64, &[]);
+    let ext4 = image.load();
+    ext4.generic_create(ROOT_INO, "d3", DIR_MODE)
+        .expect("mkdir failed");
+    for path in ["d3/f0", "d3/f1", "f1"] {
+        let file = ext4
+            .generic_create(ROOT_INO, path, FILE_MODE)
+            .expect("create failed");
+        ext4.write(file, 0, &[1; 3 * BLOCK_SIZE])
+            .expect("write failed");
+    }
+    for path in ["d3/f0", "d3/f1", "f1"] {
+        ext4.generic_remove(ROOT_INO, path)
+            .expect("remove file failed");
+        ext4.generic_lookup(ROOT_INO, path)
+            .expect_err("file not removed");
+    }
+    ext4.generic_remove(ROOT_INO, "d3/not_exist")
+        .expect_err("remove file failed");
+    ext4.generic_remove(ROOT_INO, "d3")
+        .expect("remove dir failed");
+    image.finish(ext4);
+}
+
+#[test]
+fn prefix_names() {
+    let image = TestImage::new("prefix_names", 64, &[]);
+    let ext4 = image.load();
+    ext4.generic_create(ROOT_INO, "d", DIR_MODE)
+        .expect("mkdir failed");
+    // A name is not matched by its prefixes, nor by longer names
+    let foo = ext4
+        .generic_create(ROOT_INO, "d/foo", FILE_MODE)
+        .expect("create failed");
+    ext4.generic_lookup(ROOT_INO, "d/f")
+        .expect_err("prefix matched");
+    ext4.generic_lookup(ROOT_INO, "d/fo")
+        .expect_err("prefix matched");
+    ext4.generic_lookup(ROOT_INO, "d/fooo")
+        .expect_err("longer name matched");
+    let f = ext4
+        .generic_create(ROOT_INO, "d/f", FILE_MODE)
+        .expect("create failed");
+    let fooo = ext4
+        .generic_create(ROOT_INO, "d/fooo", FILE_MODE)
+        .expect("create failed");
+    assert_eq!(ext4.generic_lookup(ROOT_INO, "d/f").unwrap(), f);
+    assert_eq!(ext4.generic_lookup(ROOT_INO, "d/foo").unwrap(), foo);
+    assert_eq!(ext4.generic_lookup(ROOT_INO, "d/fooo").unwrap(), fooo);
+    // Removing a name leaves the names it is a prefix of
+    ext4.generic_remove(ROOT_INO, "d/f").expect("remove failed");
+    ext4.generic_lookup(ROOT_INO, "d/f")
+        .expect_err("file not removed");
+    assert_eq!(ext4.generic_lookup(ROOT_INO, "d/foo").unwrap(), foo);
+    ext4.generic_remove(ROOT_INO, "d/foo")
+        .expect("remove failed");
+    assert_eq!(ext4.generic_lookup(ROOT_INO, "d/fooo").unwrap(), fooo);
+    image.finish(ext4);
+}
+
+#[cfg(feature = "casefold")]
+#[test]
+fn casefold_prefix_names() {
+    let image = TestImage::new("casefold_prefix_names", 64, &["-O", "casefold"]);
+    image.debugfs_script(&[
+        "mkdir d",
+        // Casefold, keeping the extents flag
+        "set_inode_field d flags 0x40080000",
+        "write /dev/null d/Foo",
+    ]);
+    let ext4 = image.load();
+    let foo = ext4
+        .generic_lookup(ROOT_INO, "d/FOO")
+        .expect("lookup failed");
+    assert_eq!(ext4.generic_lookup(ROOT_INO, "d/foo").unwrap(), foo);
+    ext4.generic_lookup(ROOT_INO, "d/F")
+        .expect_err("prefix matched");
+    ext4.generic_lookup(ROOT_INO, "d/fO")
+        .expect_err("prefix matched");
+    ext4.generic_lookup(ROOT_INO, "d/fooo")
+        .expect_err("longer name matched");
+}
+
+#[cfg(feature = "casefold")]
+#[test]
+fn casefold_unicode() {
+    let image = TestImage::new("casefold_unicode", 64, &["-O", "casefold"]);
+    image.debugfs_script(&[
+        "mkdir d",
+        // Casefold, keeping the extents flag
+        "set_inode_field d flags 0x40080000",
+        "write /dev/null d/Straße",
+        // Precomposed é
+        "write /dev/null d/Caf\u{e9}",
+        // ṩ: s, dot below, dot above
+        "write /dev/null d/s\u{323}\u{307}",
+        "write /dev/null d/\u{d55c}\u{ae00}",
+    ]);
+    let ext4 = image.load();
+    let lookup = |name: &str| ext4.generic_lookup(ROOT_INO, &format!("d/{}", name));
+    // Full case folding
+    let strasse = lookup("Straße").expect("lookup failed");
+    assert_eq!(lookup("STRASSE").unwrap(), strasse);
+    assert_eq!(lookup("strasse").unwrap(), strasse);
+    assert_eq!(lookup("STRA\u{1e9e}E").unwrap(), strasse);
+    lookup("STRASE").expect_err("shorter name matched");
+    // Canonical decomposition and order of combining marks
+    let cafe = lookup("caf\u{e9}").expect("lookup failed");
+    assert_eq!(lookup("CAFE\u{301}").unwrap(), cafe);
+    assert_eq!(lookup("Caf\u{c9}").unwrap(), cafe);
+    lookup("cafe").expect_err("accent ignored");
+    let s = lookup("\u{1e69}").expect("lookup failed");
+    assert_eq!(lookup("S\u{307}\u{323}").unwrap(), s);
+    assert_eq!(lookup("\u{1e60}\u{323}").unwrap(), s);
+    // Hangul syllables and their jamo
+    let hangul = lookup("\u{d55c}\u{ae00}").expect("lookup failed");
+    assert_eq!(
+        lookup("\u{1112}\u{1161}\u{11ab}\u{1100}\u{1173}\u{11af}").unwrap(),
+        hangul
+    );
+    // Default ignorable code points
+    assert_eq!(lookup("Caf\u{200b}\u{e9}").unwrap(), cafe);
+}
+
+#[test]
+fn xattr() {
+    let image = TestImage::new("xattr", 64, &[]);
+    let ext4 = image.load();
+    let file = ext4
+        .generic_create(ROOT_INO, "f2", FILE_MODE)
+        .expect("Create failed");
+    ext4.setxattr(file, "user.testone", "hello world".as_bytes())
+        .expect("setxattr failed");
+    ext4.setxattr(file, "user.testtwo", "world hello".as_bytes())
+        .expect("setxattr failed");
+
+    let names = ext4.listxattr(file).expect("listxattr failed");
+    assert_eq!(names, vec!["user.testone", "user.testtwo"]);
+
+    let value = ext4
+        .getxattr(file, "user.testone")
+        .expect("getxattr failed");
+    assert_eq!(value, "hello world".as_bytes());
+    let value = ext4
+        .getxattr(file, "user.testtwo")
+        .expect("getxattr failed");
+    assert_eq!(value, "world hello".as_bytes());
+
+    ext4.removexattr(file, "user.testone")
+        .expect("removexattr failed");
+    ext4.getxattr(file, "user.testone")
+        .expect_err("getxattr failed");
+    let names = ext4.listxattr(file).expect("listxattr failed");
+    assert_eq!(names, vec!["user.testtwo"]);
+}
+
+#[test]
+fn unmount() {
+    let image = TestImage::new("unmount", 64, &[]);
+    let field = |name: &str| {
+        let stats = image.debugfs(false, "stats");
+        let line = stats.lines().find(|line| line.starts_with(name)).unwrap();
+        line[name.len()..].trim().to_string()
+    };
+    let ext4 = image.load();
+    assert_eq!(field("Filesystem state:"), "not clean");
+    assert_eq!(field("Mount count:"), "1");
+    ext4.generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    ext4.unmount();
+    assert_eq!(field("Filesystem state:"), "clean");
+    image.fsck();
+    let ext4 = image.load();
+    assert_eq!(field("Mount count:"), "2");
+    ext4.generic_lookup(ROOT_INO, "f").expect("lookup failed");
+}
+
+#[test]
+fn error_policy() {
+    let image = TestImage::new("error_policy", 64, &[]);
+    let mut ext4 = image.load();
+    assert_eq!(ext4.error_policy(), ErrorPolicy::Continue);
+    ext4.set_error_policy(ErrorPolicy::RemountRo);
+    // e2fsprogs only shows the details of errors with a time
+    ext4.set_wall_clock(|| 1_700_000_000);
+    let dir = ext4
+        .mkdir(ROOT_INO, "d", InodeMode::ALL_RWX)
+        .expect("mkdir failed");
+    ext4.create(dir, "f", FILE_MODE).expect("create failed");
+    let mut block = [0; BLOCK_SIZE];
+    ext4.raw()
+        .read_mapped_block(dir, 0, &mut block)
+        .expect("raw read failed");
+    block[30] ^= 0xff;
+    ext4.raw()
+        .write_mapped_block(dir, 0, &block)
+        .expect("raw write failed");
+    assert!(!ext4.is_read_only());
+    // The error is recorded and the instance becomes read-only
+    let err = ext4.lookup(dir, "f").expect_err("corruption not detected");
+    assert_eq!(err.code(), ErrCode::EFSBADCRC);
+    assert!(ext4.is_read_only());
+    let err = ext4
+        .create(ROOT_INO, "g", FILE_MODE)
+        .expect_err("write to read-only filesystem");
+    assert_eq!(err.code(), ErrCode::EROFS);
+    let stats = image.debugfs(false, "stats");
+    assert!(
+        stats.contains("FS Error count:"),
+        "error not recorded:\n{}",
+        stats
+    );
+    assert!(
+        stats.contains("dir_read_block"),
+        "error function not recorded:\n{}",
+        stats
+    );
+}
+
+#[test]
+fn malformed_metadata() {
+    let image = TestImage::new("malformed_metadata", 64, &[]);
+    let dir = TestDir::new("malformed_metadata");
+    let data = dir.path().join("data");
+    std::fs::write(&data, [1; 4 * BLOCK_SIZE]).unwrap();
+    image.debugfs_script(&[&format!("write {} f", data.display()), "write /dev/null g"]);
+    {
+        let ext4 = image.load();
+        let g = ext4.lookup(ROOT_INO, "g").expect("lookup failed");
+        ext4.setxattr(g, "user.a", b"value")
+            .expect("setxattr failed");
+        ext4.flush_all();
+    }
+    // More entries in the extent root than it holds, and an xattr block
+    // without magic
+    let stat = image.debugfs(false, "stat g");
+    let acl = stat.split("File ACL: ").nth(1).unwrap();
+    let acl = acl.split_whitespace().next().unwrap();
+    image.debugfs_script(&[
+        "set_inode_field f block[0] 0x0005F30A",
+        &format!("zap_block -l 4 -p 0 {}", acl),
+    ]);
+    let ext4 = image.load();
+    let f = ext4.lookup(ROOT_INO, "f").expect("lookup failed");
+    let mut buf = [0; BLOCK_SIZE];
+    let err = ext4
+        .read(f, 0, &mut buf)
+        .expect_err("corruption not detected");
+    assert_eq!(err.code(), ErrCode::EFSCORRUPTED);
+    let err = ext4.write(f, 0, b"x").expect_err("corruption not detected");
+    assert_eq!(err.code(), ErrCode::EFSCORRUPTED);
+    let g = ext4.lookup(ROOT_INO, "g").expect("lookup failed");
+    let err = ext4
+        .getxattr(g, "user.a")
+        .expect_err("corruption not detected");
+    assert_eq!(err.code(), ErrCode::EFSCORRUPTED);
+    let err = ext4.listxattr(g).expect_err("corruption not detected");
+    assert_eq!(err.code(), ErrCode::EFSCORRUPTED);
+    let err = ext4
+        .setxattr(g, "user.b", b"x")
+        .expect_err("corruption not detected");
+    assert_eq!(err.code(), ErrCode::EFSCORRUPTED);
+}
+
+#[test]
+fn corrupted_images() {
+    for (name, args) in [
+        ("corrupted_csum", &[][..]),
+        ("corrupted_no_csum", &["-O", "^metadata_csum"][..]),
+    ] {
+        let image = TestImage::new(name, 16, args);
+        {
+            let ext4 = image.load();
+            ext4.generic_create(ROOT_INO, "d", DIR_MODE)
+                .expect("mkdir failed");
+            let f = ext4
+                .generic_create(ROOT_INO, "d/f", FILE_MODE)
+                .expect("create failed");
+            ext4.write(f, 0, &[1; 3 * BLOCK_SIZE])
+                .expect("write failed");
+            ext4.setxattr(f, "user.a", &[2; 1000])
+                .expect("setxattr failed");
+            ext4.flush_all();
+        }
+        // The inode table block holding the first inodes, and the blocks
+        // of the directories
+        let imap = image.debugfs(false, "imap <2>");
+        let located = imap.split("located at block ").nth(1).unwrap();
+        let table = located.split(',').next().unwrap().parse::<usize>().unwrap();
+        let root = image
+            .debugfs(false, "bmap <2> 0")
+            .trim()
+            .parse::<usize>()
+            .unwrap();
+        let dir = image
+            .debugfs(false, "bmap d 0")
+            .trim()
+            .parse::<usize>()
+            .unwrap();
+        let bytes = std::fs::read(image.path()).unwrap();
+        let mut rng = Rng::new(1);
+        for _ in 0..100 {
+            let mut bytes = bytes.clone();
+            let block = [table, root, dir][rng.below(3) as usize];
+            for _ in 0..1 + rng.below(8) {
+                let pos = block * BLOCK_SIZE + rng.below(BLOCK_SIZE as u64) as usize;
+                bytes[pos] = rng.next() as u8;
+            }
+            // Any operation may fail, none may panic
+            let dev = Arc::new(MemBlockDevice::from_image(&bytes));
+            let Ok(ext4) = Ext4::load(dev) else {
+                continue;
+            };
+            let _ = ext4.listdir(ROOT_INO);
+            if let Ok(f) = ext4.generic_lookup(ROOT_INO, "d/f") {
+                let mut buf = [0; BLOCK_SIZE];
+                let _ = ext4.read(f, 0, &mut buf);
+                let _ = ext4.write(f, 2 * BLOCK_SIZE as u64, b"x");
+                let _ = ext4.getxattr(f, "user.a");
+                let _ = ext4.setxattr(f, "user.b", b"x");
+            }
+            let _ = ext4.generic_create(ROOT_INO, "d/g", FILE_MODE);
+            let _ = ext4.generic_remove(ROOT_INO, "d/f");
+            let _ = ext4.generic_remove(ROOT_INO, "d/g");
+            let _ = ext4.generic_remove(ROOT_INO, "d");
+        }
+    }
+}
+
+#[test]
+fn unsupported_features() {
+    let image = TestImage::new(
+        "unsupported_features",
+        64,
+        &["-O", "bigalloc", "-C", "16384"],
+    );
+    let dir = TestDir::new("unsupported_features");
+    let data = dir.path().join("data");
+    std::fs::write(&data, b"extract me").unwrap();
+    image.debugfs(true, &format!("write {} f", data.display()));
+    let mount_count = |image: &TestImage| {
+        let output = image.dumpe2fs();
+        let line = output.lines().find(|line| line.starts_with("Mount count:"));
+        line.map(str::to_owned)
+    };
+    let before = mount_count(&image);
+    let ext4 = image.load();
+    assert!(ext4.is_read_only());
+    let unsupported = ext4.unsupported_features();
+    assert_eq!(unsupported.ro_compat, FeatureRoCompat::BIGALLOC);
+    assert!(unsupported.incompat.is_empty());
+    // Files can still be read
+    let file = ext4.lookup(ROOT_INO, "f").expect("lookup failed");
+    let mut buf = [0; 10];
+    ext4.read(file, 0, &mut buf).expect("read failed");
+    assert_eq!(&buf, b"extract me");
+    let err = ext4
+        .create(ROOT_INO, "g", FILE_MODE)
+        .expect_err("write to read-only filesystem");
+    assert_eq!(err.code(), ErrCode::EROFS);
+    image.finish(ext4);
+    // Nothing was written, not even the mount count
+    assert_eq!(mount_count(&image), before);
+    assert!(TestImage::new("supported_features", 64, &[])
+        .load()
+        .unsupported_features()
+        .is_empty());
+}
+
+#[test]
+fn image_features() {
+    let image = TestImage::new("image_features", 64, &["-O", "^metadata_csum,quota"]);
+    let ext4 = image.load();
+    let features = ext4.features();
+    assert!(features.has_extents() && features.has_64bit() && features.has_filetype());
+    assert!(features.has_quota());
+    assert!(!features.has_metadata_csum());
+    assert!(!features.has_bigalloc());
+    assert!(features.ro_compat.contains(FeatureRoCompat::QUOTA));
+    image.finish(ext4);
+    let image = TestImage::new("image_features_ext2", 64, &["-t", "ext2"]);
+    let ext4 = image.load();
+    let features = ext4.features();
+    assert!(!features.has_extents() && !features.has_journal());
+    image.finish(ext4);
+}
+
+/// Mark inodes as encrypted with an encryption context, as the kernel
+/// would. `debugfs` stores the context without the encryption name index,
+/// which is patched in the inode before its checksum is recomputed.
+fn encrypt_inodes(image: &TestImage, paths: &[&str], context: &[u8]) {
+    let dir = TestDir::new("encrypt_inodes");
+    let ctx = dir.path().join("ctx");
+    std::fs::write(&ctx, context).unwrap();
+    for path in paths {
+        image.debugfs_script(&[
+            &format!("set_inode_field {} flags 0x80800", path),
+            &format!("ea_set -f {} {} c", ctx.display(), path),
+        ]);
+        let imap = image.debugfs(false, &format!("imap {}", path));
+        let located = imap.split("located at block ").nth(1).unwrap();
+        let (block, offset) = located.trim().split_once(", offset 0x").unwrap();
+        let pos = block.parse::<usize>().unwrap() * BLOCK_SIZE
+            + usize::from_str_radix(offset, 16).unwrap();
+        let mut bytes = std::fs::read(image.path()).unwrap();
+        let record = &mut bytes[pos..pos + 256];
+        // The entry of "c": name length 1, name index 0, then the name
+        let entry = (128..240)
+            .find(|&i| record[i] == 1 && record[i + 1] == 0 && record[i + 16] == b'c')
+            .unwrap();
+        record[entry + 1] = 9;
+        std::fs::write(image.path(), bytes).unwrap();
+        let output = std::process::Command::new("debugfs")
+            .args(["-n", "-w", "-R"])
+            .arg(format!("set_inode_field {} checksum calc", path))
+            .arg(image.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+    }
+}
+
+/// Decrypts by reversing names and flipping the bits of contents.
+struct ReverseCrypto;
+
+impl CryptoProvider for ReverseCrypto {
+    fn decrypt_block(
+        &self,
+        _policy: &EncryptionPolicy,
+        _inode: u32,
+        _iblock: u32,
+        data: &mut [u8],
+    ) -> Result<()> {
+        data.iter_mut().for_each(|b| *b = !*b);
+        Ok(())
+    }
+
+    fn decrypt_name(
+        &self,
+        _policy: &EncryptionPolicy,
+        _inode: u32,
+        name: &[u8],
+    ) -> Result<Vec<u8>> {
+        Ok(name.iter().rev().copied().collect())
+    }
+}
+
+#[test]
+fn encryption() {
+    let image = TestImage::new("encryption", 64, &["-O", "encrypt"]);
+    let dir = TestDir::new("encryption");
+    let data = dir.path().join("data");
+    let plain = b"secret data";
+    std::fs::write(&data, plain.map(|b| !b)).unwrap();
+    // Names of encrypted directories are at least 16 bytes long
+    let name = "fedcba9876543210";
+    image.debugfs_script(&["mkdir d", &format!("write {} d/{}", data.display(), name)]);
+    let mut context = vec![1, 1, 4, 0];
+    context.extend_from_slice(b"AAAAAAAA");
+    context.extend_from_slice(&[7; 16]);
+    encrypt_inodes(&image, &["d", &format!("d/{}", name)], &context);
+    let mut ext4 = image.load();
+    assert_eq!(ext4.encryption_policy(ROOT_INO).unwrap(), None);
+    let d = ext4.lookup(ROOT_INO, "d").expect("lookup failed");
+    let policy = ext4.encryption_policy(d).unwrap().expect("no policy");
+    assert_eq!(policy.version, 1);
+    assert_eq!((policy.contents_mode, policy.filenames_mode), (1, 4));
+    assert_eq!(policy.master_key, b"AAAAAAAA");
+    assert_eq!(policy.nonce, [7; 16]);
+    // Without a key, names are raw and contents cannot be read
+    let file = ext4.lookup(d, name).expect("lookup failed");
+    let entries = ext4.listdir(d).expect("listdir failed");
+    assert!(entries.iter().any(|e| e.name() == name));
+    let mut buf = [0; 11];
+    let err = ext4.read(file, 0, &mut buf).unwrap_err();
+    assert_eq!(err.code(), ErrCode::ENOKEY);
+    // Encrypted files and directories are not modified
+    let err = ext4.write(file, 0, b"x").unwrap_err();
+    assert_eq!(err.code(), ErrCode::ENOKEY);
+    let err = ext4.create(d, "new", FILE_MODE).unwrap_err();
+    assert_eq!(err.code(), ErrCode::ENOKEY);
+    let err = ext4.unlink(d, name).unwrap_err();
+    assert_eq!(err.code(), ErrCode::ENOKEY);
+    // With a key, names and contents are decrypted
+    ext4.set_crypto_provider(Some(Arc::new(ReverseCrypto)));
+    let decrypted: String = name.chars().rev().collect();
+    let entries = ext4.listdir(d).expect("listdir failed");
+    assert!(entries.iter().any(|e| e.name() == decrypted));
+    assert_eq!(ext4.lookup(d, &decrypted).expect("lookup failed"), file);
+    let n = ext4.read(file, 0, &mut buf).expect("read failed");
+    assert_eq!(n, plain.len());
+    assert_eq!(&buf, plain);
+    let err = ext4.write(file, 0, b"x").unwrap_err();
+    assert_eq!(err.code(), ErrCode::ENOKEY);
+    image.finish(ext4);
+}
+
+/// Hashes with four FNV-1a lanes into a 32-byte digest, standing in for
+/// SHA-256.
+struct FnvHasher;
+
+impl VerityHasher for FnvHasher {
+    fn hash(&self, algorithm: u8, data: &[u8]) -> Result<Vec<u8>> {
+        assert_eq!(algorithm, 1);
+        let mut digest = Vec::new();
+        for lane in 0..4u64 {
+            let mut h = 0xcbf29ce484222325 ^ lane;
+            for &b in data {
+                h = (h ^ b as u64).wrapping_mul(0x100000001b3);
+            }
+            digest.extend_from_slice(&h.to_le_bytes());
+        }
+        Ok(digest)
+    }
+}
+
+/// Build the contents of a verity file as the kernel lays them out: the
+/// data, the Merkle tree from the next 64 KiB boundary, root level first,
+/// then the descriptor with its size in the last 4 bytes of its block.
+fn verity_contents(data: &[u8], salt: &[u8]) -> Vec<u8> {
+    let mut padded_salt = salt.to_vec();
+    if !salt.is_empty() {
+        padded_salt.resize(64, 0);
+    }
+    let hash = |block: &[u8]| {
+        FnvHasher
+            .hash(1, &[&padded_salt[..], block].concat())
+            .unwrap()
+    };
+    // Levels from the leaves up, each a sequence of zero-padded blocks
+    let mut levels: Vec<Vec<u8>> = Vec::new();
+    let mut blocks: Vec<Vec<u8>> = data.chunks(BLOCK_SIZE).map(<[u8]>::to_vec).collect();
+    loop {
+        let mut level = Vec::new();
+        for block in &blocks {
+            let mut block = block.clone();
+            block.resize(BLOCK_SIZE, 0);
+            level.extend_from_slice(&hash(&block));
+        }
+        level.resize(level.len().next_multiple_of(BLOCK_SIZE), 0);
+        blocks = level.chunks(BLOCK_SIZE).map(<[u8]>::to_vec).collect();
+        levels.push(level);
+        if blocks.len() == 1 {
+            break;
+        }
+    }
+    let root_hash = hash(&blocks[0]);
+    let mut contents = data.to_vec();
+    contents.resize(data.len().next_multiple_of(65536), 0);
+    for level in levels.iter().rev() {
+        contents.extend_from_slice(level);
+    }
+    let mut desc = vec![0; BLOCK_SIZE];
+    desc[..4].copy_from_slice(&[1, 1, 12, salt.len() as u8]);
+    desc[8..16].copy_from_slice(&(data.len() as u64).to_le_bytes());
+    desc[16..48].copy_from_slice(&root_hash);
+    desc[80..80 + salt.len()].copy_from_slice(salt);
+    desc[BLOCK_SIZE - 4..].copy_from_slice(&256u32.to_le_bytes());
+    contents.extend_from_slice(&desc);
+    contents
+}
+
+#[test]
+fn verity() {
+    let image = TestImage::new("verity", 64, &["-O", "verity"]);
+    let dir = TestDir::new("verity");
+    let path = dir.path().join("contents");
+    // Two leaf blocks, and a partial last data block
+    let size = 200 * BLOCK_SIZE - 1000;
+    let data: Vec<u8> = (0..size).map(|i| (i * 7 + 3) as u8).collect();
+    std::fs::write(&path, verity_contents(&data, b"salt")).unwrap();
+    image.debugfs_script(&[
+        &format!("write {} f", path.display()),
+        &format!("set_inode_field f size {}", size),
+        "set_inode_field f flags 0x180000",
+    ]);
+    let mut ext4 = image.load();
+    let file = ext4.lookup(ROOT_INO, "f").expect("lookup failed");
+    assert!(ext4.is_verity(file).unwrap());
+    assert!(!ext4.is_verity(ROOT_INO).unwrap());
+    let desc = ext4.verity_descriptor(file).unwrap().unwrap();
+    assert_eq!((desc.hash_algorithm, desc.block_size), (1, BLOCK_SIZE));
+    assert_eq!(desc.data_size, size as u64);
+    assert_eq!(desc.salt, b"salt");
+    // Verified reads of the data only, not of the tree past it
+    ext4.set_verity_hasher(Some(Arc::new(FnvHasher)));
+    let mut buf = vec![0; size + BLOCK_SIZE];
+    let n = ext4.read(file, 0, &mut buf).expect("read failed");
+    assert_eq!(&buf[..n], &data[..]);
+    // Neither written nor resized
+    let err = ext4.write(file, 0, b"x").unwrap_err();
+    assert_eq!(err.code(), ErrCode::EPERM);
+    let err = ext4.write(file, size as u64, b"x").unwrap_err();
+    assert_eq!(err.code(), ErrCode::EPERM);
+    for new_size in [0, size as u64 + 1] {
+        let res = ext4.setattr(
+            file,
+            None,
+            None,
+            None,
+            Some(new_size),
+            None,
+            None,
+            None,
+            None,
+        );
+        assert_eq!(res.unwrap_err().code(), ErrCode::EPERM);
+    }
+    // Corrupted data fails verification, unless no hasher is set
+    let raw = ext4.raw();
+    let mut block = [0; BLOCK_SIZE];
+    raw.read_mapped_block(file, 150, &mut block)
+        .expect("raw read failed");
+    block[10] ^= 1;
+    raw.write_mapped_block(file, 150, &block)
+        .expect("raw write failed");
+    let offset = 150 * BLOCK_SIZE as u64;
+    let err = ext4.read(file, offset, &mut buf[..10]).unwrap_err();
+    assert_eq!(err.code(), ErrCode::EIO);
+    ext4.read(file, 0, &mut buf[..BLOCK_SIZE])
+        .expect("read failed");
+    ext4.set_verity_hasher(None);
+    ext4.read(file, offset, &mut buf[..BLOCK_SIZE])
+        .expect("read failed");
+    assert_eq!(buf[..BLOCK_SIZE], block);
+    image.finish(ext4);
+}
+
+#[test]
+fn lost_and_found() {
+    let image = TestImage::new("lost_and_found", 64, &[]);
+    image.debugfs(true, "rmdir lost+found");
+    let ext4 = image.load();
+    let sub = ext4
+        .generic_create(ROOT_INO, "d/sub", DIR_MODE)
+        .expect("mkdir failed");
+    ext4.finalize();
+    drop(ext4);
+    // Disconnect "d/sub" without fixing the link counts
+    image.debugfs(true, "unlink /d/sub");
+    let ext4 = image.load();
+    // An orphaned tmpfile
+    let file = ext4
+        .create_unlinked(FILE_MODE)
+        .expect("create tmpfile failed");
+    let lpf = ext4.lost_and_found().expect("create lost+found failed");
+    assert_eq!(
+        ext4.lookup(ROOT_INO, "lost+found").expect("lookup failed"),
+        lpf
+    );
+    assert_eq!(
+        ext4.lost_and_found().expect("lookup lost+found failed"),
+        lpf
+    );
+    let name = ext4.reattach(sub).expect("reattach dir failed");
+    assert_eq!(name, format!("#{}", sub));
+    assert_eq!(ext4.lookup(sub, "..").expect("lookup failed"), lpf);
+    let name = ext4.reattach(file).expect("reattach file failed");
+    assert_eq!(ext4.lookup(lpf, &name).expect("lookup failed"), file);
+    let err = ext4.reattach(file).expect_err("reattached twice");
+    assert_eq!(err.code(), ErrCode::EEXIST);
+    let err = ext4.reattach(ROOT_INO).expect_err("reattached root");
+    assert_eq!(err.code(), ErrCode::EINVAL);
+    image.finish(ext4);
+}
+
+#[test]
+fn append_extends_extent() {
+    let image = TestImage::new("append_extends_extent", 64, &[]);
+    let ext4 = image.load();
+    let file = ext4
+        .generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    // One block per write, then past a hole
+    for i in (0..64).chain(100..132) {
+        ext4.write(file, i * BLOCK_SIZE as u64, &[i as u8; BLOCK_SIZE])
+            .expect("write failed");
+    }
+    let root = ext4.dump_tree(file).expect("dump tree failed");
+    assert_eq!(root.depth, 0);
+    let extents: Vec<_> = root
+        .entries
+        .iter()
+        .map(|entry| match entry {
+            ExtentTreeEntry::Extent {
+                start_lblock,
+                block_count,
+                ..
+            } => (*start_lblock, *block_count),
+            ExtentTreeEntry::Index { .. } => panic!("index in a leaf"),
+        })
+        .collect();
+    assert_eq!(extents, [(0, 64), (100, 32)]);
+    image.finish(ext4);
+}
+
+#[test]
+fn raw_mapped_blocks() {
+    let image = TestImage::new("raw_mapped_blocks", 64, &[]);
+    let ext4 = image.load();
+    let file = ext4
+        .generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    // Blocks 0 and 4, with holes between them and a partial tail
+    ext4.write(file, 0, &[1; BLOCK_SIZE]).expect("write failed");
+    ext4.write(file, 4 * BLOCK_SIZE as u64, &[5; 100])
+        .expect("write failed");
+    let raw = ext4.raw();
+    raw.write_mapped_block(file, 2, &[3; BLOCK_SIZE])
+        .expect("raw write failed");
+    raw.write_mapped_block(file, 5, &[6; BLOCK_SIZE])
+        .expect("raw write failed");
+    let err = raw
+        .write_mapped_block(file, 7, &[8; BLOCK_SIZE])
+        .unwrap_err();
+    assert_eq!(err.code(), ErrCode::EINVAL);
+    let mut block = [0; BLOCK_SIZE];
+    for (lblock, fill) in [(0, 1), (2, 3), (5, 6)] {
+        raw.read_mapped_block(file, lblock, &mut block)
+            .expect("raw read failed");
+        assert_eq!(block, [fill; BLOCK_SIZE]);
+    }
+    raw.read_mapped_block(file, 4, &mut block)
+        .expect("raw read failed");
+    assert!(block[..100].iter().all(|&b| b == 5));
+    let err = raw.read_mapped_block(file, 3, &mut block).unwrap_err();
+    assert_eq!(err.code(), ErrCode::ENOENT);
+    assert_eq!(raw.mapped_block_count(file).unwrap(), 4);
+    ext4.setattr(
+        file,
+        None,
+        None,
+        None,
+        Some(6 * BLOCK_SIZE as u64),
+        None,
+        None,
+        None,
+        None,
+    )
+    .expect("setattr failed");
+    image.finish(ext4);
+}
+
+/// Inode whose block mappings were last invalidated, `u32::MAX` for all.
+static INVALIDATED: AtomicU32 = AtomicU32::new(0);
+
+#[test]
+fn block_mapping() {
+    let image = TestImage::new("block_mapping", 64, &[]);
+    let mut ext4 = image.load();
+    ext4.set_mapping_invalidator(Some(|inode| {
+        INVALIDATED.store(inode.unwrap_or(u32::MAX), Ordering::SeqCst);
+    }));
+    let file = ext4
+        .generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    // Blocks 0 to 3 and 8, with a hole between them
+    ext4.write(file, 0, &[1; 4 * BLOCK_SIZE])
+        .expect("write failed");
+    ext4.write(file, 8 * BLOCK_SIZE as u64, &[2; 100])
+        .expect("write failed");
+    let root = ext4.dump_tree(file).expect("dump tree failed");
+    let starts: Vec<_> = root
+        .entries
+        .iter()
+        .map(|entry| match entry {
+            ExtentTreeEntry::Extent { start_pblock, .. } => *start_pblock,
+            ExtentTreeEntry::Index { .. } => panic!("index in a leaf"),
+        })
+        .collect();
+    let mapping = |lblock, pblock, len| BlockMapping {
+        lblock,
+        pblock,
+        len,
+    };
+    let mappings = ext4
+        .get_block_mapping(file, 2..12)
+        .expect("get mapping failed");
+    let expected = [
+        mapping(2, Some(starts[0] + 2), 2),
+        mapping(4, None, 4),
+        mapping(8, Some(starts[1]), 1),
+        mapping(9, None, 3),
+    ];
+    assert_eq!(mappings, expected);
+    assert!(ext4.get_block_mapping(file, 20..20).unwrap().is_empty());
+    // bmap agrees, without allocating holes
+    let free = ext4.super_block_info().free_blocks;
+    assert_eq!(
+        ext4.bmap(file, 3).expect("bmap failed"),
+        Some(starts[0] + 3)
+    );
+    assert_eq!(ext4.bmap(file, 8).expect("bmap failed"), Some(starts[1]));
+    assert_eq!(ext4.bmap(file, 5).expect("bmap failed"), None);
+    assert_eq!(ext4.bmap(file, 100).expect("bmap failed"), None);
+    assert_eq!(ext4.super_block_info().free_blocks, free);
+    assert_eq!(ext4.bmap(ROOT_INO, 0).unwrap_err().code(), ErrCode::EISDIR);
+    let err = ext4.get_block_mapping(ROOT_INO, 0..1).unwrap_err();
+    assert_eq!(err.code(), ErrCode::EISDIR);
+    // Overwriting mapped blocks keeps the mappings
+    INVALIDATED.store(0, Ordering::SeqCst);
+    ext4.write(file, 0, &[3; BLOCK_SIZE]).expect("write failed");
+    assert_eq!(INVALIDATED.load(Ordering::SeqCst), 0);
+    // Filling a hole invalidates them
+    ext4.write(file, 5 * BLOCK_SIZE as u64, &[4; BLOCK_SIZE])
+        .expect("write failed");
+    assert_eq!(INVALIDATED.load(Ordering::SeqCst), file);
+    let mappings = ext4
+        .get_block_mapping(file, 4..6)
+        .expect("get mapping failed");
+    assert_eq!(mappings.len(), 2);
+    assert_eq!(mappings[0], mapping(4, None, 1));
+    assert!(mappings[1].pblock.is_some());
+    // So does freeing the file
+    INVALIDATED.store(0, Ordering::SeqCst);
+    ext4.unlink(ROOT_INO, "f").expect("unlink failed");
+    assert_eq!(INVALIDATED.load(Ordering::SeqCst), file);
+    image.finish(ext4);
+}
+
+#[test]
+fn page_io() {
+    let image = TestImage::new("page_io", 64, &[]);
+    let ext4 = image.load();
+    let file = ext4
+        .generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    // Page 0 holds data, page 1 is a hole, page 2 ends the file
+    ext4.write(file, 0, &[1; BLOCK_SIZE]).expect("write failed");
+    ext4.write(file, 2 * BLOCK_SIZE as u64, &[2; 100])
+        .expect("write failed");
+    let mut page = [9; BLOCK_SIZE];
+    assert!(ext4
+        .read_page(file, 0, &mut page)
+        .expect("read_page failed"));
+    assert_eq!(page, [1; BLOCK_SIZE]);
+    assert!(!ext4
+        .read_page(file, 1, &mut page)
+        .expect("read_page failed"));
+    assert_eq!(page, [0; BLOCK_SIZE]);
+    assert!(ext4
+        .read_page(file, 2, &mut page)
+        .expect("read_page failed"));
+    assert_eq!(page[..100], [2; 100]);
+    assert_eq!(page[100..], [0; BLOCK_SIZE - 100]);
+    assert!(!ext4
+        .read_page(file, 3, &mut page)
+        .expect("read_page failed"));
+    // Writing back stops at the end of file
+    assert_eq!(
+        ext4.write_page(file, 1, &[3; BLOCK_SIZE])
+            .expect("write_page failed"),
+        BLOCK_SIZE
+    );
+    assert_eq!(
+        ext4.write_page(file, 2, &[4; BLOCK_SIZE])
+            .expect("write_page failed"),
+        100
+    );
+    assert_eq!(
+        ext4.write_page(file, 3, &[5; BLOCK_SIZE])
+            .expect("write_page failed"),
+        0
+    );
+    assert_eq!(
+        ext4.getattr(file).expect("getattr failed").size,
+        2 * BLOCK_SIZE as u64 + 100
+    );
+    assert!(ext4
+        .read_page(file, 1, &mut page)
+        .expect("read_page failed"));
+    assert_eq!(page, [3; BLOCK_SIZE]);
+    // A write fault maps a zeroed block in a hole
+    ext4.write(file, 6 * BLOCK_SIZE as u64, &[6; 10])
+        .expect("write failed");
+    let pblock = ext4
+        .get_writable_block(file, 4)
+        .expect("get_writable_block failed");
+    let mappings = ext4
+        .get_block_mapping(file, 4..5)
+        .expect("get mapping failed");
+    assert_eq!(mappings[0].pblock, Some(pblock));
+    assert!(ext4
+        .read_page(file, 4, &mut page)
+        .expect("read_page failed"));
+    assert_eq!(page, [0; BLOCK_SIZE]);
+    assert_eq!(
+        ext4.get_writable_block(file, 0)
+            .expect("get_writable_block failed"),
+        ext4.get_block_mapping(file, 0..1).unwrap()[0]
+            .pblock
+            .unwrap()
+    );
+    let err = ext4
+        .get_writable_block(file, 7)
+        .expect_err("mapped past the end of file");
+    assert_eq!(err.code(), ErrCode::EINVAL);
+    let err = ext4
+        .get_writable_block(ROOT_INO, 0)
+        .expect_err("mapped a directory");
+    assert_eq!(err.code(), ErrCode::EISDIR);
+    image.finish(ext4);
+}
+
+#[test]
+fn pin_block() {
+    let image = TestImage::new("pin_block", 64, &[]);
+    let ext4 = image.load();
+    let file = ext4
+        .generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    // Blocks 0 to 3 and a hole at block 4
+    ext4.write(file, 0, &[1; 4 * BLOCK_SIZE])
+        .expect("write failed");
+    ext4.write(file, 5 * BLOCK_SIZE as u64, &[2; 10])
+        .expect("write failed");
+    let pblock = ext4.pin_block(file, 2).expect("pin failed");
+    assert_eq!(ext4.bmap(file, 2).unwrap(), Some(pblock));
+    assert_eq!(ext4.pin_block(file, 4).unwrap_err().code(), ErrCode::ENOENT);
+    assert_eq!(ext4.pin_block(file, 2).expect("pin failed"), pblock);
+    // Pinned blocks are neither freed nor moved
+    let busy = |res: Result<()>| assert_eq!(res.unwrap_err().code(), ErrCode::EBUSY);
+    busy(ext4.setattr(
+        file,
+        None,
+        None,
+        None,
+        Some(BLOCK_SIZE as u64),
+        None,
+        None,
+        None,
+        None,
+    ));
+    busy(ext4.defragment(file).map(|_| ()));
+    busy(ext4.unlink(ROOT_INO, "f"));
+    assert_eq!(ext4.lookup(ROOT_INO, "f").expect("lookup failed"), file);
+    // Shrinking above them is fine
+    ext4.setattr(
+        file,
+        None,
+        None,
+        None,
+        Some(3 * BLOCK_SIZE as u64),
+        None,
+        None,
+        None,
+        None,
+    )
+    .expect("setattr failed");
+    // Pins are counted
+    ext4.unpin_block(file, 2).expect("unpin failed");
+    busy(ext4.unlink(ROOT_INO, "f"));
+    ext4.unpin_block(file, 2).expect("unpin failed");
+    assert_eq!(
+        ext4.unpin_block(file, 2).unwrap_err().code(),
+        ErrCode::EINVAL
+    );
+    ext4.unlink(ROOT_INO, "f").expect("unlink failed");
+    image.finish(ext4);
+}
+
+/// Number of checksums computed by `bitwise_crc32c`.
+static CRC32C_CALLS: AtomicU32 = AtomicU32::new(0);
+
+/// CRC32C one bit at a time, standing in for a hardware implementation.
+fn bitwise_crc32c(crc: u32, data: &[u8]) -> u32 {
+    CRC32C_CALLS.fetch_add(1, Ordering::SeqCst);
+    let mut crc = crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[test]
+fn crc32c_provider() {
+    let image = TestImage::new("crc32c_provider", 64, &[]);
+    Ext4::set_crc32c(Some(bitwise_crc32c));
+    let ext4 = image.load();
+    let file = ext4
+        .generic_create(ROOT_INO, "d/f", FILE_MODE)
+        .expect("create failed");
+    ext4.write(file, 0, &[1; 3 * BLOCK_SIZE])
+        .expect("write failed");
+    // Metadata checksummed by the provider passes e2fsck
+    image.finish(ext4);
+    Ext4::set_crc32c(None);
+    assert!(CRC32C_CALLS.load(Ordering::SeqCst) > 0);
+}
+
+#[test]
+fn dump() {
+    let image = TestImage::new("dump", 64, &[]);
+    let ext4 = image.load();
+    let dir = ext4
+        .generic_create(ROOT_INO, "d", DIR_MODE)
+        .expect("mkdir failed");
+    let a = ext4.create(dir, "a", FILE_MODE).expect("create failed");
+    let b = ext4.create(dir, "b", FILE_MODE).expect("create failed");
+    // Leave holes so that `a` needs more extents than the root holds
+    for i in 0..8 {
+        ext4.write(a, (2 * i * BLOCK_SIZE) as u64, &[1; BLOCK_SIZE])
+            .expect("write failed");
+        ext4.write(b, (i * BLOCK_SIZE) as u64, &[2; BLOCK_SIZE])
+            .expect("write failed");
+    }
+    let root = ext4.dump_tree(a).expect("dump tree failed");
+    assert_eq!(root.pblock, 0);
+    assert!(root.magic_valid && root.checksum_valid);
+    assert!(root.depth >= 1, "extent root not split");
+    let mut extents = Vec::new();
+    let mut nodes = vec![root];
+    while let Some(node) = nodes.pop() {
+        assert!(node.magic_valid && node.checksum_valid);
+        for entry in node.entries {
+            match entry {
+                ExtentTreeEntry::Index { child, .. } => nodes.push(child),
+                ExtentTreeEntry::Extent {
+                    start_lblock,
+                    block_count,
+                    ..
+                } => extents.push((start_lblock, block_count)),
+            }
+        }
+    }
+    extents.sort();
+    assert_eq!(extents.iter().map(|e| e.1).sum::<u32>(), 8);
+    assert_eq!(extents[0].0, 0);
+    ext4.dump_tree(dir).expect("dump directory tree failed");
+
+    let blocks = ext4.dump_dir(dir).expect("dump dir failed");
+    assert_eq!(blocks.len(), 1);
+    let block = &blocks[0];
+    assert!(block.checksum_valid);
+    assert!(block.tail_checksum.is_some());
+    assert_eq!(block.malformed_at, None);
+    let names: Vec<_> = block.entries.iter().map(|de| de.name.as_slice()).collect();
+    assert_eq!(names, [&b"."[..], b"..", b"a", b"b"]);
+    assert_eq!(block.entries[0].offset, 0);
+    let end = block
+        .entries
+        .last()
+        .map(|de| de.offset + de.rec_len as usize);
+    assert_eq!(end, Some(BLOCK_SIZE - 12));
+    let err = ext4.dump_dir(a).expect_err("dump file as dir");
+    assert_eq!(err.code(), ErrCode::ENOTDIR);
+    image.finish(ext4);
+}
+
+#[test]
+fn inode_flags() {
+    let image = TestImage::new("inode_flags", 64, &[]);
+    let mut ext4 = image.load();
+    let file = ext4
+        .generic_write_atomic(ROOT_INO, "f", b"log")
+        .expect("write failed");
+    assert_eq!(
+        ext4.getflags(file).expect("getflags failed"),
+        InodeFlags::empty()
+    );
+    ext4.setflags(file, InodeFlags::APPEND | InodeFlags::NODUMP)
+        .expect("setflags failed");
+    // Append-only: writes only at the end, no truncation or removal
+    let err = ext4
+        .write(file, 0, b"new")
+        .expect_err("overwrite append-only file");
+    assert_eq!(err.code(), ErrCode::EPERM);
+    ext4.write(file, 3, b"+1").expect("append failed");
+    let err = ext4
+        .setattr(file, None, None, None, Some(0), None, None, None, None)
+        .expect_err("truncate append-only file");
+    assert_eq!(err.code(), ErrCode::EPERM);
+    let err = ext4
+        .unlink(ROOT_INO, "f")
+        .expect_err("unlink append-only file");
+    assert_eq!(err.code(), ErrCode::EPERM);
+    // Immutable: no writes at all
+    ext4.setflags(file, InodeFlags::IMMUTABLE)
+        .expect("setflags failed");
+    let err = ext4
+        .write(file, 5, b"+2")
+        .expect_err("write immutable file");
+    assert_eq!(err.code(), ErrCode::EPERM);
+    ext4.unlink(ROOT_INO, "f")
+        .expect_err("unlink immutable file");
+    // Only root may change the immutable and append-only flags
+    ext4.set_credentials(Some(Credentials::new(1000, 1000)));
+    let err = ext4
+        .setflags(file, InodeFlags::empty())
+        .expect_err("clear flags as user");
+    assert_eq!(err.code(), ErrCode::EPERM);
+    ext4.set_credentials(None);
+    ext4.setflags(file, InodeFlags::NOATIME)
+        .expect("setflags failed");
+    let mut buf = [0; 5];
+    ext4.read(file, 0, &mut buf).expect("read failed");
+    assert_eq!(&buf, b"log+1");
+    image.finish(ext4);
+    // The flags are stored where e2fsprogs expects them
+    let stat = image.debugfs(false, "stat /f");
+    assert!(
+        stat.contains("Flags: 0x80080"),
+        "unexpected flags:\n{}",
+        stat
+    );
+    let ext4 = image.load();
+    ext4.unlink(ROOT_INO, "f").expect("unlink failed");
+}
+
+#[test]
+fn chattr() {
+    let image = TestImage::new("chattr", 64, &[]);
+    let ext4 = image.load();
+    let dir = ext4
+        .mkdir(ROOT_INO, "d", InodeMode::ALL_RWX)
+        .expect("mkdir failed");
+    let file = ext4.create(dir, "f", FILE_MODE).expect("create failed");
+    ext4.write(file, 0, b"data").expect("write failed");
+    // Append-only directory: entries may be added but not removed
+    ext4.setflags(dir, InodeFlags::APPEND)
+        .expect("setflags failed");
+    ext4.create(dir, "g", FILE_MODE)
+        .expect("create in append-only dir failed");
+    let err = ext4
+        .unlink(dir, "g")
+        .expect_err("unlink from append-only dir");
+    assert_eq!(err.code(), ErrCode::EPERM);
+    let err = ext4
+        .rename(dir, "f", ROOT_INO, "f")
+        .expect_err("rename out of append-only dir");
+    assert_eq!(err.code(), ErrCode::EPERM);
+    // Immutable directory: no new entries either
+    ext4.setflags(dir, InodeFlags::IMMUTABLE)
+        .expect("setflags failed");
+    let err = ext4
+        .create(dir, "h", FILE_MODE)
+        .expect_err("create in immutable dir");
+    assert_eq!(err.code(), ErrCode::EPERM);
+    ext4.mkdir(dir, "h", InodeMode::ALL_RWX)
+        .expect_err("mkdir in immutable dir");
+    ext4.link(file, dir, "h")
+        .expect_err("link in immutable dir");
+    ext4.setflags(dir, InodeFlags::empty())
+        .expect("setflags failed");
+    // Immutable file: no rename, link, attributes or xattrs
+    ext4.setflags(file, InodeFlags::IMMUTABLE)
+        .expect("setflags failed");
+    ext4.rename(dir, "f", dir, "f2")
+        .expect_err("rename immutable file");
+    ext4.link(file, dir, "f2").expect_err("link immutable file");
+    ext4.setattr(
+        file,
+        Some(FILE_MODE),
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .expect_err("chmod immutable file");
+    ext4.setxattr(file, "user.a", b"1")
+        .expect_err("setxattr on immutable file");
+    ext4.generic_write_atomic(dir, "f", b"new")
+        .expect_err("replace immutable file");
+    ext4.generic_remove(dir, "f")
+        .expect_err("remove immutable file");
+    ext4.setflags(file, InodeFlags::empty())
+        .expect("setflags failed");
+    ext4.rename(dir, "f", dir, "f2").expect("rename failed");
+    image.finish(ext4);
+}
+
+#[test]
+fn secure_delete() {
+    let image = TestImage::new("secure_delete", 64, &[]);
+    let dev = image.device();
+    let ext4 = Ext4::load(dev.clone()).expect("open ext4 failed");
+    let file = ext4
+        .generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    ext4.write(file, 0, &[9; 2 * BLOCK_SIZE])
+        .expect("write failed");
+    ext4.setflags(file, InodeFlags::SECRM)
+        .expect("setflags failed");
+    let freed = ext4.bmap(file, 1).unwrap().unwrap();
+    // Shrinking zeroes the data past the new end of file
+    ext4.setattr(file, None, None, None, Some(100), None, None, None, None)
+        .expect("truncate failed");
+    let mut block = [0; BLOCK_SIZE];
+    ext4.raw()
+        .read_mapped_block(file, 0, &mut block)
+        .expect("raw read failed");
+    assert!(block[..100].iter().all(|&b| b == 9));
+    assert!(block[100..].iter().all(|&b| b == 0));
+    // The block past the new end of file is freed zeroed
+    assert_eq!(ext4.bmap(file, 1).unwrap(), None);
+    ext4.flush_all();
+    assert_eq!(dev.read_block(freed).data, [0; BLOCK_SIZE]);
+    // Removing flushes the zeroed blocks to the device
+    let flushes = dev.flushes();
+    ext4.unlink(ROOT_INO, "f").expect("unlink failed");
+    assert!(dev.flushes() > flushes);
+    image.finish(ext4);
+}
+
+#[test]
+fn shrink_frees_blocks() {
+    for (name, args) in [
+        ("shrink_extents", &[][..]),
+        ("shrink_block_map", &["-t", "ext2", "-I", "128"][..]),
+    ] {
+        let image = TestImage::new(name, 64, args);
+        let ext4 = image.load();
+        let free = ext4.super_block_info().free_blocks;
+        let file = ext4
+            .generic_create(ROOT_INO, "f", FILE_MODE)
+            .expect("create failed");
+        // Fragmented enough for an extent tree below the root, and past the
+        // double indirect block
+        for i in 0..2000 {
+            ext4.write(file, i * 2 * BLOCK_SIZE as u64, &[5; BLOCK_SIZE])
+                .expect("write failed");
+        }
+        let shrink = |size: u64| {
+            ext4.setattr(file, None, None, None, Some(size), None, None, None, None)
+                .expect("truncate failed");
+        };
+        shrink(1000 * BLOCK_SIZE as u64 + 10);
+        assert!(ext4.bmap(file, 1000).unwrap().is_some());
+        assert_eq!(ext4.bmap(file, 1002).unwrap(), None);
+        let mut buf = [0; 16];
+        assert_eq!(
+            ext4.read(file, 1000 * BLOCK_SIZE as u64, &mut buf)
+                .expect("read failed"),
+            10
+        );
+        assert_eq!(buf[..10], [5; 10]);
+        shrink(0);
+        assert_eq!(ext4.bmap(file, 0).unwrap(), None);
+        assert_eq!(ext4.super_block_info().free_blocks, free);
+        image.finish(ext4);
+    }
+}
+
+#[test]
+fn zero_on_free() {
+    let image = TestImage::new("zero_on_free", 64, &[]);
+    let mut ext4 = image.load();
+    let write_and_remove = |ext4: &Ext4, name: &str| -> Vec<u64> {
+        let file = ext4
+            .generic_create(ROOT_INO, name, FILE_MODE)
+            .expect("create failed");
+        ext4.write(file, 0, &[7; 3 * BLOCK_SIZE])
+            .expect("write failed");
+        let pblocks = (0..3)
+            .map(|i| ext4.bmap(file, i).unwrap().unwrap())
+            .collect();
+        ext4.flush_all();
+        ext4.unlink(ROOT_INO, name).expect("unlink failed");
+        ext4.flush_all();
+        pblocks
+    };
+    // Freed blocks are left as they are by default
+    for pblock in write_and_remove(&ext4, "kept") {
+        assert_eq!(image.device().read_block(pblock).data, [7; BLOCK_SIZE]);
+    }
+    ext4.set_zero_on_free(true);
+    for pblock in write_and_remove(&ext4, "zeroed") {
+        assert_eq!(image.device().read_block(pblock).data, [0; BLOCK_SIZE]);
+    }
+    image.finish(ext4);
+}
+
+#[test]
+fn reuse_freed_blocks() {
+    let image = TestImage::new("reuse_freed_blocks", 64, &[]);
+    let ext4 = image.load();
+    // Freed blocks keep their content
+    let file = ext4
+        .generic_create(ROOT_INO, "junk", FILE_MODE)
+        .expect("create failed");
+    ext4.write(file, 0, &[0xa5; 64 * BLOCK_SIZE])
+        .expect("write failed");
+    let pblock = ext4.bmap(file, 0).unwrap().unwrap();
+    ext4.unlink(ROOT_INO, "junk").expect("unlink failed");
+    ext4.flush_all();
+    assert_eq!(image.device().read_block(pblock).data, [0xa5; BLOCK_SIZE]);
+    // New metadata blocks do not pick it up
+    for i in 0..8 {
+        let dir = ext4
+            .mkdir(ROOT_INO, &format!("d{}", i), DIR_MODE)
+            .expect("mkdir failed");
+        for j in 0..200 {
+            ext4.create(dir, &format!("file_with_a_long_name_{}", j), FILE_MODE)
+                .expect("create failed");
+        }
+        ext4.setxattr(dir, "user.i", &[i as u8])
+            .expect("setxattr failed");
+        assert_eq!(ext4.listxattr(dir).expect("listxattr failed"), ["user.i"]);
+        assert_eq!(ext4.listdir(dir).expect("listdir failed").len(), 202);
+    }
+    image.finish(ext4);
+}
+
+#[test]
+fn block_accounting() {
+    let image = TestImage::new("block_accounting", 64, &[]);
+    let ext4 = image.load();
+    let a = ext4
+        .generic_create(ROOT_INO, "a", FILE_MODE)
+        .expect("create failed");
+    let b = ext4
+        .generic_create(ROOT_INO, "b", FILE_MODE)
+        .expect("create failed");
+    // Interleaved writes fragment both files past the extents the inode holds
+    for i in (0..32).step_by(2).chain((1..32).step_by(2)) {
+        ext4.write(a, (i * BLOCK_SIZE) as u64, &[1; BLOCK_SIZE])
+            .expect("write failed");
+        ext4.write(b, (i * BLOCK_SIZE) as u64, &[2; BLOCK_SIZE])
+            .expect("write failed");
+    }
+    assert!(ext4.dump_tree(a).expect("dump tree failed").depth > 0);
+    assert_eq!(ext4.raw().mapped_block_count(a).unwrap(), 32);
+    // Extent tree and xattr blocks are counted
+    let blocks = ext4.getattr(a).expect("getattr failed").blocks;
+    assert!(blocks > 32 * (BLOCK_SIZE as u64 / 512), "blocks {}", blocks);
+    ext4.setxattr(a, "user.a", b"a").expect("setxattr failed");
+    assert_eq!(
+        ext4.getattr(a).expect("getattr failed").blocks,
+        blocks + BLOCK_SIZE as u64 / 512
+    );
+    let dir = ext4.mkdir(ROOT_INO, "d", DIR_MODE).expect("mkdir failed");
+    ext4.setxattr(dir, "user.d", b"d").expect("setxattr failed");
+    // Directory sizes cover their blocks
+    for i in 0..100 {
+        ext4.create(dir, &format!("{:0>100}", i), FILE_MODE)
+            .expect("create failed");
+    }
+    let attr = ext4.getattr(dir).expect("getattr failed");
+    assert_eq!(attr.size % BLOCK_SIZE as u64, 0);
+    assert_eq!(
+        attr.blocks,
+        (attr.size / BLOCK_SIZE as u64 + 1) * (BLOCK_SIZE as u64 / 512)
+    );
+    ext4.defragment(b).expect("defragment failed");
+    image.finish(ext4);
+}
+
+#[test]
+fn dir_holes() {
+    let image = TestImage::new("dir_holes", 64, &[]);
+    let ext4 = image.load();
+    let dir = ext4.mkdir(ROOT_INO, "d", DIR_MODE).expect("mkdir failed");
+    let name = |i: usize| format!("{:0200}", i);
+    for i in 0..50 {
+        ext4.create(dir, &name(i), FILE_MODE)
+            .expect("create failed");
+    }
+    let blocks = ext4.dump_dir(dir).expect("dump dir failed");
+    assert_eq!(blocks.len(), 3);
+    let hole_entries = blocks[1].entries.iter().filter(|e| e.inode != 0).count();
+    image.finish(ext4);
+    // Punch a hole in the middle of the directory
+    image.debugfs(true, "punch d 1 1");
+    let ext4 = image.load();
+    let blocks = ext4.dump_dir(dir).expect("dump dir failed");
+    assert_eq!(blocks.iter().map(|b| b.lblock).collect::<Vec<_>>(), [0, 2]);
+    let entries = ext4.listdir(dir).expect("listdir failed");
+    assert_eq!(entries.len(), 2 + 50 - hole_entries);
+    for entry in entries.iter().skip(2) {
+        ext4.lookup(dir, &entry.name()).expect("lookup failed");
+    }
+    // Entries are added, renamed and removed around the hole
+    let last = entries.last().unwrap().name();
+    ext4.rename(dir, &last, dir, "renamed")
+        .expect("rename failed");
+    ext4.lookup(dir, "renamed").expect("lookup failed");
+    ext4.unlink(dir, "renamed").expect("unlink failed");
+    ext4.create(dir, "new", FILE_MODE).expect("create failed");
+    ext4.lookup(dir, "new").expect("lookup failed");
+    assert_eq!(
+        ext4.listdir(dir).expect("listdir failed").len(),
+        entries.len()
+    );
+}
+
+#[test]
+fn transaction() {
+    let image = TestImage::new("transaction", 64, &[]);
+    let ext4 = image.load();
+    let file = ext4
+        .with_transaction(|txn| {
+            let dir = txn.mkdir(ROOT_INO, "etc", DIR_MODE)?;
+            let file = txn.create(dir, "conf.tmp", FILE_MODE)?;
+            txn.write(file, 0, b"key=value")?;
+            txn.rename(dir, "conf.tmp", dir, "conf")?;
+            Ok(file)
+        })
+        .expect("transaction failed");
+    // A failing transaction leaves nothing behind
+    let err = ext4
+        .with_transaction(|txn| {
+            txn.create(ROOT_INO, "partial", FILE_MODE)?;
+            txn.lookup(ROOT_INO, "missing")
+        })
+        .expect_err("transaction succeeded");
+    assert_eq!(err.code(), ErrCode::ENOENT);
+    assert_eq!(
+        ext4.lookup(ROOT_INO, "partial").unwrap_err().code(),
+        ErrCode::ENOENT
+    );
+    image.finish(ext4);
+
+    let ext4 = image.load();
+    assert_eq!(ext4.generic_lookup(ROOT_INO, "etc/conf").unwrap(), file);
+    let mut buf = [0; 9];
+    ext4.read(file, 0, &mut buf).expect("read failed");
+    assert_eq!(&buf, b"key=value");
+    image.finish(ext4);
+}
+
+#[test]
+fn concurrent_transaction() {
+    let image = TestImage::new("concurrent_transaction", 64, &[]);
+    let ext4 = image.load();
+    let started = std::sync::Barrier::new(2);
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            let err = ext4
+                .with_transaction(|txn| {
+                    txn.create(ROOT_INO, "dropped", FILE_MODE)?;
+                    started.wait();
+                    // Give the other thread time to write while this runs
+                    std::thread::sleep(std::time::Duration::from_millis(200));
+                    txn.lookup(ROOT_INO, "missing")
+                })
+                .expect_err("transaction succeeded");
+            assert_eq!(err.code(), ErrCode::ENOENT);
+        });
+        s.spawn(|| {
+            started.wait();
+            // Neither dropped with the other transaction nor nested in it
+            let file = ext4
+                .create(ROOT_INO, "kept", FILE_MODE)
+                .expect("create failed");
+            ext4.write(file, 0, b"kept").expect("write failed");
+            ext4.with_transaction(|txn| txn.create(ROOT_INO, "committed", FILE_MODE))
+                .expect("transaction failed");
+        });
+    });
+    image.finish(ext4);
+
+    let ext4 = image.load();
+    assert_eq!(
+        ext4.lookup(ROOT_INO, "dropped").unwrap_err().code(),
+        ErrCode::ENOENT
+    );
+    let file = ext4.lookup(ROOT_INO, "kept").expect("lookup failed");
+    let mut buf = [0; 4];
+    ext4.read(file, 0, &mut buf).expect("read failed");
+    assert_eq!(&buf, b"kept");
+    ext4.lookup(ROOT_INO, "committed").expect("lookup failed");
+    image.finish(ext4);
+}
+
+#[test]
+fn journal_replay() {
+    let image = TestImage::new("journal_replay", 64, &[]);
+    let dir = TestDir::new("journal_replay");
+    let data = dir.path().join("block");
+    std::fs::write(&data, [0xab; BLOCK_SIZE]).unwrap();
+    // Leave a committed transaction in the journal, as after a crash
+    image.debugfs_script(&["jo", &format!("jw -b 10000 {}", data.display()), "jc"]);
+    let ext4 = image.load();
+    image.finish(ext4);
+    let block = image.device().read_block(10000);
+    assert_eq!(block.data, [0xab; BLOCK_SIZE]);
+    assert!(image.dumpe2fs().contains("Journal start:            0"));
+}
+
+#[test]
+fn journal_replay_checksums() {
+    let dir = TestDir::new("journal_replay_checksums");
+    let data = dir.path().join("block");
+    std::fs::write(&data, [0xab; BLOCK_SIZE]).unwrap();
+    // Journal block 1 is the descriptor block, 2 the logged block
+    for (name, corrupt) in [
+        ("intact", None),
+        ("descriptor", Some(1)),
+        ("logged", Some(2)),
+    ] {
+        let image = TestImage::new(&format!("journal_replay_checksums_{}", name), 64, &[]);
+        image.debugfs_script(&["jo -c", &format!("jw -b 10000 {}", data.display()), "jc"]);
+        if let Some(jblock) = corrupt {
+            let request = format!("bmap <8> {}", jblock);
+            let block = image
+                .debugfs(false, &request)
+                .trim()
+                .parse::<usize>()
+                .unwrap();
+            let mut bytes = std::fs::read(image.path()).unwrap();
+            bytes[block * BLOCK_SIZE + 1000] ^= 1;
+            std::fs::write(image.path(), bytes).unwrap();
+        }
+        let ext4 = image.load();
+        image.finish(ext4);
+        let block = image.device().read_block(10000);
+        let expected = if corrupt.is_none() { 0xab } else { 0 };
+        assert_eq!(block.data, [expected; BLOCK_SIZE], "{}", name);
+    }
+}
+
+#[test]
+fn data_journal() {
+    let image = TestImage::new("data_journal", 64, &[]);
+    let sequence = |image: &TestImage| {
+        let output = image.dumpe2fs();
+        let line = output
+            .lines()
+            .find(|line| line.starts_with("Journal sequence:"));
+        line.map(str::to_owned)
+    };
+    let before = sequence(&image);
+    let mut ext4 = image.load();
+    // Larger than what one transaction in the 4 MiB journal holds
+    let data: Vec<u8> = (0..3 << 20).map(|i| (i % 251) as u8).collect();
+    let file = ext4
+        .generic_create(ROOT_INO, "log", FILE_MODE)
+        .expect("create failed");
+    ext4.setflags(file, InodeFlags::JOURNAL_DATA)
+        .expect("setflags failed");
+    assert_eq!(
+        ext4.write(file, 0, &data).expect("write failed"),
+        data.len()
+    );
+    ext4.set_data_journal(true);
+    let other = ext4
+        .generic_create(ROOT_INO, "other", FILE_MODE)
+        .expect("create failed");
+    ext4.write(other, 0, b"journaled").expect("write failed");
+    image.finish(ext4);
+    assert_ne!(sequence(&image), before);
+
+    let ext4 = image.load();
+    let mut buf = vec![0; data.len()];
+    ext4.read(file, 0, &mut buf).expect("read failed");
+    assert!(buf == data);
+    let mut buf = [0; 9];
+    ext4.read(other, 0, &mut buf).expect("read failed");
+    assert_eq!(&buf, b"journaled");
+    image.finish(ext4);
+}
+
+#[test]
+fn partial_block_writes() {
+    let image = TestImage::new("partial_block_writes", 64, &[]);
+    let mut ext4 = image.load();
+    ext4.set_data_journal(true);
+    let file = ext4
+        .generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    let mut data = vec![0x11; 4 * BLOCK_SIZE];
+    ext4.write(file, 0, &data).expect("write failed");
+    // Sequential writes smaller than a block, straddling block boundaries
+    for (i, chunk) in data.chunks_mut(1000).enumerate() {
+        chunk.fill(i as u8);
+        ext4.write(file, i as u64 * 1000, chunk)
+            .expect("write failed");
+    }
+    // Disjoint writes to the same block in one transaction
+    ext4.with_transaction(|txn| {
+        txn.write(file, 10, b"head")?;
+        txn.write(file, 2000, b"tail")?;
+        txn.write(file, 14, b"-next")?;
+        let mut buf = [0; 4];
+        txn.read(file, 2000, &mut buf)?;
+        assert_eq!(&buf, b"tail");
+        Ok(())
+    })
+    .expect("transaction failed");
+    data[10..19].copy_from_slice(b"head-next");
+    data[2000..2004].copy_from_slice(b"tail");
+    let mut buf = vec![0; data.len()];
+    ext4.read(file, 0, &mut buf).expect("read failed");
+    assert!(buf == data);
+    image.finish(ext4);
+
+    let ext4 = image.load();
+    buf.fill(0);
+    ext4.read(file, 0, &mut buf).expect("read failed");
+    assert!(buf == data);
+    image.finish(ext4);
+}
+
+#[test]
+fn sparse_random_access() {
+    let image = TestImage::new("sparse_random_access", 64, &[]);
+    let ext4 = image.load();
+    let file = ext4
+        .generic_create(ROOT_INO, "sparse", FILE_MODE)
+        .expect("create failed");
+    // Every other block in a scattered order, enough for a multi-level tree
+    let order: Vec<u64> = (0..200).map(|i| (i * 73) % 200 * 2).collect();
+    let fill = |lblock: u64| (lblock % 251) as u8 + 1;
+    for &lblock in &order {
+        ext4.write(
+            file,
+            lblock * BLOCK_SIZE as u64,
+            &[fill(lblock); BLOCK_SIZE],
+        )
+        .expect("write failed");
+    }
+    let check = |filled: bool| {
+        let mut buf = [0; BLOCK_SIZE];
+        for _ in 0..2 {
+            for &lblock in order.iter().rev() {
+                for lblock in [lblock, lblock + 1] {
+                    buf.fill(0);
+                    ext4.read(file, lblock * BLOCK_SIZE as u64, &mut buf)
+                        .expect("read failed");
+                    let expected = if lblock % 2 == 0 || filled {
+                        fill(lblock)
+                    } else {
+                        0
+                    };
+                    assert!(buf.iter().all(|&b| b == expected), "block {}", lblock);
+                }
+            }
+        }
+    };
+    check(false);
+    // Filling the holes must not be hidden by cached lookups
+    for &lblock in &order {
+        let lblock = lblock + 1;
+        ext4.write(
+            file,
+            lblock * BLOCK_SIZE as u64,
+            &[fill(lblock); BLOCK_SIZE],
+        )
+        .expect("write failed");
+    }
+    check(true);
+    image.finish(ext4);
+}
+
+#[test]
+fn huge_file() {
+    let image = TestImage::new("huge_file", 64, &[]);
+    let ext4 = image.load();
+    let file = ext4
+        .generic_create(ROOT_INO, "huge", FILE_MODE)
+        .expect("create failed");
+    // Across the 4 GiB boundary, then past it, leaving holes
+    let data: Vec<u8> = (0..200).map(|i| i as u8).collect();
+    ext4.write(file, (4 << 30) - 100, &data)
+        .expect("write failed");
+    ext4.write(file, 5 << 30, b"huge").expect("write failed");
+    let attr = ext4.getattr(file).expect("getattr failed");
+    assert_eq!(attr.size, (5 << 30) + 4);
+    // Only the blocks written are mapped, in 512-byte units
+    assert_eq!(attr.blocks, 3 * (BLOCK_SIZE / 512) as u64);
+    let mut buf = vec![1; 200];
+    assert_eq!(
+        ext4.read(file, (4 << 30) - 100, &mut buf)
+            .expect("read failed"),
+        200
+    );
+    assert_eq!(buf, data);
+    assert_eq!(
+        ext4.read(file, 1 << 30, &mut buf).expect("read failed"),
+        200
+    );
+    assert!(buf.iter().all(|&b| b == 0));
+    assert_eq!(
+        ext4.read(file, (5 << 30) + 4, &mut buf)
+            .expect("read failed"),
+        0
+    );
+    // Growing leaves a hole
+    ext4.setattr(
+        file,
+        None,
+        None,
+        None,
+        Some(6 << 30),
+        None,
+        None,
+        None,
+        None,
+    )
+    .expect("setattr failed");
+    assert_eq!(
+        ext4.read(file, 5 << 30, &mut buf).expect("read failed"),
+        200
+    );
+    assert_eq!(&buf[..4], b"huge");
+    assert!(buf[4..].iter().all(|&b| b == 0));
+    // The last byte a file can hold
+    ext4.write(file, MAX_FILE_SIZE - 1, b"!")
+        .expect("write failed");
+    let err = ext4.write(file, MAX_FILE_SIZE - 1, b"!!").unwrap_err();
+    assert_eq!(err.code(), ErrCode::EFBIG);
+    let err = ext4
+        .setattr(
+            file,
+            None,
+            None,
+            None,
+            Some(MAX_FILE_SIZE + 1),
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap_err();
+    assert_eq!(err.code(), ErrCode::EFBIG);
+    image.finish(ext4);
+
+    let ext4 = image.load();
+    let file = ext4
+        .generic_lookup(ROOT_INO, "huge")
+        .expect("lookup failed");
+    assert_eq!(
+        ext4.getattr(file).expect("getattr failed").size,
+        MAX_FILE_SIZE
+    );
+    image.finish(ext4);
+}
+
+#[test]
+fn dir_shrink() {
+    let image = TestImage::new("dir_shrink", 64, &[]);
+    let mut ext4 = image.load();
+    ext4.set_dir_shrink(true);
+    let dir = ext4
+        .generic_create(ROOT_INO, "d", DIR_MODE)
+        .expect("mkdir failed");
+    let names: Vec<String> = (0..400).map(|i| format!("{:0>60}", i)).collect();
+    for name in &names {
+        ext4.create(dir, name, FILE_MODE).expect("create failed");
+    }
+    let grown = ext4.getattr(dir).expect("getattr failed").size;
+    assert!(grown > 4 * BLOCK_SIZE as u64);
+    // Removed entries are coalesced, so recreating them does not grow
+    // the directory
+    for name in names.iter().step_by(2) {
+        ext4.unlink(dir, name).expect("unlink failed");
+    }
+    for name in names.iter().step_by(2) {
+        ext4.create(dir, name, FILE_MODE).expect("create failed");
+    }
+    assert_eq!(ext4.getattr(dir).expect("getattr failed").size, grown);
+    // Emptied blocks at the end are released
+    for name in names.iter().rev() {
+        ext4.unlink(dir, name).expect("unlink failed");
+    }
+    assert_eq!(
+        ext4.getattr(dir).expect("getattr failed").size,
+        BLOCK_SIZE as u64
+    );
+    ext4.create(dir, "f", FILE_MODE).expect("create failed");
+    ext4.rename(dir, "f", dir, "g").expect("rename failed");
+    assert_eq!(ext4.listdir(dir).expect("listdir failed").len(), 3);
+    image.finish(ext4);
+}
+
+#[test]
+fn create_many() {
+    let image = TestImage::new("create_many", 64, &[]);
+    let ext4 = image.load();
+    let dir = ext4
+        .generic_create(ROOT_INO, "d", DIR_MODE)
+        .expect("mkdir failed");
+    let names: Vec<String> = (0..2000).map(|i| format!("file{}", i)).collect();
+    let mut entries: Vec<(&str, InodeMode)> = names
+        .iter()
+        .map(|name| (name.as_str(), FILE_MODE))
+        .collect();
+    entries.push(("sub", DIR_MODE));
+    let created = ext4.create_many(dir, &entries).expect("create_many failed");
+    assert_eq!(created.len(), entries.len());
+    for ((name, _), inode) in entries.iter().zip(&created) {
+        assert_eq!(ext4.lookup(dir, name).expect("lookup failed"), *inode);
+    }
+    assert_eq!(
+        ext4.listdir(dir).expect("listdir failed").len(),
+        entries.len() + 2
+    );
+    let sub = *created.last().unwrap();
+    ext4.create(sub, "f", FILE_MODE).expect("create failed");
+    assert_eq!(ext4.getattr(dir).expect("getattr failed").links, 3);
+    // Names are checked before anything is created
+    let err = ext4
+        .create_many(dir, &[("ok", FILE_MODE), ("a/b", FILE_MODE)])
+        .expect_err("invalid name");
+    assert_eq!(err.code(), ErrCode::EINVAL);
+    ext4.lookup(dir, "ok")
+        .expect_err("created before the error");
+    image.finish(ext4);
+}
+
+#[test]
+fn dir_reuse() {
+    let image = TestImage::new("dir_reuse", 64, &[]);
+    let ext4 = image.load();
+    let dir = ext4
+        .generic_create(ROOT_INO, "d", DIR_MODE)
+        .expect("mkdir failed");
+    let names: Vec<String> = (0..50).map(|i| format!("{:0>60}", i)).collect();
+    for name in &names {
+        ext4.create(dir, name, FILE_MODE).expect("create failed");
+    }
+    assert_eq!(
+        ext4.getattr(dir).expect("getattr failed").size,
+        BLOCK_SIZE as u64
+    );
+    // The space of removed entries is scattered over the block, and no
+    // single gap fits the longer names
+    for name in names.iter().step_by(2) {
+        ext4.unlink(dir, name).expect("unlink failed");
+    }
+    let long_names: Vec<String> = (0..10).map(|i| format!("{:0>200}", i)).collect();
+    for name in &long_names {
+        ext4.create(dir, name, FILE_MODE).expect("create failed");
+    }
+    // It is coalesced instead of growing the directory
+    assert_eq!(
+        ext4.getattr(dir).expect("getattr failed").size,
+        BLOCK_SIZE as u64
+    );
+    for name in names.iter().skip(1).step_by(2).chain(&long_names) {
+        ext4.lookup(dir, name).expect("lookup failed");
+    }
+    assert_eq!(
+        ext4.listdir(dir).expect("listdir failed").len(),
+        2 + 25 + 10
+    );
+    image.finish(ext4);
+}
+
+#[test]
+fn listdir_attrs() {
+    let image = TestImage::new("listdir_attrs", 64, &[]);
+    let ext4 = image.load();
+    let dir = ext4
+        .generic_create(ROOT_INO, "d", DIR_MODE)
+        .expect("mkdir failed");
+    for i in 0..100 {
+        let file = ext4
+            .create(dir, &format!("f{}", i), FILE_MODE)
+            .expect("create failed");
+        ext4.write(file, 0, &vec![1; i * 100])
+            .expect("write failed");
+    }
+    ext4.mkdir(dir, "sub", DIR_MODE).expect("mkdir failed");
+    let entries = ext4.listdir_attrs(dir).expect("listdir failed");
+    assert_eq!(entries.len(), 103);
+    for (entry, attr) in entries {
+        assert_eq!(attr.ino, entry.inode());
+        let expected = ext4.getattr(entry.inode()).expect("getattr failed");
+        assert_eq!(attr.size, expected.size);
+        assert_eq!(attr.ftype, expected.ftype);
+        assert_eq!(attr.links, expected.links);
+    }
+    image.finish(ext4);
+}
+
+#[test]
+fn walk() {
+    let image = TestImage::new("walk", 64, &[]);
+    let ext4 = image.load();
+    let top = ext4
+        .generic_create(ROOT_INO, "top", DIR_MODE)
+        .expect("mkdir failed");
+    for path in ["a", "a/b", "a/b/c", "d"] {
+        ext4.generic_create(top, path, DIR_MODE)
+            .expect("mkdir failed");
+    }
+    for path in ["f", "a/f", "a/b/f", "a/b/c/f"] {
+        ext4.generic_create(top, path, FILE_MODE)
+            .expect("create failed");
+    }
+    let collect = |options: WalkOptions, control: &dyn Fn(&str) -> WalkControl| {
+        let mut paths = Vec::new();
+        ext4.walk(top, options, |path, _| {
+            paths.push(path.to_string());
+            control(path)
+        })
+        .expect("walk failed");
+        paths
+    };
+    // Each directory comes before its entries
+    let all = collect(WalkOptions::default(), &|_| WalkControl::Continue);
+    let mut sorted = all.clone();
+    sorted.sort();
+    assert_eq!(
+        sorted,
+        ["a", "a/b", "a/b/c", "a/b/c/f", "a/b/f", "a/f", "d", "f"]
+    );
+    for path in &all {
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            let pos = |p: &str| all.iter().position(|x| x == p).unwrap();
+            assert!(pos(parent) < pos(path));
+        }
+    }
+    // Dot entries are reported but not descended into
+    let options = WalkOptions {
+        include_dots: true,
+        ..Default::default()
+    };
+    let dots = collect(options, &|_| WalkControl::Continue);
+    assert_eq!(dots.len(), all.len() + 2 * 5);
+    assert!(dots.iter().any(|p| p == "a/b/.."));
+    // Skip a subtree
+    let skipped = collect(WalkOptions::default(), &|path| {
+        if path == "a/b" {
+            WalkControl::SkipDir
+        } else {
+            WalkControl::Continue
+        }
+    });
+    assert!(skipped.iter().any(|p| p == "a/b"));
+    assert!(!skipped.iter().any(|p| p.starts_with("a/b/")));
+    // Stop early
+    let stopped = collect(WalkOptions::default(), &|path| {
+        if path == "a/b/c" {
+            WalkControl::Stop
+        } else {
+            WalkControl::Continue
+        }
+    });
+    assert_eq!(stopped.last().unwrap(), "a/b/c");
+    assert!(stopped.len() < all.len());
+    // Limit the depth
+    let options = WalkOptions {
+        max_depth: Some(2),
+        ..Default::default()
+    };
+    let mut shallow = collect(options, &|_| WalkControl::Continue);
+    shallow.sort();
+    assert_eq!(shallow, ["a", "a/b", "a/f", "d", "f"]);
+    image.finish(ext4);
+}
+
+#[test]
+fn copy_remove_recursive() {
+    let image = TestImage::new("copy_remove_recursive", 64, &[]);
+    let ext4 = image.load();
+    let mut rng = Rng::new(7);
+    let mut files = Vec::new();
+    for (i, path) in ["src/a", "src/d/b", "src/d/e/c", "src/d/e/f/g"]
+        .iter()
+        .enumerate()
+    {
+        let file = ext4
+            .generic_create(ROOT_INO, path, FILE_MODE)
+            .expect("create failed");
+        let data: Vec<u8> = (0..i * 30000 + 10).map(|_| rng.next() as u8).collect();
+        ext4.write(file, 0, &data).expect("write failed");
+        files.push((path.trim_start_matches("src/"), data));
+    }
+    let d = ext4.generic_lookup(ROOT_INO, "src/d").unwrap();
+    ext4.setxattr(d, "user.tag", b"dir")
+        .expect("setxattr failed");
+    ext4.generic_copy(ROOT_INO, "src", "dst")
+        .expect("copy failed");
+    for (path, data) in &files {
+        let file = ext4
+            .generic_lookup(ROOT_INO, &format!("dst/{}", path))
+            .expect("lookup failed");
+        let mut buf = vec![0; data.len()];
+        assert_eq!(
+            ext4.read(file, 0, &mut buf).expect("read failed"),
+            data.len()
+        );
+        assert_eq!(&buf, data);
+    }
+    let d_copy = ext4.generic_lookup(ROOT_INO, "dst/d").unwrap();
+    assert_eq!(ext4.getxattr(d_copy, "user.tag").unwrap(), b"dir");
+    // Copying over an object or into itself fails
+    let err = ext4.generic_copy(ROOT_INO, "src/a", "dst/a").unwrap_err();
+    assert_eq!(err.code(), ErrCode::EEXIST);
+    let err = ext4
+        .generic_copy(ROOT_INO, "src", "src/d/copy")
+        .unwrap_err();
+    assert_eq!(err.code(), ErrCode::EINVAL);
+    // Remove the original tree as one transaction
+    ext4.with_transaction(|txn| txn.generic_remove_recursive(ROOT_INO, "src"))
+        .expect("remove failed");
+    assert_eq!(
+        ext4.lookup(ROOT_INO, "src").unwrap_err().code(),
+        ErrCode::ENOENT
+    );
+    ext4.generic_remove_recursive(ROOT_INO, "dst/a")
+        .expect("remove failed");
+    assert!(ext4.generic_lookup(ROOT_INO, "dst/d/e/f/g").is_ok());
+    image.finish(ext4);
+}
+
+#[test]
+fn ext2_image() {
+    for (name, args) in [
+        ("ext2", &["-t", "ext2", "-I", "128"][..]),
+        (
+            "ext2_nofiletype",
+            &["-t", "ext2", "-I", "128", "-O", "^filetype"][..],
+        ),
+    ] {
+        let image = TestImage::new(name, 64, args);
+        let ext4 = image.load();
+        let mut rng = Rng::new(7);
+        let dir = ext4
+            .generic_create(ROOT_INO, "d", DIR_MODE)
+            .expect("create failed");
+        // Past the direct and the single indirect blocks
+        let data: Vec<u8> = (0..5 << 20).map(|_| rng.next() as u8).collect();
+        let big = ext4
+            .generic_create(dir, "big", FILE_MODE)
+            .expect("create failed");
+        ext4.write(big, 0, &data).expect("write failed");
+        // A hole in the double indirect range
+        let sparse = ext4
+            .generic_create(dir, "sparse", FILE_MODE)
+            .expect("create failed");
+        ext4.write(sparse, 8 << 20, b"tail").expect("write failed");
+        // Enough entries for several directory blocks
+        for i in 0..300 {
+            ext4.generic_create(dir, &format!("file-{:04}", i), FILE_MODE)
+                .expect("create failed");
+        }
+        let long = "t".repeat(100);
+        ext4.symlink(dir, "fast", "big").expect("symlink failed");
+        ext4.symlink(dir, "slow", &long).expect("symlink failed");
+        for i in (0..300).step_by(2) {
+            ext4.generic_remove(dir, &format!("file-{:04}", i))
+                .expect("remove failed");
+        }
+        image.finish(ext4);
+
+        let ext4 = image.load();
+        let big = ext4
+            .generic_lookup(ROOT_INO, "d/big")
+            .expect("lookup failed");
+        let mut buf = vec![0; data.len()];
+        assert_eq!(
+            ext4.read(big, 0, &mut buf).expect("read failed"),
+            data.len()
+        );
+        assert_eq!(buf, data);
+        let sparse = ext4
+            .generic_lookup(ROOT_INO, "d/sparse")
+            .expect("lookup failed");
+        let mut buf = [1; 8];
+        assert_eq!(
+            ext4.read(sparse, (8 << 20) - 4, &mut buf)
+                .expect("read failed"),
+            8
+        );
+        assert_eq!(&buf, b"\0\0\0\0tail");
+        let slow = ext4
+            .generic_lookup(ROOT_INO, "d/slow")
+            .expect("lookup failed");
+        assert_eq!(
+            ext4.readlink(slow).expect("readlink failed"),
+            long.as_bytes()
+        );
+        let fast = ext4
+            .generic_lookup(ROOT_INO, "d/fast")
+            .expect("lookup failed");
+        assert_eq!(ext4.readlink(fast).expect("readlink failed"), b"big");
+        assert!(ext4.generic_lookup(ROOT_INO, "d/file-0001").is_ok());
+        assert!(ext4.generic_lookup(ROOT_INO, "d/file-0000").is_err());
+        ext4.generic_remove_recursive(ROOT_INO, "d")
+            .expect("remove failed");
+        image.finish(ext4);
+    }
+}
+
+#[test]
+fn mode_conversion() {
+    // S_IFREG | S_ISUID | S_ISGID | S_ISVTX | 0755
+    let raw: u32 = 0o107755;
+    let mode = InodeMode::from(raw);
+    assert_eq!(u32::from(mode), raw);
+    assert_eq!(FileType::from(mode), FileType::RegularFile);
+    assert!(mode.contains(InodeMode::SET_UID | InodeMode::SET_GID | InodeMode::STICKY));
+    assert_eq!(InodeMode::from(FileType::Directory), InodeMode::DIRECTORY);
+    assert_eq!(u32::from(InodeMode::from(FileType::SymLink)), 0o120000);
+    assert_eq!(InodeMode::from(FileType::Unknown), InodeMode::empty());
+
+    let image = TestImage::new("mode_conversion", 64, &[]);
+    let ext4 = image.load();
+    let file = ext4.create(ROOT_INO, "suid", mode).expect("create failed");
+    let attr = ext4.getattr(file).expect("getattr failed");
+    assert_eq!(
+        u32::from(InodeMode::from_type_and_perm(attr.ftype, attr.perm)),
+        raw
+    );
+    image.finish(ext4);
+}
+
+#[test]
+fn setid_bits() {
+    let image = TestImage::new("setid_bits", 64, &[]);
+    let mut ext4 = image.load();
+    let setid = InodeMode::SET_UID | InodeMode::SET_GID;
+    let file = ext4
+        .create(ROOT_INO, "prog", FILE_MODE | setid)
+        .expect("create failed");
+    ext4.setattr(
+        file,
+        None,
+        Some(1000),
+        Some(1000),
+        None,
+        None,
+        None,
+        None,
+        None,
+    )
+    .expect("setattr failed");
+    // chmod keeps the file type and the special bits
+    let perm = InodeMode::ALL_RWX | setid | InodeMode::STICKY;
+    ext4.setattr(file, Some(perm), None, None, None, None, None, None, None)
+        .expect("setattr failed");
+    let attr = ext4.getattr(file).expect("getattr failed");
+    assert_eq!(attr.ftype, FileType::RegularFile);
+    assert_eq!(attr.perm, perm);
+    // The owner keeps them when writing
+    ext4.set_credentials(Some(Credentials::new(1000, 1000)));
+    ext4.write(file, 0, b"owner").expect("write failed");
+    assert!(ext4.getattr(file).unwrap().perm.contains(setid));
+    // Another user clears them
+    ext4.set_credentials(Some(Credentials::new(1001, 1001)));
+    ext4.write(file, 0, b"other").expect("write failed");
+    let attr = ext4.getattr(file).expect("getattr failed");
+    assert_eq!(attr.perm, InodeMode::ALL_RWX | InodeMode::STICKY);
+    // Setting setgid outside the group of the file drops it
+    let perm = InodeMode::ALL_RWX | setid;
+    ext4.setattr(file, Some(perm), None, None, None, None, None, None, None)
+        .expect("setattr failed");
+    assert_eq!(
+        ext4.getattr(file).unwrap().perm,
+        InodeMode::ALL_RWX | InodeMode::SET_UID
+    );
+    // Without group execution, setgid survives a write
+    ext4.set_credentials(None);
+    let perm = InodeMode::from_bits_retain(0o666) | setid;
+    ext4.setattr(file, Some(perm), None, None, None, None, None, None, None)
+        .expect("setattr failed");
+    ext4.set_credentials(Some(Credentials::new(1001, 1001)));
+    ext4.setattr(file, None, None, None, Some(0), None, None, None, None)
+        .expect("setattr failed");
+    let attr = ext4.getattr(file).expect("getattr failed");
+    assert_eq!(
+        attr.perm,
+        InodeMode::from_bits_retain(0o666) | InodeMode::SET_GID
+    );
+    ext4.set_credentials(None);
+    image.finish(ext4);
+}
+
+#[test]
+fn statx_attr() {
+    let image = TestImage::new("statx_attr", 64, &[]);
+    let ext4 = image.load();
+    let file = ext4
+        .create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    let attr = ext4.getattr(file).expect("getattr failed");
+    assert_eq!(attr.blksize, BLOCK_SIZE as u32);
+    assert_eq!(attr.rdev, (0, 0));
+    assert!(attr
+        .attributes_mask
+        .contains(AttrMask::BASIC_STATS | AttrMask::BTIME));
+    image.finish(ext4);
+    // 500 ns above the 2 epoch bits
+    image.debugfs(true, "sif /f mtime_extra 2000");
+    let ext4 = image.load();
+    let attr = ext4.getattr(file).expect("getattr failed");
+    assert_eq!(attr.mtime_nsec, 500);
+    // Setting whole seconds clears the nanoseconds
+    ext4.setattr(file, None, None, None, None, None, Some(100), None, None)
+        .expect("setattr failed");
+    let attr = ext4.getattr(file).expect("getattr failed");
+    assert_eq!((attr.mtime, attr.mtime_nsec), (100, 0));
+    image.finish(ext4);
+
+    // 128-byte inodes have no creation time nor nanoseconds
+    let image = TestImage::new("statx_attr_small", 64, &["-I", "128"]);
+    let ext4 = image.load();
+    let attr = ext4.getattr(ROOT_INO).expect("getattr failed");
+    assert!(attr.attributes_mask.contains(AttrMask::BASIC_STATS));
+    assert!(!attr.attributes_mask.contains(AttrMask::BTIME));
+    assert_eq!(attr.mtime_nsec, 0);
+    image.finish(ext4);
+}
+
+#[test]
+fn extra_isize() {
+    // New inodes get the extended fields the superblock asks for, here
+    // nanoseconds but no creation time
+    let image = TestImage::new("extra_isize", 64, &[]);
+    image.debugfs(true, "ssv want_extra_isize 16");
+    let ext4 = image.load();
+    let file = ext4
+        .create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    ext4.setattr(
+        file,
+        None,
+        None,
+        None,
+        None,
+        Some(10),
+        Some(20),
+        Some(30),
+        Some(40),
+    )
+    .expect("setattr failed");
+    let attr = ext4.getattr(file).expect("getattr failed");
+    assert!(!attr.attributes_mask.contains(AttrMask::BTIME));
+    assert_eq!((attr.atime, attr.mtime, attr.ctime), (10, 20, 30));
+    ext4.setxattr(file, "user.a", b"value")
+        .expect("setxattr failed");
+    image.finish(ext4);
+    let stat = image.debugfs(false, "stat /f");
+    assert!(stat.contains("Size of extra inode fields: 16"), "{}", stat);
+    let ext4 = image.load();
+    assert_eq!(
+        ext4.getxattr(file, "user.a").expect("getxattr failed"),
+        b"value"
+    );
+    image.finish(ext4);
+
+    // 128-byte inodes keep the lower 16 bits of their checksum only
+    let image = TestImage::new(
+        "extra_isize_small",
+        64,
+        &["-I", "128", "-O", "metadata_csum"],
+    );
+    let ext4 = image.load();
+    let file = ext4
+        .create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    ext4.setattr(
+        file,
+        None,
+        None,
+        None,
+        None,
+        Some(10),
+        Some(20),
+        Some(30),
+        Some(40),
+    )
+    .expect("setattr failed");
+    ext4.write(file, 0, b"data").expect("write failed");
+    image.finish(ext4);
+}
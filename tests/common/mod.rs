@@ -0,0 +1,299 @@
+//! Shared helpers for the integration tests.
+//!
+//! Images are created with e2fsprogs under the cargo target directory and
+//! removed when the test finishes.
+
+#![allow(dead_code)]
+
+use another_ext4::{
+    Block, BlockDevice, Ext4, FileBlockDevice, InodeMode, SectorDevice, BLOCK_SIZE, EXT4_ROOT_INO,
+};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+pub const ROOT_INO: u32 = EXT4_ROOT_INO;
+pub const FILE_MODE: InodeMode = InodeMode::FILE.union(InodeMode::ALL_RWX);
+pub const DIR_MODE: InodeMode = InodeMode::DIRECTORY.union(InodeMode::ALL_RWX);
+
+fn scratch_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_TARGET_TMPDIR")).join(name)
+}
+
+/// A scratch directory, removed on drop.
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let path = scratch_path(name);
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// An ext4 image file created by `mkfs.ext4`, removed on drop.
+pub struct TestImage {
+    path: PathBuf,
+}
+
+impl TestImage {
+    /// Create an empty image of `size_mb` MiB with extra `mkfs.ext4` arguments.
+    pub fn new(name: &str, size_mb: u64, args: &[&str]) -> Self {
+        Self::mkfs(name, size_mb, args, None)
+    }
+
+    /// Create an image populated with the contents of `root`.
+    pub fn from_dir(name: &str, size_mb: u64, args: &[&str], root: &Path) -> Self {
+        Self::mkfs(name, size_mb, args, Some(root))
+    }
+
+    fn mkfs(name: &str, size_mb: u64, args: &[&str], root: Option<&Path>) -> Self {
+        let path = scratch_path(&format!("{}.img", name));
+        let _ = std::fs::remove_file(&path);
+        let file = File::create(&path).unwrap();
+        file.set_len(size_mb * 1024 * 1024).unwrap();
+        drop(file);
+        let mut cmd = Command::new("mkfs.ext4");
+        cmd.args(["-q", "-F", "-b", &BLOCK_SIZE.to_string()])
+            .args(args);
+        if let Some(root) = root {
+            cmd.arg("-d").arg(root);
+        }
+        let output = cmd.arg(&path).output().expect("mkfs.ext4 not found");
+        assert!(
+            output.status.success(),
+            "mkfs.ext4 failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn path_str(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+
+    /// Open the image as a counting block device.
+    pub fn device(&self) -> Arc<BlockFile> {
+        Arc::new(BlockFile::new(self.path_str()))
+    }
+
+    /// Load the filesystem on the image.
+    pub fn load(&self) -> Ext4 {
+        Ext4::load(self.device()).expect("open ext4 failed")
+    }
+
+    /// Finalize the filesystem, then check the image with `e2fsck -f -n`.
+    pub fn finish(&self, ext4: Ext4) {
+        ext4.finalize();
+        drop(ext4);
+        self.fsck();
+    }
+
+    /// Check the image with `e2fsck -f -n`, which must find no problem.
+    pub fn fsck(&self) {
+        let output = Command::new("e2fsck")
+            .args(["-f", "-n", self.path_str()])
+            .output()
+            .expect("e2fsck not found");
+        assert!(
+            output.status.success(),
+            "e2fsck failed:\n{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    /// Run a `debugfs` request on the image and return its output.
+    pub fn debugfs(&self, writable: bool, request: &str) -> String {
+        let mut cmd = Command::new("debugfs");
+        if writable {
+            cmd.arg("-w");
+        }
+        let output = cmd
+            .args(["-R", request, self.path_str()])
+            .output()
+            .expect("debugfs not found");
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+
+    /// Run several `debugfs` requests in one writable session and return
+    /// the output.
+    pub fn debugfs_script(&self, requests: &[&str]) -> String {
+        let mut child = Command::new("debugfs")
+            .args(["-w", "-f", "-", self.path_str()])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("debugfs not found");
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(requests.join("\n").as_bytes()).unwrap();
+        drop(stdin);
+        let output = child.wait_with_output().unwrap();
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+
+    /// Run `dumpe2fs` on the image and return its output.
+    pub fn dumpe2fs(&self) -> String {
+        let output = Command::new("dumpe2fs")
+            .arg(self.path_str())
+            .output()
+            .expect("dumpe2fs not found");
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+}
+
+impl Drop for TestImage {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A `FileBlockDevice` that counts the requests it receives.
+#[derive(Debug)]
+pub struct BlockFile {
+    dev: FileBlockDevice,
+    /// Number of blocks discarded
+    discarded: AtomicU64,
+    /// Number of blocks hinted for readahead
+    readahead: AtomicU64,
+    /// Number of device cache flushes
+    flushes: AtomicU64,
+}
+
+impl BlockFile {
+    pub fn new(path: &str) -> Self {
+        Self {
+            dev: FileBlockDevice::open(path).unwrap(),
+            discarded: AtomicU64::new(0),
+            readahead: AtomicU64::new(0),
+            flushes: AtomicU64::new(0),
+        }
+    }
+
+    /// The number of blocks discarded so far.
+    pub fn discarded(&self) -> u64 {
+        self.discarded.load(Ordering::Relaxed)
+    }
+
+    /// The number of device cache flushes so far.
+    pub fn flushes(&self) -> u64 {
+        self.flushes.load(Ordering::Relaxed)
+    }
+
+    /// The number of blocks hinted for readahead so far.
+    pub fn readahead(&self) -> u64 {
+        self.readahead.load(Ordering::Relaxed)
+    }
+}
+
+impl BlockDevice for BlockFile {
+    fn read_block(&self, block_id: u64) -> Block {
+        self.dev.read_block(block_id)
+    }
+
+    fn write_block(&self, block: &Block) {
+        self.dev.write_block(block)
+    }
+
+    fn block_count(&self) -> Option<u64> {
+        self.dev.block_count()
+    }
+
+    fn flush(&self) {
+        self.dev.flush();
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn discard(&self, _start: u64, count: u64) {
+        self.discarded.fetch_add(count, Ordering::Relaxed);
+    }
+
+    fn readahead(&self, _start: u64, count: u64) {
+        self.readahead.fetch_add(count, Ordering::Relaxed);
+    }
+}
+
+/// A file accessed by sectors of a given size.
+#[derive(Debug)]
+pub struct SectorFile {
+    file: File,
+    sector_size: usize,
+}
+
+impl SectorFile {
+    pub fn new(path: &str, sector_size: usize) -> Self {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        Self { file, sector_size }
+    }
+}
+
+impl SectorDevice for SectorFile {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn read_sectors(&self, start: u64, buf: &mut [u8]) {
+        assert_eq!(buf.len() % self.sector_size, 0);
+        let mut file = &self.file;
+        let _r = file.seek(SeekFrom::Start(start * self.sector_size as u64));
+        let _r = file.read_exact(buf);
+    }
+
+    fn write_sectors(&self, start: u64, buf: &[u8]) {
+        assert_eq!(buf.len() % self.sector_size, 0);
+        let mut file = &self.file;
+        let _r = file.seek(SeekFrom::Start(start * self.sector_size as u64));
+        let _r = file.write_all(buf);
+    }
+
+    fn sector_count(&self) -> Option<u64> {
+        let len = self.file.metadata().ok()?.len();
+        Some(len / self.sector_size as u64)
+    }
+}
+
+/// A small deterministic PRNG (xorshift64*), so failures are reproducible.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A random number in `0..n`.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n.max(1)
+    }
+}
@@ -0,0 +1,312 @@
+//! Block device interface: discard, readahead, flush, size and adapters.
+
+mod common;
+
+use another_ext4::{
+    diff_devices, BlockDevice, ErrCode, Ext4, ExtentTreeEntry, FileBlockDevice, MemBlockDevice,
+    SectorAdapter, BLOCK_SIZE, O_DIRECT,
+};
+use common::*;
+use std::fs::OpenOptions;
+use std::sync::Arc;
+
+#[test]
+fn discard() {
+    let image = TestImage::new("discard", 64, &[]);
+    let file = image.device();
+    let mut ext4 = Ext4::load(file.clone()).expect("open ext4 failed");
+    ext4.generic_write_atomic(ROOT_INO, "f", &[1; 3 * 4096])
+        .expect("write failed");
+    ext4.generic_remove(ROOT_INO, "f").expect("remove failed");
+    assert_eq!(file.discarded(), 0);
+    ext4.set_discard(true);
+    ext4.generic_write_atomic(ROOT_INO, "f", &[1; 3 * 4096])
+        .expect("write failed");
+    ext4.generic_remove(ROOT_INO, "f").expect("remove failed");
+    assert!(file.discarded() >= 3);
+}
+
+#[test]
+fn trim() {
+    let image = TestImage::new("trim", 64, &[]);
+    let file = image.device();
+    let ext4 = Ext4::load(file.clone()).expect("open ext4 failed");
+    // Batched trim of all free space
+    let trimmed = ext4.trim(0, u64::MAX, 1).expect("trim failed");
+    assert!(trimmed > 0);
+    assert_eq!(file.discarded(), trimmed);
+    assert_eq!(ext4.trim(0, u64::MAX, u64::MAX).expect("trim failed"), 0);
+    ext4.trim(u64::MAX, 1, 1).expect_err("trim beyond the end");
+}
+
+#[test]
+fn readahead() {
+    let image = TestImage::new("readahead", 64, &[]);
+    let file = image.device();
+    let mut ext4 = Ext4::load(file.clone()).expect("open ext4 failed");
+    let ino = ext4
+        .generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    ext4.write(ino, 0, &[1; 16 * 4096]).expect("write failed");
+    let mut buf = [0; 4096];
+    // Disabled by default
+    ext4.read(ino, 0, &mut buf).expect("read failed");
+    assert_eq!(file.readahead(), 0);
+    ext4.set_readahead(4);
+    // Sequential reads prefetch the next blocks
+    ext4.read(ino, 0, &mut buf).expect("read failed");
+    assert_eq!(file.readahead(), 4);
+    ext4.read(ino, 4096, &mut buf).expect("read failed");
+    assert_eq!(file.readahead(), 8);
+    // Random reads do not
+    ext4.read(ino, 10 * 4096, &mut buf).expect("read failed");
+    assert_eq!(file.readahead(), 8);
+    // No readahead past the end of the file
+    ext4.read(ino, 11 * 4096, &mut buf).expect("read failed");
+    ext4.read(ino, 12 * 4096, &mut buf).expect("read failed");
+    ext4.read(ino, 13 * 4096, &mut buf).expect("read failed");
+    assert_eq!(file.readahead(), 8 + 4 + 3 + 2);
+}
+
+#[test]
+fn flush() {
+    let image = TestImage::new("flush", 64, &[]);
+    let file = image.device();
+    let ext4 = Ext4::load(file.clone()).expect("open ext4 failed");
+    ext4.generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    let flushes = file.flushes();
+    ext4.flush_all();
+    assert_eq!(file.flushes(), flushes + 1);
+}
+
+#[test]
+fn truncated_image() {
+    let image = TestImage::new("truncated_image", 64, &[]);
+    let img = OpenOptions::new().write(true).open(image.path()).unwrap();
+    img.set_len(32 * 1024 * 1024).unwrap();
+    assert_eq!(
+        Ext4::load(image.device()).map(|_| ()).unwrap_err().code(),
+        ErrCode::EINVAL
+    );
+}
+
+#[test]
+fn invalid_super_block() {
+    let image = TestImage::new("invalid_super_block", 64, &[]);
+    let bytes = std::fs::read(image.path()).unwrap();
+    // Superblock fields the geometry is derived from: inode count, first
+    // data block, block size, blocks and inodes per group, then the block
+    // bitmap and the inode table of the first group
+    let fields = [
+        (1024, 1),
+        (1024 + 0x14, 1 << 20),
+        (1024 + 0x18, 0),
+        (1024 + 0x20, 0),
+        (1024 + 0x28, 0),
+        (1024 + 0x28, 1 << 20),
+        (BLOCK_SIZE, 1 << 20),
+        (BLOCK_SIZE + 0x08, 16383),
+    ];
+    for (offset, value) in fields {
+        let mut bytes = bytes.clone();
+        bytes[offset..offset + 4].copy_from_slice(&u32::to_le_bytes(value));
+        let dev = Arc::new(MemBlockDevice::from_image(&bytes));
+        assert_eq!(
+            Ext4::load(dev).map(|_| ()).unwrap_err().code(),
+            ErrCode::EINVAL,
+            "field at {:#x} set to {}",
+            offset,
+            value
+        );
+    }
+}
+
+#[test]
+fn sector_device() {
+    let image = TestImage::new("sector_device", 64, &[]);
+    let data: Vec<u8> = (0..3 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+    // Small sectors are aggregated into blocks
+    let dev = SectorAdapter::new(SectorFile::new(image.path_str(), 512));
+    let ext4 = Ext4::load(Arc::new(dev)).expect("open ext4 failed");
+    let ino = ext4
+        .generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    ext4.write(ino, 0, &data).expect("write failed");
+    ext4.flush_all();
+    drop(ext4);
+    // Large sectors are read-modify-written
+    let dev = SectorAdapter::new(SectorFile::new(image.path_str(), 4 * BLOCK_SIZE));
+    let ext4 = Ext4::load(Arc::new(dev)).expect("open ext4 failed");
+    let mut buf = vec![0; data.len()];
+    assert_eq!(
+        ext4.read(ino, 0, &mut buf).expect("read failed"),
+        data.len()
+    );
+    assert_eq!(buf, data);
+    ext4.write(ino, BLOCK_SIZE as u64, &[7; 100])
+        .expect("write failed");
+    ext4.flush_all();
+    drop(ext4);
+    let ext4 = image.load();
+    ext4.read(ino, 0, &mut buf).expect("read failed");
+    assert_eq!(&buf[BLOCK_SIZE..BLOCK_SIZE + 100], &[7; 100]);
+    assert_eq!(&buf[..BLOCK_SIZE], &data[..BLOCK_SIZE]);
+    assert_eq!(&buf[BLOCK_SIZE + 100..], &data[BLOCK_SIZE + 100..]);
+}
+
+#[test]
+fn mem_device() {
+    let image = TestImage::new("mem_device", 64, &[]);
+    let bytes = std::fs::read(image.path()).unwrap();
+    let dev = Arc::new(MemBlockDevice::from_image(&bytes));
+    let checkpoint = dev.checkpoint();
+    let ext4 = Ext4::load(dev.clone()).expect("open ext4 failed");
+    let ino = ext4
+        .generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    ext4.write(ino, 0, &[3; 2 * BLOCK_SIZE])
+        .expect("write failed");
+    ext4.flush_all();
+    drop(ext4);
+    // A clone is independent of the original
+    let copy = Arc::new((*dev).clone());
+    assert_eq!(copy.to_image(), dev.to_image());
+    dev.restore(&checkpoint);
+    assert_eq!(dev.to_image(), bytes);
+    let ext4 = Ext4::load(dev.clone()).expect("open ext4 failed");
+    assert!(ext4.lookup(ROOT_INO, "f").is_err());
+    let ext4 = Ext4::load(copy).expect("open ext4 failed");
+    let mut buf = [0; BLOCK_SIZE];
+    ext4.read(ino, BLOCK_SIZE as u64, &mut buf)
+        .expect("read failed");
+    assert_eq!(buf, [3; BLOCK_SIZE]);
+}
+
+#[test]
+fn direct_device() {
+    let image = TestImage::new("direct_device", 64, &[]);
+    // O_DIRECT is not supported by every host filesystem
+    let Ok(dev) = FileBlockDevice::open_direct(image.path()) else {
+        return;
+    };
+    let ext4 = Ext4::load(Arc::new(dev)).expect("open ext4 failed");
+    let ino = ext4
+        .generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    ext4.write(ino, 0, &[5; BLOCK_SIZE]).expect("write failed");
+    ext4.flush_all();
+    drop(ext4);
+    let ext4 = image.load();
+    let mut buf = [0; BLOCK_SIZE];
+    ext4.read(ino, 0, &mut buf).expect("read failed");
+    assert_eq!(buf, [5; BLOCK_SIZE]);
+}
+
+#[test]
+fn direct_io() {
+    let image = TestImage::new("direct_io", 64, &[]);
+    let ext4 = image.load();
+    let ino = ext4
+        .generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    ext4.write(ino, 0, &[1; 4 * BLOCK_SIZE])
+        .expect("write failed");
+    let fh = ext4.open(ino, O_DIRECT).expect("open failed");
+    // Direct I/O must cover whole blocks
+    let err = ext4.write_handle(fh, 1, &[2; BLOCK_SIZE]).unwrap_err();
+    assert_eq!(err.code(), ErrCode::EINVAL);
+    let err = ext4.read_handle(fh, 0, &mut [0; 100]).unwrap_err();
+    assert_eq!(err.code(), ErrCode::EINVAL);
+    // A direct write replaces cached data and reaches the device at once
+    ext4.write_handle(fh, BLOCK_SIZE as u64, &[2; 2 * BLOCK_SIZE])
+        .expect("write failed");
+    let Ok(ExtentTreeEntry::Extent { start_pblock, .. }) =
+        ext4.dump_tree(ino).map(|root| root.entries[0].clone())
+    else {
+        panic!("file not mapped by an extent");
+    };
+    let dev = FileBlockDevice::open(image.path_str()).unwrap();
+    assert_eq!(dev.read_block(start_pblock + 2).data, [2; BLOCK_SIZE]);
+    // Buffered and direct reads see the same data
+    let mut buf = vec![0; 4 * BLOCK_SIZE];
+    ext4.read_handle(fh, 0, &mut buf).expect("read failed");
+    let mut expected = vec![1; 4 * BLOCK_SIZE];
+    expected[BLOCK_SIZE..3 * BLOCK_SIZE].fill(2);
+    assert_eq!(buf, expected);
+    ext4.read(ino, 0, &mut buf).expect("read failed");
+    assert_eq!(buf, expected);
+    // Direct writes may extend the file
+    ext4.write_handle(fh, 4 * BLOCK_SIZE as u64, &[3; BLOCK_SIZE])
+        .expect("write failed");
+    assert_eq!(ext4.getattr(ino).unwrap().size, 5 * BLOCK_SIZE as u64);
+    ext4.release(fh).expect("release failed");
+    image.finish(ext4);
+}
+
+#[test]
+fn diff_images() {
+    let image = TestImage::new("diff_images", 64, &[]);
+    let bytes = std::fs::read(image.path()).unwrap();
+    let dev = Arc::new(MemBlockDevice::from_image(&bytes));
+    let orig = MemBlockDevice::from_image(&bytes);
+    assert!(diff_devices(dev.as_ref(), &orig, None).is_empty());
+    let ext4 = Ext4::load(dev.clone()).expect("open ext4 failed");
+    let ino = ext4
+        .generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    ext4.write(ino, 0, &[7; 100]).expect("write failed");
+    ext4.flush_all();
+    let Ok(ExtentTreeEntry::Extent { start_pblock, .. }) =
+        ext4.dump_tree(ino).map(|root| root.entries[0].clone())
+    else {
+        panic!("file not mapped by an extent");
+    };
+    drop(ext4);
+    let diffs = diff_devices(dev.as_ref(), &orig, None);
+    let data = diffs
+        .iter()
+        .find(|d| d.block == start_pblock)
+        .expect("data block not found");
+    assert_eq!(data.bytes, 0..100);
+    // The superblock changed as well
+    assert_eq!(diffs[0].block, 0);
+    assert!(diffs.windows(2).all(|w| w[0].block < w[1].block));
+}
+
+#[test]
+fn cow_snapshot() {
+    let image = TestImage::new("cow_snapshot", 64, &[]);
+    let bytes = std::fs::read(image.path()).unwrap();
+    let mut ext4 = Ext4::load_cow(image.device()).expect("open ext4 failed");
+    let f1 = ext4
+        .generic_create(ROOT_INO, "f1", FILE_MODE)
+        .expect("create failed");
+    ext4.write(f1, 0, &[1; BLOCK_SIZE]).expect("write failed");
+    let snapshot = ext4.snapshot().expect("snapshot failed");
+    ext4.write(f1, 0, &[2; BLOCK_SIZE]).expect("write failed");
+    ext4.generic_create(ROOT_INO, "f2", FILE_MODE)
+        .expect("create failed");
+    ext4.rollback(&snapshot).expect("rollback failed");
+    // The state at the snapshot is back, in place
+    assert_eq!(
+        ext4.lookup(ROOT_INO, "f2").unwrap_err().code(),
+        ErrCode::ENOENT
+    );
+    let mut buf = [0; BLOCK_SIZE];
+    ext4.read(f1, 0, &mut buf).expect("read failed");
+    assert_eq!(buf, [1; BLOCK_SIZE]);
+    // Nothing reached the image so far
+    assert_eq!(std::fs::read(image.path()).unwrap(), bytes);
+    ext4.finalize();
+    ext4.commit().expect("commit failed");
+    drop(ext4);
+    image.fsck();
+    let ext4 = image.load();
+    ext4.lookup(ROOT_INO, "f1").expect("lookup failed");
+    // Snapshots need a copy-on-write device
+    assert_eq!(
+        ext4.snapshot().err().map(|e| e.code()),
+        Some(ErrCode::ENOTSUP)
+    );
+}
@@ -0,0 +1,139 @@
+//! Images populated by e2fsprogs from a host directory tree.
+
+mod common;
+
+use another_ext4::{Ext4, FileType, BLOCK_SIZE};
+use common::*;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+
+/// Number of entries in the indexed directory.
+const BIG_DIR_ENTRIES: usize = 500;
+
+/// Offset of the data after the hole in the sparse file.
+const SPARSE_TAIL: usize = 8 * 1024 * 1024;
+
+/// A slow symlink target, too long to be stored in the inode.
+fn long_target() -> String {
+    "long/".repeat(20) + "target"
+}
+
+/// Populate `root` with the fixture tree.
+fn populate(root: &Path) {
+    fs::create_dir_all(root.join("a/b/c")).unwrap();
+    fs::write(root.join("a/b/c/hello"), b"hello fixture").unwrap();
+    let data: Vec<u8> = (0..3 * BLOCK_SIZE + 100).map(|i| (i % 251) as u8).collect();
+    fs::write(root.join("a/data"), data).unwrap();
+    // A file with a hole in the middle
+    let sparse = fs::File::create(root.join("sparse")).unwrap();
+    std::os::unix::fs::FileExt::write_all_at(&sparse, b"head", 0).unwrap();
+    std::os::unix::fs::FileExt::write_all_at(&sparse, b"tail", SPARSE_TAIL as u64).unwrap();
+    // Fast and slow symlinks
+    symlink("a/b/c/hello", root.join("fast_link")).unwrap();
+    symlink(long_target(), root.join("slow_link")).unwrap();
+    // A directory large enough to be indexed
+    fs::create_dir(root.join("big")).unwrap();
+    for i in 0..BIG_DIR_ENTRIES {
+        fs::write(root.join(format!("big/entry_{:04}", i)), i.to_string()).unwrap();
+    }
+}
+
+/// Create the fixture image, index its directories and add an xattr.
+fn fixture_image(name: &str, args: &[&str]) -> TestImage {
+    let root = TestDir::new(&format!("{}_root", name));
+    populate(root.path());
+    let image = TestImage::from_dir(name, 64, args, root.path());
+    image.debugfs(true, "ea_set /a/data user.color blue");
+    // Build hash tree indexes for large directories
+    let _ = std::process::Command::new("e2fsck")
+        .args(["-fyD", image.path_str()])
+        .output()
+        .unwrap();
+    let htree = image.debugfs(false, "htree_dump /big");
+    assert!(htree.contains("Root node dump"), "/big is not indexed");
+    image
+}
+
+fn read_all(ext4: &Ext4, path: &str) -> Vec<u8> {
+    let file = ext4.generic_lookup(ROOT_INO, path).expect("lookup failed");
+    let size = ext4.getattr(file).expect("getattr failed").size as usize;
+    let mut buf = vec![0; size];
+    assert_eq!(ext4.read(file, 0, &mut buf).expect("read failed"), size);
+    buf
+}
+
+fn check_fixture(ext4: &Ext4) {
+    // Regular files
+    assert_eq!(read_all(ext4, "a/b/c/hello"), b"hello fixture");
+    let data = read_all(ext4, "a/data");
+    assert_eq!(data.len(), 3 * BLOCK_SIZE + 100);
+    assert!(data.iter().enumerate().all(|(i, &b)| b == (i % 251) as u8));
+    // Holes read as zeros
+    let sparse = read_all(ext4, "sparse");
+    assert_eq!(sparse.len(), SPARSE_TAIL + 4);
+    assert_eq!(&sparse[..4], b"head");
+    assert!(sparse[4..SPARSE_TAIL].iter().all(|&b| b == 0));
+    assert_eq!(&sparse[SPARSE_TAIL..], b"tail");
+    // Symlinks
+    for (path, target) in [
+        ("fast_link", "a/b/c/hello".to_string()),
+        ("slow_link", long_target()),
+    ] {
+        let link = ext4.generic_lookup(ROOT_INO, path).expect("lookup failed");
+        let attr = ext4.getattr(link).expect("getattr failed");
+        assert_eq!(attr.ftype, FileType::SymLink);
+        assert_eq!(attr.size, target.len() as u64);
+    }
+    // Extended attributes
+    let file = ext4
+        .generic_lookup(ROOT_INO, "a/data")
+        .expect("lookup failed");
+    assert_eq!(
+        ext4.getxattr(file, "user.color").expect("getxattr failed"),
+        b"blue"
+    );
+    let names = ext4.listxattr(file).expect("listxattr failed");
+    assert!(names.contains(&"user.color".to_string()));
+    // Indexed directory
+    let big = ext4.generic_lookup(ROOT_INO, "big").expect("lookup failed");
+    let entries = ext4.listdir(big).expect("listdir failed");
+    assert_eq!(entries.len(), BIG_DIR_ENTRIES + 2);
+    for i in [0, 1, BIG_DIR_ENTRIES / 2, BIG_DIR_ENTRIES - 1] {
+        let content = read_all(ext4, &format!("big/entry_{:04}", i));
+        assert_eq!(content, i.to_string().as_bytes());
+    }
+}
+
+#[test]
+fn fixture_tree() {
+    let image = fixture_image("fixture_tree", &[]);
+    check_fixture(&image.load());
+}
+
+#[test]
+fn fixture_tree_no_csum() {
+    let image = fixture_image("fixture_tree_no_csum", &["-O", "^metadata_csum"]);
+    check_fixture(&image.load());
+}
+
+#[test]
+fn fixture_tree_modify() {
+    let image = fixture_image("fixture_tree_modify", &[]);
+    {
+        let ext4 = image.load();
+        ext4.generic_write_atomic(ROOT_INO, "a/b/c/new", b"new file")
+            .expect("write failed");
+        ext4.generic_remove(ROOT_INO, "a/b/c/hello")
+            .expect("remove failed");
+        ext4.generic_rename(ROOT_INO, "a/data", "a/b/data")
+            .expect("rename failed");
+        image.finish(ext4);
+    }
+    let ext4 = image.load();
+    assert_eq!(read_all(&ext4, "a/b/c/new"), b"new file");
+    ext4.generic_lookup(ROOT_INO, "a/b/c/hello")
+        .expect_err("file not removed");
+    assert_eq!(read_all(&ext4, "a/b/data").len(), 3 * BLOCK_SIZE + 100);
+    assert_eq!(read_all(&ext4, "sparse").len(), SPARSE_TAIL + 4);
+}
@@ -0,0 +1,297 @@
+//! Randomized operations cross-checked against an in-memory model.
+
+mod common;
+
+use another_ext4::{Ext4, FileType, InodeId, BLOCK_SIZE};
+use common::*;
+use std::collections::BTreeMap;
+
+/// Directories of the model, created before the random operations.
+const DIRS: &[&str] = &["d0", "d1", "d0/d2"];
+
+/// Number of random operations per seed.
+const OPS: usize = 400;
+
+/// Expected file contents by path.
+type Model = BTreeMap<String, Vec<u8>>;
+
+fn random_path(rng: &mut Rng) -> String {
+    let name = format!("f{}", rng.below(24));
+    match rng.below(DIRS.len() as u64 + 1) as usize {
+        0 => name,
+        i => format!("{}/{}", DIRS[i - 1], name),
+    }
+}
+
+fn random_data(rng: &mut Rng, max_len: u64) -> Vec<u8> {
+    let len = rng.below(max_len) as usize + 1;
+    let byte = rng.next() as u8;
+    (0..len).map(|i| byte.wrapping_add(i as u8)).collect()
+}
+
+/// Pick an existing file of the model.
+fn random_file(rng: &mut Rng, model: &Model) -> Option<String> {
+    if model.is_empty() {
+        return None;
+    }
+    let i = rng.below(model.len() as u64) as usize;
+    model.keys().nth(i).cloned()
+}
+
+fn step(ext4: &Ext4, model: &mut Model, rng: &mut Rng) {
+    match rng.below(7) {
+        // Create
+        0 => {
+            let path = random_path(rng);
+            let res = ext4.generic_create(ROOT_INO, &path, FILE_MODE);
+            assert_eq!(res.is_ok(), !model.contains_key(&path), "create {}", path);
+            model.entry(path).or_default();
+        }
+        // Write, possibly beyond the end of the file
+        1 | 2 => {
+            let Some(path) = random_file(rng, model) else {
+                return;
+            };
+            let content = model.get_mut(&path).unwrap();
+            let offset = rng.below(content.len() as u64 + 2 * BLOCK_SIZE as u64) as usize;
+            let data = random_data(rng, 4 * BLOCK_SIZE as u64);
+            let file = ext4.generic_lookup(ROOT_INO, &path).expect("lookup failed");
+            ext4.write(file, offset as u64, &data)
+                .expect("write failed");
+            if content.len() < offset + data.len() {
+                content.resize(offset + data.len(), 0);
+            }
+            content[offset..offset + data.len()].copy_from_slice(&data);
+        }
+        // Read a range
+        3 => {
+            let Some(path) = random_file(rng, model) else {
+                return;
+            };
+            let content = &model[&path];
+            let offset = rng.below(content.len() as u64 + 1) as usize;
+            let mut buf = vec![0; rng.below(3 * BLOCK_SIZE as u64) as usize + 1];
+            let file = ext4.generic_lookup(ROOT_INO, &path).expect("lookup failed");
+            let n = ext4
+                .read(file, offset as u64, &mut buf)
+                .expect("read failed");
+            let end = content.len().min(offset + buf.len());
+            assert_eq!(
+                &buf[..n],
+                &content[offset..end],
+                "read {} at {}",
+                path,
+                offset
+            );
+        }
+        // Truncate
+        4 => {
+            let Some(path) = random_file(rng, model) else {
+                return;
+            };
+            let content = model.get_mut(&path).unwrap();
+            let size = rng.below(content.len() as u64 + 1) as usize;
+            let file = ext4.generic_lookup(ROOT_INO, &path).expect("lookup failed");
+            let handle = ext4.inode_handle(file).expect("get handle failed");
+            handle.truncate(size as u64).expect("truncate failed");
+            content.truncate(size);
+        }
+        // Remove
+        5 => {
+            let Some(path) = random_file(rng, model) else {
+                return;
+            };
+            ext4.generic_remove(ROOT_INO, &path).expect("remove failed");
+            model.remove(&path);
+        }
+        // Rename to a free path
+        _ => {
+            let Some(src) = random_file(rng, model) else {
+                return;
+            };
+            let dst = random_path(rng);
+            if model.contains_key(&dst) {
+                return;
+            }
+            ext4.generic_rename(ROOT_INO, &src, &dst)
+                .expect("rename failed");
+            let content = model.remove(&src).unwrap();
+            model.insert(dst, content);
+        }
+    }
+}
+
+/// Check that the filesystem holds exactly the files of the model.
+fn check(ext4: &Ext4, model: &Model) {
+    for dir in [""].iter().chain(DIRS) {
+        let ino = ext4.generic_lookup(ROOT_INO, dir).expect("lookup failed");
+        let mut files: Vec<String> = ext4
+            .listdir(ino)
+            .expect("listdir failed")
+            .iter()
+            .filter(|entry| {
+                let attr = ext4.getattr(entry.inode()).expect("getattr failed");
+                attr.ftype == FileType::RegularFile
+            })
+            .map(|entry| match *dir {
+                "" => entry.name(),
+                _ => format!("{}/{}", dir, entry.name()),
+            })
+            .collect();
+        files.sort();
+        let expected: Vec<&String> = model
+            .keys()
+            .filter(|path| match path.rsplit_once('/') {
+                Some((parent, _)) => parent == *dir,
+                None => dir.is_empty(),
+            })
+            .collect();
+        assert_eq!(
+            files.iter().collect::<Vec<_>>(),
+            expected,
+            "entries of /{}",
+            dir
+        );
+    }
+    for (path, content) in model {
+        let file = ext4.generic_lookup(ROOT_INO, path).expect("lookup failed");
+        assert_eq!(
+            ext4.getattr(file).expect("getattr failed").size,
+            content.len() as u64
+        );
+        let mut buf = vec![0; content.len()];
+        ext4.read(file, 0, &mut buf).expect("read failed");
+        assert!(buf == *content, "content of {}", path);
+    }
+}
+
+fn run(seed: u64) {
+    let image = TestImage::new(&format!("model_{}", seed), 64, &[]);
+    let mut rng = Rng::new(seed);
+    let mut model = Model::new();
+    {
+        let ext4 = image.load();
+        for dir in DIRS {
+            ext4.generic_create(ROOT_INO, dir, DIR_MODE)
+                .expect("mkdir failed");
+        }
+        for _ in 0..OPS {
+            step(&ext4, &mut model, &mut rng);
+        }
+        check(&ext4, &model);
+        image.finish(ext4);
+    }
+    // Everything persists across a reload
+    check(&image.load(), &model);
+}
+
+#[test]
+fn model_seed_1() {
+    run(1);
+}
+
+#[test]
+fn model_seed_2() {
+    run(0x5eed);
+}
+
+#[test]
+fn model_seed_3() {
+    run(0xdead_beef);
+}
+
+fn shuffle(blocks: &mut [u32], rng: &mut Rng) {
+    for i in (1..blocks.len()).rev() {
+        blocks.swap(i, rng.below(i as u64 + 1) as usize);
+    }
+}
+
+/// Read the tag of a logical block written by `run_extent_tree`, 0 for a
+/// hole.
+fn read_tag(ext4: &Ext4, file: InodeId, lblock: u32) -> u32 {
+    let mut buf = [0; 4];
+    ext4.read(file, lblock as u64 * BLOCK_SIZE as u64, &mut buf)
+        .expect("read failed");
+    u32::from_le_bytes(buf)
+}
+
+/// Check that the even logical blocks in `mapped` hold their tag, and the
+/// odd blocks after them are holes.
+fn check_tags<'a>(ext4: &Ext4, file: InodeId, mapped: impl IntoIterator<Item = &'a u32>) {
+    for &lblock in mapped {
+        assert_eq!(read_tag(ext4, file, lblock), lblock, "block {}", lblock);
+        assert_eq!(read_tag(ext4, file, lblock + 1), 0, "hole {}", lblock + 1);
+    }
+}
+
+/// Map single blocks of a file until its extent tree is `depth` levels
+/// deep, cross-checking the mapping on the way. Only even logical blocks
+/// are written, so that every block gets an extent of its own.
+///
+/// Blocks are written in a random order, or with `backwards`, in shuffled
+/// runs going down from the end of the file. Inserting before the first
+/// extents keeps splitting the leftmost nodes, which grows the tree with
+/// fewer blocks.
+fn run_extent_tree(seed: u64, depth: u16, backwards: bool) {
+    let image = TestImage::new(&format!("extent_tree_{}", seed), 512, &[]);
+    let mut rng = Rng::new(seed);
+    let max_blocks: u32 = 100_000;
+    let mut order: Vec<u32> = Vec::with_capacity(max_blocks as usize);
+    if backwards {
+        let mut end = max_blocks;
+        while end > 0 {
+            let start = end.saturating_sub(rng.below(64) as u32 + 1);
+            let mut run: Vec<u32> = (start..end).collect();
+            shuffle(&mut run, &mut rng);
+            order.extend(run);
+            end = start;
+        }
+    } else {
+        order.extend(0..max_blocks);
+        shuffle(&mut order, &mut rng);
+    }
+
+    let ext4 = image.load();
+    let file = ext4
+        .generic_create(ROOT_INO, "f", FILE_MODE)
+        .expect("create failed");
+    let mut mapped = Vec::new();
+    for (i, lblock) in order.into_iter().map(|block| block * 2).enumerate() {
+        ext4.write(
+            file,
+            lblock as u64 * BLOCK_SIZE as u64,
+            &lblock.to_le_bytes(),
+        )
+        .expect("write failed");
+        mapped.push(lblock);
+        if i % 1024 == 1023 {
+            // Check a sample of the blocks so far
+            let sample = (0..64).map(|_| &mapped[rng.below(mapped.len() as u64) as usize]);
+            check_tags(&ext4, file, sample);
+            let root = ext4.dump_tree(file).expect("dump tree failed");
+            if root.depth >= depth {
+                break;
+            }
+        }
+    }
+    let root = ext4.dump_tree(file).expect("dump tree failed");
+    assert!(root.depth >= depth, "extent tree of depth {}", root.depth);
+    check_tags(&ext4, file, &mapped);
+    image.finish(ext4);
+
+    let ext4 = image.load();
+    check_tags(&ext4, file, &mapped);
+    // Freeing the file frees every tree node
+    ext4.unlink(ROOT_INO, "f").expect("unlink failed");
+    image.finish(ext4);
+}
+
+#[test]
+fn extent_tree_random() {
+    run_extent_tree(7, 2, false);
+}
+
+#[test]
+fn extent_tree_backwards() {
+    run_extent_tree(0xe47, 3, true);
+}
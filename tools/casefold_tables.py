@@ -0,0 +1,93 @@
+#!/usr/bin/env python3
+"""Generate src/ext4_defs/casefold_tables.rs from the Unicode database of
+the running Python.
+
+Names in casefolded directories are compared like the kernel's `utf8`
+encoding does: full case folding, then canonical decomposition, with
+default ignorable code points removed.
+
+Usage: tools/casefold_tables.py > src/ext4_defs/casefold_tables.rs
+"""
+
+import unicodedata
+
+# Default_Ignorable_Code_Point, from DerivedCoreProperties.txt
+IGNORABLE = [
+    (0x00AD, 0x00AD), (0x034F, 0x034F), (0x061C, 0x061C),
+    (0x115F, 0x1160), (0x17B4, 0x17B5), (0x180B, 0x180F),
+    (0x200B, 0x200F), (0x202A, 0x202E), (0x2060, 0x206F),
+    (0x3164, 0x3164), (0xFE00, 0xFE0F), (0xFEFF, 0xFEFF),
+    (0xFFA0, 0xFFA0), (0xFFF0, 0xFFF8), (0x1BCA0, 0x1BCA3),
+    (0x1D173, 0x1D17A), (0xE0000, 0xE0FFF),
+]
+
+# Hangul syllables are decomposed algorithmically
+HANGUL = range(0xAC00, 0xAC00 + 11172)
+
+
+def ignorable(cp):
+    """Whether a code point is dropped, whatever its folding."""
+    return any(lo <= cp <= hi for lo, hi in IGNORABLE)
+
+
+def char(cp):
+    c = chr(cp)
+    if c.isascii() and c.isalnum():
+        return "'%s'" % c
+    return "'\\u{%x}'" % cp
+
+
+def string(s):
+    return '"%s"' % "".join(
+        c if c.isascii() and c.isalnum() else "\\u{%x}" % ord(c) for c in s
+    )
+
+
+def main():
+    fold = []
+    combining = []
+    for cp in range(0x110000):
+        if 0xD800 <= cp < 0xE000 or cp in HANGUL:
+            continue
+        c = chr(cp)
+        if unicodedata.category(c) == "Cn":
+            continue
+        folded = unicodedata.normalize("NFD", c.casefold())
+        if folded != c and not ignorable(cp):
+            fold.append((cp, folded))
+        ccc = unicodedata.combining(c)
+        if ccc:
+            if combining and combining[-1][1] == cp - 1 and combining[-1][2] == ccc:
+                combining[-1][1] = cp
+            else:
+                combining.append([cp, cp, ccc])
+
+    print("//! Unicode tables for comparing names in casefolded directories,")
+    print("//! generated by `tools/casefold_tables.py` from Unicode %s. Do not"
+          % unicodedata.unidata_version)
+    print("//! edit.")
+    print()
+    print("/// Ranges of default ignorable code points, dropped from names. Sorted.")
+    print("pub(super) static IGNORABLE: &[(char, char)] = &[")
+    for lo, hi in IGNORABLE:
+        print("    (%s, %s)," % (char(lo), char(hi)))
+    print("];")
+    print()
+    print("/// Code points folded to something else: the canonical decomposition")
+    print("/// of their full case folding. Sorted by code point, Hangul syllables")
+    print("/// and default ignorable code points are not included.")
+    print("pub(super) static FOLD: &[(char, &str)] = &[")
+    for cp, folded in fold:
+        print("    (%s, %s)," % (char(cp), string(folded)))
+    print("];")
+    print()
+    print("/// Ranges of code points with a non-zero canonical combining class,")
+    print("/// sorted.")
+    print("pub(super) static COMBINING_CLASS: &[(char, char, u8)] = &[")
+    for lo, hi, ccc in combining:
+        print("    (%s, %s, %d)," % (char(lo), char(hi), ccc))
+    print("];")
+
+
+if __name__ == "__main__":
+    main()